@@ -12,9 +12,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-const {promisify} = require('util');
+const fs = require('fs');
+const path = require('path');
+
 const seshatNative = require('./index.node');
 
+/**
+ * Errors that are caused by the database carry a <code>code</code> property
+ * describing what went wrong. Unlike the messages of the errors the codes
+ * are stable, they are one of:
+ *
+ * <ul>
+ * <li><code>DATABASE_CORRUPTED</code>: The database needs to be recreated.</li>
+ * <li><code>REINDEX_REQUIRED</code>: The index needs to be rebuilt.</li>
+ * <li><code>DATABASE_LOCKED</code>: Another process uses the database.</li>
+ * <li><code>DATABASE_BUSY</code>: The operation can be retried later.</li>
+ * <li><code>WRONG_PASSPHRASE</code>: A passphrase of the database is
+ * wrong.</li>
+ * <li><code>VERSION_MISMATCH</code>: The database or a backup was created by
+ * an incompatible version.</li>
+ * <li><code>CONFIG_MISMATCH</code>: The database was created with a different
+ * configuration.</li>
+ * <li><code>IO_ERROR</code> and <code>DISK_FULL</code>: Reading or writing a
+ * file failed.</li>
+ * <li><code>INVALID_QUERY</code>: The search term or its options are
+ * invalid.</li>
+ * <li><code>INVALID_EVENT</code>: An event is invalid or too large.</li>
+ * <li><code>INVALID_CHECKPOINT</code>: A crawler checkpoint is invalid, e.g.
+ * because its token is empty.</li>
+ * <li><code>NOT_FOUND</code>: An event, the index or a watermark couldn't be
+ * found.</li>
+ * <li><code>UNSUPPORTED</code>: The operation isn't supported by the
+ * configuration of the database.</li>
+ * <li><code>DATABASE_CLOSED</code>: The database has been closed.</li>
+ * <li><code>QUEUE_FULL</code>, <code>CANCELLED</code>,
+ * <code>BACKUP_FAILED</code>, <code>IMPORT_FAILED</code>,
+ * <code>DATABASE_ERROR</code> and <code>INDEX_ERROR</code> for other
+ * failures.</li>
+ * </ul>
+ *
+ * Errors about invalid arguments don't have a code.
+ */
+
 /**
  * @typedef searchResult
  * @type {Object}
@@ -22,28 +61,129 @@ const seshatNative = require('./index.node');
  * in the next search call.
  * @property {number} count The total number of results that were found.
  * @property {Array.<singleResult>} results The list of results that was found.
+ * @property {Array.<string>} highlights The search terms that were found in
+ * any of the results.
+ * @property {number} skipped The number of stored events that couldn't be
+ * loaded, e.g. because they are corrupted, including the results that were
+ * left out because the search required their context. If this is non-zero a
+ * reindex of the database might be necessary.
+ * @property {{room_id: Object.<string, roomGroup>}} groups The results grouped
+ * by room, only present if grouping was requested.
+ * @property {Array.<string>} room_order The ids of the rooms that matched,
+ * ordered by their best result, only present if the results were ordered by
+ * room.
+ * @property {searchTiming} timing How long the search took, only present if
+ * timing information was requested.
+ * @property {boolean} timed_out Did the search run out of time, the results
+ * then only contain the events that were found until the timeout.
+ */
+
+/**
+ * @typedef searchTiming
+ * @type {Object}
+ * @property {number} index_millis The milliseconds it took to query the
+ * index.
+ * @property {number} context_millis The milliseconds it took to load the
+ * results and their context from the database.
+ * @property {number} total_millis The milliseconds the whole search took.
+ */
+
+/**
+ * @typedef roomGroup
+ * @type {Object}
+ * @property {number} count The number of events of the room that matched the
+ * search, including the ones that aren't part of the current batch.
+ * @property {Array.<string>} results The event ids of the best matching events
+ * of the room.
  */
 
 /**
  * @typedef singleResult
  * @type {Object}
  * @property {number} rank The rank of the search result.
+ * @property {number} raw_score The rank before it was normalized, only set if
+ * the search normalized the scores.
  * @property {matrixEvent} result The full event of the search result.
  * @property {searchContext} context The context of the result, containing
  * events before and after the result.
+ * @property {Array.<string>} highlights The search terms that were found in
+ * the result.
+ * @property {Array.<string>} matched_fields The fields of the result that
+ * contained a search term, any of <code>content.body</code>,
+ * <code>content.topic</code>, <code>content.name</code> and
+ * <code>sender</code>.
+ * @property {?string} matched_field The field of the result that contained a
+ * search term, a field of the event content is preferred over the
+ * <code>sender</code>. Null if the field couldn't be determined, e.g. for
+ * advanced queries.
+ * @property {boolean} source_available Is the result the full event. If the
+ * database doesn't store event sources, the result only contains the ID, the
+ * room, the sender and the timestamp of the event and the full event needs to
+ * be fetched from the homeserver.
+ * @property {?string} room_display_name The name of the room of the result,
+ * or its canonical alias if the room doesn't have a name. Null if neither
+ * an <code>m.room.name</code> nor an <code>m.room.canonical_alias</code>
+ * event of the room was added.
+ * @property {contextTruncation} context_truncated Which sides of the context
+ * contain fewer events than requested because the result is at the edge of
+ * the indexed history of its room.
+ */
+
+/**
+ * @typedef contextTruncation
+ * @type {Object}
+ * @property {boolean} before Are there fewer events before the result than
+ * requested, e.g. because it's the first indexed event of its room.
+ * @property {boolean} after Are there fewer events after the result than
+ * requested, e.g. because it's the latest indexed event of its room.
+ */
+
+/**
+ * @typedef contextEventInfo
+ * @type {Object}
+ * @property {string} event_id The ID of the context event.
+ * @property {number} server_ts The timestamp of the context event.
+ * @property {number} insert_id The position of the context event in the
+ * order the events were stored in, context events that share a timestamp are
+ * ordered by it. Only comparable to other insert ids of the same database.
  */
 
 /**
  * @typedef searchContext
  * @type {Object}
  * @property {Array.<matrixEvent>} events_before Events that happened before the
- * search result.
+ * search result, the closest one first, i.e. in descending order of their
+ * timestamps.
  * @property {Array.<matrixEvent>} events_after Events that happened after the
- * search result.
+ * search result, the closest one first, i.e. in ascending order of their
+ * timestamps.
+ * @property {Array.<contextEventInfo>} events_before_info The IDs and
+ * timestamps of the events in <code>events_before</code>, in the same order.
+ * @property {Array.<contextEventInfo>} events_after_info The IDs and
+ * timestamps of the events in <code>events_after</code>, in the same order.
  * @property {{user_id: matrixProfile}} profile_info The historic profile
  * information of the users that sent the events returned.
  */
 
+/**
+ * @typedef eventWithContext
+ * @type {Object}
+ * @property {matrixEvent} result The full event.
+ * @property {searchContext} context The context of the event, containing
+ * events before and after it.
+ * @property {number} skipped The number of context events that were skipped
+ * because they couldn't be deserialized.
+ */
+
+/**
+ * @typedef exportSummary
+ * @type {Object}
+ * @property {number} count The number of search results that were written.
+ * @property {number} skipped The number of search results that were left out
+ * because their event couldn't be deserialized.
+ * @property {number} duration How long the export took, in milliseconds.
+ */
+
 /**
  * @typedef matrixEvent
  * @type {Object}
@@ -62,6 +202,10 @@ const seshatNative = require('./index.node');
  * @type {Object}
  * @property {string} displayname The users display name, if one is set.
  * @property {string} avatar_url The users avatar url, if one is set.
+ *
+ * Both properties are trimmed, empty strings, <code>null</code> and
+ * <code>undefined</code> mean that the property isn't set. Adding an event
+ * with a profile property of any other type throws a <code>TypeError</code>.
  */
 
 
@@ -71,11 +215,29 @@ const seshatNative = require('./index.node');
  * @property {string} roomId The unique id of the room that this checkpoint
  * belongs to.
  * @property {string} token The token that can be used to fetch more events for
- * the given room.
+ * the given room. The token can't be empty or contain whitespace,
+ * surrounding whitespace of the token and the room id is removed.
  * @property {boolean} fullCrawl Is this checkpoint of a crawl that should
- * re-crawl the complete room history.
+ * re-crawl the complete room history. Defaults to false.
  * @property {string} direction The crawl direction of the checkpoint. Can be
- * one of "b" or "f".
+ * one of "b" or "f", "backwards" and "forwards" are accepted as well when
+ * storing a checkpoint. Defaults to "b".
+ */
+
+/**
+ * @typedef backlogResult
+ * @type {Object}
+ * @property {boolean} added False if all the stored events were already in
+ * the database, true if any of them is new.
+ * @property {Array.<skippedEvent>} skipped The events that were skipped
+ * because they are invalid.
+ */
+
+/**
+ * @typedef skippedEvent
+ * @type {Object}
+ * @property {number} index The index of the event in the added batch.
+ * @property {string} reason Why the event was skipped.
  */
 
 /**
@@ -92,9 +254,107 @@ const seshatNative = require('./index.node');
  * @type {Object}
  * @property {number} size The number of bytes that the database is consuming on
  * the disk.
- * @property {number} eventCount The number events that are stored in the
+ * @property {?number} eventCount The number events that are stored in the
+ * database, null if the database is empty.
+ * @property {?number} roomCount The number of rooms the database knows about,
+ * null if the database is empty.
+ * @property {number} queuedEvents The number of events that are queued up to be
+ * written to the database. This can be used to throttle the addition of events.
+ * @property {number} tombstoneCount The number of events that were deleted
+ * since the database was last compacted.
+ * @property {number} sourceCacheHits The number of search results whose
+ * source was served from the source cache since the database was opened.
+ * @property {number} sourceCacheMisses The number of search results whose
+ * source had to be loaded from the database since it was opened.
+ * @property {string} consistency The result of comparing the event store
+ * with the search index when the database was opened, one of "consistent",
+ * "indexBehind", "storeBehind" or "diverged". A mismatch is reported until
+ * the database is repaired.
+ */
+
+/**
+ * @typedef databaseSize
+ * @type {Object}
+ * @property {number} indexBytes The size of the live files of the search
+ * index.
+ * @property {number} storeBytes The size of the SQLite database, including
+ * its WAL and journal files.
+ * @property {number} totalBytes The sum of the two sizes.
+ */
+
+/**
+ * @typedef databaseMetrics
+ * @type {Object}
+ * @property {number} eventsAdded The number of events that were stored.
+ * @property {number} eventsDeleted The number of events that were deleted.
+ * @property {number} commits The number of commits of the index.
+ * @property {number} searches The number of searches that were served.
+ * @property {number} searchErrors The number of searches that failed.
+ * @property {number} sourceCacheHits The number of search results whose
+ * source was served from the source cache.
+ * @property {number} sourceCacheMisses The number of search results whose
+ * source had to be loaded from the database.
+ */
+
+/**
+ * The sequence number of an add of events, see <code>addEvent()</code>.
+ *
+ * Sequence numbers are numbers as long as they don't exceed
+ * <code>Number.MAX_SAFE_INTEGER</code>, larger ones are decimal strings since
+ * a number would silently lose precision. <code>BigInt(sequence)</code>
+ * converts both forms if sequence numbers need to be compared.
+ *
+ * @typedef sequenceNumber
+ * @type {number|string}
+ */
+
+/**
+ * @typedef opstamps
+ * @type {Object}
+ * @property {sequenceNumber} queued The sequence number of the last add of
+ * events, see <code>addEvent()</code>.
+ * @property {sequenceNumber} committed The sequence number of the last add whose
+ * events were committed. The events of every add with a sequence number that
+ * is smaller or equal were written to the database and are searchable after a
+ * reload.
+ */
+
+/**
+ * @typedef writerError
+ * @type {Object}
+ * @property {string} operation The operation that failed, e.g.
+ * "autoCommit", "historicEvents" or "retentionPurge".
+ * @property {string|null} roomId The room the operation was working on, if it
+ * was limited to a room.
+ * @property {string} kind The kind of the error, one of "DatabaseError",
+ * "IndexError", "IOError", "FsError", "DatabaseLocked" or "Error". Database
+ * and IO errors are usually caused by a full disk, an index error may mean
+ * that the database needs to be reindexed.
+ * @property {string} code The stable code of the error, as for the errors
+ * that are thrown by the methods of the database.
+ * @property {string} message The description of the error.
+ */
+
+/**
+ * @typedef versionInfo
+ * @type {Object}
+ * @property {string} crateVersion The version of the Seshat library.
+ * @property {number} databaseVersion The version of the schema of the
  * database.
- * @property {number} roomCount The number of rooms the database knows about.
+ * @property {number} indexVersion The version of the schema of the search
+ * index.
+ */
+
+/**
+ * @typedef roomInfo
+ * @type {Object}
+ * @property {string} roomId The unique id of the room.
+ * @property {number} eventCount The number of events of the room that are
+ * stored in the database.
+ * @property {?number} oldestEventTs The timestamp of the oldest stored event of
+ * the room, null if the room doesn't have any events.
+ * @property {?number} newestEventTs The timestamp of the newest stored event of
+ * the room, null if the room doesn't have any events.
  */
 
 /**
@@ -146,12 +406,137 @@ class Seshat {
      * @param {string} path The path where the database should be stored. If a
      * database already exist in the given folder the database will be reused.
      * @param {object} config Additional configuration for the database.
-     * database already exist in the given folder the database will be reused.
      * @param  {string} config.language The language that the database should
      * use for indexing. Picking the correct indexing language may improve the
-     * search.  @param  {string} config.passphrase The passphrase that should be
+     * search.
+     * @param  {boolean} config.detectLanguage Should the language of every
+     * message be detected, messages are then indexed using the rules of their
+     * own language instead of the <code>language</code> of the database.
+     * Useful for accounts that use multiple languages, defaults to false.
+     * Changing this for an existing database requires a reindex.
+     * @param  {Array.<string>} config.detectedLanguages The languages that
+     * language detection chooses from, e.g. <code>["english", "german"]</code>.
+     * Defaults to all the supported languages.
+     * @param  {boolean} config.splitIdentifiers Should CamelCase identifiers in
+     * messages be split into their words, so searching for "searcher" finds
+     * messages mentioning <code>IndexSearcher</code>. Useful for rooms that
+     * discuss code, defaults to false. Changing this for an existing database
+     * requires a reindex.
+     * @param  {string} config.indexPath The directory the search index should
+     * be stored in, e.g. on a volume that isn't backed up. Defaults to the
+     * database directory.
+     * @param  {string} config.storePath The directory the SQLite store, which
+     * contains the events and the crawler checkpoints, should be stored in.
+     * Defaults to the database directory.
+     * @param  {string} config.passphrase The passphrase that should be
      * used to encrypt the database. The database is left unencrypted it no
      * passphrase is set.
+     * @param  {boolean} config.wal Should the database use write-ahead logging,
+     * defaults to true.
+     * @param  {number} config.busyTimeout How long, in milliseconds, should the
+     * database wait for a lock held by another connection.
+     * @param  {number} config.writeQueueCapacity How many events can be queued
+     * up before <code>addEvent()</code> throws a <code>QUEUE_FULL</code>
+     * error until the writer catches up.
+     * @param  {number} config.autoCommitInterval How long, in milliseconds,
+     * should the database wait before it automatically commits added events.
+     * Events are only committed manually if this isn't set.
+     * @param  {number} config.autoCommitAfterEvents After how many added events
+     * should the database automatically commit them.
+     * @param  {number} config.lockTimeout How long, in milliseconds, should the
+     * database wait for another process to release the index, defaults to 0.
+     * @param  {boolean} config.forceUnlock Remove a stale index lock before
+     * opening the database. This corrupts the index if another process is
+     * still using the database, it should only be used for recovery.
+     * @param  {number} config.retention How long, in milliseconds, should
+     * events be kept. Older events are periodically deleted from the database
+     * if this is set.
+     * @param  {Array.<string>} config.indexedMsgtypes The msgtypes of the
+     * messages that should be stored, e.g. <code>["m.text"]</code> to skip
+     * notices and emotes. Messages of any type are stored if this isn't set.
+     * @param  {boolean} config.indexStateHistory Should every name and topic
+     * of a room be searchable, defaults to false. Only the latest name and
+     * topic of a room are searchable otherwise.
+     * @param  {boolean} config.storeSources Should the full events be stored,
+     * defaults to true. If disabled, search results only contain the ID, the
+     * room, the sender and the timestamp of the events and no context. The
+     * setting can't be changed once the database was created.
+     * @param  {string} config.sourceEncryptionPassphrase The passphrase that
+     * the stored events and sender profiles should be encrypted with. Unlike
+     * the <code>passphrase</code> this leaves the structure of the database
+     * and the index unencrypted. Only new databases can be encrypted this way.
+     * @param  {number} config.compactionThreshold The number of deleted events
+     * after which compacting the database is worthwhile, defaults to 1000.
+     * @param  {boolean} config.autoCompaction Should the database be compacted
+     * automatically while it's idle once the compaction threshold is reached,
+     * defaults to false.
+     * @param  {number} config.searchTokenTtl How long, in milliseconds, does
+     * the <code>next_batch</code> token of a search stay valid, defaults to 5
+     * minutes.
+     * @param  {boolean} config.autoReload Should the index be reloaded
+     * automatically after every commit, defaults to true. If disabled the
+     * <code>reload()</code> method needs to be called before searches find
+     * newly committed events.
+     * @param  {number} config.connectionPoolSize The maximal number of
+     * database connections that are kept open for concurrent reads, defaults
+     * to 10. Two of them are always used by the database itself.
+     * @param  {number} config.connectionTimeout How long, in milliseconds,
+     * should a read wait for a free connection before failing, defaults to 30
+     * seconds. Reads that return a promise wait in the background, if they
+     * don't get a connection in time the promise is rejected.
+     * @param  {number} config.writerHeapSize The amount of memory, in bytes,
+     * the index writer may use to buffer events, defaults to 50MB. Values below
+     * 3MB are raised to 3MB.
+     * @param  {number} config.maxQueryLength The maximal number of characters
+     * of a search term, defaults to 1000.
+     * @param  {number} config.maxQueryTerms The maximal number of distinct
+     * words of a search term, defaults to 64.
+     * @param  {number} config.maxQueryClauses The maximal number of clauses a
+     * search may expand to, every word is searched for in every searched
+     * field. Defaults to 512.
+     * @param  {number} config.maxWildcardTerms The maximal number of terms a
+     * search word with a wildcard, e.g. <code>report*</code>, is expanded to.
+     * Defaults to 100.
+     * @param  {number} config.maxRestrictedIds The maximal number of event ids
+     * a search may be restricted to using the <code>restrict_to_events</code>
+     * search option. Defaults to 1000.
+     * @param  {boolean} config.leadingWildcards Should wildcards at the start
+     * of search words, e.g. <code>*port</code>, be allowed. Those need to be
+     * matched against every term of the index. Defaults to false.
+     * @param  {boolean} config.recordSearches Should the terms of successful
+     * searches be recorded in the search history, defaults to false.
+     * @param  {string} config.defaultOperator How the words of a search term
+     * are combined by default, either <code>"and"</code> to only return
+     * events containing all of them or <code>"or"</code> to return events
+     * containing any of them. Defaults to <code>"or"</code>.
+     * @param  {number} config.maxIndexedBodyBytes The maximal number of bytes
+     * of an event body that are indexed, longer bodies are truncated.
+     * Defaults to 256 KiB.
+     * @param  {boolean} config.truncateStoredBodies Should the bodies in the
+     * stored event sources be truncated to the same length as the indexed
+     * ones, defaults to false.
+     * @param  {number} config.maxEventSize The maximal size of an event in
+     * bytes, adding a bigger event throws an error and bigger events from the
+     * room history are dropped. Defaults to 16 MiB.
+     * @param  {number} config.sourceCacheCapacity The number of events whose
+     * sources are kept in memory for consecutive searches, 0 disables the
+     * cache. Defaults to 1000.
+     * @param  {string} config.logPath The path of a log file the library
+     * should write its tracing events to, the file is rotated daily. The log
+     * is global to the process, only the first database that is opened with
+     * a log path sets it up.
+     * @param  {string} config.logLevel The maximal level of the logged events,
+     * one of "error", "warn", "info", "debug" or "trace". Debug logs the
+     * duration of every search, commit and reload. Defaults to "info".
+     *
+     * @throws {ReindexError} If the database needs to be reindexed.
+     * @throws {Error} If the database can't be opened, the <code>code</code>
+     * property of the error contains the kind of the underlying error, e.g.
+     * <code>WRONG_PASSPHRASE</code> for a wrong passphrase,
+     * <code>NOT_FOUND</code> if the store contains events but the index is
+     * missing or <code>DATABASE_LOCKED</code> if another process is using
+     * the database.
+     * @throws {TypeError} If the path is empty.
      *
      * @constructor
      *
@@ -179,23 +564,101 @@ class Seshat {
             }
         }
     }
+
+    /**
+     * The version of the Seshat library.
+     *
+     * @type {string}
+     */
+    static get version() {
+        return seshatNative.version;
+    }
+
     /**
      * Add an event to the database.
      *
      * This method adds an event only to a queue. To write the events to the
      * database the <code>commit()</code> methods needs to be called.
      *
+     * <code>m.reaction</code> events aren't indexed, they only count towards
+     * the reactions of the event they annotate, see the
+     * <code>boost_by_reactions</code> argument of <code>search()</code>.
+     * <code>m.room.canonical_alias</code> events aren't indexed either, the
+     * alias is only used to label the search results of rooms without a name,
+     * see <code>getRoomNames()</code>.
+     *
      * @param  {matrixEvent} matrixEvent A Matrix event that should be added to
      * the database.
      * @param  {matrixProfile} profile The user profile of the sender at the
      * time the event was sent.
      *
-     * @return {Void}
+     * Adding an event never blocks. If the write queue is full, an
+     * <code>Error</code> with the <code>QUEUE_FULL</code> code is thrown and
+     * the event isn't added, the caller should wait for the writer to catch
+     * up, e.g. by awaiting <code>commit()</code>, before it adds the event
+     * again.
+     *
+     * @throws {TypeError} If the event is invalid, if it is larger than the
+     * <code>maxEventSize</code> of the database config or if the writer of
+     * the database has stopped because of a failure.
+     * @throws {Error} With the <code>QUEUE_FULL</code> code if the write
+     * queue is full.
+     *
+     * @return {sequenceNumber} The sequence number of the add. Once a commit
+     * returns a number that is equal or larger, the event is committed and searchable
+     * after a reload.
      */
     addEvent(matrixEvent, profile = {}) {
         return seshatNative.addEvent(this.inner, matrixEvent, profile);
     };
 
+    /**
+     * Add an event to the database.
+     * This is the asynchronous equivalent of the <code>addEvent()</code>
+     * method, the event is parsed and queued up on a background thread.
+     *
+     * This method adds an event only to a queue. To write the events to the
+     * database the <code>commit()</code> methods needs to be called.
+     *
+     * @param  {matrixEvent} matrixEvent A Matrix event that should be added to
+     * the database.
+     * @param  {matrixProfile} profile The user profile of the sender at the
+     * time the event was sent.
+     *
+     * @return {Promise<sequenceNumber>} A promise that will resolve to the
+     * sequence number of the add once the event has been accepted by the write queue,
+     * or reject if the event is invalid. See <code>addEvent()</code>.
+     */
+    async addEventAsync(matrixEvent, profile = {}) {
+        return seshatNative.addEventAsync(this.inner, matrixEvent, profile);
+    };
+
+    /**
+     * Add a batch of events to the database.
+     *
+     * This is meant for bursts of live events, all the events are parsed in
+     * one go and queued up together. If any of the events is invalid none of
+     * them will be queued up and the promise will reject with an error that
+     * contains the index of the invalid event. The same happens if any of
+     * the events is larger than the <code>maxEventSize</code> of the
+     * database config, the error names the event that is too large.
+     *
+     * This method adds the events only to a queue. To write the events to
+     * the database the <code>commit()</code> methods needs to be called.
+     *
+     * @param  {array<matrixEvent,matrixProfile>} events An array of objects
+     * of the form <code>{event, profile, source}</code>, the profile and the
+     * pre-serialized JSON source of the event are optional.
+     *
+     * @return {Promise<{count: number, sequence: sequenceNumber}>} A promise
+     * that will resolve to the number of events that were queued up and the
+     * sequence number that the whole batch shares, see
+     * <code>addEvent()</code>.
+     */
+    async addEvents(events) {
+        return seshatNative.addEvents(this.inner, events);
+    };
+
     /**
      * Delete an event from the database.
      *
@@ -205,12 +668,134 @@ class Seshat {
      * @param  {string} eventId The unique id of the event that should be
      * deleted from the database.
      *
-     * @return {Promise<boolean>} A boolean indicating if the event was removed
-     * from the index or if a commit later on will be needed.
+     * @return {Promise<boolean>} A boolean indicating if the event was deleted
+     * right away, or if it isn't part of the database yet and the deletion was
+     * deferred until the event gets added. Deferred deletions make sure that
+     * redacted events that are crawled later on don't get indexed.
+     *
+     * The deletion takes part in the normal commit cycle, once a
+     * <code>commit()</code> resolves the event won't be returned by searches
+     * anymore. Deleting an event that was never added to the database
+     * isn't an error.
      */
     async deleteEvent(eventId) {
-        const deleteEvent = promisify(seshatNative.deleteEvent);
-        return deleteEvent(this.inner, eventId);
+        return seshatNative.deleteEvent(this.inner, eventId);
+    };
+
+    /**
+     * Delete all events that are older than the given timestamp.
+     *
+     * The events are removed from the index and the database right away,
+     * crawler checkpoints are left untouched.
+     *
+     * @param  {number} timestamp The timestamp, in milliseconds since the
+     * epoch, events older than this will be deleted.
+     *
+     * @return {Promise<number>} The number of deleted events.
+     */
+    async deleteEventsOlderThan(timestamp) {
+        return seshatNative.deleteEventsOlderThan(this.inner, timestamp);
+    };
+
+    /**
+     * Enable or disable the indexing of a room.
+     *
+     * Events of rooms that have their indexing disabled are dropped when they
+     * are added to the database.
+     *
+     * @param  {string} roomId The ID of the room.
+     * @param  {boolean} enabled Should the events of the room be indexed.
+     * @param  {boolean} purge Should the events of the room that are already
+     * part of the database be deleted if the indexing is disabled, defaults
+     * to false.
+     *
+     * @return {Promise<number>} The number of deleted events.
+     */
+    async setRoomIndexing(roomId, enabled, purge = false) {
+        return seshatNative.setRoomIndexing(this.inner, roomId, enabled, purge);
+    };
+
+    /**
+     * Delete all the events and the crawler checkpoints of a room.
+     *
+     * New events of the room are still added, but batches from the room
+     * history that were queued before the room was deleted, e.g. by a running
+     * import, are dropped. A batch that only contained events of the room
+     * resolves to false, like a batch of already known events.
+     *
+     * @param  {string} roomId The ID of the room.
+     *
+     * @return {Promise<number>} The number of deleted events.
+     */
+    async deleteRoom(roomId) {
+        return seshatNative.deleteRoom(this.inner, roomId);
+    };
+
+    /**
+     * Delete all the events of a sender, e.g. if the sender asked for their
+     * personal data to be erased.
+     *
+     * The events are deleted in batches, together with the stored profiles
+     * of the sender. Afterwards searches, the context of search results and
+     * profile lookups don't show anything from the sender once the index is
+     * reloaded.
+     *
+     * @param  {string} userId The user ID of the sender.
+     * @param  {function(number)} progress An optional function that is called
+     * with the number of deleted events after every batch.
+     *
+     * @return {Promise<number>} The number of deleted events.
+     */
+    async deleteSender(userId, progress = null) {
+        return seshatNative.deleteSender(this.inner, userId, progress);
+    };
+
+    /**
+     * Compact the database if enough events were deleted since the last
+     * compaction.
+     *
+     * Deleted events keep taking up disk space until the database is
+     * compacted, the <code>tombstoneCount</code> of the database stats can be
+     * used to decide when to compact it.
+     *
+     * @return {Promise<boolean>} True if the database was compacted, false if
+     * the compaction threshold wasn't reached.
+     */
+    async compact() {
+        return seshatNative.compact(this.inner);
+    };
+
+    /**
+     * Compact the database right away and release the free disk space.
+     *
+     * Unlike <code>compact()</code> this ignores the compaction threshold,
+     * it's meant to be used after a big purge. The work happens in the
+     * background after the queued up events are committed, searches can
+     * continue meanwhile.
+     *
+     * @return {Promise<number>} The number of bytes the database and the
+     * index shrank by.
+     */
+    async shrink() {
+        return seshatNative.shrink(this.inner);
+    };
+
+    /**
+     * Repair a database whose event store and search index disagree.
+     *
+     * The <code>consistency</code> of the database stats tells if the store
+     * and the index disagree, e.g. because only one of them was restored
+     * from a backup. Events of the store are added to the index again if the
+     * index is behind, this needs the event sources to be stored. Events that
+     * the store doesn't contain are removed from the index if the store is
+     * behind. The work happens in the background after the queued up events
+     * are committed.
+     *
+     * @return {Promise<boolean>} True if the database was repaired, false if
+     * the store and the index were consistent.
+     */
+    async repair() {
+        return seshatNative.repair(this.inner);
     };
 
     /**
@@ -221,15 +806,30 @@ class Seshat {
      *
      * @param  {boolean} force Force the commit, commits to the index are
      * usually rate limited. This gets around the limit and forces the
-     * documents to be added to the index. This should only be used for testing
-     * purposes.
+     * documents to be added to the index. A forced commit syncs the database
+     * to disk as well.
      *
-     * @return {Promise<number>} The latest stamp of the commit. The stamp is
-     * a unique incrementing number that identifies the commit.
+     * @return {Promise<sequenceNumber>} The sequence number of the last add
+     * whose events were committed. Every event whose add returned a number that is
+     * smaller or equal is searchable after a reload.
      */
     async commit(force = false) {
-        const commit = promisify(seshatNative.commit);
-        return commit(this.inner, force);
+        return seshatNative.commit(this.inner, force);
+    }
+
+    /**
+     * Commit the queued up events and sync the database to disk.
+     *
+     * The promise resolves once both the index and the event store are
+     * durable, this should be called before the system suspends or the
+     * application quits. Events that are added in the meantime are part of
+     * the next commit.
+     *
+     * @return {Promise<sequenceNumber>} The sequence number of the last add
+     * whose events were committed.
+     */
+    async forceCommit() {
+        return seshatNative.commit(this.inner, true);
     }
 
     /**
@@ -242,8 +842,8 @@ class Seshat {
      * documents to be added to the index. This should only be used for testing
      * purposes.
      *
-     * @return {number} The latest stamp of the commit. The stamp is a unique
-     * incrementing number that identifies the commit.
+     * @return {sequenceNumber} The sequence number of the last add whose
+     * events were committed, see <code>commit()</code>.
      */
     commitSync(wait = false, force = false) {
         return seshatNative.commitSync(this.inner, wait, force);
@@ -251,60 +851,293 @@ class Seshat {
 
     /**
      * Reload the indexer of the database to reflect the changes of the last
-     * commit. A reload will happen automatically, this method is mainly useful
-     * for unit testing purposes to force a reload before a search.
+     * commit. A reload happens automatically after every commit unless the
+     * <code>autoReload</code> option is disabled, in that case this method
+     * needs to be called for searches to find newly committed events.
+     *
+     * This method blocks while the index is being reloaded, which may take a
+     * while after a large commit. The <code>reloadAsync()</code> method
+     * reloads the index on a background thread.
+     *
+     * @throws {Error} If the index couldn't be reloaded.
      */
     reload() {
         seshatNative.reload(this.inner);
     };
 
+    /**
+     * Reload the indexer of the database to reflect the changes of the last
+     * commit.
+     * This is the asynchronous equivalent of the <code>reload()</code>
+     * method.
+     *
+     * @return {Promise} A promise that will resolve once the index has been
+     * reloaded or reject if the index couldn't be reloaded.
+     */
+    async reloadAsync() {
+        return seshatNative.reloadAsync(this.inner);
+    };
+
+    /**
+     * Wait until the events of a room up to the given timestamp are
+     * searchable.
+     *
+     * The events that were added before this method was called, live ones as
+     * well as ones from the room history, are part of the wait. Once they are
+     * committed the index is reloaded, searches started afterwards find them
+     * without a manual reload. This method doesn't commit on its own, the
+     * events become searchable with the next commit, e.g. the one of a chunk
+     * added using <code>addHistoricEvents()</code>.
+     *
+     * @param  {string} roomId The ID of the room whose events should become
+     * searchable.
+     * @param  {number} timestamp The timestamp of the newest event that should
+     * become searchable.
+     * @param  {number} timeout How many milliseconds to wait for the events to
+     * be committed.
+     *
+     * @return {Promise<boolean>} True once the events are searchable, false if
+     * they weren't committed within the timeout.
+     */
+    async waitForRoomVisibility(roomId, timestamp, timeout) {
+        return seshatNative.waitForRoomVisibility(this.inner, roomId, timestamp, timeout);
+    };
+
+    /**
+     * Prepare the database for searches.
+     *
+     * The first search after opening a large database needs to read the index
+     * from disk. Calling this method when the search UI is opened, before the
+     * user submits a search, loads the index on a background thread so the
+     * first search is as fast as the later ones.
+     *
+     * @return {Promise} A promise that will resolve once the database has
+     * been preloaded.
+     */
+    async preload() {
+        return seshatNative.preload(this.inner);
+    };
+
     /**
      * Search the database for events using the given search term.
      * This is the asynchronous equivalent of the <code>searchSync()</code>
      * method.
      *
      * @param  {object} args Arguments object for the search.
-     * @param  {string} args.searchTerm The term that is used to search the
-     * database.
+     * @param  {string} args.search_term The term that is used to search the
+     * database. Leading and trailing whitespace is ignored. Words with a
+     * leading minus, e.g. <code>-standup</code>, exclude events containing
+     * them. Words with a trailing wildcard, e.g. <code>report*</code>, match
+     * all words that start with them.
      * @param  {number} args.limit The maximum number of events that the search
-     * should return.
+     * should return, at most 1000.
      * @param  {number} args.before_limit The number of events to fetch that
-     * preceded the event that matched the search term.
+     * preceded the event that matched the search term, at most 50.
      * @param  {number} args.after_limit The number of events to fetch that
-     * followed the event that matched the search term.
-     * @param  {boolean} args.order_by_recency Should the search results be
-     * ordered by event recency.
+     * followed the event that matched the search term, at most 50.
+     * @param  {Object.<string, {before: number, after: number}>}
+     * args.context_overrides Different context limits for the results of
+     * some rooms, keyed by the room ID, e.g. less context for direct
+     * messages than for busy rooms. A limit that is left out falls back to
+     * <code>before_limit</code> or <code>after_limit</code>. The limits are
+     * at most 50 and the limits of at most 100 rooms can be overridden.
+     * @param  {boolean|string} args.order_by_recency Should the search
+     * results be ordered by event recency. If set to <code>"boosted"</code>
+     * the results are ordered by relevance, with newer events preferred over
+     * older ones of similar relevance.
+     * @param  {number} args.recency_half_life_days The number of days after
+     * which the relevance of an event is halved if the results are ordered
+     * with a recency boost, defaults to 30.
      * @param  {string} args.next_batch The token to request the next page of
-     * results.
+     * results. Every page is served from the same snapshot of the index as
+     * the first one, even if events were added or deleted in the meantime.
+     * @param  {Array.<string>} args.keys The event fields that should be
+     * searched, any of <code>content.body</code>, <code>content.topic</code>
+     * and <code>content.name</code>. All fields are searched if no keys are
+     * given. Only the given fields are searched, the <code>count</code> of
+     * the result only includes events that matched in one of them, e.g.
+     * <code>["content.name"]</code> only searches the names of rooms.
+     * @param  {boolean} args.group_by_room Should the results be grouped by
+     * room, the groups are returned in the <code>groups</code> property of the
+     * result.
+     * @param  {boolean} args.group_rooms Should the results of a room follow
+     * each other, the rooms are ordered by their best result and the results
+     * of a room keep the order of the search. The order of the rooms is
+     * returned in the <code>room_order</code> property of the result and is
+     * the same for all the batches of a search. Defaults to false.
+     * @param  {boolean} args.search_senders Should the sender of events be
+     * searched as well, defaults to false.
+     * @param  {boolean} args.normalize_scores Should the ranks of the results
+     * be divided by the highest rank of the returned page, the best result of
+     * every page then has a rank of 1. Defaults to false.
+     * @param  {boolean} args.boost_by_reactions Should events that were
+     * reacted to rank higher, the rank of every result is multiplied by
+     * <code>1 + ln(1 + reactions)</code>. Results ordered by recency or by
+     * room aren't boosted. Defaults to false.
+     * @param  {boolean} args.include_timing Should the result contain the
+     * time the search took, defaults to false.
+     * @param  {boolean} args.include_context Should the context of the
+     * results be loaded. If false, the <code>events_before</code> and
+     * <code>events_after</code> of the context of every result are empty,
+     * regardless of the context limits, which makes the search considerably
+     * faster. Defaults to true.
+     * @param  {boolean} args.include_profiles Should the profiles of the
+     * senders be returned, if false the <code>profile_info</code> of the
+     * context of every result is empty. Defaults to true.
+     * @param  {boolean} args.include_redacted_stubs Should redacted messages
+     * keep their place in the context of the results. If true, a stub of the
+     * form <code>{event_id, redacted: true}</code> takes the place of every
+     * redacted message, otherwise they are left out. Defaults to false.
+     * @param  {boolean} args.require_context Should results whose event or
+     * context fails to load, e.g. because the database is corrupted, be left
+     * out instead of failing the search. Left out results are counted in the
+     * <code>skipped</code> property of the result. Results at the edge of the
+     * indexed history are still returned, see
+     * <code>context_truncated</code>. Defaults to false.
+     * @param  {number} args.timeout The number of milliseconds the search may
+     * take. A search that runs out of time returns the results it found so
+     * far and sets the <code>timed_out</code> property of the result. The
+     * search isn't limited by default.
+     * @param  {boolean} args.advanced_syntax Should the search term be parsed
+     * using the full query syntax of the index, e.g.
+     * <code>topic:meeting OR body:agenda^2</code>. The room and thread filters
+     * still apply. Defaults to false.
+     * @param  {string} args.default_operator How the words of the search term
+     * are combined, either <code>"and"</code> or <code>"or"</code>. Defaults
+     * to the <code>defaultOperator</code> of the database config.
+     * @param  {string} args.thread_id Limit the search to the replies of the
+     * thread with the given root event id.
+     * @param  {Array.<string>} args.restrict_to_events Limit the search to the
+     * events with the given ids, other events are never returned however well
+     * they match. At most <code>maxRestrictedIds</code> ids may be given.
+     * @param  {Array.<string>} args.not_senders Exclude the events of the
+     * senders with the given user IDs, e.g. the local user to only find what
+     * others wrote. The count and the pagination of the results take the
+     * exclusion into account.
+     * @param  {boolean} args.contains_url Only return message events that do,
+     * or if false don't, contain an <code>http://</code> or
+     * <code>https://</code> link. If true for a search of a single room that
+     * is ordered by recency, the search term may be empty to list the latest
+     * links of the room.
+     * @param  {number} args.after_timestamp Only return events that were sent
+     * at or after the given timestamp, in milliseconds since the Unix epoch.
+     * The count and the pagination of the results take the limit into
+     * account.
+     * @param  {string} args.after_watermark Only return events that were sent
+     * at or after the timestamp of the watermark with the given name, see
+     * <code>setWatermark()</code>. The watermark is looked up when the search
+     * starts, if <code>after_timestamp</code> is given as well the later of
+     * the two is used. The search is rejected with a <code>TypeError</code>
+     * whose code is <code>NOT_FOUND</code> if the watermark doesn't exist.
      *
      * @return {Promise<searchResult>} The array of events that matched
-     * the search term.
+     * the search term. The promise is rejected with a <code>TypeError</code>
+     * if the search term doesn't contain any words and doesn't list links, if
+     * the <code>next_batch</code> token is unknown or has expired or if the
+     * search term exceeds the configured query limits, in which case the
+     * message starts with "The search query is too long", or if the search is
+     * restricted to too many events. A search term
+     * using the advanced syntax that can't be parsed is rejected with a
+     * message starting with "The search query is invalid", as are search terms
+     * that only contain excluded words or leading wildcards that aren't
+     * enabled.
      */
     async search(args) {
-        const search = promisify(seshatNative.search);
-        return search(this.inner, args);
+        return seshatNative.search(this.inner, args);
+    }
+
+    /**
+     * Run multiple searches at once.
+     *
+     * All the searches are run against the same snapshot of the database,
+     * their results are consistent with each other.
+     *
+     * @param  {Array.<object>} queries The arguments objects of the searches,
+     * as for the <code>search()</code> method.
+     *
+     * @return {Promise<Array.<searchResult|TypeError>>} The results of the
+     * searches in the order of the queries. A search that fails, e.g.
+     * because its arguments are invalid, has a <code>TypeError</code> in
+     * place of its result, the other searches aren't affected by it.
+     */
+    async searchBatch(queries) {
+        return seshatNative.searchBatch(this.inner, queries);
+    }
+
+    /**
+     * Start a search that can be cancelled.
+     *
+     * This is useful for searches that are started on every keystroke, only
+     * the latest search is of interest and the previous ones can be
+     * cancelled to free up the threads they are using.
+     *
+     * @param  {object} args Arguments object for the search, as for the
+     * <code>search()</code> method.
+     *
+     * @return {SearchHandle} The handle of the search, its
+     * <code>result</code> property is a promise that resolves to the
+     * <code>searchResult</code>. The promise of a cancelled search is
+     * rejected with an error whose <code>kind</code> property is
+     * <code>"SearchCancelled"</code>.
+     */
+    searchAsync(args) {
+        const cancellation = seshatNative.createSearchCancellation();
+        const result = seshatNative.search(this.inner, args, cancellation);
+        return new SearchHandle(cancellation, result);
     }
 
     /**
      * Search the database for events using the given search term.
      *
+     * The search arguments can either be passed as a single arguments object,
+     * as for the <code>search()</code> method, or positionally.
+     *
      * @param  {string} term The term that is used to search the database.
      * @param  {number} limit The maximum number of events that the search
-     * should return.
+     * should return, at most 1000.
      * @param  {number} before_limit The number of events to fetch that
-     * preceded the event that matched the search term.
+     * preceded the event that matched the search term, at most 50.
      * @param  {number} after_limit The number of events to fetch that followed
-     * the event that matched the search term.
+     * the event that matched the search term, at most 50.
      * @param  {boolean} order_by_recency Should the search results be ordered
      * by event recency.
      *
      * @return {searchResult} The array of events that matched the
      * search term.
+     *
+     * @throws {TypeError} If the search term doesn't contain any words.
      */
     searchSync(term, limit = 10, before_limit = 0, after_limit = 0,
         order_by_recency = false) {
-        return seshatNative.searchSync(this.inner, term, limit, before_limit,
-            after_limit, order_by_recency);
+        const args = typeof term === 'object' ? term : {
+            search_term: term,
+            limit: limit,
+            before_limit: before_limit,
+            after_limit: after_limit,
+            order_by_recency: order_by_recency,
+        };
+
+        return seshatNative.searchSync(this.inner, args);
+    }
+
+    /**
+     * Check if a search term is going to be accepted by a search.
+     *
+     * Only the term is parsed, the database isn't searched, which makes this
+     * cheap enough to be called on every keystroke of the search box.
+     *
+     * @param  {string} term The search term that should be checked.
+     * @param  {boolean} advanced Does the term use the advanced query syntax,
+     * as for the <code>advanced_syntax</code> argument of
+     * <code>search()</code>. Defaults to false.
+     *
+     * @return {string|null} Null if the term is valid, otherwise the message
+     * of the error the search would be rejected with. Syntax errors of
+     * advanced terms end with the position of an unclosed quote or
+     * parenthesis if the term contains one.
+     */
+    validateQuery(term, advanced = false) {
+        return seshatNative.validateQuery(this.inner, term, advanced);
     }
 
     /**
@@ -314,40 +1147,65 @@ class Seshat {
      * added to the database.
      * @param  {checkpoint} newCheckpoint
      * @param  {checkpoint} oldCheckPoint
+     * @param  {object} options The options for adding the events, as for the
+     * <code>addHistoricEvents()</code> method.
      *
-     * @return {boolean} True if the added events were already in the store,
-     * false otherwise.
+     * @return {boolean|backlogResult} False if the added events were already
+     * in the store, true if any of them is new. A
+     * <code>backlogResult</code> if the <code>allowPartial</code> option is
+     * set.
      */
-    addHistoricEventsSync(events, newCheckpoint = null, oldCheckPoint = null) {
+    addHistoricEventsSync(events, newCheckpoint = null, oldCheckPoint = null,
+        options = {}) {
         return seshatNative.addHistoricEventsSync(this.inner, events,
-            newCheckpoint, oldCheckPoint);
+            newCheckpoint, oldCheckPoint, options);
     }
 
     /**
      * Add a batch of events from the room history to the database.
      *
+     * Every element of the events array is an object of the form
+     * <code>{event, profile, source}</code>, the profile is optional. The
+     * source is the event serialized as a JSON string, it's optional as well
+     * but passing it in avoids serializing large batches of events again.
+     *
+     * By default a single invalid event rejects the whole batch and nothing
+     * is stored. If the <code>allowPartial</code> option is set, invalid
+     * events are skipped instead, the valid ones and the checkpoints are
+     * stored and the promise resolves to a <code>backlogResult</code>
+     * listing the skipped events, a crawler can log those and move on.
+     *
      * @param  {array<matrixEvent>} events An array of events that will be
      * added to the database.
      * @param  {checkpoint} newCheckpoint
      * @param  {checkpoint} oldCheckPoint
+     * @param  {object} options The options for adding the events.
+     * @param  {boolean} options.allowPartial Should invalid events be skipped
+     * instead of rejecting the whole batch, defaults to false.
      *
-     * @return {Promise<boolean>} A promise that will resolve to true if all
-     * the events have already been added to the database, false otherwise.
+     * @return {Promise<boolean|backlogResult>} A promise that will resolve to
+     * false if all the events have already been added to the database, true
+     * otherwise. A crawler can stop paginating once a chunk only contains
+     * known events. If the <code>allowPartial</code> option is set the
+     * promise resolves to a <code>backlogResult</code> instead.
      */
-    async addHistoricEvents(events, newCheckpoint = null, oldCheckPoint = null) {
-        const addHistoricEvents = promisify(seshatNative.addHistoricEvents);
-
-        return addHistoricEvents(
+    async addHistoricEvents(events, newCheckpoint = null, oldCheckPoint = null,
+        options = {}) {
+        return seshatNative.addHistoricEvents(
             this.inner,
             events,
             newCheckpoint,
             oldCheckPoint,
+            options,
         );
     }
 
     /**
      * Add a message crawler checkpoint.
      *
+     * The checkpoint is written using the same queue as historic events.
+     * Adding a checkpoint that already exists is a no-op.
+     *
      * @param  {checkpoint} checkpoint
      *
      * @return {Promise} A promise that will resolve when the checkpoint has
@@ -359,6 +1217,9 @@ class Seshat {
 
     /**
      * Remove a message crawler checkpoint.
+     *
+     * Removing a checkpoint that doesn't exist is a no-op.
+     *
      * @param  {checkpoint} checkpoint
      *
      * @return {Promise} A promise that will resolve when the checkpoint has
@@ -376,20 +1237,98 @@ class Seshat {
      * array of checkpoints when they are loaded from the database.
      */
     async loadCheckpoints() {
-        const loadCheckpoints = promisify(seshatNative.loadCheckpoints);
-        return loadCheckpoints(this.inner);
+        return seshatNative.loadCheckpoints(this.inner);
+    }
+
+    /**
+     * Check if the database is still able to write events.
+     *
+     * The writer of the database stops if it panics or if writes keep
+     * failing, e.g. because the disk is full. Events that are added after
+     * that aren't written, the database needs to be reopened.
+     *
+     * @return {Promise} A promise that will resolve if the writer is running
+     * and reject with the error that stopped it otherwise.
+     */
+    async getStatus() {
+        return seshatNative.getStatus(this.inner);
     }
 
     /**
      * Get the size of the database.
-     * This returns the number of bytes the database is using on disk.
+     * This returns the number of bytes the database is using on disk. Only
+     * the files of the live index are counted, files of merged segments that
+     * weren't cleaned up yet are ignored.
      *
      * @return {Promise<number>} A promise that will resolve to the database
      * size in bytes.
      */
     async getSize() {
-        const getSize = promisify(seshatNative.getSize);
-        return getSize(this.inner);
+        return seshatNative.getSize(this.inner);
+    }
+
+    /**
+     * Get the size of the database split up into the size of the search
+     * index and the size of the event store.
+     *
+     * @return {Promise<databaseSize>} A promise that will resolve to the
+     * sizes in bytes.
+     */
+    async getSizeBreakdown() {
+        return seshatNative.getSize(this.inner, true);
+    }
+
+    /**
+     * Get the versions of the library and of the schemas of the database.
+     *
+     * @return {Promise<versionInfo>} A promise that will resolve to an object
+     * containing the version information.
+     */
+    async getVersionInfo() {
+        return seshatNative.getVersionInfo(this.inner);
+    }
+
+    /**
+     * Get the operational counters of the database.
+     *
+     * The counters count the operations since the database was opened.
+     *
+     * @return {Promise<databaseMetrics>} A promise that will resolve to an
+     * object containing the counters.
+     */
+    async getMetrics() {
+        return seshatNative.getMetrics(this.inner);
+    }
+
+    /**
+     * Get the sequence numbers of the last queued and of the last committed
+     * add of events.
+     *
+     * Apps that keep their own queue of the events they added can drop the
+     * events up to the committed sequence number, this covers the commits
+     * that happen automatically as well. The sequence numbers start again at
+     * 0 every time the database is opened.
+     *
+     * @return {Promise<opstamps>} A promise that will resolve to an object
+     * containing both sequence numbers.
+     */
+    async getOpstamps() {
+        return seshatNative.getOpstamps(this.inner);
+    }
+
+    /**
+     * Get the errors that happened in the background since the last call.
+     *
+     * These are errors of automatic commits, compactions and retention
+     * purges, and errors of operations whose result nobody waited for.
+     * Errors are returned oldest first and only once, the app should poll
+     * this e.g. after a commit.
+     *
+     * @return {Promise<Array.<writerError>>} A promise that will resolve to
+     * the list of errors.
+     */
+    async getPendingErrors() {
+        return seshatNative.getPendingErrors(this.inner);
     }
 
     /**
@@ -399,46 +1338,70 @@ class Seshat {
      * containing statistical information of the database.
      */
     async getStats() {
-        const getStats = promisify(seshatNative.getStats);
-        return getStats(this.inner);
+        return seshatNative.getStats(this.inner);
     }
 
     /**
      * Delete the Seshat database.
      *
+     * The directories of the index and of the store are deleted as well if
+     * the database config put them outside of the database directory.
+     *
+     * Operations that are already running are given up to ten seconds to
+     * finish before the database is closed. Operations that were started but
+     * didn't run yet are rejected with a <code>DATABASE_CLOSED</code> error.
+     *
      * @return {Promise} A promise that will resolve when the database has
      * been deleted.
      */
     async delete() {
-        const deleteDb = promisify(seshatNative.deleteDb);
-        return deleteDb(this.inner);
+        return seshatNative.deleteDb(this.inner);
+    }
+
+    /**
+     * Create a backup of the database.
+     *
+     * The queued up events are committed and the database and the index are
+     * copied into a single tar archive. Events that are added while the backup
+     * is being created are written once the backup is done.
+     *
+     * @param  {string} path The path of the archive that should be created.
+     *
+     * @return {Promise} A promise that will resolve when the backup has been
+     * written, or reject if the backup couldn't be created.
+     */
+    async backup(path) {
+        return seshatNative.backup(this.inner, path);
     }
 
     /**
      * Shutdown and close the Seshat database.
      *
+     * Operations that are already running are given up to ten seconds to
+     * finish, see <code>delete()</code>.
+     *
      * @return {Promise} A promise that will resolve when the database has
      * been closed.
      */
     async shutdown() {
-        const shutdown = promisify(seshatNative.shutdown);
-        return shutdown(this.inner);
+        return seshatNative.shutdown(this.inner);
     }
 
     /**
      * Change the passphrase of the database
      *
-     * This will also close the database, just like shutdown does.
+     * The database is reopened using the new passphrase and can be used right
+     * after the returned promise resolves.
      *
      * @param  {string} newPassphrase The new passphrase that should from now on
      * be used to encrypt the database.
      *
      * @return {Promise} A promise that will resolve when the passphrase has
-     * been changed.
+     * been changed, or reject if the passphrase couldn't be changed, e.g.
+     * because the database isn't encrypted.
      */
     async changePassphrase(newPassphrase) {
-        const changePassphrase = promisify(seshatNative.changePassphrase);
-        return changePassphrase(this.inner, newPassphrase);
+        return seshatNative.changePassphrase(this.inner, newPassphrase);
     }
 
     /**
@@ -449,8 +1412,135 @@ class Seshat {
      * otherwise.
      */
     async isEmpty() {
-        const isEmpty = promisify(seshatNative.isEmpty);
-        return isEmpty(this.inner);
+        return seshatNative.isEmpty(this.inner);
+    }
+
+    /**
+     * Get the events out of the given ones that are already stored in the
+     * database.
+     *
+     * A crawler can use this to find out that a chunk of the room history
+     * overlaps with events that were already added, e.g. by the live event
+     * stream, and stop crawling early.
+     *
+     * @param  {Array.<string>} eventIds The IDs of the events that should be
+     * checked, duplicates are allowed.
+     *
+     * @return {Promise<Array.<string>>} A promise that will resolve to the
+     * IDs of the events that are stored in the database, in no particular
+     * order.
+     */
+    async filterKnownEvents(eventIds) {
+        if (eventIds.length === 0) {
+            return [];
+        }
+
+        return seshatNative.filterKnownEvents(this.inner, eventIds);
+    }
+
+    /**
+     * Get the timestamp of the newest event that is stored for each of the
+     * given rooms.
+     *
+     * A crawler can compare these with the timestamps of the live timeline
+     * to find out how far behind the index is.
+     *
+     * @param  {Array.<string>} roomIds The IDs of the rooms that should be
+     * checked.
+     *
+     * @return {Promise<Object.<string, ?number>>} A promise that will resolve
+     * to an object mapping every given room ID to the origin server timestamp
+     * of the newest event of the room, or null if the room has no events.
+     */
+    async getNewestEventTimestamps(roomIds) {
+        if (roomIds.length === 0) {
+            return {};
+        }
+
+        return seshatNative.getNewestEventTimestamps(this.inner, roomIds);
+    }
+
+    /**
+     * Get the names that are displayed for the given rooms.
+     *
+     * The name of a room is taken from the newest <code>m.room.name</code>
+     * event that was added, the canonical alias of the room is used if the
+     * room doesn't have a name.
+     *
+     * @param  {Array.<string>} roomIds The IDs of the rooms that should be
+     * looked up.
+     *
+     * @return {Promise<Object.<string, ?string>>} A promise that will resolve
+     * to an object mapping every given room ID to the name of the room, or
+     * null if the room has neither a name nor an alias.
+     */
+    async getRoomNames(roomIds) {
+        if (roomIds.length === 0) {
+            return {};
+        }
+
+        return seshatNative.getRoomNames(this.inner, roomIds);
+    }
+
+    /**
+     * Get the last known profiles of the given senders.
+     *
+     * The profile of a sender is the one they had when they sent their most
+     * recent stored event, this is available for users that already left the
+     * room as well.
+     *
+     * @param  {Array.<string>} senders The user IDs of the senders that
+     * should be looked up.
+     *
+     * @return {Promise<Object.<string, ?matrixProfile>>} A promise that will
+     * resolve to an object mapping every given sender to their profile, or
+     * null if the database doesn't contain any event of the sender.
+     */
+    async getProfiles(senders) {
+        if (senders.length === 0) {
+            return {};
+        }
+
+        return seshatNative.getProfiles(this.inner, senders);
+    }
+
+    /**
+     * Get the number of reactions of the given events.
+     *
+     * Reactions count as soon as they are added, they don't need to be
+     * committed.
+     *
+     * @param  {Array.<string>} eventIds The IDs of the events that should be
+     * looked up.
+     *
+     * @return {Promise<Object.<string, number>>} A promise that will resolve
+     * to an object mapping every given event ID to the number of its
+     * reactions.
+     */
+    async getReactionCounts(eventIds) {
+        if (eventIds.length === 0) {
+            return {};
+        }
+
+        return seshatNative.getReactionCounts(this.inner, eventIds);
+    }
+
+    /**
+     * Check if an event was stored and committed to the index.
+     *
+     * <code>addEvent()</code> only queues the event up, this allows callers
+     * to check that an event wasn't dropped once the commit that should
+     * contain it is done, e.g. once <code>commit()</code> returned a number
+     * that is equal or larger than the sequence number of the add. Unlike a
+     * search this doesn't need the index to be reloaded.
+     *
+     * @param  {string} eventId The ID of the event that should be checked.
+     *
+     * @return {Promise<boolean>} A promise that will resolve to true if the
+     * event was committed, false otherwise.
+     */
+    async wasEventIndexed(eventId) {
+        return seshatNative.wasEventIndexed(this.inner, eventId);
     }
 
     /**
@@ -463,19 +1553,162 @@ class Seshat {
      * database contains events for the given room, false otherwise.
      */
     async isRoomIndexed(roomId) {
-        const isRoomIndexed = promisify(seshatNative.isRoomIndexed);
-        return isRoomIndexed(this.inner, roomId);
+        return seshatNative.isRoomIndexed(this.inner, roomId);
+    }
+
+    /**
+     * Get statistical information about the events of a room.
+     *
+     * @param  {string} roomId The ID of the room.
+     *
+     * @return {Promise<roomInfo>} A promise that will resolve to an object
+     * containing the number of stored events of the room and the time range
+     * they cover.
+     */
+    async getRoomInfo(roomId) {
+        return seshatNative.getRoomInfo(this.inner, roomId);
+    }
+
+    /**
+     * Get statistical information about the events of every room.
+     *
+     * Rooms that don't have any events stored in the database aren't part of
+     * the result.
+     *
+     * @return {Promise<Array.<roomInfo>>} A promise that will resolve to an
+     * array containing an object for every room.
+     */
+    async getRoomInfos() {
+        return seshatNative.getRoomInfos(this.inner);
+    }
+
+    /**
+     * Get the most recently used search terms.
+     *
+     * Search terms are only recorded if the <code>recordSearches</code>
+     * option was enabled when the database was opened, at most 100 terms are
+     * kept.
+     *
+     * @param  {number} limit The maximal number of search terms that should
+     * be returned, defaults to 10.
+     *
+     * @return {Promise<Array.<string>>} A promise that will resolve to an
+     * array of search terms, the most recently used one first.
+     */
+    async getSearchHistory(limit = 10) {
+        return seshatNative.getSearchHistory(this.inner, limit);
+    }
+
+    /**
+     * Remove all the search terms from the search history.
+     *
+     * @return {Promise<void>} A promise that will resolve once the search
+     * history has been cleared.
+     */
+    async clearSearchHistory() {
+        return seshatNative.clearSearchHistory(this.inner);
+    }
+
+    /**
+     * Get an event together with the events surrounding it in its room.
+     *
+     * The context is loaded the same way as the context of search results,
+     * without running a search.
+     *
+     * @param  {string} eventId The ID of the event.
+     * @param  {number} beforeLimit The number of events to fetch that
+     * preceded the event, at most 50.
+     * @param  {number} afterLimit The number of events to fetch that followed
+     * the event, at most 50.
+     *
+     * @return {Promise<?eventWithContext>} A promise that will resolve to the
+     * event and its context, or to null if the event isn't part of the
+     * database.
+     */
+    async getContext(eventId, beforeLimit = 0, afterLimit = 0) {
+        return seshatNative.getContext(this.inner, eventId, beforeLimit, afterLimit);
+    }
+
+    /**
+     * Get the rooms that have their indexing disabled.
+     *
+     * @return {Promise<Array.<string>>} A promise that will resolve to an
+     * array containing the IDs of the rooms.
+     */
+    async getUnindexedRooms() {
+        return seshatNative.getUnindexedRooms(this.inner);
+    }
+
+    /**
+     * Mark a room as fully crawled or not.
+     *
+     * A room should be marked as fully crawled once its whole history has
+     * been fetched and its last crawler checkpoint was removed. The mark is
+     * removed automatically if a new backwards checkpoint is added for the
+     * room.
+     *
+     * @param  {string} roomId The ID of the room.
+     * @param  {boolean} fullyCrawled Has the whole history of the room been
+     * indexed.
+     *
+     * @return {Promise<void>} A promise that will resolve once the new state
+     * has been stored in the database.
+     */
+    async setRoomFullyCrawled(roomId, fullyCrawled) {
+        return seshatNative.setRoomFullyCrawled(this.inner, roomId, fullyCrawled);
+    }
+
+    /**
+     * Get the rooms that are marked as fully crawled.
+     *
+     * @return {Promise<Array.<string>>} A promise that will resolve to an
+     * array containing the IDs of the rooms.
+     */
+    async getFullyCrawledRooms() {
+        return seshatNative.getFullyCrawledRooms(this.inner);
+    }
+
+    /**
+     * Get the rooms that the database contains events for.
+     *
+     * This can be used to scope searches to a room or to find rooms that the
+     * user has left since they were indexed.
+     *
+     * @param  {boolean} includeCheckpointOnly Should rooms that only have a
+     * crawler checkpoint but no events yet be included, defaults to false.
+     *
+     * @return {Promise<Array.<string>>} A promise that will resolve to a
+     * sorted array containing the IDs of the rooms.
+     */
+    async getIndexedRooms(includeCheckpointOnly = false) {
+        return seshatNative.getIndexedRooms(this.inner, includeCheckpointOnly);
+    }
+
+    /**
+     * Get the rooms that have events but no crawler checkpoint.
+     *
+     * These are rooms whose crawl either finished or lost its checkpoint, e.g.
+     * because the application crashed in the middle of a crawl. Rooms that
+     * have their indexing disabled aren't returned.
+     *
+     * @return {Promise<Array.<string>>} A promise that will resolve to an
+     * array containing the IDs of the rooms.
+     */
+    async getUncrawledRooms() {
+        return seshatNative.getUncrawledRooms(this.inner);
     }
 
     /**
      * Get the custom user specific version from the database.
      *
+     * The version is stored alongside the index, it isn't used by Seshat
+     * itself and is 0 for a fresh database.
+     *
      * @return {Promise<number>} A promise that will resolve to a number that
      * represents the user version of the database.
      */
     async getUserVersion() {
-        const getUserVersion = promisify(seshatNative.getUserVersion);
-        return getUserVersion(this.inner);
+        return seshatNative.getUserVersion(this.inner);
     }
 
     /**
@@ -488,8 +1721,38 @@ class Seshat {
      * has been stored in the database.
      */
     async setUserVersion(version) {
-        const setUserVersion = promisify(seshatNative.setUserVersion);
-        return setUserVersion(this.inner, version);
+        return seshatNative.setUserVersion(this.inner, version);
+    }
+
+    /**
+     * Set a named watermark to the given timestamp.
+     *
+     * Watermarks mark points in time, e.g. the time the user verified their
+     * session, searches can be limited to the events after a watermark using
+     * the <code>after_watermark</code> argument of <code>search()</code>.
+     * Setting an existing watermark moves it.
+     *
+     * @param  {string} name The name of the watermark.
+     * @param  {number} timestamp The timestamp, in milliseconds since the
+     * Unix epoch, the watermark should be set to.
+     *
+     * @return {Promise<void>} A promise that will resolve once the watermark
+     * has been stored in the database.
+     */
+    async setWatermark(name, timestamp) {
+        return seshatNative.setWatermark(this.inner, name, timestamp);
+    }
+
+    /**
+     * Get the timestamp of a named watermark.
+     *
+     * @param  {string} name The name of the watermark.
+     *
+     * @return {Promise<?number>} A promise that will resolve to the timestamp
+     * of the watermark, or null if the watermark was never set.
+     */
+    async getWatermark(name) {
+        return seshatNative.getWatermark(this.inner, name);
     }
 
     /**
@@ -498,7 +1761,8 @@ class Seshat {
      * @param  {object} args Arguments object for the method.
      * @param  {string} args.roomId The ID of the room for which the events
      * should be loaded.
-     * @param  {number} args.limit The maximum number of events to return.
+     * @param  {number} args.limit The maximum number of events to return,
+     * defaults to 10 and is capped at 100.
      * @param  {string} args.fromEvent An event id of a previous event returned
      * by this method. If set events that are older than the event with the
      * given event ID will be returned.
@@ -507,11 +1771,196 @@ class Seshat {
      * "f".
      *
      * @return {Promise<[loadResult]>} A promise that will resolve to an array
-     * of Matrix events that contain mxc URLs.
+     * of Matrix events that contain mxc URLs, together with the profile of
+     * their sender. Rejects with a TypeError if the roomId is missing or any
+     * of the arguments is invalid.
      */
     async loadFileEvents(args) {
-        const loadFileEvents = promisify(seshatNative.loadFileEvents);
-        return loadFileEvents(this.inner, args);
+        return seshatNative.loadFileEvents(this.inner, args);
+    }
+
+    /**
+     * Load message events that contain an <code>http://</code> or
+     * <code>https://</code> link.
+     *
+     * @param  {object} args Arguments object for the method.
+     * @param  {string} args.roomId The ID of the room for which the events
+     * should be loaded.
+     * @param  {number} args.limit The maximum number of events to return,
+     * defaults to 10 and is capped at 100.
+     * @param  {string} args.fromEvent An event id of a previous event returned
+     * by this method. If set events that are older than the event with the
+     * given event ID will be returned.
+     * @param {string} args.direction The direction that we are going to
+     * continue lading events to. Can be either "backwards", "b", "forwards" or
+     * "f".
+     *
+     * @return {Promise<[loadResult]>} A promise that will resolve to an array
+     * of Matrix events that contain links, together with the profile of their
+     * sender. Rejects with a TypeError if the roomId is missing or any of the
+     * arguments is invalid.
+     */
+    async loadUrlEvents(args) {
+        return seshatNative.loadUrlEvents(this.inner, args);
+    }
+
+    /**
+     * Import events from a room export file.
+     *
+     * The file can either contain a JSON array of Matrix events or an object
+     * with a <code>room_id</code> and the events in a <code>messages</code>
+     * array. Events that can't be searched are skipped, events that are
+     * already in the database are counted as duplicates.
+     *
+     * @param  {string} filePath The path of the room export.
+     *
+     * @return {Promise<object>} A promise that will resolve to an object
+     * containing the number of <code>imported</code>, <code>skipped</code> and
+     * <code>duplicates</code> events.
+     */
+    async importEvents(filePath) {
+        return seshatNative.importEvents(this.inner, filePath);
+    }
+
+    /**
+     * Export all the events of the database into a file.
+     *
+     * The file uses the JSON Lines format, every line contains an object with
+     * the <code>room_id</code>, the <code>event</code> itself, the
+     * <code>profile</code> of the sender and whether the event has already
+     * been <code>indexed</code>. Events whose source couldn't be read contain
+     * an <code>error</code> and the <code>event_id</code> instead.
+     *
+     * @param  {string} filePath The path of the file the events should be
+     * written to.
+     *
+     * @return {Promise<number>} A promise that will resolve to the number of
+     * exported events.
+     */
+    async exportEvents(filePath) {
+        return seshatNative.exportEvents(this.inner, filePath);
+    }
+
+    /**
+     * Export all the events that match a search into a file.
+     *
+     * The file uses the JSON Lines format, every line contains a search
+     * result of the same form as the results of <code>search()</code>, with
+     * the <code>rank</code>, the event as the <code>result</code>, its
+     * <code>context</code> and the <code>highlights</code> and
+     * <code>matched_fields</code> of the result. The search results are
+     * written out in batches, memory usage doesn't grow with the number of
+     * matching events.
+     *
+     * @param  {object} args Arguments object for the search, as for the
+     * <code>search()</code> method. The <code>limit</code>, the
+     * <code>timeout</code> and the grouping of the results are ignored, every
+     * matching event is exported.
+     * @param  {string} filePath The path of the file the search results
+     * should be written to.
+     * @param  {function(number)} progress An optional function that is called
+     * with the number of written search results after every batch of 100
+     * results.
+     *
+     * @return {Promise<exportSummary>} A promise that will resolve to a
+     * summary of the export once it is done.
+     */
+    async exportSearch(args, filePath, progress = null) {
+        return seshatNative.exportSearch(this.inner, args, progress, filePath);
+    }
+
+    /**
+     * Start importing events from the room history in chunks.
+     *
+     * This is meant for large imports, e.g. the initial crawl of a room,
+     * where the events shouldn't be held in memory all at once.
+     *
+     * @return {BulkImport} A handle that is used to add the chunks of events
+     * and to finish the import.
+     */
+    startBulkImport() {
+        return new BulkImport(seshatNative.startBulkImport(this.inner));
+    }
+}
+
+/**
+ * A search that is in progress.<br>
+ *
+ * A search handle is returned by the <code>searchAsync()</code> method of the
+ * Seshat class.
+ */
+class SearchHandle {
+    /**
+     * Create a new search handle.
+     *
+     * @param {object} cancellation The native cancellation handle.
+     * @param {Promise<searchResult>} result The promise of the search result.
+     */
+    constructor(cancellation, result) {
+        this.cancellation = cancellation;
+        this.result = result;
+    }
+
+    /**
+     * Cancel the search.
+     *
+     * The search stops at the next opportunity and its result is rejected.
+     * Cancelling a search that already finished does nothing.
+     */
+    cancel() {
+        seshatNative.cancelSearch(this.cancellation);
+    }
+}
+
+/**
+ * A bulk import of events from the room history.<br>
+ *
+ * Every chunk of events is written to the database as soon as it is added,
+ * the crawler checkpoints are only replaced once the import is finished. If
+ * the import is abandoned without finishing it the old checkpoint stays in
+ * the database.
+ *
+ * A bulk import is started using the <code>startBulkImport()</code> method
+ * of the Seshat class.
+ */
+class BulkImport {
+    /**
+     * Create a new bulk import.
+     *
+     * @param {object} inner The native bulk import handle.
+     */
+    constructor(inner) {
+        this.inner = inner;
+    }
+
+    /**
+     * Add a chunk of events to the database.
+     *
+     * @param  {array<matrixEvent>} events An array of events of the same
+     * form as for the <code>addHistoricEvents()</code> method.
+     *
+     * @return {Promise<boolean>} A promise that will resolve to false if all
+     * the events have already been added to the database, true otherwise. A
+     * crawler can stop paginating once a chunk only contains known events.
+     */
+    async addChunk(events) {
+        return seshatNative.bulkImportAddChunk(this.inner, events);
+    }
+
+    /**
+     * Finish the import, replacing the old checkpoint with the new one.
+     *
+     * No more chunks can be added once the import is finished.
+     *
+     * @param  {checkpoint} newCheckpoint
+     * @param  {checkpoint} oldCheckPoint
+     *
+     * @return {Promise} A promise that will resolve once the checkpoints
+     * have been replaced.
+     */
+    async finish(newCheckpoint = null, oldCheckPoint = null) {
+        return seshatNative.bulkImportFinish(this.inner, newCheckpoint,
+            oldCheckPoint);
     }
 }
 
@@ -532,9 +1981,16 @@ class Seshat {
  * database already exist in the given folder the database will be reused.
  * @param  {string} config.language The language that the database should use
  * for indexing. Picking the correct indexing language may improve the search.
+ * @param  {string} config.indexPath The directory of the search index, if
+ * the database was opened with a separate one.
+ * @param  {string} config.storePath The directory of the SQLite store, if the
+ * database was opened with a separate one.
  * @param  {string} config.passphrase The passphrase that should be used to
  * encrypt the database. The database is left unencrypted it no passphrase is
  * set.
+ * @param  {boolean} config.wal Should the database use write-ahead logging.
+ * @param  {number} config.busyTimeout How long, in milliseconds, should the
+ * database wait for a lock held by another connection.
  *
  * @constructor
  *
@@ -567,8 +2023,7 @@ class SeshatRecovery {
      * represents the user version of the database.
      */
     async getUserVersion() {
-        const getUserVersion = promisify(seshatNative.getUserVersionRecoveryDb);
-        return getUserVersion(this.inner);
+        return seshatNative.getUserVersionRecoveryDb(this.inner);
     }
 
     /**
@@ -578,8 +2033,7 @@ class SeshatRecovery {
      * been closed.
      */
     async shutdown() {
-        const shutdown = promisify(seshatNative.shutdownRecoveryDb);
-        return shutdown(this.inner);
+        return seshatNative.shutdownRecoveryDb(this.inner);
     }
 
     /**
@@ -589,13 +2043,255 @@ class SeshatRecovery {
      * been re-indexed.
      */
     async reindex() {
-        const reindex = promisify(seshatNative.reindexRecoveryDb);
-        return reindex(this.inner);
+        return seshatNative.reindexRecoveryDb(this.inner);
+    }
+}
+
+// Every database needs a connection for itself, one for its writer and one
+// to hand out to readers.
+const MIN_CONNECTIONS_PER_DATABASE = 3;
+const MIN_WRITER_HEAP_SIZE = 3000000;
+const DEFAULT_CONNECTION_POOL_SIZE = 10;
+const DEFAULT_WRITER_HEAP_SIZE = 50000000;
+
+/**
+ * Get a config value, or the fallback if the value isn't set.
+ *
+ * @param  {*} value The config value.
+ * @param  {*} fallback The value to use if the config value isn't set.
+ *
+ * @return {*} The value or the fallback.
+ */
+function valueOr(value, fallback) {
+    return value === undefined || value === null ? fallback : value;
+}
+
+/**
+ * Turn an account id into a directory name, characters that aren't allowed
+ * in file names on every platform are percent-encoded.
+ *
+ * @param  {string} accountId The id of the account.
+ *
+ * @return {string} The name of the directory of the account.
+ */
+function encodeAccountId(accountId) {
+    return Array.from(Buffer.from(accountId, 'utf8')).map((byte) => {
+        const c = String.fromCharCode(byte);
+        return /[A-Za-z0-9\-_.]/.test(c) ? c :
+            '%' + byte.toString(16).toUpperCase().padStart(2, '0');
+    }).join('');
+}
+
+/**
+ * Turn a directory name back into an account id.
+ *
+ * @param  {string} name The name of the directory of an account.
+ *
+ * @return {string|null} The id of the account, null if the directory doesn't
+ * belong to an account.
+ */
+function decodeAccountId(name) {
+    if (!/^([A-Za-z0-9\-_.]|%[0-9A-Fa-f]{2})+$/.test(name)) {
+        return null;
+    }
+
+    try {
+        return decodeURIComponent(name);
+    } catch (e) {
+        return null;
+    }
+}
+
+/**
+ * Manager for the Seshat databases of several accounts.<br>
+ *
+ * Every account gets its own database, with a separate index and store, in a
+ * directory below the root directory of the manager. The databases share a
+ * budget of database connections and of index writer memory, every database
+ * that is opened gets at most what's left of the budgets.
+ *
+ * Each database still commits on its own writer thread, the manager only
+ * makes sure that the threads and connection pools stay small if many
+ * accounts are open at once.
+ *
+ * @example
+ * let manager = new SeshatManager("/home/example/seshat");
+ * let db = manager.open("@alice:example.org", {passphrase: "secret"});
+ * await manager.close("@alice:example.org");
+ */
+class SeshatManager {
+    /**
+     * Create a new manager for the databases in the given root directory.
+     *
+     * @param {string} root The directory the databases of the accounts are
+     * stored in, it is created if it doesn't exist.
+     * @param {object} config Additional configuration for the manager.
+     * @param {number} config.connectionBudget The number of database
+     * connections all the open databases may use together, defaults to 30.
+     * Every database gets at least three connections.
+     * @param {number} config.writerHeapBudget The amount of memory, in bytes,
+     * the index writers of all the open databases may use together, defaults
+     * to 100MB. Every database gets at least 3MB.
+     */
+    constructor(root, config = undefined) {
+        config = config || {};
+        fs.mkdirSync(root, {recursive: true});
+
+        this.root = root;
+        this.connectionBudget = valueOr(config.connectionBudget, 30);
+        this.writerHeapBudget = valueOr(config.writerHeapBudget, 100000000);
+        this.databases = new Map();
+    }
+
+    /**
+     * Open the database of an account, creating it if it doesn't exist.
+     *
+     * The <code>connectionPoolSize</code> and the <code>writerHeapSize</code>
+     * of the config are lowered to what's left of the budgets of the manager.
+     * If the database of the account is already open it is returned as is.
+     *
+     * @param  {string} accountId The id of the account, e.g. its Matrix user
+     * id.
+     * @param  {object} config The configuration of the database, see the
+     * constructor of the <code>Seshat</code> class.
+     *
+     * @throws {TypeError} If the account id is empty.
+     *
+     * @return {Seshat} The database of the account.
+     */
+    open(accountId, config = undefined) {
+        if (typeof accountId !== 'string' || accountId.length === 0) {
+            throw new TypeError('The account id must be a non-empty string');
+        }
+
+        const open = this.databases.get(accountId);
+
+        if (open) {
+            return open.database;
+        }
+
+        config = Object.assign({}, config);
+
+        let usedConnections = 0;
+        let usedHeap = 0;
+
+        for (const d of this.databases.values()) {
+            usedConnections += d.connections;
+            usedHeap += d.writerHeapSize;
+        }
+
+        const connections = Math.max(MIN_CONNECTIONS_PER_DATABASE, Math.min(
+            valueOr(config.connectionPoolSize, DEFAULT_CONNECTION_POOL_SIZE),
+            this.connectionBudget - usedConnections));
+        const writerHeapSize = Math.max(MIN_WRITER_HEAP_SIZE, Math.min(
+            valueOr(config.writerHeapSize, DEFAULT_WRITER_HEAP_SIZE),
+            this.writerHeapBudget - usedHeap));
+
+        config.connectionPoolSize = connections;
+        config.writerHeapSize = writerHeapSize;
+
+        const database = new Seshat(this.accountPath(accountId), config);
+        this.databases.set(accountId, {database, connections, writerHeapSize});
+
+        return database;
+    }
+
+    /**
+     * Get the database of an account if it's open.
+     *
+     * @param  {string} accountId The id of the account.
+     *
+     * @return {Seshat|null} The database of the account, null if it isn't
+     * open.
+     */
+    get(accountId) {
+        const open = this.databases.get(accountId);
+        return open ? open.database : null;
+    }
+
+    /**
+     * Close the database of an account.
+     *
+     * The queued up events of the database are committed, the other
+     * databases aren't affected. The budget the database used is freed up for
+     * databases that are opened later on.
+     *
+     * @param  {string} accountId The id of the account.
+     *
+     * @return {Promise<boolean>} A promise that will resolve to false if the
+     * database of the account wasn't open, true once it has been closed.
+     */
+    async close(accountId) {
+        const open = this.databases.get(accountId);
+
+        if (!open) {
+            return false;
+        }
+
+        this.databases.delete(accountId);
+        await open.database.shutdown();
+
+        return true;
+    }
+
+    /**
+     * Delete the database of an account, it is closed first if it's open.
+     *
+     * @param  {string} accountId The id of the account.
+     *
+     * @return {Promise} A promise that will resolve once the database has been
+     * deleted.
+     */
+    async delete(accountId) {
+        const open = this.databases.get(accountId);
+
+        if (open) {
+            this.databases.delete(accountId);
+            return open.database.delete();
+        }
+
+        await fs.promises.rm(this.accountPath(accountId),
+            {recursive: true, force: true});
+    }
+
+    /**
+     * Get the ids of the accounts that have a database in the root directory,
+     * whether they are open or not.
+     *
+     * @return {Array.<string>} The sorted ids of the accounts.
+     */
+    listAccounts() {
+        return fs.readdirSync(this.root, {withFileTypes: true})
+            .filter((entry) => entry.isDirectory())
+            .map((entry) => decodeAccountId(entry.name))
+            .filter((accountId) => accountId !== null)
+            .sort();
+    }
+
+    /**
+     * Get the ids of the accounts whose database is open.
+     *
+     * @return {Array.<string>} The sorted ids of the accounts.
+     */
+    openAccounts() {
+        return Array.from(this.databases.keys()).sort();
+    }
+
+    /**
+     * Get the directory of the database of an account.
+     *
+     * @param  {string} accountId The id of the account.
+     *
+     * @return {string} The path of the database directory.
+     */
+    accountPath(accountId) {
+        return path.join(this.root, encodeAccountId(accountId));
     }
 }
 
 module.exports = {
     Seshat: Seshat,
+    SeshatManager: SeshatManager,
     SeshatRecovery: SeshatRecovery,
     ReindexError: ReindexError,
 };
@@ -12,38 +12,57 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod logging;
 mod tasks;
 mod utils;
 
 use neon::prelude::*;
-use seshat::{Database, Error, LoadConfig, LoadDirection, Profile, RecoveryDatabase, RecoveryInfo};
+use seshat::{
+    BulkImport, CancellationToken, Config, Database, Error, ErrorKind, RecoveryDatabase,
+    RecoveryInfo,
+};
 use std::cell::RefCell;
+use std::path::PathBuf;
 use std::sync::atomic::Ordering;
-use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crate::tasks::*;
 use crate::utils::*;
 
 pub struct Seshat {
     database: Option<Database>,
+    config: Config,
+    tasks: TaskGuard,
 }
 pub struct SeshatRecovery {
     database: Option<RecoveryDatabase>,
     info: RecoveryInfo,
 }
+pub struct SeshatBulkImport {
+    inner: Option<BulkImport>,
+}
+pub struct SeshatSearchCancellation {
+    inner: CancellationToken,
+}
 
 impl Finalize for Seshat {}
 impl Finalize for SeshatRecovery {}
+impl Finalize for SeshatBulkImport {}
+impl Finalize for SeshatSearchCancellation {}
 
-const CLOSED_ERROR: &str = "Database has been closed or deleted";
+const FINISHED_IMPORT_ERROR: &str = "The bulk import has already been finished";
 
 impl SeshatRecovery {
     fn new(mut cx: FunctionContext) -> JsResult<JsBox<RefCell<SeshatRecovery>>> {
-        let db_path: String = cx.argument::<JsString>(0)?.value(&mut cx);
+        let db_path = parse_database_path(&mut cx)?;
         let args = cx.argument_opt(1);
         let config = parse_database_config(&mut cx, args)?;
-        let database = RecoveryDatabase::new_with_config(db_path, &config)
-            .expect("Can't open recovery database.");
+        let database = match RecoveryDatabase::new_with_config(db_path, &config) {
+            Ok(db) => db,
+            Err(e) => return throw_open_error(&mut cx, e),
+        };
         let info = database.info().clone();
 
         Ok(cx.boxed(RefCell::new(SeshatRecovery {
@@ -52,7 +71,7 @@ impl SeshatRecovery {
         })))
     }
 
-    fn reindex(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn reindex(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<SeshatRecovery>>>(0)?;
 
         let database = {
@@ -71,34 +90,24 @@ impl SeshatRecovery {
         task.schedule(cx)
     }
 
-    fn get_user_version(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn get_user_version(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<SeshatRecovery>>>(0)?;
 
-        let connection = {
-            let db = &mut this.borrow_mut().database;
-
-            db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
         };
 
-        let connection = match connection {
-            Ok(c) => match c {
-                Ok(c) => c,
-                Err(e) => {
-                    return cx.throw_type_error(format!(
-                        "Unable to get a database connection {}",
-                        e.to_string()
-                    ))
-                }
-            },
-            Err(e) => return cx.throw_type_error(e),
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
         };
 
-        let task = GetUserVersionTask { connection };
+        let task = GetUserVersionTask { pool };
         task.schedule(cx)
     }
 
-    fn shutdown(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn shutdown(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<SeshatRecovery>>>(0)?;
 
         let database = {
@@ -137,7 +146,7 @@ impl SeshatRecovery {
 
 impl Seshat {
     fn new(mut cx: FunctionContext) -> JsResult<JsBox<RefCell<Seshat>>> {
-        let db_path: String = cx.argument::<JsString>(0)?.value(&mut cx);
+        let db_path = parse_database_path(&mut cx)?;
         let args = cx.argument_opt(1);
 
         let config = parse_database_config(&mut cx, args)?;
@@ -150,433 +159,1309 @@ impl Seshat {
                 // throw a RangeError here, let's hack around this by using
                 // one here.
                 let error = match e {
-                    Error::ReindexError => cx.throw_range_error("Database needs to be reindexed"),
-                    e => cx.throw_error(format!("Error opening the database: {:?}", e)),
+                    Error::ReindexError => {
+                        let js_error = cx.range_error("Database needs to be reindexed")?;
+                        throw_with_code(&mut cx, js_error, ErrorKind::ReindexRequired)
+                    }
+                    e => throw_open_error(&mut cx, e),
                 };
                 return error;
             }
         };
 
-        Ok(cx.boxed(RefCell::new(Seshat { database: Some(db) })))
+        Ok(cx.boxed(RefCell::new(Seshat {
+            database: Some(db),
+            config,
+            tasks: TaskGuard::default(),
+        })))
     }
 
-    fn add_historic_events_sync(mut cx: FunctionContext) -> JsResult<JsBoolean> {
-        let receiver = add_historic_events_helper(&mut cx)?;
-        let ret = receiver.recv().unwrap();
+    fn add_historic_events_sync(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let (receiver, skipped) = add_historic_events_helper(&mut cx)?;
+        let ret = receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError));
 
         match ret {
-            Ok(r) => Ok(cx.boolean(r)),
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Ok(r) => backlog_result_to_js(&mut cx, r, skipped.as_deref()),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
         }
     }
 
-    fn add_historic_events(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-        let receiver = add_historic_events_helper(&mut cx)?;
+    fn add_historic_events(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let (receiver, skipped) = add_historic_events_helper(&mut cx)?;
 
-        let task = AddBacklogTask { receiver };
+        let task = AddBacklogTask { receiver, skipped };
         task.schedule(cx)
     }
 
-    fn load_checkpoints(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn load_checkpoints(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
 
-        let connection = {
-            let db = &mut this.borrow_mut().database;
-
-            db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
         };
 
-        let connection = match connection {
-            Ok(c) => match c {
-                Ok(c) => c,
-                Err(e) => {
-                    return cx.throw_type_error(format!(
-                        "Unable to get a database connection {}",
-                        e.to_string()
-                    ))
-                }
-            },
-            Err(e) => return cx.throw_type_error(e),
+        // The task takes a connection out of the pool on a worker thread,
+        // waiting for a free connection doesn't block the JS thread and
+        // errors getting one are passed to the callback.
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
         };
 
-        let task = LoadCheckPointsTask { connection };
-        task.schedule(cx)
+        let task = LoadCheckPointsTask { pool };
+        task.schedule_guarded(cx)
     }
 
-    fn add_event(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn add_event(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
         let event = cx.argument::<JsObject>(1)?;
-        let event = parse_event(&mut cx, event)?;
 
-        let profile = match cx.argument_opt(2) {
-            Some(p) => {
-                let p = p.downcast::<JsObject, _>(&mut cx).or_throw(&mut cx)?;
-                parse_profile(&mut cx, p)?
-            }
-            None => Profile {
-                displayname: None,
-                avatar_url: None,
-            },
+        let ret = if let Some(reaction) = parse_reaction(&mut cx, event)? {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| -> seshat::Result<u64> {
+                db.status()?;
+                db.try_add_reaction(reaction)
+            })
+        } else if let Some(alias) = parse_room_alias(&mut cx, event)? {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| -> seshat::Result<u64> {
+                db.status()?;
+                db.try_add_room_alias(alias)
+            })
+        } else {
+            let event = parse_event(&mut cx, event)?;
+
+            let profile = cx.argument_opt(2);
+            let profile = parse_optional_profile(&mut cx, profile)?;
+
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| -> seshat::Result<u64> {
+                db.status()?;
+                db.try_add_event(event, profile)
+            })
         };
 
+        // Adds don't block the JS thread if the write queue is full, the
+        // caller needs to wait for the writer to catch up instead.
+        match ret {
+            Some(Ok(sequence)) => Ok(sequence_to_js(&mut cx, sequence)),
+            Some(Err(e @ Error::QueueFull)) => {
+                let js_error = cx.error(e.to_string())?;
+                throw_with_code(&mut cx, js_error, e.kind())
+            }
+            Some(Err(e)) => throw_seshat_type_error(&mut cx, &e),
+            None => throw_closed_error(&mut cx),
+        }
+    }
+
+    fn status(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
         let ret = {
             let db = &this.borrow().database;
-            db.as_ref().map_or_else(
-                || Err(CLOSED_ERROR),
-                |db| {
-                    db.add_event(event, profile);
-                    Ok(())
-                },
-            )
+            db.as_ref().map(|db| db.status())
         };
 
         match ret {
-            Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_type_error(e),
+            Some(Ok(_)) => Ok(cx.undefined()),
+            Some(Err(e)) => {
+                let js_error = cx.error(e.to_string())?;
+                throw_with_code(&mut cx, js_error, e.kind())
+            }
+            None => {
+                let js_error = cx.error(CLOSED_ERROR)?;
+                throw_with_code(&mut cx, js_error, ErrorKind::Closed)
+            }
         }
     }
 
-    fn delete_event(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn get_version_info(mut cx: FunctionContext) -> JsResult<JsObject> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-        let event_id = cx.argument::<JsString>(1)?.value(&mut cx);
 
-        let receiver = {
-            let db = &mut this.borrow_mut().database;
-            db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.delete_event(&event_id)))
+        let ret = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_version_info())
         };
 
-        let receiver = match receiver {
-            Ok(r) => r,
-            Err(e) => return cx.throw_type_error(e),
+        let info = match ret {
+            Some(Ok(i)) => i,
+            Some(Err(e)) => return throw_seshat_type_error(&mut cx, &e),
+            None => return throw_closed_error(&mut cx),
         };
 
-        let task = DeleteEventTask { receiver };
-        task.schedule(cx)
+        let result = cx.empty_object();
+        let crate_version = cx.string(info.crate_version);
+        let database_version = cx.number(info.database_version as f64);
+        let index_version = cx.number(info.index_version as f64);
+        result.set(&mut cx, "crateVersion", crate_version)?;
+        result.set(&mut cx, "databaseVersion", database_version)?;
+        result.set(&mut cx, "indexVersion", index_version)?;
+
+        Ok(result)
     }
 
-    fn commit(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn get_opstamps(mut cx: FunctionContext) -> JsResult<JsObject> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-        let force: bool = match cx.argument_opt(1) {
-            Some(w) => w
-                .downcast::<JsBoolean, _>(&mut cx)
-                .or_throw(&mut cx)?
-                .value(&mut cx),
-            None => false,
+
+        let opstamps = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map(|db| (db.last_queued_opstamp(), db.last_committed_opstamp()))
         };
 
-        let receiver = {
-            let db = &mut this.borrow_mut().database;
-            db.as_mut().map_or_else(
-                || Err(CLOSED_ERROR),
-                |db| {
-                    if force {
-                        Ok(db.force_commit_no_wait())
-                    } else {
-                        Ok(db.commit_no_wait())
-                    }
-                },
-            )
+        let (queued, committed) = match opstamps {
+            Some(o) => o,
+            None => return throw_closed_error(&mut cx),
         };
 
-        let receiver = match receiver {
-            Ok(r) => r,
-            Err(e) => return cx.throw_type_error(e),
+        let result = cx.empty_object();
+        let queued = sequence_to_js(&mut cx, queued);
+        let committed = sequence_to_js(&mut cx, committed);
+        result.set(&mut cx, "queued", queued)?;
+        result.set(&mut cx, "committed", committed)?;
+
+        Ok(result)
+    }
+
+    fn get_metrics(mut cx: FunctionContext) -> JsResult<JsObject> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let metrics = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_metrics()))
         };
 
-        let task = CommitTask { receiver };
-        task.schedule(cx)
+        let metrics = match metrics {
+            Ok(m) => m,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let result = cx.empty_object();
+        let events_added = cx.number(metrics.events_added as f64);
+        let events_deleted = cx.number(metrics.events_deleted as f64);
+        let commits = cx.number(metrics.commits as f64);
+        let searches = cx.number(metrics.searches as f64);
+        let search_errors = cx.number(metrics.search_errors as f64);
+        let source_cache_hits = cx.number(metrics.source_cache_hits as f64);
+        let source_cache_misses = cx.number(metrics.source_cache_misses as f64);
+        result.set(&mut cx, "eventsAdded", events_added)?;
+        result.set(&mut cx, "eventsDeleted", events_deleted)?;
+        result.set(&mut cx, "commits", commits)?;
+        result.set(&mut cx, "searches", searches)?;
+        result.set(&mut cx, "searchErrors", search_errors)?;
+        result.set(&mut cx, "sourceCacheHits", source_cache_hits)?;
+        result.set(&mut cx, "sourceCacheMisses", source_cache_misses)?;
+
+        Ok(result)
     }
 
-    fn reload(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn get_pending_errors(mut cx: FunctionContext) -> JsResult<JsArray> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
 
-        let ret = {
-            let db = &mut this.borrow_mut().database;
-            db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.reload()))
+        let errors = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.take_errors()))
         };
 
-        match ret {
-            Ok(r) => match r {
-                Ok(()) => Ok(cx.undefined()),
-                Err(e) => {
-                    let message = format!("Error opening the database: {:?}", e);
-                    cx.throw_type_error(message)
-                }
-            },
-            Err(e) => cx.throw_type_error(e),
+        let errors = match errors {
+            Ok(e) => e,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let result = JsArray::new(&mut cx, errors.len() as u32);
+
+        for (i, error) in errors.into_iter().enumerate() {
+            let js_error = cx.empty_object();
+            let operation = cx.string(error.operation.as_str());
+            let room_id: Handle<JsValue> = match error.room_id {
+                Some(r) => cx.string(r).upcast(),
+                None => cx.null().upcast(),
+            };
+            let kind = cx.string(error.kind);
+            let code = cx.string(error.code);
+            let message = cx.string(error.message);
+            js_error.set(&mut cx, "operation", operation)?;
+            js_error.set(&mut cx, "roomId", room_id)?;
+            js_error.set(&mut cx, "kind", kind)?;
+            js_error.set(&mut cx, "code", code)?;
+            js_error.set(&mut cx, "message", message)?;
+            result.set(&mut cx, i as u32, js_error)?;
         }
+
+        Ok(result)
     }
 
-    fn get_stats(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn add_event_async(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let event = cx.argument::<JsObject>(1)?;
+        let profile = cx.argument::<JsValue>(2)?;
 
-        let connection = {
-            let db = &mut this.borrow_mut().database;
+        let sender = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_event_sender()))
+        };
 
-            db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+        let sender = match sender {
+            Ok(s) => s,
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
-        let connection = match connection {
-            Ok(c) => match c {
-                Ok(c) => c,
-                Err(e) => {
-                    return cx.throw_type_error(format!(
-                        "Unable to get a database connection {}",
-                        e.to_string()
-                    ))
-                }
+        // Parsing errors are passed to the callback instead of being thrown.
+        let parsed = cx.try_catch(|cx| {
+            if let Some(reaction) = parse_reaction(cx, event)? {
+                return Ok(ParsedEvent::Reaction(reaction));
+            }
+
+            if let Some(alias) = parse_room_alias(cx, event)? {
+                return Ok(ParsedEvent::RoomAlias(alias));
+            }
+
+            let event = parse_event(cx, event)?;
+            let profile = parse_optional_profile(cx, Some(profile))?;
+            Ok(ParsedEvent::Event(event, profile))
+        });
+
+        let task = match parsed {
+            Ok(ParsedEvent::Event(event, profile)) => AddEventTask::Queue {
+                sender,
+                inner: Mutex::new(Some((event, profile))),
             },
-            Err(e) => return cx.throw_type_error(e),
+            Ok(ParsedEvent::Reaction(reaction)) => AddEventTask::Reaction { sender, reaction },
+            Ok(ParsedEvent::RoomAlias(alias)) => AddEventTask::RoomAlias { sender, alias },
+            Err(e) => AddEventTask::InvalidEvent(e.root(&mut cx)),
         };
 
-        let task = StatsTask { connection };
-        task.schedule(cx)
+        task.schedule_guarded(cx)
     }
 
-    fn get_size(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn add_events(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let js_events = cx.argument::<JsArray>(1)?;
+        let js_events: Vec<Handle<JsValue>> = js_events.to_vec(&mut cx)?;
 
-        let path = {
-            let db = &mut this.borrow_mut().database;
+        let sender = {
+            let db = &this.borrow().database;
             db.as_ref()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_path().to_path_buf()))
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_event_sender()))
         };
 
-        let path = match path {
-            Ok(p) => p,
-            Err(e) => return cx.throw_type_error(e),
+        let sender = match sender {
+            Ok(s) => s,
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
-        let task = GetSizeTask { path };
-        task.schedule(cx)
+        // All the events are parsed before any of them is queued up, a single
+        // invalid event fails the whole batch. Like in `add_event_async()`
+        // parsing errors are passed to the callback.
+        let mut index = 0;
+        let parsed = cx.try_catch(|cx| {
+            let mut events = Vec::with_capacity(js_events.len());
+            let mut reactions = Vec::new();
+            let mut aliases = Vec::new();
+
+            for (i, obj) in js_events.into_iter().enumerate() {
+                index = i;
+
+                if let Some(reaction) = parse_reaction_with_profile(cx, obj)? {
+                    reactions.push(reaction);
+                } else if let Some(alias) = parse_room_alias_with_profile(cx, obj)? {
+                    aliases.push(alias);
+                } else {
+                    events.push(parse_event_with_profile(cx, obj)?);
+                }
+            }
+
+            Ok((events, reactions, aliases))
+        });
+
+        let task = match parsed {
+            Ok(events) => AddEventsTask::Queue {
+                sender,
+                inner: Mutex::new(Some(events)),
+            },
+            Err(e) => {
+                let message = error_message(&mut cx, e)?;
+                let error =
+                    cx.type_error(format!("Invalid event at index {}: {}", index, message))?;
+                AddEventsTask::InvalidEvent(error.upcast::<JsValue>().root(&mut cx))
+            }
+        };
+
+        task.schedule_guarded(cx)
     }
 
-    fn is_empty(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn delete_event(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let event_id = cx.argument::<JsString>(1)?.value(&mut cx);
 
-        let connection = {
+        let receiver = {
             let db = &mut this.borrow_mut().database;
-
             db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.delete_event(&event_id)))
         };
 
-        let connection = match connection {
-            Ok(c) => match c {
-                Ok(c) => c,
-                Err(e) => {
-                    return cx.throw_type_error(format!(
-                        "Unable to get a database connection {}",
-                        e.to_string()
-                    ))
-                }
-            },
-            Err(e) => return cx.throw_type_error(e),
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
-        let task = IsEmptyTask { connection };
+        let task = DeleteEventTask { receiver };
         task.schedule(cx)
     }
 
-    fn is_room_indexed(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn delete_events_older_than(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-        let room_id = cx.argument::<JsString>(1)?.value(&mut cx);
+        let timestamp = cx.argument::<JsNumber>(1)?.value(&mut cx) as i64;
 
-        let connection = {
+        let receiver = {
             let db = &mut this.borrow_mut().database;
-
-            db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+            db.as_mut().map_or_else(
+                || Err(CLOSED_ERROR),
+                |db| Ok(db.delete_events_older_than(timestamp)),
+            )
         };
 
-        let connection = match connection {
-            Ok(c) => match c {
-                Ok(c) => c,
-                Err(e) => {
-                    return cx.throw_type_error(format!(
-                        "Unable to get a database connection {}",
-                        e.to_string()
-                    ))
-                }
-            },
-            Err(e) => return cx.throw_type_error(e),
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
-        let task = IsRoomIndexedTask {
-            connection,
-            room_id,
-        };
+        let task = DeleteOlderThanTask { receiver };
         task.schedule(cx)
     }
 
-    fn get_user_version(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn set_room_indexing(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let room_id = cx.argument::<JsString>(1)?.value(&mut cx);
+        let enabled = cx.argument::<JsBoolean>(2)?.value(&mut cx);
+        let purge = cx.argument::<JsBoolean>(3)?.value(&mut cx);
 
-        let connection = {
+        let receiver = {
             let db = &mut this.borrow_mut().database;
-
-            db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+            db.as_mut().map_or_else(
+                || Err(CLOSED_ERROR),
+                |db| Ok(db.set_room_indexing(&room_id, enabled, purge)),
+            )
         };
 
-        let connection = match connection {
-            Ok(c) => match c {
-                Ok(c) => c,
-                Err(e) => {
-                    return cx.throw_type_error(format!(
-                        "Unable to get a database connection {}",
-                        e.to_string()
-                    ))
-                }
-            },
-            Err(e) => return cx.throw_type_error(e),
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
-        let task = GetUserVersionTask { connection };
+        let task = SetRoomIndexingTask { receiver };
         task.schedule(cx)
     }
 
-    fn set_user_version(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn delete_room(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-        let version = cx.argument::<JsNumber>(1)?;
+        let room_id = cx.argument::<JsString>(1)?.value(&mut cx);
 
-        let connection = {
+        let receiver = {
             let db = &mut this.borrow_mut().database;
-
             db.as_mut()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.delete_room(&room_id)))
         };
 
-        let connection = match connection {
-            Ok(c) => match c {
-                Ok(c) => c,
-                Err(e) => {
-                    return cx.throw_type_error(format!(
-                        "Unable to get a database connection {}",
-                        e.to_string()
-                    ))
-                }
-            },
-            Err(e) => return cx.throw_type_error(e),
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
-        let task = SetUserVersionTask {
-            connection,
-            new_version: version.value(&mut cx) as i64,
-        };
+        let task = DeleteRoomTask { receiver };
         task.schedule(cx)
     }
 
-    fn commit_sync(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn delete_sender(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-
-        let wait: bool = match cx.argument_opt(1) {
-            Some(w) => w
-                .downcast::<JsBoolean, _>(&mut cx)
-                .or_throw(&mut cx)?
-                .value(&mut cx),
-            None => false,
+        let user_id = cx.argument::<JsString>(1)?.value(&mut cx);
+        let progress = cx.argument::<JsValue>(2)?;
+
+        let progress = match progress.downcast::<JsFunction, _>(&mut cx) {
+            Ok(f) => Some(ProgressCallback {
+                channel: cx.channel(),
+                callback: Arc::new(f.root(&mut cx)),
+            }),
+            Err(_) if progress.is_a::<JsNull, _>(&mut cx) => None,
+            Err(_) if progress.is_a::<JsUndefined, _>(&mut cx) => None,
+            Err(_) => return cx.throw_type_error("The progress callback must be a function"),
         };
 
-        let force: bool = match cx.argument_opt(2) {
-            Some(w) => w
-                .downcast::<JsBoolean, _>(&mut cx)
-                .or_throw(&mut cx)?
-                .value(&mut cx),
-            None => false,
-        };
+        let (progress_sender, progress_receiver) = channel();
 
-        let ret = {
+        let receiver = {
             let db = &mut this.borrow_mut().database;
+            db.as_mut().map(|db| {
+                db.delete_sender_with_progress(&user_id, move |count| {
+                    progress_sender.send(count).unwrap_or(())
+                })
+            })
+        };
 
-            if wait {
-                db.as_mut().map_or_else(
-                    || Err(CLOSED_ERROR),
-                    |db| {
-                        if force {
-                            Ok(Some(db.force_commit()))
-                        } else {
-                            Ok(Some(db.commit()))
-                        }
-                    },
-                )
-            } else {
-                db.as_mut().map_or_else(
-                    || Err(CLOSED_ERROR),
-                    |db| {
-                        db.commit_no_wait();
-                        Ok(None)
-                    },
-                )
+        let receiver = match receiver {
+            Some(r) => r,
+            None => {
+                if let Some(progress) = progress {
+                    progress.release(&mut cx);
+                }
+                return throw_closed_error(&mut cx);
             }
         };
 
-        let ret = match ret {
-            Ok(r) => r,
-            Err(e) => return cx.throw_type_error(e),
+        let task = DeleteSenderTask {
+            receiver,
+            progress_receiver,
+            progress,
         };
-
-        match ret {
-            Some(_) => Ok(cx.undefined()),
-            None => Ok(cx.undefined()),
-        }
+        task.schedule(cx)
     }
 
-    fn search_sync(mut cx: FunctionContext) -> JsResult<JsObject> {
+    fn compact(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-        let args = cx.argument::<JsObject>(1)?;
-        let (term, config) = parse_search_object(&mut cx, args)?;
 
-        let ret = {
+        let receiver = {
             let db = &mut this.borrow_mut().database;
-            db.as_ref()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.search(&term, &config)))
-        };
-
-        let ret = match ret {
-            Ok(r) => r,
-            Err(e) => return cx.throw_type_error(e),
+            db.as_mut()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.compact()))
         };
 
-        let mut ret = match ret {
+        let receiver = match receiver {
             Ok(r) => r,
-            Err(e) => return cx.throw_type_error(e.to_string()),
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
-        let count = ret.count;
-        let results = JsArray::new(&mut cx, ret.results.len() as u32);
-        let count = cx.number(count as f64);
-
-        for (i, element) in ret.results.drain(..).enumerate() {
-            let object = search_result_to_js(&mut cx, element)?;
-            results.set(&mut cx, i as u32, object)?;
-        }
-
-        let search_result = cx.empty_object();
-        let highlights = JsArray::new(&mut cx, 0);
+        let task = CompactTask { receiver };
+        task.schedule(cx)
+    }
 
-        search_result.set(&mut cx, "count", count)?;
-        search_result.set(&mut cx, "results", results)?;
-        search_result.set(&mut cx, "highlights", highlights)?;
+    fn shrink(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
 
-        if let Some(next_batch) = ret.next_batch {
-            let next_batch = cx.string(next_batch.hyphenated().to_string());
-            search_result.set(&mut cx, "next_batch", next_batch)?;
+        let receiver = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.shrink()))
+        };
+
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = ShrinkTask { receiver };
+        task.schedule(cx)
+    }
+
+    fn repair(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let receiver = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.repair()))
+        };
+
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = RepairTask { receiver };
+        task.schedule(cx)
+    }
+
+    fn commit(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let force: bool = match cx.argument_opt(1) {
+            Some(w) => w
+                .downcast::<JsBoolean, _>(&mut cx)
+                .or_throw(&mut cx)?
+                .value(&mut cx),
+            None => false,
+        };
+
+        let receiver = {
+            let db = &mut this.borrow_mut().database;
+            db.as_mut().map_or_else(
+                || Err(CLOSED_ERROR),
+                |db| {
+                    if force {
+                        Ok(db.force_commit_no_wait())
+                    } else {
+                        Ok(db.commit_no_wait())
+                    }
+                },
+            )
+        };
+
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = CommitTask { receiver };
+        task.schedule(cx)
+    }
+
+    fn reload(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let ret = {
+            let db = &mut this.borrow_mut().database;
+            db.as_mut()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.reload()))
+        };
+
+        match ret {
+            Ok(r) => match r {
+                Ok(()) => Ok(cx.undefined()),
+                Err(e) => throw_seshat_error(&mut cx, "Error reloading the database", &e),
+            },
+            Err(_) => throw_closed_error(&mut cx),
+        }
+    }
+
+    fn reload_async(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let reloader = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_reloader()))
+        };
+
+        let reloader = match reloader {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = ReloadTask { reloader };
+        task.schedule_guarded(cx)
+    }
+
+    fn wait_for_room_visibility(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let room_id = cx.argument::<JsString>(1)?.value(&mut cx);
+        let timestamp = cx.argument::<JsNumber>(2)?.value(&mut cx);
+        let timeout = cx.argument::<JsNumber>(3)?.value(&mut cx);
+
+        if !timestamp.is_finite() {
+            return cx.throw_type_error("The timestamp needs to be a number");
+        }
+
+        if !timeout.is_finite() || timeout < 0.0 {
+            return cx.throw_type_error("The timeout needs to be a non-negative number");
+        }
+
+        let ret = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| {
+                (
+                    db.room_visibility_no_wait(&room_id, timestamp as i64),
+                    db.get_reloader(),
+                )
+            })
+        };
+
+        let (receiver, reloader) = match ret {
+            Some(r) => r,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = WaitForRoomVisibilityTask {
+            receiver,
+            reloader,
+            timeout: Duration::from_millis(timeout as u64),
+        };
+        task.schedule(cx)
+    }
+
+    fn preload(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let searcher = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_searcher()))
+        };
+
+        let searcher = match searcher {
+            Ok(s) => s,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = PreloadTask { inner: searcher };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_stats(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let ret = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map(|db| (db.get_connection_pool(), db.queue_length()))
+        };
+
+        let (pool, queued_events) = match ret {
+            Some(r) => r,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = StatsTask {
+            pool,
+            queued_events,
+        };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_size(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let breakdown: bool = match cx.argument_opt(1) {
+            Some(b) => b
+                .downcast::<JsBoolean, _>(&mut cx)
+                .or_throw(&mut cx)?
+                .value(&mut cx),
+            None => false,
+        };
+
+        let files = {
+            let db = &mut this.borrow_mut().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_files()))
+        };
+
+        let files = match files {
+            Ok(f) => match f {
+                Ok(f) => f,
+                Err(e) => {
+                    let message = format!("Error getting the database size: {}", e);
+                    return cx.throw_type_error(message);
+                }
+            },
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = GetSizeTask { files, breakdown };
+        task.schedule_guarded(cx)
+    }
+
+    fn is_empty(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = IsEmptyTask { pool };
+        task.schedule_guarded(cx)
+    }
+
+    fn filter_known_events(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let js_event_ids = cx.argument::<JsArray>(1)?.to_vec(&mut cx)?;
+
+        let mut event_ids = Vec::with_capacity(js_event_ids.len());
+
+        for event_id in js_event_ids {
+            let event_id = event_id
+                .downcast::<JsString, _>(&mut cx)
+                .or_else(|_| cx.throw_type_error("The event ids need to be strings"))?
+                .value(&mut cx);
+            event_ids.push(event_id);
+        }
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = KnownEventsTask { pool, event_ids };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_newest_event_timestamps(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let js_room_ids = cx.argument::<JsArray>(1)?.to_vec(&mut cx)?;
+
+        let mut room_ids = Vec::with_capacity(js_room_ids.len());
+
+        for room_id in js_room_ids {
+            let room_id = room_id
+                .downcast::<JsString, _>(&mut cx)
+                .or_else(|_| cx.throw_type_error("The room ids need to be strings"))?
+                .value(&mut cx);
+            room_ids.push(room_id);
+        }
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = NewestEventTimestampsTask { pool, room_ids };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_room_names(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let js_room_ids = cx.argument::<JsArray>(1)?.to_vec(&mut cx)?;
+
+        let mut room_ids = Vec::with_capacity(js_room_ids.len());
+
+        for room_id in js_room_ids {
+            let room_id = room_id
+                .downcast::<JsString, _>(&mut cx)
+                .or_else(|_| cx.throw_type_error("The room ids need to be strings"))?
+                .value(&mut cx);
+            room_ids.push(room_id);
+        }
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = RoomNamesTask { pool, room_ids };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_reaction_counts(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let js_event_ids = cx.argument::<JsArray>(1)?.to_vec(&mut cx)?;
+
+        let mut event_ids = Vec::with_capacity(js_event_ids.len());
+
+        for event_id in js_event_ids {
+            let event_id = event_id
+                .downcast::<JsString, _>(&mut cx)
+                .or_else(|_| cx.throw_type_error("The event ids need to be strings"))?
+                .value(&mut cx);
+            event_ids.push(event_id);
+        }
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = ReactionCountsTask { pool, event_ids };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_profiles(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let js_senders = cx.argument::<JsArray>(1)?.to_vec(&mut cx)?;
+
+        let mut senders = Vec::with_capacity(js_senders.len());
+
+        for sender in js_senders {
+            let sender = sender
+                .downcast::<JsString, _>(&mut cx)
+                .or_else(|_| cx.throw_type_error("The senders need to be strings"))?
+                .value(&mut cx);
+            senders.push(sender);
+        }
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = ProfilesTask { pool, senders };
+        task.schedule_guarded(cx)
+    }
+
+    fn is_room_indexed(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let room_id = cx.argument::<JsString>(1)?.value(&mut cx);
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = IsRoomIndexedTask { pool, room_id };
+        task.schedule_guarded(cx)
+    }
+
+    fn was_event_indexed(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let event_id = cx.argument::<JsString>(1)?.value(&mut cx);
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = WasEventIndexedTask { pool, event_id };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_room_info(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let room_id = cx.argument::<JsString>(1)?.value(&mut cx);
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = RoomInfoTask { pool, room_id };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_room_infos(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = RoomInfosTask { pool };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_unindexed_rooms(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = UnindexedRoomsTask { pool };
+        task.schedule_guarded(cx)
+    }
+
+    fn set_room_fully_crawled(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let room_id = cx.argument::<JsString>(1)?.value(&mut cx);
+        let fully_crawled = cx.argument::<JsBoolean>(2)?.value(&mut cx);
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = SetRoomFullyCrawledTask {
+            pool,
+            room_id,
+            fully_crawled,
+        };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_fully_crawled_rooms(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = FullyCrawledRoomsTask { pool };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_indexed_rooms(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let include_checkpoint_only: bool = match cx.argument_opt(1) {
+            Some(b) => b
+                .downcast::<JsBoolean, _>(&mut cx)
+                .or_throw(&mut cx)?
+                .value(&mut cx),
+            None => false,
+        };
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = IndexedRoomsTask {
+            pool,
+            include_checkpoint_only,
+        };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_uncrawled_rooms(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = UncrawledRoomsTask { pool };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_search_history(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let limit = cx.argument::<JsNumber>(1)?.value(&mut cx);
+
+        if !limit.is_finite() || limit < 0.0 {
+            return cx
+                .throw_type_error("The search history limit needs to be a non-negative number");
+        }
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = SearchHistoryTask {
+            pool,
+            limit: limit as usize,
+        };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_context(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let event_id = cx.argument::<JsString>(1)?.value(&mut cx);
+        let before_limit = parse_context_limit(&mut cx, 2)?;
+        let after_limit = parse_context_limit(&mut cx, 3)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = GetContextTask {
+            pool,
+            event_id,
+            before_limit,
+            after_limit,
+        };
+        task.schedule_guarded(cx)
+    }
+
+    fn clear_search_history(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = ClearSearchHistoryTask { pool };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_user_version(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = GetUserVersionTask { pool };
+        task.schedule_guarded(cx)
+    }
+
+    fn set_user_version(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let version = cx.argument::<JsNumber>(1)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = SetUserVersionTask {
+            pool,
+            new_version: version.value(&mut cx) as i64,
+        };
+        task.schedule_guarded(cx)
+    }
+
+    fn get_watermark(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let name = cx.argument::<JsString>(1)?.value(&mut cx);
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = GetWatermarkTask { pool, name };
+        task.schedule_guarded(cx)
+    }
+
+    fn set_watermark(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let name = cx.argument::<JsString>(1)?.value(&mut cx);
+        let timestamp = cx.argument::<JsNumber>(2)?.value(&mut cx);
+
+        if name.is_empty() {
+            return cx.throw_type_error("The name of the watermark can't be empty");
+        }
+
+        if !timestamp.is_finite() {
+            return cx.throw_type_error("The timestamp of the watermark needs to be a number");
+        }
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = SetWatermarkTask {
+            pool,
+            name,
+            timestamp: timestamp as i64,
+        };
+        task.schedule_guarded(cx)
+    }
+
+    fn commit_sync(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let wait: bool = match cx.argument_opt(1) {
+            Some(w) => w
+                .downcast::<JsBoolean, _>(&mut cx)
+                .or_throw(&mut cx)?
+                .value(&mut cx),
+            None => false,
+        };
+
+        let force: bool = match cx.argument_opt(2) {
+            Some(w) => w
+                .downcast::<JsBoolean, _>(&mut cx)
+                .or_throw(&mut cx)?
+                .value(&mut cx),
+            None => false,
+        };
+
+        let ret = {
+            let db = &mut this.borrow_mut().database;
+
+            if wait {
+                db.as_mut().map_or_else(
+                    || Err(CLOSED_ERROR),
+                    |db| {
+                        if force {
+                            Ok(Some(db.force_commit()))
+                        } else {
+                            Ok(Some(db.commit()))
+                        }
+                    },
+                )
+            } else {
+                db.as_mut().map_or_else(
+                    || Err(CLOSED_ERROR),
+                    |db| {
+                        db.commit_no_wait();
+                        Ok(None)
+                    },
+                )
+            }
+        };
+
+        let ret = match ret {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        match ret {
+            Some(Ok(opstamp)) => Ok(sequence_to_js(&mut cx, opstamp)),
+            Some(Err(e)) => {
+                let js_error = cx.type_error(format!("Error writing to database: {}", e))?;
+                throw_with_code(&mut cx, js_error, e.kind())
+            }
+            None => Ok(cx.undefined().upcast()),
         }
+    }
+
+    fn search_sync(mut cx: FunctionContext) -> JsResult<JsObject> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let args = cx.argument::<JsObject>(1)?;
+        let (term, config) = parse_search_object(&mut cx, args)?;
+
+        // Only the searcher is acquired while the database is borrowed, the
+        // search itself runs without holding on to the database.
+        let searcher = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_searcher()))
+        };
+
+        let searcher = match searcher {
+            Ok(s) => s,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let ret = match searcher.search(&term, &config) {
+            Ok(r) => r,
+            Err(e) if e.kind() == ErrorKind::Closed => return throw_closed_error(&mut cx),
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+
+        search_batch_to_js(&mut cx, ParsedSearchBatch::parse(ret))
+    }
+
+    fn validate_query(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let term = cx.argument::<JsString>(1)?.value(&mut cx);
+        let advanced_syntax = match cx.argument_opt(2) {
+            Some(a) => a
+                .downcast::<JsBoolean, _>(&mut cx)
+                .or_else(|_| cx.throw_type_error("The advanced flag needs to be a boolean"))?
+                .value(&mut cx),
+            None => false,
+        };
+
+        let ret = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map(|db| db.validate_query(&term, advanced_syntax))
+        };
 
-        Ok(search_result.upcast())
+        match ret {
+            Some(Ok(())) => Ok(cx.null().upcast()),
+            Some(Err(e)) => Ok(cx.string(e.to_string()).upcast()),
+            None => throw_closed_error(&mut cx),
+        }
     }
 
-    fn search(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn search(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
         let args = cx.argument::<JsObject>(1)?;
 
         let (term, config) = parse_search_object(&mut cx, args)?;
 
+        // A search that can be cancelled passes its cancellation handle as
+        // the third argument.
+        let cancellation = match cx.argument_opt(2) {
+            Some(c) => match c.downcast::<JsBox<SeshatSearchCancellation>, _>(&mut cx) {
+                Ok(c) => c.inner.clone(),
+                Err(_) => CancellationToken::new(),
+            },
+            None => CancellationToken::new(),
+        };
+
         let searcher = {
             let db = &mut this.borrow_mut().database;
             db.as_ref()
@@ -585,43 +1470,92 @@ impl Seshat {
 
         let searcher = match searcher {
             Ok(s) => s,
-            Err(e) => return cx.throw_type_error(e.to_string()),
+            Err(_) => return throw_closed_error(&mut cx),
         };
 
         let task = SearchTask {
             inner: searcher,
             term,
             config,
+            cancellation,
         };
-        task.schedule(cx)
+        task.schedule_guarded(cx)
+    }
+
+    fn search_batch(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let js_queries = cx.argument::<JsArray>(1)?;
+        let js_queries: Vec<Handle<JsValue>> = js_queries.to_vec(&mut cx)?;
+
+        // Invalid search arguments only fail their own query, the error is
+        // returned in place of its result.
+        let mut queries = Vec::with_capacity(js_queries.len());
+
+        for query in js_queries {
+            let parsed = cx.try_catch(|cx| {
+                let args = query.downcast_or_throw::<JsObject, _>(cx)?;
+                parse_search_object(cx, args)
+            });
+
+            let parsed = match parsed {
+                Ok(q) => Ok(q),
+                Err(e) => Err(error_message(&mut cx, e)?),
+            };
+
+            queries.push(parsed);
+        }
+
+        let searcher = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_searcher()))
+        };
+
+        let searcher = match searcher {
+            Ok(s) => s,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = SearchBatchTask {
+            inner: searcher,
+            queries,
+        };
+        task.schedule_guarded(cx)
     }
 
-    fn delete(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn delete(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-        let db = this.borrow_mut().database.take();
+        let (db, tasks) = {
+            let mut seshat = this.borrow_mut();
+            (seshat.database.take(), seshat.tasks.clone())
+        };
 
         let db = match db {
             Some(db) => db,
             None => return cx.throw_type_error(CLOSED_ERROR),
         };
 
-        let db_path = db.get_path().to_path_buf();
-        let receiver = db.shutdown();
+        tasks.close();
 
         let task = DeleteTask {
-            db_path,
-            shutdown_receiver: receiver,
+            locations: db.get_locations(),
+            database: Mutex::new(Some(db)),
+            tasks,
         };
         task.schedule(cx)
     }
 
-    fn change_passphrase(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn change_passphrase(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
         let new_passphrase = cx.argument::<JsString>(1)?;
 
-        let db = {
-            let db = &mut this.borrow_mut().database;
-            db.take()
+        let (db, config, tasks) = {
+            let mut seshat = this.borrow_mut();
+            (
+                seshat.database.take(),
+                seshat.config.clone(),
+                seshat.tasks.clone(),
+            )
         };
 
         let db = match db {
@@ -630,19 +1564,42 @@ impl Seshat {
         };
 
         let task = ChangePassphraseTask {
+            this: this.root(&mut cx),
             database: Mutex::new(Some(db)),
+            tasks,
+            config,
             new_passphrase: new_passphrase.value(&mut cx),
+            reopened: Mutex::new(None),
         };
 
         task.schedule(cx)
     }
 
-    fn shutdown(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn backup(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let path = cx.argument::<JsString>(1)?.value(&mut cx);
 
-        let db = {
-            let db = &mut this.borrow_mut().database;
-            db.take()
+        let receiver = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.backup(&path)))
+        };
+
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(_) => return throw_closed_error(&mut cx),
+        };
+
+        let task = BackupTask { receiver };
+        task.schedule(cx)
+    }
+
+    fn shutdown(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let (db, tasks) = {
+            let mut seshat = this.borrow_mut();
+            (seshat.database.take(), seshat.tasks.clone())
         };
 
         let db = match db {
@@ -650,67 +1607,247 @@ impl Seshat {
             None => return cx.throw_type_error(CLOSED_ERROR),
         };
 
-        let receiver = db.shutdown();
+        tasks.close();
 
         let task = ShutDownTask {
-            shutdown_receiver: receiver,
+            database: Mutex::new(Some(db)),
+            tasks,
         };
         task.schedule(cx)
     }
 
-    fn load_file_events(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    fn load_file_events(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let args = cx.argument::<JsObject>(1)?;
+
+        let config = parse_load_config(&mut cx, args)?;
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = LoadEventsTask {
+            pool,
+            config,
+            events: LoadedEvents::Files,
+        };
+
+        task.schedule_guarded(cx)
+    }
+
+    fn load_url_events(mut cx: FunctionContext) -> JsResult<JsValue> {
         let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
         let args = cx.argument::<JsObject>(1)?;
 
-        let room_id = args
-            .get::<JsString, _, _>(&mut cx, "roomId")?
-            .value(&mut cx);
+        let config = parse_load_config(&mut cx, args)?;
 
-        let mut config = LoadConfig::new(room_id);
-        let limit = args.get::<JsNumber, _, _>(&mut cx, "limit")?.value(&mut cx);
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
 
-        config = config.limit(limit as usize);
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
 
-        if let Some(e) = args.get_opt::<JsString, _, _>(&mut cx, "fromEvent")? {
-            config = config.from_event(e.value(&mut cx));
+        let task = LoadEventsTask {
+            pool,
+            config,
+            events: LoadedEvents::Urls,
         };
 
-        if let Some(d) = args.get_opt::<JsString, _, _>(&mut cx, "direction")? {
-            let direction = match d.value(&mut cx).to_lowercase().as_ref() {
-                "backwards" | "backward" | "b" => LoadDirection::Backwards,
-                "forwards" | "forward" | "f" => LoadDirection::Forwards,
-                "" => LoadDirection::Backwards,
-                d => return cx.throw_error(format!("Unknown load direction {}", d)),
-            };
+        task.schedule_guarded(cx)
+    }
 
-            config = config.direction(direction);
-        }
+    fn import_events(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let path = cx.argument::<JsString>(1)?.value(&mut cx);
 
-        let connection = {
-            let db = &mut this.borrow_mut().database;
+        let ret = {
+            let db = &this.borrow().database;
             db.as_ref()
-                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.get_connection()))
+                .map(|db| (db.start_bulk_import(), db.get_connection_pool()))
         };
 
-        let connection = match connection {
-            Ok(s) => match s {
-                Ok(s) => s,
-                Err(e) => return cx.throw_type_error(e.to_string()),
-            },
-            Err(e) => return cx.throw_type_error(e.to_string()),
+        let (import, pool) = match ret {
+            Some(r) => r,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = ImportEventsTask {
+            import: Mutex::new(Some(import)),
+            pool,
+            path: PathBuf::from(path),
+        };
+
+        task.schedule_guarded(cx)
+    }
+
+    fn export_search(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let args = cx.argument::<JsObject>(1)?;
+        let progress = cx.argument::<JsValue>(2)?;
+        let path = cx.argument::<JsString>(3)?.value(&mut cx);
+
+        let (term, config) = parse_search_object(&mut cx, args)?;
+
+        // The path is the last argument so the export always returns a
+        // promise, the progress function is optional.
+        let progress = match progress.downcast::<JsFunction, _>(&mut cx) {
+            Ok(f) => Some(ProgressCallback {
+                channel: cx.channel(),
+                callback: Arc::new(f.root(&mut cx)),
+            }),
+            Err(_) if progress.is_a::<JsNull, _>(&mut cx) => None,
+            Err(_) if progress.is_a::<JsUndefined, _>(&mut cx) => None,
+            Err(_) => return cx.throw_type_error("The progress callback must be a function"),
+        };
+
+        let searcher = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_searcher())
+        };
+
+        let searcher = match searcher {
+            Some(s) => s,
+            None => {
+                if let Some(progress) = progress {
+                    progress.release(&mut cx);
+                }
+                return throw_closed_error(&mut cx);
+            }
         };
 
-        let task = LoadFileEventsTask {
-            inner: connection,
+        let task = ExportSearchTask {
+            inner: searcher,
+            term,
             config,
+            path: PathBuf::from(path),
+            progress,
+        };
+
+        task.schedule_guarded(cx)
+    }
+
+    fn export_events(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let path = cx.argument::<JsString>(1)?.value(&mut cx);
+
+        let pool = {
+            let db = &this.borrow().database;
+            db.as_ref().map(|db| db.get_connection_pool())
+        };
+
+        let pool = match pool {
+            Some(p) => p,
+            None => return throw_closed_error(&mut cx),
+        };
+
+        let task = ExportEventsTask {
+            pool,
+            path: PathBuf::from(path),
+        };
+
+        task.schedule_guarded(cx)
+    }
+}
+
+impl SeshatSearchCancellation {
+    fn new(mut cx: FunctionContext) -> JsResult<JsBox<SeshatSearchCancellation>> {
+        Ok(cx.boxed(SeshatSearchCancellation {
+            inner: CancellationToken::new(),
+        }))
+    }
+
+    fn cancel(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+        let this = cx.argument::<JsBox<SeshatSearchCancellation>>(0)?;
+        this.inner.cancel();
+
+        Ok(cx.undefined())
+    }
+}
+
+impl SeshatBulkImport {
+    fn new(mut cx: FunctionContext) -> JsResult<JsBox<RefCell<SeshatBulkImport>>> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+
+        let import = {
+            let db = &this.borrow().database;
+            db.as_ref()
+                .map_or_else(|| Err(CLOSED_ERROR), |db| Ok(db.start_bulk_import()))
+        };
+
+        match import {
+            Ok(i) => Ok(cx.boxed(RefCell::new(SeshatBulkImport { inner: Some(i) }))),
+            Err(_) => throw_closed_error(&mut cx),
+        }
+    }
+
+    fn add_chunk(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<SeshatBulkImport>>>(0)?;
+        let js_events = cx.argument::<JsArray>(1)?;
+        let js_events: Vec<Handle<JsValue>> = js_events.to_vec(&mut cx)?;
+
+        let mut events = Vec::with_capacity(js_events.len());
+
+        for obj in js_events {
+            events.push(parse_event_with_profile(&mut cx, obj)?);
+        }
+
+        let receiver = {
+            let import = &this.borrow().inner;
+            import
+                .as_ref()
+                .map_or_else(|| Err(FINISHED_IMPORT_ERROR), |i| Ok(i.add_chunk(events)))
         };
 
+        let receiver = match receiver {
+            Ok(r) => r,
+            Err(e) => return cx.throw_type_error(e),
+        };
+
+        let task = AddBacklogTask {
+            receiver,
+            skipped: None,
+        };
+        task.schedule(cx)
+    }
+
+    fn finish(mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<SeshatBulkImport>>>(0)?;
+
+        let js_checkpoint = cx.argument_opt(1);
+        let new_checkpoint = parse_checkpoint(&mut cx, js_checkpoint)?;
+        let js_checkpoint = cx.argument_opt(2);
+        let old_checkpoint = parse_checkpoint(&mut cx, js_checkpoint)?;
+
+        let import = this.borrow_mut().inner.take();
+
+        let receiver = match import {
+            Some(i) => i.finish(new_checkpoint, old_checkpoint),
+            None => return cx.throw_type_error(FINISHED_IMPORT_ERROR),
+        };
+
+        let task = AddBacklogTask {
+            receiver,
+            skipped: None,
+        };
         task.schedule(cx)
     }
 }
 
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    let version = cx.string(seshat::VERSION);
+    cx.export_value("version", version)?;
+
     cx.export_function("createRecoveryDb", SeshatRecovery::new)?;
     cx.export_function("reindexRecoveryDb", SeshatRecovery::reindex)?;
     cx.export_function("getUserVersionRecoveryDb", SeshatRecovery::get_user_version)?;
@@ -722,22 +1859,73 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("addHistoricEvents", Seshat::add_historic_events)?;
     cx.export_function("loadCheckpoints", Seshat::load_checkpoints)?;
     cx.export_function("addEvent", Seshat::add_event)?;
+    cx.export_function("addEventAsync", Seshat::add_event_async)?;
+    cx.export_function("addEvents", Seshat::add_events)?;
     cx.export_function("deleteEvent", Seshat::delete_event)?;
+    cx.export_function("deleteEventsOlderThan", Seshat::delete_events_older_than)?;
+    cx.export_function("setRoomIndexing", Seshat::set_room_indexing)?;
+    cx.export_function("deleteRoom", Seshat::delete_room)?;
+    cx.export_function("deleteSender", Seshat::delete_sender)?;
+    cx.export_function("compact", Seshat::compact)?;
+    cx.export_function("shrink", Seshat::shrink)?;
+    cx.export_function("repair", Seshat::repair)?;
     cx.export_function("commit", Seshat::commit)?;
     cx.export_function("reload", Seshat::reload)?;
+    cx.export_function("reloadAsync", Seshat::reload_async)?;
+    cx.export_function("waitForRoomVisibility", Seshat::wait_for_room_visibility)?;
+    cx.export_function("preload", Seshat::preload)?;
     cx.export_function("getStats", Seshat::get_stats)?;
+    cx.export_function("getStatus", Seshat::status)?;
     cx.export_function("getSize", Seshat::get_size)?;
+    cx.export_function("getVersionInfo", Seshat::get_version_info)?;
+    cx.export_function("getMetrics", Seshat::get_metrics)?;
+    cx.export_function("getOpstamps", Seshat::get_opstamps)?;
+    cx.export_function("getPendingErrors", Seshat::get_pending_errors)?;
     cx.export_function("isEmpty", Seshat::is_empty)?;
     cx.export_function("isRoomIndexed", Seshat::is_room_indexed)?;
+    cx.export_function("wasEventIndexed", Seshat::was_event_indexed)?;
+    cx.export_function("getReactionCounts", Seshat::get_reaction_counts)?;
+    cx.export_function("filterKnownEvents", Seshat::filter_known_events)?;
+    cx.export_function(
+        "getNewestEventTimestamps",
+        Seshat::get_newest_event_timestamps,
+    )?;
+    cx.export_function("getRoomNames", Seshat::get_room_names)?;
+    cx.export_function("getProfiles", Seshat::get_profiles)?;
+    cx.export_function("getRoomInfo", Seshat::get_room_info)?;
+    cx.export_function("getRoomInfos", Seshat::get_room_infos)?;
+    cx.export_function("getUnindexedRooms", Seshat::get_unindexed_rooms)?;
+    cx.export_function("setRoomFullyCrawled", Seshat::set_room_fully_crawled)?;
+    cx.export_function("getFullyCrawledRooms", Seshat::get_fully_crawled_rooms)?;
+    cx.export_function("getIndexedRooms", Seshat::get_indexed_rooms)?;
+    cx.export_function("getUncrawledRooms", Seshat::get_uncrawled_rooms)?;
+    cx.export_function("getSearchHistory", Seshat::get_search_history)?;
+    cx.export_function("clearSearchHistory", Seshat::clear_search_history)?;
+    cx.export_function("getContext", Seshat::get_context)?;
     cx.export_function("getUserVersion", Seshat::get_user_version)?;
     cx.export_function("setUserVersion", Seshat::set_user_version)?;
+    cx.export_function("getWatermark", Seshat::get_watermark)?;
+    cx.export_function("setWatermark", Seshat::set_watermark)?;
     cx.export_function("commitSync", Seshat::commit_sync)?;
     cx.export_function("searchSync", Seshat::search_sync)?;
+    cx.export_function("validateQuery", Seshat::validate_query)?;
     cx.export_function("search", Seshat::search)?;
+    cx.export_function("searchBatch", Seshat::search_batch)?;
     cx.export_function("deleteDb", Seshat::delete)?;
     cx.export_function("changePassphrase", Seshat::change_passphrase)?;
+    cx.export_function("backup", Seshat::backup)?;
     cx.export_function("shutdown", Seshat::shutdown)?;
     cx.export_function("loadFileEvents", Seshat::load_file_events)?;
+    cx.export_function("loadUrlEvents", Seshat::load_url_events)?;
+    cx.export_function("importEvents", Seshat::import_events)?;
+    cx.export_function("exportEvents", Seshat::export_events)?;
+    cx.export_function("exportSearch", Seshat::export_search)?;
+
+    cx.export_function("startBulkImport", SeshatBulkImport::new)?;
+    cx.export_function("bulkImportAddChunk", SeshatBulkImport::add_chunk)?;
+    cx.export_function("bulkImportFinish", SeshatBulkImport::finish)?;
+    cx.export_function("createSearchCancellation", SeshatSearchCancellation::new)?;
+    cx.export_function("cancelSearch", SeshatSearchCancellation::cancel)?;
 
     Ok(())
 }
@@ -0,0 +1,53 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+
+use tracing::Level;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+/// Write the tracing events of Seshat to a log file that is rotated daily.
+///
+/// The date of the day is appended to the file name of the given path. The
+/// closing of a span is logged as well, this records how long e.g. a search
+/// took.
+///
+/// The subscriber is global to the process, only the first call installs it,
+/// later calls leave the existing subscriber in place.
+pub(crate) fn init_file_logging(path: &Path, level: Level) -> Result<(), String> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("{} isn't a file path", path.display()))?;
+    let directory = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(file_name.to_string_lossy())
+        .build(directory)
+        .map_err(|e| e.to_string())?;
+
+    // Setting the subscriber only fails if one is already installed.
+    let _ = tracing_subscriber::fmt()
+        .with_writer(appender)
+        .with_max_level(level)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(false)
+        .try_init();
+
+    Ok(())
+}
@@ -12,15 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use fs_extra::dir;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 use crate::utils::*;
+use crate::Seshat;
 use neon::prelude::*;
 use seshat::{
-    CheckpointDirection, Connection, CrawlerCheckpoint, DatabaseStats, LoadConfig, Profile,
-    Receiver, RecoveryDatabase, SearchBatch, SearchConfig, Searcher,
+    BulkImport, CancellationToken, CheckpointDirection, Config, ConnectionPool, CrawlerCheckpoint,
+    DatabaseFiles, DatabaseSize, DatabaseStats, Event, EventSender, EventWithContext,
+    ExportSummary, ImportSummary, LoadConfig, Profile, Reaction, Receiver, RecoveryDatabase,
+    Reloader, RoomAlias, RoomInfo, SearchConfig, Searcher,
 };
 
 pub trait Task: Send + Sized + 'static {
@@ -38,48 +44,1498 @@ pub trait Task: Send + Sized + 'static {
 
     /// Schedule the task to be executed on a background thread.
     ///
-    /// The last argument of the `FunctionContext` needs to be a `JsFunction`.
-    fn schedule(self, mut cx: FunctionContext) -> JsResult<JsUndefined> {
-        let callback = cx
-            .argument::<JsFunction>(cx.len().saturating_sub(1))?
-            .root(&mut cx);
-        let queue = cx.channel();
-
-        std::thread::spawn(move || {
-            let result = self.perform();
-
-            queue.send(move |mut cx| {
-                let result =
-                    cx.try_catch(|cx| cx.compute_scoped(move |cx| self.complete(cx, result)));
-
-                let callback = callback.into_inner(&mut cx);
-                let this = cx.undefined();
-
-                let args = match result {
-                    Ok(v) => vec![cx.null().upcast(), v.as_value(&mut cx)],
-                    Err(e) => vec![e.upcast()],
-                };
+    /// If the last argument of the `FunctionContext` is a `JsFunction` it will
+    /// be called with the result of the task, otherwise a `JsPromise` is
+    /// returned that will be settled with the result of the task.
+    fn schedule(self, cx: FunctionContext) -> JsResult<JsValue> {
+        spawn_task(self, cx, None)
+    }
+
+    /// Schedule a task that uses the resources of a database.
+    ///
+    /// The database needs to be the first argument of the `FunctionContext`.
+    /// The task is registered with the `TaskGuard` of the database, it fails
+    /// with a closed error instead of being performed if the database is shut
+    /// down or deleted before the task starts.
+    fn schedule_guarded(self, mut cx: FunctionContext) -> JsResult<JsValue> {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let in_flight = this.borrow().tasks.enter();
+
+        match in_flight {
+            Some(in_flight) => spawn_task(self, cx, Some(in_flight)),
+            None => throw_closed_error(&mut cx),
+        }
+    }
+}
+
+fn spawn_task<T: Task>(
+    task: T,
+    mut cx: FunctionContext,
+    in_flight: Option<InFlightTask>,
+) -> JsResult<JsValue> {
+    let last = cx.argument_opt(cx.len().saturating_sub(1));
+
+    let (completion, ret) = match last {
+        Some(f) if f.is_a::<JsFunction, _>(&mut cx) => {
+            let callback = f.downcast_or_throw::<JsFunction, _>(&mut cx)?;
+            (
+                Completion::Callback(callback.root(&mut cx)),
+                cx.undefined().upcast(),
+            )
+        }
+        _ => {
+            let (deferred, promise) = cx.promise();
+            (Completion::Promise(deferred), promise.upcast())
+        }
+    };
+
+    let queue = cx.channel();
+
+    std::thread::spawn(move || {
+        // The database might have been closed while the task waited for its
+        // thread, `None` means that the task wasn't performed.
+        let result = match &in_flight {
+            Some(t) if t.is_closed() => None,
+            _ => Some(task.perform()),
+        };
+
+        // The task doesn't use the database anymore, a shutdown doesn't need
+        // to wait for the result to be delivered.
+        drop(in_flight);
 
-                callback.call(&mut cx, this, args)?;
-                Ok(())
+        queue.send(move |mut cx| {
+            let result = cx.try_catch(|cx| match result {
+                Some(result) => cx.compute_scoped(move |cx| task.complete(cx, result)),
+                None => throw_closed_error(cx),
             });
+
+            match completion {
+                Completion::Callback(callback) => {
+                    let callback = callback.into_inner(&mut cx);
+                    let this = cx.undefined();
+
+                    let args = match result {
+                        Ok(v) => vec![cx.null().upcast(), v.as_value(&mut cx)],
+                        Err(e) => vec![e.upcast()],
+                    };
+
+                    callback.call(&mut cx, this, args)?;
+                }
+                Completion::Promise(deferred) => match result {
+                    Ok(v) => deferred.resolve(&mut cx, v),
+                    Err(e) => deferred.reject(&mut cx, e),
+                },
+            }
+
+            Ok(())
         });
+    });
+
+    Ok(ret)
+}
+
+/// How long shutting down, deleting or changing the passphrase of a database
+/// waits for the tasks that use the database to finish.
+pub(crate) const TASK_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct TaskGuardState {
+    closed: bool,
+    in_flight: usize,
+}
+
+/// Keeps track of the tasks that use the resources of a database.
+///
+/// Tasks capture the connections and searchers of a database by value and
+/// run on their own threads. Shutting the database down or deleting it closes
+/// the guard, tasks that didn't start yet fail with a closed error, and waits
+/// for the tasks that already started to finish before the writer is torn
+/// down.
+///
+/// Tasks that only wait for the result of an operation that was already sent
+/// to the writer aren't registered, the writer finishes the operations it
+/// received before it shuts down.
+#[derive(Clone, Default)]
+pub(crate) struct TaskGuard {
+    inner: Arc<(Mutex<TaskGuardState>, Condvar)>,
+}
+
+impl TaskGuard {
+    /// Register a task that is about to be scheduled.
+    ///
+    /// Returns `None` if the guard is closed.
+    fn enter(&self) -> Option<InFlightTask> {
+        let mut state = self.inner.0.lock().unwrap();
+
+        if state.closed {
+            None
+        } else {
+            state.in_flight += 1;
+            Some(InFlightTask(self.clone()))
+        }
+    }
+
+    /// Close the guard, tasks that are scheduled or started afterwards fail
+    /// with a closed error.
+    pub(crate) fn close(&self) {
+        self.inner.0.lock().unwrap().closed = true;
+    }
+
+    /// Wait until none of the registered tasks are running anymore.
+    ///
+    /// Returns false if the tasks didn't finish within the timeout.
+    pub(crate) fn wait_idle(&self, timeout: Duration) -> bool {
+        let (state, finished) = &*self.inner;
+        let state = state.lock().unwrap();
+
+        let (_state, result) = finished
+            .wait_timeout_while(state, timeout, |s| s.in_flight > 0)
+            .unwrap();
+
+        !result.timed_out()
+    }
+}
+
+/// A task that was registered with a `TaskGuard`, unregisters the task when
+/// it's dropped.
+struct InFlightTask(TaskGuard);
+
+impl InFlightTask {
+    fn is_closed(&self) -> bool {
+        self.0.inner.0.lock().unwrap().closed
+    }
+}
+
+impl Drop for InFlightTask {
+    fn drop(&mut self) {
+        let (state, finished) = &*self.0.inner;
+        state.lock().unwrap().in_flight -= 1;
+        finished.notify_all();
+    }
+}
+
+/// The way the result of a scheduled task is delivered to JavaScript.
+enum Completion {
+    Callback(Root<JsFunction>),
+    Promise(Deferred),
+}
+
+pub(crate) struct CommitTask {
+    pub(crate) receiver: Receiver<seshat::Result<u64>>,
+}
+
+impl Task for CommitTask {
+    type Output = u64;
+    type Error = seshat::Error;
+    type JsEvent = JsValue;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(opstamp) => Ok(sequence_to_js(&mut cx, opstamp)),
+            Err(e) => {
+                let js_error = cx.type_error(format!("Error writing to database: {}", e))?;
+                throw_with_code(&mut cx, js_error, e.kind())
+            }
+        }
+    }
+}
+
+pub(crate) enum AddEventTask {
+    Queue {
+        sender: EventSender,
+        inner: Mutex<Option<(Event, Profile)>>,
+    },
+    /// The event is a reaction, it's only counted.
+    Reaction {
+        sender: EventSender,
+        reaction: Reaction,
+    },
+    /// The event sets the canonical alias of a room, it's only remembered to
+    /// label search results.
+    RoomAlias {
+        sender: EventSender,
+        alias: RoomAlias,
+    },
+    /// The event couldn't be parsed, the error that `parse_event()` threw is
+    /// delivered to the callback.
+    InvalidEvent(Root<JsValue>),
+}
+
+impl Task for AddEventTask {
+    type Output = u64;
+    type Error = seshat::Error;
+    type JsEvent = JsValue;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        match self {
+            AddEventTask::Queue { sender, inner } => {
+                let (event, profile) = inner.lock().unwrap().take().unwrap();
+                sender.send(event, profile)
+            }
+            AddEventTask::Reaction { sender, reaction } => sender.send_reaction(reaction.clone()),
+            AddEventTask::RoomAlias { sender, alias } => sender.send_room_alias(alias.clone()),
+            AddEventTask::InvalidEvent(_) => Ok(0),
+        }
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        if let AddEventTask::InvalidEvent(error) = self {
+            let error = error.into_inner(&mut cx);
+            return cx.throw(error);
+        }
+
+        match result {
+            Ok(sequence) => Ok(sequence_to_js(&mut cx, sequence)),
+            Err(e) => throw_seshat_error(&mut cx, "Error adding the event", &e),
+        }
+    }
+}
+
+pub(crate) enum AddEventsTask {
+    Queue {
+        sender: EventSender,
+        inner: Mutex<Option<(Vec<(Event, Profile)>, Vec<Reaction>, Vec<RoomAlias>)>>,
+    },
+    /// One of the events couldn't be parsed, none of the events were queued
+    /// and the error is delivered to the callback.
+    InvalidEvent(Root<JsValue>),
+}
+
+impl Task for AddEventsTask {
+    type Output = (usize, u64);
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        match self {
+            AddEventsTask::Queue { sender, inner } => {
+                let (events, reactions, aliases) = inner.lock().unwrap().take().unwrap();
+                let count = events.len() + reactions.len() + aliases.len();
+                let mut sequence = if events.is_empty() && count > 0 {
+                    0
+                } else {
+                    sender.send_batch(events)?
+                };
+
+                // Reactions aren't part of the batch, they are only counted.
+                for reaction in reactions {
+                    sequence = sender.send_reaction(reaction)?;
+                }
+
+                for alias in aliases {
+                    sequence = sender.send_room_alias(alias)?;
+                }
+
+                Ok((count, sequence))
+            }
+            AddEventsTask::InvalidEvent(_) => Ok((0, 0)),
+        }
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        if let AddEventsTask::InvalidEvent(error) = self {
+            let error = error.into_inner(&mut cx);
+            return cx.throw(error);
+        }
+
+        let (count, sequence) = match result {
+            Ok(r) => r,
+            Err(e) => return throw_seshat_error(&mut cx, "Error adding the events", &e),
+        };
+
+        let ret = cx.empty_object();
+        let count = cx.number(count as f64);
+        let sequence = sequence_to_js(&mut cx, sequence);
+
+        ret.set(&mut cx, "count", count)?;
+        ret.set(&mut cx, "sequence", sequence)?;
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct ReloadTask {
+    pub(crate) reloader: Reloader,
+}
+
+impl Task for ReloadTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        self.reloader.reload()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_seshat_error(&mut cx, "Error reloading the database", &e),
+        }
+    }
+}
+
+pub(crate) struct WaitForRoomVisibilityTask {
+    pub(crate) receiver: Receiver<seshat::Result<()>>,
+    pub(crate) reloader: Reloader,
+    pub(crate) timeout: Duration,
+}
+
+impl Task for WaitForRoomVisibilityTask {
+    type Output = bool;
+    type Error = seshat::Error;
+    type JsEvent = JsBoolean;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        match self.receiver.recv_timeout(self.timeout) {
+            Ok(Ok(())) => self.reloader.reload().map(|_| true),
+            Ok(Err(e)) => Err(e),
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => Err(seshat::Error::WriterClosedError),
+        }
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(visible) => Ok(cx.boolean(visible)),
+            Err(e) => throw_seshat_error(&mut cx, "Error waiting for the room", &e),
+        }
+    }
+}
+
+pub(crate) struct PreloadTask {
+    pub(crate) inner: Searcher,
+}
+
+impl Task for PreloadTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        self.inner.preload()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_seshat_error(&mut cx, "Error preloading the database", &e),
+        }
+    }
+}
+
+pub(crate) struct SearchTask {
+    pub(crate) inner: Searcher,
+    pub(crate) term: String,
+    pub(crate) config: SearchConfig,
+    pub(crate) cancellation: CancellationToken,
+}
+
+impl Task for SearchTask {
+    type Output = ParsedSearchBatch;
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        // The sources of the results are deserialized here, off the JS
+        // thread, which only needs to build the JS objects.
+        self.inner
+            .search_cancellable(&self.term, &self.config, &self.cancellation)
+            .map(ParsedSearchBatch::parse)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let ret = match result {
+            Ok(r) => r,
+            // Cancelled searches are rejected with an error whose kind tells
+            // them apart from failed searches.
+            Err(e @ seshat::Error::SearchCancelled) => {
+                let js_error = cx.error(e.to_string())?;
+                let js_kind = cx.string("SearchCancelled");
+                js_error.set(&mut cx, "kind", js_kind)?;
+                return throw_with_code(&mut cx, js_error, e.kind());
+            }
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+
+        search_batch_to_js(&mut cx, ret)
+    }
+}
+
+pub(crate) struct SearchBatchTask {
+    pub(crate) inner: Searcher,
+    /// The parsed search arguments, or the message of the error that
+    /// parsing them produced.
+    pub(crate) queries: Vec<Result<(String, SearchConfig), String>>,
+}
+
+impl Task for SearchBatchTask {
+    type Output = Vec<Result<ParsedSearchBatch, String>>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let valid: Vec<(String, SearchConfig)> = self
+            .queries
+            .iter()
+            .filter_map(|q| q.as_ref().ok().cloned())
+            .collect();
+        let mut results = self.inner.search_batch(&valid).into_iter();
+
+        Ok(self
+            .queries
+            .iter()
+            .map(|q| match q {
+                Ok(_) => results
+                    .next()
+                    .expect("Every valid query has a result")
+                    .map(ParsedSearchBatch::parse)
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.clone()),
+            })
+            .collect())
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let results = match result {
+            Ok(r) => r,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, results.len() as u32);
+
+        for (i, result) in results.into_iter().enumerate() {
+            let value: Handle<JsValue> = match result {
+                Ok(batch) => search_batch_to_js(&mut cx, batch)?.upcast(),
+                Err(e) => cx.type_error(e)?.upcast(),
+            };
+            ret.set(&mut cx, i as u32, value)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct AddBacklogTask {
+    pub(crate) receiver: Receiver<seshat::Result<bool>>,
+    pub(crate) skipped: Option<Vec<(usize, String)>>,
+}
+
+impl Task for AddBacklogTask {
+    type Output = bool;
+    type Error = seshat::Error;
+    type JsEvent = JsValue;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(r) => backlog_result_to_js(&mut cx, r, self.skipped.as_deref()),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct LoadCheckPointsTask {
+    pub(crate) pool: ConnectionPool,
+}
 
-        Ok(cx.undefined())
+impl Task for LoadCheckPointsTask {
+    type Output = Vec<CrawlerCheckpoint>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.load_checkpoints()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let mut checkpoints = match result {
+            Ok(c) => c,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let count = checkpoints.len();
+        let ret = JsArray::new(&mut cx, count as u32);
+
+        for (i, c) in checkpoints.drain(..).enumerate() {
+            let js_checkpoint = cx.empty_object();
+
+            let room_id = cx.string(c.room_id);
+            let token = cx.string(c.token);
+            let full_crawl = cx.boolean(c.full_crawl);
+            let direction = match c.direction {
+                CheckpointDirection::Backwards => cx.string("b"),
+                CheckpointDirection::Forwards => cx.string("f"),
+            };
+
+            js_checkpoint.set(&mut cx, "roomId", room_id)?;
+            js_checkpoint.set(&mut cx, "token", token)?;
+            js_checkpoint.set(&mut cx, "fullCrawl", full_crawl)?;
+            js_checkpoint.set(&mut cx, "direction", direction)?;
+
+            ret.set(&mut cx, i as u32, js_checkpoint)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct IsEmptyTask {
+    pub(crate) pool: ConnectionPool,
+}
+
+impl Task for IsEmptyTask {
+    type Output = bool;
+    type Error = seshat::Error;
+    type JsEvent = JsBoolean;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.is_empty()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(r) => Ok(cx.boolean(r)),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct KnownEventsTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) event_ids: Vec<String>,
+}
+
+impl Task for KnownEventsTask {
+    type Output = Vec<String>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_known_event_ids(&self.event_ids)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let event_ids = match result {
+            Ok(e) => e,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, event_ids.len() as u32);
+
+        for (i, event_id) in event_ids.iter().enumerate() {
+            let js_event_id = cx.string(event_id);
+            ret.set(&mut cx, i as u32, js_event_id)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct NewestEventTimestampsTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) room_ids: Vec<String>,
+}
+
+impl Task for NewestEventTimestampsTask {
+    type Output = HashMap<String, Option<i64>>;
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_newest_event_timestamps(&self.room_ids)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let timestamps = match result {
+            Ok(t) => t,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = cx.empty_object();
+
+        for (room_id, timestamp) in timestamps {
+            let js_timestamp = match timestamp {
+                Some(ts) => cx.number(ts as f64).upcast::<JsValue>(),
+                None => cx.null().upcast(),
+            };
+            ret.set(&mut cx, room_id.as_str(), js_timestamp)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct RoomNamesTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) room_ids: Vec<String>,
+}
+
+impl Task for RoomNamesTask {
+    type Output = HashMap<String, Option<String>>;
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_room_names(&self.room_ids)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let names = match result {
+            Ok(n) => n,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = cx.empty_object();
+
+        for (room_id, name) in names {
+            let js_name = match name {
+                Some(name) => cx.string(name).upcast::<JsValue>(),
+                None => cx.null().upcast(),
+            };
+            ret.set(&mut cx, room_id.as_str(), js_name)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct ReactionCountsTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) event_ids: Vec<String>,
+}
+
+impl Task for ReactionCountsTask {
+    type Output = HashMap<String, u64>;
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_reaction_counts(&self.event_ids)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let counts = match result {
+            Ok(c) => c,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = cx.empty_object();
+
+        for (event_id, count) in counts {
+            let js_count = cx.number(count as f64);
+            ret.set(&mut cx, event_id.as_str(), js_count)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct ProfilesTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) senders: Vec<String>,
+}
+
+impl Task for ProfilesTask {
+    type Output = HashMap<String, Option<Profile>>;
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.load_profiles(&self.senders)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let profiles = match result {
+            Ok(p) => p,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = cx.empty_object();
+
+        for (sender, profile) in profiles {
+            let js_profile = match profile {
+                Some(p) => profile_to_js(&mut cx, p)?.upcast::<JsValue>(),
+                None => cx.null().upcast(),
+            };
+            ret.set(&mut cx, sender.as_str(), js_profile)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct IsRoomIndexedTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) room_id: String,
+}
+
+impl Task for IsRoomIndexedTask {
+    type Output = bool;
+    type Error = seshat::Error;
+    type JsEvent = JsBoolean;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.is_room_indexed(&self.room_id)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(r) => Ok(cx.boolean(r)),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct WasEventIndexedTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) event_id: String,
+}
+
+impl Task for WasEventIndexedTask {
+    type Output = bool;
+    type Error = seshat::Error;
+    type JsEvent = JsBoolean;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.was_event_indexed(&self.event_id)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(r) => Ok(cx.boolean(r)),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct RoomInfoTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) room_id: String,
+}
+
+impl Task for RoomInfoTask {
+    type Output = RoomInfo;
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_room_info(&self.room_id)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(info) => room_info_to_js(&mut cx, info),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct RoomInfosTask {
+    pub(crate) pool: ConnectionPool,
+}
+
+impl Task for RoomInfosTask {
+    type Output = Vec<RoomInfo>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_all_room_infos()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let infos = match result {
+            Ok(i) => i,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, infos.len() as u32);
+
+        for (i, info) in infos.into_iter().enumerate() {
+            let js_info = room_info_to_js(&mut cx, info)?;
+            ret.set(&mut cx, i as u32, js_info)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct UnindexedRoomsTask {
+    pub(crate) pool: ConnectionPool,
+}
+
+impl Task for UnindexedRoomsTask {
+    type Output = Vec<String>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_unindexed_rooms()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let rooms = match result {
+            Ok(r) => r,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, rooms.len() as u32);
+
+        for (i, room_id) in rooms.iter().enumerate() {
+            let js_room_id = cx.string(room_id);
+            ret.set(&mut cx, i as u32, js_room_id)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct SetRoomFullyCrawledTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) room_id: String,
+    pub(crate) fully_crawled: bool,
+}
+
+impl Task for SetRoomFullyCrawledTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.set_room_fully_crawled(&self.room_id, self.fully_crawled)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => {
+                throw_seshat_error(&mut cx, "Error while setting the crawl state of a room", &e)
+            }
+        }
+    }
+}
+
+pub(crate) struct FullyCrawledRoomsTask {
+    pub(crate) pool: ConnectionPool,
+}
+
+impl Task for FullyCrawledRoomsTask {
+    type Output = Vec<String>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_fully_crawled_rooms()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let rooms = match result {
+            Ok(r) => r,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, rooms.len() as u32);
+
+        for (i, room_id) in rooms.iter().enumerate() {
+            let js_room_id = cx.string(room_id);
+            ret.set(&mut cx, i as u32, js_room_id)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct IndexedRoomsTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) include_checkpoint_only: bool,
+}
+
+impl Task for IndexedRoomsTask {
+    type Output = Vec<String>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        let mut rooms = connection.get_rooms()?;
+
+        if self.include_checkpoint_only {
+            rooms.extend(connection.get_checkpoint_only_rooms()?);
+            rooms.sort();
+        }
+
+        Ok(rooms)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let rooms = match result {
+            Ok(r) => r,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, rooms.len() as u32);
+
+        for (i, room_id) in rooms.iter().enumerate() {
+            let js_room_id = cx.string(room_id);
+            ret.set(&mut cx, i as u32, js_room_id)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct UncrawledRoomsTask {
+    pub(crate) pool: ConnectionPool,
+}
+
+impl Task for UncrawledRoomsTask {
+    type Output = Vec<String>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_rooms_without_checkpoints()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let rooms = match result {
+            Ok(r) => r,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, rooms.len() as u32);
+
+        for (i, room_id) in rooms.iter().enumerate() {
+            let js_room_id = cx.string(room_id);
+            ret.set(&mut cx, i as u32, js_room_id)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct SearchHistoryTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) limit: usize,
+}
+
+impl Task for SearchHistoryTask {
+    type Output = Vec<String>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.load_recent_searches(self.limit)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let terms = match result {
+            Ok(t) => t,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+        let ret = JsArray::new(&mut cx, terms.len() as u32);
+
+        for (i, term) in terms.iter().enumerate() {
+            let js_term = cx.string(term);
+            ret.set(&mut cx, i as u32, js_term)?;
+        }
+
+        Ok(ret)
+    }
+}
+
+pub(crate) struct GetContextTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) event_id: String,
+    pub(crate) before_limit: usize,
+    pub(crate) after_limit: usize,
+}
+
+impl Task for GetContextTask {
+    type Output = Option<EventWithContext>;
+    type Error = seshat::Error;
+    type JsEvent = JsValue;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_context(&self.event_id, self.before_limit, self.after_limit)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(Some(context)) => Ok(event_with_context_to_js(&mut cx, context)?.upcast()),
+            Ok(None) => Ok(cx.null().upcast()),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct ClearSearchHistoryTask {
+    pub(crate) pool: ConnectionPool,
+}
+
+impl Task for ClearSearchHistoryTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.clear_search_history()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct StatsTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) queued_events: usize,
+}
+
+impl Task for StatsTask {
+    type Output = DatabaseStats;
+    type Error = seshat::Error;
+    type JsEvent = JsObject;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+        connection.get_stats()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(r) => {
+                let result = cx.empty_object();
+
+                // An empty database reports null counts, the size is always
+                // known.
+                let (event_count, room_count) = if r.event_count == 0 {
+                    (cx.null().upcast::<JsValue>(), cx.null().upcast::<JsValue>())
+                } else {
+                    (
+                        cx.number(r.event_count as f64).upcast(),
+                        cx.number(r.room_count as f64).upcast(),
+                    )
+                };
+                let size = cx.number(r.size as f64);
+                let queued_events = cx.number(self.queued_events as f64);
+                let tombstone_count = cx.number(r.tombstone_count as f64);
+                let source_cache_hits = cx.number(r.source_cache_hits as f64);
+                let source_cache_misses = cx.number(r.source_cache_misses as f64);
+                let consistency = cx.string(r.consistency.as_str());
+                result.set(&mut cx, "eventCount", event_count)?;
+                result.set(&mut cx, "roomCount", room_count)?;
+                result.set(&mut cx, "size", size)?;
+                result.set(&mut cx, "queuedEvents", queued_events)?;
+                result.set(&mut cx, "tombstoneCount", tombstone_count)?;
+                result.set(&mut cx, "sourceCacheHits", source_cache_hits)?;
+                result.set(&mut cx, "sourceCacheMisses", source_cache_misses)?;
+                result.set(&mut cx, "consistency", consistency)?;
+                Ok(result)
+            }
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct GetSizeTask {
+    pub(crate) files: DatabaseFiles,
+    pub(crate) breakdown: bool,
+}
+
+impl Task for GetSizeTask {
+    type Output = DatabaseSize;
+    type Error = seshat::Error;
+    type JsEvent = JsValue;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        self.files.get_size()
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let size = match result {
+            Ok(s) => s,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+
+        if !self.breakdown {
+            return Ok(cx.number(size.total_bytes() as f64).upcast());
+        }
+
+        let result = cx.empty_object();
+        let index_bytes = cx.number(size.index_bytes as f64);
+        let store_bytes = cx.number(size.store_bytes as f64);
+        let total_bytes = cx.number(size.total_bytes() as f64);
+        result.set(&mut cx, "indexBytes", index_bytes)?;
+        result.set(&mut cx, "storeBytes", store_bytes)?;
+        result.set(&mut cx, "totalBytes", total_bytes)?;
+
+        Ok(result.upcast())
+    }
+}
+
+pub(crate) struct DeleteTask {
+    pub(crate) locations: Vec<PathBuf>,
+    pub(crate) database: Mutex<Option<seshat::Database>>,
+    pub(crate) tasks: TaskGuard,
+}
+
+pub(crate) struct ShutDownTask {
+    pub(crate) database: Mutex<Option<seshat::Database>>,
+    pub(crate) tasks: TaskGuard,
+}
+
+/// Shut a database down once the tasks that use it are done, or once they
+/// took too long to finish.
+///
+/// The tasks keep the resources they use alive, shutting down underneath a
+/// task that didn't finish in time makes its database operations fail
+/// instead of returning results.
+fn shutdown_guarded(
+    database: &Mutex<Option<seshat::Database>>,
+    tasks: &TaskGuard,
+) -> seshat::Result<()> {
+    tasks.wait_idle(TASK_DRAIN_TIMEOUT);
+
+    match database.lock().unwrap().take() {
+        // A disconnected channel means that the writer has already stopped.
+        Some(db) => db.shutdown().recv().unwrap_or(Ok(())),
+        None => Ok(()),
+    }
+}
+
+impl Task for ShutDownTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        shutdown_guarded(&self.database, &self.tasks)
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+pub(crate) struct BackupTask {
+    pub(crate) receiver: Receiver<seshat::Result<()>>,
+}
+
+impl Task for BackupTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_seshat_error(&mut cx, "Error creating a backup", &e),
+        }
+    }
+}
+
+impl Task for DeleteTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        shutdown_guarded(&self.database, &self.tasks)?;
+
+        for location in &self.locations {
+            match std::fs::remove_dir_all(location) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
+    }
+}
+
+/// The kind of events a `LoadEventsTask` loads.
+pub(crate) enum LoadedEvents {
+    /// Events that contain an mxc URL to a file.
+    Files,
+    /// Message events that contain a link.
+    Urls,
+}
+
+pub(crate) struct LoadEventsTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) config: LoadConfig,
+    pub(crate) events: LoadedEvents,
+}
+
+impl Task for LoadEventsTask {
+    type Output = Vec<(String, Profile)>;
+    type Error = seshat::Error;
+    type JsEvent = JsArray;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let connection = self.pool.get()?;
+
+        match self.events {
+            LoadedEvents::Files => connection.load_file_events(&self.config),
+            LoadedEvents::Urls => connection.load_url_events(&self.config),
+        }
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        let mut ret = match result {
+            Ok(r) => r,
+            Err(e) => return throw_seshat_type_error(&mut cx, &e),
+        };
+
+        let results = JsArray::new(&mut cx, ret.len() as u32);
+
+        for (i, (source, profile)) in ret.drain(..).enumerate() {
+            let result = cx.empty_object();
+
+            let event = deserialize_event(&mut cx, &source)?;
+            let profile = profile_to_js(&mut cx, profile)?;
+            result.set(&mut cx, "event", event)?;
+            result.set(&mut cx, "profile", profile)?;
+
+            results.set(&mut cx, i as u32, result)?;
+        }
+
+        Ok(results)
+    }
+}
+
+pub(crate) struct ReindexTask {
+    pub(crate) inner: Mutex<Option<RecoveryDatabase>>,
+}
+
+impl Task for ReindexTask {
+    type Output = ();
+    type Error = seshat::Error;
+    type JsEvent = JsUndefined;
+
+    fn perform(&self) -> Result<Self::Output, Self::Error> {
+        let mut db = self.inner.lock().unwrap().take().unwrap();
+        db.delete_the_index()?;
+        db.open_index()?;
+
+        let mut events = db.load_events_deserialized(500, None)?;
+        db.index_events(&events)?;
+
+        loop {
+            events = db.load_events_deserialized(500, events.last())?;
+
+            if events.is_empty() {
+                break;
+            }
+
+            db.index_events(&events)?;
+            db.commit()?;
+        }
+
+        db.commit_and_close()?;
+
+        Ok(())
+    }
+
+    fn complete<'a, 'b>(
+        self,
+        mut cx: ComputeContext<'a, 'b>,
+        result: Result<Self::Output, Self::Error>,
+    ) -> JsResult<'a, Self::JsEvent> {
+        match result {
+            Ok(_) => Ok(cx.undefined()),
+            Err(e) => throw_seshat_type_error(&mut cx, &e),
+        }
     }
 }
 
-pub(crate) struct CommitTask {
-    pub(crate) receiver: Receiver<seshat::Result<()>>,
+pub(crate) struct DeleteEventTask {
+    pub(crate) receiver: Receiver<seshat::Result<bool>>,
 }
 
-impl Task for CommitTask {
-    type Output = ();
+impl Task for DeleteEventTask {
+    type Output = bool;
     type Error = seshat::Error;
-    type JsEvent = JsUndefined;
+    type JsEvent = JsBoolean;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.receiver.recv().unwrap()
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -88,25 +1544,25 @@ impl Task for CommitTask {
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
-            Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_error(format!("Error writing to database: {}", e.to_string())),
+            Ok(b) => Ok(cx.boolean(b)),
+            Err(e) => throw_seshat_error(&mut cx, "Error deleting an event", &e),
         }
     }
 }
 
-pub(crate) struct SearchTask {
-    pub(crate) inner: Searcher,
-    pub(crate) term: String,
-    pub(crate) config: SearchConfig,
+pub(crate) struct DeleteOlderThanTask {
+    pub(crate) receiver: Receiver<seshat::Result<usize>>,
 }
 
-impl Task for SearchTask {
-    type Output = SearchBatch;
+impl Task for DeleteOlderThanTask {
+    type Output = usize;
     type Error = seshat::Error;
-    type JsEvent = JsObject;
+    type JsEvent = JsNumber;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.inner.search(&self.term, &self.config)
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -114,46 +1570,26 @@ impl Task for SearchTask {
         mut cx: ComputeContext<'a, 'b>,
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
-        let mut ret = match result {
-            Ok(r) => r,
-            Err(e) => return cx.throw_type_error(e.to_string()),
-        };
-
-        let results = JsArray::new(&mut cx, ret.results.len() as u32);
-        let count = cx.number(ret.count as f64);
-
-        for (i, element) in ret.results.drain(..).enumerate() {
-            let object = search_result_to_js(&mut cx, element)?;
-            results.set(&mut cx, i as u32, object)?;
-        }
-
-        let search_result = cx.empty_object();
-        let highlights = JsArray::new(&mut cx, 0);
-
-        search_result.set(&mut cx, "count", count)?;
-        search_result.set(&mut cx, "results", results)?;
-        search_result.set(&mut cx, "highlights", highlights)?;
-
-        if let Some(next_batch) = ret.next_batch {
-            let next_batch = cx.string(next_batch.hyphenated().to_string());
-            search_result.set(&mut cx, "next_batch", next_batch)?;
+        match result {
+            Ok(count) => Ok(cx.number(count as f64)),
+            Err(e) => throw_seshat_error(&mut cx, "Error deleting events", &e),
         }
-
-        Ok(search_result)
     }
 }
 
-pub(crate) struct AddBacklogTask {
-    pub(crate) receiver: Receiver<seshat::Result<bool>>,
+pub(crate) struct SetRoomIndexingTask {
+    pub(crate) receiver: Receiver<seshat::Result<usize>>,
 }
 
-impl Task for AddBacklogTask {
-    type Output = bool;
+impl Task for SetRoomIndexingTask {
+    type Output = usize;
     type Error = seshat::Error;
-    type JsEvent = JsBoolean;
+    type JsEvent = JsNumber;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.receiver.recv().unwrap()
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -162,23 +1598,25 @@ impl Task for AddBacklogTask {
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
-            Ok(r) => Ok(cx.boolean(r)),
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Ok(count) => Ok(cx.number(count as f64)),
+            Err(e) => throw_seshat_error(&mut cx, "Error changing the indexing of a room", &e),
         }
     }
 }
 
-pub(crate) struct LoadCheckPointsTask {
-    pub(crate) connection: Connection,
+pub(crate) struct DeleteRoomTask {
+    pub(crate) receiver: Receiver<seshat::Result<usize>>,
 }
 
-impl Task for LoadCheckPointsTask {
-    type Output = Vec<CrawlerCheckpoint>;
+impl Task for DeleteRoomTask {
+    type Output = usize;
     type Error = seshat::Error;
-    type JsEvent = JsArray;
+    type JsEvent = JsNumber;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.connection.load_checkpoints()
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -186,47 +1624,36 @@ impl Task for LoadCheckPointsTask {
         mut cx: ComputeContext<'a, 'b>,
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
-        let mut checkpoints = match result {
-            Ok(c) => c,
-            Err(e) => return cx.throw_type_error(e.to_string()),
-        };
-        let count = checkpoints.len();
-        let ret = JsArray::new(&mut cx, count as u32);
-
-        for (i, c) in checkpoints.drain(..).enumerate() {
-            let js_checkpoint = cx.empty_object();
-
-            let room_id = cx.string(c.room_id);
-            let token = cx.string(c.token);
-            let full_crawl = cx.boolean(c.full_crawl);
-            let direction = match c.direction {
-                CheckpointDirection::Backwards => cx.string("b"),
-                CheckpointDirection::Forwards => cx.string("f"),
-            };
-
-            js_checkpoint.set(&mut cx, "roomId", room_id)?;
-            js_checkpoint.set(&mut cx, "token", token)?;
-            js_checkpoint.set(&mut cx, "fullCrawl", full_crawl)?;
-            js_checkpoint.set(&mut cx, "direction", direction)?;
-
-            ret.set(&mut cx, i as u32, js_checkpoint)?;
+        match result {
+            Ok(count) => Ok(cx.number(count as f64)),
+            Err(e) => throw_seshat_error(&mut cx, "Error deleting the room", &e),
         }
-
-        Ok(ret)
     }
 }
 
-pub(crate) struct IsEmptyTask {
-    pub(crate) connection: Connection,
+pub(crate) struct DeleteSenderTask {
+    pub(crate) receiver: Receiver<seshat::Result<usize>>,
+    pub(crate) progress_receiver: Receiver<usize>,
+    pub(crate) progress: Option<ProgressCallback>,
 }
 
-impl Task for IsEmptyTask {
-    type Output = bool;
+impl Task for DeleteSenderTask {
+    type Output = usize;
     type Error = seshat::Error;
-    type JsEvent = JsBoolean;
+    type JsEvent = JsNumber;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.connection.is_empty()
+        // The writer thread drops its end of the progress channel once the
+        // deletion is done.
+        for count in self.progress_receiver.iter() {
+            if let Some(progress) = &self.progress {
+                progress.report(count);
+            }
+        }
+
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -234,25 +1661,30 @@ impl Task for IsEmptyTask {
         mut cx: ComputeContext<'a, 'b>,
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
+        if let Some(progress) = self.progress {
+            progress.release(&mut cx);
+        }
+
         match result {
-            Ok(r) => Ok(cx.boolean(r)),
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Ok(count) => Ok(cx.number(count as f64)),
+            Err(e) => throw_seshat_error(&mut cx, "Error deleting the events of the sender", &e),
         }
     }
 }
 
-pub(crate) struct IsRoomIndexedTask {
-    pub(crate) connection: Connection,
-    pub(crate) room_id: String,
+pub(crate) struct CompactTask {
+    pub(crate) receiver: Receiver<seshat::Result<bool>>,
 }
 
-impl Task for IsRoomIndexedTask {
+impl Task for CompactTask {
     type Output = bool;
     type Error = seshat::Error;
     type JsEvent = JsBoolean;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.connection.is_room_indexed(&self.room_id)
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -261,23 +1693,25 @@ impl Task for IsRoomIndexedTask {
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
-            Ok(r) => Ok(cx.boolean(r)),
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Ok(compacted) => Ok(cx.boolean(compacted)),
+            Err(e) => throw_seshat_error(&mut cx, "Error compacting the database", &e),
         }
     }
 }
 
-pub(crate) struct StatsTask {
-    pub(crate) connection: Connection,
+pub(crate) struct RepairTask {
+    pub(crate) receiver: Receiver<seshat::Result<bool>>,
 }
 
-impl Task for StatsTask {
-    type Output = DatabaseStats;
+impl Task for RepairTask {
+    type Output = bool;
     type Error = seshat::Error;
-    type JsEvent = JsObject;
+    type JsEvent = JsBoolean;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.connection.get_stats()
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -286,32 +1720,25 @@ impl Task for StatsTask {
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
-            Ok(r) => {
-                let result = cx.empty_object();
-                let event_count = cx.number(r.event_count as f64);
-                let room_count = cx.number(r.room_count as f64);
-                let size = cx.number(r.size as f64);
-                result.set(&mut cx, "eventCount", event_count)?;
-                result.set(&mut cx, "roomCount", room_count)?;
-                result.set(&mut cx, "size", size)?;
-                Ok(result)
-            }
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Ok(repaired) => Ok(cx.boolean(repaired)),
+            Err(e) => throw_seshat_error(&mut cx, "Error repairing the database", &e),
         }
     }
 }
 
-pub(crate) struct GetSizeTask {
-    pub(crate) path: PathBuf,
+pub(crate) struct ShrinkTask {
+    pub(crate) receiver: Receiver<seshat::Result<u64>>,
 }
 
-impl Task for GetSizeTask {
+impl Task for ShrinkTask {
     type Output = u64;
-    type Error = fs_extra::error::Error;
+    type Error = seshat::Error;
     type JsEvent = JsNumber;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        dir::get_size(&self.path)
+        self.receiver
+            .recv()
+            .unwrap_or(Err(seshat::Error::WriterClosedError))
     }
 
     fn complete<'a, 'b>(
@@ -320,29 +1747,64 @@ impl Task for GetSizeTask {
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
-            Ok(r) => Ok(cx.number(r as f64)),
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Ok(reclaimed) => Ok(cx.number(reclaimed as f64)),
+            Err(e) => throw_seshat_error(&mut cx, "Error shrinking the database", &e),
         }
     }
 }
 
-pub(crate) struct DeleteTask {
-    pub(crate) db_path: PathBuf,
-    pub(crate) shutdown_receiver: Receiver<seshat::Result<()>>,
-}
-
-pub(crate) struct ShutDownTask {
-    pub(crate) shutdown_receiver: Receiver<seshat::Result<()>>,
+pub(crate) struct ChangePassphraseTask {
+    pub(crate) this: Root<JsBox<RefCell<Seshat>>>,
+    pub(crate) database: Mutex<Option<seshat::Database>>,
+    /// The tasks of the database, the passphrase is changed once they are
+    /// done.
+    pub(crate) tasks: TaskGuard,
+    pub(crate) config: Config,
+    pub(crate) new_passphrase: String,
+    /// The database, reopened after the passphrase change, together with the
+    /// config that was used to open it.
+    pub(crate) reopened: Mutex<Option<(seshat::Database, Config)>>,
 }
 
-impl Task for ShutDownTask {
+impl Task for ChangePassphraseTask {
     type Output = ();
     type Error = seshat::Error;
     type JsEvent = JsUndefined;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.shutdown_receiver.recv().unwrap()?;
-        Ok(())
+        let database = self
+            .database
+            .lock()
+            .unwrap()
+            .take()
+            .expect("No database found while changing passphrase");
+        let path = database.get_path().to_path_buf();
+
+        // The database is reopened afterwards, the guard stays open so the
+        // tasks of the reopened database can use it.
+        self.tasks.wait_idle(TASK_DRAIN_TIMEOUT);
+
+        let ret = database.change_passphrase(&self.new_passphrase);
+
+        // Changing the passphrase shuts the database down, reopen it so it
+        // stays usable. If the passphrase couldn't be changed, the old one
+        // should still work.
+        let config = if ret.is_ok() {
+            self.config
+                .clone()
+                .set_passphrase(self.new_passphrase.as_str())
+        } else {
+            self.config.clone()
+        };
+
+        match seshat::Database::new_with_config(&path, &config) {
+            Ok(db) => {
+                *self.reopened.lock().unwrap() = Some((db, config));
+                ret
+            }
+            // Prefer reporting the error of the passphrase change.
+            Err(e) => ret.and(Err(e)),
+        }
     }
 
     fn complete<'a, 'b>(
@@ -350,22 +1812,33 @@ impl Task for ShutDownTask {
         mut cx: ComputeContext<'a, 'b>,
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
+        let this = self.this.into_inner(&mut cx);
+
+        if let Some((database, config)) = self.reopened.into_inner().unwrap() {
+            let mut seshat = this.borrow_mut();
+            seshat.database = Some(database);
+            seshat.config = config;
+        }
+
         match result {
             Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Err(e) => throw_seshat_error(&mut cx, "Error while changing the passphrase", &e),
         }
     }
 }
 
-impl Task for DeleteTask {
-    type Output = ();
+pub(crate) struct GetUserVersionTask {
+    pub(crate) pool: ConnectionPool,
+}
+
+impl Task for GetUserVersionTask {
+    type Output = i64;
     type Error = seshat::Error;
-    type JsEvent = JsUndefined;
+    type JsEvent = JsNumber;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.shutdown_receiver.recv().unwrap()?;
-        std::fs::remove_dir_all(self.db_path.clone())?;
-        Ok(())
+        let connection = self.pool.get()?;
+        connection.get_user_version()
     }
 
     fn complete<'a, 'b>(
@@ -374,24 +1847,29 @@ impl Task for DeleteTask {
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
-            Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_type_error(e.to_string()),
+            Ok(version) => {
+                let version = cx.number(version as f64);
+                Ok(version)
+            }
+            Err(e) => throw_seshat_error(&mut cx, "Error while getting the user version", &e),
         }
     }
 }
 
-pub(crate) struct LoadFileEventsTask {
-    pub(crate) inner: Connection,
-    pub(crate) config: LoadConfig,
+pub(crate) struct ExportEventsTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) path: PathBuf,
 }
 
-impl Task for LoadFileEventsTask {
-    type Output = Vec<(String, Profile)>;
+impl Task for ExportEventsTask {
+    type Output = usize;
     type Error = seshat::Error;
-    type JsEvent = JsArray;
+    type JsEvent = JsNumber;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.inner.load_file_events(&self.config)
+        let connection = self.pool.get()?;
+        let file = std::fs::File::create(&self.path)?;
+        connection.dump_events(std::io::BufWriter::new(file))
     }
 
     fn complete<'a, 'b>(
@@ -399,59 +1877,68 @@ impl Task for LoadFileEventsTask {
         mut cx: ComputeContext<'a, 'b>,
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
-        let mut ret = match result {
-            Ok(r) => r,
-            Err(e) => return cx.throw_type_error(e.to_string()),
-        };
+        match result {
+            Ok(count) => Ok(cx.number(count as f64)),
+            Err(e) => throw_seshat_error(&mut cx, "Error exporting the events", &e),
+        }
+    }
+}
 
-        let results = JsArray::new(&mut cx, ret.len() as u32);
+/// A JS function that is called with the progress of a task.
+pub(crate) struct ProgressCallback {
+    pub(crate) channel: Channel,
+    pub(crate) callback: Arc<Root<JsFunction>>,
+}
 
-        for (i, (source, profile)) in ret.drain(..).enumerate() {
-            let result = cx.empty_object();
+impl ProgressCallback {
+    /// Call the function with the given count on the JS thread.
+    fn report(&self, count: usize) {
+        let callback = self.callback.clone();
 
-            let event = deserialize_event(&mut cx, &source)?;
-            let profile = profile_to_js(&mut cx, profile)?;
-            result.set(&mut cx, "event", event)?;
-            result.set(&mut cx, "profile", profile)?;
+        self.channel.send(move |mut cx| {
+            let callback = callback.to_inner(&mut cx);
+            let this = cx.undefined();
+            let count = cx.number(count as f64);
+            callback.call(&mut cx, this, vec![count.upcast()])?;
+            Ok(())
+        });
+    }
 
-            results.set(&mut cx, i as u32, result)?;
+    /// Release the function, the progress reports were queued up before the
+    /// task completes so they don't hold on to it anymore.
+    pub(crate) fn release<'a, C: Context<'a>>(self, cx: &mut C) {
+        if let Ok(callback) = Arc::try_unwrap(self.callback) {
+            callback.drop(cx);
         }
-
-        Ok(results)
     }
 }
 
-pub(crate) struct ReindexTask {
-    pub(crate) inner: Mutex<Option<RecoveryDatabase>>,
+pub(crate) struct ExportSearchTask {
+    pub(crate) inner: Searcher,
+    pub(crate) term: String,
+    pub(crate) config: SearchConfig,
+    pub(crate) path: PathBuf,
+    pub(crate) progress: Option<ProgressCallback>,
 }
 
-impl Task for ReindexTask {
-    type Output = ();
+impl Task for ExportSearchTask {
+    type Output = ExportSummary;
     type Error = seshat::Error;
-    type JsEvent = JsUndefined;
+    type JsEvent = JsObject;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        let mut db = self.inner.lock().unwrap().take().unwrap();
-        db.delete_the_index()?;
-        db.open_index()?;
-
-        let mut events = db.load_events_deserialized(500, None)?;
-        db.index_events(&events)?;
-
-        loop {
-            events = db.load_events_deserialized(500, events.last())?;
-
-            if events.is_empty() {
-                break;
-            }
-
-            db.index_events(&events)?;
-            db.commit()?;
-        }
-
-        db.commit_and_close()?;
-
-        Ok(())
+        let file = std::fs::File::create(&self.path)?;
+
+        self.inner.export_search_with_progress(
+            &self.term,
+            &self.config,
+            std::io::BufWriter::new(file),
+            |count| {
+                if let Some(progress) = &self.progress {
+                    progress.report(count);
+                }
+            },
+        )
     }
 
     fn complete<'a, 'b>(
@@ -459,24 +1946,50 @@ impl Task for ReindexTask {
         mut cx: ComputeContext<'a, 'b>,
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
-        match result {
-            Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_type_error(e.to_string()),
+        if let Some(progress) = self.progress {
+            progress.release(&mut cx);
         }
+
+        let summary = match result {
+            Ok(s) => s,
+            Err(e) => return throw_seshat_error(&mut cx, "Error exporting the search results", &e),
+        };
+
+        let ret = cx.empty_object();
+        let count = cx.number(summary.count as f64);
+        let skipped = cx.number(summary.skipped as f64);
+        let duration = cx.number(summary.duration.as_secs_f64() * 1000.0);
+
+        ret.set(&mut cx, "count", count)?;
+        ret.set(&mut cx, "skipped", skipped)?;
+        ret.set(&mut cx, "duration", duration)?;
+
+        Ok(ret)
     }
 }
 
-pub(crate) struct DeleteEventTask {
-    pub(crate) receiver: Receiver<seshat::Result<bool>>,
+pub(crate) struct ImportEventsTask {
+    pub(crate) import: Mutex<Option<BulkImport>>,
+    pub(crate) pool: ConnectionPool,
+    pub(crate) path: PathBuf,
 }
 
-impl Task for DeleteEventTask {
-    type Output = bool;
+impl Task for ImportEventsTask {
+    type Output = ImportSummary;
     type Error = seshat::Error;
-    type JsEvent = JsBoolean;
+    type JsEvent = JsObject;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.receiver.recv().unwrap()
+        let connection = self.pool.get()?;
+        let import = self
+            .import
+            .lock()
+            .unwrap()
+            .take()
+            .expect("Import task was run twice");
+        let file = std::fs::File::open(&self.path)?;
+
+        import.import_events(&connection, std::io::BufReader::new(file))
     }
 
     fn complete<'a, 'b>(
@@ -484,31 +1997,37 @@ impl Task for DeleteEventTask {
         mut cx: ComputeContext<'a, 'b>,
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
-        match result {
-            Ok(b) => Ok(cx.boolean(b)),
-            Err(e) => cx.throw_error(format!("Error deleting an event: {}", e.to_string())),
-        }
+        let summary = match result {
+            Ok(s) => s,
+            Err(e) => return throw_seshat_error(&mut cx, "Error importing the events", &e),
+        };
+
+        let ret = cx.empty_object();
+        let imported = cx.number(summary.imported as f64);
+        let skipped = cx.number(summary.skipped as f64);
+        let duplicates = cx.number(summary.duplicates as f64);
+
+        ret.set(&mut cx, "imported", imported)?;
+        ret.set(&mut cx, "skipped", skipped)?;
+        ret.set(&mut cx, "duplicates", duplicates)?;
+
+        Ok(ret)
     }
 }
 
-pub(crate) struct ChangePassphraseTask {
-    pub(crate) database: Mutex<Option<seshat::Database>>,
-    pub(crate) new_passphrase: String,
+pub(crate) struct SetUserVersionTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) new_version: i64,
 }
 
-impl Task for ChangePassphraseTask {
+impl Task for SetUserVersionTask {
     type Output = ();
     type Error = seshat::Error;
     type JsEvent = JsUndefined;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        let database = self
-            .database
-            .lock()
-            .unwrap()
-            .take()
-            .expect("No database found while changing passphrase");
-        database.change_passphrase(&self.new_passphrase)
+        let connection = self.pool.get()?;
+        connection.set_user_version(self.new_version)
     }
 
     fn complete<'a, 'b>(
@@ -518,25 +2037,24 @@ impl Task for ChangePassphraseTask {
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
             Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_error(format!(
-                "Error while changing the passphrase: {}",
-                e.to_string()
-            )),
+            Err(e) => throw_seshat_error(&mut cx, "Error while setting the user version", &e),
         }
     }
 }
 
-pub(crate) struct GetUserVersionTask {
-    pub(crate) connection: Connection,
+pub(crate) struct GetWatermarkTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) name: String,
 }
 
-impl Task for GetUserVersionTask {
-    type Output = i64;
+impl Task for GetWatermarkTask {
+    type Output = Option<i64>;
     type Error = seshat::Error;
-    type JsEvent = JsNumber;
+    type JsEvent = JsValue;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.connection.get_user_version()
+        let connection = self.pool.get()?;
+        connection.get_watermark(&self.name)
     }
 
     fn complete<'a, 'b>(
@@ -545,30 +2063,27 @@ impl Task for GetUserVersionTask {
         result: Result<Self::Output, Self::Error>,
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
-            Ok(version) => {
-                let version = cx.number(version as f64);
-                Ok(version)
-            }
-            Err(e) => cx.throw_error(format!(
-                "Error while getting the user version: {}",
-                e.to_string()
-            )),
+            Ok(Some(timestamp)) => Ok(cx.number(timestamp as f64).upcast()),
+            Ok(None) => Ok(cx.null().upcast()),
+            Err(e) => throw_seshat_error(&mut cx, "Error while getting the watermark", &e),
         }
     }
 }
 
-pub(crate) struct SetUserVersionTask {
-    pub(crate) connection: Connection,
-    pub(crate) new_version: i64,
+pub(crate) struct SetWatermarkTask {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) name: String,
+    pub(crate) timestamp: i64,
 }
 
-impl Task for SetUserVersionTask {
+impl Task for SetWatermarkTask {
     type Output = ();
     type Error = seshat::Error;
     type JsEvent = JsUndefined;
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
-        self.connection.set_user_version(self.new_version)
+        let connection = self.pool.get()?;
+        connection.set_watermark(&self.name, self.timestamp)
     }
 
     fn complete<'a, 'b>(
@@ -578,10 +2093,7 @@ impl Task for SetUserVersionTask {
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
             Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_error(format!(
-                "Error while setting the user version: {}",
-                e.to_string()
-            )),
+            Err(e) => throw_seshat_error(&mut cx, "Error while setting the watermark", &e),
         }
     }
 }
@@ -610,7 +2122,10 @@ impl Task for ShutDownRecoveryDatabaseTask {
     ) -> JsResult<'a, Self::JsEvent> {
         match result {
             Ok(_) => Ok(cx.undefined()),
-            Err(e) => cx.throw_error(e.to_string()),
+            Err(e) => {
+                let js_error = cx.error(e.to_string())?;
+                throw_with_code(&mut cx, js_error, e.kind())
+            }
         }
     }
 }
@@ -12,47 +12,609 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::logging::init_file_logging;
 use crate::Seshat;
 use neon::prelude::*;
 use seshat::{
-    CheckpointDirection, Config, CrawlerCheckpoint, Event, EventType, Language, Profile, Receiver,
-    SearchConfig, SearchResult,
+    CheckpointDirection, Config, ContextEvent, CrawlerCheckpoint, ErrorKind, Event, EventBuilder,
+    EventType, EventWithContext, Language, LoadConfig, LoadDirection, Profile, QueryOperator,
+    Reaction, Receiver, RoomAlias, RoomInfo, SearchBatch, SearchConfig, SearchOrder, SearchResult,
+    FILE_MSGTYPES,
 };
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::HashMap, path::PathBuf, time::Duration};
+use tracing::Level;
 use uuid::Uuid;
 
+pub(crate) fn parse_database_path(cx: &mut FunctionContext) -> NeonResult<String> {
+    let path = cx.argument::<JsString>(0)?.value(&mut *cx);
+
+    if path.trim().is_empty() {
+        return cx.throw_type_error("The database path can't be empty");
+    }
+
+    Ok(path)
+}
+
+/// Throw a JS error for a database that couldn't be opened.
+///
+/// The `code` property of the error contains the kind of the underlying
+/// Seshat error, this allows callers to decide if the database should be
+/// recreated.
+pub(crate) fn throw_open_error<'a, C: Context<'a>, T>(
+    cx: &mut C,
+    error: seshat::Error,
+) -> NeonResult<T> {
+    throw_seshat_error(cx, "Error opening the database", &error)
+}
+
+/// Throw a JS error with the kind of a Seshat error attached.
+///
+/// The `code` property of the error contains the stable name of the kind,
+/// e.g. `DATABASE_LOCKED`, which allows callers to react to specific
+/// failures without matching the error message.
+pub(crate) fn throw_with_code<'a, C: Context<'a>, T>(
+    cx: &mut C,
+    error: Handle<'a, JsError>,
+    kind: ErrorKind,
+) -> NeonResult<T> {
+    let code = cx.string(kind.as_str());
+    error.set(cx, "code", code)?;
+
+    cx.throw(error)
+}
+
+pub(crate) const CLOSED_ERROR: &str = "Database has been closed or deleted";
+
+/// Throw a `TypeError` for an operation on a database that has been closed.
+pub(crate) fn throw_closed_error<'a, C: Context<'a>, T>(cx: &mut C) -> NeonResult<T> {
+    let js_error = cx.type_error(CLOSED_ERROR)?;
+    throw_with_code(cx, js_error, ErrorKind::Closed)
+}
+
+/// Throw a `TypeError` for a Seshat error.
+pub(crate) fn throw_seshat_type_error<'a, C: Context<'a>, T>(
+    cx: &mut C,
+    error: &seshat::Error,
+) -> NeonResult<T> {
+    let js_error = cx.type_error(error.to_string())?;
+    throw_with_code(cx, js_error, error.kind())
+}
+
+/// Throw an `Error` for a Seshat error, the message of the Seshat error is
+/// prefixed with a description of the failed operation.
+pub(crate) fn throw_seshat_error<'a, C: Context<'a>, T>(
+    cx: &mut C,
+    operation: &str,
+    error: &seshat::Error,
+) -> NeonResult<T> {
+    let js_error = cx.error(format!("{}: {}", operation, error))?;
+    throw_with_code(cx, js_error, error.kind())
+}
+
+/// The largest integer that a JS number can represent exactly,
+/// `Number.MAX_SAFE_INTEGER`.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Convert a sequence number to a JS number, if a JS number can represent it
+/// exactly.
+fn sequence_as_number(sequence: u64) -> Option<f64> {
+    if sequence <= MAX_SAFE_INTEGER {
+        Some(sequence as f64)
+    } else {
+        None
+    }
+}
+
+/// Convert a sequence number to a JS value.
+///
+/// Sequence numbers are returned as numbers as long as they don't exceed
+/// `Number.MAX_SAFE_INTEGER`, larger ones are returned as decimal strings
+/// instead of silently losing precision.
+pub(crate) fn sequence_to_js<'a, C: Context<'a>>(cx: &mut C, sequence: u64) -> Handle<'a, JsValue> {
+    match sequence_as_number(sequence) {
+        Some(n) => cx.number(n).upcast(),
+        None => cx.string(sequence.to_string()).upcast(),
+    }
+}
+
+/// Parse the name of a query operator, either "and" or "or".
+fn parse_query_operator(operator: &str) -> Option<QueryOperator> {
+    match operator.to_lowercase().as_ref() {
+        "and" => Some(QueryOperator::And),
+        "or" => Some(QueryOperator::Or),
+        _ => None,
+    }
+}
+
+/// The keys that the database config object may contain.
+const DATABASE_CONFIG_KEYS: &[&str] = &[
+    "language",
+    "detectLanguage",
+    "detectedLanguages",
+    "splitIdentifiers",
+    "indexPath",
+    "storePath",
+    "passphrase",
+    "wal",
+    "busyTimeout",
+    "writeQueueCapacity",
+    "autoCommitInterval",
+    "autoCommitAfterEvents",
+    "lockTimeout",
+    "forceUnlock",
+    "retention",
+    "indexedMsgtypes",
+    "indexStateHistory",
+    "storeSources",
+    "sourceEncryptionPassphrase",
+    "compactionThreshold",
+    "autoCompaction",
+    "searchTokenTtl",
+    "autoReload",
+    "connectionPoolSize",
+    "connectionTimeout",
+    "writerHeapSize",
+    "maxQueryLength",
+    "maxQueryTerms",
+    "maxQueryClauses",
+    "recordSearches",
+    "defaultOperator",
+    "leadingWildcards",
+    "maxWildcardTerms",
+    "maxRestrictedIds",
+    "maxIndexedBodyBytes",
+    "truncateStoredBodies",
+    "maxEventSize",
+    "sourceCacheCapacity",
+    "logPath",
+    "logLevel",
+];
+
+/// Get a value from the database config object.
+///
+/// Returns `None` if the value is undefined or null, throws a type error naming
+/// the key if the value has the wrong type.
+fn get_config_value<'a, V: Value>(
+    cx: &mut FunctionContext<'a>,
+    config: Handle<'a, JsObject>,
+    key: &str,
+    type_name: &str,
+) -> NeonResult<Option<Handle<'a, V>>> {
+    let value = config.get_value(&mut *cx, key)?;
+
+    if value.is_a::<JsUndefined, _>(cx) || value.is_a::<JsNull, _>(cx) {
+        return Ok(None);
+    }
+
+    match value.downcast::<V, _>(cx) {
+        Ok(v) => Ok(Some(v)),
+        Err(_) => cx.throw_type_error(format!(
+            "The database config key {} needs to be a {}",
+            key, type_name
+        )),
+    }
+}
+
+fn get_config_path(
+    cx: &mut FunctionContext,
+    config: Handle<JsObject>,
+    key: &str,
+) -> NeonResult<Option<String>> {
+    match get_config_value::<JsString>(cx, config, key, "string")? {
+        Some(v) => {
+            let path = v.value(cx);
+
+            if path.trim().is_empty() {
+                cx.throw_type_error(format!("The database config key {} can't be empty", key))
+            } else {
+                Ok(Some(path))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn get_config_count(
+    cx: &mut FunctionContext,
+    config: Handle<JsObject>,
+    key: &str,
+) -> NeonResult<Option<f64>> {
+    match get_config_value::<JsNumber>(cx, config, key, "number")? {
+        Some(v) => {
+            let value = v.value(cx);
+
+            if value.is_finite() && value >= 0.0 {
+                Ok(Some(value))
+            } else {
+                cx.throw_type_error(format!(
+                    "The database config key {} needs to be a non-negative number",
+                    key
+                ))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
 pub(crate) fn parse_database_config(
     cx: &mut FunctionContext,
     argument: Option<Handle<JsValue>>,
 ) -> Result<Config, neon::result::Throw> {
     let mut config = Config::new();
 
-    if let Some(c) = argument {
-        let c = c.downcast::<JsObject, _>(cx).or_throw(&mut *cx)?;
+    let c = match argument {
+        Some(c) if !c.is_a::<JsUndefined, _>(cx) && !c.is_a::<JsNull, _>(cx) => c
+            .downcast::<JsObject, _>(cx)
+            .or_else(|_| cx.throw_type_error("The database config needs to be an object"))?,
+        _ => return Ok(config),
+    };
+
+    // Reject unknown keys, a typo in e.g. the passphrase key would otherwise
+    // silently create an unencrypted database.
+    let keys = c.get_own_property_names(&mut *cx)?.to_vec(&mut *cx)?;
+
+    for key in keys {
+        let key = key.to_string(&mut *cx)?.value(cx);
+
+        if !DATABASE_CONFIG_KEYS.contains(&key.as_str()) {
+            return cx.throw_type_error(format!("Unknown database config key: {}", key));
+        }
+    }
+
+    if let Some(l) = get_config_value::<JsString>(cx, c, "language", "string")? {
+        let language = Language::from(l.value(cx).as_ref());
+
+        match language {
+            Language::Unknown => {
+                let value = l.value(cx);
+                return cx.throw_type_error(format!("Unsupported language: {}", value));
+            }
+            _ => {
+                config = config.set_language(&language);
+            }
+        }
+    }
+
+    if let Some(d) = get_config_value::<JsBoolean>(cx, c, "detectLanguage", "boolean")? {
+        config = config.set_detect_language(d.value(cx));
+    }
+
+    if let Some(l) = get_config_value::<JsArray>(cx, c, "detectedLanguages", "array")? {
+        let mut languages = Vec::new();
 
-        if let Some(l) = c.get_opt::<JsString, _, _>(&mut *cx, "language")? {
-            let language = Language::from(l.value(cx).as_ref());
+        for language in l.to_vec(&mut *cx)? {
+            let value = language
+                .downcast::<JsString, _>(cx)
+                .or_else(|_| {
+                    cx.throw_type_error(
+                        "The database config key detectedLanguages needs to contain strings",
+                    )
+                })?
+                .value(cx);
 
-            match language {
+            match Language::from(value.as_ref()) {
                 Language::Unknown => {
-                    let value = l.value(cx);
                     return cx.throw_type_error(format!("Unsupported language: {}", value));
                 }
-                _ => {
-                    config = config.set_language(&language);
+                language => languages.push(language),
+            }
+        }
+
+        config = config.set_detected_languages(&languages);
+    }
+
+    if let Some(s) = get_config_value::<JsBoolean>(cx, c, "splitIdentifiers", "boolean")? {
+        config = config.set_split_identifiers(s.value(cx));
+    }
+
+    if let Some(p) = get_config_path(cx, c, "indexPath")? {
+        config = config.set_index_path(p);
+    }
+
+    if let Some(p) = get_config_path(cx, c, "storePath")? {
+        config = config.set_store_path(p);
+    }
+
+    if let Some(p) = get_config_value::<JsString>(cx, c, "passphrase", "string")? {
+        let passphrase: String = p.value(cx);
+        config = config.set_passphrase(passphrase);
+    }
+
+    if let Some(w) = get_config_value::<JsBoolean>(cx, c, "wal", "boolean")? {
+        config = config.set_wal(w.value(cx));
+    }
+
+    if let Some(t) = get_config_count(cx, c, "busyTimeout")? {
+        config = config.set_busy_timeout(Duration::from_millis(t as u64));
+    }
+
+    if let Some(capacity) = get_config_count(cx, c, "writeQueueCapacity")? {
+        config = config.set_write_queue_capacity(capacity as usize);
+    }
+
+    if let Some(t) = get_config_count(cx, c, "autoCommitInterval")? {
+        config = config.set_auto_commit_interval(Some(Duration::from_millis(t as u64)));
+    }
+
+    if let Some(count) = get_config_count(cx, c, "autoCommitAfterEvents")? {
+        config = config.set_auto_commit_after_events(Some(count as usize));
+    }
+
+    if let Some(t) = get_config_count(cx, c, "lockTimeout")? {
+        config = config.set_lock_timeout(Duration::from_millis(t as u64));
+    }
+
+    if let Some(f) = get_config_value::<JsBoolean>(cx, c, "forceUnlock", "boolean")? {
+        config = config.set_force_unlock(f.value(cx));
+    }
+
+    if let Some(t) = get_config_count(cx, c, "retention")? {
+        config = config.set_retention(Some(Duration::from_millis(t as u64)));
+    }
+
+    if let Some(m) = get_config_value::<JsArray>(cx, c, "indexedMsgtypes", "array")? {
+        let mut msgtypes = Vec::new();
+
+        for msgtype in m.to_vec(&mut *cx)? {
+            let msgtype = msgtype
+                .downcast::<JsString, _>(cx)
+                .or_else(|_| {
+                    cx.throw_type_error(
+                        "The database config key indexedMsgtypes needs to contain strings",
+                    )
+                })?
+                .value(cx);
+            msgtypes.push(msgtype);
+        }
+
+        config = config.set_indexed_msgtypes(Some(msgtypes));
+    }
+
+    if let Some(h) = get_config_value::<JsBoolean>(cx, c, "indexStateHistory", "boolean")? {
+        config = config.set_index_state_history(h.value(cx));
+    }
+
+    if let Some(s) = get_config_value::<JsBoolean>(cx, c, "storeSources", "boolean")? {
+        config = config.set_store_sources(s.value(cx));
+    }
+
+    if let Some(p) = get_config_value::<JsString>(cx, c, "sourceEncryptionPassphrase", "string")? {
+        config = config.set_source_encryption_passphrase(Some(p.value(cx)));
+    }
+
+    if let Some(t) = get_config_count(cx, c, "compactionThreshold")? {
+        config = config.set_compaction_threshold(t as usize);
+    }
+
+    if let Some(a) = get_config_value::<JsBoolean>(cx, c, "autoCompaction", "boolean")? {
+        config = config.set_auto_compaction(a.value(cx));
+    }
+
+    if let Some(t) = get_config_count(cx, c, "searchTokenTtl")? {
+        config = config.set_search_token_ttl(Duration::from_millis(t as u64));
+    }
+
+    if let Some(a) = get_config_value::<JsBoolean>(cx, c, "autoReload", "boolean")? {
+        config = config.set_auto_reload(a.value(cx));
+    }
+
+    if let Some(s) = get_config_count(cx, c, "connectionPoolSize")? {
+        config = config.set_connection_pool_size(s.min(u32::MAX as f64) as u32);
+    }
+
+    if let Some(t) = get_config_count(cx, c, "connectionTimeout")? {
+        config = config.set_connection_timeout(Duration::from_millis(t as u64));
+    }
+
+    if let Some(s) = get_config_count(cx, c, "writerHeapSize")? {
+        config = config.set_writer_heap_size(s as usize);
+    }
+
+    if let Some(l) = get_config_count(cx, c, "maxQueryLength")? {
+        config = config.set_max_query_length(l as usize);
+    }
+
+    if let Some(t) = get_config_count(cx, c, "maxQueryTerms")? {
+        config = config.set_max_query_terms(t as usize);
+    }
+
+    if let Some(n) = get_config_count(cx, c, "maxQueryClauses")? {
+        config = config.set_max_query_clauses(n as usize);
+    }
+
+    if let Some(n) = get_config_count(cx, c, "maxWildcardTerms")? {
+        config = config.set_max_wildcard_terms(n as usize);
+    }
+
+    if let Some(n) = get_config_count(cx, c, "maxRestrictedIds")? {
+        config = config.set_max_restricted_ids(n as usize);
+    }
+
+    if let Some(l) = get_config_value::<JsBoolean>(cx, c, "leadingWildcards", "boolean")? {
+        config = config.set_leading_wildcards(l.value(cx));
+    }
+
+    if let Some(r) = get_config_value::<JsBoolean>(cx, c, "recordSearches", "boolean")? {
+        config = config.set_record_searches(r.value(cx));
+    }
+
+    if let Some(o) = get_config_value::<JsString>(cx, c, "defaultOperator", "string")? {
+        let value = o.value(cx);
+
+        match parse_query_operator(&value) {
+            Some(operator) => config = config.set_default_operator(operator),
+            None => {
+                return cx.throw_type_error(format!(
+                    "Invalid defaultOperator {}, needs to be \"and\" or \"or\"",
+                    value
+                ))
+            }
+        }
+    }
+
+    if let Some(n) = get_config_count(cx, c, "maxIndexedBodyBytes")? {
+        config = config.set_max_indexed_body_bytes(n as usize);
+    }
+
+    if let Some(t) = get_config_value::<JsBoolean>(cx, c, "truncateStoredBodies", "boolean")? {
+        config = config.set_truncate_stored_bodies(t.value(cx));
+    }
+
+    if let Some(n) = get_config_count(cx, c, "maxEventSize")? {
+        config = config.set_max_event_size(n as usize);
+    }
+
+    if let Some(n) = get_config_count(cx, c, "sourceCacheCapacity")? {
+        config = config.set_source_cache_capacity(n as usize);
+    }
+
+    // The log subscriber is global to the process, it isn't part of the
+    // database config and is installed as soon as the config is parsed.
+    let log_level = match get_config_value::<JsString>(cx, c, "logLevel", "string")? {
+        Some(l) => {
+            let value = l.value(cx);
+
+            match value.parse::<Level>() {
+                Ok(level) => level,
+                Err(_) => {
+                    return cx.throw_type_error(format!(
+                        "Invalid logLevel {}, needs to be one of \"error\", \"warn\", \"info\", \"debug\" or \"trace\"",
+                        value
+                    ))
                 }
             }
         }
+        None => Level::INFO,
+    };
+
+    if let Some(p) = get_config_value::<JsString>(cx, c, "logPath", "string")? {
+        let path = PathBuf::from(p.value(cx));
 
-        if let Some(p) = c.get_opt::<JsString, _, _>(&mut *cx, "passphrase")? {
-            let passphrase: String = p.value(cx);
-            config = config.set_passphrase(passphrase);
+        if let Err(e) = init_file_logging(&path, log_level) {
+            return cx.throw_type_error(format!("Error opening the log file: {}", e));
         }
     }
 
     Ok(config)
 }
 
+/// The maximum number of results a single search may return.
+const MAX_SEARCH_LIMIT: usize = 1000;
+/// The maximum number of context events a search result may contain, this is
+/// separately applied to the events before and after the result.
+const MAX_CONTEXT_LIMIT: usize = 50;
+/// The maximum number of rooms a search may override the context limits of.
+const MAX_CONTEXT_OVERRIDES: usize = 100;
+/// The default half-life, in days, of the relevance of search results if they
+/// are ordered by relevance with a recency boost.
+const DEFAULT_RECENCY_HALF_LIFE_DAYS: f64 = 30.0;
+/// The maximum half-life, in days, that a recency boost may use.
+const MAX_RECENCY_HALF_LIFE_DAYS: f64 = 36500.0;
+
+/// Parse a numeric search option, the value needs to be an integer between 0
+/// and the given maximum.
+fn parse_search_limit(
+    cx: &mut FunctionContext,
+    argument: Handle<JsObject>,
+    key: &str,
+    max: usize,
+) -> Result<Option<usize>, neon::result::Throw> {
+    let value = match argument.get_opt::<JsValue, _, _>(&mut *cx, key)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let value = value
+        .downcast::<JsNumber, _>(cx)
+        .or_else(|_| {
+            cx.throw_type_error(format!("The search option {} needs to be a number", key))
+        })?
+        .value(cx);
+
+    if !value.is_finite() || value.fract() != 0.0 || value < 0.0 || value > max as f64 {
+        return cx.throw_type_error(format!(
+            "Invalid search option {} {}, needs to be an integer between 0 and {}",
+            key, value, max
+        ));
+    }
+
+    Ok(Some(value as usize))
+}
+
+/// Parse the per room context limits of a search, an object mapping room ids
+/// to objects containing a `before` and an `after` limit.
+///
+/// A limit that is left out falls back to the given global limit of the
+/// search.
+fn parse_context_overrides(
+    cx: &mut FunctionContext,
+    overrides: Handle<JsObject>,
+    (before_limit, after_limit): (usize, usize),
+) -> NeonResult<HashMap<String, (usize, usize)>> {
+    let room_ids = overrides
+        .get_own_property_names(&mut *cx)?
+        .to_vec(&mut *cx)?;
+
+    if room_ids.len() > MAX_CONTEXT_OVERRIDES {
+        return cx.throw_type_error(format!(
+            "The context limits of at most {} rooms can be overridden",
+            MAX_CONTEXT_OVERRIDES
+        ));
+    }
+
+    let mut ret = HashMap::with_capacity(room_ids.len());
+
+    for room_id in room_ids {
+        let room_id = room_id
+            .downcast::<JsString, _>(cx)
+            .or_else(|_| cx.throw_type_error("The context overrides need to be keyed by room id"))?
+            .value(cx);
+
+        if room_id.len() < 2 || !room_id.starts_with('!') {
+            return cx.throw_type_error(format!(
+                "The context override for {} isn't keyed by a valid room id",
+                room_id
+            ));
+        }
+
+        let limits = overrides
+            .get::<JsValue, _, _>(&mut *cx, room_id.as_str())?
+            .downcast::<JsObject, _>(cx)
+            .or_else(|_| {
+                cx.throw_type_error(format!(
+                    "The context override for {} needs to be an object",
+                    room_id
+                ))
+            })?;
+
+        let before =
+            parse_search_limit(cx, limits, "before", MAX_CONTEXT_LIMIT)?.unwrap_or(before_limit);
+        let after =
+            parse_search_limit(cx, limits, "after", MAX_CONTEXT_LIMIT)?.unwrap_or(after_limit);
+
+        ret.insert(room_id, (before, after));
+    }
+
+    Ok(ret)
+}
+
+/// Parse a context limit that is passed as the argument at the given index,
+/// the limit needs to be an integer between 0 and the maximal context limit
+/// of searches.
+pub(crate) fn parse_context_limit(cx: &mut FunctionContext, index: i32) -> NeonResult<usize> {
+    let value = cx.argument::<JsNumber>(index)?.value(&mut *cx);
+
+    if !value.is_finite() || value.fract() != 0.0 || value < 0.0 || value > MAX_CONTEXT_LIMIT as f64
+    {
+        return cx.throw_type_error(format!(
+            "Invalid context limit {}, needs to be an integer between 0 and {}",
+            value, MAX_CONTEXT_LIMIT
+        ));
+    }
+
+    Ok(value as usize)
+}
+
 pub(crate) fn parse_search_object(
     cx: &mut FunctionContext,
     argument: Handle<JsObject>,
@@ -63,27 +625,144 @@ pub(crate) fn parse_search_object(
 
     let mut config = SearchConfig::new();
 
-    if let Some(v) = argument.get_opt::<JsNumber, _, _>(&mut *cx, "limit")? {
-        config.limit(v.value(cx) as usize);
+    if let Some(v) = parse_search_limit(cx, argument, "limit", MAX_SEARCH_LIMIT)? {
+        config.limit(v);
     }
 
-    if let Some(v) = argument.get_opt::<JsNumber, _, _>(&mut *cx, "before_limit")? {
-        config.before_limit(v.value(cx) as usize);
+    let before_limit = parse_search_limit(cx, argument, "before_limit", MAX_CONTEXT_LIMIT)?;
+    let after_limit = parse_search_limit(cx, argument, "after_limit", MAX_CONTEXT_LIMIT)?;
+
+    if let Some(v) = before_limit {
+        config.before_limit(v);
     }
 
-    if let Some(v) = argument.get_opt::<JsNumber, _, _>(&mut *cx, "after_limit")? {
-        config.after_limit(v.value(cx) as usize);
+    if let Some(v) = after_limit {
+        config.after_limit(v);
     }
 
-    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "order_by_recency")? {
-        config.order_by_recency(v.value(cx));
+    if let Some(o) = argument.get_opt::<JsObject, _, _>(&mut *cx, "context_overrides")? {
+        let defaults = (before_limit.unwrap_or(0), after_limit.unwrap_or(0));
+        config.context_overrides(parse_context_overrides(cx, o, defaults)?);
+    }
+
+    if let Some(v) = argument.get_opt::<JsValue, _, _>(&mut *cx, "order_by_recency")? {
+        if let Ok(v) = v.downcast::<JsBoolean, _>(cx) {
+            config.order_by_recency(v.value(cx));
+        } else if v
+            .downcast::<JsString, _>(cx)
+            .map_or(false, |v| v.value(cx) == "boosted")
+        {
+            let half_life_days =
+                match argument.get_opt::<JsNumber, _, _>(&mut *cx, "recency_half_life_days")? {
+                    Some(d) => d.value(cx),
+                    None => DEFAULT_RECENCY_HALF_LIFE_DAYS,
+                };
+
+            if !(half_life_days > 0.0 && half_life_days <= MAX_RECENCY_HALF_LIFE_DAYS) {
+                return cx.throw_type_error(format!(
+                    "Invalid search option recency_half_life_days {}, needs to be a \
+                     positive number of at most {}",
+                    half_life_days, MAX_RECENCY_HALF_LIFE_DAYS
+                ));
+            }
+
+            config.order_by(SearchOrder::RecencyBoosted {
+                half_life: Duration::from_secs_f64(half_life_days * 24.0 * 60.0 * 60.0),
+            });
+        } else {
+            return cx.throw_type_error(
+                "The search option order_by_recency needs to be a boolean or \"boosted\"",
+            );
+        }
     }
 
     if let Some(r) = argument.get_opt::<JsString, _, _>(&mut *cx, "room_id")? {
         config.for_room(&r.value(cx));
     }
 
-    if let Some(t) = argument.get_opt::<JsString, _, _>(&mut *cx, "next_batch")? {
+    if let Some(t) = argument.get_opt::<JsString, _, _>(&mut *cx, "thread_id")? {
+        config.for_thread(&t.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "group_by_room")? {
+        config.group_by_room(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "group_rooms")? {
+        config.group_rooms(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "search_senders")? {
+        config.search_senders(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "normalize_scores")? {
+        config.normalize_scores(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "boost_by_reactions")? {
+        config.boost_by_reactions(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "include_timing")? {
+        config.include_timing(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "include_context")? {
+        config.include_context(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "include_profiles")? {
+        config.include_profiles(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "include_redacted_stubs")? {
+        config.include_redacted_stubs(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "require_context")? {
+        config.require_context(v.value(cx));
+    }
+
+    if let Some(t) = argument.get_opt::<JsValue, _, _>(&mut *cx, "timeout")? {
+        let value = t
+            .downcast::<JsNumber, _>(cx)
+            .or_else(|_| cx.throw_type_error("The search option timeout needs to be a number"))?
+            .value(cx);
+
+        if !value.is_finite() || value < 0.0 {
+            return cx.throw_type_error(format!(
+                "Invalid search option timeout {}, needs to be a non-negative number",
+                value
+            ));
+        }
+
+        config.timeout(Duration::from_secs_f64(value / 1000.0));
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "advanced_syntax")? {
+        config.advanced_syntax(v.value(cx));
+    }
+
+    if let Some(o) = argument.get_opt::<JsString, _, _>(&mut *cx, "default_operator")? {
+        let value = o.value(cx);
+
+        match parse_query_operator(&value) {
+            Some(operator) => {
+                config.default_operator(operator);
+            }
+            None => {
+                return cx.throw_type_error(format!(
+                    "Invalid search option default_operator {}, needs to be \"and\" or \"or\"",
+                    value
+                ))
+            }
+        }
+    }
+
+    let next_batch = argument.get_opt::<JsString, _, _>(&mut *cx, "next_batch")?;
+
+    if let Some(t) = next_batch {
         let token = if let Ok(t) = Uuid::parse_str(&t.value(cx)) {
             t
         } else {
@@ -100,20 +779,166 @@ pub(crate) fn parse_search_object(
         for key in keys.drain(..) {
             let key = key
                 .downcast::<JsString, _>(cx)
-                .or_throw(&mut *cx)?
+                .or_else(|_| cx.throw_type_error("Search keys need to be strings"))?
                 .value(cx);
             match key.as_ref() {
                 "content.body" => config.with_key(EventType::Message),
                 "content.topic" => config.with_key(EventType::Topic),
                 "content.name" => config.with_key(EventType::Name),
-                _ => return cx.throw_type_error(format!("Invalid search key {}", key)),
+                _ => {
+                    return cx.throw_type_error(format!(
+                        "Invalid search key {}, supported keys are \
+                         content.body, content.topic and content.name",
+                        key
+                    ))
+                }
             };
         }
     }
 
+    if let Some(e) = argument.get_opt::<JsArray, _, _>(&mut *cx, "restrict_to_events")? {
+        let js_ids: Vec<Handle<JsValue>> = e.to_vec(&mut *cx)?;
+        let mut event_ids = Vec::with_capacity(js_ids.len());
+
+        for id in js_ids {
+            let id = id
+                .downcast::<JsString, _>(cx)
+                .or_else(|_| cx.throw_type_error("The restricted event ids need to be strings"))?
+                .value(cx);
+            event_ids.push(id);
+        }
+
+        config.restrict_to_events(event_ids);
+    }
+
+    if let Some(s) = argument.get_opt::<JsArray, _, _>(&mut *cx, "not_senders")? {
+        let js_senders: Vec<Handle<JsValue>> = s.to_vec(&mut *cx)?;
+        let mut senders = Vec::with_capacity(js_senders.len());
+
+        for sender in js_senders {
+            let sender = sender
+                .downcast::<JsString, _>(cx)
+                .or_else(|_| cx.throw_type_error("The excluded senders need to be strings"))?
+                .value(cx);
+
+            if sender.len() < 2 || !sender.starts_with('@') || !sender.contains(':') {
+                return cx.throw_type_error(format!(
+                    "The excluded sender {} isn't a valid user id",
+                    sender
+                ));
+            }
+
+            senders.push(sender);
+        }
+
+        config.not_senders(senders);
+    }
+
+    if let Some(v) = argument.get_opt::<JsBoolean, _, _>(&mut *cx, "contains_url")? {
+        config.contains_url(v.value(cx));
+    }
+
+    if let Some(v) = argument.get_opt::<JsNumber, _, _>(&mut *cx, "after_timestamp")? {
+        let timestamp = v.value(cx);
+
+        if !timestamp.is_finite() {
+            return cx.throw_type_error("The after_timestamp needs to be a number");
+        }
+
+        config.after_timestamp(timestamp as i64);
+    }
+
+    if let Some(v) = argument.get_opt::<JsString, _, _>(&mut *cx, "after_watermark")? {
+        let name = v.value(cx);
+
+        if name.is_empty() {
+            return cx.throw_type_error("The after_watermark can't be empty");
+        }
+
+        config.after_watermark(name);
+    }
+
+    let term = term.trim().to_owned();
+
+    // Continuing a search reuses the term of the first batch.
+    if next_batch.is_none()
+        && !config.allows_empty_term()
+        && !term.chars().any(char::is_alphanumeric)
+    {
+        return cx.throw_type_error("The search term can't be empty");
+    }
+
     Ok((term, config))
 }
 
+/// The number of file events that are loaded if no limit is given.
+const DEFAULT_FILE_EVENTS_LIMIT: usize = 10;
+/// The maximum number of file events a single call may load.
+const MAX_FILE_EVENTS_LIMIT: usize = 100;
+
+/// Parse the arguments object of a `loadFileEvents()` call into a
+/// `LoadConfig`.
+pub(crate) fn parse_load_config(
+    cx: &mut FunctionContext,
+    argument: Handle<JsObject>,
+) -> NeonResult<LoadConfig> {
+    let room_id = argument
+        .get_opt::<JsValue, _, _>(&mut *cx, "roomId")?
+        .and_then(|r| r.downcast::<JsString, _>(cx).ok())
+        .map(|r| r.value(cx));
+
+    let room_id = match room_id {
+        Some(r) if !r.is_empty() => r,
+        _ => return cx.throw_type_error("The option roomId needs to be a non-empty string"),
+    };
+
+    let limit = match argument.get_opt::<JsValue, _, _>(&mut *cx, "limit")? {
+        Some(l) => {
+            let limit = l
+                .downcast::<JsNumber, _>(cx)
+                .or_else(|_| cx.throw_type_error("The option limit needs to be a number"))?
+                .value(cx);
+
+            if !limit.is_finite() || limit.fract() != 0.0 || limit < 1.0 {
+                return cx.throw_type_error(format!(
+                    "Invalid limit {}, needs to be a positive integer",
+                    limit
+                ));
+            }
+
+            (limit as usize).min(MAX_FILE_EVENTS_LIMIT)
+        }
+        None => DEFAULT_FILE_EVENTS_LIMIT,
+    };
+
+    let mut config = LoadConfig::new(room_id).limit(limit);
+
+    if let Some(e) = argument.get_opt::<JsValue, _, _>(&mut *cx, "fromEvent")? {
+        let event_id = e
+            .downcast::<JsString, _>(cx)
+            .or_else(|_| cx.throw_type_error("The option fromEvent needs to be a string"))?
+            .value(cx);
+        config = config.from_event(event_id);
+    }
+
+    if let Some(d) = argument.get_opt::<JsValue, _, _>(&mut *cx, "direction")? {
+        let direction = d
+            .downcast::<JsString, _>(cx)
+            .or_else(|_| cx.throw_type_error("The option direction needs to be a string"))?
+            .value(cx);
+
+        let direction = match direction.to_lowercase().as_ref() {
+            "backwards" | "backward" | "b" | "" => LoadDirection::Backwards,
+            "forwards" | "forward" | "f" => LoadDirection::Forwards,
+            d => return cx.throw_type_error(format!("Unknown load direction {}", d)),
+        };
+
+        config = config.direction(direction);
+    }
+
+    Ok(config)
+}
+
 pub(crate) fn parse_checkpoint(
     cx: &mut FunctionContext,
     argument: Option<Handle<JsValue>>,
@@ -130,132 +955,570 @@ pub(crate) fn parse_checkpoint(
     }
 }
 
+/// Add a batch of historic events given as the arguments of the function.
+///
+/// The arguments are the events, the new and the old checkpoint and an
+/// optional options object. If the `allowPartial` option is set, events that
+/// can't be parsed are skipped instead of failing the whole batch, the
+/// indices of the skipped events and the reason they were skipped are
+/// returned alongside the receiver.
 pub(crate) fn add_historic_events_helper(
     cx: &mut FunctionContext,
-) -> Result<Receiver<seshat::Result<bool>>, neon::result::Throw> {
+) -> Result<(Receiver<seshat::Result<bool>>, Option<Vec<(usize, String)>>), neon::result::Throw> {
     let js_events = cx.argument::<JsArray>(1)?;
-    let mut js_events: Vec<Handle<JsValue>> = js_events.to_vec(cx)?;
+    let js_events: Vec<Handle<JsValue>> = js_events.to_vec(cx)?;
+
+    let js_checkpoint = cx.argument_opt(2);
+    let new_checkpoint: Option<CrawlerCheckpoint> = parse_checkpoint(cx, js_checkpoint)?;
+
+    let js_checkpoint = cx.argument_opt(3);
+    let old_checkpoint: Option<CrawlerCheckpoint> = parse_checkpoint(cx, js_checkpoint)?;
+
+    let options = cx
+        .argument_opt(4)
+        .and_then(|o| o.downcast::<JsObject, _>(cx).ok());
+
+    let allow_partial = match options {
+        Some(options) => options
+            .get_opt::<JsBoolean, _, _>(&mut *cx, "allowPartial")?
+            .map_or(false, |v| v.value(cx)),
+        None => false,
+    };
+
+    let mut events: Vec<(Event, Profile)> = Vec::with_capacity(js_events.len());
+
+    let skipped = if allow_partial {
+        let mut skipped = Vec::new();
+
+        for (i, obj) in js_events.into_iter().enumerate() {
+            match cx.try_catch(|cx| parse_event_with_profile(cx, obj)) {
+                Ok(event) => events.push(event),
+                Err(e) => skipped.push((i, error_message(cx, e)?)),
+            }
+        }
+
+        Some(skipped)
+    } else {
+        for obj in js_events {
+            events.push(parse_event_with_profile(cx, obj)?);
+        }
+
+        None
+    };
+
+    let receiver = {
+        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
+        let db = &this.borrow_mut().database;
+        db.as_ref()
+            .map(|db| db.add_historic_events(events, new_checkpoint, old_checkpoint))
+    };
+
+    let receiver = match receiver {
+        Some(r) => r,
+        None => return throw_closed_error(cx),
+    };
+
+    Ok((receiver, skipped))
+}
+
+/// Get the message of a caught JS exception.
+pub(crate) fn error_message<'a, C: Context<'a>>(
+    cx: &mut C,
+    error: Handle<'a, JsValue>,
+) -> NeonResult<String> {
+    Ok(match error.downcast::<JsError, _>(cx) {
+        Ok(e) => e.get::<JsString, _, _>(&mut *cx, "message")?.value(cx),
+        Err(_) => error.to_string(cx)?.value(cx),
+    })
+}
+
+/// Convert the result of adding historic events to a JS value.
+///
+/// Without a list of skipped events the result is a boolean that tells if
+/// any of the events was new, otherwise it's an object of the form
+/// `{added, skipped: [{index, reason}]}`.
+pub(crate) fn backlog_result_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    added: bool,
+    skipped: Option<&[(usize, String)]>,
+) -> JsResult<'a, JsValue> {
+    let skipped = match skipped {
+        Some(s) => s,
+        None => return Ok(cx.boolean(added).upcast()),
+    };
+
+    let js_skipped = JsArray::new(cx, skipped.len() as u32);
+
+    for (i, (index, reason)) in skipped.iter().enumerate() {
+        let object = cx.empty_object();
+        let index = cx.number(*index as f64);
+        let reason = cx.string(reason);
+
+        object.set(&mut *cx, "index", index)?;
+        object.set(&mut *cx, "reason", reason)?;
+        js_skipped.set(&mut *cx, i as u32, object)?;
+    }
+
+    let result = cx.empty_object();
+    let added = cx.boolean(added);
+
+    result.set(&mut *cx, "added", added)?;
+    result.set(&mut *cx, "skipped", js_skipped)?;
+
+    Ok(result.upcast())
+}
+
+/// Parse an object of the form `{event, profile?, source?}` into an event and
+/// the profile of its sender.
+///
+/// The source is the event serialized as a JSON string, if it's given the
+/// event doesn't need to be serialized again.
+pub(crate) fn parse_event_with_profile(
+    cx: &mut FunctionContext,
+    obj: Handle<JsValue>,
+) -> Result<(Event, Profile), neon::result::Throw> {
+    let obj = obj.downcast::<JsObject, _>(cx).or_throw(cx)?;
+
+    let source = obj
+        .get_opt::<JsValue, _, _>(cx, "source")?
+        .filter(|s| !s.is_a::<JsNull, _>(cx))
+        .map(|s| {
+            s.downcast::<JsString, _>(cx)
+                .or_else(|_| cx.throw_type_error("Event has an invalid source"))
+                .map(|s| s.value(cx))
+        })
+        .transpose()?;
+
+    let event = obj.get::<JsObject, _, _>(cx, "event")?;
+    let event = parse_event_with_source(cx, event, source)?;
+
+    let profile = obj.get_value(cx, "profile")?;
+    let profile = parse_optional_profile(cx, Some(profile))?;
+
+    Ok((event, profile))
+}
+
+pub(crate) fn deserialize_event<'a, C: Context<'a>>(
+    cx: &mut C,
+    source: &str,
+) -> Result<Handle<'a, JsValue>, neon::result::Throw> {
+    let source = serde_json::from_str(source);
+    let source: serde_json::Value = match source {
+        Ok(s) => s,
+        Err(e) => {
+            return cx.throw_type_error(format!(
+                "Couldn't load the event from the store: {}",
+                e.to_string()
+            ))
+        }
+    };
+
+    let ret = match neon_serde3::to_value(&mut *cx, &source) {
+        Ok(v) => v,
+        Err(e) => return cx.throw_error::<_, _>(e.to_string()),
+    };
+
+    Ok(ret)
+}
+
+pub(crate) fn highlights_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    highlights: &[String],
+) -> JsResult<'a, JsArray> {
+    let js_highlights = JsArray::new(cx, highlights.len() as u32);
+
+    for (i, highlight) in highlights.iter().enumerate() {
+        let highlight = cx.string(highlight);
+        js_highlights.set(cx, i as u32, highlight)?;
+    }
+
+    Ok(js_highlights)
+}
+
+/// A context event whose source was deserialized ahead of its conversion to
+/// JS.
+pub(crate) struct ParsedContextEvent {
+    event_id: String,
+    server_ts: i64,
+    insert_id: i64,
+    source: serde_json::Value,
+}
+
+/// Deserialize the sources of a list of context events.
+///
+/// Events that fail to deserialize are left out, the number of skipped
+/// events is added to `skipped`.
+fn parse_context_events(events: Vec<ContextEvent>, skipped: &mut usize) -> Vec<ParsedContextEvent> {
+    let mut parsed = Vec::with_capacity(events.len());
+
+    for event in events {
+        match serde_json::from_str(&event.source) {
+            Ok(source) => parsed.push(ParsedContextEvent {
+                event_id: event.event_id,
+                server_ts: event.server_ts,
+                insert_id: event.insert_id,
+                source,
+            }),
+            Err(_) => *skipped += 1,
+        }
+    }
+
+    parsed
+}
+
+/// A search result whose event sources were deserialized ahead of its
+/// conversion to JS.
+pub(crate) struct ParsedSearchResult {
+    result: SearchResult,
+    event: serde_json::Value,
+    events_before: Vec<ParsedContextEvent>,
+    events_after: Vec<ParsedContextEvent>,
+}
+
+/// A batch of search results whose event sources were deserialized ahead of
+/// its conversion to JS.
+///
+/// Deserializing the sources is the expensive part of the conversion, it
+/// doesn't need a JS context and can happen on the worker thread of a task,
+/// leaving only the construction of the JS objects to the JS thread.
+pub(crate) struct ParsedSearchBatch {
+    batch: SearchBatch,
+    results: Vec<ParsedSearchResult>,
+    skipped: usize,
+}
+
+impl ParsedSearchBatch {
+    /// Deserialize the sources of the results of a batch and of their
+    /// context.
+    ///
+    /// Results whose event can't be deserialized are dropped, context events
+    /// that can't be deserialized are left out. Both are counted as skipped.
+    pub(crate) fn parse(mut batch: SearchBatch) -> Self {
+        let mut results = Vec::with_capacity(batch.results.len());
+        let mut skipped = 0;
+
+        for mut result in batch.results.drain(..) {
+            let event = match serde_json::from_str(&result.event_source) {
+                Ok(e) => e,
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            // The sources aren't needed anymore once they are deserialized,
+            // don't keep them around until the conversion is done.
+            result.event_source = String::new();
+            let events_before =
+                parse_context_events(std::mem::take(&mut result.events_before), &mut skipped);
+            let events_after =
+                parse_context_events(std::mem::take(&mut result.events_after), &mut skipped);
+
+            results.push(ParsedSearchResult {
+                result,
+                event,
+                events_before,
+                events_after,
+            });
+        }
+
+        ParsedSearchBatch {
+            batch,
+            results,
+            skipped,
+        }
+    }
+}
+
+/// The property names of the objects that describe context events.
+///
+/// A search result can contain many context events, their property names
+/// are created once per conversion and shared by every object instead of
+/// being created again for every single one.
+struct ContextInfoKeys<'a> {
+    event_id: Handle<'a, JsString>,
+    server_ts: Handle<'a, JsString>,
+    insert_id: Handle<'a, JsString>,
+}
+
+impl<'a> ContextInfoKeys<'a> {
+    fn new<C: Context<'a>>(cx: &mut C) -> Self {
+        ContextInfoKeys {
+            event_id: cx.string("event_id"),
+            server_ts: cx.string("server_ts"),
+            insert_id: cx.string("insert_id"),
+        }
+    }
+}
+
+/// Convert a list of context events to JS arrays, one containing the
+/// deserialized sources of the events and one containing their ids,
+/// timestamps and insert ids, both in the order of the given events.
+fn events_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    events: &[ParsedContextEvent],
+    keys: &ContextInfoKeys<'a>,
+) -> NeonResult<(Handle<'a, JsArray>, Handle<'a, JsArray>)> {
+    let js_events = JsArray::new(cx, events.len() as u32);
+    let js_info = JsArray::new(cx, events.len() as u32);
+
+    for (i, event) in events.iter().enumerate() {
+        let js_event = match neon_serde3::to_value(&mut *cx, &event.source) {
+            Ok(v) => v,
+            Err(e) => return cx.throw_error::<_, _>(e.to_string()),
+        };
+
+        let info = cx.empty_object();
+        let event_id = cx.string(&event.event_id);
+        let server_ts = cx.number(event.server_ts as f64);
+        let insert_id = cx.number(event.insert_id as f64);
+        info.set(&mut *cx, keys.event_id, event_id)?;
+        info.set(&mut *cx, keys.server_ts, server_ts)?;
+        info.set(&mut *cx, keys.insert_id, insert_id)?;
+
+        js_events.set(&mut *cx, i as u32, js_event)?;
+        js_info.set(&mut *cx, i as u32, info)?;
+    }
+
+    Ok((js_events, js_info))
+}
+
+/// Convert a search result to a JS object.
+fn search_result_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    parsed: ParsedSearchResult,
+    keys: &ContextInfoKeys<'a>,
+) -> JsResult<'a, JsObject> {
+    let ParsedSearchResult {
+        mut result,
+        event,
+        events_before,
+        events_after,
+    } = parsed;
+
+    let rank = cx.number(f64::from(result.score));
+
+    let event = match neon_serde3::to_value(&mut *cx, &event) {
+        Ok(v) => v,
+        Err(e) => return cx.throw_error::<_, _>(e.to_string()),
+    };
+
+    let object = cx.empty_object();
+    let context = context_to_js(
+        cx,
+        &events_before,
+        &events_after,
+        result.profile_info.drain(),
+        keys,
+    )?;
+
+    let highlights = highlights_to_js(cx, &result.highlights)?;
+    let matched_fields = highlights_to_js(cx, &result.matched_fields)?;
+
+    // A hit that matched in its content as well as in its sender is reported
+    // as a hit of the content field.
+    let matched_field: Handle<JsValue> = match result
+        .matched_fields
+        .iter()
+        .find(|f| *f != "sender")
+        .or_else(|| result.matched_fields.first())
+    {
+        Some(f) => cx.string(f).upcast(),
+        None => cx.null().upcast(),
+    };
+    let source_available = cx.boolean(result.source_available);
+    let room_display_name: Handle<JsValue> = match &result.room_display_name {
+        Some(name) => cx.string(name).upcast(),
+        None => cx.null().upcast(),
+    };
 
-    let js_checkpoint = cx.argument_opt(2);
-    let new_checkpoint: Option<CrawlerCheckpoint> = parse_checkpoint(cx, js_checkpoint)?;
+    let context_truncated = cx.empty_object();
+    let before = cx.boolean(result.context_truncated.before);
+    let after = cx.boolean(result.context_truncated.after);
+    context_truncated.set(&mut *cx, "before", before)?;
+    context_truncated.set(&mut *cx, "after", after)?;
 
-    let js_checkpoint = cx.argument_opt(3);
-    let old_checkpoint: Option<CrawlerCheckpoint> = parse_checkpoint(cx, js_checkpoint)?;
+    object.set(&mut *cx, "rank", rank)?;
 
-    let mut events: Vec<(Event, Profile)> = Vec::new();
+    if let Some(raw_score) = result.raw_score {
+        let raw_score = cx.number(f64::from(raw_score));
+        object.set(&mut *cx, "raw_score", raw_score)?;
+    }
 
-    for obj in js_events.drain(..) {
-        let obj = obj.downcast::<JsObject, _>(cx).or_throw(cx)?;
+    object.set(&mut *cx, "result", event)?;
+    object.set(&mut *cx, "context", context)?;
+    object.set(&mut *cx, "highlights", highlights)?;
+    object.set(&mut *cx, "matched_fields", matched_fields)?;
+    object.set(&mut *cx, "matched_field", matched_field)?;
+    object.set(&mut *cx, "source_available", source_available)?;
+    object.set(&mut *cx, "room_display_name", room_display_name)?;
+    object.set(&mut *cx, "context_truncated", context_truncated)?;
 
-        let event = obj.get::<JsObject, _, _>(cx, "event")?;
-        let event = parse_event(cx, event)?;
+    Ok(object)
+}
 
-        let profile: Profile = match obj.get_opt::<JsObject, _, _>(cx, "profile")? {
-            Some(p) => parse_profile(cx, p)?,
-            None => Profile {
-                displayname: None,
-                avatar_url: None,
-            },
-        };
+/// Convert the context of an event to a JS object containing the events
+/// before and after the event and the profiles of their senders.
+///
+/// Both lists of events are ordered by their distance to the event, the
+/// closest one first. The `events_before_info` and `events_after_info`
+/// arrays contain the ids and timestamps of the events at the same position.
+fn context_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    events_before: &[ParsedContextEvent],
+    events_after: &[ParsedContextEvent],
+    profiles: impl Iterator<Item = (String, Profile)>,
+    keys: &ContextInfoKeys<'a>,
+) -> JsResult<'a, JsObject> {
+    let context = cx.empty_object();
 
-        events.push((event, profile));
-    }
+    let (before, before_info) = events_to_js(cx, events_before, keys)?;
+    let (after, after_info) = events_to_js(cx, events_after, keys)?;
+    let profile_info = cx.empty_object();
 
-    let receiver = {
-        let this = cx.argument::<JsBox<RefCell<Seshat>>>(0)?;
-        let db = &this.borrow_mut().database;
-        db.as_ref().map_or_else(
-            || Err("Database has been deleted"),
-            |db| Ok(db.add_historic_events(events, new_checkpoint, old_checkpoint)),
-        )
-    };
+    for (sender, profile) in profiles {
+        let (js_sender, js_profile) = sender_and_profile_to_js(cx, sender, profile)?;
+        profile_info.set(&mut *cx, js_sender, js_profile)?;
+    }
 
-    let receiver = match receiver {
-        Ok(r) => r,
-        Err(e) => return cx.throw_type_error(e),
-    };
+    context.set(&mut *cx, "events_before", before)?;
+    context.set(&mut *cx, "events_after", after)?;
+    context.set(&mut *cx, "events_before_info", before_info)?;
+    context.set(&mut *cx, "events_after_info", after_info)?;
+    context.set(&mut *cx, "profile_info", profile_info)?;
 
-    Ok(receiver)
+    Ok(context)
 }
 
-pub(crate) fn deserialize_event<'a, C: Context<'a>>(
+/// Convert an event and its context to a JS object, shaped like a search
+/// result without the search specific properties.
+///
+/// Context events that can't be deserialized are skipped, the `skipped`
+/// property of the object contains their number.
+pub(crate) fn event_with_context_to_js<'a, C: Context<'a>>(
     cx: &mut C,
-    source: &str,
-) -> Result<Handle<'a, JsValue>, neon::result::Throw> {
-    let source = serde_json::from_str(source);
-    let source: serde_json::Value = match source {
-        Ok(s) => s,
-        Err(e) => {
-            return cx.throw_type_error(format!(
-                "Couldn't load the event from the store: {}",
-                e.to_string()
-            ))
-        }
+    context: EventWithContext,
+) -> JsResult<'a, JsObject> {
+    let event: serde_json::Value = match serde_json::from_str(&context.event_source) {
+        Ok(e) => e,
+        Err(e) => return cx.throw_type_error(format!("Unable to parse the event {}", e)),
     };
-
-    let ret = match neon_serde3::to_value(&mut *cx, &source) {
+    let event = match neon_serde3::to_value(&mut *cx, &event) {
         Ok(v) => v,
         Err(e) => return cx.throw_error::<_, _>(e.to_string()),
     };
 
-    Ok(ret)
+    let mut skipped = 0;
+    let events_before = parse_context_events(context.events_before, &mut skipped);
+    let events_after = parse_context_events(context.events_after, &mut skipped);
+    let keys = ContextInfoKeys::new(cx);
+
+    let js_context = context_to_js(
+        cx,
+        &events_before,
+        &events_after,
+        context.profile_info.into_iter(),
+        &keys,
+    )?;
+    let skipped = cx.number(skipped as f64);
+
+    let object = cx.empty_object();
+    object.set(&mut *cx, "result", event)?;
+    object.set(&mut *cx, "context", js_context)?;
+    object.set(&mut *cx, "skipped", skipped)?;
+
+    Ok(object)
 }
 
-pub(crate) fn search_result_to_js<'a, C: Context<'a>>(
+/// Convert a batch of search results to a JS object.
+///
+/// Events that are stored in the database but can't be deserialized are
+/// skipped, the `skipped` property of the object contains the number of
+/// skipped events, see `ParsedSearchBatch::parse()`, and the number of
+/// results the search left out because their context couldn't be loaded.
+pub(crate) fn search_batch_to_js<'a, C: Context<'a>>(
     cx: &mut C,
-    mut result: SearchResult,
-) -> Result<Handle<'a, JsObject>, neon::result::Throw> {
-    let rank = cx.number(f64::from(result.score));
+    parsed: ParsedSearchBatch,
+) -> JsResult<'a, JsObject> {
+    let ParsedSearchBatch {
+        batch,
+        results: parsed_results,
+        skipped,
+    } = parsed;
+
+    let results = JsArray::new(cx, parsed_results.len() as u32);
+    let count = cx.number(batch.count as f64);
+    let keys = ContextInfoKeys::new(cx);
+
+    for (i, result) in parsed_results.into_iter().enumerate() {
+        let object = search_result_to_js(cx, result, &keys)?;
+        results.set(&mut *cx, i as u32, object)?;
+    }
 
-    let event = deserialize_event(cx, &result.event_source)?;
+    let search_result = cx.empty_object();
+    let highlights = highlights_to_js(cx, &batch.highlights)?;
+    let skipped = cx.number((skipped + batch.skipped) as f64);
 
-    let object = cx.empty_object();
-    let context = cx.empty_object();
+    search_result.set(&mut *cx, "count", count)?;
+    search_result.set(&mut *cx, "results", results)?;
+    search_result.set(&mut *cx, "highlights", highlights)?;
+    search_result.set(&mut *cx, "skipped", skipped)?;
 
-    let before = JsArray::new(cx, result.events_before.len() as u32);
-    let after = JsArray::new(cx, result.events_after.len() as u32);
-    let profile_info = cx.empty_object();
+    let timed_out = cx.boolean(batch.timed_out);
+    search_result.set(&mut *cx, "timed_out", timed_out)?;
 
-    for (i, event) in result.events_before.iter().enumerate() {
-        let js_event = serde_json::from_str(event);
-        let js_event: serde_json::Value = match js_event {
-            Ok(e) => e,
-            Err(_) => continue,
-        };
-        let js_event = match neon_serde3::to_value(&mut *cx, &js_event) {
-            Ok(v) => v,
-            Err(e) => return cx.throw_error::<_, _>(e.to_string()),
-        };
-        before.set(&mut *cx, i as u32, js_event)?;
+    if let Some(next_batch) = batch.next_batch {
+        let next_batch = cx.string(next_batch.hyphenated().to_string());
+        search_result.set(&mut *cx, "next_batch", next_batch)?;
     }
 
-    for (i, event) in result.events_after.iter().enumerate() {
-        let js_event = serde_json::from_str(event);
-        let js_event: serde_json::Value = match js_event {
-            Ok(e) => e,
-            Err(_) => continue,
-        };
-        let js_event = match neon_serde3::to_value(&mut *cx, &js_event) {
-            Ok(v) => v,
-            Err(e) => return cx.throw_error::<_, _>(e.to_string()),
-        };
-        after.set(&mut *cx, i as u32, js_event)?;
+    if let Some(groups) = batch.groups {
+        let js_groups = cx.empty_object();
+        let room_groups = cx.empty_object();
+
+        for (room_id, group) in groups {
+            let js_group = cx.empty_object();
+            let count = cx.number(group.count as f64);
+            let results = JsArray::new(cx, group.top_event_ids.len() as u32);
+
+            for (i, event_id) in group.top_event_ids.into_iter().enumerate() {
+                let event_id = cx.string(event_id);
+                results.set(&mut *cx, i as u32, event_id)?;
+            }
+
+            js_group.set(&mut *cx, "count", count)?;
+            js_group.set(&mut *cx, "results", results)?;
+            room_groups.set(&mut *cx, room_id.as_str(), js_group)?;
+        }
+
+        js_groups.set(&mut *cx, "room_id", room_groups)?;
+        search_result.set(&mut *cx, "groups", js_groups)?;
     }
 
-    for (sender, profile) in result.profile_info.drain() {
-        let (js_sender, js_profile) = sender_and_profile_to_js(cx, sender, profile)?;
-        profile_info.set(&mut *cx, js_sender, js_profile)?;
+    if !batch.room_order.is_empty() {
+        let room_order = JsArray::new(cx, batch.room_order.len() as u32);
+
+        for (i, room_id) in batch.room_order.into_iter().enumerate() {
+            let room_id = cx.string(room_id);
+            room_order.set(&mut *cx, i as u32, room_id)?;
+        }
+
+        search_result.set(&mut *cx, "room_order", room_order)?;
     }
 
-    context.set(&mut *cx, "events_before", before)?;
-    context.set(&mut *cx, "events_after", after)?;
-    context.set(&mut *cx, "profile_info", profile_info)?;
+    if let Some(timing) = batch.timing {
+        let js_timing = cx.empty_object();
+        let index_millis = cx.number(timing.index_millis);
+        let context_millis = cx.number(timing.context_millis);
+        let total_millis = cx.number(timing.total_millis);
 
-    object.set(&mut *cx, "rank", rank)?;
-    object.set(&mut *cx, "result", event)?;
-    object.set(&mut *cx, "context", context)?;
+        js_timing.set(&mut *cx, "index_millis", index_millis)?;
+        js_timing.set(&mut *cx, "context_millis", context_millis)?;
+        js_timing.set(&mut *cx, "total_millis", total_millis)?;
+        search_result.set(&mut *cx, "timing", js_timing)?;
+    }
 
-    Ok(object)
+    Ok(search_result)
 }
 
 pub(crate) fn profile_to_js<'a, C: Context<'a>>(
@@ -289,6 +1552,31 @@ pub(crate) fn profile_to_js<'a, C: Context<'a>>(
     Ok(js_profile)
 }
 
+pub(crate) fn room_info_to_js<'a, C: Context<'a>>(
+    cx: &mut C,
+    info: RoomInfo,
+) -> Result<Handle<'a, JsObject>, neon::result::Throw> {
+    let js_info = cx.empty_object();
+
+    let room_id = cx.string(info.room_id);
+    let event_count = cx.number(info.event_count as f64);
+    let oldest_event_ts = match info.oldest_event_ts {
+        Some(ts) => cx.number(ts as f64).upcast::<JsValue>(),
+        None => cx.null().upcast(),
+    };
+    let newest_event_ts = match info.newest_event_ts {
+        Some(ts) => cx.number(ts as f64).upcast::<JsValue>(),
+        None => cx.null().upcast(),
+    };
+
+    js_info.set(&mut *cx, "roomId", room_id)?;
+    js_info.set(&mut *cx, "eventCount", event_count)?;
+    js_info.set(&mut *cx, "oldestEventTs", oldest_event_ts)?;
+    js_info.set(&mut *cx, "newestEventTs", newest_event_ts)?;
+
+    Ok(js_info)
+}
+
 pub(crate) fn sender_and_profile_to_js<'a, C: Context<'a>>(
     cx: &mut C,
     sender: String,
@@ -300,9 +1588,195 @@ pub(crate) fn sender_and_profile_to_js<'a, C: Context<'a>>(
     Ok((js_sender, js_profile))
 }
 
+/// Parse a JS event into a reaction if it's an `m.reaction` event.
+///
+/// Returns `None` for any other event type. Reactions need to annotate
+/// another event, a reaction without an `m.annotation` relation is rejected.
+pub(crate) fn parse_reaction(
+    cx: &mut FunctionContext,
+    event: Handle<JsObject>,
+) -> Result<Option<Reaction>, neon::result::Throw> {
+    let event_type = event.get_value(&mut *cx, "type")?;
+
+    match event_type.downcast::<JsString, _>(cx) {
+        Ok(t) if t.value(cx) == "m.reaction" => (),
+        _ => return Ok(None),
+    }
+
+    let get_string =
+        |cx: &mut FunctionContext, object: Handle<JsObject>, key: &str, error: &str| {
+            Ok(object
+                .get_value(&mut *cx, key)
+                .and_then(|v| {
+                    v.downcast::<JsString, _>(cx)
+                        .or_else(|_| cx.throw_type_error(error))
+                })?
+                .value(cx))
+        };
+
+    let event_id = get_string(
+        cx,
+        event,
+        "event_id",
+        "Event doesn't contain a valid event id",
+    )?;
+    let room_id = get_string(
+        cx,
+        event,
+        "room_id",
+        "Event doesn't contain a valid room id",
+    )?;
+
+    let relation = event
+        .get_value(&mut *cx, "content")
+        .and_then(|v| {
+            v.downcast::<JsObject, _>(cx)
+                .or_else(|_| cx.throw_type_error("Event doesn't contain any content"))
+        })?
+        .get_value(&mut *cx, "m.relates_to")
+        .and_then(|v| {
+            v.downcast::<JsObject, _>(cx)
+                .or_else(|_| cx.throw_type_error("Reaction doesn't contain a valid relation"))
+        })?;
+
+    let rel_type = get_string(
+        cx,
+        relation,
+        "rel_type",
+        "Reaction doesn't contain a valid relation",
+    )?;
+
+    if rel_type != "m.annotation" {
+        return cx.throw_type_error(format!(
+            "Unsupported reaction relation {rel_type}, reactions need to be m.annotation relations"
+        ));
+    }
+
+    let target_event_id = get_string(
+        cx,
+        relation,
+        "event_id",
+        "Reaction doesn't contain a valid related event id",
+    )?;
+
+    Ok(Some(Reaction::new(&event_id, &target_event_id, &room_id)))
+}
+
+/// Parse an object of the form `{event, profile?, source?}` into a reaction
+/// if the event is an `m.reaction` event, see `parse_reaction()`.
+pub(crate) fn parse_reaction_with_profile(
+    cx: &mut FunctionContext,
+    obj: Handle<JsValue>,
+) -> Result<Option<Reaction>, neon::result::Throw> {
+    let obj = obj.downcast::<JsObject, _>(cx).or_throw(cx)?;
+    let event = obj.get::<JsObject, _, _>(cx, "event")?;
+
+    parse_reaction(cx, event)
+}
+
+/// An event that was passed to `addEvent()`, only message and state events
+/// are indexed.
+pub(crate) enum ParsedEvent {
+    Event(Event, Profile),
+    Reaction(Reaction),
+    RoomAlias(RoomAlias),
+}
+
+/// Parse a JS event into a room alias if it's an `m.room.canonical_alias`
+/// event.
+///
+/// Returns `None` for any other event type. An event without an alias
+/// removes the alias of the room, it's returned as an empty alias.
+pub(crate) fn parse_room_alias(
+    cx: &mut FunctionContext,
+    event: Handle<JsObject>,
+) -> Result<Option<RoomAlias>, neon::result::Throw> {
+    let event_type = event.get_value(&mut *cx, "type")?;
+
+    match event_type.downcast::<JsString, _>(cx) {
+        Ok(t) if t.value(cx) == "m.room.canonical_alias" => (),
+        _ => return Ok(None),
+    }
+
+    let room_id = event
+        .get_value(&mut *cx, "room_id")
+        .and_then(|v| {
+            v.downcast::<JsString, _>(cx)
+                .or_else(|_| cx.throw_type_error("Event doesn't contain a valid room id"))
+        })?
+        .value(cx);
+
+    let server_ts = event
+        .get_value(&mut *cx, "origin_server_ts")
+        .and_then(|v| {
+            v.downcast::<JsNumber, _>(cx)
+                .or_else(|_| cx.throw_type_error("Event doesn't contain a valid timestamp"))
+        })?
+        .value(cx) as i64;
+
+    let content = event
+        .get_value(&mut *cx, "content")?
+        .downcast::<JsObject, _>(cx);
+
+    let alias = match content {
+        Ok(c) => match c.get_value(&mut *cx, "alias")?.downcast::<JsString, _>(cx) {
+            Ok(a) => a.value(cx),
+            Err(_) => String::new(),
+        },
+        Err(_) => String::new(),
+    };
+
+    Ok(Some(RoomAlias::new(&room_id, &alias, server_ts)))
+}
+
+/// Parse an object of the form `{event, profile?, source?}` into a room alias
+/// if the event is an `m.room.canonical_alias` event, see
+/// `parse_room_alias()`.
+pub(crate) fn parse_room_alias_with_profile(
+    cx: &mut FunctionContext,
+    obj: Handle<JsValue>,
+) -> Result<Option<RoomAlias>, neon::result::Throw> {
+    let obj = obj.downcast::<JsObject, _>(cx).or_throw(cx)?;
+    let event = obj.get::<JsObject, _, _>(cx, "event")?;
+
+    parse_room_alias(cx, event)
+}
+
 pub(crate) fn parse_event(
     cx: &mut FunctionContext,
     event: Handle<JsObject>,
+) -> Result<Event, neon::result::Throw> {
+    parse_event_with_source(cx, event, None)
+}
+
+/// Serialize a JS object to a JSON string.
+///
+/// This uses `JSON.stringify()` which is a lot faster than converting the
+/// object to a `serde_json::Value` and serializing that.
+fn stringify(
+    cx: &mut FunctionContext,
+    object: Handle<JsObject>,
+) -> Result<String, neon::result::Throw> {
+    let json = cx.global().get::<JsObject, _, _>(cx, "JSON")?;
+    let stringify = json.get::<JsFunction, _, _>(cx, "stringify")?;
+
+    stringify
+        .call(cx, json, [object.upcast::<JsValue>()])?
+        .downcast::<JsString, _>(cx)
+        .or_else(|_| cx.throw_type_error("Cannot serialize event"))
+        .map(|s| s.value(cx))
+}
+
+/// Parse a JS event.
+///
+/// If a pre-serialized source of the event is given it's used as the source
+/// of the event, otherwise the event gets serialized. The event is validated
+/// by the `EventBuilder`, which checks among other things that the source
+/// belongs to the event.
+pub(crate) fn parse_event_with_source(
+    cx: &mut FunctionContext,
+    event: Handle<JsObject>,
+    source: Option<String>,
 ) -> Result<Event, neon::result::Throw> {
     let get_string = |cx: &mut FunctionContext, event: Handle<JsObject>, key: &str, error: &str| {
         Ok(event
@@ -314,6 +1788,22 @@ pub(crate) fn parse_event(
             .value(cx))
     };
 
+    let get_optional_string =
+        |cx: &mut FunctionContext, object: Handle<JsObject>, key: &str, error: &str| {
+            let value = object.get_value(&mut *cx, key)?;
+
+            if value.is_a::<JsUndefined, _>(cx) || value.is_a::<JsNull, _>(cx) {
+                Ok(None)
+            } else {
+                Ok(Some(
+                    value
+                        .downcast::<JsString, _>(cx)
+                        .or_else(|_| cx.throw_type_error(error))?
+                        .value(cx),
+                ))
+            }
+        };
+
     let sender = get_string(cx, event, "sender", "Event doesn't contain a valid sender")?;
     let event_id = get_string(
         cx,
@@ -327,7 +1817,13 @@ pub(crate) fn parse_event(
             v.downcast::<JsNumber, _>(cx)
                 .or_else(|_| cx.throw_type_error("Event doesn't contain a valid timestamp"))
         })?
-        .value(cx) as i64;
+        .value(cx);
+
+    if !server_timestamp.is_finite() {
+        return cx.throw_type_error("Event doesn't contain a valid timestamp");
+    }
+
+    let server_timestamp = server_timestamp as i64;
 
     let room_id = get_string(
         cx,
@@ -342,25 +1838,14 @@ pub(crate) fn parse_event(
     })?;
     let event_type = get_string(cx, event, "type", "Event doesn't contain a valid type")?;
 
-    let event_type: EventType = match event_type.as_ref() {
-        "m.room.message" => EventType::Message,
-        "m.room.name" => EventType::Name,
-        "m.room.topic" => EventType::Topic,
-        e => return cx.throw_type_error(format!("Unsupported event type {e}")),
-    };
-
-    let key = match event_type {
-        EventType::Message => "body",
-        EventType::Topic => "topic",
-        EventType::Name => "name",
-    };
+    let event_type = EventType::from(event_type.as_ref());
 
-    let content_value = get_string(
-        cx,
-        content,
-        key,
-        &format!("Event deosn't contain a valid {key}"),
-    )?;
+    if let EventType::Unknown(e) = &event_type {
+        return cx.throw_type_error(format!(
+            "Unsupported event type {e}, supported types are \
+             m.room.message, m.room.topic and m.room.name"
+        ));
+    }
 
     let msgtype = match event_type {
         EventType::Message => Some(get_string(
@@ -372,58 +1857,150 @@ pub(crate) fn parse_event(
         _ => None,
     };
 
-    let event_value = event.as_value(&mut *cx);
-    let event_source: serde_json::Value = match neon_serde3::from_value(&mut *cx, event_value) {
-        Ok(v) => v,
-        Err(e) => return cx.throw_error::<_, _>(e.to_string()),
+    let is_file = msgtype
+        .as_deref()
+        .map_or(false, |t| FILE_MSGTYPES.contains(&t));
+
+    let content_value = if is_file {
+        // Files are searchable by their filename, prefer the dedicated
+        // filename field and fall back to the body. Files without either are
+        // still stored, they just won't be found by a full text search.
+        let filename =
+            get_optional_string(cx, content, "filename", "Event has an invalid filename")?;
+
+        match filename {
+            Some(f) => f,
+            None => get_optional_string(cx, content, "body", "Event has an invalid body")?
+                .unwrap_or_default(),
+        }
+    } else {
+        let key = match event_type {
+            EventType::Topic => "topic",
+            EventType::Name => "name",
+            _ => "body",
+        };
+
+        get_string(
+            cx,
+            content,
+            key,
+            &format!("Event deosn't contain a valid {key}"),
+        )?
     };
-    let event_source: String = serde_json::to_string(&event_source)
-        .or_else(|e| cx.throw_type_error(format!("Cannot serialize event {}", e)))?;
-
-    Ok(Event {
-        event_type,
-        content_value,
-        msgtype,
-        event_id,
-        sender,
-        server_ts: server_timestamp,
-        room_id,
-        source: event_source,
-    })
+
+    let relation = content.get_value(&mut *cx, "m.relates_to")?;
+
+    let thread_id = match relation.downcast::<JsObject, _>(cx) {
+        Ok(relation) => {
+            let rel_type =
+                get_optional_string(cx, relation, "rel_type", "Event has an invalid relation")?;
+
+            if rel_type.as_deref() == Some("m.thread") {
+                get_optional_string(
+                    cx,
+                    relation,
+                    "event_id",
+                    "Event has an invalid thread relation",
+                )?
+            } else {
+                None
+            }
+        }
+        Err(_) => None,
+    };
+
+    let event_source = match source {
+        Some(source) => source,
+        None => stringify(cx, event)?,
+    };
+
+    let mut builder = EventBuilder::new(event_type)
+        .content_value(content_value)
+        .event_id(event_id)
+        .sender(sender)
+        .server_ts(server_timestamp)
+        .room_id(room_id)
+        .source(event_source);
+
+    if let Some(msgtype) = msgtype {
+        builder = builder.msgtype(msgtype);
+    }
+
+    if let Some(thread_id) = thread_id {
+        builder = builder.thread_id(thread_id);
+    }
+
+    builder.build().or_else(|e| throw_seshat_type_error(cx, &e))
 }
 
+/// Parse the profile of the sender of an event.
 pub(crate) fn parse_profile(
     cx: &mut FunctionContext,
     profile: Handle<JsObject>,
 ) -> Result<Profile, neon::result::Throw> {
-    let get_optional_string =
-        |cx: &mut FunctionContext, value: Handle<'_, JsValue>, error: &str| {
-            if value.is_a::<JsUndefined, _>(cx) || value.is_a::<JsNull, _>(cx) {
-                Ok(None)
-            } else {
-                Ok(Some(
-                    value
-                        .downcast::<JsString, _>(cx)
-                        .or_else(|_| cx.throw_type_error(error))?
-                        .value(cx),
-                ))
-            }
-        };
+    Ok(Profile {
+        displayname: get_profile_field(cx, profile, "displayname")?,
+        avatar_url: get_profile_field(cx, profile, "avatar_url")?,
+    })
+}
+
+/// Get a field of a profile.
+///
+/// Strings are trimmed, empty strings as well as `null` and `undefined` mean
+/// that the field isn't set. Values of any other type throw a type error
+/// naming the field.
+fn get_profile_field(
+    cx: &mut FunctionContext,
+    profile: Handle<JsObject>,
+    field: &str,
+) -> Result<Option<String>, neon::result::Throw> {
+    let value = profile.get_value(&mut *cx, field)?;
 
-    let displayname: Option<String> = profile
-        .get_value(cx, "displayname")
-        .and_then(|v| get_optional_string(cx, v, "Event has an invalid display name"))?;
+    if value.is_a::<JsUndefined, _>(cx) || value.is_a::<JsNull, _>(cx) {
+        return Ok(None);
+    }
 
-    let avatar_url: Option<String> = profile
-        .get_value(&mut *cx, "avatar_url")
-        .and_then(|v| get_optional_string(cx, v, "Event has an invalid avatar URL"))?;
+    let value = match value.downcast::<JsString, _>(cx) {
+        Ok(v) => v.value(cx),
+        Err(_) => {
+            return cx.throw_type_error(format!(
+                "The {} of the profile must be a string or null",
+                field
+            ))
+        }
+    };
 
-    Ok(Profile {
-        displayname,
-        avatar_url,
+    let value = value.trim();
+
+    Ok(if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
     })
 }
 
+/// Parse a profile that might be missing.
+///
+/// A missing, null or undefined profile is treated as an empty profile, see
+/// `parse_profile()` for the rules its fields follow.
+pub(crate) fn parse_optional_profile(
+    cx: &mut FunctionContext,
+    profile: Option<Handle<JsValue>>,
+) -> Result<Profile, neon::result::Throw> {
+    match profile {
+        Some(p) if !p.is_a::<JsUndefined, _>(cx) && !p.is_a::<JsNull, _>(cx) => {
+            let p = p
+                .downcast::<JsObject, _>(cx)
+                .or_else(|_| cx.throw_type_error("Event has an invalid profile"))?;
+            parse_profile(cx, p)
+        }
+        _ => Ok(Profile {
+            displayname: None,
+            avatar_url: None,
+        }),
+    }
+}
+
 pub(crate) fn js_checkpoint_to_rust(
     cx: &mut FunctionContext,
     object: Handle<JsObject>,
@@ -443,13 +2020,36 @@ pub(crate) fn js_checkpoint_to_rust(
         "backwards" | "backward" | "b" => CheckpointDirection::Backwards,
         "forwards" | "forward" | "f" => CheckpointDirection::Forwards,
         "" => CheckpointDirection::Backwards,
-        d => return cx.throw_error(format!("Unknown checkpoint direction {}", d)),
+        d => {
+            return cx.throw_type_error(format!(
+                "Unknown checkpoint direction {}, supported directions are \
+                 b, f, backwards and forwards",
+                d
+            ))
+        }
     };
 
-    Ok(CrawlerCheckpoint {
-        room_id,
-        token,
-        full_crawl,
-        direction,
-    })
+    let mut checkpoint = match CrawlerCheckpoint::new(&room_id, &token) {
+        Ok(c) => c,
+        Err(e) => return throw_seshat_type_error(cx, &e),
+    };
+
+    checkpoint.full_crawl = full_crawl;
+    checkpoint.direction = direction;
+
+    Ok(checkpoint)
+}
+
+#[test]
+fn sequence_numbers_keep_their_precision() {
+    assert_eq!(sequence_as_number(0), Some(0.0));
+    assert_eq!(
+        sequence_as_number(MAX_SAFE_INTEGER),
+        Some(9_007_199_254_740_991.0)
+    );
+
+    // 2^53 + 1 would be rounded down to 2^53.
+    assert_eq!(sequence_as_number(MAX_SAFE_INTEGER + 1), None);
+    assert_eq!(sequence_as_number(MAX_SAFE_INTEGER + 2), None);
+    assert_eq!(sequence_as_number(u64::MAX), None);
 }
@@ -2,7 +2,8 @@ const fs = require('fs');
 const os = require('os');
 const path = require('path');
 
-const {Seshat, ReindexError, SeshatRecovery} = require('../');
+const {Seshat, SeshatManager, ReindexError, SeshatRecovery} = require('../');
+const seshatNative = require('../index.node');
 
 const matrixEvent = {
     type: 'm.room.message',
@@ -67,6 +68,19 @@ const videoEvent = {
     origin_server_ts: 1516362244100,
 };
 
+const namedFileEvent = {
+    type: 'm.room.message',
+    event_id: '$15163622492Efile:localhost',
+    room_id: '!TESTROOM',
+    sender: '@alice:example.org',
+    content: {
+        body: 'The numbers for this quarter',
+        filename: 'quarterly_report-final.pdf',
+        msgtype: 'm.file',
+    },
+    origin_server_ts: 1516362244110,
+};
+
 const beforeMatrixEvent = {
     type: 'm.room.message',
     event_id: '$15163622445EBvFA:localhost',
@@ -169,6 +183,25 @@ describe('Database', function() {
         const db = new Seshat(tempDir);
     });
 
+    it('should throw a catchable error if the database can\'t be opened.', function() {
+        expect(() => new Seshat('')).toThrow(TypeError);
+
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        fs.writeFileSync(path.join(tempDir, 'events.db'), 'This is not a database');
+
+        let error;
+
+        try {
+            new Seshat(tempDir);
+        } catch (e) {
+            error = e;
+        }
+
+        expect(error).toBeInstanceOf(Error);
+        expect(error.message).toMatch('Error opening the database');
+        expect(error.code).toBeDefined();
+    });
+
     const db = createDb();
 
     it('should allow the addition of events.', function() {
@@ -177,6 +210,7 @@ describe('Database', function() {
 
     it('should allow the addition of an event without a profile.', function() {
         db.addEvent(matrixEvent);
+        db.addEvent(matrixEvent, null);
     });
 
     it('should allow the addition of an event with a profile that only contains a display name.', function() {
@@ -211,6 +245,49 @@ describe('Database', function() {
         expect(results.results[0].result).toEqual(matrixEvent);
     });
 
+    it('should run sync searches alongside reads on the connection pool', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.addCrawlerCheckpoint(checkPoint);
+
+        await db.commit(true);
+        db.reload();
+
+        const loads = [];
+
+        for (let i = 0; i < 20; i++) {
+            loads.push(db.loadCheckpoints());
+            expect(db.searchSync({search_term: 'Test'}).count).toBe(1);
+        }
+
+        for (const checkpoints of await Promise.all(loads)) {
+            expect(checkpoints).toEqual([checkPoint]);
+        }
+    });
+
+    it('should share the connection pool between background reads', async function() {
+        const db = new Seshat(fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-')), {
+            connectionPoolSize: 4,
+        });
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.addCrawlerCheckpoint(checkPoint);
+        await db.commit(true);
+
+        const reads = [];
+
+        for (let i = 0; i < 250; i++) {
+            reads.push(db.loadCheckpoints());
+            reads.push(db.getStats());
+        }
+
+        const results = await Promise.all(reads);
+
+        for (let i = 0; i < results.length; i += 2) {
+            expect(results[i]).toEqual([checkPoint]);
+            expect(results[i + 1].eventCount).toBe(1);
+        }
+    });
+
     it('should return a search result for the stored event using promises', async function() {
         const db = createDb();
         db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
@@ -226,23 +303,220 @@ describe('Database', function() {
         expect(results.results[0].result).toEqual(matrixEvent);
     });
 
+    it('should return the matched search terms as highlights', async function() {
+        const db = createDb();
+        const event = {
+            ...matrixEvent,
+            event_id: '$15163622445EBvZH:localhost',
+            content: {
+                body: 'Foo bar baz',
+                msgtype: 'm.text',
+            },
+        };
+        db.addEvent(event, matrixProfile);
+
+        await db.commit(true);
+        db.reload();
+
+        const check = (results) => {
+            expect(results.count).toBe(1);
+            const highlights = results.highlights.map(h => h.toLowerCase());
+            expect(highlights).toContain('foo');
+            expect(highlights).toContain('bar');
+            expect(results.results[0].highlights).toEqual(results.highlights);
+        };
+
+        check(await db.search({search_term: 'Foo bar'}));
+        check(db.searchSync({search_term: 'Foo bar'}));
+    });
+
+    it('should skip corrupted events when searching', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        let db = new Seshat(tempDir, {wal: false});
+
+        const brokenEvent = {
+            ...matrixEvent,
+            event_id: '$broken:localhost',
+            content: {
+                body: 'Broken message',
+                msgtype: 'm.text',
+            },
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(brokenEvent, matrixProfile);
+        await db.commit(true);
+        await db.shutdown();
+
+        // Corrupt the stored source of the broken event, the replacement has
+        // the same length so the database file stays intact.
+        const dbPath = path.join(tempDir, 'events.db');
+        const contents = fs.readFileSync(dbPath, 'latin1');
+        expect(contents).toContain('"body":"Broken');
+        fs.writeFileSync(
+            dbPath,
+            contents.split('"body":"Broken').join('"body":[Broken'),
+            'latin1',
+        );
+
+        db = new Seshat(tempDir);
+        const results = await db.search({search_term: 'message'});
+        expect(results.count).toBe(2);
+        expect(results.results.length).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+        expect(results.skipped).toBe(1);
+    });
+
+    it('should allow events to be added asynchronously', async function() {
+        const db = createDb();
+        await db.addEventAsync(matrixEvent, matrixProfile);
+
+        await db.commit(true);
+        await db.reloadAsync();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+    });
+
+    it('should allow the native async methods to be used with promises', async function() {
+        const db = createDb();
+
+        await seshatNative.addEventAsync(db.inner, matrixEvent, matrixProfile);
+        await seshatNative.commit(db.inner, true);
+        await seshatNative.reloadAsync(db.inner);
+
+        const results = await seshatNative.search(db.inner, {search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+    });
+
+    it('should allow the native async methods to be used with callbacks', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfile);
+
+        const opstamp = await new Promise((resolve, reject) => {
+            const ret = seshatNative.commit(db.inner, true,
+                (err, opstamp) => err ? reject(err) : resolve(opstamp));
+            expect(ret).toBeUndefined();
+        });
+        expect(typeof opstamp).toBe('number');
+
+        const stats = await new Promise((resolve, reject) => {
+            seshatNative.getStats(db.inner, (err, stats) => err ? reject(err) : resolve(stats));
+        });
+        expect(stats.eventCount).toBe(1);
+    });
+
+    it('should return sequence numbers that can be compared to commits', async function() {
+        const db = createDb();
+
+        const first = db.addEvent(matrixEvent, matrixProfile);
+        const second = await db.addEventAsync(topicEvent, matrixProfile);
+        expect(second).toBeGreaterThan(first);
+
+        const {sequence} = await db.addEvents([
+            {event: {...matrixEvent, event_id: '$15163622445EBvZK:localhost'}},
+        ]);
+        expect(sequence).toBeGreaterThan(second);
+
+        const committed = await db.commit(true);
+        expect(committed).toBeGreaterThanOrEqual(sequence);
+
+        db.reload();
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(3);
+    });
+
+    it('should tell if an added event was indexed', async function() {
+        const db = createDb();
+
+        const sequence = db.addEvent(matrixEvent, matrixProfile);
+        expect(await db.commit(true)).toBeGreaterThanOrEqual(sequence);
+
+        expect(await db.wasEventIndexed(matrixEvent.event_id)).toBe(true);
+        expect(await db.wasEventIndexed(laterMatrixEvent.event_id)).toBe(false);
+
+        await db.shutdown();
+        expect(() => db.addEvent(laterMatrixEvent, matrixProfile)).toThrow(TypeError);
+        await expect(db.wasEventIndexed(matrixEvent.event_id)).rejects.toThrow(TypeError);
+    });
+
+    it('should reject asynchronously added events that are invalid', async function() {
+        const db = createDb();
+        await expect(db.addEventAsync(badEvent)).rejects.toThrow(TypeError);
+    });
+
+    it('should allow a batch of events to be added', async function() {
+        const db = createDb();
+        const {count} = await db.addEvents([
+            {event: matrixEvent, profile: matrixProfile},
+            {event: topicEvent},
+        ]);
+        expect(count).toBe(2);
+
+        await db.commit(true);
+        const stats = await db.getStats();
+        expect(stats.eventCount).toBe(2);
+    });
+
+    it('should reject a batch of events if one of them is invalid', async function() {
+        const db = createDb();
+        await expect(db.addEvents([
+            {event: matrixEvent},
+            {event: badEvent},
+        ])).rejects.toThrow(/index 1/);
+
+        await db.commit(true);
+        const stats = await db.getStats();
+        expect(stats.eventCount).toBe(0);
+    });
+
+    it('should truncate huge event bodies and reject too large events', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {
+            maxIndexedBodyBytes: 1024,
+            maxEventSize: 1024 * 1024,
+        });
+
+        const body = 'wörd '.repeat(400) + 'löst ' + 'wörd '.repeat(1000);
+        db.addEvent({...matrixEvent, content: {body, msgtype: 'm.text'}});
+
+        const tooLarge = {
+            ...matrixEvent,
+            event_id: '$tooLarge:localhost',
+            content: {body: 'x'.repeat(2 * 1024 * 1024), msgtype: 'm.text'},
+        };
+        expect(() => db.addEvent(tooLarge)).toThrow(/too large/);
+        await expect(db.addEvents([{event: tooLarge}])).rejects.toThrow(/too large/);
+
+        await db.commit(true);
+        db.reload();
+
+        expect((await db.search({search_term: 'wörd'})).count).toBe(1);
+        expect((await db.search({search_term: 'löst'})).count).toBe(0);
+
+        const stats = await db.getStats();
+        expect(stats.eventCount).toBe(1);
+    });
+
     it('should allow messages from the backlog to be added in a batched way', async function() {
         const db = createDb();
         let ret = db.addHistoricEventsSync(exampleEvents, checkPoint);
-        expect(ret).toBeFalsy();
+        expect(ret).toBe(true);
 
         db.reload();
         const results = await db.search({search_term: 'Test'});
         expect(Object.entries(results).length).not.toBe(0);
 
         let ret2 = db.addHistoricEventsSync(exampleEvents, checkPoint);
-        expect(ret2).toBeTruthy();
+        expect(ret2).toBe(false);
     });
 
     it('shouldn\'t tell us that all events are added if none were given', async function() {
         const db = createDb();
         let ret = db.addHistoricEventsSync([], checkPoint);
-        expect(ret).toBeFalsy();
+        expect(ret).toBe(true);
     });
 
     it('should add messages to an encrypted db and correctly report if they are already added', async function() {
@@ -267,16 +541,259 @@ describe('Database', function() {
         ]
 
         let ret = db.addHistoricEventsSync(events, checkPoint);
-        expect(ret).toBeFalsy();
+        expect(ret).toBe(true);
 
         ret = db.addHistoricEventsSync(events, checkPoint);
-        expect(ret).toBeTruthy();
+        expect(ret).toBe(false);
+    });
+
+    it('should allow us to add and remove crawler checkpoints', async function() {
+        const db = createDb();
+        const otherCheckPoint = {...checkPoint, roomId: '!TESTROOM2'};
+
+        await db.addCrawlerCheckpoint(checkPoint);
+        // Adding the same checkpoint again doesn't create a duplicate.
+        await db.addCrawlerCheckpoint(checkPoint);
+        await db.addCrawlerCheckpoint(otherCheckPoint);
+
+        let checkpoints = await db.loadCheckpoints();
+        expect(checkpoints.length).toBe(2);
+        expect(checkpoints).toContainEqual(checkPoint);
+        expect(checkpoints).toContainEqual(otherCheckPoint);
+
+        await db.removeCrawlerCheckpoint(checkPoint);
+        // Removing a checkpoint that doesn't exist is a no-op.
+        await db.removeCrawlerCheckpoint(checkPoint);
+
+        checkpoints = await db.loadCheckpoints();
+        expect(checkpoints).toEqual([otherCheckPoint]);
+    });
+
+    it('should round-trip the direction and the crawl type of checkpoints', async function() {
+        const db = createDb();
+        const forwardsCheckPoint = {
+            roomId: '!TESTROOM2',
+            token: '5678',
+            fullCrawl: true,
+            direction: 'forwards',
+        };
+
+        await db.addCrawlerCheckpoint(checkPoint);
+        await db.addCrawlerCheckpoint(forwardsCheckPoint);
+        await db.addCrawlerCheckpoint({roomId: '!TESTROOM3', token: '9012'});
+
+        const checkpoints = await db.loadCheckpoints();
+        expect(checkpoints.length).toBe(3);
+        expect(checkpoints).toContainEqual(checkPoint);
+        expect(checkpoints).toContainEqual({...forwardsCheckPoint, direction: 'f'});
+        expect(checkpoints).toContainEqual({
+            roomId: '!TESTROOM3',
+            token: '9012',
+            fullCrawl: false,
+            direction: 'b',
+        });
+
+        await expect(db.addCrawlerCheckpoint({...checkPoint, direction: 'sideways'}))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should validate and canonicalize checkpoints', async function() {
+        const db = createDb();
+
+        await expect(db.addCrawlerCheckpoint({...checkPoint, token: ''}))
+            .rejects.toMatchObject({code: 'INVALID_CHECKPOINT'});
+        await expect(db.addHistoricEvents([{event: matrixEvent}], {...checkPoint, token: ' \n'}))
+            .rejects.toThrow(TypeError);
+        await expect(db.addCrawlerCheckpoint({...checkPoint, roomId: 'TESTROOM'}))
+            .rejects.toThrow(TypeError);
+        expect(await db.isEmpty()).toBe(true);
+
+        await db.addCrawlerCheckpoint({...checkPoint, token: ` ${checkPoint.token}\n`});
+        await db.addCrawlerCheckpoint(checkPoint);
+
+        let checkpoints = await db.loadCheckpoints();
+        expect(checkpoints).toEqual([checkPoint]);
+
+        await db.removeCrawlerCheckpoint({...checkPoint, token: `${checkPoint.token} `});
+        checkpoints = await db.loadCheckpoints();
+        expect(checkpoints).toEqual([]);
+    });
+
+    it('should allow backlog events to be added with a pre-serialized source', async function() {
+        const db = createDb();
+        const events = [];
+
+        for (let i = 0; i < 1000; i++) {
+            const event = {
+                ...matrixEvent,
+                event_id: `$${i}:localhost`,
+                origin_server_ts: matrixEvent.origin_server_ts + i,
+            };
+            events.push({event: event, source: JSON.stringify(event)});
+        }
+
+        expect(await db.addHistoricEvents(events, checkPoint)).toBe(true);
+        db.reload();
+
+        const stats = await db.getStats();
+        expect(stats.eventCount).toBe(1000);
+
+        const results = await db.search({search_term: 'Test', limit: 1});
+        expect(results.count).toBe(1000);
+        expect(events.map(e => e.event)).toContainEqual(results.results[0].result);
+    });
+
+    it('should wait until the backlog of a room is searchable', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {autoReload: false});
+        const events = [];
+
+        for (let i = 0; i < 10; i++) {
+            const event = {
+                ...matrixEvent,
+                event_id: `$backlog${i}:localhost`,
+                origin_server_ts: matrixEvent.origin_server_ts - 100 + i,
+            };
+            events.push({event: event});
+        }
+
+        const added = db.addHistoricEvents(events, checkPoint);
+        const newest = events[events.length - 1].event.origin_server_ts;
+        expect(await db.waitForRoomVisibility(matrixEvent.room_id, newest, 5000)).toBe(true);
+
+        // The index was reloaded, no manual reload is needed.
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(10);
+        expect(await added).toBe(true);
+
+        // Live events need a commit.
+        db.addEvent(matrixEvent, matrixProfile);
+        expect(await db.waitForRoomVisibility(matrixEvent.room_id, matrixEvent.origin_server_ts, 10))
+            .toBe(false);
+
+        await expect(db.waitForRoomVisibility(matrixEvent.room_id, newest, -1))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should reject a pre-serialized source that belongs to another event', async function() {
+        const db = createDb();
+        const source = JSON.stringify({...matrixEvent, event_id: '$other:localhost'});
+
+        await expect(db.addHistoricEvents([{event: matrixEvent, source: source}]))
+            .rejects.toThrow(TypeError);
+        await expect(db.addHistoricEvents([{event: matrixEvent, source: '{"event_id'}]))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should skip invalid backlog events if a partial batch is allowed', async function() {
+        const db = createDb();
+        const newCheckPoint = {...checkPoint, token: '5678'};
+        const events = [];
+
+        for (let i = 0; i < 50; i++) {
+            const event = {...matrixEvent, event_id: `$${i}:localhost`};
+            events.push({event: event, source: JSON.stringify(event)});
+        }
+
+        events[7] = {
+            event: {...events[7].event, content: {msgtype: 'm.text', body: null}},
+        };
+        events[31].source = '{"even';
+
+        await db.addCrawlerCheckpoint(checkPoint);
+
+        // A strict batch fails as a whole.
+        await expect(db.addHistoricEvents(events, newCheckPoint, checkPoint))
+            .rejects.toThrow(TypeError);
+        expect(await db.loadCheckpoints()).toEqual([checkPoint]);
+
+        const result = await db.addHistoricEvents(events, newCheckPoint, checkPoint,
+            {allowPartial: true});
+        expect(result.added).toBe(true);
+        expect(result.skipped.map(s => s.index)).toEqual([7, 31]);
+        expect(result.skipped[0].reason).toMatch(/body/);
+        expect(result.skipped[1].reason).toMatch(/source/);
+
+        expect(await db.loadCheckpoints()).toEqual([newCheckPoint]);
+        expect((await db.getStats()).eventCount).toBe(48);
+    });
+
+    it('should reject events with invalid identifiers', function() {
+        const db = createDb();
+
+        expect(() => db.addEvent({...matrixEvent, event_id: 'EBvZB:localhost'}, matrixProfile))
+            .toThrow(/the event id "EBvZB:localhost" is invalid/);
+        expect(() => db.addEvent({...matrixEvent, room_id: 'TESTROOM'}, matrixProfile))
+            .toThrow(/the room id "TESTROOM" is invalid/);
+        expect(() => db.addEvent({...matrixEvent, sender: 'alice'}, matrixProfile))
+            .toThrow(/the sender "alice" is invalid/);
+        expect(() => db.addEvent({...matrixEvent, content: {body: ' ', msgtype: 'm.text'}}, matrixProfile))
+            .toThrow(TypeError('The event is invalid: the body of the message is empty'));
+    });
+
+    it('should allow events to be imported in chunks', async function() {
+        const db = createDb();
+        const newCheckPoint = {...checkPoint, token: '5678'};
+        const chunk = (start) => [...Array(10).keys()].map(i => ({
+            event: {...matrixEvent, event_id: `$${start + i}:localhost`},
+        }));
+
+        await db.addCrawlerCheckpoint(checkPoint);
+
+        // Abandoning an import leaves the old checkpoint intact.
+        let bulkImport = db.startBulkImport();
+        expect(await bulkImport.addChunk(chunk(0))).toBe(true);
+        expect(await db.loadCheckpoints()).toEqual([checkPoint]);
+
+        bulkImport = db.startBulkImport();
+        await bulkImport.addChunk(chunk(10));
+        await bulkImport.addChunk(chunk(20));
+        await bulkImport.finish(newCheckPoint, checkPoint);
+
+        expect(await db.loadCheckpoints()).toEqual([newCheckPoint]);
+        expect((await db.getStats()).eventCount).toBe(30);
+
+        await expect(bulkImport.addChunk(chunk(30))).rejects.toThrow(TypeError);
+        await expect(bulkImport.finish()).rejects.toThrow(TypeError);
+    });
+
+    it('should allow backlog events without a profile', async function() {
+        const db = createDb();
+        const event = (id, sender) => ({
+            ...matrixEvent,
+            event_id: `$${id}:localhost`,
+            sender: sender,
+        });
+
+        const events = [
+            {event: event('withProfile', '@alice:example.org'), profile: matrixProfile},
+            {event: event('missingProfile', '@bob:example.org')},
+            {event: event('nullProfile', '@carol:example.org'), profile: null},
+            {event: event('emptyProfile', '@dave:example.org'), profile: {}},
+        ];
+
+        expect(await db.addHistoricEvents(events, checkPoint)).toBe(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(4);
+
+        for (const result of results.results) {
+            const profile = result.context.profile_info[result.result.sender];
+
+            if (result.result.sender === '@alice:example.org') {
+                expect(profile.displayname).toBe(matrixProfile.displayname);
+            } else {
+                expect(profile.displayname).toBeNull();
+                expect(profile.avatar_url).toBeNull();
+            }
+        }
     });
 
     it('should allow messages from the backlog to be added using a promise', async function() {
         const db = createDb();
         let ret = await db.addHistoricEvents(exampleEvents, checkPoint)
-        expect(ret).toBeFalsy();
+        expect(ret).toBe(true);
         db.reload();
 
         const results = await db.search({search_term: 'Test'});
@@ -286,7 +803,7 @@ describe('Database', function() {
         expect(checkpoints[0]).toEqual(checkPoint);
 
         let ret2 = await db.addHistoricEvents(exampleEvents, checkPoint)
-        expect(ret2).toBeTruthy();
+        expect(ret2).toBe(false);
     });
 
     it('should allow to search events in a specific room', async function() {
@@ -340,116 +857,2233 @@ describe('Database', function() {
         expect(results.results[1].rank).toBeLessThanOrEqual(results.results[2].rank);
     });
 
-    it('should allow us to get the size of the database', async function() {
-        const db = createDb();
-        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
-        db.addEvent(matrixEventRoom2, matrixProfileOnlyDisplayName);
-
-        await db.commit(true);
-        let size = await db.getSize();
-        expect(size).toBeGreaterThan(0)
-    });
-
-    it('should allow us to add different event types', async function() {
-        const db = createDb();
-        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
-        db.addEvent(topicEvent, matrixProfileOnlyDisplayName);
-        db.addEvent(nameEvent, matrixProfileOnlyDisplayName);
-
-        await db.commit(true);
-        db.reload();
-    });
-
-    it('should not barf on nul bytes in the event', async function() {
-        const db = createDb();
-        const events = [{event: nulByteEvent, profile: matrixProfileOnlyDisplayName}];
-        db.addHistoricEvents(events);
-        await db.commit(true);
-        db.reload();
-    });
-
-    it('should allow us to search with a specific key', async function() {
+    it('should serve every page of a search from the same snapshot', async function() {
         const db = createDb();
         db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(beforeMatrixEvent, matrixProfileOnlyDisplayName);
 
         await db.commit(true);
         db.reload();
 
-        let results = await db.search({
+        const firstPage = await db.search({
             search_term: 'Test',
-            keys: ["content.topic"]
+            order_by_recency: true,
+            limit: 1,
         });
-        expect(results.count).toBe(0);
+        expect(firstPage.count).toBe(2);
+        expect(firstPage.results[0].result).toEqual(matrixEvent);
 
-        db.addEvent(topicEvent);
+        db.addEvent(laterMatrixEvent, matrixProfileOnlyDisplayName);
         await db.commit(true);
         db.reload();
 
-        results = await db.search({
+        const secondPage = await db.search({
             search_term: 'Test',
-            keys: ["content.topic"]
+            limit: 1,
+            next_batch: firstPage.next_batch,
         });
-        expect(results.count).toBe(1);
-        expect(results.results[0].result).toEqual(topicEvent);
+        expect(secondPage.count).toBe(2);
+        expect(secondPage.results[0].result).toEqual(beforeMatrixEvent);
 
-        results = await db.search({
+        await expect(db.search({
             search_term: 'Test',
-        });
-        expect(results.count).toBe(2);
+            next_batch: '00000000-0000-0000-0000-000000000000',
+        })).rejects.toThrow(TypeError);
     });
 
-    it('should allow us to create a db with a specific language', async function() {
+    it('should report errors that happen during a commit', async function() {
         const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
-        expect(() => new Seshat(tempDir, {language: "unknown"})).toThrow('Unsupported language: unknown');
-
-        const db = new Seshat(tempDir, {language: "german"});
-
-        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
-        await db.commit(true);
-        db.reload();
+        const db = new Seshat(tempDir);
+        db.addEvent(matrixEvent, matrixProfile);
 
-        results = await db.search({
-            search_term: 'Test',
-        });
-        expect(results.count).toBe(1);
+        fs.chmodSync(tempDir, 0o555);
+
+        try {
+            // Privileged users ignore the directory permissions, there's
+            // nothing to test in that case.
+            try {
+                fs.writeFileSync(path.join(tempDir, 'probe'), '');
+                return;
+            } catch (e) {}
+
+            const commit = db.commit(true);
+            await expect(commit).rejects.toThrow(TypeError);
+            await expect(commit).rejects.toThrow('Error writing to database');
+        } finally {
+            fs.chmodSync(tempDir, 0o755);
+        }
+
+        // The failed commit didn't take the database down with it.
+        expect(typeof await db.commit(true)).toBe('number');
     });
 
-    it('should allow us to delete the db', async function() {
+    it('should sync the database to disk on a forced commit', async function() {
         const db = createDb();
-        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        const opstamp = await db.commit();
 
         db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        const pending = db.forceCommit();
+        db.addEvent(laterMatrixEvent, matrixProfileOnlyDisplayName);
+
+        expect(await pending).toBeGreaterThan(opstamp);
+        await db.forceCommit();
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(2);
+    });
+
+    it('should find committed events without a reload', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+    });
+
+    it('should only reload the index manually if auto reloading is disabled', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {autoReload: false});
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        let results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(0);
+
+        db.reload();
+        results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+    });
+
+    it('should log the duration of searches to the log file', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const logDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-log-'));
+        const logPath = path.join(logDir, 'seshat.log');
+        const db = new Seshat(tempDir, {logPath: logPath, logLevel: 'debug'});
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        await db.search({search_term: 'Test'});
+
+        const logFiles = fs.readdirSync(logDir).filter((f) => f.startsWith('seshat.log'));
+        expect(logFiles.length).toBe(1);
+
+        const log = fs.readFileSync(path.join(logDir, logFiles[0]), 'utf8');
+        expect(log).toMatch(/search.*term_length=4.*close.*time\.busy/);
+        expect(log).toContain('Committed the index');
+    });
+
+    it('should reject an invalid log level', function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        expect(() => new Seshat(tempDir, {logLevel: 'verbose'})).toThrow(/logLevel/);
+    });
+
+    it('should allow us to run multiple searches at once', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.searchBatch([
+            {search_term: 'Test'},
+            {search_term: ' '},
+            {search_term: 'Test', limit: -1},
+            {search_term: 'Test', room_id: '!other_room:localhost'},
+        ]);
+
+        expect(results.length).toBe(4);
+        expect(results[0].count).toBe(1);
+        expect(results[1]).toBeInstanceOf(TypeError);
+        expect(results[2]).toBeInstanceOf(TypeError);
+        expect(results[2].message).toMatch(/limit/);
+        expect(results[3].count).toBe(0);
+    });
+
+    it('should allow us to cancel a search', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        // The search might finish before it notices the cancellation, but it
+        // never returns partial results.
+        const cancelled = db.searchAsync({search_term: 'Test'});
+        cancelled.cancel();
+        await cancelled.result.then(
+            (results) => expect(results.count).toBe(1),
+            (e) => expect(e.kind).toBe('SearchCancelled'),
+        );
+
+        // Cancelling a finished search does nothing.
+        const finished = db.searchAsync({search_term: 'Test'});
+        const results = await finished.result;
+        finished.cancel();
+        expect(results.count).toBe(1);
+    });
+
+    it('should serve many concurrent reads from a small connection pool', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {connectionPoolSize: 3});
+        await db.addCrawlerCheckpoint(checkPoint);
+
+        const loads = [...Array(100).keys()].map(() => db.loadCheckpoints());
+        for (const checkpoints of await Promise.all(loads)) {
+            expect(checkpoints).toEqual([checkPoint]);
+        }
+    });
+
+    it('should return the opstamp of the commit', async function() {
+        const db = createDb();
+
+        const opstamp = await db.commit(true);
+        expect(typeof opstamp).toBe('number');
+
+        db.addEvent(matrixEvent, matrixProfile);
+        const newOpstamp = await db.commit(true);
+        expect(newOpstamp).toBeGreaterThan(opstamp);
+
+        expect(db.commitSync(true, true)).toBe(newOpstamp);
+        expect(db.commitSync(false)).toBeUndefined();
+    });
+
+    it('should allow us to reload the database asynchronously', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir);
+        db.addEvent(matrixEvent, matrixProfile);
+
+        await db.commit(true);
+        await db.reloadAsync();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+
+        // Remove the index metadata underneath the database.
+        fs.unlinkSync(path.join(tempDir, 'meta.json'));
+
+        expect(() => db.reload()).toThrow('Error reloading the database');
+        await expect(db.reloadAsync()).rejects.toThrow('Error reloading the database');
+    });
+
+    it('should report that the database writer is running', async function() {
+        const db = createDb();
+        await expect(db.getStatus()).resolves.toBeUndefined();
+
+        await db.shutdown();
+        await expect(db.getStatus()).rejects.toThrow();
+    });
+
+    it('should allow us to get the size of the database', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(matrixEventRoom2, matrixProfileOnlyDisplayName);
+
+        await db.commit(true);
+        let size = await db.getSize();
+        expect(size).toBeGreaterThan(0)
+    });
+
+    it('should allow us to get the size of the index and the store', async function() {
+        const db = createDb();
+
+        let size = await db.getSizeBreakdown();
+        expect(size.indexBytes).toBeGreaterThan(0);
+        expect(size.storeBytes).toBeGreaterThan(0);
+        expect(size.totalBytes).toBe(size.indexBytes + size.storeBytes);
+        expect(await db.getSize()).toBe(size.totalBytes);
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        let newSize = await db.getSizeBreakdown();
+        expect(newSize.indexBytes).toBeGreaterThan(size.indexBytes);
+    });
+
+    it('should allow us to add different event types', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(topicEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(nameEvent, matrixProfileOnlyDisplayName);
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'topic', keys: ["content.topic"]});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(topicEvent);
+
+        results = await db.search({search_term: 'room', keys: ["content.name"]});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(nameEvent);
+
+        results = await db.search({search_term: 'message', keys: ["content.body"]});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+    });
+
+    it('should only search the names of rooms if requested', async function() {
+        // Keep the old name of the renamed room searchable as well.
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {indexStateHistory: true});
+        const otherNameEvent = {
+            ...nameEvent,
+            event_id: '$15163622445EBvZO:localhost',
+            room_id: '!TESTROOM2',
+            content: {name: 'Another test room'},
+            origin_server_ts: nameEvent.origin_server_ts + 1000,
+        };
+        const renamedEvent = {
+            ...nameEvent,
+            event_id: '$15163622445EBvZP:localhost',
+            content: {name: 'Renamed test room'},
+            origin_server_ts: nameEvent.origin_server_ts + 2000,
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(topicEvent, matrixProfile);
+        db.addEvent(nameEvent, matrixProfile);
+        db.addEvent(otherNameEvent, matrixProfile);
+        db.addEvent(renamedEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        // The term is contained in messages and topics as well.
+        expect((await db.search({search_term: 'test'})).count).toBe(5);
+
+        let results = await db.search({search_term: 'test', keys: ['content.name']});
+        expect(results.count).toBe(3);
+        expect(results.results.map(r => r.matched_field))
+            .toEqual(['content.name', 'content.name', 'content.name']);
+
+        results = await db.search({
+            search_term: 'test',
+            keys: ['content.name'],
+            room_id: nameEvent.room_id,
+        });
+        expect(results.count).toBe(2);
+        expect(results.results.map(r => r.result.room_id))
+            .toEqual([nameEvent.room_id, nameEvent.room_id]);
+
+        results = await db.search({
+            search_term: 'test',
+            keys: ['content.name'],
+            order_by_recency: true,
+        });
+        expect(results.count).toBe(3);
+        expect(results.results.map(r => r.result))
+            .toEqual([renamedEvent, otherNameEvent, nameEvent]);
+
+        results = await db.search({
+            search_term: 'test',
+            keys: ['content.name'],
+            room_id: nameEvent.room_id,
+            order_by_recency: true,
+            limit: 1,
+        });
+        expect(results.count).toBe(2);
+        expect(results.results.map(r => r.result)).toEqual([renamedEvent]);
+
+        results = await db.search({search_term: 'message', keys: ['content.body']});
+        expect(results.results[0].matched_field).toBe('content.body');
+    });
+
+    it('should only find the latest name of a room', async function() {
+        const db = createDb();
+        const firstRename = {
+            ...nameEvent,
+            event_id: '$15163622445EBvZQ:localhost',
+            content: {name: 'Avocado room'},
+            origin_server_ts: nameEvent.origin_server_ts + 1000,
+        };
+        const secondRename = {
+            ...nameEvent,
+            event_id: '$15163622445EBvZR:localhost',
+            content: {name: 'Banana room'},
+            origin_server_ts: nameEvent.origin_server_ts + 2000,
+        };
+
+        db.addEvent(nameEvent, matrixProfile);
+        db.addEvent(firstRename, matrixProfile);
+        db.addEvent(secondRename, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'avocado', keys: ['content.name']});
+        expect(results.count).toBe(0);
+
+        results = await db.search({search_term: 'banana', keys: ['content.name']});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(secondRename);
+
+        results = await db.search({search_term: 'room', keys: ['content.name']});
+        expect(results.count).toBe(1);
+    });
+
+    it('should not return replies when searching for the text they quote', async function() {
+        const db = createDb();
+        const parent = {
+            ...matrixEvent,
+            event_id: '$parent:localhost',
+            content: {
+                body: 'The secret phrase is swordfish',
+                msgtype: 'm.text',
+            },
+        };
+        const reply = {
+            ...matrixEvent,
+            event_id: '$reply:localhost',
+            content: {
+                body: '> <@alice:example.org> The secret phrase is swordfish\n\nGood to know',
+                format: 'org.matrix.custom.html',
+                formatted_body: '<mx-reply>The secret phrase is swordfish</mx-reply>Good to know',
+                msgtype: 'm.text',
+            },
+        };
+
+        db.addEvent(parent, matrixProfile);
+        db.addEvent(reply, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: '"secret phrase"'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(parent);
+
+        // The stored source of the reply is left untouched.
+        results = await db.search({search_term: 'good'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(reply);
+    });
+
+    it('should reject unsupported event types', function() {
+        const db = createDb();
+        const reactionEvent = {...matrixEvent, type: 'm.reaction'};
+
+        expect(() => db.addEvent(reactionEvent, matrixProfileOnlyDisplayName))
+            .toThrow(TypeError('Unsupported event type m.reaction, supported types are ' +
+                'm.room.message, m.room.topic and m.room.name'));
+    });
+
+    it('should not barf on nul bytes in the event', async function() {
+        const db = createDb();
+        const events = [{event: nulByteEvent, profile: matrixProfileOnlyDisplayName}];
+        db.addHistoricEvents(events);
+        await db.commit(true);
+        db.reload();
+    });
+
+    it('should allow us to search with a specific key', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({
+            search_term: 'Test',
+            keys: ["content.topic"]
+        });
+        expect(results.count).toBe(0);
+
+        db.addEvent(topicEvent);
+        await db.commit(true);
+        db.reload();
+
+        results = await db.search({
+            search_term: 'Test',
+            keys: ["content.topic"]
+        });
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(topicEvent);
+
+        results = await db.search({
+            search_term: 'Test',
+        });
+        expect(results.count).toBe(2);
+
+        await expect(db.search({search_term: 'Test', keys: ["content.foo"]}))
+            .rejects.toThrow(TypeError('Invalid search key content.foo, supported keys are ' +
+                'content.body, content.topic and content.name'));
+        await expect(db.search({search_term: 'Test', keys: [1]}))
+            .rejects.toThrow(TypeError('Search keys need to be strings'));
+    });
+
+    it('should return the requested number of context events', async function() {
+        const db = createDb();
+        db.addEvent(beforeMatrixEvent, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'before', after_limit: 1});
+        expect(results.count).toBe(1);
+        expect(results.results[0].context.events_before.length).toBe(0);
+        expect(results.results[0].context.events_after).toEqual([matrixEvent]);
+
+        results = await db.search({search_term: 'before', after_limit: 2});
+        expect(results.results[0].context.events_after.length).toBe(2);
+
+        results = db.searchSync('later', 10, 2, 0);
+        expect(results.results[0].context.events_before.length).toBe(2);
+        expect(results.results[0].context.events_after.length).toBe(0);
+    });
+
+    it('should order context events by their distance to the result', async function() {
+        const db = createDb();
+        db.addEvent(beforeMatrixEvent, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'later', before_limit: 2});
+        let context = results.results[0].context;
+        expect(context.events_before).toEqual([matrixEvent, beforeMatrixEvent]);
+        expect(context.events_before_info).toEqual([
+            {event_id: matrixEvent.event_id, server_ts: matrixEvent.origin_server_ts, insert_id: 2},
+            {
+                event_id: beforeMatrixEvent.event_id,
+                server_ts: beforeMatrixEvent.origin_server_ts,
+                insert_id: 1,
+            },
+        ]);
+        expect(context.events_after_info).toEqual([]);
+
+        results = await db.search({search_term: 'before', after_limit: 2});
+        context = results.results[0].context;
+        expect(context.events_after).toEqual([matrixEvent, laterMatrixEvent]);
+        expect(context.events_after_info.map(e => e.event_id))
+            .toEqual([matrixEvent.event_id, laterMatrixEvent.event_id]);
+    });
+
+    it('should convert large result sets with their full context', async function() {
+        const db = createDb();
+        const events = [];
+
+        for (let i = 0; i < 120; i++) {
+            events.push({
+                ...matrixEvent,
+                event_id: `$bulk${i}:localhost`,
+                content: {body: `Bulk message number ${i}`, msgtype: 'm.text'},
+                origin_server_ts: matrixEvent.origin_server_ts + i,
+            });
+        }
+
+        await db.addEvents(events.map(event => ({event, profile: matrixProfile})));
+        await db.commit(true);
+        db.reload();
+
+        const args = {
+            search_term: 'bulk',
+            limit: 100,
+            before_limit: 10,
+            after_limit: 10,
+            order_by_recency: true,
+        };
+        const results = await db.search(args);
+
+        // The newest events are returned first, their context is ordered by
+        // the distance to the result.
+        const context = (index) => {
+            const before = events.slice(Math.max(0, index - 10), index).reverse();
+            const after = events.slice(index + 1, index + 11);
+            const info = (e) => ({event_id: e.event_id, server_ts: e.origin_server_ts});
+
+            return {
+                events_before: before,
+                events_after: after,
+                events_before_info: before.map(info),
+                events_after_info: after.map(info),
+                profile_info: {[matrixEvent.sender]: {...matrixProfile, avatar_url: null}},
+            };
+        };
+        const expected = events.slice(20).reverse().map((event, i) => ({
+            rank: results.results[i].rank,
+            result: event,
+            context: context(119 - i),
+            highlights: results.highlights,
+            matched_fields: ['content.body'],
+            matched_field: 'content.body',
+            source_available: true,
+            room_display_name: null,
+            context_truncated: {before: false, after: 119 - i >= 110},
+        }));
+
+        expect(results.count).toBe(120);
+        expect(results.skipped).toBe(0);
+        expect(results.results).toEqual(expected);
+
+        // The synchronous search converts the results the same way.
+        expect(db.searchSync(args).results).toEqual(expected);
+    });
+
+    it('should leave redacted events out of the context', async function() {
+        const db = createDb();
+        db.addEvent(beforeMatrixEvent, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+
+        await db.commit(true);
+        expect(await db.deleteEvent(matrixEvent.event_id)).toBe(true);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'later', before_limit: 2});
+        let context = results.results[0].context;
+        expect(context.events_before).toEqual([beforeMatrixEvent]);
+
+        results = await db.search({
+            search_term: 'later',
+            before_limit: 2,
+            include_redacted_stubs: true,
+        });
+        context = results.results[0].context;
+        expect(context.events_before).toEqual([
+            {event_id: matrixEvent.event_id, redacted: true},
+            beforeMatrixEvent,
+        ]);
+        expect(JSON.stringify(context)).not.toContain(matrixEvent.content.body);
+    });
+
+    it('should skip the context if asked to', async function() {
+        const db = createDb();
+        db.addEvent(beforeMatrixEvent, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({
+            search_term: 'later', before_limit: 2, include_context: false,
+        });
+        let context = results.results[0].context;
+        expect(results.results[0].result).toEqual(laterMatrixEvent);
+        expect(context.events_before).toEqual([]);
+        expect(context.events_before_info).toEqual([]);
+        expect(context.events_after).toEqual([]);
+        expect(Object.keys(context.profile_info)).toEqual([laterMatrixEvent.sender]);
+
+        results = await db.search({
+            search_term: 'later', before_limit: 2, include_profiles: false,
+        });
+        context = results.results[0].context;
+        expect(context.events_before.length).toBe(2);
+        expect(context.profile_info).toEqual({});
+    });
+
+    it('should reject invalid search options', async function() {
+        const db = createDb();
+
+        await expect(db.search({search_term: 'Test', limit: NaN}))
+            .rejects.toThrow(TypeError('Invalid search option limit NaN, ' +
+                'needs to be an integer between 0 and 1000'));
+        await expect(db.search({search_term: 'Test', limit: 1001}))
+            .rejects.toThrow(/limit/);
+        await expect(db.search({search_term: 'Test', before_limit: -1}))
+            .rejects.toThrow(/before_limit/);
+        await expect(db.search({search_term: 'Test', after_limit: 1.5}))
+            .rejects.toThrow(/after_limit/);
+        await expect(db.search({search_term: 'Test', after_limit: "2"}))
+            .rejects.toThrow(TypeError('The search option after_limit needs to be a number'));
+
+        expect(() => db.searchSync('Test', 10, 0, 51)).toThrow(/after_limit/);
+        expect(() => db.searchSync('Test', Infinity)).toThrow(TypeError);
+    });
+
+    it('should allow us to create a db with a specific language', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        expect(() => new Seshat(tempDir, {language: "unknown"})).toThrow('Unsupported language: unknown');
+
+        const db = new Seshat(tempDir, {language: "german"});
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        results = await db.search({
+            search_term: 'Test',
+        });
+        expect(results.count).toBe(1);
+    });
+
+    it('should allow us to detect the language of messages', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        expect(() => new Seshat(tempDir, {detectedLanguages: ["klingon"]}))
+            .toThrow('Unsupported language: klingon');
+
+        const db = new Seshat(tempDir, {
+            detectLanguage: true,
+            detectedLanguages: ["english", "german"],
+        });
+
+        const event = JSON.parse(JSON.stringify(matrixEvent));
+        event.content.body = 'Die alten Häuser in der Stadt wurden im letzten Jahr renoviert';
+        db.addEvent(event, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Haus'});
+        expect(results.count).toBe(1);
+    });
+
+    it('should allow us to split identifiers into their words', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {splitIdentifiers: true});
+
+        const event = JSON.parse(JSON.stringify(matrixEvent));
+        event.content.body = 'the IndexSearcher struct';
+        db.addEvent(event, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        for (const term of ['index', 'searcher', 'IndexSearcher', 'index_searcher']) {
+            const results = await db.search({search_term: term});
+            expect(results.count).toBe(1);
+        }
+
+        expect(() => new Seshat(tempDir, {splitIdentifiers: 'yes'}))
+            .toThrow(TypeError('The database config key splitIdentifiers needs to be a boolean'));
+    });
+
+    it('should reject unknown or invalid config keys', function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+
+        expect(() => new Seshat(tempDir, {pasphrase: "wordpass"}))
+            .toThrow(TypeError('Unknown database config key: pasphrase'));
+        expect(() => new Seshat(tempDir, {passphrase: 1234}))
+            .toThrow(TypeError('The database config key passphrase needs to be a string'));
+        expect(() => new Seshat(tempDir, {wal: "yes"}))
+            .toThrow(TypeError('The database config key wal needs to be a boolean'));
+        expect(() => new Seshat(tempDir, {writeQueueCapacity: -1}))
+            .toThrow(TypeError('The database config key writeQueueCapacity needs to be a non-negative number'));
+    });
+
+    it('should apply the config options to the database', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        let db = new Seshat(tempDir, {
+            passphrase: "wordpass",
+            language: "english",
+            wal: false,
+            busyTimeout: 1000,
+            writeQueueCapacity: 10,
+        });
+
+        for (let i = 0; i < 20; i++) {
+            const event = {...matrixEvent, event_id: `$event${i}:localhost`};
+
+            // Adds don't block once the queue is full, they throw and the
+            // writer needs to catch up first.
+            for (;;) {
+                try {
+                    db.addEvent(event, matrixProfile);
+                    break;
+                } catch (e) {
+                    expect(e.code).toBe('QUEUE_FULL');
+                    await db.commit();
+                }
+            }
+        }
+
+        await db.commit(true);
+        await db.shutdown();
+
+        // The database is encrypted, opening it without the passphrase fails.
+        expect(() => new Seshat(tempDir)).toThrow('Error opening the database');
+
+        db = new Seshat(tempDir, {passphrase: "wordpass", language: "english"});
+        const stats = await db.getStats();
+        expect(stats.eventCount).toBe(20);
+    });
+
+    it('should commit automatically after the configured number of events', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {autoCommitAfterEvents: 2});
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent({...matrixEvent, event_id: '$second:localhost'}, matrixProfile);
+
+        // A normal commit is rate limited, the events are only searchable
+        // because the writer committed them on its own.
+        await db.commit();
+        db.reload();
+
+        const results = db.searchSync({search_term: 'Test'});
+        expect(results.count).toBe(2);
+    });
+
+    it('should reject empty search terms', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        for (const term of ['', '   ', '!!!']) {
+            expect(() => db.searchSync({search_term: term}))
+                .toThrow(TypeError("The search term can't be empty"));
+            await expect(db.search({search_term: term}))
+                .rejects.toThrow(TypeError);
+        }
+
+        const results = db.searchSync({search_term: '  Test  '});
+        expect(results.count).toBe(1);
+    });
+
+    it('should throw a clear error if the database is already open', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir);
+
+        let error;
+        try {
+            new Seshat(tempDir);
+        } catch (e) {
+            error = e;
+        }
+
+        expect(error.code).toBe('DATABASE_LOCKED');
+        expect(error.message).toContain('another process is already using the index');
+
+        await db.shutdown();
+        expect(() => new Seshat(tempDir)).not.toThrow();
+    });
+
+    it('should attach stable codes to errors', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {maxRestrictedIds: 1});
+
+        let error;
+        try {
+            new Seshat(tempDir);
+        } catch (e) {
+            error = e;
+        }
+        expect(error.code).toBe('DATABASE_LOCKED');
+
+        await expect(db.search({
+            search_term: 'Test',
+            restrict_to_events: ['$0:localhost', '$1:localhost'],
+        })).rejects.toMatchObject({code: 'INVALID_QUERY'});
+
+        await db.shutdown();
+
+        error = undefined;
+        try {
+            db.searchSync({search_term: 'Test'});
+        } catch (e) {
+            error = e;
+        }
+        expect(error.code).toBe('DATABASE_CLOSED');
+    });
+
+    it('should create a backup of the database', async function() {
+        const db = createDb();
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const archive = path.join(tempDir, 'backup.tar');
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.backup(archive);
+
+        expect(fs.statSync(archive).size).toBeGreaterThan(0);
+
+        // The backup committed the event.
+        db.reload();
+        expect(db.searchSync({search_term: 'Test'}).count).toBe(1);
+
+        await db.shutdown();
+        await expect(db.backup(archive)).rejects.toThrow(TypeError);
+    });
+
+    it('should import events from a room export', async function() {
+        const db = createDb();
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const exportPath = path.join(tempDir, 'export.json');
+
+        const {room_id, ...event} = matrixEvent;
+        fs.writeFileSync(exportPath, JSON.stringify({
+            room_id: room_id,
+            messages: [
+                event,
+                event,
+                {...event, event_id: '$member:localhost', type: 'm.room.member'},
+            ],
+        }));
+
+        expect(await db.importEvents(exportPath))
+            .toEqual({imported: 1, skipped: 1, duplicates: 1});
+
+        db.reload();
+        const results = db.searchSync({search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+    });
+
+    it('should export the events as JSON lines', async function() {
+        const db = createDb();
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const exportPath = path.join(tempDir, 'events.jsonl');
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(
+            {...matrixEvent, event_id: '$later:localhost', origin_server_ts: 1516362244027},
+            matrixProfile,
+        );
+        await db.commit(true);
+
+        expect(await db.exportEvents(exportPath)).toBe(2);
+
+        const lines = fs.readFileSync(exportPath, 'utf8').trim().split('\n')
+            .map(line => JSON.parse(line));
+        expect(lines.map(line => line.event.event_id))
+            .toEqual([matrixEvent.event_id, '$later:localhost']);
+        expect(lines[0].event).toEqual(matrixEvent);
+        expect(lines[0].room_id).toBe(matrixEvent.room_id);
+        expect(lines[0].indexed).toBe(true);
+    });
+
+    it('should export search results as JSON lines', async function() {
+        const db = createDb();
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const exportPath = path.join(tempDir, 'search.jsonl');
+
+        for (let i = 0; i < 150; i++) {
+            db.addEvent(
+                {...matrixEvent, event_id: `$export${i}:localhost`, origin_server_ts: 1516362244026 + i},
+                matrixProfile,
+            );
+        }
+        await db.commit(true);
+        db.reload();
+
+        const progress = [];
+        const summary = await db.exportSearch(
+            {search_term: 'Test', limit: 1, before_limit: 1},
+            exportPath,
+            (count) => progress.push(count),
+        );
+        expect(summary.count).toBe(150);
+        expect(summary.skipped).toBe(0);
+        expect(progress).toEqual([100, 150]);
+
+        const lines = fs.readFileSync(exportPath, 'utf8').trim().split('\n')
+            .map(line => JSON.parse(line));
+        expect(new Set(lines.map(line => line.result.event_id)).size).toBe(150);
+
+        const line = lines.find(line => line.result.event_id === '$export1:localhost');
+        expect(line.context.events_before[0].event_id).toBe('$export0:localhost');
+        expect(line.context.profile_info[matrixEvent.sender].displayname)
+            .toBe(matrixProfile.displayname);
+
+        await expect(db.exportSearch({search_term: 'Test'}, exportPath, 'progress'))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should allow us to delete events older than a timestamp', async function() {
+        const db = createDb();
+        const laterEvent = {
+            ...matrixEvent,
+            event_id: '$later:localhost',
+            origin_server_ts: matrixEvent.origin_server_ts + 1000,
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterEvent, matrixProfile);
+        await db.commit(true);
+
+        const deleted = await db.deleteEventsOlderThan(matrixEvent.origin_server_ts + 1);
+        expect(deleted).toBe(1);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(laterEvent);
+    });
+
+    it('should allow us to compact the database', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {compactionThreshold: 1});
+        const laterEvent = {
+            ...matrixEvent,
+            event_id: '$later:localhost',
+            origin_server_ts: matrixEvent.origin_server_ts + 1000,
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterEvent, matrixProfile);
+        await db.commit(true);
+
+        expect(await db.compact()).toBe(false);
+
+        await db.deleteEventsOlderThan(matrixEvent.origin_server_ts + 1);
+        expect((await db.getStats()).tombstoneCount).toBe(1);
+
+        expect(await db.compact()).toBe(true);
+        expect((await db.getStats()).tombstoneCount).toBe(0);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(laterEvent);
+    });
+
+    it('should allow us to shrink the database', async function() {
+        const db = createDb();
+        const events = [...Array(200).keys()].map(i => ({
+            event: {
+                ...matrixEvent,
+                event_id: `$${i}:localhost`,
+                origin_server_ts: i,
+            },
+        }));
+
+        await db.addEvents(events);
+        await db.commit(true);
+        await db.deleteEventsOlderThan(150);
+
+        const reclaimed = await db.shrink();
+        expect(reclaimed).toBeGreaterThan(0);
+
+        const stats = await db.getStats();
+        expect(stats.eventCount).toBe(50);
+        expect(stats.tombstoneCount).toBe(0);
+    });
+
+    it('should detect and repair a store that is behind the index', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const backupDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const storeFiles = dir => fs.readdirSync(dir).filter(f => f.startsWith('events.db'));
+
+        let db = new Seshat(tempDir);
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        expect((await db.getStats()).consistency).toBe('consistent');
+        await db.shutdown();
+
+        for (const file of storeFiles(tempDir)) {
+            fs.copyFileSync(path.join(tempDir, file), path.join(backupDir, file));
+        }
+
+        db = new Seshat(tempDir);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+        await db.commit(true);
+        await db.shutdown();
+
+        // Restore only the store from the backup.
+        for (const file of storeFiles(tempDir)) {
+            fs.unlinkSync(path.join(tempDir, file));
+        }
+        for (const file of storeFiles(backupDir)) {
+            fs.copyFileSync(path.join(backupDir, file), path.join(tempDir, file));
+        }
+
+        db = new Seshat(tempDir);
+        expect((await db.getStats()).consistency).toBe('storeBehind');
+
+        expect(await db.repair()).toBe(true);
+        expect(await db.repair()).toBe(false);
+        expect((await db.getStats()).consistency).toBe('consistent');
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+    });
+
+    it('should allow us to get information about the indexed rooms', async function() {
+        const db = createDb();
+        const laterEvent = {
+            ...matrixEvent,
+            event_id: '$later:localhost',
+            origin_server_ts: matrixEvent.origin_server_ts + 1000,
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterEvent, matrixProfile);
+        await db.commit(true);
+
+        const info = await db.getRoomInfo(matrixEvent.room_id);
+        expect(info).toEqual({
+            roomId: matrixEvent.room_id,
+            eventCount: 2,
+            oldestEventTs: matrixEvent.origin_server_ts,
+            newestEventTs: laterEvent.origin_server_ts,
+        });
+
+        const unknown = await db.getRoomInfo('!unknown:localhost');
+        expect(unknown.eventCount).toBe(0);
+        expect(unknown.oldestEventTs).toBeNull();
+
+        expect(await db.getRoomInfos()).toEqual([info]);
+    });
+
+    it('should allow us to group search results by room', async function() {
+        const db = createDb();
+        const otherRoomEvent = {
+            ...matrixEvent,
+            event_id: '$other:localhost',
+            room_id: '!other_room:localhost',
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent({...matrixEvent, event_id: '$second:localhost'}, matrixProfile);
+        db.addEvent(otherRoomEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({
+            search_term: 'Test',
+            limit: 1,
+            group_by_room: true,
+        });
+        expect(results.results.length).toBe(1);
+
+        const groups = results.groups.room_id;
+        expect(groups[matrixEvent.room_id].count).toBe(2);
+        expect(groups[matrixEvent.room_id].results.length).toBe(1);
+        expect(groups[otherRoomEvent.room_id]).toEqual({
+            count: 1,
+            results: [otherRoomEvent.event_id],
+        });
+
+        const ungrouped = await db.search({search_term: 'Test'});
+        expect(ungrouped.groups).toBeUndefined();
+    });
+
+    it('should allow us to preload the database', async function() {
+        const db = createDb();
+        await db.preload();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        await db.preload();
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+
+        await db.shutdown();
+        await expect(db.preload()).rejects.toThrow(TypeError);
+    });
+
+    it('should allow us to exclude the events of senders', async function() {
+        const db = createDb();
+
+        for (let i = 0; i < 10; i++) {
+            db.addEvent({
+                ...matrixEvent,
+                event_id: `$${i}:localhost`,
+                sender: i % 2 === 0 ? '@alice:example.org' : '@bob:example.org',
+                origin_server_ts: matrixEvent.origin_server_ts + i,
+            }, matrixProfile);
+        }
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'Test', limit: 10});
+        expect(results.count).toBe(10);
+        expect(results.results.length).toBe(10);
+
+        results = await db.search({
+            search_term: 'Test',
+            limit: 3,
+            not_senders: ['@alice:example.org'],
+        });
+        expect(results.count).toBe(5);
+
+        const senders = results.results.map(r => r.result.sender);
+
+        while (results.next_batch) {
+            results = await db.search({search_term: 'Test', next_batch: results.next_batch});
+            senders.push(...results.results.map(r => r.result.sender));
+        }
+
+        expect(senders).toEqual(Array(5).fill('@bob:example.org'));
+
+        await expect(db.search({search_term: 'Test', not_senders: ['alice']}))
+            .rejects.toThrow(TypeError);
+        await expect(db.search({search_term: 'Test', not_senders: [1]}))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should allow us to order search results by room', async function() {
+        const db = createDb();
+        const rooms = ['!a:localhost', '!b:localhost', '!c:localhost'];
+
+        for (let i = 0; i < 9; i++) {
+            db.addEvent({
+                ...matrixEvent,
+                event_id: `$${i}:localhost`,
+                room_id: rooms[i % 3],
+                origin_server_ts: matrixEvent.origin_server_ts + i,
+            }, matrixProfile);
+        }
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({
+            search_term: 'Test',
+            limit: 4,
+            order_by_recency: true,
+            group_rooms: true,
+        });
+        expect(results.room_order).toEqual(['!c:localhost', '!b:localhost', '!a:localhost']);
+
+        const roomIds = results.results.map(r => r.result.room_id);
+
+        while (results.next_batch) {
+            results = await db.search({
+                search_term: 'Test',
+                limit: 4,
+                next_batch: results.next_batch,
+            });
+            expect(results.room_order).toEqual(['!c:localhost', '!b:localhost', '!a:localhost']);
+            roomIds.push(...results.results.map(r => r.result.room_id));
+        }
+
+        expect(roomIds).toEqual([
+            ...Array(3).fill('!c:localhost'),
+            ...Array(3).fill('!b:localhost'),
+            ...Array(3).fill('!a:localhost'),
+        ]);
+
+        const ungrouped = await db.search({search_term: 'Test'});
+        expect(ungrouped.room_order).toBeUndefined();
+    });
+
+    it('should allow us to order search results with a recency boost', async function() {
+        const db = createDb();
+        const day = 24 * 60 * 60 * 1000;
+        const now = Date.now();
+
+        const oldEvent = {...matrixEvent, event_id: '$old:localhost', origin_server_ts: now - 366 * day};
+        const newEvent = {...matrixEvent, event_id: '$new:localhost', origin_server_ts: now - day};
+
+        db.addEvent(oldEvent, matrixProfile);
+        db.addEvent(newEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({
+            search_term: 'Test',
+            order_by_recency: 'boosted',
+            recency_half_life_days: 7,
+        });
+        expect(results.count).toBe(2);
+        expect(results.results.map(r => r.result.event_id).sort())
+            .toEqual([newEvent.event_id, oldEvent.event_id].sort());
+        expect(results.results.find(r => r.result.event_id === newEvent.event_id).rank)
+            .toBeGreaterThan(results.results.find(r => r.result.event_id === oldEvent.event_id).rank);
+
+        await expect(db.search({search_term: 'Test', order_by_recency: 'sometimes'}))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should report which field of a result matched', async function() {
+        const db = createDb();
+        const topicEvent = {
+            event_id: '$topic:localhost',
+            sender: matrixEvent.sender,
+            room_id: matrixEvent.room_id,
+            origin_server_ts: matrixEvent.origin_server_ts,
+            type: 'm.room.topic',
+            content: {topic: 'Test topic'},
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(topicEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(2);
+
+        const fields = Object.fromEntries(results.results.map(r => [r.result.event_id, r.matched_fields]));
+        expect(fields[matrixEvent.event_id]).toEqual(['content.body']);
+        expect(fields[topicEvent.event_id]).toEqual(['content.topic']);
+    });
+
+    it('should allow us to search for the sender of events', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'alice'});
+        expect(results.count).toBe(0);
+
+        results = await db.search({search_term: 'alice', search_senders: true});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+        expect(results.results[0].matched_fields).toEqual(['sender']);
+    });
+
+    it('should normalize the ranks of search results if requested', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(topicEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'test'});
+        expect(results.results[0].raw_score).toBeUndefined();
+
+        results = await db.search({search_term: 'test', normalize_scores: true});
+        expect(results.count).toBe(2);
+
+        const maxRawScore = Math.max(...results.results.map(r => r.raw_score));
+        expect(Math.max(...results.results.map(r => r.rank))).toBe(1);
+
+        for (const result of results.results) {
+            expect(result.rank).toBeGreaterThan(0);
+            expect(result.rank).toBeCloseTo(result.raw_score / maxRawScore);
+        }
+    });
+
+    it('should boost the ranks of reacted to events if requested', async function() {
+        const db = createDb();
+        const otherEvent = {...matrixEvent, event_id: '$15163622445EBvZX:localhost'};
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(otherEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'test', limit: 2});
+        expect(results.results[0].rank).toBe(results.results[1].rank);
+        const reactedId = results.results[1].result.event_id;
+
+        const reaction = {
+            type: 'm.reaction',
+            event_id: '$reaction:localhost',
+            room_id: matrixEvent.room_id,
+            sender: '@bob:example.org',
+            content: {
+                'm.relates_to': {
+                    rel_type: 'm.annotation',
+                    event_id: reactedId,
+                    key: '👍',
+                },
+            },
+            origin_server_ts: 1516362244030,
+        };
+        db.addEvent(reaction);
+        await db.commit(true);
+
+        expect(await db.getReactionCounts([reactedId, matrixEvent.room_id]))
+            .toEqual({[reactedId]: 1, [matrixEvent.room_id]: 0});
+
+        results = await db.search({search_term: 'test', limit: 1});
+        expect(results.results[0].result.event_id).not.toBe(reactedId);
+
+        results = await db.search({search_term: 'test', limit: 1, boost_by_reactions: true});
+        expect(results.count).toBe(2);
+        expect(results.results[0].result.event_id).toBe(reactedId);
+
+        expect(() => db.addEvent({...reaction, content: {}})).toThrow(TypeError);
+    });
+
+    it('should include timing information in search results if requested', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'test'});
+        expect(results.timing).toBeUndefined();
+
+        results = await db.search({search_term: 'test', include_timing: true});
+        expect(results.count).toBe(1);
+        expect(results.timing.index_millis).toBeGreaterThanOrEqual(0);
+        expect(results.timing.context_millis).toBeGreaterThanOrEqual(0);
+        expect(results.timing.total_millis).toBeGreaterThanOrEqual(results.timing.index_millis);
+    });
+
+    it('should return partial results if a search runs out of time', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'test', timeout: 60000});
+        expect(results.count).toBe(1);
+        expect(results.timed_out).toBe(false);
+
+        results = await db.search({search_term: 'test', timeout: 0});
+        expect(results.timed_out).toBe(true);
+        expect(results.results.length).toBe(0);
+
+        await expect(db.search({search_term: 'test', timeout: -1})).rejects.toThrow(/timeout/);
+    });
+
+    it('should allow searching with the advanced query syntax', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(topicEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'topic:test', advanced_syntax: true});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result.event_id).toBe(topicEvent.event_id);
+
+        results = await db.search({
+            search_term: 'body:test OR topic:test',
+            advanced_syntax: true,
+            room_id: '!OTHERROOM',
+        });
+        expect(results.count).toBe(0);
+
+        await expect(db.search({search_term: 'body:(test', advanced_syntax: true}))
+            .rejects.toThrow('The search query is invalid');
+    });
+
+    it('should combine the words of a search term using the default operator', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(topicEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'message topic'});
+        expect(results.count).toBe(2);
+
+        results = await db.search({search_term: 'message topic', default_operator: 'and'});
+        expect(results.count).toBe(0);
+
+        results = await db.search({search_term: 'test message', default_operator: 'and'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+
+        await expect(db.search({search_term: 'test', default_operator: 'xor'}))
+            .rejects.toThrow(TypeError);
+
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const andDb = new Seshat(tempDir, {defaultOperator: 'and'});
+
+        andDb.addEvent(matrixEvent, matrixProfile);
+        andDb.addEvent(topicEvent, matrixProfile);
+        await andDb.commit(true);
+        andDb.reload();
+
+        results = await andDb.search({search_term: 'message topic'});
+        expect(results.count).toBe(0);
+
+        results = await andDb.search({search_term: 'message topic', default_operator: 'or'});
+        expect(results.count).toBe(2);
+
+        expect(() => new Seshat(fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-')),
+            {defaultOperator: 'xor'})).toThrow(TypeError);
+    });
+
+    it('should exclude events containing words with a leading minus', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(topicEvent, matrixProfile);
+        db.addEvent(matrixEventRoom2, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'test -topic', room_id: '!TESTROOM'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+
+        await expect(db.search({search_term: '-topic', room_id: '!TESTROOM'}))
+            .rejects.toThrow('only contains excluded terms');
+    });
+
+    it('should load the context of an event without searching', async function() {
+        const db = createDb();
+
+        db.addEvent(beforeMatrixEvent, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const context = await db.getContext(matrixEvent.event_id, 1, 1);
+        expect(context.result).toEqual(matrixEvent);
+        expect(context.context.events_before).toEqual([beforeMatrixEvent]);
+        expect(context.context.events_after).toEqual([laterMatrixEvent]);
+        expect(Object.keys(context.context.profile_info)).toEqual([matrixEvent.sender]);
+
+        const search = await db.search({search_term: 'Test', before_limit: 1, after_limit: 1});
+        const searchResult = search.results.find(r => r.result.event_id === matrixEvent.event_id);
+        expect(context.context).toEqual(searchResult.context);
+
+        expect(await db.getContext('$unknown:localhost', 1, 1)).toBeNull();
+        expect(() => seshatNative.getContext(db.inner, matrixEvent.event_id, 51, 0))
+            .toThrow(TypeError);
+    });
+
+    it('should expand words with a trailing wildcard', async function() {
+        const db = createDb();
+
+        const event = (eventId, body) => ({
+            ...matrixEvent,
+            event_id: eventId,
+            content: {body, msgtype: 'm.text'},
+        });
+
+        db.addEvent(event('$reports', 'Weekly reports'), matrixProfile);
+        db.addEvent(event('$reporting', 'Reporting is done'), matrixProfile);
+        db.addEvent(event('$passport', 'Lost my passport'), matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'report*'});
+        expect(results.count).toBe(2);
+        expect(results.results.map(r => r.result.event_id).sort())
+            .toEqual(['$reporting', '$reports']);
+
+        await expect(db.search({search_term: '*port'}))
+            .rejects.toThrow('Wildcards at the start');
+    });
+
+    it('should reject search terms that exceed the query limits', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {maxQueryLength: 100, maxQueryTerms: 2});
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        await expect(db.search({search_term: 'Test '.repeat(30)}))
+            .rejects.toThrow('The search query is too long');
+        await expect(db.search({search_term: 'one two three'}))
+            .rejects.toThrow('The search query is too long');
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+    });
+
+    it('should validate search terms without searching', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {maxQueryLength: 100, maxQueryTerms: 2});
+
+        expect(db.validateQuery('Test')).toBeNull();
+        expect(db.validateQuery('"Test message"')).toBeNull();
+        expect(db.validateQuery('body:test OR topic:test', true)).toBeNull();
+
+        expect(db.validateQuery('body:"test', true))
+            .toMatch(/^The search query is invalid.* at position 5$/);
+        expect(db.validateQuery('Test '.repeat(30)))
+            .toMatch(/^The search query is too long/);
+        expect(db.validateQuery('one two three'))
+            .toMatch(/^The search query is too long/);
+        expect(db.validateQuery('   ')).toMatch(/empty/);
+
+        expect(() => db.validateQuery('Test', 'yes')).toThrow(TypeError);
+    });
+
+    it('should record the search history if enabled', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {recordSearches: true});
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        expect(await db.getSearchHistory()).toEqual([]);
+
+        await db.search({search_term: 'Test'});
+        await db.search({search_term: 'nonexistent'});
+        await db.search({search_term: 'Test'});
+
+        expect(await db.getSearchHistory()).toEqual(['Test', 'nonexistent']);
+        expect(await db.getSearchHistory(1)).toEqual(['Test']);
+
+        await db.clearSearchHistory();
+        expect(await db.getSearchHistory()).toEqual([]);
+    });
+
+    it('should allow us to search in a thread', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+
+        for (let i = 0; i < 3; i++) {
+            db.addEvent({
+                ...matrixEvent,
+                event_id: `$reply${i}:localhost`,
+                content: {
+                    body: 'Test reply',
+                    msgtype: 'm.text',
+                    'm.relates_to': {
+                        rel_type: 'm.thread',
+                        event_id: matrixEvent.event_id,
+                    },
+                },
+            }, matrixProfile);
+            db.addEvent({
+                ...matrixEvent,
+                event_id: `$message${i}:localhost`,
+            }, matrixProfile);
+        }
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(7);
+
+        results = await db.search({
+            search_term: 'Test',
+            thread_id: matrixEvent.event_id,
+        });
+        expect(results.count).toBe(3);
+
+        const eventIds = results.results.map(r => r.result.event_id).sort();
+        expect(eventIds).toEqual(['$reply0:localhost', '$reply1:localhost', '$reply2:localhost']);
+    });
+
+    it('should allow searching within a set of events', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {maxRestrictedIds: 3});
+
+        for (let i = 0; i < 10; i++) {
+            const body = i < 3 ? 'Test message' : 'Test test test message';
+            db.addEvent({
+                ...matrixEvent,
+                event_id: `$${i}:localhost`,
+                content: {body: body, msgtype: 'm.text'},
+            }, matrixProfile);
+        }
+
+        await db.commit(true);
+        db.reload();
+
+        const restricted = ['$0:localhost', '$1:localhost', '$2:localhost'];
+        const results = await db.search({
+            search_term: 'Test',
+            limit: 10,
+            restrict_to_events: restricted,
+        });
+        expect(results.count).toBe(3);
+        expect(results.results.map(r => r.result.event_id).sort()).toEqual(restricted);
+
+        await expect(db.search({
+            search_term: 'Test',
+            restrict_to_events: [...restricted, '$3:localhost'],
+        })).rejects.toThrow(/Too many restricted event ids/);
+        await expect(db.search({search_term: 'Test', restrict_to_events: [1]}))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should skip messages that have a msgtype that isn\'t indexed', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir, {indexedMsgtypes: ['m.text']});
+
+        const notice = {
+            ...matrixEvent,
+            event_id: '$notice:localhost',
+            content: {
+                body: 'Test notice',
+                msgtype: 'm.notice',
+            },
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(notice, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+
+        expect(() => new Seshat(tempDir, {indexedMsgtypes: 'm.text'})).toThrow(TypeError);
+    });
+
+    it('should allow us to disable the indexing of a room', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+
+        expect(await db.setRoomIndexing(matrixEvent.room_id, false)).toBe(0);
+        expect(await db.getUnindexedRooms()).toEqual([matrixEvent.room_id]);
+
+        db.addEvent({...matrixEvent, event_id: '$dropped:localhost'}, matrixProfile);
+        await db.commit(true);
+        expect((await db.getStats()).eventCount).toBe(1);
+
+        expect(await db.setRoomIndexing(matrixEvent.room_id, false, true)).toBe(1);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(0);
+
+        await db.setRoomIndexing(matrixEvent.room_id, true);
+        expect(await db.getUnindexedRooms()).toEqual([]);
+    });
+
+    it('should allow us to delete a room', async function() {
+        const db = createDb();
+
+        await db.addHistoricEvents([{event: matrixEvent, profile: matrixProfile}], checkPoint);
+        db.addEvent(matrixEventRoom2, matrixProfile);
+        await db.commit(true);
+
+        expect(await db.deleteRoom(matrixEvent.room_id)).toBe(1);
+        await db.commit(true);
+        db.reload();
+
+        expect((await db.search({search_term: 'Test', room_id: matrixEvent.room_id})).count)
+            .toBe(0);
+        expect(await db.loadCheckpoints()).toEqual([]);
+        expect(await db.getIndexedRooms()).toEqual([matrixEventRoom2.room_id]);
+
+        // Events that are added after the room was deleted are kept.
+        await db.addHistoricEvents([{event: matrixEvent, profile: matrixProfile}]);
+        expect(await db.getIndexedRooms())
+            .toEqual([matrixEvent.room_id, matrixEventRoom2.room_id]);
+    });
+
+    it('should allow us to delete the events of a sender', async function() {
+        const db = createDb();
+        const bobEvent = {
+            ...matrixEvent,
+            event_id: '$bob:localhost',
+            sender: '@bob:example.org',
+            origin_server_ts: matrixEvent.origin_server_ts + 1,
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(matrixEventRoom2, matrixProfile);
+        db.addEvent(bobEvent, {displayname: 'Bob'});
+        await db.commit(true);
+
+        const progress = [];
+        expect(await db.deleteSender(matrixEvent.sender, (count) => progress.push(count)))
+            .toBe(2);
+        expect(progress).toEqual([2]);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test', before_limit: 1});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result.sender).toBe(bobEvent.sender);
+        expect(results.results[0].context.events_before).toEqual([]);
+        expect(await db.getProfiles([matrixEvent.sender])).toEqual({
+            [matrixEvent.sender]: null,
+        });
+
+        expect(await db.deleteSender(matrixEvent.sender)).toBe(0);
+        await expect(db.deleteSender(matrixEvent.sender, 1)).rejects.toThrow(TypeError);
+    });
+
+    it('should remember which rooms are fully crawled', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        let db = new Seshat(tempDir);
+
+        expect(await db.getFullyCrawledRooms()).toEqual([]);
+
+        await db.setRoomFullyCrawled(checkPoint.roomId, true);
+        await db.setRoomFullyCrawled('!other:localhost', true);
+        await db.setRoomFullyCrawled('!other:localhost', false);
+        await db.shutdown();
+
+        db = new Seshat(tempDir);
+        expect(await db.getFullyCrawledRooms()).toEqual([checkPoint.roomId]);
+
+        // A new backwards checkpoint means the history needs to be crawled
+        // again.
+        await db.addHistoricEvents([], {...checkPoint, direction: 'b'});
+        expect(await db.getFullyCrawledRooms()).toEqual([]);
+    });
+
+    it('should list the rooms that have events but no checkpoint', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(matrixEventRoom2, matrixProfile);
+        await db.commit(true);
+        await db.addCrawlerCheckpoint(checkPoint);
+
+        expect(await db.getUncrawledRooms()).toEqual([matrixEventRoom2.room_id]);
+
+        const rooms = await new Promise((resolve, reject) => {
+            seshatNative.getUncrawledRooms(db.inner, (err, r) => err ? reject(err) : resolve(r));
+        });
+        expect(rooms).toEqual([matrixEventRoom2.room_id]);
+    });
+
+    it('should list the rooms that have events', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEventRoom2, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+        await db.commit(true);
+        await db.addCrawlerCheckpoint({...checkPoint, roomId: '!OTHERROOM'});
+
+        expect(await db.getIndexedRooms())
+            .toEqual([matrixEvent.room_id, matrixEventRoom2.room_id]);
+        expect(await db.getIndexedRooms(true))
+            .toEqual(['!OTHERROOM', matrixEvent.room_id, matrixEventRoom2.room_id]);
+    });
+
+    it('should allow us to search without storing the events', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        let db = new Seshat(tempDir, {storeSources: false});
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test', before_limit: 1});
+        expect(results.count).toBe(1);
+        expect(results.results[0].source_available).toBe(false);
+        expect(results.results[0].result).toEqual({
+            event_id: matrixEvent.event_id,
+            room_id: matrixEvent.room_id,
+            sender: matrixEvent.sender,
+            origin_server_ts: matrixEvent.origin_server_ts,
+        });
+        expect(results.results[0].context.events_before).toEqual([]);
+
+        await db.shutdown();
+
+        let error;
+        try {
+            db = new Seshat(tempDir);
+        } catch (e) {
+            error = e;
+        }
+        expect(error.code).toBe('CONFIG_MISMATCH');
+    });
+
+    it('should allow us to encrypt the stored events', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        let db = new Seshat(tempDir, {sourceEncryptionPassphrase: 'wordpass'});
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(matrixEvent);
+        expect(results.results[0].context.profile_info[matrixEvent.sender])
+            .toEqual({...matrixProfile, avatar_url: null});
+
+        await db.shutdown();
+
+        let error;
+        try {
+            db = new Seshat(tempDir, {sourceEncryptionPassphrase: 'password'});
+        } catch (e) {
+            error = e;
+        }
+        expect(error.code).toBe('WRONG_PASSPHRASE');
+    });
+
+    it('should allow us to delete the db', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        await db.delete()
+
+        expect(() => db
+            .addEvent(matrixEvent, matrixProfileOnlyDisplayName))
+            .toThrow(TypeError('Database has been closed or deleted'));
+    });
+
+    it('should allow separate locations for the index and the store', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const indexPath = path.join(tempDir, 'index');
+        const storePath = path.join(tempDir, 'store');
+        const config = {indexPath, storePath};
+        let db = new Seshat(tempDir, config);
+
+        db.addEvent(matrixEvent, matrixProfile);
+        await db.commit(true);
+
+        expect(fs.existsSync(path.join(indexPath, 'meta.json'))).toBe(true);
+        expect(fs.existsSync(path.join(storePath, 'events.db'))).toBe(true);
+        expect(fs.existsSync(path.join(tempDir, 'events.db'))).toBe(false);
+
+        const size = await db.getSizeBreakdown();
+        expect(size.indexBytes).toBeGreaterThan(0);
+        expect(size.storeBytes).toBeGreaterThan(0);
+
+        await db.shutdown();
+
+        fs.renameSync(indexPath, path.join(tempDir, 'moved'));
+
+        let error;
+        try {
+            db = new Seshat(tempDir, config);
+        } catch (e) {
+            error = e;
+        }
+        expect(error.code).toBe('NOT_FOUND');
+
+        db = new Seshat(tempDir, {...config, indexPath: path.join(tempDir, 'moved')});
+        await db.delete();
+
+        expect(fs.existsSync(tempDir)).toBe(false);
+    });
+
+    it('should settle running operations when the db is deleted', async function() {
+        const db = createDb();
+        const events = [];
+
+        for (let i = 0; i < 500; i++) {
+            events.push({
+                ...matrixEvent,
+                event_id: `$racing${i}:localhost`,
+                content: {body: `Racing message number ${i}`, msgtype: 'm.text'},
+                origin_server_ts: matrixEvent.origin_server_ts + i,
+            });
+        }
+
+        await db.addEvents(events.map(event => ({event, profile: matrixProfile})));
+        await db.commit(true);
+        db.reload();
+
+        const settled = {search: 0, checkpoints: 0};
+        const outcome = (name) => [
+            (result) => {
+                settled[name] += 1;
+                return {result};
+            },
+            (error) => {
+                settled[name] += 1;
+                return {error};
+            },
+        ];
+
+        const search = db.search({
+            search_term: 'racing',
+            limit: 500,
+            before_limit: 10,
+            after_limit: 10,
+        }).then(...outcome('search'));
+        const checkpoints = db.loadCheckpoints().then(...outcome('checkpoints'));
+
+        await db.delete();
+
+        // The operations either finished before the database was closed or
+        // were rejected because it was closed.
+        for (const {result, error} of [await search, await checkpoints]) {
+            if (error) {
+                expect(error).toBeInstanceOf(TypeError);
+                expect(error.code).toBe('DATABASE_CLOSED');
+            } else {
+                expect(result).toBeDefined();
+            }
+        }
+
+        const {result} = await search;
+        if (result) {
+            expect(result.count).toBe(500);
+        }
+
+        expect(settled).toEqual({search: 1, checkpoints: 1});
+        await expect(db.search({search_term: 'racing'}))
+            .rejects.toMatchObject({code: 'DATABASE_CLOSED'});
+    });
+
+    it('should remove the database files on delete', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const db = new Seshat(tempDir);
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        await db.delete();
+        expect(fs.existsSync(tempDir)).toBeFalsy();
+
+        await expect(db.delete())
+            .rejects.toThrow(TypeError('Database has been closed or deleted'));
+        await expect(db.search({search_term: 'Test'}))
+            .rejects.toThrow(TypeError('Database has been closed or deleted'));
+        expect(() => db.reload())
+            .toThrow(TypeError('Database has been closed or deleted'));
+    });
+
+    it('should allow us to check if the db is empty', async function() {
+        const db = createDb();
+        expect(await db.isEmpty()).toBeTruthy();
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        expect(await db.isEmpty()).toBeFalsy();
+    });
+
+    it('should not report a db that only contains checkpoints as empty', async function() {
+        const db = createDb();
+        await db.addHistoricEvents([], checkPoint);
+
+        expect(await db.isEmpty()).toBeFalsy();
+    });
+
+    it('should allow us to check if a room is already indexed', async function() {
+        const db = createDb();
+        expect(await db.isEmpty()).toBeTruthy();
+        expect(await db.isRoomIndexed(matrixEvent.room_id)).toBeFalsy();
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        expect(await db.isEmpty()).toBeFalsy();
+        expect(await db.isRoomIndexed(matrixEvent.room_id)).toBeTruthy();
+        expect(await db.isRoomIndexed("!fakeRoom:localhost")).toBeFalsy();
+    });
+
+    it('should allow us to check which events are already stored', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+        await db.commit(true);
+
+        const known = await db.filterKnownEvents([
+            matrixEvent.event_id,
+            '$unknown:localhost',
+            laterMatrixEvent.event_id,
+            matrixEvent.event_id,
+        ]);
+        expect(known.sort()).toEqual([matrixEvent.event_id, laterMatrixEvent.event_id].sort());
+        expect(await db.filterKnownEvents([])).toEqual([]);
+        await expect(db.filterKnownEvents([1])).rejects.toThrow(TypeError);
+    });
+
+    it('should allow us to get the newest event timestamp of rooms', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+        await db.commit(true);
+
+        const timestamps = await db.getNewestEventTimestamps([
+            matrixEvent.room_id,
+            '!unknown:localhost',
+        ]);
+        expect(timestamps).toEqual({
+            [matrixEvent.room_id]: laterMatrixEvent.origin_server_ts,
+            '!unknown:localhost': null,
+        });
+        expect(await db.getNewestEventTimestamps([])).toEqual({});
+        await expect(db.getNewestEventTimestamps([1])).rejects.toThrow(TypeError);
+    });
+
+    it('should label search results with the name of the room', async function() {
+        const db = createDb();
+        const aliasEvent = {
+            type: 'm.room.canonical_alias',
+            event_id: '$15163622445EBvZA:localhost',
+            room_id: '!TESTROOM',
+            sender: '@alice:example.org',
+            content: {
+                alias: '#test:localhost',
+            },
+            origin_server_ts: 1516362244000,
+        };
+        const oldNameEvent = {
+            ...nameEvent,
+            event_id: '$15163622445EBvZO:localhost',
+            content: {name: 'Old test room'},
+            origin_server_ts: nameEvent.origin_server_ts - 1000,
+        };
+
+        db.addEvent(matrixEvent, matrixProfile);
         await db.commit(true);
         db.reload();
 
-        await db.delete()
+        let results = await db.search({search_term: 'message'});
+        expect(results.results[0].room_display_name).toBeNull();
 
-        expect(() => db
-            .addEvent(matrixEvent, matrixProfileOnlyDisplayName))
-            .toThrow(TypeError('Database has been closed or deleted'));
+        await db.addEventAsync(aliasEvent);
+        await db.commit(true);
+
+        results = await db.search({search_term: 'message'});
+        expect(results.results[0].room_display_name).toEqual('#test:localhost');
+
+        await db.addEvents([{event: nameEvent}, {event: oldNameEvent}]);
+        await db.commit(true);
+        db.reload();
+
+        results = await db.search({search_term: 'message'});
+        expect(results.results[0].room_display_name).toEqual('Test room');
+
+        expect(await db.getRoomNames(['!TESTROOM', '!unknown:localhost'])).toEqual({
+            '!TESTROOM': 'Test room',
+            '!unknown:localhost': null,
+        });
+        expect(await db.getRoomNames([])).toEqual({});
+        await expect(db.getRoomNames([1])).rejects.toThrow(TypeError);
     });
 
-    it('should allow us to check if the db is empty', async function() {
+    it('should allow us to get the last known profiles of senders', async function() {
         const db = createDb();
-        expect(await db.isEmpty()).toBeTruthy();
+        const renamedProfile = {
+            displayname: 'Alice (back from wonderland)',
+            avatar_url: 'mxc://localhost/wonderland',
+        };
 
-        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, renamedProfile);
         await db.commit(true);
 
-        expect(await db.isEmpty()).toBeFalsy();
+        const profiles = await db.getProfiles([matrixEvent.sender, '@unknown:localhost']);
+        expect(profiles).toEqual({
+            [matrixEvent.sender]: renamedProfile,
+            '@unknown:localhost': null,
+        });
+        expect(await db.getProfiles([])).toEqual({});
+        await expect(db.getProfiles([1])).rejects.toThrow(TypeError);
     });
 
-    it('should allow us to check if a room is already indexed', async function() {
+    it('should limit searches to the events after a watermark', async function() {
         const db = createDb();
-        expect(await db.isEmpty()).toBeTruthy();
-        expect(await db.isRoomIndexed(matrixEvent.room_id)).toBeFalsy();
 
-        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(beforeMatrixEvent, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
         await db.commit(true);
+        db.reload();
 
-        expect(await db.isEmpty()).toBeFalsy();
-        expect(await db.isRoomIndexed(matrixEvent.room_id)).toBeTruthy();
-        expect(await db.isRoomIndexed("!fakeRoom:localhost")).toBeFalsy();
+        expect(await db.getWatermark('verified')).toBeNull();
+
+        const error = await db.search({search_term: 'Test', after_watermark: 'verified'})
+            .catch((e) => e);
+        expect(error).toBeInstanceOf(TypeError);
+        expect(error.code).toBe('NOT_FOUND');
+
+        await db.setWatermark('verified', matrixEvent.origin_server_ts);
+        expect(await db.getWatermark('verified')).toBe(matrixEvent.origin_server_ts);
+
+        let results = await db.search({search_term: 'Test', after_watermark: 'verified'});
+        expect(results.count).toBe(2);
+        const ids = results.results.map((r) => r.result.event_id).sort();
+        expect(ids).toEqual([matrixEvent.event_id, laterMatrixEvent.event_id].sort());
+
+        // The later of a timestamp limit and a watermark is used.
+        results = await db.search({
+            search_term: 'Test',
+            after_watermark: 'verified',
+            after_timestamp: laterMatrixEvent.origin_server_ts,
+        });
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(laterMatrixEvent);
+
+        await db.setWatermark('verified', laterMatrixEvent.origin_server_ts + 1);
+        results = await db.search({search_term: 'Test', after_watermark: 'verified'});
+        expect(results.count).toBe(0);
+
+        await expect(db.setWatermark('', 10)).rejects.toThrow(TypeError);
+        await expect(db.search({search_term: 'Test', after_watermark: ''}))
+            .rejects.toThrow(TypeError);
+    });
+
+    it('should allow the context limits to be overridden per room', async function() {
+        const db = createDb();
+        const rooms = ['!direct:localhost', '!busy:localhost'];
+
+        for (const room of rooms) {
+            for (let i = 0; i < 7; i++) {
+                db.addEvent({
+                    ...matrixEvent,
+                    event_id: `$${room.slice(1, 5)}${i}:localhost`,
+                    room_id: room,
+                    origin_server_ts: matrixEvent.origin_server_ts + i,
+                }, matrixProfile);
+            }
+        }
+
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({
+            search_term: 'Test',
+            limit: 20,
+            before_limit: 1,
+            after_limit: 1,
+            context_overrides: {'!busy:localhost': {before: 3, after: 2}},
+        });
+        expect(results.results.length).toBe(14);
+
+        const context = {};
+        for (const result of results.results) {
+            context[result.result.event_id] = [
+                result.context.events_before.length,
+                result.context.events_after.length,
+            ];
+        }
+
+        expect(context['$dire3:localhost']).toEqual([1, 1]);
+        expect(context['$busy3:localhost']).toEqual([3, 2]);
+
+        // A limit that is left out falls back to the global one.
+        const fallback = await db.search({
+            search_term: 'Test',
+            limit: 20,
+            before_limit: 1,
+            after_limit: 1,
+            context_overrides: {'!busy:localhost': {after: 3}},
+        });
+        const busy = fallback.results.find((r) => r.result.event_id === '$busy3:localhost');
+        expect(busy.context.events_before.length).toBe(1);
+        expect(busy.context.events_after.length).toBe(3);
+
+        await expect(db.search({
+            search_term: 'Test',
+            context_overrides: {'!busy:localhost': {before: 51}},
+        })).rejects.toThrow(TypeError);
+        await expect(db.search({
+            search_term: 'Test',
+            context_overrides: {'busy': {before: 1}},
+        })).rejects.toThrow(TypeError);
+        await expect(db.search({
+            search_term: 'Test',
+            context_overrides: {'!busy:localhost': 3},
+        })).rejects.toThrow(TypeError);
+    });
+
+    it('should report results at the edge of the indexed history', async function() {
+        const db = createDb();
+
+        db.addEvent(beforeMatrixEvent, matrixProfile);
+        db.addEvent(matrixEvent, matrixProfile);
+        db.addEvent(laterMatrixEvent, matrixProfile);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({
+            search_term: 'Test',
+            before_limit: 1,
+            after_limit: 1,
+            require_context: true,
+        });
+        expect(results.count).toBe(3);
+        expect(results.skipped).toBe(0);
+
+        const truncation = {};
+
+        for (const result of results.results) {
+            truncation[result.result.event_id] = result.context_truncated;
+        }
+
+        expect(truncation).toEqual({
+            [beforeMatrixEvent.event_id]: {before: true, after: false},
+            [matrixEvent.event_id]: {before: false, after: false},
+            [laterMatrixEvent.event_id]: {before: false, after: true},
+        });
     });
 
     it('should allow us to store a user specified version in the db', async function() {
@@ -461,6 +3095,91 @@ describe('Database', function() {
         expect(await db.getUserVersion()).toEqual(10);
     });
 
+    it('should keep the user version when the db is reopened', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        let db = new Seshat(tempDir);
+
+        await db.setUserVersion(3);
+        await db.shutdown();
+
+        db = new Seshat(tempDir);
+        expect(await db.getUserVersion()).toEqual(3);
+    });
+
+    it('should report the library and schema versions', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        let db = new Seshat(tempDir);
+
+        const info = await db.getVersionInfo();
+        expect(info.crateVersion).toEqual(Seshat.version);
+        expect(typeof info.databaseVersion).toEqual('number');
+        expect(typeof info.indexVersion).toEqual('number');
+        await db.shutdown();
+
+        db = new Seshat(tempDir);
+        expect(await db.getVersionInfo()).toEqual(info);
+    });
+
+    it('should count the operations of the database', async function() {
+        const db = createDb();
+
+        let metrics = await db.getMetrics();
+        expect(metrics.eventsAdded).toBe(0);
+        expect(metrics.searches).toBe(0);
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        await db.search({search_term: 'Test'});
+        await expect(db.search({search_term: '...'})).rejects.toBeTruthy();
+        await db.deleteEvent(matrixEvent.event_id);
+
+        metrics = await db.getMetrics();
+        expect(metrics.eventsAdded).toBe(1);
+        expect(metrics.eventsDeleted).toBe(1);
+        expect(metrics.commits).toBe(1);
+        expect(metrics.searches).toBe(1);
+        expect(metrics.searchErrors).toBe(1);
+        expect(metrics.sourceCacheMisses).toBe(1);
+    });
+
+    it('should report the queued and committed opstamps', async function() {
+        const db = createDb();
+        expect(await db.getOpstamps()).toEqual({queued: 0, committed: 0});
+
+        const opstamp = db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        let opstamps = await db.getOpstamps();
+        expect(opstamps.queued).toBe(opstamp);
+        expect(opstamps.queued).toBeGreaterThan(opstamps.committed);
+
+        await db.commit(true);
+        opstamps = await db.getOpstamps();
+        expect(opstamps).toEqual({queued: opstamp, committed: opstamp});
+    });
+
+    it('should return sequence numbers as safe integers', async function() {
+        const db = createDb();
+
+        const sequence = db.addEvent(matrixEvent, matrixProfile);
+        const {sequence: batchSequence} = await db.addEvents([{event: topicEvent}]);
+        const committed = await db.commit(true);
+
+        for (const s of [sequence, batchSequence, committed]) {
+            expect(Number.isSafeInteger(s)).toBe(true);
+        }
+
+        // Both forms of a sequence number can be compared as a BigInt.
+        expect(BigInt(committed) >= BigInt(batchSequence)).toBe(true);
+        expect(BigInt('9007199254740993') > BigInt(Number.MAX_SAFE_INTEGER)).toBe(true);
+    });
+
+    it('should not report background errors of a healthy database', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        expect(await db.getPendingErrors()).toEqual([]);
+    });
+
     it('should allow us to create an encrypted db', async function() {
         const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
         let db = new Seshat(tempDir, {passphrase: "wordpass"});
@@ -483,12 +3202,33 @@ describe('Database', function() {
         expect(await db.isEmpty()).toBeFalsy();
 
         await db.changePassphrase("password");
+
+        // The database stays usable after the passphrase has been changed.
+        db.addEvent(topicEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        await db.reloadAsync();
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(2);
+        await db.shutdown();
+
         expect(() => db = new Seshat(tempDir, {passphrase: "wordpass"})).toThrow('');
 
         db = new Seshat(tempDir, {passphrase: "password"});
         expect(await db.isEmpty()).toBeFalsy();
     });
 
+    it('should report an error when changing the passphrase of an unencrypted db', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+
+        await expect(db.changePassphrase("password"))
+            .rejects.toThrow('Error while changing the passphrase');
+
+        // The database is reopened with its old config and stays usable.
+        expect(await db.isEmpty()).toBeFalsy();
+    });
+
     it('should allow us to load events that contain files from the db', async function() {
         const db = createDb();
         db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
@@ -538,23 +3278,177 @@ describe('Database', function() {
         expect(events[1].event).toEqual(videoEvent);
     });
 
+    it('should paginate through file events without duplicates or gaps', async function() {
+        const db = createDb();
+        const fileEvents = [];
+
+        for (let i = 0; i < 12; i++) {
+            const event = {
+                ...fileEvent,
+                event_id: `$151636225${i}Epage:localhost`,
+                origin_server_ts: fileEvent.origin_server_ts + i * 1000,
+                content: {body: `Page file ${i}`, msgtype: 'm.file'},
+            };
+            fileEvents.push(event);
+            db.addEvent(event, matrixProfileOnlyDisplayName);
+        }
+
+        await db.commit(true);
+
+        const seen = [];
+        let fromEvent = undefined;
+
+        for (let page = 0; page < 3; page++) {
+            const events = await db.loadFileEvents({roomId: fileEvent.room_id, limit: 4, fromEvent});
+            expect(events.length).toBe(4);
+            expect(events[0].profile.displayname).toEqual(matrixProfileOnlyDisplayName.displayname);
+            seen.push(...events.map((e) => e.event.event_id));
+            fromEvent = events[events.length - 1].event.event_id;
+        }
+
+        const expected = fileEvents.map((e) => e.event_id).reverse();
+        expect(seen).toEqual(expected);
+
+        const events = await db.loadFileEvents({roomId: fileEvent.room_id, fromEvent});
+        expect(events.length).toBe(0);
+
+        // Without a limit, ten events are loaded.
+        expect((await db.loadFileEvents({roomId: fileEvent.room_id})).length).toBe(10);
+    });
+
+    it('should validate the arguments for loading file events', async function() {
+        const db = createDb();
+
+        await expect(db.loadFileEvents({})).rejects.toThrow(TypeError);
+        await expect(db.loadFileEvents({roomId: 5})).rejects.toThrow(/roomId/);
+        await expect(db.loadFileEvents({roomId: fileEvent.room_id, limit: -1}))
+            .rejects.toThrow(/limit/);
+        await expect(db.loadFileEvents({roomId: fileEvent.room_id, limit: "10"}))
+            .rejects.toThrow(/limit/);
+        await expect(db.loadFileEvents({roomId: fileEvent.room_id, direction: "sideways"}))
+            .rejects.toThrow(/direction/);
+    });
+
+    it('should allow us to load and search events that contain links', async function() {
+        const db = createDb();
+        const linkEvent = {
+            ...matrixEvent,
+            event_id: '$15163622449EBvZB:localhost',
+            origin_server_ts: matrixEvent.origin_server_ts + 1000,
+            content: {body: 'Test link https://matrix.org', msgtype: 'm.text'},
+        };
+        const schemeEvent = {
+            ...matrixEvent,
+            event_id: '$15163622450EBvZB:localhost',
+            origin_server_ts: matrixEvent.origin_server_ts + 2000,
+            content: {body: 'Test the https:// scheme', msgtype: 'm.text'},
+        };
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(linkEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(schemeEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(fileEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        await db.reloadAsync();
+
+        let events = await db.loadUrlEvents({roomId: matrixEvent.room_id, limit: 10});
+        expect(events.length).toBe(1);
+        expect(events[0].event).toEqual(linkEvent);
+        expect(events[0].profile.displayname).toEqual(matrixProfileOnlyDisplayName.displayname);
+
+        events = await db.loadUrlEvents({roomId: matrixEvent.room_id, fromEvent: linkEvent.event_id});
+        expect(events.length).toBe(0);
+
+        let results = await db.search({search_term: 'Test', contains_url: true});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(linkEvent);
+
+        // Files never count as links.
+        results = await db.search({search_term: 'Test', contains_url: false});
+        expect(results.count).toBe(3);
+
+        await expect(db.search({search_term: '', contains_url: true}))
+            .rejects.toThrow(TypeError);
+
+        results = await db.search({
+            search_term: '',
+            room_id: matrixEvent.room_id,
+            order_by_recency: true,
+            contains_url: true,
+        });
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(linkEvent);
+
+        await expect(db.loadUrlEvents({})).rejects.toThrow(TypeError);
+    });
+
+    it('should allow us to search for files using their filename', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        db.addEvent(namedFileEvent, matrixProfileOnlyDisplayName);
+        db.addEvent({
+            ...imageEvent,
+            event_id: '$15163622492Eimage:localhost',
+            content: {msgtype: 'm.image'},
+        }, matrixProfileOnlyDisplayName);
+
+        await db.commit(true);
+        db.reload();
+
+        let results = await db.search({search_term: 'quarterly report'});
+        expect(results.count).toBe(1);
+        expect(results.results[0].result).toEqual(namedFileEvent);
+
+        results = await db.search({search_term: 'numbers'});
+        expect(results.count).toBe(0);
+
+        const stats = await db.getStats();
+        expect(stats.eventCount).toBe(3);
+    });
+
     it('should allow us query the database for statistics', async function() {
         const db = createDb();
 
         let stats = await db.getStats(true);
-        expect(stats.eventCount).toBe(0);
-        expect(stats.roomCount).toBe(0);
+        expect(stats.eventCount).toBeNull();
+        expect(stats.roomCount).toBeNull();
+        expect(typeof stats.size).toBe('number');
 
         db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
         db.addEvent(fileEvent, matrixProfileOnlyDisplayName);
         db.addEvent(imageEvent, matrixProfileOnlyDisplayName);
         db.addEvent(videoEvent, matrixProfileOnlyDisplayName);
 
+        stats = await db.getStats(true);
+        expect(stats.queuedEvents).toBe(4);
+
         await db.commit(true);
         stats = await db.getStats(true);
         expect(stats.eventCount).toBe(4);
         expect(stats.roomCount).toBe(1);
         expect(stats.size).toBeGreaterThan(0);
+        expect(stats.queuedEvents).toBe(0);
+    });
+
+    it('should serve the sources of repeated search results from a cache', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        const first = await db.search({search_term: 'Test'});
+        let stats = await db.getStats();
+        expect(stats.sourceCacheHits).toBe(0);
+        expect(stats.sourceCacheMisses).toBe(1);
+
+        const second = await db.search({search_term: 'Test message'});
+        expect(second.results[0].result).toEqual(first.results[0].result);
+        stats = await db.getStats();
+        expect(stats.sourceCacheHits).toBe(1);
+
+        await db.deleteEvent(matrixEvent.event_id);
+        const afterDelete = await db.search({search_term: 'Test'});
+        expect(afterDelete.results).toEqual([]);
     });
 
     it('should allow us to delete events from the database/index', async function() {
@@ -569,7 +3463,7 @@ describe('Database', function() {
         expect(results.results[0].result).toEqual(matrixEvent);
 
         let deleted = await db.deleteEvent(matrixEvent.event_id);
-        expect(deleted).toBeFalsy();
+        expect(deleted).toBe(true);
         await db.commit(true);
         db.reload();
 
@@ -587,7 +3481,7 @@ describe('Database', function() {
         expect(results.count).toBe(2);
 
         deleted = await db.deleteEvent(matrixEvent.event_id);
-        expect(deleted).toBeFalsy();
+        expect(deleted).toBe(true);
         await db.commit(true);
         db.reload();
 
@@ -596,6 +3490,68 @@ describe('Database', function() {
         expect(results.results[0].result).toEqual(fileEvent);
     });
 
+    it('should drop events that were deleted before they were added', async function() {
+        const db = createDb();
+
+        const deleted = await db.deleteEvent(matrixEvent.event_id);
+        expect(deleted).toBe(false);
+
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(0);
+    });
+
+    it('should allow us to delete events that were never added', async function() {
+        const db = createDb();
+        db.addEvent(matrixEvent, matrixProfileOnlyDisplayName);
+
+        await db.deleteEvent('$unknown:localhost');
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(1);
+    });
+
+    it('should reject profile fields that aren\'t strings', async function() {
+        const db = createDb();
+
+        expect(() => db.addEvent(matrixEvent, {displayname: 42}))
+            .toThrow(TypeError('The displayname of the profile must be a string or null'));
+        expect(() => db.addEvent(matrixEvent, {avatar_url: {}}))
+            .toThrow(TypeError('The avatar_url of the profile must be a string or null'));
+        await expect(db.addHistoricEvents([{event: matrixEvent, profile: {displayname: true}}]))
+            .rejects.toThrow(TypeError('The displayname of the profile must be a string or null'));
+        expect(await db.isEmpty()).toBe(true);
+    });
+
+    it('should trim profile fields and treat empty ones as unset', async function() {
+        const db = createDb();
+
+        db.addEvent(matrixEvent, {displayname: '  Alice \n', avatar_url: ' '});
+        await db.addHistoricEvents([{
+            event: {...matrixEvent, event_id: '$backlog:localhost', sender: '@bob:example.org'},
+            profile: {displayname: '', avatar_url: ' mxc://localhost/bob'},
+        }]);
+        await db.commit(true);
+        db.reload();
+
+        const results = await db.search({search_term: 'Test'});
+        expect(results.count).toBe(2);
+
+        const profiles = {};
+        for (const result of results.results) {
+            Object.assign(profiles, result.context.profile_info);
+        }
+
+        expect(profiles['@alice:example.org']).toEqual({displayname: 'Alice', avatar_url: null});
+        expect(profiles['@bob:example.org'])
+            .toEqual({displayname: null, avatar_url: 'mxc://localhost/bob'});
+    });
+
     it('should accept events if the avatar URL is null.', function() {
         const badProfile = {
             displayname: 'Alice (from wonderland)',
@@ -628,6 +3584,50 @@ describe('Database', function() {
         expect(() => db.addEvent(badEvent, matrixProfile)).toThrow(TypeError('Event doesn\'t contain a valid timestamp'));
     });
 
+    it('should reject events with a timestamp that isn\'t finite', function() {
+        const db = createDb();
+
+        for (const timestamp of [NaN, Infinity, -Infinity]) {
+            const event = {...matrixEvent, origin_server_ts: timestamp};
+            expect(() => db.addEvent(event, matrixProfile)).toThrow(TypeError('Event doesn\'t contain a valid timestamp'));
+        }
+    });
+
+    it('should manage the databases of several accounts', async function() {
+        const tempDir = fs.mkdtempSync(path.join(os.tmpdir(), 'seshat-'));
+        const manager = new SeshatManager(tempDir, {connectionBudget: 8});
+
+        const alice = manager.open('@alice:example.org');
+        const bob = manager.open('@bob:example.org');
+        expect(manager.open('@alice:example.org')).toBe(alice);
+        expect(() => manager.open('')).toThrow(TypeError);
+
+        alice.addEvent(matrixEvent, matrixProfile);
+        await alice.commit();
+        await bob.commit();
+        alice.reload();
+        bob.reload();
+
+        expect(manager.listAccounts()).toEqual(['@alice:example.org', '@bob:example.org']);
+        expect((await alice.search({search_term: 'Test'})).count).toBe(1);
+        expect((await bob.search({search_term: 'Test'})).count).toBe(0);
+
+        // Closing one account doesn't affect the other one.
+        expect(await manager.close('@bob:example.org')).toBe(true);
+        expect(await manager.close('@bob:example.org')).toBe(false);
+        expect(manager.get('@bob:example.org')).toBeNull();
+        expect(manager.openAccounts()).toEqual(['@alice:example.org']);
+        expect((await alice.search({search_term: 'Test'})).count).toBe(1);
+
+        await manager.delete('@bob:example.org');
+        expect(manager.listAccounts()).toEqual(['@alice:example.org']);
+
+        await manager.close('@alice:example.org');
+        const reopened = manager.open('@alice:example.org');
+        expect((await reopened.search({search_term: 'Test'})).count).toBe(1);
+        await manager.close('@alice:example.org');
+    });
+
     it('should allow us to reindex a database', async function() {
         const dir = '../data/database/v2';
         expect(() => new Seshat(dir)).toThrow(ReindexError);
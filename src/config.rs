@@ -12,13 +12,158 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use uuid::Uuid;
 #[cfg(feature = "encryption")]
 use zeroize::Zeroizing;
 
-use crate::events::{EventType, RoomId};
+use crate::{
+    events::{EventId, EventType, RoomId},
+    index::{TANTIVY_MIN_WRITER_HEAP_SIZE, TANTIVY_WRITER_HEAP_SIZE},
+};
 
 const DEFAULT_LOAD_LIMIT: usize = 20;
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_WRITE_QUEUE_CAPACITY: usize = 1000;
+const DEFAULT_COMPACTION_THRESHOLD: usize = 1000;
+const DEFAULT_SEARCH_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_CONNECTION_POOL_SIZE: u32 = 10;
+const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+// The writer thread and the database itself each hold a connection for as
+// long as the database is open.
+const MIN_CONNECTION_POOL_SIZE: u32 = 3;
+const DEFAULT_MAX_QUERY_LENGTH: usize = 1000;
+const DEFAULT_MAX_QUERY_TERMS: usize = 64;
+const DEFAULT_MAX_QUERY_CLAUSES: usize = 512;
+const DEFAULT_MAX_WILDCARD_TERMS: usize = 100;
+const DEFAULT_MAX_RESTRICTED_IDS: usize = 1000;
+const DEFAULT_MAX_INDEXED_BODY_BYTES: usize = 256 * 1024;
+const DEFAULT_MAX_EVENT_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_SOURCE_CACHE_CAPACITY: usize = 1000;
+// How many times the limit of a search is fetched from the index if the
+// results are boosted by their reactions.
+const REACTION_BOOST_CANDIDATES: usize = 5;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+/// Limits for the complexity of search queries.
+pub(crate) struct QueryLimits {
+    /// The maximal length of a search term in characters.
+    pub(crate) max_length: usize,
+    /// The maximal number of distinct words of a search term.
+    pub(crate) max_terms: usize,
+    /// The maximal number of term clauses the query may expand to, every
+    /// word is searched for in every searched field.
+    pub(crate) max_clauses: usize,
+    /// The maximal number of terms a word with a wildcard is expanded to.
+    pub(crate) max_wildcard_terms: usize,
+    /// The maximal number of events a search may be restricted to.
+    pub(crate) max_restricted_ids: usize,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        QueryLimits {
+            max_length: DEFAULT_MAX_QUERY_LENGTH,
+            max_terms: DEFAULT_MAX_QUERY_TERMS,
+            max_clauses: DEFAULT_MAX_QUERY_CLAUSES,
+            max_wildcard_terms: DEFAULT_MAX_WILDCARD_TERMS,
+            max_restricted_ids: DEFAULT_MAX_RESTRICTED_IDS,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+/// Limits for the size of the events that are added to the database.
+pub(crate) struct EventSizeLimits {
+    /// The maximal number of bytes of the body of an event that are indexed.
+    pub(crate) max_indexed_body_bytes: usize,
+    /// Should the bodies in the stored sources be truncated to the same
+    /// length as the indexed ones.
+    pub(crate) truncate_stored_bodies: bool,
+    /// The maximal size of an event in bytes, bigger events are rejected.
+    pub(crate) max_event_size: usize,
+}
+
+impl Default for EventSizeLimits {
+    fn default() -> Self {
+        EventSizeLimits {
+            max_indexed_body_bytes: DEFAULT_MAX_INDEXED_BODY_BYTES,
+            truncate_stored_bodies: false,
+            max_event_size: DEFAULT_MAX_EVENT_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// The order of search results.
+pub enum SearchOrder {
+    /// Order the results by their relevance to the search term.
+    Rank,
+    /// Order the results by the timestamp of the events, newest first.
+    Recency,
+    /// Order the results by their relevance to the search term, newer events
+    /// are preferred over older ones with a similar relevance.
+    RecencyBoosted {
+        /// The relevance of an event is halved for every half-life of its
+        /// age.
+        half_life: Duration,
+    },
+}
+
+impl Default for SearchOrder {
+    fn default() -> Self {
+        SearchOrder::Rank
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+/// How the words of a search term are combined if the term doesn't combine
+/// them explicitly.
+pub enum QueryOperator {
+    /// Events need to contain all the words of the search term.
+    And,
+    /// Events need to contain at least one of the words of the search term.
+    Or,
+}
+
+impl Default for QueryOperator {
+    fn default() -> Self {
+        QueryOperator::Or
+    }
+}
+
+/// The number of context events that are loaded before and after the
+/// results of a search, see `SearchConfig::context_overrides()`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ContextLimits {
+    pub(crate) before: usize,
+    pub(crate) after: usize,
+    pub(crate) overrides: HashMap<RoomId, (usize, usize)>,
+}
+
+impl ContextLimits {
+    pub(crate) fn new(before: usize, after: usize) -> Self {
+        ContextLimits {
+            before,
+            after,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// The number of events to load before and after the results of the
+    /// given room.
+    pub(crate) fn for_room(&self, room_id: &str) -> (usize, usize) {
+        self.overrides
+            .get(room_id)
+            .copied()
+            .unwrap_or((self.before, self.after))
+    }
+}
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(default)]
@@ -30,10 +175,30 @@ pub struct SearchConfig {
     pub(crate) limit: usize,
     pub(crate) before_limit: usize,
     pub(crate) after_limit: usize,
-    pub(crate) order_by_recency: bool,
+    pub(crate) context_overrides: HashMap<RoomId, (usize, usize)>,
+    pub(crate) order: SearchOrder,
     pub(crate) room_id: Option<RoomId>,
+    pub(crate) thread_id: Option<EventId>,
+    pub(crate) restrict_to_events: Option<Vec<EventId>>,
+    pub(crate) not_senders: Vec<String>,
+    pub(crate) contains_url: Option<bool>,
+    pub(crate) min_timestamp: Option<i64>,
+    pub(crate) after_watermark: Option<String>,
     pub(crate) keys: Vec<EventType>,
     pub(crate) next_batch: Option<Uuid>,
+    pub(crate) group_by_room: bool,
+    pub(crate) group_rooms: bool,
+    pub(crate) search_senders: bool,
+    pub(crate) normalize_scores: bool,
+    pub(crate) boost_by_reactions: bool,
+    pub(crate) include_timing: bool,
+    pub(crate) include_context: bool,
+    pub(crate) include_profiles: bool,
+    pub(crate) include_redacted_stubs: bool,
+    pub(crate) require_context: bool,
+    pub(crate) advanced_syntax: bool,
+    pub(crate) default_operator: Option<QueryOperator>,
+    pub(crate) timeout: Option<Duration>,
 }
 
 impl SearchConfig {
@@ -52,6 +217,114 @@ impl SearchConfig {
         self
     }
 
+    /// Limit the search to the events of a specific thread.
+    /// Events that aren't part of a thread are never part of the search
+    /// result if this is set.
+    /// # Arguments
+    ///
+    /// * `thread_id` - The event id of the root of the thread.
+    pub fn for_thread(&mut self, thread_id: &str) -> &mut Self {
+        self.thread_id = Some(thread_id.to_owned());
+        self
+    }
+
+    /// Limit the search to the given events.
+    ///
+    /// Only the given events are scored, events that aren't part of the list
+    /// are never part of the search result however well they match. This
+    /// allows a known set of events to be ranked by a search term without
+    /// searching the whole index. Restricting a search to more events than
+    /// the configured maximum fails with a `TooManyRestrictedIds` error,
+    /// see `Config::set_max_restricted_ids()`. The default is to search
+    /// all events.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_ids` - The ids of the events that should be searched.
+    pub fn restrict_to_events(&mut self, event_ids: Vec<EventId>) -> &mut Self {
+        self.restrict_to_events = Some(event_ids);
+        self
+    }
+
+    /// Exclude the events of the given senders from the search.
+    ///
+    /// This can be used to only find the messages other users sent, e.g. by
+    /// excluding the local user. The events are excluded by the search
+    /// itself, so the count and the pagination of the results take the
+    /// filter into account. The default is to return the events of every
+    /// sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `senders` - The user ids of the senders whose events should be
+    ///   excluded.
+    pub fn not_senders(&mut self, senders: Vec<String>) -> &mut Self {
+        self.not_senders = senders;
+        self
+    }
+
+    /// Limit the search to events that do or don't contain a link.
+    ///
+    /// A link is an `http://` or `https://` URL in the body of a message,
+    /// other events never contain one. If this is set for a search that is
+    /// limited to a room and ordered by recency the search term may be
+    /// empty, which returns the latest events of the room that contain a
+    /// link. The default is to return events regardless of their links.
+    ///
+    /// # Arguments
+    ///
+    /// * `contains_url` - Flag to determine if the events should or shouldn't
+    ///   contain a link.
+    pub fn contains_url(&mut self, contains_url: bool) -> &mut Self {
+        self.contains_url = Some(contains_url);
+        self
+    }
+
+    /// Limit the search to events that were sent at or after the given
+    /// timestamp.
+    ///
+    /// The events are filtered by the search itself, so the count and the
+    /// pagination of the results take the filter into account. The default
+    /// is to return events regardless of their age.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - The timestamp, in milliseconds since the Unix epoch,
+    ///   of the oldest event that should be returned.
+    pub fn after_timestamp(&mut self, timestamp: i64) -> &mut Self {
+        self.min_timestamp = Some(timestamp);
+        self
+    }
+
+    /// Limit the search to events that were sent at or after the timestamp
+    /// of a named watermark, see `Database::set_watermark()`.
+    ///
+    /// The watermark is looked up when the search starts, a search that
+    /// continues a previous one with a `next_batch` token keeps using the
+    /// timestamp the watermark had back then. If the config has a timestamp
+    /// limit as well, the later of the two is used. Searching after a
+    /// watermark that doesn't exist fails with a `WatermarkNotFound` error.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the watermark.
+    pub fn after_watermark<N: Into<String>>(&mut self, name: N) -> &mut Self {
+        self.after_watermark = Some(name.into());
+        self
+    }
+
+    /// The lowest value of the date field of the index an event may have to
+    /// be part of the search results.
+    pub(crate) fn min_date(&self) -> Option<u64> {
+        self.min_timestamp.map(|ts| ts.max(0) as u64)
+    }
+
+    /// Can the search term be empty, only searches that list the events of a
+    /// room with links don't need one, see `SearchConfig::contains_url()`.
+    pub fn allows_empty_term(&self) -> bool {
+        self.contains_url == Some(true) && self.room_id.is_some() && self.is_ordered_by_recency()
+    }
+
     /// Limit the number of events that will be returned in the search result.
     /// The default for the limit is 10.
     /// # Arguments
@@ -86,6 +359,20 @@ impl SearchConfig {
         self
     }
 
+    /// Use different context limits for the results of the given rooms.
+    ///
+    /// The results of rooms that aren't part of the map use the limits set
+    /// by `before_limit()` and `after_limit()`. This allows e.g. direct
+    /// messages to get less context than busy rooms in the same search.
+    /// # Arguments
+    ///
+    /// * `overrides` - A map from a room id to the number of events that
+    ///   should be loaded before and after the results of the room.
+    pub fn context_overrides(&mut self, overrides: HashMap<RoomId, (usize, usize)>) -> &mut Self {
+        self.context_overrides = overrides;
+        self
+    }
+
     /// Should the matching events be ordered by recency. The default is to
     /// order them by the search score.
     /// # Arguments
@@ -93,10 +380,29 @@ impl SearchConfig {
     /// * `order_by_recency` - Flag to determine if we should order by recency.
     ///   result.
     pub fn order_by_recency(&mut self, order_by_recency: bool) -> &mut Self {
-        self.order_by_recency = order_by_recency;
+        self.order = if order_by_recency {
+            SearchOrder::Recency
+        } else {
+            SearchOrder::Rank
+        };
+        self
+    }
+
+    /// Set the order of the search results.
+    /// The default is to order the results by their relevance.
+    /// # Arguments
+    ///
+    /// * `order` - The order the search results should be returned in.
+    pub fn order_by(&mut self, order: SearchOrder) -> &mut Self {
+        self.order = order;
         self
     }
 
+    /// Are the results ordered purely by the timestamp of the events.
+    pub(crate) fn is_ordered_by_recency(&self) -> bool {
+        self.order == SearchOrder::Recency
+    }
+
     /// Set the event types that should be used as search keys.
     ///
     /// This limits which events will be searched for. This method can be called
@@ -114,6 +420,248 @@ impl SearchConfig {
         self
     }
 
+    /// Group the search results by room.
+    ///
+    /// If set, the search result contains the number of matching events for
+    /// every room together with the ids of the best matching events of the
+    /// room. The counts include all matching events, not only the ones of the
+    /// current batch. The default is to not group the results.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_by_room` - Flag to determine if the results should be grouped.
+    pub fn group_by_room(&mut self, group_by_room: bool) -> &mut Self {
+        self.group_by_room = group_by_room;
+        self
+    }
+
+    /// Order the search results so that the matches of a room follow each
+    /// other.
+    ///
+    /// The rooms are ordered by their best match, i.e. the highest scoring
+    /// one or the newest one if the results are ordered by recency. The
+    /// matches of a room keep the order of the search. The order of the rooms
+    /// is returned with the search result and stays the same for all the
+    /// batches of the search. The default is to not reorder the results.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_rooms` - Flag to determine if the results should be ordered
+    ///   by room.
+    pub fn group_rooms(&mut self, group_rooms: bool) -> &mut Self {
+        self.group_rooms = group_rooms;
+        self
+    }
+
+    /// Search the senders of events as well.
+    ///
+    /// If set, the user ids of the senders are searched together with the
+    /// content of the events, searching for "alice" finds the events that
+    /// `@alice:example.org` sent. The default is to only search the content.
+    ///
+    /// # Arguments
+    ///
+    /// * `search_senders` - Flag to determine if senders should be searched.
+    pub fn search_senders(&mut self, search_senders: bool) -> &mut Self {
+        self.search_senders = search_senders;
+        self
+    }
+
+    /// Rescale the scores of the returned results to the range between 0 and
+    /// 1.
+    ///
+    /// The scores of a batch are divided by the highest score of the batch,
+    /// the best result of every batch has a score of 1. Unlike raw scores,
+    /// which depend on the term frequencies and the size of the index, these
+    /// can be compared between searches. The raw scores are kept in the
+    /// `raw_score` field of the results. The default is to return raw scores.
+    ///
+    /// # Arguments
+    ///
+    /// * `normalize_scores` - Flag to determine if scores should be
+    ///   normalized.
+    pub fn normalize_scores(&mut self, normalize_scores: bool) -> &mut Self {
+        self.normalize_scores = normalize_scores;
+        self
+    }
+
+    /// Rank the events that were reacted to higher.
+    ///
+    /// The score of every result is multiplied by `1 + ln(1 + reactions)`,
+    /// where `reactions` is the number of reactions of the event, see
+    /// `Database::add_reaction()`. The index can't take the reactions into
+    /// account, so more results than the limit are fetched from the index
+    /// and the best of them are returned after they were boosted. Results
+    /// that are ordered by recency or by room aren't boosted. The default is
+    /// to rank events by their score alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `boost_by_reactions` - Flag to determine if reactions should boost
+    ///   the scores of the results.
+    pub fn boost_by_reactions(&mut self, boost_by_reactions: bool) -> &mut Self {
+        self.boost_by_reactions = boost_by_reactions;
+        self
+    }
+
+    /// Should the scores of the results be boosted by their reactions.
+    pub(crate) fn is_boosted_by_reactions(&self) -> bool {
+        self.boost_by_reactions && !self.is_ordered_by_recency() && !self.group_rooms
+    }
+
+    /// The number of results that should be fetched from the index, results
+    /// that are boosted after the search need to be over-fetched.
+    pub(crate) fn candidate_limit(&self) -> usize {
+        if self.is_boosted_by_reactions() {
+            self.limit.saturating_mul(REACTION_BOOST_CANDIDATES)
+        } else {
+            self.limit
+        }
+    }
+
+    /// Measure how long the phases of the search take.
+    ///
+    /// If set, the `timing` field of the returned `SearchBatch` contains the
+    /// time it took to query the index and to load the events from the
+    /// database. The default is to not measure the search.
+    ///
+    /// # Arguments
+    ///
+    /// * `include_timing` - Flag to determine if the search should be timed.
+    pub fn include_timing(&mut self, include_timing: bool) -> &mut Self {
+        self.include_timing = include_timing;
+        self
+    }
+
+    /// Load the context of the matching events.
+    ///
+    /// If unset, the `events_before` and `events_after` fields of the results
+    /// are empty regardless of the context limits, and the database isn't
+    /// queried for the context at all. Searches that only need the ids and
+    /// scores of the matching events, e.g. to suggest results while the user
+    /// types, are considerably faster without it. The default is to load the
+    /// context.
+    ///
+    /// # Arguments
+    ///
+    /// * `include_context` - Flag to determine if the context of the results
+    ///   should be loaded.
+    pub fn include_context(&mut self, include_context: bool) -> &mut Self {
+        self.include_context = include_context;
+        self
+    }
+
+    /// Return the profiles of the senders of the results.
+    ///
+    /// If unset, the `profile_info` field of the results is empty. The
+    /// default is to return the profiles.
+    ///
+    /// # Arguments
+    ///
+    /// * `include_profiles` - Flag to determine if the profiles of the
+    ///   senders should be returned.
+    pub fn include_profiles(&mut self, include_profiles: bool) -> &mut Self {
+        self.include_profiles = include_profiles;
+        self
+    }
+
+    /// Keep the place of redacted messages in the context of the results.
+    ///
+    /// Redacted messages are removed from the database, by default they
+    /// aren't part of the context of other events. If set, a stub takes their
+    /// place in the context, its source only contains the event id and a
+    /// `redacted` flag, e.g. `{"event_id": "$id", "redacted": true}`, so the
+    /// UI can show that a message was deleted. Stubs count towards the context
+    /// limits. The default is to leave redacted messages out.
+    ///
+    /// # Arguments
+    ///
+    /// * `include_redacted_stubs` - Flag to determine if redacted messages
+    ///   should be part of the context as stubs.
+    pub fn include_redacted_stubs(&mut self, include_redacted_stubs: bool) -> &mut Self {
+        self.include_redacted_stubs = include_redacted_stubs;
+        self
+    }
+
+    /// Only return results whose context could be loaded.
+    ///
+    /// By default a result or a context event that fails to load, e.g.
+    /// because its row in the database is corrupted, fails the whole search.
+    /// If set, such results are left out instead and counted in the
+    /// `skipped` field of the `SearchBatch`. Results that have fewer context
+    /// events than requested because they are at the edge of the indexed
+    /// history are still returned, see `SearchResult::context_truncated`.
+    ///
+    /// # Arguments
+    ///
+    /// * `require_context` - Flag to determine if results whose context
+    ///   fails to load should be left out.
+    pub fn require_context(&mut self, require_context: bool) -> &mut Self {
+        self.require_context = require_context;
+        self
+    }
+
+    /// The number of context events that should be loaded before and after
+    /// every result.
+    pub(crate) fn context_limits(&self) -> ContextLimits {
+        if self.include_context {
+            ContextLimits {
+                before: self.before_limit,
+                after: self.after_limit,
+                overrides: self.context_overrides.clone(),
+            }
+        } else {
+            ContextLimits::default()
+        }
+    }
+
+    /// Hand the search term to the query parser as it is.
+    ///
+    /// This allows the full query syntax of the index to be used, e.g. terms
+    /// scoped to a field, boosts and boolean operators. The room and thread
+    /// filters are still applied on top of the parsed query. A term that
+    /// can't be parsed is rejected with a `QuerySyntaxError`. The default is
+    /// to not use the advanced syntax.
+    ///
+    /// # Arguments
+    ///
+    /// * `advanced_syntax` - Flag to determine if the search term should be
+    ///   parsed using the advanced query syntax.
+    pub fn advanced_syntax(&mut self, advanced_syntax: bool) -> &mut Self {
+        self.advanced_syntax = advanced_syntax;
+        self
+    }
+
+    /// Set how the words of the search term are combined.
+    ///
+    /// The default is to use the operator of the database configuration, see
+    /// `Config::set_default_operator()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `operator` - The operator that combines the words of the search
+    ///   term.
+    pub fn default_operator(&mut self, operator: QueryOperator) -> &mut Self {
+        self.default_operator = Some(operator);
+        self
+    }
+
+    /// Limit the time the search may take.
+    ///
+    /// Once the time runs out the search stops collecting matches from the
+    /// index and loading events from the database. The results that were
+    /// gathered so far are returned and the `timed_out` field of the returned
+    /// `SearchBatch` is set, the count of the batch only includes the
+    /// matches that were collected. The default is to not limit the search.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time the search may take.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     /// The point to return events from. If given, this should be a next_batch
     ///   result from a previous search.
     pub fn next_batch(&mut self, token: Uuid) -> &mut Self {
@@ -128,15 +676,36 @@ impl Default for SearchConfig {
             limit: 10,
             before_limit: 0,
             after_limit: 0,
-            order_by_recency: false,
+            context_overrides: HashMap::new(),
+            order: SearchOrder::Rank,
             room_id: None,
+            thread_id: None,
+            restrict_to_events: None,
+            not_senders: Vec::new(),
+            contains_url: None,
+            min_timestamp: None,
+            after_watermark: None,
             keys: Vec::new(),
             next_batch: None,
+            group_by_room: false,
+            group_rooms: false,
+            search_senders: false,
+            normalize_scores: false,
+            boost_by_reactions: false,
+            include_timing: false,
+            include_context: true,
+            include_profiles: true,
+            include_redacted_stubs: false,
+            require_context: false,
+            advanced_syntax: false,
+            default_operator: None,
+            timeout: None,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[allow(missing_docs)]
 pub enum Language {
     Arabic,
@@ -159,6 +728,28 @@ pub enum Language {
     Unknown,
 }
 
+/// The languages that have a stemmer, these can be used for language
+/// detection.
+const STEMMED_LANGUAGES: [Language; 17] = [
+    Language::Arabic,
+    Language::Danish,
+    Language::Dutch,
+    Language::English,
+    Language::Finnish,
+    Language::French,
+    Language::German,
+    Language::Greek,
+    Language::Hungarian,
+    Language::Italian,
+    Language::Portuguese,
+    Language::Romanian,
+    Language::Russian,
+    Language::Spanish,
+    Language::Swedish,
+    Language::Tamil,
+    Language::Turkish,
+];
+
 impl Language {
     pub(crate) fn as_tokenizer_name(&self) -> String {
         match self {
@@ -189,6 +780,53 @@ impl Language {
             _ => panic!("Unsupported language by tantivy"),
         }
     }
+
+    /// The ISO 639-1 code of the language, `None` for an unknown language.
+    pub(crate) fn as_iso_code(&self) -> Option<&'static str> {
+        match self {
+            Language::Arabic => Some("ar"),
+            Language::Danish => Some("da"),
+            Language::Dutch => Some("nl"),
+            Language::English => Some("en"),
+            Language::Finnish => Some("fi"),
+            Language::French => Some("fr"),
+            Language::German => Some("de"),
+            Language::Greek => Some("el"),
+            Language::Hungarian => Some("hu"),
+            Language::Italian => Some("it"),
+            Language::Portuguese => Some("pt"),
+            Language::Romanian => Some("ro"),
+            Language::Russian => Some("ru"),
+            Language::Spanish => Some("es"),
+            Language::Swedish => Some("sv"),
+            Language::Tamil => Some("ta"),
+            Language::Turkish => Some("tr"),
+            Language::Unknown => None,
+        }
+    }
+
+    pub(crate) fn as_whatlang(&self) -> Option<whatlang::Lang> {
+        match self {
+            Language::Arabic => Some(whatlang::Lang::Ara),
+            Language::Danish => Some(whatlang::Lang::Dan),
+            Language::Dutch => Some(whatlang::Lang::Nld),
+            Language::English => Some(whatlang::Lang::Eng),
+            Language::Finnish => Some(whatlang::Lang::Fin),
+            Language::French => Some(whatlang::Lang::Fra),
+            Language::German => Some(whatlang::Lang::Deu),
+            Language::Greek => Some(whatlang::Lang::Ell),
+            Language::Hungarian => Some(whatlang::Lang::Hun),
+            Language::Italian => Some(whatlang::Lang::Ita),
+            Language::Portuguese => Some(whatlang::Lang::Por),
+            Language::Romanian => Some(whatlang::Lang::Ron),
+            Language::Russian => Some(whatlang::Lang::Rus),
+            Language::Spanish => Some(whatlang::Lang::Spa),
+            Language::Swedish => Some(whatlang::Lang::Swe),
+            Language::Tamil => Some(whatlang::Lang::Tam),
+            Language::Turkish => Some(whatlang::Lang::Tur),
+            Language::Unknown => None,
+        }
+    }
 }
 
 impl From<&str> for Language {
@@ -220,8 +858,39 @@ impl From<&str> for Language {
 /// Configuration for the seshat database.
 pub struct Config {
     pub(crate) language: Language,
+    pub(crate) detect_language: bool,
+    pub(crate) detected_languages: Vec<Language>,
+    pub(crate) split_identifiers: bool,
+    pub(crate) index_path: Option<PathBuf>,
+    pub(crate) store_path: Option<PathBuf>,
     #[cfg(feature = "encryption")]
     pub(crate) passphrase: Option<Zeroizing<String>>,
+    pub(crate) wal: bool,
+    pub(crate) busy_timeout: Duration,
+    pub(crate) write_queue_capacity: usize,
+    pub(crate) auto_commit_interval: Option<Duration>,
+    pub(crate) auto_commit_after_events: Option<usize>,
+    pub(crate) lock_timeout: Duration,
+    pub(crate) force_unlock: bool,
+    pub(crate) retention: Option<Duration>,
+    pub(crate) indexed_msgtypes: Option<Vec<String>>,
+    pub(crate) index_state_history: bool,
+    pub(crate) store_sources: bool,
+    #[cfg(feature = "encryption")]
+    pub(crate) source_encryption_passphrase: Option<Zeroizing<String>>,
+    pub(crate) compaction_threshold: usize,
+    pub(crate) auto_compaction: bool,
+    pub(crate) search_token_ttl: Duration,
+    pub(crate) auto_reload: bool,
+    pub(crate) connection_pool_size: u32,
+    pub(crate) connection_timeout: Duration,
+    pub(crate) writer_heap_size: usize,
+    pub(crate) query_limits: QueryLimits,
+    pub(crate) record_searches: bool,
+    pub(crate) default_operator: QueryOperator,
+    pub(crate) leading_wildcards: bool,
+    pub(crate) size_limits: EventSizeLimits,
+    pub(crate) source_cache_capacity: usize,
 }
 
 impl Config {
@@ -240,6 +909,110 @@ impl Config {
         self
     }
 
+    /// Detect the language of every message and index it using the stemmer
+    /// of that language.
+    ///
+    /// Every detected language gets its own body field in the index, searches
+    /// go through all of them. Messages whose language can't be detected
+    /// with enough confidence, e.g. short ones, are indexed using the
+    /// language set with `set_language()`. Disabled by default.
+    ///
+    /// Changing this for an existing database changes the layout of the
+    /// index, opening the database fails with a `ReindexError` until the
+    /// index is rebuilt.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should the language of messages be detected.
+    pub fn set_detect_language(mut self, enabled: bool) -> Self {
+        self.detect_language = enabled;
+        self
+    }
+
+    /// Set the languages that language detection chooses from.
+    ///
+    /// Defaults to all the languages that have a stemmer, i.e. all languages
+    /// except `Language::Unknown`. Unknown and repeated languages are
+    /// ignored. Only used if language detection is enabled, changing the
+    /// languages for an existing database requires the index to be rebuilt.
+    ///
+    /// # Arguments
+    ///
+    /// * `languages` - The languages that should be detected.
+    pub fn set_detected_languages(mut self, languages: &[Language]) -> Self {
+        self.detected_languages.clear();
+
+        for language in languages {
+            if *language != Language::Unknown && !self.detected_languages.contains(language) {
+                self.detected_languages.push(language.clone());
+            }
+        }
+
+        self
+    }
+
+    /// Split the CamelCase identifiers of messages into their words, so
+    /// searching for "searcher" finds messages that mention `IndexSearcher`.
+    ///
+    /// The identifiers stay searchable as a whole. snake_case and kebab-case
+    /// identifiers are always split into their words, with this enabled
+    /// searching for `index_searcher` finds `IndexSearcher` as well. Only
+    /// the bodies of messages are affected, searches go through the same
+    /// splitting. Disabled by default.
+    ///
+    /// Changing this for an existing database changes the layout of the
+    /// index, opening the database fails with a `ReindexError` until the
+    /// index is rebuilt.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should identifiers be split into their words.
+    pub fn set_split_identifiers(mut self, enabled: bool) -> Self {
+        self.split_identifiers = enabled;
+        self
+    }
+
+    /// Set the directory the search index is stored in.
+    ///
+    /// The index is usually much bigger than the SQLite store, this allows it
+    /// to live on a different volume than the store. Defaults to the
+    /// directory of the database. The directory is created if it doesn't
+    /// exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The directory of the search index.
+    pub fn set_index_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.index_path = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Set the directory the SQLite store, which contains the events and the
+    /// crawler checkpoints, is stored in.
+    ///
+    /// Defaults to the directory of the database. The directory is created
+    /// if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The directory of the SQLite store.
+    pub fn set_store_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.store_path = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Get the directory of the search index for a database in the given
+    /// directory.
+    pub(crate) fn index_path(&self, path: &Path) -> PathBuf {
+        self.index_path.clone().unwrap_or_else(|| path.to_owned())
+    }
+
+    /// Get the directory of the SQLite store for a database in the given
+    /// directory.
+    pub(crate) fn store_path(&self, path: &Path) -> PathBuf {
+        self.store_path.clone().unwrap_or_else(|| path.to_owned())
+    }
+
     /// Set the passphrase of the database.
     /// # Arguments
     ///
@@ -249,14 +1022,513 @@ impl Config {
         self.passphrase = Some(Zeroizing::new(passphrase.into()));
         self
     }
+
+    /// Enable or disable the write-ahead log of the Sqlite database.
+    ///
+    /// The write-ahead log is enabled by default, it allows readers to
+    /// continue reading while the database is being written to. Some network
+    /// filesystems don't support it, in which case it needs to be disabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should the write-ahead log be used.
+    pub fn set_wal(mut self, enabled: bool) -> Self {
+        self.wal = enabled;
+        self
+    }
+
+    /// Set the busy timeout of the Sqlite database connections.
+    ///
+    /// This is the time a connection waits for a lock held by another
+    /// connection to be released before giving up with a database busy error.
+    /// Defaults to 5 seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time to wait for a lock.
+    pub fn set_busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Set the capacity of the write queue.
+    ///
+    /// Events that are added to the database are queued up until they are
+    /// written to the database. If the queue is full, adding events blocks
+    /// until the writer catches up. Defaults to 1000 events.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The maximal number of events that can be queued up.
+    pub fn set_write_queue_capacity(mut self, capacity: usize) -> Self {
+        self.write_queue_capacity = capacity.max(1);
+        self
+    }
+
+    /// Let the writer thread commit automatically after the given interval.
+    ///
+    /// If set, the writer thread commits the events that were added to the
+    /// database once the interval has passed since the last commit. Manual
+    /// commits restart the interval. Auto commits are disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - The time between automatic commits, `None` disables
+    ///   them.
+    pub fn set_auto_commit_interval(mut self, interval: Option<Duration>) -> Self {
+        self.auto_commit_interval = interval;
+        self
+    }
+
+    /// Let the writer thread commit automatically once the given number of
+    /// events is waiting to be committed.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The number of uncommitted events that triggers a commit,
+    ///   `None` disables automatic commits based on the event count.
+    pub fn set_auto_commit_after_events(mut self, count: Option<usize>) -> Self {
+        self.auto_commit_after_events = count.map(|c| c.max(1));
+        self
+    }
+
+    /// Set how long opening the database should wait for the index lock.
+    ///
+    /// Only a single process may write to the index at a time. If another
+    /// process holds the index lock, opening the database is retried until
+    /// the timeout runs out, after which a `DatabaseLocked` error is returned.
+    /// Defaults to not waiting at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time to wait for the index lock to be released.
+    pub fn set_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Remove the index lock before opening the database.
+    ///
+    /// This should only be used to recover from a stale lock that was left
+    /// behind, if another process is still using the database it will corrupt
+    /// the index.
+    ///
+    /// # Arguments
+    ///
+    /// * `force` - Should the index lock be removed.
+    pub fn set_force_unlock(mut self, force: bool) -> Self {
+        self.force_unlock = force;
+        self
+    }
+
+    /// Set how long events should be kept in the database.
+    ///
+    /// If set, the writer thread periodically deletes events that are older
+    /// than the retention period, starting right after the database is
+    /// opened. Events are kept forever by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `retention` - The maximal age of events in the database.
+    pub fn set_retention(mut self, retention: Option<Duration>) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Set the message types of the `m.room.message` events that should be
+    /// stored and indexed.
+    ///
+    /// Message events with a msgtype that isn't part of the list, e.g. the
+    /// `m.notice` messages of bots, are dropped when they are added to the
+    /// database. Topic and name events are always stored. All messages are
+    /// stored by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `msgtypes` - The msgtypes that should be stored, `None` stores
+    ///   messages of any type.
+    pub fn set_indexed_msgtypes(mut self, msgtypes: Option<Vec<String>>) -> Self {
+        self.indexed_msgtypes = msgtypes;
+        self
+    }
+
+    /// Should every name and topic a room ever had be searchable.
+    ///
+    /// By default indexing a name or topic event of a room removes the
+    /// previous name or topic of the room from the index, searching for an
+    /// old name of a room doesn't find the room anymore. The old events stay
+    /// in the database and are still part of the context of other events.
+    /// Events that are older than the current name or topic, e.g. ones the
+    /// crawler found in the room history, are only stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `index_state_history` - Should all the names and topics of rooms be
+    ///   indexed, not only the latest ones.
+    pub fn set_index_state_history(mut self, index_state_history: bool) -> Self {
+        self.index_state_history = index_state_history;
+        self
+    }
+
+    /// Should the full sources of events be stored in the database.
+    ///
+    /// If disabled, only the event id, the room, the sender and the timestamp
+    /// of events are stored. Search results then contain a minimal event
+    /// source that is reconstructed from those and no context events. Such a
+    /// database can't be reindexed. Sources are stored by default.
+    ///
+    /// The setting is recorded when the database is created, opening the
+    /// database with a different setting fails with a
+    /// `StoreSourcesMismatch` error.
+    ///
+    /// # Arguments
+    ///
+    /// * `store_sources` - Should the sources of events be stored.
+    pub fn set_store_sources(mut self, store_sources: bool) -> Self {
+        self.store_sources = store_sources;
+        self
+    }
+
+    /// Set the passphrase that the stored event sources are encrypted with.
+    ///
+    /// Unlike the database passphrase this doesn't need SQLCipher, only the
+    /// event sources and the profiles of senders are encrypted while the
+    /// structure of the database and the index are left as they are.
+    ///
+    /// The sources of a new database are encrypted if a passphrase is set,
+    /// a database that already contains unencrypted events can't be switched
+    /// to encrypted sources. Opening a database with encrypted sources using
+    /// a wrong passphrase, or without one, fails with a `WrongPassphrase`
+    /// error.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase of the event sources, `None` stores
+    ///   them unencrypted.
+    #[cfg(feature = "encryption")]
+    pub fn set_source_encryption_passphrase<P: Into<String>>(
+        mut self,
+        passphrase: Option<P>,
+    ) -> Self {
+        self.source_encryption_passphrase = passphrase.map(|p| Zeroizing::new(p.into()));
+        self
+    }
+
+    /// Set the number of deleted events after which a compaction of the
+    /// database is worthwhile.
+    ///
+    /// Compacting a database with fewer deleted events does nothing. Defaults
+    /// to 1000 events.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - The number of deleted events that need to pile up
+    ///   before the database is compacted.
+    pub fn set_compaction_threshold(mut self, threshold: usize) -> Self {
+        self.compaction_threshold = threshold.max(1);
+        self
+    }
+
+    /// Let the writer thread compact the database once it has been idle for
+    /// a while and the compaction threshold is reached.
+    ///
+    /// Automatic compactions are disabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should the database be compacted automatically.
+    pub fn set_auto_compaction(mut self, enabled: bool) -> Self {
+        self.auto_compaction = enabled;
+        self
+    }
+
+    /// Set how long the `next_batch` token of a search stays valid.
+    ///
+    /// Every page of a paginated search is served from the same snapshot of
+    /// the index as the first one, the snapshot is released once the token
+    /// expires. Defaults to 5 minutes.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - The time after which a `next_batch` token expires.
+    pub fn set_search_token_ttl(mut self, ttl: Duration) -> Self {
+        self.search_token_ttl = ttl;
+        self
+    }
+
+    /// Reload the index automatically after every commit.
+    ///
+    /// Searches that are done after a commit returns find the committed
+    /// events. If disabled, the index needs to be reloaded manually using
+    /// `Database::reload()`. Enabled by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should the index be reloaded after every commit.
+    pub fn set_auto_reload(mut self, enabled: bool) -> Self {
+        self.auto_reload = enabled;
+        self
+    }
+
+    /// Set the maximal number of Sqlite connections the database keeps open.
+    ///
+    /// Connections returned by `Database::get_connection()` are taken from
+    /// a pool and returned to it once they are dropped. Two connections are
+    /// always in use by the database itself, the size is raised to at least
+    /// three so a reading connection can be handed out. Defaults to 10
+    /// connections.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The maximal number of connections in the pool.
+    pub fn set_connection_pool_size(mut self, size: u32) -> Self {
+        self.connection_pool_size = size.max(MIN_CONNECTION_POOL_SIZE);
+        self
+    }
+
+    /// Set how long getting a connection waits for one to be returned to an
+    /// exhausted pool.
+    ///
+    /// Once the timeout runs out a `PoolTimeout` error is returned. Defaults
+    /// to 30 seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time to wait for a free connection.
+    pub fn set_connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = timeout;
+        self
+    }
+
+    /// Set the amount of memory in bytes the index writer may use to buffer
+    /// events before they are written to disk.
+    ///
+    /// A smaller heap makes the writer flush more often, which is useful if
+    /// many databases are open at once. The size is raised to at least 3MB,
+    /// Tantivy doesn't allow less. Defaults to 50MB.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The size of the writer heap in bytes.
+    pub fn set_writer_heap_size(mut self, size: usize) -> Self {
+        self.writer_heap_size = size.max(TANTIVY_MIN_WRITER_HEAP_SIZE);
+        self
+    }
+
+    /// Set the maximal length of a search term in characters.
+    ///
+    /// Searching for longer terms fails with a `QueryTooComplex` error
+    /// instead of running a query that takes a long time. Defaults to 1000
+    /// characters.
+    ///
+    /// # Arguments
+    ///
+    /// * `length` - The maximal number of characters of a search term.
+    pub fn set_max_query_length(mut self, length: usize) -> Self {
+        self.query_limits.max_length = length.max(1);
+        self
+    }
+
+    /// Set the maximal number of distinct words a search term may contain.
+    ///
+    /// Searching for terms with more words fails with a `QueryTooComplex`
+    /// error. Defaults to 64 words.
+    ///
+    /// # Arguments
+    ///
+    /// * `terms` - The maximal number of words of a search term.
+    pub fn set_max_query_terms(mut self, terms: usize) -> Self {
+        self.query_limits.max_terms = terms.max(1);
+        self
+    }
+
+    /// Set the maximal number of clauses a search query may expand to.
+    ///
+    /// Every word of a search term is searched for in every searched field,
+    /// each of those is a clause of the query. Searches with more clauses
+    /// fail with a `QueryTooComplex` error. Defaults to 512 clauses.
+    ///
+    /// # Arguments
+    ///
+    /// * `clauses` - The maximal number of clauses of a search query.
+    pub fn set_max_query_clauses(mut self, clauses: usize) -> Self {
+        self.query_limits.max_clauses = clauses.max(1);
+        self
+    }
+
+    /// Set the maximal number of terms a search word with a wildcard is
+    /// expanded to.
+    ///
+    /// A word like `report*` searches for all the terms of the index that
+    /// start with "report", only the first of those up to the limit are
+    /// searched for. Defaults to 100 terms.
+    ///
+    /// # Arguments
+    ///
+    /// * `terms` - The maximal number of terms a wildcard is expanded to.
+    pub fn set_max_wildcard_terms(mut self, terms: usize) -> Self {
+        self.query_limits.max_wildcard_terms = terms.max(1);
+        self
+    }
+
+    /// Set the maximal number of events a search may be restricted to.
+    ///
+    /// Searches that are restricted to more events, see
+    /// `SearchConfig::restrict_to_events()`, fail with a
+    /// `TooManyRestrictedIds` error. Defaults to 1000 events.
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - The maximal number of event ids a search may be restricted
+    ///   to.
+    pub fn set_max_restricted_ids(mut self, ids: usize) -> Self {
+        self.query_limits.max_restricted_ids = ids.max(1);
+        self
+    }
+
+    /// Record the terms of successful searches in the search history.
+    ///
+    /// The history can be loaded using
+    /// `Connection::load_recent_searches()`. Continuing a search using a
+    /// `next_batch` token doesn't record the term again. Searches aren't
+    /// recorded by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should search terms be recorded.
+    pub fn set_record_searches(mut self, enabled: bool) -> Self {
+        self.record_searches = enabled;
+        self
+    }
+
+    /// Set how the words of a search term are combined by default.
+    ///
+    /// Searches can override this using `SearchConfig::default_operator()`.
+    /// Explicit operators in the search term are always respected. Defaults
+    /// to `QueryOperator::Or`, events containing any of the words match.
+    ///
+    /// # Arguments
+    ///
+    /// * `operator` - The operator that combines the words of search terms.
+    pub fn set_default_operator(mut self, operator: QueryOperator) -> Self {
+        self.default_operator = operator;
+        self
+    }
+
+    /// Allow wildcards at the start of search words, e.g. `*port`.
+    ///
+    /// Such words need to be matched against every term of the index, which
+    /// is slow for big indices. If not allowed, search terms containing them
+    /// are rejected with a `LeadingWildcard` error. Defaults to false.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should wildcards at the start of words be allowed.
+    pub fn set_leading_wildcards(mut self, enabled: bool) -> Self {
+        self.leading_wildcards = enabled;
+        self
+    }
+
+    /// Set the maximal number of bytes of the body of an event that are
+    /// indexed.
+    ///
+    /// Longer bodies, e.g. pasted logs, are truncated before they are
+    /// indexed, only the words at the start of them can be found. The
+    /// stored source of the event is kept as it is unless
+    /// `set_truncate_stored_bodies()` is enabled. Defaults to 256 KiB.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The maximal number of indexed bytes of an event body.
+    pub fn set_max_indexed_body_bytes(mut self, bytes: usize) -> Self {
+        self.size_limits.max_indexed_body_bytes = bytes.max(1);
+        self
+    }
+
+    /// Truncate the bodies in the stored sources of events as well.
+    ///
+    /// The string fields of the event content that are longer than the
+    /// limit of `set_max_indexed_body_bytes()` are truncated before the
+    /// source is stored. Search results then contain the truncated body.
+    /// Defaults to false, the sources are stored as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Should the bodies of the stored sources be truncated.
+    pub fn set_truncate_stored_bodies(mut self, enabled: bool) -> Self {
+        self.size_limits.truncate_stored_bodies = enabled;
+        self
+    }
+
+    /// Set the maximal size of an event in bytes.
+    ///
+    /// The size of an event is the size of its source, or of its body if
+    /// that is bigger. Adding a bigger event fails with an `EventTooLarge`
+    /// error, bigger events from the room history are dropped. Defaults to
+    /// 16 MiB.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The maximal size of an event in bytes.
+    pub fn set_max_event_size(mut self, size: usize) -> Self {
+        self.size_limits.max_event_size = size.max(1);
+        self
+    }
+
+    /// Set the number of events whose sources are cached for searches.
+    ///
+    /// Consecutive searches tend to return the same events, the sources of
+    /// the most recently returned events are kept in memory instead of being
+    /// loaded from the database again. The context of the events is always
+    /// loaded from the database. Defaults to 1000 events, 0 disables the
+    /// cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The maximal number of cached events.
+    pub fn set_source_cache_capacity(mut self, capacity: usize) -> Self {
+        self.source_cache_capacity = capacity;
+        self
+    }
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             language: Language::Unknown,
+            detect_language: false,
+            detected_languages: STEMMED_LANGUAGES.to_vec(),
+            split_identifiers: false,
+            index_path: None,
+            store_path: None,
             #[cfg(feature = "encryption")]
             passphrase: None,
+            wal: true,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            write_queue_capacity: DEFAULT_WRITE_QUEUE_CAPACITY,
+            auto_commit_interval: None,
+            auto_commit_after_events: None,
+            lock_timeout: Duration::from_secs(0),
+            force_unlock: false,
+            retention: None,
+            indexed_msgtypes: None,
+            index_state_history: false,
+            store_sources: true,
+            #[cfg(feature = "encryption")]
+            source_encryption_passphrase: None,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            auto_compaction: false,
+            search_token_ttl: DEFAULT_SEARCH_TOKEN_TTL,
+            auto_reload: true,
+            connection_pool_size: DEFAULT_CONNECTION_POOL_SIZE,
+            connection_timeout: DEFAULT_CONNECTION_TIMEOUT,
+            writer_heap_size: TANTIVY_WRITER_HEAP_SIZE,
+            query_limits: QueryLimits::default(),
+            record_searches: false,
+            default_operator: QueryOperator::Or,
+            leading_wildcards: false,
+            size_limits: EventSizeLimits::default(),
+            source_cache_capacity: DEFAULT_SOURCE_CACHE_CAPACITY,
         }
     }
 }
@@ -0,0 +1,248 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    path::Path,
+};
+
+use serde_json::Value;
+
+use crate::{
+    database::{DATABASE_VERSION, EVENTS_DB_NAME, INDEX_META},
+    error::{Error, Result},
+    Database,
+};
+
+#[cfg(test)]
+use crate::{config::SearchConfig, events::Profile, EVENT};
+#[cfg(test)]
+use tempfile::tempdir;
+
+/// The name of the file describing the backup, it's the first file in every
+/// backup archive.
+const BACKUP_MANIFEST: &str = "seshat-backup.json";
+/// How many times should we try to copy the index while merges are changing
+/// it.
+const BACKUP_RETRIES: usize = 5;
+
+impl Database {
+    /// Write a backup archive of the database directory.
+    ///
+    /// This needs to be called from the writer thread after the queued events
+    /// were committed so neither the Sqlite database nor the list of index
+    /// segments changes while they are being copied.
+    ///
+    /// If the index and the Sqlite store live in separate directories, the
+    /// files of both are put into the archive, a restore puts them into a
+    /// single directory.
+    pub(crate) fn write_backup(
+        connection: &rusqlite::Connection,
+        index_dir: &Path,
+        store_dir: &Path,
+        archive_path: &Path,
+    ) -> Result<()> {
+        // Move the content of the write-ahead log into the database file, the
+        // log is copied as well in case readers prevented the checkpoint.
+        connection.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+        for _ in 0..BACKUP_RETRIES {
+            if Database::try_write_backup(index_dir, store_dir, archive_path)? {
+                return Ok(());
+            }
+        }
+
+        fs::remove_file(archive_path)?;
+
+        Err(Error::BackupError(
+            "The index kept changing while it was being copied".to_owned(),
+        ))
+    }
+
+    /// Copy the database directories into a tar archive.
+    ///
+    /// Merges of index segments may finish while we copy the files, a merge
+    /// replaces the `meta.json` file and deletes the merged segments
+    /// afterwards. Segments that the `meta.json` file lists can't go away
+    /// while the file stays the same, so the copy is consistent if the file
+    /// didn't change. Returns false if the copy needs to be retried.
+    fn try_write_backup(index_dir: &Path, store_dir: &Path, archive_path: &Path) -> Result<bool> {
+        let meta = fs::read(index_dir.join(INDEX_META))?;
+        let manifest = serde_json::json!({ "database_version": DATABASE_VERSION }).to_string();
+
+        let mut archive = tar::Builder::new(File::create(archive_path)?);
+        Database::append_file(&mut archive, BACKUP_MANIFEST, manifest.as_bytes())?;
+
+        let dirs = if index_dir == store_dir {
+            vec![index_dir]
+        } else {
+            vec![index_dir, store_dir]
+        };
+
+        for dir in dirs {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let name_str = name.to_string_lossy();
+
+                // Lock files and the shared memory file of the write-ahead log
+                // are recreated when the database is opened.
+                if !entry.file_type()?.is_file()
+                    || name_str.ends_with(".lock")
+                    || name_str == INDEX_META
+                    || name_str == format!("{}-shm", EVENTS_DB_NAME)
+                {
+                    continue;
+                }
+
+                match archive.append_path_with_name(entry.path(), &name) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Database::append_file(&mut archive, INDEX_META, &meta)?;
+        archive.into_inner()?;
+
+        Ok(fs::read(index_dir.join(INDEX_META))? == meta)
+    }
+
+    fn append_file(archive: &mut tar::Builder<File>, name: &str, data: &[u8]) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o600);
+        header.set_cksum();
+
+        archive.append_data(&mut header, name, data)?;
+
+        Ok(())
+    }
+
+    /// Restore a database from a backup archive.
+    ///
+    /// The archive is unpacked into the given directory, the database can be
+    /// opened afterwards using the normal `new()` method.
+    ///
+    /// Returns a `BackupVersionError` if the archive was created by a newer
+    /// version of Seshat.
+    ///
+    /// # Arguments
+    ///
+    /// * `archive_path` - The path of the archive that was created using the
+    ///   `backup()` method.
+    /// * `path` - The directory the database should be restored into. The
+    ///   directory will be created if it doesn't exist, it must be empty
+    ///   otherwise.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(archive_path: P, path: Q) -> Result<()> {
+        let path = path.as_ref();
+        let mut archive = tar::Archive::new(File::open(archive_path)?);
+        let mut entries = archive.entries()?;
+
+        let mut manifest = match entries.next() {
+            Some(entry) => entry?,
+            None => return Err(Error::BackupError("The backup is empty".to_owned())),
+        };
+
+        if manifest.path()?.as_ref() != Path::new(BACKUP_MANIFEST) {
+            return Err(Error::BackupError(
+                "The backup doesn't contain a manifest".to_owned(),
+            ));
+        }
+
+        let mut content = String::new();
+        manifest.read_to_string(&mut content)?;
+
+        let version = serde_json::from_str::<Value>(&content)
+            .ok()
+            .and_then(|v| v["database_version"].as_i64())
+            .ok_or_else(|| Error::BackupError("The backup manifest is invalid".to_owned()))?;
+
+        if version > DATABASE_VERSION {
+            return Err(Error::BackupVersionError(version));
+        }
+
+        fs::create_dir_all(path)?;
+
+        if fs::read_dir(path)?.next().is_some() {
+            return Err(Error::BackupError(format!(
+                "The directory {} isn't empty",
+                path.display()
+            )));
+        }
+
+        for entry in entries {
+            if !entry?.unpack_in(path)? {
+                return Err(Error::BackupError(
+                    "The backup contains an invalid path".to_owned(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn backup_and_restore() {
+    let tmpdir = tempdir().unwrap();
+    let archive = tmpdir.path().join("backup.tar");
+    let db_dir = tmpdir.path().join("db");
+    let restored_dir = tmpdir.path().join("restored");
+    fs::create_dir(&db_dir).unwrap();
+
+    let db = Database::new(&db_dir).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    // The backup commits the queued up events.
+    db.add_event(EVENT.clone(), profile);
+    db.backup(&archive).recv().unwrap().unwrap();
+
+    Database::restore(&archive, &restored_dir).unwrap();
+
+    let restored = Database::new(&restored_dir).unwrap();
+    let result = restored.search("test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.results.len(), 1);
+    assert_eq!(result.results[0].event_source, EVENT.source);
+
+    let connection = restored.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 1);
+
+    // Restoring into a directory that is in use fails.
+    assert!(matches!(
+        Database::restore(&archive, &restored_dir),
+        Err(Error::BackupError(_))
+    ));
+}
+
+#[test]
+fn restore_a_backup_from_a_newer_version() {
+    let tmpdir = tempdir().unwrap();
+    let archive_path = tmpdir.path().join("backup.tar");
+
+    let mut archive = tar::Builder::new(File::create(&archive_path).unwrap());
+    let manifest = serde_json::json!({ "database_version": DATABASE_VERSION + 1 }).to_string();
+    Database::append_file(&mut archive, BACKUP_MANIFEST, manifest.as_bytes()).unwrap();
+    archive.into_inner().unwrap();
+
+    let restored_dir = tmpdir.path().join("restored");
+
+    assert!(matches!(
+        Database::restore(&archive_path, &restored_dir),
+        Err(Error::BackupVersionError(v)) if v == DATABASE_VERSION + 1
+    ));
+    assert!(!restored_dir.exists());
+}
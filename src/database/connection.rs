@@ -15,22 +15,28 @@
 /// A Seshat database connection.
 /// The connection can be used to read data out of the database using a
 /// separate thread.
+use std::collections::HashMap;
+use std::io::Write;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use fs_extra::dir;
-use r2d2::PooledConnection;
+use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::{json, Value};
 
 use crate::{
-    config::LoadConfig,
-    error::Result,
-    events::{CrawlerCheckpoint, Profile, SerializedEvent},
+    config::{Config, LoadConfig},
+    database::{
+        consistency::ConsistencyState, source_cache::SharedSourceCache, source_cipher::SourceCipher,
+    },
+    error::{Error, Result},
+    events::{ContextEvent, CrawlerCheckpoint, EventId, MxId, Profile, RoomId, SerializedEvent},
     Database,
 };
 
 /// Statistical information about the database.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DatabaseStats {
     /// The number number of bytes the database is using on disk.
@@ -39,12 +45,91 @@ pub struct DatabaseStats {
     pub event_count: u64,
     /// The number of rooms that the database knows about.
     pub room_count: u64,
+    /// The number of events that were deleted since the database was last
+    /// compacted.
+    pub tombstone_count: u64,
+    /// The number of search results whose source was served from the source
+    /// cache since the database was opened.
+    pub source_cache_hits: u64,
+    /// The number of search results whose source had to be loaded from the
+    /// database since the database was opened.
+    pub source_cache_misses: u64,
+    /// The result of the comparison of the store with the index when the
+    /// database was opened, kept until the database is repaired.
+    pub consistency: ConsistencyState,
+}
+
+/// Statistical information about the events of a single room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomInfo {
+    /// The unique id of the room.
+    pub room_id: String,
+    /// The number of events of the room that the database knows about.
+    pub event_count: u64,
+    /// The timestamp of the oldest event of the room, `None` if the room
+    /// doesn't have any events.
+    pub oldest_event_ts: Option<i64>,
+    /// The timestamp of the newest event of the room, `None` if the room
+    /// doesn't have any events.
+    pub newest_event_ts: Option<i64>,
+}
+
+/// An event together with the events that surround it in its room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventWithContext {
+    /// The serialized source of the event.
+    pub event_source: SerializedEvent,
+    /// Events that happened before the event, the closest one first.
+    pub events_before: Vec<ContextEvent>,
+    /// Events that happened after the event, the closest one first.
+    pub events_after: Vec<ContextEvent>,
+    /// The profiles of the senders of the event and of the context events.
+    pub profile_info: HashMap<MxId, Profile>,
+}
+
+/// A handle to the connection pool of a database.
+///
+/// The handle can be cloned and sent to other threads, connections can then be
+/// taken out of the pool where they are used instead of on the thread that
+/// owns the database. The connections are shared with the database and every
+/// other handle.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    pub(crate) pool: Pool<SqliteConnectionManager>,
+    pub(crate) config: Config,
+    pub(crate) locations: Vec<PathBuf>,
+    pub(crate) source_cipher: Option<Arc<SourceCipher>>,
+    pub(crate) source_cache: SharedSourceCache,
+}
+
+impl ConnectionPool {
+    /// Get a database connection out of the pool.
+    /// Note that this connection should only be used for reading.
+    ///
+    /// The connection is returned to the pool once it's dropped. If every
+    /// connection is in use, this waits for one to be returned and fails with
+    /// a `PoolTimeout` error once the configured connection timeout runs out.
+    pub fn get(&self) -> Result<Connection> {
+        let connection = self.pool.get().map_err(|_| Error::PoolTimeout)?;
+        Database::unlock(&connection, &self.config)?;
+        Database::set_pragmas(&connection, &self.config)?;
+
+        Ok(Connection {
+            inner: connection,
+            locations: self.locations.clone(),
+            source_cipher: self.source_cipher.clone(),
+            source_cache: self.source_cache.clone(),
+        })
+    }
 }
 
 /// A Seshat database connection that can be used for reading.
 pub struct Connection {
     pub(crate) inner: PooledConnection<SqliteConnectionManager>,
-    pub(crate) path: PathBuf,
+    pub(crate) locations: Vec<PathBuf>,
+    pub(crate) source_cipher: Option<Arc<SourceCipher>>,
+    pub(crate) source_cache: SharedSourceCache,
 }
 
 impl Connection {
@@ -101,18 +186,387 @@ impl Connection {
         Ok(event_count != 0 || checkpoint_count != 0)
     }
 
+    /// Mark a room as fully crawled or not.
+    ///
+    /// A room should be marked as fully crawled once its whole history has
+    /// been fetched and its last crawler checkpoint was removed. The flag is
+    /// cleared automatically if a new backwards checkpoint is added for the
+    /// room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    /// * `fully_crawled` - Whether the whole history of the room is indexed.
+    pub fn set_room_fully_crawled(&self, room_id: &str, fully_crawled: bool) -> Result<()> {
+        Database::retry_on_busy(|| {
+            Ok(Database::set_room_fully_crawled_helper(
+                self,
+                room_id,
+                fully_crawled,
+            )?)
+        })
+    }
+
+    /// Has the whole history of a room been crawled.
+    ///
+    /// Returns false for rooms that were never marked as fully crawled.
+    pub fn is_room_fully_crawled(&self, room_id: &str) -> Result<bool> {
+        Ok(Database::is_room_fully_crawled(&self.inner, room_id)?)
+    }
+
+    /// Get the ids of the rooms that are marked as fully crawled.
+    pub fn get_fully_crawled_rooms(&self) -> Result<Vec<RoomId>> {
+        Ok(Database::load_fully_crawled_rooms(&self.inner)?)
+    }
+
+    /// Get the ids of the given events that are already stored in the
+    /// database.
+    ///
+    /// This allows a crawler to detect that it reached events that were
+    /// already added, e.g. by the live event stream. The ids are returned in
+    /// no particular order, every id at most once.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_ids` - The ids of the events that should be checked, may
+    ///   contain duplicates.
+    pub fn get_known_event_ids(&self, event_ids: &[EventId]) -> Result<Vec<EventId>> {
+        if event_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        Ok(Database::load_known_event_ids(&self.inner, event_ids)?)
+    }
+
+    /// Check if an event was written to the database and committed to the
+    /// index.
+    ///
+    /// Unlike `Database::is_event_indexed()` this doesn't need the index to
+    /// be reloaded, it allows callers to check that an event they added
+    /// wasn't dropped, e.g. because of its msgtype or because the writer
+    /// failed, once the commit that should contain it is done.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that should be checked.
+    pub fn was_event_indexed(&self, event_id: &str) -> Result<bool> {
+        Ok(Database::is_event_committed(&self.inner, event_id)?)
+    }
+
+    /// Get the number of reactions of the given events.
+    ///
+    /// Every given event is part of the returned map, events that nobody
+    /// reacted to have a count of zero. See `Database::add_reaction()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_ids` - The ids of the events, may contain duplicates.
+    pub fn get_reaction_counts(&self, event_ids: &[EventId]) -> Result<HashMap<EventId, u64>> {
+        if event_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        Ok(Database::load_reaction_counts(&self.inner, event_ids)?)
+    }
+
+    /// Get the timestamp of the newest event of a room.
+    ///
+    /// This is a cheap lookup that can be used to decide if a room needs to
+    /// catch up with events that were sent while the app was offline.
+    ///
+    /// Returns `None` if the database doesn't contain any event of the room.
+    pub fn get_newest_event_ts(&self, room_id: &str) -> Result<Option<i64>> {
+        Ok(Database::load_newest_event_ts(&self.inner, room_id)?)
+    }
+
+    /// Get the timestamps of the newest events of the given rooms.
+    ///
+    /// Every given room is part of the returned map, rooms that don't have
+    /// any events map to `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_ids` - The ids of the rooms that should be looked up.
+    pub fn get_newest_event_timestamps(
+        &self,
+        room_ids: &[RoomId],
+    ) -> Result<HashMap<RoomId, Option<i64>>> {
+        if room_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        Ok(Database::load_newest_event_timestamps(
+            &self.inner,
+            room_ids,
+        )?)
+    }
+
+    /// Get the names that should be displayed for the given rooms.
+    ///
+    /// The name of a room is taken from its newest `m.room.name` event, the
+    /// canonical alias of the room is used if it doesn't have a name. Every
+    /// given room is part of the returned map, rooms that have neither map to
+    /// `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_ids` - The ids of the rooms that should be looked up.
+    pub fn get_room_names(&self, room_ids: &[RoomId]) -> Result<HashMap<RoomId, Option<String>>> {
+        if room_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        Ok(Database::load_room_names(&self.inner, room_ids)?)
+    }
+
+    /// Get the last known profile of a user.
+    ///
+    /// This is the profile the user had when they sent their most recent
+    /// stored event, it's available for users that aren't members of the
+    /// room anymore as well.
+    ///
+    /// Returns `None` if the database doesn't contain any event of the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The user id of the user.
+    /// * `room_id` - Only look at the events of this room, the profile of a
+    ///   user can differ between rooms.
+    pub fn load_profile(&self, sender: &str, room_id: Option<&RoomId>) -> Result<Option<Profile>> {
+        let profile =
+            Database::load_latest_profile(&self.inner, sender, room_id.map(String::as_str))?;
+
+        match profile {
+            Some(mut profile) => {
+                if let Some(cipher) = &self.source_cipher {
+                    cipher.decrypt_profile(&mut profile)?;
+                }
+
+                Ok(Some(profile))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get the last known profiles of the given users.
+    ///
+    /// Every given user is part of the returned map, users that don't have
+    /// any events map to `None`. See `load_profile()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `senders` - The user ids of the users, may contain duplicates.
+    pub fn load_profiles(&self, senders: &[MxId]) -> Result<HashMap<MxId, Option<Profile>>> {
+        let mut profiles = HashMap::with_capacity(senders.len());
+
+        for sender in senders {
+            if !profiles.contains_key(sender) {
+                profiles.insert(sender.clone(), self.load_profile(sender, None)?);
+            }
+        }
+
+        Ok(profiles)
+    }
+
+    /// Get the ids of the rooms that have their indexing disabled.
+    pub fn get_unindexed_rooms(&self) -> Result<Vec<String>> {
+        Ok(Database::load_unindexed_rooms(&self.inner)?)
+    }
+
+    /// Get the ids of the rooms that the database contains events for.
+    ///
+    /// Rooms that only have a crawler checkpoint aren't returned, see
+    /// `get_checkpoint_only_rooms()`.
+    pub fn get_rooms(&self) -> Result<Vec<RoomId>> {
+        Ok(Database::load_rooms(&self.inner)?)
+    }
+
+    /// Get the ids of the rooms that have a crawler checkpoint but no events
+    /// yet, e.g. because their crawl just started.
+    pub fn get_checkpoint_only_rooms(&self) -> Result<Vec<RoomId>> {
+        Ok(Database::load_checkpoint_only_rooms(&self.inner)?)
+    }
+
+    /// Get the ids of the rooms that have events but no crawler checkpoint.
+    ///
+    /// These are rooms whose crawl either finished or lost its checkpoint,
+    /// e.g. because of a crash in the middle of a crawl. Rooms that have their
+    /// indexing disabled aren't returned.
+    pub fn get_rooms_without_checkpoints(&self) -> Result<Vec<RoomId>> {
+        Ok(Database::load_rooms_without_checkpoints(&self.inner)?)
+    }
+
     /// Get statistical information of the database.
     pub fn get_stats(&self) -> Result<DatabaseStats> {
         let event_count = Database::get_event_count(&self.inner)? as u64;
         let room_count = Database::get_room_count(&self.inner)? as u64;
-        let size = dir::get_size(&self.path)?;
+        let tombstone_count = Database::get_tombstone_count(&self.inner)? as u64;
+        let size = Database::get_locations_size(&self.locations)?;
+        let consistency = Database::load_consistency(&self.inner)?;
+        let cache = self.source_cache.lock().unwrap();
         Ok(DatabaseStats {
             size,
             event_count,
             room_count,
+            tombstone_count,
+            source_cache_hits: cache.hits(),
+            source_cache_misses: cache.misses(),
+            consistency,
         })
     }
 
+    /// Get the directory the index of the database was last opened from.
+    ///
+    /// This allows the location of the index to be found using only the
+    /// store, `None` if the store was never opened by a version of Seshat
+    /// that records the location.
+    pub fn get_recorded_index_path(&self) -> Result<Option<PathBuf>> {
+        Database::load_index_path(&self.inner)
+    }
+
+    /// Get statistical information about the events of a room.
+    ///
+    /// A room that the database doesn't know about has an event count of 0
+    /// and no timestamps.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room.
+    pub fn get_room_info(&self, room_id: &str) -> Result<RoomInfo> {
+        let info = self.query_row(
+            "SELECT COUNT(events.id), MIN(events.server_ts), MAX(events.server_ts)
+             FROM events
+             INNER JOIN rooms on rooms.id = events.room_id
+             WHERE rooms.room_id = ?1",
+            [room_id],
+            |row| {
+                Ok(RoomInfo {
+                    room_id: room_id.to_owned(),
+                    event_count: row.get::<_, i64>(0)? as u64,
+                    oldest_event_ts: row.get(1)?,
+                    newest_event_ts: row.get(2)?,
+                })
+            },
+        )?;
+
+        Ok(info)
+    }
+
+    /// Get statistical information about the events of every room.
+    ///
+    /// Rooms without any events aren't part of the result.
+    pub fn get_all_room_infos(&self) -> Result<Vec<RoomInfo>> {
+        let mut stmt = self.prepare(
+            "SELECT rooms.room_id, COUNT(events.id), MIN(events.server_ts),
+                    MAX(events.server_ts)
+             FROM events
+             INNER JOIN rooms on rooms.id = events.room_id
+             GROUP BY rooms.room_id
+             ORDER BY rooms.room_id",
+        )?;
+
+        let infos = stmt.query_map([], |row| {
+            Ok(RoomInfo {
+                room_id: row.get(0)?,
+                event_count: row.get::<_, i64>(1)? as u64,
+                oldest_event_ts: row.get(2)?,
+                newest_event_ts: row.get(3)?,
+            })
+        })?;
+
+        Ok(infos.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Write all the stored events to the writer as JSON Lines.
+    ///
+    /// Every line is a JSON object containing the source of the event, the
+    /// profile of the sender at the time the event was sent and whether the
+    /// event has already been committed to the index. Events are ordered by
+    /// room and timestamp and are streamed out of the database, so memory
+    /// usage doesn't grow with the number of events.
+    ///
+    /// Events whose stored source can't be parsed are written out with an
+    /// `error` field in place of the event source, events whose profile can't
+    /// be read are written out with an empty profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The writer the events should be written to.
+    ///
+    /// Returns the number of events that were written.
+    pub fn dump_events<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let mut stmt = self.prepare(
+            "SELECT rooms.room_id, events.event_id, source, displayname, avatar_url,
+                    uncommitted_events.id IS NULL
+             FROM events
+             INNER JOIN rooms ON rooms.id = events.room_id
+             INNER JOIN profile ON profile.id = events.profile_id
+             LEFT JOIN uncommitted_events ON uncommitted_events.event_id = events.id
+             ORDER BY rooms.room_id, events.server_ts, events.id",
+        )?;
+
+        let mut rows = stmt.query([])?;
+        let mut count = 0;
+
+        while let Some(row) = rows.next()? {
+            let room_id: String = row.get(0)?;
+            let event_id: String = row.get(1)?;
+
+            let event = row
+                .get::<_, String>(2)
+                .map_err(|e| e.to_string())
+                .and_then(|source| match &self.source_cipher {
+                    Some(cipher) => cipher.decrypt(&source).map_err(|e| e.to_string()),
+                    None => Ok(source),
+                })
+                .and_then(|source| {
+                    serde_json::from_str::<Value>(&source).map_err(|e| e.to_string())
+                });
+
+            // A profile that can't be read doesn't abort the dump, the event
+            // is written out with an empty profile instead.
+            let profile = self.dump_profile(row).unwrap_or_default();
+
+            let line = match event {
+                Ok(event) => json!({
+                    "room_id": room_id,
+                    "event": event,
+                    "profile": {
+                        "displayname": profile.displayname,
+                        "avatar_url": profile.avatar_url,
+                    },
+                    "indexed": row.get::<_, bool>(5)?,
+                }),
+                Err(e) => json!({
+                    "room_id": room_id,
+                    "event_id": event_id,
+                    "error": e,
+                }),
+            };
+
+            writeln!(writer, "{}", line)?;
+            count += 1;
+        }
+
+        writer.flush()?;
+
+        Ok(count)
+    }
+
+    /// Read and decrypt the profile of an event that is dumped.
+    fn dump_profile(&self, row: &rusqlite::Row) -> Result<Profile> {
+        let mut profile = Profile {
+            displayname: row.get(3)?,
+            avatar_url: row.get(4)?,
+        };
+
+        if let Some(cipher) = &self.source_cipher {
+            cipher.decrypt_profile(&mut profile)?;
+        }
+
+        Ok(profile)
+    }
+
     /// Load events that contain an mxc URL to a file.
     /// # Arguments
     ///
@@ -132,19 +586,146 @@ impl Connection {
         &self,
         load_config: &LoadConfig,
     ) -> Result<Vec<(SerializedEvent, Profile)>> {
-        Ok(Database::load_file_events(
+        let mut events = Database::load_file_events(
             self,
             &load_config.room_id,
             load_config.limit,
             load_config.from_event.as_deref(),
             &load_config.direction,
-        )?)
+        )?;
+
+        if let Some(cipher) = &self.source_cipher {
+            for (source, profile) in &mut events {
+                *source = cipher.decrypt(source)?;
+                cipher.decrypt_profile(profile)?;
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Load message events that contain a link to a web page.
+    /// # Arguments
+    ///
+    /// * `load_config` - Configuration deciding which events and how many of
+    ///   them should be loaded.
+    ///
+    /// # Examples
+    ///
+    /// ```noexecute
+    /// let config = LoadConfig::new("!testroom:localhost").limit(10);
+    /// let result = connection.load_url_events(&config);
+    /// ```
+    ///
+    /// Returns a list of tuples containing the serialized events and the
+    ///   profile of the sender at the time when the event was sent.
+    pub fn load_url_events(
+        &self,
+        load_config: &LoadConfig,
+    ) -> Result<Vec<(SerializedEvent, Profile)>> {
+        let mut events = Database::load_url_events(
+            self,
+            &load_config.room_id,
+            load_config.limit,
+            load_config.from_event.as_deref(),
+            &load_config.direction,
+        )?;
+
+        if let Some(cipher) = &self.source_cipher {
+            for (source, profile) in &mut events {
+                *source = cipher.decrypt(source)?;
+                cipher.decrypt_profile(profile)?;
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Load an event together with the events surrounding it in its room.
+    ///
+    /// The context is loaded the same way as the context of search results,
+    /// the context events are ordered by their timestamp and their event id.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The event id of the event.
+    /// * `before_limit` - The maximum number of events that happened before
+    ///   the event that should be loaded.
+    /// * `after_limit` - The maximum number of events that happened after the
+    ///   event that should be loaded.
+    ///
+    /// Returns `None` if the event isn't part of the database.
+    pub fn get_context(
+        &self,
+        event_id: &str,
+        before_limit: usize,
+        after_limit: usize,
+    ) -> Result<Option<EventWithContext>> {
+        let context = Database::retry_on_busy(|| {
+            Ok(Database::load_event_with_context(
+                self,
+                event_id,
+                before_limit,
+                after_limit,
+            )?)
+        })?;
+
+        let mut context = match context {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        if let Some(cipher) = &self.source_cipher {
+            context.event_source = cipher.decrypt(&context.event_source)?;
+
+            for event in context
+                .events_before
+                .iter_mut()
+                .chain(context.events_after.iter_mut())
+            {
+                event.source = cipher.decrypt(&event.source)?;
+            }
+
+            for profile in context.profile_info.values_mut() {
+                cipher.decrypt_profile(profile)?;
+            }
+        }
+
+        Ok(Some(context))
+    }
+
+    /// Record a search term in the search history.
+    ///
+    /// Searching for a term again moves it to the top of the history, only
+    /// the 100 most recent terms are kept. Note that the terms are only
+    /// encrypted if the database itself is encrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be recorded.
+    pub fn add_historic_search(&self, term: &str) -> Result<()> {
+        Database::retry_on_busy(|| Ok(Database::add_search_term(self, term)?))
+    }
+
+    /// Load the most recently used search terms, the most recent one first.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximal number of search terms that should be loaded.
+    pub fn load_recent_searches(&self, limit: usize) -> Result<Vec<String>> {
+        Ok(Database::load_recent_searches(self, limit)?)
+    }
+
+    /// Remove all the search terms from the search history.
+    pub fn clear_search_history(&self) -> Result<()> {
+        Database::retry_on_busy(|| Ok(Database::clear_search_history(self)?))
     }
 
     /// Get the user version stored in the database.
     ///
     /// This version isn't used anywhere internally and can be set by the user
     /// to signal changes between the JSON that gets stored inside of Seshat.
+    /// The user version of a fresh database is 0.
     pub fn get_user_version(&self) -> Result<i64> {
         Database::get_user_version(self)
     }
@@ -155,7 +736,27 @@ impl Connection {
     ///
     /// * `version` - The new version that will be stored in the database.
     pub fn set_user_version(&self, version: i64) -> Result<()> {
-        Database::set_user_version(self, version)
+        Database::retry_on_busy(|| Database::set_user_version(self, version))
+    }
+
+    /// Set a named watermark to the given timestamp, see
+    /// `Database::set_watermark()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the watermark.
+    /// * `timestamp` - The timestamp the watermark should be set to.
+    pub fn set_watermark(&self, name: &str, timestamp: i64) -> Result<()> {
+        Database::retry_on_busy(|| Ok(Database::store_watermark(self, name, timestamp)?))
+    }
+
+    /// Get the timestamp of a named watermark, `None` if it was never set.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the watermark.
+    pub fn get_watermark(&self, name: &str) -> Result<Option<i64>> {
+        Ok(Database::load_watermark(self, name)?)
     }
 }
 
@@ -0,0 +1,474 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashSet, sync::Arc};
+
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    OptionalExtension, ToSql,
+};
+use uuid::Uuid;
+
+use crate::{
+    database::source_cipher::SourceCipher,
+    error::Result,
+    events::{EventId, SerializedEvent},
+    index::{Index, Writer as IndexWriter},
+    Database, RecoveryDatabase,
+};
+
+#[cfg(test)]
+use crate::{config::SearchConfig, error::Error, events::Profile, EVENT, TOPIC_EVENT};
+#[cfg(test)]
+use tempfile::tempdir;
+
+/// How many events are added to the index again before the index is
+/// committed while a database is repaired.
+const REINDEX_BATCH_SIZE: usize = 500;
+
+/// The result of comparing the SQLite store of a database with its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConsistencyState {
+    /// The index contains the events of the store.
+    Consistent,
+    /// The index is missing events of the store, e.g. because the index was
+    /// wiped or restored from an older backup.
+    IndexBehind,
+    /// The index contains events that the store doesn't know about, e.g.
+    /// because only the store was restored from an older backup.
+    StoreBehind,
+    /// The index was written for a different store.
+    Diverged,
+}
+
+impl ConsistencyState {
+    /// The name of the state.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConsistencyState::Consistent => "consistent",
+            ConsistencyState::IndexBehind => "indexBehind",
+            ConsistencyState::StoreBehind => "storeBehind",
+            ConsistencyState::Diverged => "diverged",
+        }
+    }
+}
+
+impl ToSql for ConsistencyState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for ConsistencyState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "consistent" => Ok(ConsistencyState::Consistent),
+            "indexBehind" => Ok(ConsistencyState::IndexBehind),
+            "storeBehind" => Ok(ConsistencyState::StoreBehind),
+            "diverged" => Ok(ConsistencyState::Diverged),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// The state of the index that the store recorded.
+pub(crate) struct IndexState {
+    /// A random id of the store, every commit of the index stores it as its
+    /// payload.
+    pub(crate) store_id: String,
+    /// The opstamp of the last commit of the index that the store knows about.
+    pub(crate) opstamp: u64,
+    /// The result of the last consistency check, it's kept until the
+    /// database is repaired.
+    pub(crate) consistency: ConsistencyState,
+}
+
+impl Database {
+    pub(crate) fn load_index_state(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<Option<IndexState>> {
+        connection
+            .query_row(
+                "SELECT store_id, opstamp, consistency FROM index_state",
+                [],
+                |row| {
+                    Ok(IndexState {
+                        store_id: row.get(0)?,
+                        opstamp: row.get::<_, i64>(1)? as u64,
+                        consistency: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    /// Record the opstamp of the last commit of the index.
+    pub(crate) fn save_index_opstamp(
+        connection: &rusqlite::Connection,
+        opstamp: u64,
+    ) -> rusqlite::Result<()> {
+        connection.execute("UPDATE index_state SET opstamp = ?1", [opstamp as i64])?;
+        Ok(())
+    }
+
+    pub(crate) fn save_consistency(
+        connection: &rusqlite::Connection,
+        consistency: ConsistencyState,
+    ) -> rusqlite::Result<()> {
+        connection.execute("UPDATE index_state SET consistency = ?1", [consistency])?;
+        Ok(())
+    }
+
+    /// Load the result of the last consistency check, stores that weren't
+    /// checked yet are consistent.
+    pub(crate) fn load_consistency(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<ConsistencyState> {
+        Ok(Database::load_index_state(connection)?
+            .map_or(ConsistencyState::Consistent, |s| s.consistency))
+    }
+
+    /// Compare the store with the index when the database is opened.
+    ///
+    /// The store records the opstamp of every index commit after the commit
+    /// happened, an index whose last commit is older than the recorded one
+    /// is missing events. The event counts of both are compared as well,
+    /// this catches a store that was restored from an older backup and
+    /// indexes that were wiped.
+    ///
+    /// A mismatch is recorded and reported until the database is repaired.
+    /// Stores that didn't record the state of their index yet adopt the
+    /// index.
+    ///
+    /// Returns the id of the store, commits of the index should use it as
+    /// their payload.
+    pub(crate) fn check_consistency(
+        connection: &rusqlite::Connection,
+        index: &Index,
+    ) -> Result<String> {
+        let (index_opstamp, index_store_id) = index.last_commit()?;
+
+        let (store_id, consistency) = match Database::load_index_state(connection)? {
+            Some(state) if state.consistency != ConsistencyState::Consistent => {
+                return Ok(state.store_id)
+            }
+            Some(state) => {
+                let consistency = if index_store_id.map_or(false, |id| id != state.store_id) {
+                    ConsistencyState::Diverged
+                } else if index_opstamp < state.opstamp {
+                    ConsistencyState::IndexBehind
+                } else {
+                    Database::compare_event_counts(connection, index.event_count())?
+                };
+
+                (state.store_id, consistency)
+            }
+            None => {
+                let store_id = index_store_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+
+                connection.execute(
+                    "INSERT INTO index_state (store_id, opstamp, consistency)
+                     VALUES (?1, ?2, ?3)",
+                    [
+                        &store_id as &dyn ToSql,
+                        &(index_opstamp as i64),
+                        &ConsistencyState::Consistent,
+                    ],
+                )?;
+
+                let consistency = Database::compare_event_counts(connection, index.event_count())?;
+                (store_id, consistency)
+            }
+        };
+
+        if consistency != ConsistencyState::Consistent {
+            Database::save_consistency(connection, consistency)?;
+        }
+
+        Ok(store_id)
+    }
+
+    /// Compare the number of events of the index with the number of events
+    /// of the store.
+    ///
+    /// Events without any text are only kept in the store, so the index may
+    /// contain fewer events than the store. Events that weren't committed
+    /// yet or whose deletion wasn't committed yet may or may not be part of
+    /// the index.
+    fn compare_event_counts(
+        connection: &rusqlite::Connection,
+        index_events: u64,
+    ) -> rusqlite::Result<ConsistencyState> {
+        let stored_events = Database::get_event_count(connection)? as u64;
+        let uncommitted_events: i64 =
+            connection.query_row("SELECT COUNT(*) FROM uncommitted_events", [], |row| {
+                row.get(0)
+            })?;
+        let pending_deletions: i64 =
+            connection.query_row("SELECT COUNT(*) FROM pending_deletion_events", [], |row| {
+                row.get(0)
+            })?;
+
+        Ok(if index_events > stored_events + pending_deletions as u64 {
+            ConsistencyState::StoreBehind
+        } else if index_events == 0 && stored_events > uncommitted_events as u64 {
+            ConsistencyState::IndexBehind
+        } else {
+            ConsistencyState::Consistent
+        })
+    }
+
+    /// Load the sources of the stored events, ordered by their row id.
+    ///
+    /// * `after` - The row id of the last event of the previous batch, 0 to
+    ///   start from the first event.
+    pub(crate) fn load_event_sources(
+        connection: &rusqlite::Connection,
+        after: i64,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<(i64, SerializedEvent)>> {
+        let mut stmt = connection
+            .prepare("SELECT id, source FROM events WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+        let sources =
+            stmt.query_map([after, limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        sources.collect()
+    }
+
+    /// Delete the events from the index that the store doesn't contain.
+    ///
+    /// Returns the number of deleted events.
+    pub(crate) fn delete_orphaned_events(
+        connection: &rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+    ) -> Result<usize> {
+        let indexed: Vec<EventId> = index_writer.committed_event_ids()?;
+        let known: HashSet<EventId> = Database::load_known_event_ids(connection, &indexed)?
+            .into_iter()
+            .collect();
+
+        let orphans: Vec<EventId> = indexed
+            .into_iter()
+            .filter(|event_id| !known.contains(event_id))
+            .collect();
+
+        // The index is committed even if there are no orphans, the commit
+        // records the id of this store.
+        index_writer.delete_events(&orphans)?;
+
+        Ok(orphans.len())
+    }
+
+    /// Add the events of the store to the index again.
+    ///
+    /// Events that are already part of the index are replaced, events whose
+    /// source can't be parsed are skipped like they are when a
+    /// `RecoveryDatabase` reindexes the events.
+    ///
+    /// Returns the number of reindexed events.
+    pub(crate) fn reindex_stored_events(
+        connection: &rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        source_cipher: &Option<Arc<SourceCipher>>,
+        max_indexed_body_bytes: usize,
+        index_state_history: bool,
+    ) -> Result<usize> {
+        let mut last_id = 0;
+        let mut reindexed = 0;
+
+        loop {
+            let sources = Database::load_event_sources(connection, last_id, REINDEX_BATCH_SIZE)?;
+
+            last_id = match sources.last() {
+                Some((id, _)) => *id,
+                None => break,
+            };
+
+            let mut events = Vec::with_capacity(sources.len());
+
+            for (_, source) in sources {
+                let source = match source_cipher {
+                    Some(cipher) => cipher.decrypt(&source)?,
+                    None => source,
+                };
+
+                if let Ok(mut event) = RecoveryDatabase::event_from_json(&source) {
+                    event.truncate_content_value(max_indexed_body_bytes);
+                    events.push(event);
+                }
+            }
+
+            let event_ids: Vec<EventId> = events.iter().map(|e| e.event_id.clone()).collect();
+            index_writer.delete_events(&event_ids)?;
+
+            for event in &events {
+                if !index_state_history && Database::is_outdated_state_event(connection, event)? {
+                    continue;
+                }
+
+                index_writer.add_event(event);
+            }
+
+            index_writer.force_commit()?;
+            reindexed += events.len();
+        }
+
+        Ok(reindexed)
+    }
+}
+
+#[test]
+fn consistent_database() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Consistent);
+
+    db.shutdown().recv().unwrap().unwrap();
+
+    let db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Consistent);
+    assert!(!db.repair().recv().unwrap().unwrap());
+}
+
+#[test]
+fn repair_an_index_that_is_behind() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.shutdown().recv().unwrap().unwrap();
+
+    // Replace the index with an empty one, as if a cleanup tool wiped it.
+    for entry in std::fs::read_dir(tmpdir.path()).unwrap() {
+        let path = entry.unwrap().path();
+
+        if !path.is_dir()
+            && !path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with(crate::database::EVENTS_DB_NAME)
+        {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    Index::new(tmpdir.path(), &crate::Config::new()).unwrap();
+
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::IndexBehind);
+    assert!(db
+        .search("test", &SearchConfig::new())
+        .unwrap()
+        .results
+        .is_empty());
+
+    assert!(db.repair().recv().unwrap().unwrap());
+    db.reload().unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Consistent);
+
+    let result = db.search("test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.results.len(), 2);
+    db.shutdown().recv().unwrap().unwrap();
+
+    let db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Consistent);
+}
+
+#[test]
+fn repair_a_store_that_is_behind() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+
+    // Forget an event in the store only, as if the store was restored from
+    // an older backup.
+    let connection = db.get_connection().unwrap();
+    Database::delete_event_by_id(&connection, &EVENT.event_id).unwrap();
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::StoreBehind);
+    assert!(db.is_event_indexed(&EVENT.event_id).unwrap());
+
+    assert!(db.repair().recv().unwrap().unwrap());
+    db.reload().unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Consistent);
+    assert!(!db.is_event_indexed(&EVENT.event_id).unwrap());
+    assert!(db.is_event_indexed(&TOPIC_EVENT.event_id).unwrap());
+}
+
+#[test]
+fn repair_a_diverged_index() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+
+    // Pretend that the index was written for another store.
+    let connection = db.get_connection().unwrap();
+    connection
+        .execute("UPDATE index_state SET store_id = 'another store'", [])
+        .unwrap();
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Diverged);
+
+    assert!(db.repair().recv().unwrap().unwrap());
+    db.reload().unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Consistent);
+    assert!(db.is_event_indexed(&EVENT.event_id).unwrap());
+    db.shutdown().recv().unwrap().unwrap();
+
+    let db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::Consistent);
+}
+
+#[test]
+fn reindexing_needs_the_event_sources() {
+    let tmpdir = tempdir().unwrap();
+    let config = crate::Config::new().set_store_sources(false);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    connection
+        .execute("UPDATE index_state SET consistency = 'indexBehind'", [])
+        .unwrap();
+    drop(connection);
+
+    assert!(matches!(
+        db.repair().recv().unwrap(),
+        Err(Error::SourcesNotStored)
+    ));
+    assert_eq!(db.get_consistency().unwrap(), ConsistencyState::IndexBehind);
+}
@@ -0,0 +1,249 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read},
+};
+
+use serde_json::Value;
+
+use crate::{
+    error::{Error, Result},
+    events::{Event, Profile},
+    BulkImport, Connection, Database, RecoveryDatabase,
+};
+
+#[cfg(test)]
+use crate::{config::SearchConfig, EVENT, EVENT_SOURCE};
+#[cfg(test)]
+use tempfile::tempdir;
+
+/// How many events should be written to the database in a single batch.
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// Summary of an import of a room export.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportSummary {
+    /// The number of events that were added to the database.
+    pub imported: usize,
+    /// The number of events that were skipped because they aren't searchable
+    /// or couldn't be parsed.
+    pub skipped: usize,
+    /// The number of events that were already part of the database.
+    pub duplicates: usize,
+}
+
+impl Database {
+    /// Import events from a room export.
+    ///
+    /// The export can either be a plain JSON array of events or an object
+    /// containing a `room_id` and the events in a `messages` array. Events
+    /// that aren't searchable are skipped, membership events are used to
+    /// figure out the profile of the senders. The events are committed in
+    /// batches.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The reader containing the JSON encoded room export.
+    ///
+    /// Returns a summary containing the number of imported, skipped and
+    /// duplicate events.
+    pub fn import_events<R: Read>(&self, reader: R) -> Result<ImportSummary> {
+        let connection = self.get_connection()?;
+        self.start_bulk_import().import_events(&connection, reader)
+    }
+}
+
+impl BulkImport {
+    /// Import events from a room export.
+    ///
+    /// This is the same as `Database::import_events()` but it can be used from
+    /// a different thread than the one that owns the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - A database connection, used to find out which events
+    ///   are already in the database.
+    /// * `reader` - The reader containing the JSON encoded room export.
+    pub fn import_events<R: Read>(
+        self,
+        connection: &Connection,
+        reader: R,
+    ) -> Result<ImportSummary> {
+        let export: Value = serde_json::from_reader(reader).map_err(io::Error::from)?;
+
+        let (room_id, events) = match export {
+            Value::Array(events) => (None, events),
+            Value::Object(mut object) => {
+                let room_id = object
+                    .get("room_id")
+                    .and_then(Value::as_str)
+                    .map(|r| r.to_owned());
+
+                match object.remove("messages") {
+                    Some(Value::Array(events)) => (room_id, events),
+                    _ => return Err(Error::ImportError("No messages found".to_owned())),
+                }
+            }
+            _ => return Err(Error::ImportError("Unsupported export format".to_owned())),
+        };
+
+        let mut summary = ImportSummary::default();
+        let mut profiles: HashMap<String, Profile> = HashMap::new();
+        let mut seen_events = HashSet::new();
+        let mut batch = Vec::new();
+
+        for mut event in events {
+            if let (Some(room_id), Some(object)) = (&room_id, event.as_object_mut()) {
+                object
+                    .entry("room_id")
+                    .or_insert_with(|| Value::String(room_id.clone()));
+            }
+
+            if event["type"] == "m.room.member" {
+                update_profile(&mut profiles, &event);
+            }
+
+            let event = match RecoveryDatabase::event_from_json(&event.to_string()) {
+                Ok(e) => e,
+                Err(_) => {
+                    summary.skipped += 1;
+                    continue;
+                }
+            };
+
+            if Database::event_in_store(connection, &event)?
+                || !seen_events.insert(event.event_id.clone())
+            {
+                summary.duplicates += 1;
+                continue;
+            }
+
+            let profile = profiles.get(&event.sender).cloned().unwrap_or_default();
+            batch.push((event, profile));
+
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                summary.imported += self.import_batch(&mut batch)?;
+            }
+        }
+
+        summary.imported += self.import_batch(&mut batch)?;
+
+        Ok(summary)
+    }
+
+    fn import_batch(&self, batch: &mut Vec<(Event, Profile)>) -> Result<usize> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        let count = batch.len();
+
+        self.add_chunk(batch.split_off(0))
+            .recv()
+            .unwrap_or(Err(Error::WriterClosedError))?;
+
+        Ok(count)
+    }
+}
+
+/// Remember the profile of a user that joined the room.
+fn update_profile(profiles: &mut HashMap<String, Profile>, event: &Value) {
+    let user_id = match event["state_key"].as_str() {
+        Some(u) => u,
+        None => return,
+    };
+    let content = &event["content"];
+
+    if content["membership"] == "join" {
+        let profile = Profile {
+            displayname: content["displayname"].as_str().map(|d| d.to_owned()),
+            avatar_url: content["avatar_url"].as_str().map(|a| a.to_owned()),
+        };
+        profiles.insert(user_id.to_owned(), profile);
+    }
+}
+
+#[test]
+fn import_a_room_export() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    // The event is already part of the database.
+    db.add_historic_events(vec![(EVENT.clone(), Profile::default())], None, None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let event: Value = serde_json::from_str(EVENT_SOURCE).unwrap();
+    let mut message = event.clone();
+    message["event_id"] = "$imported:localhost".into();
+    message["content"]["body"] = "Imported message".into();
+    message["sender"] = "@alice:localhost".into();
+
+    let export = serde_json::json!({
+        "room_id": EVENT.room_id,
+        "messages": [
+            {
+                "type": "m.room.member",
+                "event_id": "$member:localhost",
+                "sender": "@alice:localhost",
+                "state_key": "@alice:localhost",
+                "origin_server_ts": 1516362244000u64,
+                "content": {"membership": "join", "displayname": "Alice"},
+            },
+            event,
+            message,
+            message,
+            {"type": "m.room.message", "content": {}},
+        ],
+    });
+
+    let summary = db.import_events(export.to_string().as_bytes()).unwrap();
+    assert_eq!(
+        summary,
+        ImportSummary {
+            imported: 1,
+            skipped: 2,
+            duplicates: 2,
+        }
+    );
+
+    db.reload().unwrap();
+    let result = db.search("Imported", &SearchConfig::new()).unwrap();
+    assert_eq!(result.results.len(), 1);
+    assert_eq!(
+        result.results[0].profile_info["@alice:localhost"].displayname,
+        Some("Alice".to_owned())
+    );
+}
+
+#[test]
+fn import_an_event_array() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let mut event: Value = serde_json::from_str(EVENT_SOURCE).unwrap();
+    event["room_id"] = EVENT.room_id.as_str().into();
+
+    let export = Value::Array(vec![event]);
+    let summary = db.import_events(export.to_string().as_bytes()).unwrap();
+    assert_eq!(summary.imported, 1);
+
+    assert!(matches!(
+        db.import_events("{}".as_bytes()),
+        Err(Error::ImportError(_))
+    ));
+}
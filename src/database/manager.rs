@@ -0,0 +1,370 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    index::TANTIVY_MIN_WRITER_HEAP_SIZE,
+    Database,
+};
+
+#[cfg(test)]
+use crate::{config::SearchConfig, events::Profile, EVENT};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Every database needs a connection for itself, one for its writer and one
+// to hand out to readers.
+const MIN_CONNECTIONS_PER_DATABASE: u32 = 3;
+const DEFAULT_CONNECTION_BUDGET: u32 = 30;
+const DEFAULT_WRITER_HEAP_BUDGET: usize = 100_000_000;
+
+struct OpenDatabase {
+    database: Database,
+    connections: u32,
+    writer_heap_size: usize,
+}
+
+/// Manager for the databases of several accounts.
+///
+/// Every account gets its own database, with a separate index and store, in
+/// a directory below the root directory of the manager. The databases share
+/// a budget of Sqlite connections and of index writer memory, every database
+/// that is opened gets at most what's left of the budgets.
+///
+/// Each database still commits on its own writer thread, the manager only
+/// makes sure that the threads and connection pools stay small if many
+/// accounts are open at once.
+pub struct DatabaseManager {
+    root: PathBuf,
+    connection_budget: u32,
+    writer_heap_budget: usize,
+    databases: HashMap<String, OpenDatabase>,
+}
+
+impl DatabaseManager {
+    /// Create a new manager for the databases in the given root directory.
+    ///
+    /// The directory is created if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory the databases of the accounts are stored in.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<DatabaseManager> {
+        fs::create_dir_all(&root)?;
+
+        Ok(DatabaseManager {
+            root: root.as_ref().to_path_buf(),
+            connection_budget: DEFAULT_CONNECTION_BUDGET,
+            writer_heap_budget: DEFAULT_WRITER_HEAP_BUDGET,
+            databases: HashMap::new(),
+        })
+    }
+
+    /// Set the number of Sqlite connections all the open databases may use
+    /// together.
+    ///
+    /// Every database gets at least three connections, even if the budget is
+    /// used up. Defaults to 30 connections.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - The number of connections shared by the databases.
+    pub fn set_connection_budget(mut self, budget: u32) -> Self {
+        self.connection_budget = budget;
+        self
+    }
+
+    /// Set the amount of memory in bytes the index writers of all the open
+    /// databases may use together.
+    ///
+    /// Every database gets at least 3MB, even if the budget is used up.
+    /// Defaults to 100MB.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - The writer heap size shared by the databases.
+    pub fn set_writer_heap_budget(mut self, budget: usize) -> Self {
+        self.writer_heap_budget = budget;
+        self
+    }
+
+    /// Get the directory the databases are stored in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Open the database of an account, creating it if it doesn't exist.
+    ///
+    /// The connection pool size and the writer heap size of the config are
+    /// lowered to what's left of the budgets of the manager. If the database
+    /// of the account is already open it is returned as is.
+    ///
+    /// # Arguments
+    ///
+    /// * `account_id` - The id of the account, e.g. its Matrix user id.
+    /// * `config` - The configuration the database should be opened with.
+    pub fn open(&mut self, account_id: &str, config: &Config) -> Result<&mut Database> {
+        if account_id.is_empty() {
+            return Err(Error::DatabaseOpenError(
+                "The account id is empty".to_owned(),
+            ));
+        }
+
+        if !self.databases.contains_key(account_id) {
+            let (used_connections, used_heap) = self
+                .databases
+                .values()
+                .fold((0, 0), |(connections, heap), d| {
+                    (connections + d.connections, heap + d.writer_heap_size)
+                });
+
+            let connections = config
+                .connection_pool_size
+                .min(self.connection_budget.saturating_sub(used_connections))
+                .max(MIN_CONNECTIONS_PER_DATABASE);
+            let writer_heap_size = config
+                .writer_heap_size
+                .min(self.writer_heap_budget.saturating_sub(used_heap))
+                .max(TANTIVY_MIN_WRITER_HEAP_SIZE);
+
+            let config = config
+                .clone()
+                .set_connection_pool_size(connections)
+                .set_writer_heap_size(writer_heap_size);
+
+            let database = Database::new_with_config(self.account_path(account_id), &config)?;
+
+            self.databases.insert(
+                account_id.to_owned(),
+                OpenDatabase {
+                    database,
+                    connections,
+                    writer_heap_size,
+                },
+            );
+        }
+
+        Ok(&mut self.databases.get_mut(account_id).unwrap().database)
+    }
+
+    /// Get the database of an account if it's open.
+    pub fn get(&self, account_id: &str) -> Option<&Database> {
+        self.databases.get(account_id).map(|d| &d.database)
+    }
+
+    /// Get the database of an account mutably if it's open.
+    pub fn get_mut(&mut self, account_id: &str) -> Option<&mut Database> {
+        self.databases.get_mut(account_id).map(|d| &mut d.database)
+    }
+
+    /// Close the database of an account.
+    ///
+    /// Waits until the queued up events of the database are committed, the
+    /// other databases aren't affected. The budget the database used is
+    /// freed up for databases that are opened later on.
+    ///
+    /// Returns false if the database of the account wasn't open.
+    ///
+    /// # Arguments
+    ///
+    /// * `account_id` - The id of the account.
+    pub fn close(&mut self, account_id: &str) -> Result<bool> {
+        match self.databases.remove(account_id) {
+            Some(d) => {
+                // The writer thread might already be gone if it failed, in
+                // which case there's nothing left to commit.
+                d.database.shutdown().recv().unwrap_or(Ok(()))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Delete the database of an account.
+    ///
+    /// The database is closed first if it's open, databases that were opened
+    /// with a separate index or store path should be deleted using
+    /// `Database::delete()` instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `account_id` - The id of the account.
+    pub fn delete(&mut self, account_id: &str) -> Result<()> {
+        match self.databases.remove(account_id) {
+            Some(d) => d.database.delete(),
+            None => match fs::remove_dir_all(self.account_path(account_id)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Get the ids of the accounts that have a database in the root
+    /// directory, whether they are open or not.
+    pub fn list_accounts(&self) -> Result<Vec<String>> {
+        let mut accounts = Vec::new();
+
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            if let Some(account_id) = entry.file_name().to_str().and_then(decode_account_id) {
+                accounts.push(account_id);
+            }
+        }
+
+        accounts.sort();
+        Ok(accounts)
+    }
+
+    /// Get the ids of the accounts whose database is open.
+    pub fn open_accounts(&self) -> Vec<String> {
+        let mut accounts: Vec<String> = self.databases.keys().cloned().collect();
+        accounts.sort();
+        accounts
+    }
+
+    fn account_path(&self, account_id: &str) -> PathBuf {
+        self.root.join(encode_account_id(account_id))
+    }
+}
+
+/// Turn an account id into a directory name.
+///
+/// Matrix user ids contain characters like `:` that aren't allowed in file
+/// names on every platform, those are percent-encoded.
+pub(crate) fn encode_account_id(account_id: &str) -> String {
+    let mut encoded = String::with_capacity(account_id.len());
+
+    for byte in account_id.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    encoded
+}
+
+/// Turn a directory name back into an account id.
+///
+/// Returns `None` if the name wasn't created by `encode_account_id()`.
+pub(crate) fn decode_account_id(name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut chars = name.bytes();
+
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'%' => {
+                let hex = [chars.next()?, chars.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b if b.is_ascii_alphanumeric() || b"-_.".contains(&b) => bytes.push(b),
+            _ => return None,
+        }
+    }
+
+    if bytes.is_empty() {
+        return None;
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[test]
+fn account_ids_round_trip() {
+    for account_id in &["@alice:example.org", "bob", "@carol:example.org:8448"] {
+        let encoded = encode_account_id(account_id);
+        assert!(!encoded.contains(':'));
+        assert_eq!(decode_account_id(&encoded).as_deref(), Some(*account_id));
+    }
+
+    assert_eq!(decode_account_id("not a database"), None);
+    assert_eq!(decode_account_id("%4"), None);
+}
+
+#[test]
+fn manage_databases_of_several_accounts() {
+    let tmpdir = tempdir().unwrap();
+    let mut manager = DatabaseManager::new(tmpdir.path())
+        .unwrap()
+        .set_connection_budget(8);
+
+    let config = Config::new();
+
+    let alice = manager.open("@alice:example.org", &config).unwrap();
+    alice.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    alice.force_commit().unwrap();
+    alice.reload().unwrap();
+
+    let bob = manager.open("@bob:example.org", &config).unwrap();
+    bob.force_commit().unwrap();
+    bob.reload().unwrap();
+
+    assert_eq!(
+        manager.list_accounts().unwrap(),
+        vec!["@alice:example.org", "@bob:example.org"]
+    );
+
+    // The databases are separate, only Alice's database contains the event.
+    let result = manager
+        .get("@alice:example.org")
+        .unwrap()
+        .search("test", &SearchConfig::new())
+        .unwrap();
+    assert_eq!(result.results.len(), 1);
+
+    let result = manager
+        .get("@bob:example.org")
+        .unwrap()
+        .search("test", &SearchConfig::new())
+        .unwrap();
+    assert!(result.results.is_empty());
+
+    // Closing one account doesn't affect the other one.
+    assert!(manager.close("@bob:example.org").unwrap());
+    assert!(!manager.close("@bob:example.org").unwrap());
+    assert!(manager.get("@bob:example.org").is_none());
+    assert_eq!(manager.open_accounts(), vec!["@alice:example.org"]);
+
+    let result = manager
+        .get("@alice:example.org")
+        .unwrap()
+        .search("test", &SearchConfig::new())
+        .unwrap();
+    assert_eq!(result.results.len(), 1);
+
+    // Closed databases are still listed and can be deleted.
+    assert_eq!(manager.list_accounts().unwrap().len(), 2);
+    manager.delete("@bob:example.org").unwrap();
+    assert_eq!(manager.list_accounts().unwrap(), vec!["@alice:example.org"]);
+
+    // Reopening a database keeps its events.
+    assert!(manager.close("@alice:example.org").unwrap());
+    let alice = manager.open("@alice:example.org", &config).unwrap();
+    let result = alice.search("test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.results.len(), 1);
+}
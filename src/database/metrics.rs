@@ -0,0 +1,101 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// A snapshot of the operational counters of a database.
+///
+/// The counters start at zero when the database is opened, they aren't
+/// persisted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatabaseMetrics {
+    /// The number of events that were stored.
+    pub events_added: u64,
+    /// The number of events that were deleted.
+    pub events_deleted: u64,
+    /// The number of commits of the index.
+    pub commits: u64,
+    /// The number of searches that were served.
+    pub searches: u64,
+    /// The number of searches that failed.
+    pub search_errors: u64,
+    /// The number of search results whose source was served from the source
+    /// cache.
+    pub source_cache_hits: u64,
+    /// The number of search results whose source had to be loaded from the
+    /// database.
+    pub source_cache_misses: u64,
+}
+
+/// Counters that are shared between the writer thread and the searchers.
+///
+/// The counters are atomics, updating them doesn't need a lock.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    events_added: AtomicU64,
+    events_deleted: AtomicU64,
+    commits: AtomicU64,
+    searches: AtomicU64,
+    search_errors: AtomicU64,
+}
+
+pub(crate) type SharedMetrics = Arc<Metrics>;
+
+impl Metrics {
+    pub(crate) fn new_shared() -> SharedMetrics {
+        Arc::new(Metrics::default())
+    }
+
+    pub(crate) fn add_events(&self, count: usize) {
+        self.events_added.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn delete_events(&self, count: usize) {
+        self.events_deleted
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn commit(&self) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn search<T, E>(&self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => self.searches.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.search_errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Take a snapshot of the counters, the source cache counters are kept by
+    /// the cache itself and need to be passed in.
+    pub(crate) fn snapshot(
+        &self,
+        source_cache_hits: u64,
+        source_cache_misses: u64,
+    ) -> DatabaseMetrics {
+        DatabaseMetrics {
+            events_added: self.events_added.load(Ordering::Relaxed),
+            events_deleted: self.events_deleted.load(Ordering::Relaxed),
+            commits: self.commits.load(Ordering::Relaxed),
+            searches: self.searches.load(Ordering::Relaxed),
+            search_errors: self.search_errors.load(Ordering::Relaxed),
+            source_cache_hits,
+            source_cache_misses,
+        }
+    }
+}
@@ -12,11 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod backup;
 mod connection;
+mod consistency;
+mod import;
+mod manager;
+mod metrics;
 mod recovery;
 mod searcher;
+mod source_cache;
+mod source_cipher;
 mod static_methods;
 mod writer;
+mod writer_errors;
 
 use fs_extra::dir;
 use r2d2::{Pool, PooledConnection};
@@ -24,63 +32,396 @@ use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::ToSql;
 use std::{
     fs,
+    io::Write,
+    panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
     sync::{
-        mpsc::{channel, Receiver, Sender},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{
+            channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError,
+        },
         Arc, Mutex,
     },
     thread,
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
+use tantivy::{directory::INDEX_WRITER_LOCK, TantivyError};
 
 pub use crate::database::{
-    connection::{Connection, DatabaseStats},
+    connection::{Connection, ConnectionPool, DatabaseStats, EventWithContext, RoomInfo},
+    consistency::ConsistencyState,
+    import::ImportSummary,
+    manager::DatabaseManager,
+    metrics::DatabaseMetrics,
     recovery::{RecoveryDatabase, RecoveryInfo},
-    searcher::{SearchBatch, SearchResult, Searcher},
+    searcher::{
+        CancellationToken, ContextTruncation, ExportSummary, SearchBatch, SearchResult,
+        SearchTiming, Searcher,
+    },
+    writer_errors::{WriterError, WriterOperation},
 };
 use crate::{
     config::{Config, SearchConfig},
-    database::writer::Writer,
+    database::{
+        metrics::{Metrics, SharedMetrics},
+        source_cache::{SharedSourceCache, SourceCache},
+        source_cipher::SourceCipher,
+        writer::Writer,
+        writer_errors::WriterErrors,
+    },
     error::{Error, Result},
-    events::{CrawlerCheckpoint, Event, EventId, HistoricEventsT, Profile},
-    index::{Index, Writer as IndexWriter},
+    events::{
+        CrawlerCheckpoint, Event, EventId, HistoricEventsT, MxId, Profile, Reaction, RoomAlias,
+        RoomId,
+    },
+    index::{Index, Reloader, Writer as IndexWriter, INDEX_VERSION},
 };
 
 #[cfg(test)]
 use fake::{Fake, Faker};
 #[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::atomic::AtomicBool;
+#[cfg(test)]
 use std::time;
 #[cfg(test)]
 use tempfile::tempdir;
 
 #[cfg(test)]
-use crate::events::CheckpointDirection;
+use crate::config::{Language, LoadConfig, LoadDirection};
+#[cfg(test)]
+use crate::error::ErrorKind;
+#[cfg(test)]
+use crate::events::{
+    CheckpointDirection, CheckpointValidationError, ContextEvent, EventBuilder, EventType,
+    EventValidationError,
+};
 #[cfg(test)]
-use crate::{EVENT, TOPIC_EVENT};
+use crate::{EVENT, EVENT_SOURCE, TOPIC_EVENT, TOPIC_EVENT_SOURCE};
 
-const DATABASE_VERSION: i64 = 4;
+const DATABASE_VERSION: i64 = 10;
 const EVENTS_DB_NAME: &str = "events.db";
+/// The name of the file that lists the segments of the index.
+const INDEX_META: &str = "meta.json";
+/// How long should we wait before we retry to acquire the index lock.
+const LOCK_RETRY_SLEEP: Duration = Duration::from_millis(100);
+/// How long should a blocking add wait before it retries if the write queue
+/// is full.
+const QUEUE_FULL_SLEEP: Duration = Duration::from_millis(10);
 
 pub(crate) enum ThreadMessage {
-    Event((Event, Profile)),
+    Event((Event, Profile), u64),
+    Events(Vec<(Event, Profile)>, u64),
+    Reaction(Reaction, u64),
+    RoomAlias(RoomAlias, u64),
     HistoricEvents(HistoricEventsT),
-    Write(Sender<Result<()>>, bool),
+    Write(Sender<Result<u64>>, bool),
     Delete(Sender<Result<bool>>, EventId),
+    DeleteOlderThan(Sender<Result<usize>>, i64),
+    SetRoomIndexing(Sender<Result<usize>>, RoomId, bool, Option<u64>),
+    DeleteRoom(Sender<Result<usize>>, RoomId, u64),
+    DeleteSender(Sender<Result<usize>>, MxId, Box<dyn FnMut(usize) + Send>),
+    Compact(Sender<Result<bool>>),
+    Shrink(Sender<Result<u64>>, Vec<PathBuf>),
+    Backup(Sender<Result<()>>, PathBuf, PathBuf, PathBuf),
+    Repair(Sender<Result<bool>>),
+    WaitForRoom(Sender<Result<()>>, RoomId, i64),
     ShutDown(Sender<Result<()>>),
 }
 
+impl ThreadMessage {
+    /// Replace the sequence number of an add, other messages are returned
+    /// unchanged.
+    fn with_sequence(self, sequence: u64) -> Self {
+        match self {
+            ThreadMessage::Event(event, _) => ThreadMessage::Event(event, sequence),
+            ThreadMessage::Events(events, _) => ThreadMessage::Events(events, sequence),
+            ThreadMessage::Reaction(reaction, _) => ThreadMessage::Reaction(reaction, sequence),
+            ThreadMessage::RoomAlias(alias, _) => ThreadMessage::RoomAlias(alias, sequence),
+            message => message,
+        }
+    }
+}
+
 /// The Seshat database.
 pub struct Database {
     path: PathBuf,
+    index_path: PathBuf,
+    store_path: PathBuf,
     connection: Arc<Mutex<PooledConnection<SqliteConnectionManager>>>,
     pool: r2d2::Pool<SqliteConnectionManager>,
     _write_thread: JoinHandle<()>,
-    tx: Sender<ThreadMessage>,
+    tx: SyncSender<ThreadMessage>,
+    queue_length: Arc<AtomicUsize>,
     index: Index,
     config: Config,
+    source_cipher: Option<Arc<SourceCipher>>,
+    source_cache: SharedSourceCache,
+    metrics: SharedMetrics,
+    writer_errors: WriterErrors,
+    writer_status: WriterStatus,
+    add_sequence: AddSequence,
+    committed_sequence: Arc<AtomicU64>,
+    purge_epoch: Arc<AtomicU64>,
+}
+
+/// Version information about the library and an opened database.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    /// The version of the Seshat crate.
+    pub crate_version: String,
+    /// The version of the SQLite schema of the opened database.
+    pub database_version: i64,
+    /// The version of the index schema.
+    pub index_version: i64,
+}
+
+/// The number of bytes a database is using on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatabaseSize {
+    /// The size of the live files of the search index.
+    pub index_bytes: u64,
+    /// The size of the SQLite database, including its WAL and journal files.
+    pub store_bytes: u64,
+}
+
+impl DatabaseSize {
+    /// The total number of bytes the database is using.
+    pub fn total_bytes(&self) -> u64 {
+        self.index_bytes + self.store_bytes
+    }
+}
+
+/// The files that make up a database on disk.
+///
+/// Listing the files requires the opened database, getting their size only
+/// requires the file system, so the latter can happen on another thread.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DatabaseFiles {
+    /// The live files of the search index.
+    pub index: Vec<PathBuf>,
+    /// The SQLite database and its WAL, shared memory and journal files.
+    pub store: Vec<PathBuf>,
+}
+
+impl DatabaseFiles {
+    /// Get the number of bytes the files are using on disk.
+    ///
+    /// Files that don't exist, e.g. the WAL file after a checkpoint, are
+    /// counted as empty.
+    pub fn get_size(&self) -> Result<DatabaseSize> {
+        Ok(DatabaseSize {
+            index_bytes: DatabaseFiles::sum_sizes(&self.index)?,
+            store_bytes: DatabaseFiles::sum_sizes(&self.store)?,
+        })
+    }
+
+    fn sum_sizes(files: &[PathBuf]) -> Result<u64> {
+        let mut size = 0;
+
+        for file in files {
+            match fs::metadata(file) {
+                Ok(metadata) => size += metadata.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(size)
+    }
+}
+
+/// The error that stopped the writer thread, if it stopped unexpectedly.
+type WriterStatus = Arc<Mutex<Option<String>>>;
+
+/// The sequence number of the last add, shared between the database and its
+/// event senders.
+#[derive(Clone, Default)]
+struct AddSequence {
+    /// Held while an add takes its sequence number and is queued, so the
+    /// writer receives the adds in the order of their sequence numbers.
+    lock: Arc<Mutex<()>>,
+    /// The sequence number of the last queued add, it can be read without
+    /// taking the lock.
+    last: Arc<AtomicU64>,
+}
+
+type WriterRet = (JoinHandle<()>, SyncSender<ThreadMessage>);
+
+/// A handle that can be used to add events to the database from another
+/// thread.
+///
+/// An event sender can be acquired using the `Database::get_event_sender()`
+/// method.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: SyncSender<ThreadMessage>,
+    queue_length: Arc<AtomicUsize>,
+    writer_status: WriterStatus,
+    add_sequence: AddSequence,
+    max_event_size: usize,
+}
+
+impl EventSender {
+    /// Add an event with the given profile to the database.
+    ///
+    /// This behaves like the `Database::add_event()` method, it will block if
+    /// the write queue is full until the writer catches up.
+    ///
+    /// Returns the sequence number of the add, see `Database::add_event()`.
+    /// Returns a `WriterClosedError` if the writer thread has stopped, or a
+    /// `WriterDead` error if it stopped because of a failure, in which case
+    /// the event was not added. Returns an `EventTooLarge` error if the event
+    /// exceeds the maximal event size.
+    pub fn send(&self, event: Event, profile: Profile) -> Result<u64> {
+        Database::check_event_size(&event, self.max_event_size)?;
+
+        let message = ThreadMessage::Event((event, profile), 0);
+        self.queue_length.fetch_add(1, Ordering::SeqCst);
+
+        match Database::send_add(&self.tx, &self.add_sequence, message, true) {
+            Ok(next) => Ok(next),
+            Err(_) => {
+                self.queue_length.fetch_sub(1, Ordering::SeqCst);
+                Err(Database::writer_error(&self.writer_status))
+            }
+        }
+    }
+
+    /// Add a batch of events with their profiles to the database.
+    ///
+    /// This behaves like the `Database::add_events()` method.
+    ///
+    /// Returns the sequence number of the add, the whole batch shares a
+    /// single sequence number. Returns a `WriterClosedError` if the writer
+    /// thread has stopped, or a `WriterDead` error if it stopped because of a
+    /// failure, in which case none of the events were added. Returns an
+    /// `EventTooLarge` error if any of the events exceeds the maximal event
+    /// size, none of the events were added in that case either.
+    pub fn send_batch(&self, events: Vec<(Event, Profile)>) -> Result<u64> {
+        for (event, _) in &events {
+            Database::check_event_size(event, self.max_event_size)?;
+        }
+
+        let count = events.len();
+        let message = ThreadMessage::Events(events, 0);
+        self.queue_length.fetch_add(count, Ordering::SeqCst);
+
+        match Database::send_add(&self.tx, &self.add_sequence, message, true) {
+            Ok(next) => Ok(next),
+            Err(_) => {
+                self.queue_length.fetch_sub(count, Ordering::SeqCst);
+                Err(Database::writer_error(&self.writer_status))
+            }
+        }
+    }
+
+    /// Add a reaction to the database.
+    ///
+    /// This behaves like the `Database::add_reaction()` method.
+    ///
+    /// Returns the sequence number of the add. Returns a `WriterClosedError`
+    /// if the writer thread has stopped, or a `WriterDead` error if it
+    /// stopped because of a failure, in which case the reaction was not
+    /// added.
+    pub fn send_reaction(&self, reaction: Reaction) -> Result<u64> {
+        let message = ThreadMessage::Reaction(reaction, 0);
+
+        Database::send_add(&self.tx, &self.add_sequence, message, true)
+            .map_err(|_| Database::writer_error(&self.writer_status))
+    }
+
+    /// Add the canonical alias of a room to the database.
+    ///
+    /// This behaves like the `Database::add_room_alias()` method.
+    ///
+    /// Returns the sequence number of the add, or the same errors as
+    /// `send_reaction()`.
+    pub fn send_room_alias(&self, alias: RoomAlias) -> Result<u64> {
+        let message = ThreadMessage::RoomAlias(alias, 0);
+
+        Database::send_add(&self.tx, &self.add_sequence, message, true)
+            .map_err(|_| Database::writer_error(&self.writer_status))
+    }
+}
+
+/// A handle to import a large amount of events from the room history in
+/// chunks.
+///
+/// Every chunk is written to the database as soon as it arrives, the crawler
+/// checkpoints are only replaced once the import is finished. If the import
+/// is abandoned without calling `finish()` the old checkpoint stays in the
+/// database and the import can be restarted from it.
+///
+/// A bulk import can be started using the `Database::start_bulk_import()`
+/// method.
+pub struct BulkImport {
+    tx: SyncSender<ThreadMessage>,
+    purge_epoch: u64,
 }
 
-type WriterRet = (JoinHandle<()>, Sender<ThreadMessage>);
+impl BulkImport {
+    fn send(
+        &self,
+        events: Vec<(Event, Profile)>,
+        new_checkpoint: Option<CrawlerCheckpoint>,
+        old_checkpoint: Option<CrawlerCheckpoint>,
+    ) -> Receiver<Result<bool>> {
+        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
+
+        let (new_checkpoint, old_checkpoint) =
+            match Database::canonicalize_checkpoints(new_checkpoint, old_checkpoint) {
+                Ok(c) => c,
+                Err(e) => {
+                    sender.send(Err(e)).unwrap_or(());
+                    return receiver;
+                }
+            };
+
+        let payload = (
+            new_checkpoint,
+            old_checkpoint,
+            events,
+            self.purge_epoch,
+            sender,
+        );
+        // The receiver notices if the writer thread is gone since the sender
+        // gets dropped with the message.
+        self.tx
+            .send(ThreadMessage::HistoricEvents(payload))
+            .unwrap_or(());
+
+        receiver
+    }
+
+    /// Add a chunk of events to the database.
+    ///
+    /// Returns a receiver that will receive false if all the events of the
+    /// chunk were already in the database, true otherwise.
+    pub fn add_chunk(&self, events: Vec<(Event, Profile)>) -> Receiver<Result<bool>> {
+        self.send(events, None, None)
+    }
+
+    /// Finish the import, replacing the old checkpoint with the new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_checkpoint` - A checkpoint that states where we need to continue
+    ///   fetching events from the room history.
+    /// * `old_checkpoint` - The checkpoint that was used to fetch the imported
+    ///   events.
+    pub fn finish(
+        self,
+        new_checkpoint: Option<CrawlerCheckpoint>,
+        old_checkpoint: Option<CrawlerCheckpoint>,
+    ) -> Receiver<Result<bool>> {
+        self.send(Vec::new(), new_checkpoint, old_checkpoint)
+    }
+}
 
 impl Database {
     /// Create a new Seshat database or open an existing one.
@@ -102,17 +443,23 @@ impl Database {
     /// * `path` - The directory where the database will be stored in. This
     ///   should be an empty directory if a new database should be created.
     /// * `config` - Configuration that changes the behaviour of the database.
+    ///
+    /// The index and the SQLite store are put into separate directories if
+    /// the config sets an index or a store path. Returns an `IndexNotFound`
+    /// error if the store contains events but the index is missing.
     pub fn new_with_config<P: AsRef<Path>>(path: P, config: &Config) -> Result<Database>
     where
         PathBuf: std::convert::From<P>,
     {
-        let db_path = path.as_ref().join(EVENTS_DB_NAME);
+        let (index_path, store_path) = Database::create_locations(path.as_ref(), config)?;
+
+        let db_path = store_path.join(EVENTS_DB_NAME);
         let pool = Self::get_pool(&db_path, config)?;
 
         let mut connection = pool.get()?;
 
         Database::unlock(&connection, config)?;
-        Database::set_pragmas(&connection)?;
+        Database::set_pragmas(&connection, config)?;
 
         let (version, reindex_needed) = match Database::get_version(&mut connection) {
             Ok(ret) => ret,
@@ -129,8 +476,15 @@ impl Database {
             return Err(Error::ReindexError);
         }
 
-        let index = Database::create_index(&path, config)?;
-        let writer = index.get_writer()?;
+        Database::check_store_sources(&connection, config.store_sources)?;
+        Database::check_index_path(&connection, &index_path)?;
+        let purge_epoch = Database::load_purge_epoch(&connection)?;
+        let source_cipher = Database::open_source_cipher(&connection, config)?;
+
+        let index = Database::create_index(&index_path, config)?;
+        let store_id = Database::check_consistency(&connection, &index)?;
+        let mut writer = Database::get_index_writer(&index_path, &index, config)?;
+        writer.set_commit_payload(&store_id);
 
         // Warning: Do not open a new db connection before we write the tables
         // to the DB, otherwise sqlcipher might think that we are initializing
@@ -138,24 +492,72 @@ impl Database {
         // keys and writes/reads to one of the connections might fail.
         let writer_connection = pool.get()?;
         Database::unlock(&writer_connection, config)?;
-        Database::set_pragmas(&writer_connection)?;
-
-        let (t_handle, tx) = Database::spawn_writer(writer_connection, writer);
+        Database::set_pragmas(&writer_connection, config)?;
+
+        let queue_length = Arc::new(AtomicUsize::new(0));
+        let writer_status = Arc::new(Mutex::new(None));
+        let source_cache = SourceCache::new_shared(config.source_cache_capacity);
+        let metrics = Metrics::new_shared();
+        let writer_errors = WriterErrors::default();
+        let committed_sequence = Arc::new(AtomicU64::new(0));
+        let (t_handle, tx) = Database::spawn_writer(
+            writer_connection,
+            writer,
+            config,
+            queue_length.clone(),
+            committed_sequence.clone(),
+            source_cipher.clone(),
+            source_cache.clone(),
+            metrics.clone(),
+            writer_errors.clone(),
+            writer_status.clone(),
+        );
 
         Ok(Database {
             path: path.into(),
+            index_path,
+            store_path,
             connection: Arc::new(Mutex::new(connection)),
             pool,
             _write_thread: t_handle,
             tx,
+            queue_length,
             index,
             config: config.clone(),
+            source_cipher,
+            source_cache,
+            metrics,
+            writer_errors,
+            writer_status,
+            add_sequence: AddSequence::default(),
+            committed_sequence,
+            purge_epoch: Arc::new(AtomicU64::new(purge_epoch)),
         })
     }
 
-    fn get_pool(db_path: &PathBuf, config: &Config) -> Result<Pool<SqliteConnectionManager>> {
+    /// Get the directories of the index and of the SQLite store of a database
+    /// in the given directory.
+    ///
+    /// Directories that the config sets explicitly are created if they don't
+    /// exist.
+    pub(crate) fn create_locations(path: &Path, config: &Config) -> Result<(PathBuf, PathBuf)> {
+        for location in config.index_path.iter().chain(config.store_path.iter()) {
+            fs::create_dir_all(location)?;
+        }
+
+        Ok((config.index_path(path), config.store_path(path)))
+    }
+
+    fn build_pool(db_path: &PathBuf, config: &Config) -> Result<Pool<SqliteConnectionManager>> {
         let manager = SqliteConnectionManager::file(db_path);
-        let pool = r2d2::Pool::new(manager)?;
+        Ok(r2d2::Pool::builder()
+            .max_size(config.connection_pool_size)
+            .connection_timeout(config.connection_timeout)
+            .build(manager)?)
+    }
+
+    fn get_pool(db_path: &PathBuf, config: &Config) -> Result<Pool<SqliteConnectionManager>> {
+        let pool = Database::build_pool(db_path, config)?;
         let connection = pool.get()?;
 
         // Try to unlock a single connection.
@@ -185,10 +587,7 @@ impl Database {
                 if result == "0" {
                     // In this case the migration was successful and we can now recreate the pool
                     // so the new settings come into play.
-                    let manager = SqliteConnectionManager::file(db_path);
-                    let pool = r2d2::Pool::new(manager)?;
-
-                    Ok(pool)
+                    Database::build_pool(db_path, config)
                 } else {
                     Err(Error::DatabaseUnlockError("Invalid passphrase".to_owned()))
                 }
@@ -196,11 +595,21 @@ impl Database {
         }
     }
 
-    fn set_pragmas(connection: &rusqlite::Connection) -> Result<()> {
+    fn set_pragmas(connection: &rusqlite::Connection, config: &Config) -> Result<()> {
+        connection.busy_timeout(config.busy_timeout)?;
         connection.pragma_update(None, "foreign_keys", &1 as &dyn ToSql)?;
-        connection.pragma_update(None, "journal_mode", "WAL")?;
-        connection.pragma_update(None, "synchronous", "NORMAL")?;
-        connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        // This only takes effect for new databases, existing ones are switched
+        // over the first time they are compacted.
+        connection.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+
+        if config.wal {
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        } else {
+            connection.pragma_update(None, "journal_mode", "DELETE")?;
+        }
+
         Ok(())
     }
 
@@ -216,22 +625,30 @@ impl Database {
     ///   should be an empty directory if a new database should be created.
     /// * `new_passphrase` - The passphrase that should be used instead of the
     ///   current one.
+    ///
+    /// Returns a `NotEncryptedError` if the database isn't encrypted. The
+    /// database is shut down even if changing the passphrase fails.
     #[cfg(feature = "encryption")]
     pub fn change_passphrase(self, new_passphrase: &str) -> Result<()> {
-        match &self.config.passphrase {
-            Some(p) => {
-                Index::change_passphrase(&self.path, p, new_passphrase)?;
-                self.connection.lock().unwrap().pragma_update(
-                    None,
-                    "rekey",
-                    &new_passphrase as &dyn ToSql,
-                )?;
-            }
-            None => panic!("Database isn't encrypted"),
-        }
+        let ret = match &self.config.passphrase {
+            Some(p) => self.rekey(p, new_passphrase),
+            None => Err(Error::NotEncryptedError),
+        };
 
         let receiver = self.shutdown();
-        receiver.recv().unwrap()?;
+        receiver.recv().unwrap_or(Ok(()))?;
+
+        ret
+    }
+
+    #[cfg(feature = "encryption")]
+    fn rekey(&self, passphrase: &str, new_passphrase: &str) -> Result<()> {
+        Index::change_passphrase(&self.index_path, passphrase, new_passphrase)?;
+        self.connection.lock().unwrap().pragma_update(
+            None,
+            "rekey",
+            &new_passphrase as &dyn ToSql,
+        )?;
 
         Ok(())
     }
@@ -270,9 +687,51 @@ impl Database {
     }
 
     /// Get the size of the database.
-    /// This returns the number of bytes the database is using on disk.
-    pub fn get_size(&self) -> Result<u64> {
-        Ok(dir::get_size(self.get_path())?)
+    ///
+    /// This returns the number of bytes the search index and the event store
+    /// are using on disk. Only the files of the live index are counted,
+    /// leftovers of merged segments that weren't garbage collected yet are
+    /// ignored.
+    pub fn get_size(&self) -> Result<DatabaseSize> {
+        self.get_files()?.get_size()
+    }
+
+    /// Get the files that make up the database on disk.
+    ///
+    /// See `get_size()`, this can be used to measure the size of the database
+    /// without holding on to it.
+    pub fn get_files(&self) -> Result<DatabaseFiles> {
+        let index = self
+            .index
+            .live_files()?
+            .into_iter()
+            .map(|file| self.index_path.join(file))
+            .collect();
+
+        let store = ["", "-wal", "-shm", "-journal"]
+            .iter()
+            .map(|suffix| {
+                self.store_path
+                    .join(format!("{}{}", EVENTS_DB_NAME, suffix))
+            })
+            .collect();
+
+        Ok(DatabaseFiles { index, store })
+    }
+
+    /// Get version information about the library and the opened database.
+    pub fn get_version_info(&self) -> Result<VersionInfo> {
+        let database_version: i64 = self.connection.lock().unwrap().query_row(
+            "SELECT version FROM version",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(VersionInfo {
+            crate_version: crate::VERSION.to_owned(),
+            database_version,
+            index_version: INDEX_VERSION,
+        })
     }
 
     /// Get the path of the directory where the Seshat database lives in.
@@ -280,59 +739,247 @@ impl Database {
         self.path.as_path()
     }
 
+    /// Get the path of the directory where the search index lives in.
+    pub fn get_index_path(&self) -> &Path {
+        self.index_path.as_path()
+    }
+
+    /// Get the path of the directory where the SQLite store lives in.
+    pub fn get_store_path(&self) -> &Path {
+        self.store_path.as_path()
+    }
+
+    /// Get the number of bytes the given directories are using on disk.
+    pub(crate) fn get_locations_size(locations: &[PathBuf]) -> Result<u64> {
+        let mut size = 0;
+
+        for location in locations {
+            size += dir::get_size(location)?;
+        }
+
+        Ok(size)
+    }
+
+    /// The distinct directories that a database is using, index or store
+    /// directories inside of the database directory aren't listed
+    /// separately.
+    pub(crate) fn distinct_locations(
+        path: &Path,
+        index_path: &Path,
+        store_path: &Path,
+    ) -> Vec<PathBuf> {
+        let mut locations = vec![path.to_owned()];
+
+        for location in [index_path, store_path] {
+            if !locations.iter().any(|l| location.starts_with(l)) {
+                locations.push(location.to_owned());
+            }
+        }
+
+        locations
+    }
+
+    /// Get the directories the database is using.
+    ///
+    /// This is the directory of the database, followed by the directories of
+    /// the index and of the store if the config put them elsewhere.
+    pub fn get_locations(&self) -> Vec<PathBuf> {
+        Database::distinct_locations(&self.path, &self.index_path, &self.store_path)
+    }
+
     fn create_index<P: AsRef<Path>>(path: &P, config: &Config) -> Result<Index> {
-        Ok(Index::new(path, config)?)
+        // The fields of the index depend on the language detection and the
+        // identifier splitting settings, an index that was created with
+        // different ones needs to be rebuilt.
+        match Index::new(path, config) {
+            Err(TantivyError::SchemaError(_)) => Err(Error::ReindexError),
+            ret => Ok(ret?),
+        }
+    }
+
+    /// Get a writer for the index.
+    ///
+    /// Only a single writer may exist for an index, if another process holds
+    /// the index lock we retry until the lock timeout of the config runs out
+    /// and return a `DatabaseLocked` error afterwards.
+    pub(crate) fn get_index_writer<P: AsRef<Path>>(
+        path: &P,
+        index: &Index,
+        config: &Config,
+    ) -> Result<IndexWriter> {
+        if config.force_unlock {
+            match fs::remove_file(path.as_ref().join(&INDEX_WRITER_LOCK.filepath)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+
+        let start = Instant::now();
+
+        loop {
+            match index.get_writer().map_err(Error::from) {
+                Err(Error::DatabaseLocked) if start.elapsed() < config.lock_timeout => {
+                    thread::sleep(LOCK_RETRY_SLEEP)
+                }
+                ret => return ret,
+            }
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn spawn_writer(
         connection: PooledConnection<SqliteConnectionManager>,
         index_writer: IndexWriter,
+        config: &Config,
+        queue_length: Arc<AtomicUsize>,
+        committed_sequence: Arc<AtomicU64>,
+        source_cipher: Option<Arc<SourceCipher>>,
+        source_cache: SharedSourceCache,
+        metrics: SharedMetrics,
+        writer_errors: WriterErrors,
+        writer_status: WriterStatus,
     ) -> WriterRet {
-        let (tx, rx): (_, Receiver<ThreadMessage>) = channel();
+        let (tx, rx): (_, Receiver<ThreadMessage>) = sync_channel(config.write_queue_capacity);
+        let config = config.clone();
 
         let t_handle = thread::spawn(move || {
-            let mut writer = Writer::new(connection, index_writer);
-            let mut loaded_unprocessed = false;
-
-            while let Ok(message) = rx.recv() {
-                match message {
-                    ThreadMessage::Event((event, profile)) => writer.add_event(event, profile),
-                    ThreadMessage::Write(sender, force_commit) => {
-                        // We may have events that aren't deleted or committed
-                        // to the index but are stored in the db, let us load
-                        // them from the db and commit them to the index now.
-                        // They will later be marked as committed in the
-                        // database as part of a normal write.
-                        if !loaded_unprocessed {
-                            let ret = writer.load_unprocessed_events();
-
-                            loaded_unprocessed = true;
-
-                            if ret.is_err() {
-                                sender.send(ret).unwrap_or(());
+            // The receiver is dropped, and with it the pending messages, only
+            // once the status is updated, so callers waiting for a response
+            // see why the writer stopped.
+            let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut writer = Writer::new(
+                    connection,
+                    index_writer,
+                    queue_length,
+                    committed_sequence,
+                    &config,
+                    source_cipher,
+                    source_cache,
+                    metrics,
+                    writer_errors,
+                );
+                writer.resume();
+                writer.run_timers();
+
+                loop {
+                    if let Some(e) = writer.fatal_error() {
+                        return Err(e);
+                    }
+
+                    let message = match writer.timeout() {
+                        Some(timeout) => match rx.recv_timeout(timeout) {
+                            Ok(m) => m,
+                            Err(RecvTimeoutError::Timeout) => {
+                                writer.run_timers();
+                                writer.record_index_commit();
                                 continue;
                             }
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        },
+                        None => match rx.recv() {
+                            Ok(m) => m,
+                            Err(_) => break,
+                        },
+                    };
+
+                    match message {
+                        ThreadMessage::Event((event, profile), sequence) => {
+                            writer.add_event(event, profile, sequence)
                         }
-                        let ret = writer.write_queued_events(force_commit);
-                        // Notify that we are done with the write.
-                        sender.send(ret).unwrap_or(());
-                    }
-                    ThreadMessage::HistoricEvents(m) => {
-                        let (check, old_check, events, sender) = m;
-                        let ret = writer.write_historic_events(check, old_check, events, true);
-                        sender.send(ret).unwrap_or(());
-                    }
-                    ThreadMessage::Delete(sender, event_id) => {
-                        let ret = writer.delete_event(event_id);
-                        sender.send(ret).unwrap_or(());
-                    }
-                    ThreadMessage::ShutDown(sender) => {
-                        let ret = writer.shutdown();
-                        sender.send(ret).unwrap_or(());
-                        return;
-                    }
-                };
-            }
+                        ThreadMessage::Events(events, sequence) => {
+                            writer.add_events(events, sequence)
+                        }
+                        ThreadMessage::Reaction(reaction, sequence) => {
+                            writer.add_reaction(reaction, sequence)
+                        }
+                        ThreadMessage::RoomAlias(alias, sequence) => {
+                            writer.add_room_alias(alias, sequence)
+                        }
+                        ThreadMessage::Write(sender, force_commit) => {
+                            let ret = writer.write_queued_events(force_commit);
+                            // Notify that we are done with the write.
+                            writer.reply(sender, ret, WriterOperation::Commit, None);
+                        }
+                        ThreadMessage::HistoricEvents(m) => {
+                            let (check, old_check, events, epoch, sender) = m;
+                            let room_id = Writer::historic_room_id(
+                                check.as_ref(),
+                                old_check.as_ref(),
+                                &events,
+                            );
+                            let ret =
+                                writer.write_historic_events(check, old_check, events, epoch, true);
+                            writer.reply(sender, ret, WriterOperation::HistoricEvents, room_id);
+                        }
+                        ThreadMessage::Delete(sender, event_id) => {
+                            let ret = writer.delete_event(event_id);
+                            writer.reply(sender, ret, WriterOperation::DeleteEvent, None);
+                        }
+                        ThreadMessage::DeleteOlderThan(sender, timestamp) => {
+                            let ret = writer.delete_events_older_than(timestamp);
+                            let operation = WriterOperation::DeleteEventsOlderThan;
+                            writer.reply(sender, ret, operation, None);
+                        }
+                        ThreadMessage::SetRoomIndexing(sender, room_id, enabled, purge) => {
+                            let ret = writer.set_room_indexing(room_id.clone(), enabled, purge);
+                            let operation = WriterOperation::SetRoomIndexing;
+                            writer.reply(sender, ret, operation, Some(room_id));
+                        }
+                        ThreadMessage::DeleteRoom(sender, room_id, epoch) => {
+                            let ret = writer.delete_room(room_id.clone(), epoch);
+                            writer.reply(sender, ret, WriterOperation::DeleteRoom, Some(room_id));
+                        }
+                        ThreadMessage::DeleteSender(sender, user_id, mut progress) => {
+                            let ret = writer.delete_sender(&user_id, &mut progress);
+                            writer.reply(sender, ret, WriterOperation::DeleteSender, None);
+                        }
+                        ThreadMessage::Compact(sender) => {
+                            let ret = writer.compact();
+                            writer.reply(sender, ret, WriterOperation::Compact, None);
+                        }
+                        ThreadMessage::Shrink(sender, locations) => {
+                            let ret = writer.shrink(&locations);
+                            writer.reply(sender, ret, WriterOperation::Shrink, None);
+                        }
+                        ThreadMessage::Backup(sender, index_dir, store_dir, archive_path) => {
+                            let ret = writer.backup(&index_dir, &store_dir, &archive_path);
+                            writer.reply(sender, ret, WriterOperation::Backup, None);
+                        }
+                        ThreadMessage::Repair(sender) => {
+                            let ret = writer.repair();
+                            writer.reply(sender, ret, WriterOperation::Repair, None);
+                        }
+                        ThreadMessage::WaitForRoom(sender, room_id, up_to_ts) => {
+                            writer.wait_for_room(room_id, up_to_ts, sender)
+                        }
+                        ThreadMessage::ShutDown(sender) => {
+                            let ret = writer.shutdown();
+                            sender.send(ret).unwrap_or(());
+                            return Ok(());
+                        }
+                    };
+
+                    writer.record_activity();
+                    writer.auto_commit();
+                    writer.record_index_commit();
+                }
+
+                Ok(())
+            }));
+
+            let error = match ret {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => e,
+                Err(panic) => match panic.downcast::<String>() {
+                    Ok(message) => *message,
+                    Err(panic) => panic.downcast::<&str>().map_or_else(
+                        |_| "The writer thread panicked".to_owned(),
+                        |m| (*m).to_owned(),
+                    ),
+                },
+            };
+
+            *writer_status.lock().unwrap() = Some(error);
         });
 
         (t_handle, tx)
@@ -344,834 +991,6208 @@ impl Database {
     /// * `event` - The directory where the database will be stored in. This
     /// * `profile` - The directory where the database will be stored in. This
     ///
-    /// This is a fast non-blocking operation, it only queues up the event to be
-    /// added to the database. The events will be committed to the database
-    /// only when the user calls the `commit()` method.
-    pub fn add_event(&self, event: Event, profile: Profile) {
-        let message = ThreadMessage::Event((event, profile));
-        self.tx.send(message).unwrap();
-    }
-
-    /// Delete an event from the database.
+    /// This is a fast operation, it only queues up the event to be added to
+    /// the database. The writer thread stores queued events right away so
+    /// they survive a crash, but they will be committed to the index, and
+    /// thus become searchable, only when the user calls the `commit()` method.
     ///
-    /// # Arguments
-    /// * `event_id` - The event id of the event that will be deleted.
+    /// The write queue is bounded, this will block if the queue is full until
+    /// the writer catches up. A non-blocking version of this method exists in
+    /// the `try_add_event()` method.
     ///
-    /// Note for the event to be completely removed a commit needs to be done.
+    /// Returns the sequence number of the add. Sequence numbers increase
+    /// with every add, once a commit returns a sequence number, every event
+    /// whose add returned a smaller or equal number is committed and
+    /// searchable after a reload, unless the commit returned an error.
     ///
-    /// Returns a receiver that will receive an boolean once the event has
-    /// been deleted. The boolean indicates if the event was deleted or if a
-    /// commit will be needed.
-    pub fn delete_event(&self, event_id: &str) -> Receiver<Result<bool>> {
-        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
-        let message = ThreadMessage::Delete(sender, event_id.to_owned());
-        self.tx.send(message).unwrap();
-        receiver
+    /// The event is dropped if the writer thread has stopped, `None` is
+    /// returned in that case and the `status()` method tells why. Events
+    /// that exceed the maximal event size are dropped as well.
+    pub fn add_event(&self, event: Event, profile: Profile) -> Option<u64> {
+        self.get_event_sender().send(event, profile).ok()
     }
 
-    fn commit_helper(&mut self, force: bool) -> Receiver<Result<()>> {
-        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
-        self.tx.send(ThreadMessage::Write(sender, force)).unwrap();
-        receiver
+    /// Add a batch of events with their profiles to the database.
+    ///
+    /// This behaves like the `add_event()` method, but all the events are
+    /// queued up and stored together. The whole batch shares a single
+    /// sequence number.
+    pub fn add_events(&self, events: Vec<(Event, Profile)>) -> Option<u64> {
+        self.get_event_sender().send_batch(events).ok()
     }
 
-    /// Commit the currently queued up events. This method will block. A
-    /// non-blocking version of this method exists in the `commit_no_wait()`
-    /// method.
-    pub fn commit(&mut self) -> Result<()> {
-        self.commit_helper(false).recv().unwrap()
+    /// Add a reaction to the database.
+    ///
+    /// Reactions aren't indexed, the writer thread only stores them to count
+    /// the reactions of the events they relate to. The counts can be used to
+    /// rank events that were reacted to higher, see
+    /// `SearchConfig::boost_by_reactions()`. A reaction counts as soon as it
+    /// is stored, it doesn't need to be committed.
+    ///
+    /// Returns the sequence number of the add, see `add_event()`. The
+    /// reaction is dropped if the writer thread has stopped, `None` is
+    /// returned in that case.
+    pub fn add_reaction(&self, reaction: Reaction) -> Option<u64> {
+        self.get_event_sender().send_reaction(reaction).ok()
     }
 
-    /// Commit the currently queued up events forcing the commit to the index.
-    ///
-    /// Commits are usually rate limited. This gets around the limit and forces
-    /// the documents to be added to the index.
+    /// Add the canonical alias of a room to the database.
     ///
-    /// This method will block. A non-blocking version of this method exists in
-    /// the `force_commit_no_wait()` method.
+    /// Aliases aren't indexed, they label the search results of rooms that
+    /// don't have a name, see `SearchResult::room_display_name`. Room names
+    /// are taken from the added `m.room.name` events. Only the newest alias
+    /// of a room is kept, an alias is stored as soon as it's added.
     ///
-    /// This should only be used for testing purposes.
-    pub fn force_commit(&mut self) -> Result<()> {
-        self.commit_helper(true).recv().unwrap()
+    /// Returns the sequence number of the add, see `add_event()`. The alias
+    /// is dropped if the writer thread has stopped, `None` is returned in
+    /// that case.
+    pub fn add_room_alias(&self, alias: RoomAlias) -> Option<u64> {
+        self.get_event_sender().send_room_alias(alias).ok()
     }
 
-    /// Reload the database so that a search reflects the state of the last
-    /// commit. Note that this happens automatically and this method should be
-    /// used only in unit tests.
-    pub fn reload(&mut self) -> Result<()> {
-        self.index.reload()?;
-        Ok(())
+    /// Check if the writer thread of the database is still running.
+    ///
+    /// Returns a `WriterDead` error if the writer thread stopped because it
+    /// panicked or because writes kept failing, e.g. because the disk is
+    /// full. Events that are added afterwards won't be written, the database
+    /// needs to be reopened.
+    pub fn status(&self) -> Result<()> {
+        match self.writer_status.lock().unwrap().as_ref() {
+            Some(e) => Err(Error::WriterDead(e.clone())),
+            None => Ok(()),
+        }
     }
 
-    /// Commit the currently queued up events without waiting for confirmation
-    /// that the operation is done.
+    /// Take the errors that happened in the writer thread while nobody was
+    /// waiting for the result of the operation.
     ///
-    /// Returns a receiver that will receive an empty message once the commit is
-    /// done.
-    pub fn commit_no_wait(&mut self) -> Receiver<Result<()>> {
-        self.commit_helper(false)
+    /// This includes errors of automatic commits, compactions and retention
+    /// purges and errors of operations whose receiver was dropped before the
+    /// operation finished. Errors are returned oldest first and only once,
+    /// only the most recent errors are kept.
+    pub fn take_errors(&self) -> Vec<WriterError> {
+        self.writer_errors.take()
     }
 
-    /// Commit the currently queued up events forcing the commit to the index.
-    ///
-    /// Commits are usually rate limited. This gets around the limit and forces
-    /// the documents to be added to the index.
+    /// Get the error that should be returned if the writer thread can't be
+    /// reached anymore.
+    fn writer_error(writer_status: &Mutex<Option<String>>) -> Error {
+        match writer_status.lock().unwrap().as_ref() {
+            Some(e) => Error::WriterDead(e.clone()),
+            None => Error::WriterClosedError,
+        }
+    }
+
+    /// Try to add an event with the given profile to the database.
     ///
-    /// This should only be used for testing purposes.
+    /// This is the non-blocking version of the `add_event()` method.
     ///
-    /// Returns a receiver that will receive an empty message once the commit is
-    /// done.
-    pub fn force_commit_no_wait(&mut self) -> Receiver<Result<()>> {
-        self.commit_helper(true)
+    /// Returns the sequence number of the add, see `add_event()`. Returns a
+    /// `QueueFull` error if the write queue is full, in which case
+    /// the event was not added. Callers should wait for the writer to catch
+    /// up, or commit, before adding more events. Returns an `EventTooLarge`
+    /// error if the event exceeds the maximal event size.
+    pub fn try_add_event(&self, event: Event, profile: Profile) -> Result<u64> {
+        Database::check_event_size(&event, self.config.size_limits.max_event_size)?;
+
+        let message = ThreadMessage::Event((event, profile), 0);
+        self.queue_length.fetch_add(1, Ordering::SeqCst);
+
+        match Database::send_add(&self.tx, &self.add_sequence, message, false) {
+            Ok(next) => Ok(next),
+            Err(e) => {
+                self.queue_length.fetch_sub(1, Ordering::SeqCst);
+                Err(self.try_send_error(e))
+            }
+        }
     }
 
-    /// Add the given events from the room history to the database.
-    /// # Arguments
+    /// Try to add a reaction to the database.
     ///
-    /// * `events` - The events that will be added.
-    /// * `new_checkpoint` - A checkpoint that states where we need to continue
-    ///   fetching events from the room history. This checkpoint will be
-    ///   persisted in the database.
-    /// * `old_checkpoint` - The checkpoint that was used to fetch the given
-    ///   events. This checkpoint will be removed from the database.
-    pub fn add_historic_events(
-        &self,
-        events: Vec<(Event, Profile)>,
-        new_checkpoint: Option<CrawlerCheckpoint>,
-        old_checkpoint: Option<CrawlerCheckpoint>,
-    ) -> Receiver<Result<bool>> {
-        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
-        let payload = (new_checkpoint, old_checkpoint, events, sender);
-        let message = ThreadMessage::HistoricEvents(payload);
-        self.tx.send(message).unwrap();
+    /// This is the non-blocking version of the `add_reaction()` method.
+    ///
+    /// Returns the sequence number of the add, or a `QueueFull` error if the
+    /// write queue is full, in which case the reaction was not added.
+    pub fn try_add_reaction(&self, reaction: Reaction) -> Result<u64> {
+        let message = ThreadMessage::Reaction(reaction, 0);
 
-        receiver
+        Database::send_add(&self.tx, &self.add_sequence, message, false)
+            .map_err(|e| self.try_send_error(e))
     }
 
-    /// Search the index and return events matching a search term.
-    /// This is just a helper function that gets a searcher and performs a
-    /// search on it immediately.
-    /// # Arguments
+    /// Try to add the canonical alias of a room to the database.
     ///
-    /// * `term` - The search term that should be used to search the index.
-    pub fn search(&self, term: &str, config: &SearchConfig) -> Result<SearchBatch> {
-        let searcher = self.get_searcher();
-        searcher.search(term, config)
+    /// This is the non-blocking version of the `add_room_alias()` method.
+    ///
+    /// Returns the sequence number of the add, or a `QueueFull` error if the
+    /// write queue is full, in which case the alias was not added.
+    pub fn try_add_room_alias(&self, alias: RoomAlias) -> Result<u64> {
+        let message = ThreadMessage::RoomAlias(alias, 0);
+
+        Database::send_add(&self.tx, &self.add_sequence, message, false)
+            .map_err(|e| self.try_send_error(e))
     }
 
-    /// Get a searcher that can be used to perform a search.
-    pub fn get_searcher(&self) -> Searcher {
-        let index_searcher = self.index.get_searcher();
-        Searcher {
-            inner: index_searcher,
-            database: self.connection.clone(),
+    /// Get the error that a non-blocking add returns if its message couldn't
+    /// be queued.
+    fn try_send_error(&self, error: TrySendError<ThreadMessage>) -> Error {
+        match error {
+            TrySendError::Full(_) => Error::QueueFull,
+            TrySendError::Disconnected(_) => Database::writer_error(&self.writer_status),
         }
     }
 
-    /// Get a database connection.
-    /// Note that this connection should only be used for reading.
+    /// Get an event sender that can be used to add events to the database
+    /// from another thread.
+    pub fn get_event_sender(&self) -> EventSender {
+        EventSender {
+            tx: self.tx.clone(),
+            queue_length: self.queue_length.clone(),
+            writer_status: self.writer_status.clone(),
+            add_sequence: self.add_sequence.clone(),
+            max_event_size: self.config.size_limits.max_event_size,
+        }
+    }
+
+    /// Hand an add to the writer thread under the next sequence number.
+    ///
+    /// The sequence number is taken and the add is queued while the lock of
+    /// the sequence numbers is held, so the writer receives the adds in the
+    /// order of their sequence numbers. The lock isn't held while waiting for
+    /// a full write queue, other adds, e.g. `try_add_event()`, aren't blocked
+    /// by it.
+    ///
+    /// Returns the sequence number of the add. If the queue is full a
+    /// blocking add waits and retries, otherwise the `Full` error is
+    /// returned.
+    fn send_add(
+        tx: &SyncSender<ThreadMessage>,
+        add_sequence: &AddSequence,
+        mut message: ThreadMessage,
+        block: bool,
+    ) -> std::result::Result<u64, TrySendError<ThreadMessage>> {
+        loop {
+            {
+                let _guard = add_sequence.lock.lock().unwrap();
+                let next = Database::next_sequence(add_sequence.last.load(Ordering::SeqCst));
+
+                match tx.try_send(message.with_sequence(next)) {
+                    Ok(()) => {
+                        add_sequence.last.store(next, Ordering::SeqCst);
+                        return Ok(next);
+                    }
+                    Err(TrySendError::Full(m)) if block => message = m,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            thread::sleep(QUEUE_FULL_SLEEP);
+        }
+    }
+
+    /// Get the sequence number that follows the given one.
+    ///
+    /// Sequence numbers saturate instead of wrapping around, a wrapped
+    /// number would make every later add look committed.
+    fn next_sequence(sequence: u64) -> u64 {
+        sequence.saturating_add(1)
+    }
+
+    /// Check that an event doesn't exceed the maximal event size.
+    fn check_event_size(event: &Event, max_event_size: usize) -> Result<()> {
+        let size = event.size();
+
+        if size > max_event_size {
+            Err(Error::EventTooLarge(event.event_id.clone(), size))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Validate the checkpoints of a batch of historic events before they are
+    /// handed to the writer.
+    fn canonicalize_checkpoints(
+        new_checkpoint: Option<CrawlerCheckpoint>,
+        old_checkpoint: Option<CrawlerCheckpoint>,
+    ) -> Result<(Option<CrawlerCheckpoint>, Option<CrawlerCheckpoint>)> {
+        Ok((
+            new_checkpoint.map(|c| c.canonicalize()).transpose()?,
+            old_checkpoint.map(|c| c.canonicalize()).transpose()?,
+        ))
+    }
+
+    /// Get the number of events that are queued up but aren't yet written to
+    /// the database.
+    pub fn queue_length(&self) -> usize {
+        self.queue_length.load(Ordering::SeqCst)
+    }
+
+    /// Get the sequence number of the last add of events to the write queue.
+    ///
+    /// This is the number the last call to `add_event()`, `add_events()` or
+    /// to one of their variants returned, or 0 if nothing was added since the
+    /// database was opened. The sequence numbers start again at 0 every time
+    /// the database is opened.
+    pub fn last_queued_opstamp(&self) -> u64 {
+        self.add_sequence.last.load(Ordering::SeqCst)
+    }
+
+    /// Get the sequence number of the last add whose events were committed.
+    ///
+    /// The events of every add with a sequence number smaller or equal to
+    /// this one were written to the database and to the index, they are found
+    /// by searches once the database is reloaded. This covers the commits
+    /// that happen automatically as well as the ones that `commit()` requests.
+    /// Callers that keep their own queue of events can drop the events up to
+    /// this sequence number, after a `force_commit()` if they need them to be
+    /// synced to disk.
+    pub fn last_committed_opstamp(&self) -> u64 {
+        self.committed_sequence.load(Ordering::SeqCst)
+    }
+
+    /// Delete an event from the database.
+    ///
+    /// # Arguments
+    /// * `event_id` - The event id of the event that will be deleted.
+    ///
+    /// Note for the event to be completely removed a commit needs to be done.
+    ///
+    /// Events can be deleted before they are added to the database, e.g. if
+    /// a redaction arrives before the crawler fetched the original event. The
+    /// deletion is then remembered and the event is dropped once it's added.
+    ///
+    /// Returns a receiver that will receive an boolean once the event has
+    /// been deleted. The boolean indicates if the event was deleted right
+    /// away or if the deletion was deferred until the event gets added. The
+    /// channel will be disconnected without a message if the writer thread
+    /// has stopped, see `status()`.
+    pub fn delete_event(&self, event_id: &str) -> Receiver<Result<bool>> {
+        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
+        let message = ThreadMessage::Delete(sender, event_id.to_owned());
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Delete all the events that were sent before the given timestamp.
+    ///
+    /// The events are removed from the database and from the index right
+    /// away, the index needs to be reloaded for the deletions to be reflected
+    /// in search results. Crawler checkpoints aren't affected.
+    ///
+    /// # Arguments
+    /// * `timestamp` - The timestamp, in milliseconds since the Unix epoch,
+    ///   before which events should be deleted.
+    ///
+    /// Returns a receiver that will receive the number of deleted events.
+    pub fn delete_events_older_than(&self, timestamp: i64) -> Receiver<Result<usize>> {
+        let (sender, receiver): (_, Receiver<Result<usize>>) = channel();
+        let message = ThreadMessage::DeleteOlderThan(sender, timestamp);
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Enable or disable the indexing of a room.
+    ///
+    /// Events of rooms that have their indexing disabled are dropped when
+    /// they are added to the database, the setting is persisted in the
+    /// database.
+    ///
+    /// # Arguments
+    /// * `room_id` - The id of the room.
+    /// * `enabled` - Should the events of the room be indexed.
+    /// * `purge` - Should the events of the room that are already part of the
+    ///   database be deleted if the indexing is disabled. The index needs to
+    ///   be reloaded for the deletions to be reflected in search results.
+    ///
+    /// Returns a receiver that will receive the number of deleted events.
+    pub fn set_room_indexing(
+        &self,
+        room_id: &str,
+        enabled: bool,
+        purge: bool,
+    ) -> Receiver<Result<usize>> {
+        let (sender, receiver): (_, Receiver<Result<usize>>) = channel();
+        let purge = if purge && !enabled {
+            Some(self.next_purge_epoch())
+        } else {
+            None
+        };
+        let message = ThreadMessage::SetRoomIndexing(sender, room_id.to_owned(), enabled, purge);
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Delete all the events and the crawler checkpoints of a room.
+    ///
+    /// Unlike disabling the indexing of a room, new events of the room are
+    /// still added. Batches of events from the room history that were queued
+    /// before the room was deleted, e.g. by a bulk import that was started
+    /// earlier, are dropped though, so the room isn't resurrected. This isn't
+    /// an error, a batch that only contained events of the room reports that
+    /// no new events were added.
+    ///
+    /// The index needs to be reloaded for the deletions to be reflected in
+    /// search results.
+    ///
+    /// # Arguments
+    /// * `room_id` - The id of the room.
+    ///
+    /// Returns a receiver that will receive the number of deleted events.
+    pub fn delete_room(&self, room_id: &str) -> Receiver<Result<usize>> {
+        let (sender, receiver): (_, Receiver<Result<usize>>) = channel();
+        let message =
+            ThreadMessage::DeleteRoom(sender, room_id.to_owned(), self.next_purge_epoch());
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Delete all the events of a sender, e.g. if the sender asked for their
+    /// personal data to be erased.
+    ///
+    /// The events are removed from the database and from the index in
+    /// batches, together with the stored profiles of the sender. Room names
+    /// the sender set are removed, the latest topic of a room falls back to
+    /// the newest one that is left. No redaction stubs are left behind,
+    /// context windows and profile lookups won't show anything from the
+    /// sender either. Events of the sender that are added after the deletion
+    /// are kept.
+    ///
+    /// The index needs to be reloaded for the deletions to be reflected in
+    /// search results.
+    ///
+    /// # Arguments
+    /// * `sender` - The user id of the sender.
+    ///
+    /// Returns a receiver that will receive the number of deleted events.
+    pub fn delete_sender(&self, sender: &str) -> Receiver<Result<usize>> {
+        self.delete_sender_with_progress(sender, |_| ())
+    }
+
+    /// Delete all the events of a sender and report the progress of the
+    /// deletion.
+    ///
+    /// This behaves like the `delete_sender()` method, the `progress`
+    /// function is called on the writer thread with the number of deleted
+    /// events after every batch.
+    pub fn delete_sender_with_progress(
+        &self,
+        sender: &str,
+        progress: impl FnMut(usize) + Send + 'static,
+    ) -> Receiver<Result<usize>> {
+        let (result_sender, receiver): (_, Receiver<Result<usize>>) = channel();
+        let message =
+            ThreadMessage::DeleteSender(result_sender, sender.to_owned(), Box::new(progress));
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Get the epoch for a new room purge, batches of events that were
+    /// queued with an older epoch are dropped if their room was purged.
+    fn next_purge_epoch(&self) -> u64 {
+        self.purge_epoch.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Compact the database if enough events were deleted since the last
+    /// compaction.
+    ///
+    /// Deleted events keep taking up space in the index until the segments
+    /// that contain them are merged, and in the Sqlite database until it's
+    /// vacuumed. Compacting the database commits the queued up events, merges
+    /// the affected segments of the index, removes unused index files and
+    /// sender profiles and releases the free pages of the database. This may
+    /// take a while for large databases, the number of deletions since the
+    /// last compaction is part of the database statistics.
+    ///
+    /// Returns a receiver that will receive true if the database was
+    /// compacted or false if the compaction threshold of the `Config` wasn't
+    /// reached.
+    pub fn compact(&self) -> Receiver<Result<bool>> {
+        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
+        self.tx.send(ThreadMessage::Compact(sender)).unwrap_or(());
+        receiver
+    }
+
+    /// Compact the database right away and release the free disk space.
+    ///
+    /// This behaves like `compact()` but ignores the compaction threshold,
+    /// it's meant to be used after a big purge, e.g. after the retention
+    /// period removed many events or the events of a room were purged. The
+    /// compaction happens on the writer thread, after the queued up events
+    /// are committed, searches can continue meanwhile. Vacuuming a large
+    /// database may take a while.
+    ///
+    /// Returns a receiver that will receive the number of bytes the
+    /// database and the index shrank by.
+    pub fn shrink(&self) -> Receiver<Result<u64>> {
+        let (sender, receiver): (_, Receiver<Result<u64>>) = channel();
+        let message = ThreadMessage::Shrink(sender, self.get_locations());
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Get the result of the comparison of the SQLite store with the index.
+    ///
+    /// The store and the index are compared when the database is opened, a
+    /// mismatch is reported until the database is repaired using the
+    /// `repair()` method, e.g. after asking the user to rebuild the index.
+    pub fn get_consistency(&self) -> Result<ConsistencyState> {
+        Ok(Database::load_consistency(
+            &self.connection.lock().unwrap(),
+        )?)
+    }
+
+    /// Repair a database whose store and index disagree.
+    ///
+    /// The events of the store are added to the index again if the index is
+    /// behind. Events that the store doesn't contain are deleted from the
+    /// index if the store is behind. Both happen if the index was written
+    /// for a different store. Reindexing needs the sources of the events,
+    /// a `SourcesNotStored` error is returned if the database doesn't store
+    /// them.
+    ///
+    /// The repair happens on the writer thread, after the queued up events
+    /// are committed. Repairing a large database may take a while.
+    ///
+    /// Returns a receiver that will receive true if the database needed a
+    /// repair, false if the store and the index were consistent.
+    pub fn repair(&self) -> Receiver<Result<bool>> {
+        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
+        self.tx.send(ThreadMessage::Repair(sender)).unwrap_or(());
+        receiver
+    }
+
+    fn commit_helper(&mut self, force: bool) -> Receiver<Result<u64>> {
+        let (sender, receiver): (_, Receiver<Result<u64>>) = channel();
+        // If the writer thread is gone the message, and with it the sender,
+        // gets dropped. The receiver will then notice that the channel is
+        // disconnected, so there's no need to handle the error here.
+        self.tx
+            .send(ThreadMessage::Write(sender, force))
+            .unwrap_or(());
+        receiver
+    }
+
+    /// Commit the currently queued up events. This method will block. A
+    /// non-blocking version of this method exists in the `commit_no_wait()`
+    /// method.
+    ///
+    /// Returns the sequence number of the last add whose events were
+    /// committed, see `add_event()`. Commits are rate limited, if no commit
+    /// to the index happened the sequence number of the previous commit is
+    /// returned.
+    ///
+    /// Returns an error if the events couldn't be written to the database or
+    /// to the index, e.g. if the disk is full.
+    pub fn commit(&mut self) -> Result<u64> {
+        self.commit_helper(false)
+            .recv()
+            .unwrap_or_else(|_| Err(Database::writer_error(&self.writer_status)))
+    }
+
+    /// Commit the currently queued up events forcing the commit to the index.
+    ///
+    /// Commits are usually rate limited. This gets around the limit and forces
+    /// the documents to be added to the index. Once the method returns both
+    /// the index and the Sqlite database are synced to disk, this is useful
+    /// before the system suspends or the application quits.
+    ///
+    /// Events that are added while the commit is in progress are part of the
+    /// next commit.
+    ///
+    /// This method will block. A non-blocking version of this method exists in
+    /// the `force_commit_no_wait()` method.
+    ///
+    /// Returns the sequence number of the last add whose events were
+    /// committed, see `add_event()`.
+    pub fn force_commit(&mut self) -> Result<u64> {
+        self.commit_helper(true)
+            .recv()
+            .unwrap_or_else(|_| Err(Database::writer_error(&self.writer_status)))
+    }
+
+    /// Reload the database so that a search reflects the state of the last
+    /// commit. Note that this happens automatically after every commit unless
+    /// automatic reloads are disabled in the `Config`.
+    ///
+    /// This method may block, a `Reloader` that can be used to reload the
+    /// database on another thread can be acquired using the `get_reloader()`
+    /// method.
+    pub fn reload(&mut self) -> Result<()> {
+        self.index.reload()?;
+        Ok(())
+    }
+
+    /// Get a reloader that can be used to reload the database from another
+    /// thread.
+    pub fn get_reloader(&self) -> Reloader {
+        self.index.get_reloader()
+    }
+
+    /// Commit the currently queued up events without waiting for confirmation
+    /// that the operation is done.
+    ///
+    /// Returns a receiver that will receive the sequence number of the last
+    /// committed add once the commit is done, or the error that prevented the
+    /// commit. The channel
+    /// will be disconnected without a message if the writer thread has stopped.
+    pub fn commit_no_wait(&mut self) -> Receiver<Result<u64>> {
+        self.commit_helper(false)
+    }
+
+    /// Commit the currently queued up events forcing the commit to the index.
+    ///
+    /// Commits are usually rate limited. This gets around the limit and forces
+    /// the documents to be added to the index. The index and the Sqlite
+    /// database are synced to disk before the receiver gets notified.
+    ///
+    /// Returns a receiver that will receive the sequence number of the last
+    /// committed add once the commit is done.
+    pub fn force_commit_no_wait(&mut self) -> Receiver<Result<u64>> {
+        self.commit_helper(true)
+    }
+
+    /// Wait until the events of a room up to the given timestamp are visible
+    /// to searches.
+    ///
+    /// The events that were added before this was called, live ones as well
+    /// as ones from the room history, are part of the wait. Once they are
+    /// committed the index is reloaded, searchers acquired afterwards find
+    /// them. This doesn't commit on its own, the events become visible with
+    /// the next commit, e.g. the one of a chunk of the room history or an
+    /// automatic one.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room whose events should become visible.
+    /// * `up_to_ts` - The timestamp of the newest event that should become
+    ///   visible.
+    /// * `timeout` - How long to wait for the events to be committed.
+    ///
+    /// Returns false if the events weren't committed within the timeout.
+    pub fn wait_for_room_visibility(
+        &self,
+        room_id: &str,
+        up_to_ts: i64,
+        timeout: Duration,
+    ) -> Result<bool> {
+        match self
+            .room_visibility_no_wait(room_id, up_to_ts)
+            .recv_timeout(timeout)
+        {
+            Ok(Ok(())) => {
+                self.index.reload()?;
+                Ok(true)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => Err(Database::writer_error(&self.writer_status)),
+        }
+    }
+
+    /// Wait until the events of a room up to the given timestamp are
+    /// committed without blocking, see `wait_for_room_visibility()`.
+    ///
+    /// Returns a receiver that will receive a message once the events are
+    /// committed, the index needs to be reloaded before searches find them.
+    /// The channel will be disconnected without a message if the writer
+    /// thread has stopped.
+    pub fn room_visibility_no_wait(&self, room_id: &str, up_to_ts: i64) -> Receiver<Result<()>> {
+        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
+        self.tx
+            .send(ThreadMessage::WaitForRoom(
+                sender,
+                room_id.to_owned(),
+                up_to_ts,
+            ))
+            .unwrap_or(());
+        receiver
+    }
+
+    /// Add the given events from the room history to the database.
+    /// # Arguments
+    ///
+    /// * `events` - The events that will be added.
+    /// * `new_checkpoint` - A checkpoint that states where we need to continue
+    ///   fetching events from the room history. This checkpoint will be
+    ///   persisted in the database.
+    /// * `old_checkpoint` - The checkpoint that was used to fetch the given
+    ///   events. This checkpoint will be removed from the database.
+    ///
+    /// Returns a receiver that will receive true if the chunk contained any
+    /// event that wasn't part of the database yet. A chunk that only
+    /// contains known events returns false, the crawler has caught up with
+    /// the already indexed history of the room. The receiver receives an
+    /// `Error::InvalidCheckpoint` without anything being written if one of
+    /// the checkpoints isn't valid, see `CrawlerCheckpoint::new()`. The
+    /// channel will be disconnected without a message if the writer thread
+    /// has stopped, see `status()`.
+    ///
+    /// The events and the checkpoints are stored in a single transaction.
+    /// Once they are stored the receiver gets a successful result, even if
+    /// committing the events to the index failed. The crawler should continue
+    /// from the new checkpoint in that case, the events are committed to the
+    /// index by a later commit and the commit error is returned by the next
+    /// call to `commit()`.
+    pub fn add_historic_events(
+        &self,
+        events: Vec<(Event, Profile)>,
+        new_checkpoint: Option<CrawlerCheckpoint>,
+        old_checkpoint: Option<CrawlerCheckpoint>,
+    ) -> Receiver<Result<bool>> {
+        let (sender, receiver): (_, Receiver<Result<bool>>) = channel();
+
+        let (new_checkpoint, old_checkpoint) =
+            match Database::canonicalize_checkpoints(new_checkpoint, old_checkpoint) {
+                Ok(c) => c,
+                Err(e) => {
+                    sender.send(Err(e)).unwrap_or(());
+                    return receiver;
+                }
+            };
+
+        let epoch = self.purge_epoch.load(Ordering::SeqCst);
+        let payload = (new_checkpoint, old_checkpoint, events, epoch, sender);
+        let message = ThreadMessage::HistoricEvents(payload);
+        self.tx.send(message).unwrap_or(());
+
+        receiver
+    }
+
+    /// Start importing events from the room history in chunks.
+    ///
+    /// This is useful for large imports, e.g. the initial crawl of a room,
+    /// where holding all the events in memory at once isn't desirable.
+    ///
+    /// Chunks of rooms that are purged after the import was started are
+    /// dropped.
+    pub fn start_bulk_import(&self) -> BulkImport {
+        BulkImport {
+            tx: self.tx.clone(),
+            purge_epoch: self.purge_epoch.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Search the index and return events matching a search term.
+    /// This is just a helper function that gets a searcher and performs a
+    /// search on it immediately.
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be used to search the index.
+    pub fn search(&self, term: &str, config: &SearchConfig) -> Result<SearchBatch> {
+        let searcher = self.get_searcher();
+        searcher.search(term, config)
+    }
+
+    /// Export all the events that match a search term as lines of JSON.
+    /// This is just a helper function that gets a searcher and exports the
+    /// search results using it, see `Searcher::export_search()`.
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be used to search the index.
+    /// * `config` - A SearchConfig that will modify what the search results
+    ///   should contain.
+    /// * `writer` - The writer the search results should be written to.
+    pub fn export_search<W: Write>(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        writer: W,
+    ) -> Result<ExportSummary> {
+        let searcher = self.get_searcher();
+        searcher.export_search(term, config, writer)
+    }
+
+    /// Check if a search term is going to be accepted by a search, without
+    /// searching.
+    ///
+    /// Only the term is parsed, neither the index nor the database is
+    /// touched, this is cheap enough to be done while the user is typing.
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be checked.
+    /// * `advanced_syntax` - Does the term use the advanced query syntax, see
+    ///   `SearchConfig::advanced_syntax()`.
+    ///
+    /// Returns the error a search with the term would fail with, e.g. an
+    /// `EmptySearchTerm`, a `QuerySyntaxError` or a `QueryTooComplex` error.
+    pub fn validate_query(&self, term: &str, advanced_syntax: bool) -> Result<()> {
+        let term = term.trim();
+
+        if !term.chars().any(char::is_alphanumeric) {
+            return Err(Error::EmptySearchTerm);
+        }
+
+        self.index.validate_query(term, advanced_syntax)
+    }
+
+    /// Prepare the index and the database for searches.
+    /// This is just a helper function that gets a searcher and preloads it,
+    /// see `Searcher::preload()`.
+    pub fn preload(&self) -> Result<()> {
+        self.get_searcher().preload()
+    }
+
+    /// Run multiple searches against the same snapshot of the index.
+    /// This is just a helper function that gets a searcher and performs the
+    /// searches on it immediately, see `Searcher::search_batch()`.
+    /// # Arguments
+    ///
+    /// * `queries` - The search terms and the `SearchConfig` of every search.
+    pub fn search_batch(&self, queries: &[(String, SearchConfig)]) -> Vec<Result<SearchBatch>> {
+        let searcher = self.get_searcher();
+        searcher.search_batch(queries)
+    }
+
+    /// Find events that are similar to the given event.
+    /// This is just a helper function that gets a searcher and performs the
+    /// search on it immediately.
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that the results should be similar
+    ///   to.
+    /// * `config` - A SearchConfig that limits the number of results, the room
+    ///   and the amount of context that should be returned.
+    pub fn find_similar(&self, event_id: &str, config: &SearchConfig) -> Result<Vec<SearchResult>> {
+        let searcher = self.get_searcher();
+        searcher.find_similar(event_id, config)
+    }
+
+    /// Set a named watermark to the given timestamp.
+    ///
+    /// Watermarks mark points in time, e.g. the time the user verified their
+    /// session, that searches can be limited to using
+    /// `SearchConfig::after_watermark()`. Setting an existing watermark
+    /// moves it, watermarks are stored in the database and survive a
+    /// restart.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the watermark.
+    /// * `timestamp` - The timestamp, in milliseconds since the Unix epoch,
+    ///   the watermark should be set to.
+    pub fn set_watermark(&self, name: &str, timestamp: i64) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        Database::retry_on_busy(|| Ok(Database::store_watermark(&connection, name, timestamp)?))
+    }
+
+    /// Get the timestamp of a named watermark.
+    ///
+    /// Returns `None` if the watermark was never set.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the watermark.
+    pub fn get_watermark(&self, name: &str) -> Result<Option<i64>> {
+        Ok(Database::load_watermark(
+            &self.connection.lock().unwrap(),
+            name,
+        )?)
+    }
+
+    /// Check if the event with the given event id is part of the index.
+    ///
+    /// Events are only found once they have been committed and the index was
+    /// reloaded, deleted events aren't found anymore once the deletion is
+    /// committed.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that should be looked up.
+    pub fn is_event_indexed(&self, event_id: &str) -> Result<bool> {
+        Ok(self.index.contains_event(event_id)?)
+    }
+
+    /// Get a searcher that can be used to perform a search.
+    pub fn get_searcher(&self) -> Searcher {
+        let index_searcher = self.index.get_searcher();
+        Searcher {
+            inner: index_searcher,
+            database: self.connection.clone(),
+            store_sources: self.config.store_sources,
+            source_cipher: self.source_cipher.clone(),
+            source_cache: self.source_cache.clone(),
+            metrics: self.metrics.clone(),
+            record_searches: self.config.record_searches,
+        }
+    }
+
+    /// Get a snapshot of the operational counters of the database.
+    ///
+    /// The counters count the operations since the database was opened.
+    pub fn get_metrics(&self) -> DatabaseMetrics {
+        let (hits, misses) = {
+            let cache = self.source_cache.lock().unwrap();
+            (cache.hits(), cache.misses())
+        };
+
+        self.metrics.snapshot(hits, misses)
+    }
+
+    /// Get a database connection.
+    /// Note that this connection should only be used for reading.
+    ///
+    /// The connection is taken from the connection pool of the database and
+    /// returned to it once it's dropped. If every connection is in use, this
+    /// waits for one to be returned and fails with a `PoolTimeout` error once
+    /// the configured connection timeout runs out.
     pub fn get_connection(&self) -> Result<Connection> {
-        let connection = self.pool.get()?;
-        Database::unlock(&connection, &self.config)?;
-        Database::set_pragmas(&connection)?;
+        self.get_connection_pool().get()
+    }
+
+    /// Get a handle to the connection pool of the database.
+    ///
+    /// Unlike `get_connection()` this doesn't take a connection out of the
+    /// pool yet, the handle can be moved to the thread that needs a
+    /// connection and take it there.
+    pub fn get_connection_pool(&self) -> ConnectionPool {
+        ConnectionPool {
+            pool: self.pool.clone(),
+            config: self.config.clone(),
+            locations: self.get_locations(),
+            source_cipher: self.source_cipher.clone(),
+            source_cache: self.source_cache.clone(),
+        }
+    }
+
+    /// Create a backup of the database.
+    ///
+    /// The queued up events are committed and the writer thread is paused
+    /// while the database and the index are copied into a single tar archive.
+    /// The backup can be restored using the `restore()` method.
+    ///
+    /// Returns a receiver that will receive an empty message once the backup
+    /// has been written, or the error that prevented the backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the archive that should be created.
+    pub fn backup<P: AsRef<Path>>(&self, path: P) -> Receiver<Result<()>> {
+        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
+        let message = ThreadMessage::Backup(
+            sender,
+            self.index_path.clone(),
+            self.store_path.clone(),
+            path.as_ref().to_owned(),
+        );
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Shut the database down.
+    ///
+    /// This will terminate the writer thread making sure that no writes will
+    /// happen after this operation.
+    ///
+    /// Returns a receiver that will receive an empty message once the writer
+    /// thread has shut down. The channel will be disconnected without a
+    /// message if the writer thread has already stopped.
+    pub fn shutdown(self) -> Receiver<Result<()>> {
+        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
+        let message = ThreadMessage::ShutDown(sender);
+        self.tx.send(message).unwrap_or(());
+        receiver
+    }
+
+    /// Delete the database.
+    /// Warning: This will delete the whole path that was provided at the
+    /// database creation time, as well as the whole index and store paths if
+    /// the config put them elsewhere.
+    pub fn delete(self) -> Result<()> {
+        for location in self.get_locations() {
+            match fs::remove_dir_all(location) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn create_event_db() {
+    let tmpdir = tempdir().unwrap();
+    let _db = Database::new(tmpdir.path()).unwrap();
+}
+
+#[test]
+fn store_profile() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let profile = Profile::new("Alice", "");
+
+    let id = Database::save_profile(
+        &db.connection.lock().unwrap(),
+        "@alice.example.org",
+        &profile,
+    );
+    assert_eq!(id.unwrap(), 1);
+
+    let id = Database::save_profile(
+        &db.connection.lock().unwrap(),
+        "@alice.example.org",
+        &profile,
+    );
+    assert_eq!(id.unwrap(), 1);
+
+    let profile_new = Profile::new("Alice", "mxc://some_url");
+
+    let id = Database::save_profile(
+        &db.connection.lock().unwrap(),
+        "@alice.example.org",
+        &profile_new,
+    );
+    assert_eq!(id.unwrap(), 2);
+}
+
+#[test]
+fn store_empty_profile() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let profile = Profile {
+        displayname: None,
+        avatar_url: None,
+    };
+    let id = Database::save_profile(
+        &db.connection.lock().unwrap(),
+        "@alice.example.org",
+        &profile,
+    );
+    assert_eq!(id.unwrap(), 1);
+}
+
+#[test]
+fn store_event() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+    let id = Database::save_profile(
+        &db.connection.lock().unwrap(),
+        "@alice.example.org",
+        &profile,
+    )
+    .unwrap();
+
+    let mut event = EVENT.clone();
+    let id = Database::save_event_helper(&db.connection.lock().unwrap(), &mut event, id).unwrap();
+    assert_eq!(id, 1);
+}
+
+#[test]
+fn store_event_and_profile() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let mut profile = Profile::new("Alice", "");
+    let mut event = EVENT.clone();
+    Database::save_event(&db.connection.lock().unwrap(), &mut event, &mut profile).unwrap();
+}
+
+#[test]
+fn load_event() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let mut profile = Profile::new("Alice", "");
+
+    let mut event = EVENT.clone();
+    Database::save_event(&db.connection.lock().unwrap(), &mut event, &mut profile).unwrap();
+    let events = Database::load_events(
+        &db.connection.lock().unwrap(),
+        &[
+            (1.0, "$15163622445EBvZJ:localhost".to_string()),
+            (0.3, "$FAKE".to_string()),
+        ],
+        &HashMap::new(),
+        0,
+        0,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(*EVENT.source, events[0].event_source)
+}
+
+#[test]
+fn commit_a_write() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    db.commit().unwrap();
+}
+
+#[test]
+fn commit_returns_the_opstamp() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let opstamp = db.force_commit().unwrap();
+
+    db.add_event(EVENT.clone(), profile);
+    let new_opstamp = db.force_commit().unwrap();
+    assert!(new_opstamp > opstamp);
+
+    // Nothing was added, so nothing gets committed to the index.
+    assert_eq!(db.commit().unwrap(), new_opstamp);
+}
+
+#[test]
+fn committed_adds_are_searchable() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let first = db.add_event(EVENT.clone(), profile.clone()).unwrap();
+    let second = db
+        .add_events(vec![(TOPIC_EVENT.clone(), profile.clone())])
+        .unwrap();
+    assert!(second > first);
+
+    // A normal commit is rate limited, the events aren't committed yet.
+    assert!(db.commit().unwrap() < first);
+
+    let committed = db.force_commit().unwrap();
+    assert!(committed >= second);
+
+    db.reload().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+
+    // Events without any text don't need to wait for a commit to the index.
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZK:localhost".to_string();
+    event.content_value = "".to_string();
+    let third = db.try_add_event(event, profile).unwrap();
+    assert!(third > second);
+    assert!(db.commit().unwrap() >= third);
+}
+
+#[test]
+fn force_commit_syncs_the_database() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+    let wal_path = tmpdir.path().join(format!("{}-wal", EVENTS_DB_NAME));
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+
+    // The write-ahead log was moved into the database file.
+    assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .get_stats()
+            .unwrap()
+            .event_count,
+        1
+    );
+}
+
+#[test]
+fn commits_are_searchable_without_a_reload() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+}
+
+#[test]
+fn manual_reloads() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_auto_reload(false);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap().results;
+    assert!(result.is_empty());
+
+    db.reload().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+}
+
+#[test]
+fn wait_for_room_visibility() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_auto_reload(false);
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    // Rooms without uncommitted events are visible right away.
+    assert!(db
+        .wait_for_room_visibility(&EVENT.room_id, EVENT.server_ts, Duration::from_secs(5))
+        .unwrap());
+
+    // Live events aren't visible until they are committed.
+    db.add_event(EVENT.clone(), profile.clone());
+    assert!(!db
+        .wait_for_room_visibility(&EVENT.room_id, EVENT.server_ts, Duration::from_millis(50))
+        .unwrap());
+
+    // Newer events of the room don't hold older ones back.
+    assert!(db
+        .wait_for_room_visibility(&EVENT.room_id, EVENT.server_ts - 1, Duration::from_secs(5))
+        .unwrap());
+
+    // The backlog is older than the live event, a chunk of it commits both.
+    let events: Vec<(Event, Profile)> = (0..3)
+        .map(|i| {
+            let mut event = EVENT.clone();
+            event.event_id = format!("$backlog{}:localhost", i);
+            event.server_ts = EVENT.server_ts - 100 + i;
+            (event, profile.clone())
+        })
+        .collect();
+    let up_to_ts = events.last().unwrap().0.server_ts;
+    let _receiver = db.add_historic_events(events, None, None);
+
+    assert!(db
+        .wait_for_room_visibility(&EVENT.room_id, up_to_ts, Duration::from_secs(5))
+        .unwrap());
+
+    // The index was reloaded, no manual reload is needed.
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 4);
+}
+
+#[test]
+fn auto_commit_after_events() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_auto_commit_after_events(Some(2));
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let opstamp = db.commit().unwrap();
+
+    // A single event isn't enough and a normal commit is rate limited.
+    db.add_event(EVENT.clone(), profile.clone());
+    assert_eq!(db.commit().unwrap(), opstamp);
+
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZK:localhost".to_string();
+    db.add_event(event, profile);
+
+    // Messages are processed in order, the auto commit happened before our
+    // commit request got handled.
+    assert!(db.commit().unwrap() > opstamp);
+}
+
+#[test]
+fn auto_commit_after_an_interval() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_auto_commit_interval(Some(time::Duration::from_millis(100)));
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let opstamp = db.commit().unwrap();
+    db.add_event(EVENT.clone(), profile);
+
+    thread::sleep(time::Duration::from_millis(500));
+    let new_opstamp = db.commit().unwrap();
+    assert!(new_opstamp > opstamp);
+
+    // Nothing is pending anymore, so nothing gets committed.
+    thread::sleep(time::Duration::from_millis(300));
+    assert_eq!(db.commit().unwrap(), new_opstamp);
+}
+
+#[test]
+fn opstamp_watermarks() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    assert_eq!(db.last_queued_opstamp(), 0);
+    assert_eq!(db.last_committed_opstamp(), 0);
+
+    let opstamp = db.add_event(EVENT.clone(), profile.clone()).unwrap();
+    assert_eq!(db.last_queued_opstamp(), opstamp);
+    assert!(db.last_queued_opstamp() > db.last_committed_opstamp());
+
+    assert_eq!(db.force_commit().unwrap(), opstamp);
+    assert_eq!(db.last_committed_opstamp(), opstamp);
+
+    // Automatic commits move the watermark as well.
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_auto_commit_after_events(Some(2));
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    db.add_event(EVENT.clone(), profile.clone()).unwrap();
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZK:localhost".to_string();
+    let opstamp = db.add_event(event, profile).unwrap();
+
+    // The writer handles messages in order, the automatic commit happened
+    // before the deletion got handled.
+    db.delete_event("$unknown:localhost")
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(db.last_committed_opstamp(), opstamp);
+    assert_eq!(db.last_queued_opstamp(), db.last_committed_opstamp());
+}
+
+#[test]
+fn sequence_numbers_saturate() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    // Sequence numbers beyond the precision of a double are kept intact.
+    let max_safe_integer = (1u64 << 53) - 1;
+    db.add_sequence
+        .last
+        .store(max_safe_integer, Ordering::SeqCst);
+
+    let opstamp = db.add_event(EVENT.clone(), profile.clone()).unwrap();
+    assert_eq!(opstamp, max_safe_integer + 1);
+    assert_eq!(db.force_commit().unwrap(), opstamp);
+
+    db.add_sequence.last.store(u64::MAX - 1, Ordering::SeqCst);
+
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZK:localhost".to_string();
+    assert_eq!(db.add_event(event, profile.clone()).unwrap(), u64::MAX);
+
+    let mut event = EVENT.clone();
+    event.event_id = "$15163622445EBvZL:localhost".to_string();
+    assert_eq!(db.add_event(event, profile).unwrap(), u64::MAX);
+
+    assert_eq!(db.force_commit().unwrap(), u64::MAX);
+    assert_eq!(db.last_committed_opstamp(), u64::MAX);
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .get_stats()
+            .unwrap()
+            .event_count,
+        3
+    );
+}
+
+#[test]
+fn commit_after_the_writer_stopped() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let (sender, receiver) = channel();
+    db.tx.send(ThreadMessage::ShutDown(sender)).unwrap();
+    receiver.recv().unwrap().unwrap();
+
+    assert!(matches!(db.commit(), Err(Error::WriterClosedError)));
+    assert!(matches!(db.force_commit(), Err(Error::WriterClosedError)));
+    assert!(db.commit_no_wait().recv().is_err());
+}
+
+#[test]
+fn shutdown_after_the_writer_stopped() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let (sender, receiver) = channel();
+    db.tx.send(ThreadMessage::ShutDown(sender)).unwrap();
+    receiver.recv().unwrap().unwrap();
+
+    assert!(db.shutdown().recv().is_err());
+}
+
+#[test]
+fn reload_errors_are_returned() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    db.get_reloader().reload().unwrap();
+
+    fs::remove_file(tmpdir.path().join("meta.json")).unwrap();
+
+    assert!(db.reload().is_err());
+    assert!(db.get_reloader().reload().is_err());
+}
+
+#[test]
+fn error_kinds() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let error = Database::new(tmpdir.path()).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::Locked);
+    assert_eq!(error.kind().as_str(), "DATABASE_LOCKED");
+
+    let error = db.search(" ", &SearchConfig::new()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Query);
+
+    let error = db
+        .get_searcher()
+        .find_similar("$unknown:localhost", &SearchConfig::new())
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+    assert_eq!(error.kind().as_str(), "NOT_FOUND");
+}
+
+#[test]
+fn open_a_locked_database() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    assert!(matches!(
+        Database::new(tmpdir.path()),
+        Err(Error::DatabaseLocked)
+    ));
+
+    // The second open succeeds if the lock gets released while we wait.
+    let handle = thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(200));
+        db.shutdown().recv().unwrap().unwrap();
+    });
+
+    let config = Config::new().set_lock_timeout(time::Duration::from_secs(10));
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    handle.join().unwrap();
+
+    // A forced unlock ignores the lock of the other database.
+    let config = Config::new().set_force_unlock(true);
+    let other_db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    other_db.shutdown().recv().unwrap().unwrap();
+    db.shutdown().recv().unwrap().unwrap();
+}
+
+#[test]
+fn delete_events_older_than() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    let mut new_event = EVENT.clone();
+    new_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    new_event.server_ts = EVENT.server_ts + 1000;
+
+    db.add_historic_events(
+        vec![
+            (EVENT.clone(), profile.clone()),
+            (new_event.clone(), profile),
+        ],
+        Some(checkpoint.clone()),
+        None,
+    )
+    .recv()
+    .unwrap()
+    .unwrap();
+
+    let deleted = db
+        .delete_events_older_than(EVENT.server_ts + 1)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(deleted, 1);
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].event_source, new_event.source);
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 1);
+    assert_eq!(connection.load_checkpoints().unwrap(), vec![checkpoint]);
+
+    // Nothing else is old enough.
+    let deleted = db
+        .delete_events_older_than(EVENT.server_ts + 1)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(deleted, 0);
+}
+
+#[test]
+fn delete_uncommitted_events_older_than() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+
+    // The event is stored, but the index commit is rate limited.
+    db.commit().unwrap();
+    assert!(
+        !Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    let deleted = db
+        .delete_events_older_than(EVENT.server_ts + 1)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(deleted, 1);
+    assert!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn delete_sender() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let alice = "@alice:localhost";
+    let bob = "@bob:localhost";
+
+    let event_from = |sender: &str, event_id: &str, offset: i64| {
+        let mut event = EVENT.clone();
+        event.sender = sender.to_owned();
+        event.event_id = event_id.to_owned();
+        event.server_ts = EVENT.server_ts + offset;
+        event
+    };
+
+    db.add_event(event_from(alice, "$1", 0), Profile::new("Alice", ""));
+    db.add_event(event_from(bob, "$2", 1), Profile::new("Bob", ""));
+    db.add_event(
+        event_from(alice, "$3", 2),
+        Profile::new("Alice", "mxc://localhost/alice"),
+    );
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let reported = progress.clone();
+    let deleted = db
+        .delete_sender_with_progress(alice, move |count| reported.lock().unwrap().push(count))
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(deleted, 2);
+    assert_eq!(*progress.lock().unwrap(), vec![2]);
+
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config.before_limit(2).after_limit(2);
+
+    let result = db.search("test", &config).unwrap().results;
+    assert_eq!(result.len(), 1);
+    assert!(result[0].events_before.is_empty());
+    assert!(result[0].events_after.is_empty());
+    assert_eq!(
+        result[0].profile_info.keys().collect::<Vec<_>>(),
+        vec![&bob.to_owned()]
+    );
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 1);
+    assert_eq!(connection.load_profile(alice, None).unwrap(), None);
+
+    let profiles: i64 = connection
+        .query_row(
+            "SELECT COUNT(*) FROM profile WHERE user_id = ?1",
+            [alice],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(profiles, 0);
+
+    // Nothing of the sender is left.
+    assert_eq!(db.delete_sender(alice).recv().unwrap().unwrap(), 0);
+}
+
+#[test]
+fn delete_sender_with_uncommitted_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut bob_topic = TOPIC_EVENT.clone();
+    bob_topic.event_id = "$bob_topic:localhost".to_owned();
+    bob_topic.sender = "@bob:localhost".to_owned();
+    bob_topic.server_ts -= 100;
+
+    let mut name = EVENT.clone();
+    name.event_type = EventType::Name;
+    name.content_value = "Alice's room".to_owned();
+    name.event_id = "$name:localhost".to_owned();
+
+    db.add_event(bob_topic.clone(), Profile::new("Bob", ""));
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile.clone());
+    db.add_event(name, profile);
+
+    // The events are stored, but the index commit is rate limited.
+    db.commit().unwrap();
+    assert!(
+        !Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    assert_eq!(db.delete_sender(&EVENT.sender).recv().unwrap().unwrap(), 3);
+
+    let connection = db.get_connection().unwrap();
+    let uncommitted = Database::load_uncommitted_events(&connection).unwrap();
+    assert!(uncommitted
+        .iter()
+        .all(|(_, event)| event.sender != EVENT.sender));
+
+    // The older topic of another sender takes the place of the deleted one,
+    // the name the sender gave the room is gone.
+    let latest_topic: String = connection
+        .query_row(
+            "SELECT event_id FROM latest_state_events WHERE room_id = ?1 AND type = ?2",
+            [EVENT.room_id.as_str(), "m.room.topic"],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(latest_topic, bob_topic.event_id);
+    assert_eq!(
+        connection
+            .get_room_names(&[EVENT.room_id.clone()])
+            .unwrap()
+            .get(&EVENT.room_id),
+        Some(&None)
+    );
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn compact() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_compaction_threshold(2);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let events: Vec<Event> = (0..3)
+        .map(|i| {
+            let mut event = EVENT.clone();
+            event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+            event.server_ts = EVENT.server_ts + i;
+            event
+        })
+        .collect();
+
+    for event in &events {
+        db.add_event(event.clone(), profile.clone());
+    }
+    db.force_commit().unwrap();
+
+    db.delete_event(&events[0].event_id)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .get_stats()
+            .unwrap()
+            .tombstone_count,
+        1
+    );
+
+    // Not enough events were deleted yet.
+    assert!(!db.compact().recv().unwrap().unwrap());
+
+    db.delete_events_older_than(events[2].server_ts)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .get_stats()
+            .unwrap()
+            .tombstone_count,
+        2
+    );
+
+    assert!(db.compact().recv().unwrap().unwrap());
+    db.reload().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().tombstone_count, 0);
+    let auto_vacuum: i64 = connection
+        .pragma_query_value(None, "auto_vacuum", |row| row.get(0))
+        .unwrap();
+    assert_eq!(auto_vacuum, 2);
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].event_source, events[2].source);
+}
+
+#[test]
+fn shrink() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let events: Vec<(Event, Profile)> = (0..1000)
+        .map(|i| {
+            let mut event: Event = Faker.fake();
+            event.server_ts = i;
+            (event, profile.clone())
+        })
+        .collect();
+    db.add_events(events);
+    db.force_commit().unwrap();
+
+    db.delete_events_older_than(900).recv().unwrap().unwrap();
+    db.force_commit().unwrap();
+
+    // Shrinking ignores the compaction threshold.
+    let reclaimed = db.shrink().recv().unwrap().unwrap();
+    assert!(reclaimed > 0);
+
+    let connection = db.get_connection().unwrap();
+    let stats = connection.get_stats().unwrap();
+    assert_eq!(stats.event_count, 100);
+    assert_eq!(stats.tombstone_count, 0);
+}
+
+#[test]
+fn get_size() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let size = db.get_size().unwrap();
+    assert!(size.index_bytes > 0);
+    assert!(size.store_bytes > 0);
+
+    // Garbage in the database directory isn't counted.
+    fs::write(tmpdir.path().join("garbage.idx"), vec![0u8; 4096]).unwrap();
+    assert_eq!(db.get_size().unwrap(), size);
+
+    let profile = Profile::new("Alice", "");
+    let events: Vec<(Event, Profile)> = (0..100).map(|_| (Faker.fake(), profile.clone())).collect();
+    db.add_events(events);
+    db.force_commit().unwrap();
+
+    let new_size = db.get_size().unwrap();
+    assert!(new_size.index_bytes > size.index_bytes);
+    assert!(new_size.store_bytes > size.store_bytes);
+    assert_eq!(
+        new_size.total_bytes(),
+        new_size.index_bytes + new_size.store_bytes
+    );
+}
+
+#[test]
+fn separate_index_and_store_locations() {
+    let tmpdir = tempdir().unwrap();
+    let index_path = tmpdir.path().join("index");
+    let store_path = tmpdir.path().join("store");
+    let config = Config::new()
+        .set_index_path(&index_path)
+        .set_store_path(&store_path);
+
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    assert_eq!(db.get_index_path(), index_path);
+    assert_eq!(db.get_store_path(), store_path);
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    assert!(index_path.join(INDEX_META).exists());
+    assert!(store_path.join(EVENTS_DB_NAME).exists());
+    assert!(!tmpdir.path().join(EVENTS_DB_NAME).exists());
+
+    let files = db.get_files().unwrap();
+    assert!(files.index.iter().all(|f| f.starts_with(&index_path)));
+    assert!(files.store.iter().all(|f| f.starts_with(&store_path)));
+
+    let recorded = fs::canonicalize(&index_path).unwrap();
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.get_recorded_index_path().unwrap(),
+        Some(recorded.clone())
+    );
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
+    // A store whose index went missing can't be opened.
+    let moved_path = tmpdir.path().join("moved");
+    fs::rename(&index_path, &moved_path).unwrap();
+    assert!(matches!(
+        Database::new_with_config(tmpdir.path(), &config),
+        Err(Error::IndexNotFound(p)) if p == recorded
+    ));
+
+    let config = config.set_index_path(&moved_path);
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+
+    db.delete().unwrap();
+    assert!(!moved_path.exists());
+    assert!(!store_path.exists());
+}
+
+#[test]
+fn profiles_are_deduplicated() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_compaction_threshold(1);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+    let profile_count = |db: &Database| -> i64 {
+        db.get_connection()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM profile", [], |row| row.get(0))
+            .unwrap()
+    };
+
+    for i in 0..100 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$15163622445EBvZ{}:localhost", i);
+        db.add_event(event, profile.clone());
+    }
+
+    let mut event = TOPIC_EVENT.clone();
+    event.sender = "@bob:example.org".to_owned();
+    db.add_event(event.clone(), Profile::new("Bob", ""));
+    db.force_commit().unwrap();
+
+    assert_eq!(profile_count(&db), 2);
+
+    // Profiles that aren't used anymore are deleted when compacting.
+    db.delete_event(&event.event_id).recv().unwrap().unwrap();
+    db.force_commit().unwrap();
+    assert!(db.compact().recv().unwrap().unwrap());
+    assert_eq!(profile_count(&db), 1);
+}
+
+#[test]
+fn retention_period() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.shutdown().recv().unwrap().unwrap();
+
+    // The event is years old, the writer purges it right after opening the
+    // database.
+    let config = Config::new().set_retention(Some(time::Duration::from_secs(24 * 60 * 60)));
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 0);
+    assert!(db
+        .search("test", &SearchConfig::new())
+        .unwrap()
+        .results
+        .is_empty());
+}
+
+#[test]
+fn indexed_msgtypes() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_indexed_msgtypes(Some(vec!["m.text".to_owned()]));
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut notice = EVENT.clone();
+    notice.event_id = "$15163622445EBvZK:localhost".to_string();
+    notice.msgtype = Some("m.notice".to_string());
+    notice.source = "A test notice".to_string();
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(notice.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 2);
+    assert!(result.iter().all(|r| r.event_source != notice.source));
+
+    let connection = db.get_connection().unwrap();
+    assert!(!Database::event_in_store(&connection, &notice).unwrap());
+    assert_eq!(connection.get_stats().unwrap().event_count, 2);
+}
+
+#[test]
+fn room_indexing_opt_out() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut other_event = EVENT.clone();
+    other_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    other_event.source = "Another test event".to_string();
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+
+    let purged = db
+        .set_room_indexing(&EVENT.room_id, false, false)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(purged, 0);
+
+    db.add_event(other_event.clone(), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.get_unindexed_rooms().unwrap(),
+        vec![EVENT.room_id.clone()]
+    );
+    assert!(!Database::event_in_store(&connection, &other_event).unwrap());
+    assert_eq!(
+        db.search("Test", &SearchConfig::new())
+            .unwrap()
+            .results
+            .len(),
+        1
+    );
+
+    let purged = db
+        .set_room_indexing(&EVENT.room_id, false, true)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(purged, 1);
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert!(db
+        .search("Test", &SearchConfig::new())
+        .unwrap()
+        .results
+        .is_empty());
+
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
+    // The setting survives a restart.
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    db.add_event(other_event.clone(), profile.clone());
+    db.force_commit().unwrap();
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .get_stats()
+            .unwrap()
+            .event_count,
+        0
+    );
+
+    db.set_room_indexing(&EVENT.room_id, true, false)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert!(db
+        .get_connection()
+        .unwrap()
+        .get_unindexed_rooms()
+        .unwrap()
+        .is_empty());
+
+    db.add_event(other_event, profile);
+    db.force_commit().unwrap();
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .get_stats()
+            .unwrap()
+            .event_count,
+        1
+    );
+}
+
+#[test]
+fn rooms_without_checkpoints() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let event_in = |room_id: &str, event_id: &str| {
+        let mut event = EVENT.clone();
+        event.room_id = room_id.to_owned();
+        event.event_id = event_id.to_owned();
+        event
+    };
+    let checkpoint = |room_id: &str| CrawlerCheckpoint {
+        room_id: room_id.to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    // A room that is still being crawled.
+    db.add_historic_events(
+        vec![(event_in("!crawling:localhost", "$1"), profile.clone())],
+        Some(checkpoint("!crawling:localhost")),
+        None,
+    )
+    .recv()
+    .unwrap()
+    .unwrap();
+
+    // A room that lost its checkpoint.
+    db.add_historic_events(
+        vec![(event_in("!lost:localhost", "$2"), profile.clone())],
+        None,
+        None,
+    )
+    .recv()
+    .unwrap()
+    .unwrap();
+
+    // A room that has a checkpoint but no events yet.
+    db.add_historic_events(vec![], Some(checkpoint("!empty:localhost")), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    db.add_event(event_in("!live:localhost", "$3"), profile);
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.get_rooms_without_checkpoints().unwrap(),
+        vec!["!live:localhost".to_owned(), "!lost:localhost".to_owned()]
+    );
+
+    db.set_room_indexing("!lost:localhost", false, false)
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        connection.get_rooms_without_checkpoints().unwrap(),
+        vec!["!live:localhost".to_owned()]
+    );
+}
+
+#[test]
+fn known_event_ids() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let events: Vec<Event> = (0..1200).map(|_| Faker.fake()).collect();
+
+    for event in events.iter().step_by(2) {
+        db.add_event(event.clone(), profile.clone());
+    }
+    db.force_commit().unwrap();
+
+    let mut ids: Vec<EventId> = events.iter().map(|e| e.event_id.clone()).collect();
+    ids.push(events[0].event_id.clone());
+
+    let connection = db.get_connection().unwrap();
+    let mut known = connection.get_known_event_ids(&ids).unwrap();
+    known.sort();
+
+    let mut expected: Vec<EventId> = events
+        .iter()
+        .step_by(2)
+        .map(|e| e.event_id.clone())
+        .collect();
+    expected.sort();
+
+    assert_eq!(known, expected);
+    assert!(connection.get_known_event_ids(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn list_rooms() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let event_in = |room_id: &str, event_id: &str| {
+        let mut event = EVENT.clone();
+        event.room_id = room_id.to_owned();
+        event.event_id = event_id.to_owned();
+        event
+    };
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!empty:localhost".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    db.add_event(event_in("!b:localhost", "$1"), profile.clone());
+    db.add_event(event_in("!a:localhost", "$2"), profile.clone());
+    db.add_event(event_in("!b:localhost", "$3"), profile);
+    db.add_historic_events(vec![], Some(checkpoint), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.get_rooms().unwrap(),
+        vec!["!a:localhost".to_owned(), "!b:localhost".to_owned()]
+    );
+    assert_eq!(
+        connection.get_checkpoint_only_rooms().unwrap(),
+        vec!["!empty:localhost".to_owned()]
+    );
+}
+
+#[test]
+fn delete_room_with_uncommitted_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+
+    // The live event is stored, but the index commit is rate limited.
+    db.commit().unwrap();
+    assert!(
+        !Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    assert_eq!(db.delete_room(&EVENT.room_id).recv().unwrap().unwrap(), 1);
+    assert!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert!(result.is_empty());
+}
+
+#[test]
+fn purged_rooms_are_not_resurrected() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut later_event = EVENT.clone();
+    later_event.event_id = "$later:localhost".to_owned();
+    later_event.server_ts += 10;
+
+    let mut other_event = EVENT.clone();
+    other_event.event_id = "$other:localhost".to_owned();
+    other_event.room_id = "!other:localhost".to_owned();
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: EVENT.room_id.clone(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    db.add_historic_events(
+        vec![(EVENT.clone(), profile.clone())],
+        Some(checkpoint.clone()),
+        None,
+    )
+    .recv()
+    .unwrap()
+    .unwrap();
+
+    // The import is started before the room is deleted, its chunks arrive
+    // afterwards.
+    let import = db.start_bulk_import();
+
+    assert_eq!(db.delete_room(&EVENT.room_id).recv().unwrap().unwrap(), 1);
+
+    let ret = import
+        .add_chunk(vec![
+            (later_event.clone(), profile.clone()),
+            (other_event.clone(), profile.clone()),
+        ])
+        .recv()
+        .unwrap();
+    // The event of the purged room is dropped, the rest of the chunk is
+    // added.
+    assert!(ret.unwrap());
+
+    // A chunk that only contains events of the purged room adds nothing.
+    let ret = import
+        .add_chunk(vec![(later_event.clone(), profile.clone())])
+        .recv()
+        .unwrap();
+    assert!(!ret.unwrap());
+
+    import
+        .finish(Some(checkpoint), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        Database::get_event_count_for_room(&connection, &EVENT.room_id).unwrap(),
+        0
+    );
+    assert!(Database::event_in_store(&connection, &other_event).unwrap());
+    assert!(connection.load_checkpoints().unwrap().is_empty());
+
+    let mut config = SearchConfig::new();
+    config.for_room(&EVENT.room_id);
+    assert!(db.search("Test", &config).unwrap().results.is_empty());
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
+    // Batches that are queued after the purge add events of the room again,
+    // even after a restart.
+    let db = Database::new(tmpdir.path()).unwrap();
+    db.add_historic_events(vec![(later_event.clone(), profile)], None, None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert!(Database::event_in_store(&connection, &later_event).unwrap());
+}
+
+#[test]
+fn room_fully_crawled_state() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
+
+    assert!(!connection.is_room_fully_crawled("!a:localhost").unwrap());
+
+    connection
+        .set_room_fully_crawled("!a:localhost", true)
+        .unwrap();
+    connection
+        .set_room_fully_crawled("!b:localhost", true)
+        .unwrap();
+    connection
+        .set_room_fully_crawled("!b:localhost", false)
+        .unwrap();
+
+    drop(connection);
+    drop(db);
+
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
+
+    assert!(connection.is_room_fully_crawled("!a:localhost").unwrap());
+    assert!(!connection.is_room_fully_crawled("!b:localhost").unwrap());
+    assert_eq!(
+        connection.get_fully_crawled_rooms().unwrap(),
+        vec!["!a:localhost".to_owned()]
+    );
+
+    let checkpoint = |direction| CrawlerCheckpoint {
+        room_id: "!a:localhost".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction,
+    };
+
+    // A forwards checkpoint doesn't affect the history of the room.
+    db.add_historic_events(
+        vec![],
+        Some(checkpoint(CheckpointDirection::Forwards)),
+        None,
+    )
+    .recv()
+    .unwrap()
+    .unwrap();
+    assert!(connection.is_room_fully_crawled("!a:localhost").unwrap());
+
+    db.add_historic_events(
+        vec![],
+        Some(checkpoint(CheckpointDirection::Backwards)),
+        None,
+    )
+    .recv()
+    .unwrap()
+    .unwrap();
+    assert!(!connection.is_room_fully_crawled("!a:localhost").unwrap());
+    assert!(connection.get_fully_crawled_rooms().unwrap().is_empty());
+}
+
+#[test]
+fn newest_event_timestamps() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let event_in = |room_id: &str, event_id: &str, server_ts: i64| {
+        let mut event = EVENT.clone();
+        event.room_id = room_id.to_owned();
+        event.event_id = event_id.to_owned();
+        event.server_ts = server_ts;
+        event
+    };
+
+    db.add_event(event_in("!a:localhost", "$1", 10), profile.clone());
+    db.add_event(event_in("!a:localhost", "$2", 30), profile.clone());
+    db.add_event(event_in("!a:localhost", "$3", 20), profile.clone());
+    db.add_event(event_in("!b:localhost", "$4", 5), profile);
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.get_newest_event_ts("!a:localhost").unwrap(),
+        Some(30)
+    );
+    assert_eq!(
+        connection.get_newest_event_ts("!c:localhost").unwrap(),
+        None
+    );
+
+    let rooms: Vec<RoomId> = ["!a:localhost", "!b:localhost", "!c:localhost"]
+        .iter()
+        .map(|r| r.to_string())
+        .collect();
+    let timestamps = connection.get_newest_event_timestamps(&rooms).unwrap();
+
+    assert_eq!(timestamps.len(), 3);
+    assert_eq!(timestamps["!a:localhost"], Some(30));
+    assert_eq!(timestamps["!b:localhost"], Some(5));
+    assert_eq!(timestamps["!c:localhost"], None);
+    assert!(connection
+        .get_newest_event_timestamps(&[])
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn latest_sender_profiles() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let event_in = |room_id: &str, event_id: &str, server_ts: i64| {
+        let mut event = EVENT.clone();
+        event.room_id = room_id.to_owned();
+        event.event_id = event_id.to_owned();
+        event.server_ts = server_ts;
+        event
+    };
+
+    db.add_event(
+        event_in("!a:localhost", "$1", 10),
+        Profile::new("Alice", ""),
+    );
+    db.add_event(
+        event_in("!a:localhost", "$2", 30),
+        Profile::new("Alice in a", "mxc://localhost/a"),
+    );
+    db.add_event(
+        event_in("!b:localhost", "$3", 20),
+        Profile::new("Alice in b", "mxc://localhost/b"),
+    );
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    let sender = EVENT.sender.clone();
+
+    assert_eq!(
+        connection.load_profile(&sender, None).unwrap(),
+        Some(Profile::new("Alice in a", "mxc://localhost/a"))
+    );
+    assert_eq!(
+        connection
+            .load_profile(&sender, Some(&"!b:localhost".to_owned()))
+            .unwrap(),
+        Some(Profile::new("Alice in b", "mxc://localhost/b"))
+    );
+    assert_eq!(
+        connection
+            .load_profile(&sender, Some(&"!c:localhost".to_owned()))
+            .unwrap(),
+        None
+    );
+
+    let senders = vec![sender.clone(), "@bob:localhost".to_owned(), sender.clone()];
+    let profiles = connection.load_profiles(&senders).unwrap();
+
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(
+        profiles[&sender],
+        Some(Profile::new("Alice in a", "mxc://localhost/a"))
+    );
+    assert_eq!(profiles["@bob:localhost"], None);
+}
+
+#[test]
+fn preload_database() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    // Preloading an empty database does nothing.
+    db.preload().unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.add_event(TOPIC_EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    db.preload().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 2);
+}
+
+#[test]
+fn committed_event_lookup() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert!(!connection.was_event_indexed(&EVENT.event_id).unwrap());
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    assert!(connection.was_event_indexed(&EVENT.event_id).unwrap());
+    assert!(!connection.was_event_indexed("$unknown:localhost").unwrap());
+
+    db.delete_event(&EVENT.event_id).recv().unwrap().unwrap();
+    db.force_commit().unwrap();
+    assert!(!connection.was_event_indexed(&EVENT.event_id).unwrap());
+}
+
+#[test]
+fn reactions_boost_search_results() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut other_event = EVENT.clone();
+    other_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    other_event.source = EVENT.source.replace(&EVENT.event_id, &other_event.event_id);
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(other_event.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut search_config = SearchConfig::new();
+    search_config.limit(2);
+    let batch = db.search("message", &search_config).unwrap();
+    assert_eq!(batch.results.len(), 2);
+    assert_eq!(batch.results[0].score, batch.results[1].score);
+
+    // React to the event that is ranked lower.
+    let (reacted_id, reacted_source) = if batch.results[1].event_source == EVENT.source {
+        (EVENT.event_id.clone(), EVENT.source.clone())
+    } else {
+        (other_event.event_id, other_event.source)
+    };
+
+    let reaction = Reaction::new("$reaction:localhost", &reacted_id, &EVENT.room_id);
+    db.add_reaction(reaction.clone());
+    db.add_reaction(reaction);
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    let counts = connection
+        .get_reaction_counts(&[reacted_id.clone(), "$unknown:localhost".to_string()])
+        .unwrap();
+    assert_eq!(counts[&reacted_id], 1);
+    assert_eq!(counts["$unknown:localhost"], 0);
+
+    search_config.limit(1);
+    let batch = db.search("message", &search_config).unwrap();
+    assert_ne!(batch.results[0].event_source, reacted_source);
+
+    search_config.boost_by_reactions(true);
+    let batch = db.search("message", &search_config).unwrap();
+    assert_eq!(batch.count, 2);
+    assert_eq!(batch.results.len(), 1);
+    assert_eq!(batch.results[0].event_source, reacted_source);
+
+    // The event that didn't make the cut is part of the next batch.
+    search_config.next_batch(batch.next_batch.unwrap());
+    let batch = db.search("message", &search_config).unwrap();
+    assert_eq!(batch.results.len(), 1);
+    assert_ne!(batch.results[0].event_source, reacted_source);
+
+    // A redacted reaction doesn't count anymore.
+    assert!(db
+        .delete_event("$reaction:localhost")
+        .recv()
+        .unwrap()
+        .unwrap());
+    let counts = connection
+        .get_reaction_counts(&[reacted_id.clone()])
+        .unwrap();
+    assert_eq!(counts[&reacted_id], 0);
+}
+
+#[test]
+fn events_with_urls() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let bodies = [
+        "Have a look at https://matrix.org",
+        "The http:// scheme is insecure",
+        "Docs are on HTTP://example.org/docs",
+        "No link here",
+    ];
+
+    for (i, body) in bodies.iter().enumerate() {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$url{}:localhost", i);
+        event.content_value = body.to_string();
+        event.server_ts = EVENT.server_ts + i as i64;
+        event.source = EVENT.source.replace(&EVENT.event_id, &event.event_id);
+        db.add_event(event, profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    let events = connection
+        .load_url_events(&LoadConfig::new(&EVENT.room_id).limit(10))
+        .unwrap();
+    let sources: Vec<&str> = events.iter().map(|(source, _)| source.as_str()).collect();
+
+    // The newest events come first.
+    assert_eq!(sources.len(), 2);
+    assert!(sources[0].contains("$url2:localhost"));
+    assert!(sources[1].contains("$url0:localhost"));
+
+    let mut search_config = SearchConfig::new();
+    search_config.contains_url(true);
+    let batch = db.search("link", &search_config).unwrap();
+    assert_eq!(batch.count, 0);
+
+    let batch = db.search("look docs", &search_config).unwrap();
+    assert_eq!(batch.count, 2);
+
+    search_config.contains_url(false);
+    let batch = db.search("scheme link", &search_config).unwrap();
+    assert_eq!(batch.count, 2);
+
+    // Listing the links of a room doesn't need a search term.
+    let err = db.search("", &search_config).unwrap_err();
+    assert!(matches!(err, Error::EmptySearchTerm));
+
+    search_config
+        .contains_url(true)
+        .for_room(&EVENT.room_id)
+        .order_by_recency(true);
+    let batch = db.search("", &search_config).unwrap();
+    assert_eq!(batch.count, 2);
+    assert!(batch.results[0].event_source.contains("$url2:localhost"));
+}
+
+#[test]
+fn serde_round_trip() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "mxc://localhost/alice");
+
+    let mut before_event = EVENT.clone();
+    before_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    before_event.server_ts -= 1;
+
+    db.add_event(before_event, profile.clone());
+    db.add_event(EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut search_config = SearchConfig::new();
+    search_config.before_limit(1);
+    let batch = db.search("message", &search_config).unwrap();
+    let result = batch
+        .results
+        .iter()
+        .find(|r| r.event_source == EVENT.source)
+        .unwrap();
+    assert_eq!(result.events_before.len(), 1);
+    assert!(!result.profile_info.is_empty());
+
+    let json = serde_json::to_value(result).unwrap();
+    assert!(json.get("rank").is_some());
+    assert_eq!(
+        serde_json::from_value::<SearchResult>(json).unwrap(),
+        *result
+    );
+
+    let json = serde_json::to_string(&batch).unwrap();
+    assert_eq!(serde_json::from_str::<SearchBatch>(&json).unwrap(), batch);
+
+    let json = serde_json::to_string(&*EVENT).unwrap();
+    assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), *EVENT);
+
+    // The field names match the objects of the Node binding.
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(json["displayname"], "Alice");
+    assert_eq!(json["avatar_url"], "mxc://localhost/alice");
+    assert_eq!(serde_json::from_value::<Profile>(json).unwrap(), profile);
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+    let json = serde_json::to_value(&checkpoint).unwrap();
+    assert_eq!(json["roomId"], "!test:room");
+    assert_eq!(json["token"], "1234");
+    assert_eq!(
+        serde_json::from_value::<CrawlerCheckpoint>(json).unwrap(),
+        checkpoint
+    );
+
+    let stats = db.get_connection().unwrap().get_stats().unwrap();
+    let json = serde_json::to_string(&stats).unwrap();
+    assert_eq!(serde_json::from_str::<DatabaseStats>(&json).unwrap(), stats);
+
+    let language: Language = serde_json::from_str("\"german\"").unwrap();
+    assert_eq!(language, Language::German);
+}
+
+#[test]
+fn search_without_context() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "mxc://localhost/alice");
+
+    let mut before_event = EVENT.clone();
+    before_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    before_event.server_ts -= 1;
+
+    db.add_event(before_event, profile.clone());
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut search_config = SearchConfig::new();
+    search_config.before_limit(1).include_context(false);
+    let batch = db.search("message", &search_config).unwrap();
+    assert_eq!(batch.count, 2);
+
+    for result in &batch.results {
+        assert!(result.events_before.is_empty());
+        assert!(result.events_after.is_empty());
+        assert!(!result.profile_info.is_empty());
+    }
+
+    search_config.include_context(true).include_profiles(false);
+    let batch = db.search("message", &search_config).unwrap();
+    let result = batch
+        .results
+        .iter()
+        .find(|r| r.event_source == EVENT.source)
+        .unwrap();
+    assert_eq!(result.events_before.len(), 1);
+    assert!(result.profile_info.is_empty());
+}
+
+#[test]
+fn export_search_results() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "mxc://localhost/alice");
+
+    // More matching events than fit into a single batch of the export.
+    for i in 0..250 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$export{}:localhost", i);
+        event.source = EVENT.source.replace(&EVENT.event_id, &event.event_id);
+        event.server_ts += i;
+        db.add_event(event, profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut search_config = SearchConfig::new();
+    search_config.limit(1).before_limit(1);
+
+    let mut progress = Vec::new();
+    let mut output = Vec::new();
+    let summary = db
+        .get_searcher()
+        .export_search_with_progress("message", &search_config, &mut output, |count| {
+            progress.push(count)
+        })
+        .unwrap();
+
+    assert_eq!(summary.count, 250);
+    assert_eq!(summary.skipped, 0);
+    assert_eq!(progress, vec![100, 200, 250]);
+
+    let lines: Vec<serde_json::Value> = std::str::from_utf8(&output)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 250);
+
+    let mut event_ids: Vec<&str> = lines
+        .iter()
+        .map(|l| l["result"]["event_id"].as_str().unwrap())
+        .collect();
+    event_ids.sort_unstable();
+    event_ids.dedup();
+    assert_eq!(event_ids.len(), 250);
+
+    let line = lines
+        .iter()
+        .find(|l| l["result"]["event_id"] == "$export1:localhost")
+        .unwrap();
+    assert_eq!(
+        line["context"]["events_before"][0]["event_id"],
+        "$export0:localhost"
+    );
+    assert_eq!(
+        line["context"]["profile_info"]["@example2:localhost"]["displayname"],
+        "Alice"
+    );
+
+    let summary = db
+        .export_search("nothing", &SearchConfig::new(), Vec::new())
+        .unwrap();
+    assert_eq!(summary.count, 0);
+}
+
+#[test]
+fn privacy_mode() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_store_sources(false);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut before_event = EVENT.clone();
+    before_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    before_event.server_ts -= 1;
+
+    db.add_event(before_event, profile.clone());
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut search_config = SearchConfig::new();
+    search_config.before_limit(1);
+    let results = db.search("message", &search_config).unwrap().results;
+    assert_eq!(results.len(), 2);
+
+    for result in &results {
+        let source: serde_json::Value = serde_json::from_str(&result.event_source).unwrap();
+
+        assert!(!result.source_available);
+        assert!(result.events_before.is_empty());
+        assert!(source.get("content").is_none());
+        assert_eq!(source["room_id"], EVENT.room_id.as_str());
+        assert_eq!(source["sender"], EVENT.sender.as_str());
+    }
+
+    let stored_sources: i64 = db
+        .get_connection()
+        .unwrap()
+        .query_row(
+            "SELECT COUNT(*) FROM events WHERE source LIKE '%Test message%'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(stored_sources, 0);
+
+    assert!(matches!(
+        db.find_similar(&EVENT.event_id, &SearchConfig::new()),
+        Err(Error::SourcesNotStored)
+    ));
+
+    db.shutdown().recv().unwrap().unwrap();
+
+    // The mode can't be changed once the database was created.
+    assert!(matches!(
+        Database::new(tmpdir.path()),
+        Err(Error::StoreSourcesMismatch)
+    ));
+    Database::new_with_config(tmpdir.path(), &config).unwrap();
+}
+
+#[test]
+fn privacy_mode_for_an_existing_database() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+    db.shutdown().recv().unwrap().unwrap();
+
+    let config = Config::new().set_store_sources(false);
+    assert!(matches!(
+        Database::new_with_config(tmpdir.path(), &config),
+        Err(Error::StoreSourcesMismatch)
+    ));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_sources() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_source_encryption_passphrase(Some("wordpass"));
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut before_event = EVENT.clone();
+    before_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    before_event.server_ts -= 1;
+
+    db.add_event(before_event.clone(), profile.clone());
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut search_config = SearchConfig::new();
+    search_config.before_limit(1);
+    let results = db.search("Test message", &search_config).unwrap().results;
+    assert_eq!(results.len(), 2);
+
+    let result = results
+        .iter()
+        .find(|r| r.event_source == EVENT.source)
+        .expect("The search result wasn't decrypted");
+    assert_eq!(result.events_before.len(), 1);
+    assert_eq!(result.events_before[0].source, before_event.source);
+    assert_eq!(
+        result.profile_info[&EVENT.sender].displayname.as_deref(),
+        Some("Alice")
+    );
+
+    let connection = db.get_connection().unwrap();
+    let stored: i64 = connection
+        .query_row(
+            "SELECT COUNT(*) FROM events
+             INNER JOIN profile ON profile.id = events.profile_id
+             WHERE source LIKE '%Test message%' OR displayname = 'Alice'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(stored, 0);
+
+    let mut dump = Vec::new();
+    connection.dump_events(&mut dump).unwrap();
+    let line: serde_json::Value =
+        serde_json::from_str(String::from_utf8(dump).unwrap().lines().last().unwrap()).unwrap();
+    assert_eq!(line["event"]["event_id"], EVENT.event_id.as_str());
+    assert_eq!(line["profile"]["displayname"], "Alice");
+
+    // Profiles are still deduplicated.
+    let profiles: i64 = connection
+        .query_row("SELECT COUNT(*) FROM profile", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(profiles, 1);
+
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
+    let wrong_config = Config::new().set_source_encryption_passphrase(Some("password"));
+    assert!(matches!(
+        Database::new_with_config(tmpdir.path(), &wrong_config),
+        Err(Error::WrongPassphrase)
+    ));
+    assert!(matches!(
+        Database::new(tmpdir.path()),
+        Err(Error::WrongPassphrase)
+    ));
+    Database::new_with_config(tmpdir.path(), &config).unwrap();
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_sources_for_an_existing_database() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+    db.shutdown().recv().unwrap().unwrap();
+
+    let config = Config::new().set_source_encryption_passphrase(Some("wordpass"));
+    assert!(matches!(
+        Database::new_with_config(tmpdir.path(), &config),
+        Err(Error::DatabaseOpenError(_))
+    ));
+}
+
+#[test]
+fn dump_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut other_room_event = EVENT.clone();
+    other_room_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    other_room_event.room_id = "!a_room:localhost".to_string();
+    other_room_event.source = "{ not json".to_string();
+
+    db.add_event(TOPIC_EVENT.clone(), profile.clone());
+    db.add_event(EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+    db.add_event(other_room_event.clone(), profile);
+    db.commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    let mut dump = Vec::new();
+    assert_eq!(connection.dump_events(&mut dump).unwrap(), 3);
+
+    let lines: Vec<serde_json::Value> = String::from_utf8(dump)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+
+    // Rooms are sorted by their id, the malformed event is reported but
+    // doesn't abort the dump.
+    assert_eq!(lines[0]["room_id"], "!a_room:localhost");
+    assert_eq!(lines[0]["event_id"], other_room_event.event_id.as_str());
+    assert!(lines[0]["error"].is_string());
+
+    // Events of a room are sorted by their timestamp.
+    let event: serde_json::Value = serde_json::from_str(&EVENT.source).unwrap();
+    assert_eq!(lines[1]["event"], event);
+    assert_eq!(lines[1]["profile"]["displayname"], "Alice");
+    assert_eq!(lines[1]["indexed"], true);
+    assert_eq!(lines[2]["event"]["type"], "m.room.topic");
+
+    // A profile that can't be read doesn't abort the dump either.
+    connection
+        .execute("UPDATE profile SET displayname = x'ff'", [])
+        .unwrap();
+
+    let mut dump = Vec::new();
+    assert_eq!(connection.dump_events(&mut dump).unwrap(), 3);
+
+    let line: serde_json::Value =
+        serde_json::from_str(String::from_utf8(dump).unwrap().lines().nth(1).unwrap()).unwrap();
+    assert_eq!(line["event"], event);
+    assert!(line["profile"]["displayname"].is_null());
+}
+
+#[test]
+fn empty_search_terms() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    for term in &["", "   ", "!!!", " ?! "] {
+        assert!(matches!(
+            db.search(term, &SearchConfig::new()),
+            Err(Error::EmptySearchTerm)
+        ));
+    }
+
+    let result = db.search("  test  ", &SearchConfig::new()).unwrap();
+    assert_eq!(result.results.len(), 1);
+}
+
+#[test]
+fn search_highlights() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db
+        .search("test Message nonexistent", &SearchConfig::new())
+        .unwrap();
+    assert_eq!(result.results.len(), 2);
+
+    for result in &result.results {
+        if result.event_source == EVENT.source {
+            assert_eq!(result.highlights, vec!["Message", "test"]);
+        } else {
+            assert_eq!(result.highlights, vec!["test"]);
+        }
+    }
+
+    let mut highlights = result.highlights;
+    highlights.sort();
+    assert_eq!(highlights, vec!["Message", "test"]);
+}
+
+#[test]
+fn normalized_search_scores() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let raw = db.search("test Message", &SearchConfig::new()).unwrap();
+    assert_eq!(raw.results.len(), 2);
+    assert!(raw.results.iter().all(|r| r.raw_score.is_none()));
+
+    let normalized = db
+        .search("test Message", SearchConfig::new().normalize_scores(true))
+        .unwrap();
+    assert_eq!(normalized.results.len(), 2);
+
+    let max_score = raw.results.iter().map(|r| r.score).fold(0.0, f32::max);
+
+    for (raw, normalized) in raw.results.iter().zip(&normalized.results) {
+        assert_eq!(normalized.raw_score, Some(raw.score));
+        assert_eq!(normalized.score, raw.score / max_score);
+        assert!(normalized.score > 0.0 && normalized.score <= 1.0);
+    }
+
+    assert!(normalized.results.iter().any(|r| r.score == 1.0));
+}
+
+#[test]
+fn search_timing() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap();
+    assert!(result.timing.is_none());
+
+    let result = db
+        .search("test", SearchConfig::new().include_timing(true))
+        .unwrap();
+    let timing = result.timing.unwrap();
+
+    assert!(timing.index_millis >= 0.0);
+    assert!(timing.context_millis >= 0.0);
+    assert!((timing.total_millis - timing.index_millis - timing.context_millis).abs() < 1e-6);
+
+    let result = db
+        .search("nonexistent", SearchConfig::new().include_timing(true))
+        .unwrap();
+    assert!(result.timing.is_some());
+}
+
+#[test]
+fn search_history() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
+
+    assert!(connection.load_recent_searches(10).unwrap().is_empty());
+
+    for term in &["first", "second", " first ", "", "third"] {
+        connection.add_historic_search(term).unwrap();
+    }
+
+    assert_eq!(
+        connection.load_recent_searches(10).unwrap(),
+        vec!["third", "first", "second"]
+    );
+    assert_eq!(connection.load_recent_searches(1).unwrap(), vec!["third"]);
+
+    for i in 0..150 {
+        connection
+            .add_historic_search(&format!("term {}", i))
+            .unwrap();
+    }
+
+    let history = connection.load_recent_searches(1000).unwrap();
+    assert_eq!(history.len(), 100);
+    assert_eq!(history[0], "term 149");
+    assert_eq!(history[99], "term 50");
+
+    connection.clear_search_history().unwrap();
+    assert!(connection.load_recent_searches(10).unwrap().is_empty());
+}
+
+#[test]
+fn searches_are_recorded_if_enabled() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_record_searches(true);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("Test", SearchConfig::new().limit(1)).unwrap();
+    db.search(
+        "Test",
+        SearchConfig::new()
+            .limit(1)
+            .next_batch(result.next_batch.unwrap()),
+    )
+    .unwrap();
+    db.search("nonexistent", &SearchConfig::new()).unwrap();
+    assert!(db.search("!!!", &SearchConfig::new()).is_err());
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.load_recent_searches(10).unwrap(),
+        vec!["nonexistent", "Test"]
+    );
+
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    db.search("Test", &SearchConfig::new()).unwrap();
+    let connection = db.get_connection().unwrap();
+    assert!(connection.load_recent_searches(10).unwrap().is_empty());
+}
+
+#[test]
+fn save_the_event_multithreaded() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.commit().unwrap();
+    db.reload().unwrap();
+
+    let events = Database::load_events(
+        &db.connection.lock().unwrap(),
+        &[
+            (1.0, "$15163622445EBvZJ:localhost".to_string()),
+            (0.3, "$FAKE".to_string()),
+        ],
+        &HashMap::new(),
+        0,
+        0,
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(*EVENT.source, events[0].event_source)
+}
+
+#[test]
+fn load_a_profile() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let profile = Profile::new("Alice", "");
+    let user_id = "@alice.example.org";
+    let profile_id =
+        Database::save_profile(&db.connection.lock().unwrap(), user_id, &profile).unwrap();
+
+    let loaded_profile =
+        Database::load_profile(&db.connection.lock().unwrap(), profile_id).unwrap();
+
+    assert_eq!(profile, loaded_profile);
+}
+
+#[test]
+fn load_event_context() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+
+    let mut before_event = None;
+
+    for i in 1..6 {
+        let mut event: Event = Faker.fake();
+        event.server_ts = EVENT.server_ts - i;
+        event.source = format!("Hello before event {}", i);
+
+        if before_event.is_none() {
+            before_event = Some(event.clone());
+        }
+
+        db.add_event(event, profile.clone());
+    }
+
+    let mut after_event = None;
+
+    for i in 1..6 {
+        let mut event: Event = Faker.fake();
+        event.server_ts = EVENT.server_ts + i;
+        event.source = format!("Hello after event {}", i);
+
+        if after_event.is_none() {
+            after_event = Some(event.clone());
+        }
+
+        db.add_event(event, profile.clone());
+    }
+
+    db.commit().unwrap();
+
+    for i in 1..5 {
+        let events = Database::load_events(
+            &db.connection.lock().unwrap(),
+            &[(1.0, EVENT.event_id.clone())],
+            &HashMap::new(),
+            1,
+            1,
+            false,
+        )
+        .unwrap();
+        let (before, after) = (&events[0].events_before, &events[0].events_after);
+
+        if (before.len() != 1
+            || after.len() != 1
+            || before[0].source != before_event.as_ref().unwrap().source
+            || after[0].source != after_event.as_ref().unwrap().source)
+            && i != 10
+        {
+            thread::sleep(time::Duration::from_millis(10));
+            continue;
+        }
+
+        assert_eq!(before.len(), 1);
+        assert_eq!(before[0].source, before_event.as_ref().unwrap().source);
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].source, after_event.as_ref().unwrap().source);
+
+        return;
+    }
+}
+
+#[test]
+fn load_the_context_of_many_events() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.connection.lock().unwrap();
+
+    let rooms = ["!room1:localhost", "!room2:localhost", "!room3:localhost"];
+    let mut events = Vec::new();
+
+    for i in 0..300 {
+        let mut event: Event = Faker.fake();
+        event.room_id = rooms[i % rooms.len()].to_owned();
+        event.server_ts = EVENT.server_ts + i as i64;
+        event.sender = format!("@user{}:localhost", i);
+        event.source = format!("Event {}", i);
+
+        let mut profile = Profile::new(&format!("User {}", i), "");
+        Database::save_event(&connection, &mut event, &mut profile).unwrap();
+        events.push(event);
+    }
+
+    // Pick events throughout the rooms, including ones at the start and the
+    // end of a room where there isn't enough context.
+    let search_result: Vec<(f32, EventId)> = events
+        .iter()
+        .step_by(7)
+        .chain(events.iter().rev().take(3))
+        .enumerate()
+        .map(|(i, e)| (i as f32, e.event_id.clone()))
+        .collect();
+
+    let results =
+        Database::load_events(&connection, &search_result, &HashMap::new(), 5, 4, false).unwrap();
+    assert_eq!(results.len(), search_result.len());
+
+    let insert_id = |event: &Event| -> i64 {
+        connection
+            .query_row(
+                "SELECT id FROM events WHERE event_id = ?1",
+                [&event.event_id],
+                |row| row.get(0),
+            )
+            .unwrap()
+    };
+
+    for result in results {
+        let event = events
+            .iter()
+            .find(|e| e.source == result.event_source)
+            .unwrap();
+        let room_events: Vec<&Event> = events
+            .iter()
+            .filter(|e| e.room_id == event.room_id)
+            .collect();
+
+        // The context events are ordered by their distance to the event.
+        let before: Vec<ContextEvent> = room_events
+            .iter()
+            .rev()
+            .filter(|e| e.server_ts < event.server_ts)
+            .take(5)
+            .map(|e| ContextEvent {
+                event_id: e.event_id.clone(),
+                server_ts: e.server_ts,
+                insert_id: insert_id(e),
+                source: e.source.clone(),
+                redacted: false,
+            })
+            .collect();
+        let after: Vec<ContextEvent> = room_events
+            .iter()
+            .filter(|e| e.server_ts > event.server_ts)
+            .take(4)
+            .map(|e| ContextEvent {
+                event_id: e.event_id.clone(),
+                server_ts: e.server_ts,
+                insert_id: insert_id(e),
+                source: e.source.clone(),
+                redacted: false,
+            })
+            .collect();
+
+        assert_eq!(result.events_before, before);
+        assert_eq!(result.events_after, after);
+        assert_eq!(result.profile_info.len(), before.len() + after.len() + 1);
+    }
+}
+
+#[test]
+fn redacted_events_arent_part_of_the_context() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let bodies = ["First alpha", "Second secret", "Third omega"];
+    let events: Vec<Event> = bodies
+        .iter()
+        .enumerate()
+        .map(|(i, body)| {
+            let mut event = EVENT.clone();
+            event.event_id = format!("$context{}:localhost", i);
+            event.content_value = body.to_string();
+            event.server_ts = EVENT.server_ts + i as i64;
+            event.source = EVENT_SOURCE
+                .replace(&EVENT.event_id, &event.event_id)
+                .replace("Test message, msgtype: m.text", body);
+            event
+        })
+        .collect();
+
+    for event in &events {
+        db.add_event(event.clone(), profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    assert!(db
+        .delete_event(&events[1].event_id)
+        .recv()
+        .unwrap()
+        .unwrap());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config.before_limit(2);
+
+    let batch = db.search("omega", &config).unwrap();
+    let before = &batch.results[0].events_before;
+    assert_eq!(before.len(), 1);
+    assert_eq!(before[0].event_id, events[0].event_id);
+
+    config.include_redacted_stubs(true);
+    let batch = db.search("omega", &config).unwrap();
+    let before = &batch.results[0].events_before;
+    assert_eq!(before.len(), 2);
+    assert_eq!(before[0].event_id, events[1].event_id);
+    assert!(before[0].redacted);
+    assert!(!before[0].source.contains("secret"));
+    assert_eq!(before[1].event_id, events[0].event_id);
+    assert!(!before[1].redacted);
+
+    // Deleting the room forgets its redacted events as well.
+    assert_eq!(db.delete_room(&EVENT.room_id).recv().unwrap().unwrap(), 2);
+    let connection = db.get_connection().unwrap();
+    let stubs: i64 = connection
+        .query_row("SELECT COUNT(*) FROM redacted_events", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(stubs, 0);
+}
+
+#[test]
+fn context_events_come_from_the_room_of_the_result() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.connection.lock().unwrap();
+
+    let rooms = ["!room1:localhost", "!room2:localhost"];
+    let mut events = Vec::new();
+
+    // The rooms are busy at the same time, every pair of events shares a
+    // timestamp.
+    for i in 0..40 {
+        let mut event: Event = Faker.fake();
+        event.room_id = rooms[i % rooms.len()].to_owned();
+        event.server_ts = EVENT.server_ts + (i / 2) as i64;
+        event.source = format!("Event {}", i);
+
+        let mut profile = Profile::new("Alice", "");
+        Database::save_event(&connection, &mut event, &mut profile).unwrap();
+        events.push(event);
+    }
+
+    let mut topic = TOPIC_EVENT.clone();
+    topic.room_id = rooms[0].to_owned();
+    topic.server_ts = EVENT.server_ts + 10;
+    Database::save_event(&connection, &mut topic, &mut Profile::new("Alice", "")).unwrap();
+
+    let search_result: Vec<(f32, EventId)> = events
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (i as f32, e.event_id.clone()))
+        .collect();
+
+    let results =
+        Database::load_events(&connection, &search_result, &HashMap::new(), 3, 3, false).unwrap();
+    assert_eq!(results.len(), events.len());
+
+    let room_of = |source: &str| {
+        events
+            .iter()
+            .find(|e| e.source == source)
+            .map(|e| e.room_id.clone())
+    };
+
+    for result in results {
+        let room = room_of(&result.event_source).unwrap();
+
+        for context in result.events_before.iter().chain(&result.events_after) {
+            assert_eq!(room_of(&context.source), Some(room.clone()));
+            assert_ne!(context.source, result.event_source);
+        }
+    }
+}
+
+#[test]
+fn events_sharing_a_timestamp_paginate_without_gaps() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.connection.lock().unwrap();
+
+    // Bridges tend to give a whole batch of events the same timestamp.
+    let mut events = Vec::new();
+
+    for i in 0..25 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$file{}:localhost", i);
+        event.msgtype = Some("m.file".to_owned());
+        event.source = event.event_id.clone();
+        Database::save_event(&connection, &mut event, &mut Profile::new("Alice", "")).unwrap();
+        events.push(event);
+    }
+
+    let newest_first: Vec<EventId> = events.iter().rev().map(|e| e.event_id.clone()).collect();
+
+    let mut loaded: Vec<EventId> = Vec::new();
+
+    loop {
+        let page = Database::load_file_events(
+            &connection,
+            &EVENT.room_id,
+            4,
+            loaded.last().map(|e| e.as_str()),
+            &LoadDirection::Backwards,
+        )
+        .unwrap();
+
+        if page.is_empty() {
+            break;
+        }
+
+        loaded.extend(page.into_iter().map(|(source, _)| source));
+    }
+
+    assert_eq!(loaded, newest_first);
+
+    let forwards = Database::load_file_events(
+        &connection,
+        &EVENT.room_id,
+        100,
+        Some(&events[0].event_id),
+        &LoadDirection::Forwards,
+    )
+    .unwrap();
+    let forwards: Vec<EventId> = forwards.into_iter().map(|(source, _)| source).collect();
+    assert_eq!(
+        forwards,
+        events[1..]
+            .iter()
+            .map(|e| e.event_id.clone())
+            .collect::<Vec<_>>()
+    );
+
+    // Reindexing walks over all the events the same way.
+    let mut reindexed: Vec<EventId> = Vec::new();
+    let mut last: Option<Event> = None;
+
+    loop {
+        let page = Database::load_all_events(&connection, 4, last.as_ref()).unwrap();
+
+        if page.is_empty() {
+            break;
+        }
+
+        last = page
+            .last()
+            .map(|id| events.iter().find(|e| &e.event_id == id).unwrap().clone());
+        reindexed.extend(page);
+    }
+
+    assert_eq!(reindexed, newest_first);
+
+    // The context of an event contains the events that were stored right
+    // before and after it.
+    let results = Database::load_events(
+        &connection,
+        &[(1.0, events[10].event_id.clone())],
+        &HashMap::new(),
+        3,
+        3,
+        false,
+    )
+    .unwrap();
+    let ids = |context: &[ContextEvent]| {
+        context
+            .iter()
+            .map(|e| e.event_id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        ids(&results[0].events_before),
+        vec![
+            events[9].event_id.clone(),
+            events[8].event_id.clone(),
+            events[7].event_id.clone()
+        ]
+    );
+    assert_eq!(
+        ids(&results[0].events_after),
+        vec![
+            events[11].event_id.clone(),
+            events[12].event_id.clone(),
+            events[13].event_id.clone()
+        ]
+    );
+    assert!(results[0].events_before[0].insert_id < results[0].events_after[0].insert_id);
+    assert!(results[0]
+        .events_before
+        .windows(2)
+        .all(|w| w[0].insert_id > w[1].insert_id));
+}
+
+#[test]
+fn get_event_context() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let event = |event_id: &str, sender: &str, offset: i64| {
+        Event::new(
+            EventType::Message,
+            "Hello world",
+            Some("m.text"),
+            event_id,
+            sender,
+            EVENT.server_ts + offset,
+            &EVENT.room_id,
+            &format!("Hello event {}", event_id),
+        )
+    };
+
+    db.add_event(event("$a", "@bob:localhost", -2), Profile::new("Bob", ""));
+    // Events sharing a timestamp are ordered by their insert id.
+    db.add_event(event("$b", "@bob:localhost", -1), Profile::new("Bob", ""));
+    db.add_event(event("$c", "@bob:localhost", -1), Profile::new("Bob", ""));
+    db.add_event(
+        event("$d", "@alice:localhost", 0),
+        Profile::new("Alice", ""),
+    );
+    db.add_event(
+        event("$e", "@carol:localhost", 1),
+        Profile::new("Carol", ""),
+    );
+
+    let mut other_room = event("$f", "@dave:localhost", 1);
+    other_room.room_id = "!other_room:localhost".to_owned();
+    db.add_event(other_room, Profile::new("Dave", ""));
+    db.commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    let context = connection.get_context("$d", 2, 5).unwrap().unwrap();
+
+    assert_eq!(context.event_source, "Hello event $d");
+    let ids = |events: &[ContextEvent]| {
+        events
+            .iter()
+            .map(|e| e.event_id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // The context events are ordered by their distance to the event, the
+    // closest one first.
+    assert_eq!(ids(&context.events_before), vec!["$c", "$b"]);
+    assert_eq!(ids(&context.events_after), vec!["$e"]);
+    assert_eq!(context.events_before[0].source, "Hello event $c");
+    assert_eq!(
+        context.events_before[0].server_ts,
+        context.events_before[1].server_ts
+    );
+    assert!(context.events_after[0].server_ts > context.events_before[0].server_ts);
+    assert_eq!(context.profile_info.len(), 3);
+    assert_eq!(
+        context.profile_info["@alice:localhost"],
+        Profile::new("Alice", "")
+    );
+
+    let context = connection.get_context("$d", 0, 0).unwrap().unwrap();
+    assert!(context.events_before.is_empty());
+    assert!(context.events_after.is_empty());
+    assert_eq!(context.profile_info.len(), 1);
+
+    assert!(connection
+        .get_context("$unknown:localhost", 1, 1)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn load_the_profiles_of_context_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+
+    let event = |event_id: &str, sender: &str, offset: i64| {
+        Event::new(
+            EventType::Message,
+            "Hello world",
+            Some("m.text"),
+            event_id,
+            sender,
+            EVENT.server_ts + offset,
+            &EVENT.room_id,
+            &format!("Hello event {}", event_id),
+        )
+    };
+
+    db.add_event(
+        event("$bob2", "@bob:localhost", -2),
+        Profile::new("Bobby", ""),
+    );
+    db.add_event(
+        event("$bob1", "@bob:localhost", -1),
+        Profile::new("Bob", ""),
+    );
+    db.add_event(
+        event("$alice0", "@alice:localhost", 0),
+        Profile::new("Alice", ""),
+    );
+    db.add_event(
+        event("$carol1", "@carol:localhost", 1),
+        Profile::new("Carol", ""),
+    );
+    db.add_event(
+        event("$alice2", "@alice:localhost", 2),
+        Profile::new("Alice (away)", ""),
+    );
+    db.commit().unwrap();
+
+    let results = Database::load_events(
+        &db.connection.lock().unwrap(),
+        &[(1.0, "$alice0".to_string())],
+        &HashMap::new(),
+        2,
+        2,
+        false,
+    )
+    .unwrap();
+    let profiles = &results[0].profile_info;
+
+    assert_eq!(profiles.len(), 3);
+    assert_eq!(profiles["@alice:localhost"], Profile::new("Alice", ""));
+    assert_eq!(profiles["@bob:localhost"], Profile::new("Bob", ""));
+    assert_eq!(profiles["@carol:localhost"], Profile::new("Carol", ""));
+}
+
+#[test]
+fn save_and_load_checkpoints() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    let mut connection = db.get_connection().unwrap();
+    let transaction = connection.transaction().unwrap();
+
+    Database::replace_crawler_checkpoint(&transaction, Some(&checkpoint), None).unwrap();
+    transaction.commit().unwrap();
+
+    let checkpoints = connection.load_checkpoints().unwrap();
+
+    println!("{:?}", checkpoints);
+
+    assert!(checkpoints.contains(&checkpoint));
+
+    let new_checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "12345".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    Database::replace_crawler_checkpoint(&connection, Some(&new_checkpoint), Some(&checkpoint))
+        .unwrap();
+
+    let checkpoints = connection.load_checkpoints().unwrap();
+
+    assert!(!checkpoints.contains(&checkpoint));
+    assert!(checkpoints.contains(&new_checkpoint));
+}
+
+#[test]
+fn checkpoint_validation() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let checkpoint = CrawlerCheckpoint::new(" !test:room", "1234\n").unwrap();
+    assert_eq!(checkpoint.room_id, "!test:room");
+    assert_eq!(checkpoint.token, "1234");
+    assert_eq!(checkpoint.direction, CheckpointDirection::Backwards);
+
+    let invalid = |room_id: &str, token: &str| match CrawlerCheckpoint::new(room_id, token) {
+        Err(Error::InvalidCheckpoint(e)) => e,
+        _ => panic!("The checkpoint should have been rejected"),
+    };
+
+    assert_eq!(
+        invalid("!test:room", ""),
+        CheckpointValidationError::EmptyToken
+    );
+    assert_eq!(
+        invalid("!test:room", " \n"),
+        CheckpointValidationError::EmptyToken
+    );
+    assert_eq!(
+        invalid("!test:room", "12 34"),
+        CheckpointValidationError::InvalidToken("12 34".to_owned())
+    );
+    assert_eq!(
+        invalid("test:room", "1234"),
+        CheckpointValidationError::InvalidRoomId("test:room".to_owned())
+    );
+    assert_eq!(
+        invalid("", "1234"),
+        CheckpointValidationError::InvalidRoomId("".to_owned())
+    );
+    assert_eq!(
+        invalid("!test:room", &"a".repeat(5000)),
+        CheckpointValidationError::TokenTooLong(5000)
+    );
+
+    // Invalid checkpoints are refused before anything is written.
+    let mut empty_token = checkpoint.clone();
+    empty_token.token = "".to_owned();
+    let ret = db
+        .add_historic_events(
+            vec![(EVENT.clone(), Profile::new("Alice", ""))],
+            Some(empty_token),
+            None,
+        )
+        .recv()
+        .unwrap();
+    assert_eq!(ret.unwrap_err().kind(), ErrorKind::InvalidCheckpoint);
+
+    // Checkpoints that only differ by whitespace are the same checkpoint.
+    let mut padded = checkpoint.clone();
+    padded.token = " 1234 ".to_owned();
+    db.add_historic_events(Vec::new(), Some(padded.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+    db.add_historic_events(Vec::new(), Some(checkpoint.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.load_checkpoints().unwrap(),
+        vec![checkpoint.clone()]
+    );
+    assert!(connection.is_empty().unwrap());
+
+    db.add_historic_events(Vec::new(), None, Some(padded))
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert!(connection.load_checkpoints().unwrap().is_empty());
+}
+
+#[test]
+fn historic_events_report_new_events() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let events: Vec<(Event, Profile)> = (0..10).map(|_| (Faker.fake(), profile.clone())).collect();
+
+    let add = |events: Vec<(Event, Profile)>| {
+        db.add_historic_events(events, None, None)
+            .recv()
+            .unwrap()
+            .unwrap()
+    };
+
+    assert!(add(events.clone()));
+    assert!(!add(events.clone()));
+
+    let mut partially_known = events[..5].to_vec();
+    partially_known.push((Faker.fake(), profile.clone()));
+    assert!(add(partially_known));
+
+    // An empty chunk shouldn't stop the crawler.
+    assert!(add(vec![]));
+}
+
+#[test]
+fn repeated_checkpoints_are_stored_once() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    for _ in 0..3 {
+        db.add_historic_events(vec![], Some(checkpoint.clone()), None)
+            .recv()
+            .unwrap()
+            .unwrap();
+    }
+
+    db.add_historic_events(vec![], Some(checkpoint.clone()), Some(checkpoint.clone()))
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let checkpoints = db.get_connection().unwrap().load_checkpoints().unwrap();
+    assert_eq!(checkpoints, vec![checkpoint.clone()]);
+
+    let full_crawl = CrawlerCheckpoint {
+        full_crawl: true,
+        ..checkpoint
+    };
+
+    db.add_historic_events(vec![], Some(full_crawl.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let checkpoints = db.get_connection().unwrap().load_checkpoints().unwrap();
+    assert_eq!(checkpoints, vec![full_crawl]);
+}
+
+#[test]
+fn bulk_import() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: true,
+        direction: CheckpointDirection::Backwards,
+    };
+    let new_checkpoint = CrawlerCheckpoint {
+        token: "5678".to_string(),
+        ..checkpoint.clone()
+    };
+
+    db.add_historic_events(vec![], Some(checkpoint.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let chunk =
+        || -> Vec<(Event, Profile)> { (0..10).map(|_| (Faker.fake(), profile.clone())).collect() };
+
+    // An abandoned import keeps the old checkpoint around.
+    let import = db.start_bulk_import();
+    assert!(import.add_chunk(chunk()).recv().unwrap().unwrap());
+    drop(import);
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(
+        connection.load_checkpoints().unwrap(),
+        vec![checkpoint.clone()]
+    );
+    assert_eq!(connection.get_stats().unwrap().event_count, 10);
+
+    let import = db.start_bulk_import();
+    import.add_chunk(chunk()).recv().unwrap().unwrap();
+    import.add_chunk(chunk()).recv().unwrap().unwrap();
+    import
+        .finish(Some(new_checkpoint.clone()), Some(checkpoint))
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(connection.load_checkpoints().unwrap(), vec![new_checkpoint]);
+    assert_eq!(connection.get_stats().unwrap().event_count, 30);
+}
+
+#[test]
+fn import_a_large_chunk() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+    let new_checkpoint = CrawlerCheckpoint {
+        token: "5678".to_string(),
+        ..checkpoint.clone()
+    };
+
+    db.add_historic_events(vec![], Some(checkpoint.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let events: Vec<(Event, Profile)> = (0..10_000)
+        .map(|_| (Faker.fake(), profile.clone()))
+        .collect();
+
+    let ret = db
+        .add_historic_events(events, Some(new_checkpoint.clone()), Some(checkpoint))
+        .recv()
+        .unwrap()
+        .unwrap();
+    assert!(ret);
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.load_checkpoints().unwrap(), vec![new_checkpoint]);
+
+    let stats = connection.get_stats().unwrap();
+    assert_eq!(stats.event_count, 10_000);
+    assert_eq!(stats.room_count, 1);
+}
+
+#[test]
+fn duplicate_empty_profiles() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile {
+        displayname: None,
+        avatar_url: None,
+    };
+    let user_id = "@alice.example.org";
+
+    let first_id =
+        Database::save_profile(&db.connection.lock().unwrap(), user_id, &profile).unwrap();
+    let second_id =
+        Database::save_profile(&db.connection.lock().unwrap(), user_id, &profile).unwrap();
+
+    assert_eq!(first_id, second_id);
+
+    let connection = db.connection.lock().unwrap();
+
+    let mut stmt = connection
+        .prepare("SELECT id FROM profile WHERE user_id=?1")
+        .unwrap();
+
+    let profile_ids = stmt.query_map([user_id], |row| row.get(0)).unwrap();
+
+    let mut id_count = 0;
+
+    for row in profile_ids {
+        let _profile_id: i64 = row.unwrap();
+        id_count += 1;
+    }
+
+    assert_eq!(id_count, 1);
+}
+
+#[test]
+fn is_empty() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
+    assert!(connection.is_empty().unwrap());
+
+    let profile = Profile::new("Alice", "");
+    db.add_event(EVENT.clone(), profile);
+    db.commit().unwrap();
+    assert!(!connection.is_empty().unwrap());
+}
+
+#[test]
+fn is_empty_with_only_checkpoints() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    db.add_historic_events(Vec::new(), Some(checkpoint), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    assert!(!connection.is_empty().unwrap());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_db() {
+    let tmpdir = tempdir().unwrap();
+    let db_config = Config::new().set_passphrase("test");
+    let mut db = match Database::new_with_config(tmpdir.path(), &db_config) {
+        Ok(db) => db,
+        Err(e) => panic!("Coulnd't open encrypted database {}", e),
+    };
+
+    let connection = match db.get_connection() {
+        Ok(c) => c,
+        Err(e) => panic!("Could not get database connection {}", e),
+    };
+
+    assert!(
+        connection.is_empty().unwrap(),
+        "New database should be empty"
+    );
+
+    let profile = Profile::new("Alice", "");
+    db.add_event(EVENT.clone(), profile);
+
+    match db.commit() {
+        Ok(_) => (),
+        Err(e) => panic!("Could not commit events to database {}", e),
+    }
+    assert!(
+        !connection.is_empty().unwrap(),
+        "Database shouldn't be empty anymore"
+    );
+
+    drop(db);
+
+    let db = Database::new(tmpdir.path());
+    assert!(
+        db.is_err(),
+        "opening the database without a passphrase should fail"
+    );
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn change_passphrase() {
+    let tmpdir = tempdir().unwrap();
+    let db_config = Config::new().set_passphrase("test");
+    let mut db = match Database::new_with_config(tmpdir.path(), &db_config) {
+        Ok(db) => db,
+        Err(e) => panic!("Coulnd't open encrypted database {}", e),
+    };
+
+    let connection = db
+        .get_connection()
+        .expect("Could not get database connection");
+    assert!(
+        connection.is_empty().unwrap(),
+        "New database should be empty"
+    );
+
+    let profile = Profile::new("Alice", "");
+    db.add_event(EVENT.clone(), profile);
+
+    db.commit().expect("Could not commit events to database");
+    db.change_passphrase("wordpass")
+        .expect("Could not change the database passphrase");
+
+    let db_config = Config::new().set_passphrase("wordpass");
+    let db = Database::new_with_config(tmpdir.path(), &db_config)
+        .expect("Could not open database with the new passphrase");
+    let connection = db
+        .get_connection()
+        .expect("Could not get database connection");
+    assert!(
+        !connection.is_empty().unwrap(),
+        "Database shouldn't be empty anymore"
+    );
+    drop(db);
+
+    let db_config = Config::new().set_passphrase("test");
+    let db = Database::new_with_config(tmpdir.path(), &db_config);
+    assert!(
+        db.is_err(),
+        "opening the database without a passphrase should fail"
+    );
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn change_passphrase_of_unencrypted_db() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+
+    let ret = db.change_passphrase("wordpass");
+    assert!(matches!(ret, Err(Error::NotEncryptedError)));
+
+    // The database was shut down and can be opened again.
+    Database::new(tmpdir.path()).unwrap();
+}
+
+#[test]
+fn resume_committing() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    // Check that we don't have any uncommitted events.
+    assert!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    db.add_event(EVENT.clone(), profile);
+    db.commit().unwrap();
+    db.reload().unwrap();
+
+    // Now we do have uncommitted events.
+    assert!(
+        !Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    // Since the event wasn't committed to the index the search should fail.
+    assert!(db
+        .search("test", &SearchConfig::new())
+        .unwrap()
+        .results
+        .is_empty());
+
+    // Let us drop the DB to check if we're loading the uncommitted events
+    // correctly.
+    drop(db);
+    let mut counter = 0;
+    let mut db = Database::new(tmpdir.path());
+
+    // Tantivy might still be in the process of being shut down
+    // and hold on to the write lock. Meaning that opening the database might
+    // not succeed immediately. Retry a couple of times before giving up.
+    while db.is_err() {
+        counter += 1;
+        if counter > 10 {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+        db = Database::new(tmpdir.path())
+    }
+
+    let mut db = db.unwrap();
+
+    // We still have uncommitted events.
+    assert_eq!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap()).unwrap()[0].1,
+        *EVENT
+    );
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    // A forced commit gets rid of our uncommitted events.
+    assert!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+
+    // The search is now successful.
+    assert!(!result.is_empty());
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].event_source, EVENT.source);
+}
+
+#[test]
+fn queued_events_survive_a_restart() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+
+    // Drop the database without committing.
+    drop(db);
+
+    let mut counter = 0;
+    let mut db = Database::new(tmpdir.path());
+
+    // Tantivy might still be in the process of being shut down and hold on
+    // to the write lock.
+    while db.is_err() {
+        counter += 1;
+        if counter > 10 {
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+        db = Database::new(tmpdir.path())
+    }
+
+    let mut db = db.unwrap();
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 2);
+}
+
+#[test]
+fn failed_index_commit_keeps_historic_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let old_checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    let new_checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "4321".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    db.add_historic_events(vec![], Some(old_checkpoint.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    db.index.fail_commits.store(true, Ordering::SeqCst);
+
+    let ret = db
+        .add_historic_events(
+            vec![(EVENT.clone(), profile)],
+            Some(new_checkpoint.clone()),
+            Some(old_checkpoint),
+        )
+        .recv()
+        .unwrap();
+
+    // The events and the new checkpoint are durable, so the crawler gets
+    // told to continue from the new checkpoint.
+    assert!(ret.unwrap());
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.load_checkpoints().unwrap(), vec![new_checkpoint]);
+    assert_eq!(
+        Database::load_uncommitted_events(&connection).unwrap()[0].1,
+        *EVENT
+    );
+
+    db.index.fail_commits.store(false, Ordering::SeqCst);
+
+    // The failed index commit is reported by the next commit, which commits
+    // the event to the index nonetheless.
+    assert!(db.force_commit().is_err());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    assert!(Database::load_uncommitted_events(&connection)
+        .unwrap()
+        .is_empty());
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].event_source, EVENT.source);
+}
+
+#[test]
+fn writer_stops_after_repeated_failures() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    assert!(db.status().is_ok());
+
+    db.index.fail_commits.store(true, Ordering::SeqCst);
+
+    for _ in 0..3 {
+        db.add_event(Faker.fake(), profile.clone());
+        assert!(matches!(db.force_commit(), Err(Error::IndexError(_))));
+    }
+
+    assert!(matches!(db.force_commit(), Err(Error::WriterDead(_))));
+    assert!(matches!(db.status(), Err(Error::WriterDead(_))));
+
+    let ret = db.try_add_event(EVENT.clone(), profile);
+    assert!(matches!(ret, Err(Error::WriterDead(_))));
+    assert_eq!(db.queue_length(), 0);
+}
+
+#[test]
+fn delete_event_after_the_writer_died() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.index.fail_commits.store(true, Ordering::SeqCst);
+
+    for _ in 0..3 {
+        db.add_event(Faker.fake(), profile.clone());
+        assert!(matches!(db.force_commit(), Err(Error::IndexError(_))));
+    }
+
+    assert!(matches!(db.force_commit(), Err(Error::WriterDead(_))));
+
+    // Nobody replies once the writer is gone, the status reports why.
+    assert!(db.delete_event(&EVENT.event_id).recv().is_err());
+    assert!(db
+        .add_historic_events(vec![(EVENT.clone(), profile)], None, None)
+        .recv()
+        .is_err());
+    assert!(matches!(db.status(), Err(Error::WriterDead(_))));
+}
+
+#[test]
+fn writer_errors_without_receiver() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test:room".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    assert!(db.take_errors().is_empty());
+
+    db.index.fail_commits.store(true, Ordering::SeqCst);
+
+    // Nobody waits for the result of the import, the error is kept.
+    drop(db.add_historic_events(vec![(EVENT.clone(), profile)], Some(checkpoint), None));
+    db.delete_event("$unknown:localhost")
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let errors = db.take_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].operation, WriterOperation::HistoricEvents);
+    assert_eq!(errors[0].room_id.as_deref(), Some("!test:room"));
+    assert_eq!(errors[0].kind, "IndexError");
+    assert!(db.take_errors().is_empty());
+
+    // Operations whose result is received don't keep their errors.
+    db.index.fail_commits.store(false, Ordering::SeqCst);
+    assert!(db
+        .delete_event("$unknown:localhost")
+        .recv()
+        .unwrap()
+        .is_ok());
+    assert!(db.take_errors().is_empty());
+}
+
+#[test]
+fn auto_commit_errors_are_kept() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_auto_commit_after_events(Some(1));
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.index.fail_commits.store(true, Ordering::SeqCst);
+    db.add_event(EVENT.clone(), profile);
+    db.delete_event("$unknown:localhost")
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    let errors = db.take_errors();
+    assert!(!errors.is_empty());
+    assert_eq!(errors[0].operation, WriterOperation::AutoCommit);
+    assert_eq!(errors[0].room_id, None);
+}
+
+#[test]
+fn concurrent_checkpoint_loading() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let connection = db.get_connection().unwrap();
+            let done = done.clone();
+
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    connection.load_checkpoints().unwrap();
+                }
+            })
+        })
+        .collect();
+
+    let mut old_checkpoint = None;
+
+    for i in 0..50 {
+        let events = (0..20)
+            .map(|_| (Faker.fake::<Event>(), profile.clone()))
+            .collect();
+
+        let checkpoint = CrawlerCheckpoint {
+            room_id: "!test_room:localhost".to_string(),
+            token: format!("token_{}", i),
+            full_crawl: false,
+            direction: CheckpointDirection::Backwards,
+        };
+
+        db.add_historic_events(events, Some(checkpoint.clone()), old_checkpoint)
+            .recv()
+            .unwrap()
+            .unwrap();
+
+        old_checkpoint = Some(checkpoint);
+    }
+
+    done.store(true, Ordering::SeqCst);
+
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.load_checkpoints().unwrap().len(), 1);
+}
+
+#[test]
+fn exhausted_connection_pool() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new()
+        .set_connection_pool_size(5)
+        .set_connection_timeout(Duration::from_millis(100));
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    let connections: Vec<_> = (0..3).map(|_| db.get_connection().unwrap()).collect();
+    assert!(matches!(db.get_connection(), Err(Error::PoolTimeout)));
+
+    drop(connections);
+    db.get_connection().unwrap().load_checkpoints().unwrap();
+}
+
+#[test]
+fn many_concurrent_checkpoint_loads() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_connection_pool_size(5);
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test_room:localhost".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    db.add_historic_events(vec![], Some(checkpoint.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    // Only three connections are free at a time, getting a connection waits
+    // until one of the readers returns its connection to the pool.
+    let readers: Vec<_> = (0..100)
+        .map(|_| {
+            let connection = db.get_connection().unwrap();
+
+            thread::spawn(move || connection.load_checkpoints().unwrap())
+        })
+        .collect();
+
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), vec![checkpoint.clone()]);
+    }
+}
 
-        Ok(Connection {
-            inner: connection,
-            path: self.path.clone(),
+#[test]
+fn connections_taken_on_other_threads() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_connection_pool_size(5);
+    let db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    let checkpoint = CrawlerCheckpoint {
+        room_id: "!test_room:localhost".to_string(),
+        token: "1234".to_string(),
+        full_crawl: false,
+        direction: CheckpointDirection::Backwards,
+    };
+
+    db.add_historic_events(vec![], Some(checkpoint.clone()), None)
+        .recv()
+        .unwrap()
+        .unwrap();
+
+    // The readers share the connections of the database, none of them opens
+    // a connection of its own.
+    let readers: Vec<_> = (0..500)
+        .map(|_| {
+            let pool = db.get_connection_pool();
+
+            thread::spawn(move || pool.get().unwrap().load_checkpoints().unwrap())
         })
-    }
+        .collect();
 
-    /// Shut the database down.
-    ///
-    /// This will terminate the writer thread making sure that no writes will
-    /// happen after this operation.
-    pub fn shutdown(self) -> Receiver<Result<()>> {
-        let (sender, receiver): (_, Receiver<Result<()>>) = channel();
-        let message = ThreadMessage::ShutDown(sender);
-        self.tx.send(message).unwrap();
-        receiver
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), vec![checkpoint.clone()]);
     }
 
-    /// Delete the database.
-    /// Warning: This will delete the whole path that was provided at the
-    /// database creation time.
-    pub fn delete(self) -> Result<()> {
-        fs::remove_dir_all(self.path)?;
-        Ok(())
+    db.get_connection().unwrap().load_checkpoints().unwrap();
+}
+
+#[test]
+fn bounded_write_queue() {
+    let tmpdir = tempdir().unwrap();
+    let capacity = 50;
+    let config = Config::new().set_write_queue_capacity(capacity);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..2000 {
+        let mut event: Event = Faker.fake();
+        event.server_ts += i;
+        db.add_event(event, profile.clone());
+
+        // The channel holds at most `capacity` events, the writer stores them
+        // one by one.
+        assert!(db.queue_length() <= capacity + 1);
     }
+
+    db.force_commit().unwrap();
+    assert_eq!(db.queue_length(), 0);
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 2000);
 }
 
 #[test]
-fn create_event_db() {
+fn try_add_event_while_an_add_waits_for_the_queue() {
     let tmpdir = tempdir().unwrap();
-    let _db = Database::new(tmpdir.path()).unwrap();
+    let config = Config::new().set_write_queue_capacity(1);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let event_with_id = |event_id: &str| {
+        let mut event = EVENT.clone();
+        event.event_id = event_id.to_owned();
+        event
+    };
+
+    db.add_event(EVENT.clone(), profile.clone()).unwrap();
+    db.force_commit().unwrap();
+
+    // Keep the writer thread busy until the queue was checked.
+    let (entered_sender, entered) = channel();
+    let (release, released) = channel::<()>();
+    let deletion = db.delete_sender_with_progress(&EVENT.sender, move |_| {
+        entered_sender.send(()).unwrap();
+        released.recv().unwrap();
+    });
+    entered.recv().unwrap();
+
+    let queued = db.add_event(event_with_id("$1"), profile.clone()).unwrap();
+
+    let sender = db.get_event_sender();
+    let waiting_profile = profile.clone();
+    let waiting = thread::spawn(move || sender.send(event_with_id("$2"), waiting_profile));
+    thread::sleep(time::Duration::from_millis(100));
+
+    // The waiting add doesn't block adds that shouldn't block.
+    let ret = db.try_add_event(event_with_id("$3"), profile);
+    assert!(matches!(ret, Err(Error::QueueFull)));
+    assert_eq!(db.last_queued_opstamp(), queued);
+
+    let reaction = Reaction::new("$reaction:localhost", &EVENT.event_id, &EVENT.room_id);
+    let ret = db.try_add_reaction(reaction);
+    assert!(matches!(ret, Err(Error::QueueFull)));
+    let ret = db.try_add_room_alias(RoomAlias::new(&EVENT.room_id, "#test:localhost", 10));
+    assert!(matches!(ret, Err(Error::QueueFull)));
+
+    release.send(()).unwrap();
+    assert_eq!(deletion.recv().unwrap().unwrap(), 1);
+
+    let sequence = waiting.join().unwrap().unwrap();
+    assert_eq!(db.force_commit().unwrap(), sequence);
+    assert_eq!(
+        db.get_connection()
+            .unwrap()
+            .get_stats()
+            .unwrap()
+            .event_count,
+        2
+    );
 }
 
 #[test]
-fn store_profile() {
+fn try_add_event_after_the_writer_stopped() {
     let tmpdir = tempdir().unwrap();
     let db = Database::new(tmpdir.path()).unwrap();
 
+    let (sender, receiver) = channel();
+    db.tx.send(ThreadMessage::ShutDown(sender)).unwrap();
+    receiver.recv().unwrap().unwrap();
+
+    let ret = db.try_add_event(EVENT.clone(), Profile::new("Alice", ""));
+    assert!(matches!(ret, Err(Error::WriterClosedError)));
+    assert_eq!(db.queue_length(), 0);
+}
+
+#[test]
+fn add_a_batch_of_events() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
     let profile = Profile::new("Alice", "");
 
-    let id = Database::save_profile(
-        &db.connection.lock().unwrap(),
-        "@alice.example.org",
-        &profile,
-    );
-    assert_eq!(id.unwrap(), 1);
+    let events: Vec<(Event, Profile)> = (0..10).map(|_| (Faker.fake(), profile.clone())).collect();
 
-    let id = Database::save_profile(
-        &db.connection.lock().unwrap(),
-        "@alice.example.org",
-        &profile,
+    db.add_events(events);
+    db.get_event_sender()
+        .send_batch(vec![(EVENT.clone(), profile)])
+        .unwrap();
+    db.force_commit().unwrap();
+    assert_eq!(db.queue_length(), 0);
+
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 11);
+}
+
+#[test]
+fn add_events_using_an_event_sender() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+    let sender = db.get_event_sender();
+
+    thread::spawn(move || sender.send(EVENT.clone(), profile))
+        .join()
+        .unwrap()
+        .unwrap();
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].event_source, EVENT.source);
+
+    let (sender, receiver) = channel();
+    db.tx.send(ThreadMessage::ShutDown(sender)).unwrap();
+    receiver.recv().unwrap().unwrap();
+
+    let ret = db
+        .get_event_sender()
+        .send(EVENT.clone(), Profile::new("Alice", ""));
+    assert!(matches!(ret, Err(Error::WriterClosedError)));
+    assert_eq!(db.queue_length(), 0);
+}
+
+#[test]
+fn delete_uncommitted() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 1..1000 {
+        let mut event: Event = Faker.fake();
+        event.server_ts += i;
+        db.add_event(event, profile.clone());
+
+        if i % 100 == 0 {
+            db.commit().unwrap();
+        }
+    }
+
+    db.force_commit().unwrap();
+    assert!(
+        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
     );
-    assert_eq!(id.unwrap(), 1);
+}
 
-    let profile_new = Profile::new("Alice", "mxc://some_url");
+#[test]
+fn stats_getting() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    for i in 0..1000 {
+        let mut event: Event = Faker.fake();
+        event.server_ts += i;
+        db.add_event(event, profile.clone());
+    }
+
+    db.commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+
+    let stats = connection.get_stats().unwrap();
+
+    assert_eq!(stats.event_count, 1000);
+    assert_eq!(stats.room_count, 1);
+    assert!(stats.size > 0);
+}
+
+#[test]
+fn source_cache() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_auto_reload(false);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let mut other_event = EVENT.clone();
+    other_event.event_id = "$15163622445EBvZK:localhost".to_owned();
+    other_event.source = other_event
+        .source
+        .replace(&EVENT.event_id, &other_event.event_id);
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(other_event.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let stats = || db.get_connection().unwrap().get_stats().unwrap();
+
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.results.len(), 2);
+    assert_eq!(stats().source_cache_hits, 0);
+    assert_eq!(stats().source_cache_misses, 2);
+
+    let cached = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(cached.results, result.results);
+    assert_eq!(stats().source_cache_hits, 2);
+    assert_eq!(stats().source_cache_misses, 2);
+
+    // The index still contains the deleted event until it's reloaded, its
+    // source must not be served from the cache anymore.
+    db.delete_event(&EVENT.event_id).recv().unwrap().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.results.len(), 1);
+    assert_eq!(result.results[0].event_source, other_event.source);
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    let result = db.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, other_event.source);
+}
+
+#[test]
+fn metrics() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    assert_eq!(db.get_metrics(), DatabaseMetrics::default());
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    db.search("message", &SearchConfig::new()).unwrap();
+    assert!(db.search("...", &SearchConfig::new()).is_err());
+    db.delete_event(&EVENT.event_id).recv().unwrap().unwrap();
+
+    let metrics = db.get_metrics();
+    assert_eq!(metrics.events_added, 2);
+    assert_eq!(metrics.events_deleted, 1);
+    assert_eq!(metrics.commits, 1);
+    assert_eq!(metrics.searches, 1);
+    assert_eq!(metrics.search_errors, 1);
+    assert_eq!(metrics.source_cache_misses, 1);
+
+    // Searches only update atomic counters, reading the metrics concurrently
+    // doesn't block them.
+    let searcher = db.get_searcher();
+    let search_thread = thread::spawn(move || {
+        for _ in 0..100 {
+            searcher.search("message", &SearchConfig::new()).unwrap();
+        }
+    });
+
+    while !search_thread.is_finished() {
+        db.get_metrics();
+    }
+    search_thread.join().unwrap();
+
+    assert_eq!(db.get_metrics().searches, 101);
+}
+
+#[test]
+fn search_timeout() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let events: Vec<(Event, Profile)> = (0..100)
+        .map(|i| {
+            let mut event = EVENT.clone();
+            event.event_id = format!("${}:localhost", i);
+            (event, profile.clone())
+        })
+        .collect();
+    db.add_events(events);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut config = SearchConfig::new();
+    config.limit(100);
+
+    let result = db.search("Test", &config).unwrap();
+    assert_eq!(result.results.len(), 100);
+    assert!(!result.timed_out);
+
+    // A search that runs out of time returns a partial result instead of an
+    // error.
+    let result = db
+        .search("Test", config.timeout(Duration::from_nanos(1)))
+        .unwrap();
+    assert!(result.timed_out);
+    assert!(result.results.len() < 100);
+}
+
+#[test]
+fn cancelled_search() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+    let config = SearchConfig::new();
+
+    let token = CancellationToken::new();
+    token.clone().cancel();
+    assert!(token.is_cancelled());
+    let ret = searcher.search_cancellable("Test", &config, &token);
+    assert!(matches!(ret, Err(Error::SearchCancelled)));
+
+    // Cancelling a finished search doesn't affect its results.
+    let token = CancellationToken::new();
+    let result = searcher
+        .search_cancellable("Test", &config, &token)
+        .unwrap();
+    token.cancel();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, EVENT.source);
+}
+
+#[test]
+fn language_detection() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new()
+        .set_detect_language(true)
+        .set_detected_languages(&[Language::English, Language::German]);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let bodies = [
+        "The children were playing in the beautiful gardens behind the old church",
+        "Die alten Häuser in der Stadt wurden im letzten Jahr renoviert und sind jetzt schön",
+        "ok",
+    ];
+
+    for (i, body) in bodies.iter().enumerate() {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        event.content_value = body.to_string();
+        db.add_event(event, profile.clone());
+    }
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    // The words are stemmed using the stemmer of the detected language,
+    // neither of them is stemmed by the default tokenizer.
+    let result = db.search("garden", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].matched_fields, vec!["content.body"]);
+
+    let result = db.search("Haus", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    // Messages whose language can't be detected use the default field.
+    let result = db.search("ok", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    db.shutdown().recv().unwrap().unwrap();
+
+    // The layout of the index depends on the detected languages.
+    assert!(matches!(
+        Database::new(tmpdir.path()),
+        Err(Error::ReindexError)
+    ));
+}
+
+#[test]
+fn split_identifiers() {
+    let search = |split_identifiers: bool| {
+        let tmpdir = tempdir().unwrap();
+        let config = Config::new().set_split_identifiers(split_identifiers);
+        let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+        let mut event = EVENT.clone();
+        event.content_value = "the IndexSearcher struct".to_owned();
+        db.add_event(event, Profile::new("Alice", ""));
+        db.force_commit().unwrap();
+        db.reload().unwrap();
+
+        let hits: Vec<bool> = ["index", "searcher", "IndexSearcher", "index_searcher"]
+            .iter()
+            .map(|term| db.search(term, &SearchConfig::new()).unwrap().count == 1)
+            .collect();
+
+        // Phrases that contain the identifier still match.
+        assert_eq!(
+            db.search("\"the IndexSearcher struct\"", &SearchConfig::new())
+                .unwrap()
+                .count,
+            1
+        );
+
+        db.shutdown().recv().unwrap().unwrap();
+
+        // The layout of the index depends on the setting.
+        assert!(matches!(
+            Database::new_with_config(
+                tmpdir.path(),
+                &Config::new().set_split_identifiers(!split_identifiers)
+            ),
+            Err(Error::ReindexError)
+        ));
+
+        hits
+    };
+
+    assert_eq!(search(true), vec![true, true, true, true]);
+    assert_eq!(search(false), vec![false, false, true, false]);
+}
+
+#[test]
+fn search_batch() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let searcher = db.get_searcher();
+
+    // Events that are committed after the searcher was acquired aren't
+    // found by any of the searches.
+    db.add_event(TOPIC_EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let mut room_config = SearchConfig::new();
+    room_config.for_room("!other_room:localhost");
+
+    let queries = vec![
+        ("Test".to_owned(), SearchConfig::new()),
+        ("  ".to_owned(), SearchConfig::new()),
+        ("Test".to_owned(), room_config),
+    ];
+
+    let results = searcher.search_batch(&queries);
+    assert_eq!(results.len(), 3);
+
+    let result = results[0].as_ref().unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].event_source, EVENT.source);
+    assert!(matches!(results[1], Err(Error::EmptySearchTerm)));
+    assert_eq!(results[2].as_ref().unwrap().count, 0);
+
+    let results = db.search_batch(&queries[..1]);
+    assert_eq!(results[0].as_ref().unwrap().count, 2);
+}
+
+#[test]
+fn disabled_source_cache() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_source_cache_capacity(0);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    for _ in 0..2 {
+        let result = db.search("Test", &SearchConfig::new()).unwrap();
+        assert_eq!(result.results.len(), 1);
+    }
+
+    let stats = db.get_connection().unwrap().get_stats().unwrap();
+    assert_eq!(stats.source_cache_hits, 0);
+    assert_eq!(stats.source_cache_misses, 0);
+}
+
+#[test]
+fn database_upgrade_v1() {
+    let mut path = PathBuf::from(file!());
+    path.pop();
+    path.pop();
+    path.pop();
+    path.push("data/database/v1");
+    let db = Database::new(path);
 
-    let id = Database::save_profile(
-        &db.connection.lock().unwrap(),
-        "@alice.example.org",
-        &profile_new,
-    );
-    assert_eq!(id.unwrap(), 2);
+    // Sadly the v1 database has invalid json in the source field, reindexing it
+    // won't be possible. Let's check that it's marked for a reindex.
+    match db {
+        Ok(_) => panic!("Database doesn't need a reindex."),
+        Err(e) => match e {
+            Error::ReindexError => (),
+            e => panic!("Database doesn't need a reindex: {}", e),
+        },
+    }
 }
 
 #[test]
-fn store_empty_profile() {
+fn database_upgrade_v4() {
     let tmpdir = tempdir().unwrap();
     let db = Database::new(tmpdir.path()).unwrap();
+    db.get_connection()
+        .unwrap()
+        .execute("UPDATE version SET version = '4'", [])
+        .unwrap();
+    db.shutdown().recv().unwrap().unwrap();
 
-    let profile = Profile {
-        displayname: None,
-        avatar_url: None,
-    };
-    let id = Database::save_profile(
-        &db.connection.lock().unwrap(),
-        "@alice.example.org",
-        &profile,
-    );
-    assert_eq!(id.unwrap(), 1);
+    // The sender field was added to the index in version 5.
+    assert!(matches!(
+        Database::new(tmpdir.path()),
+        Err(Error::ReindexError)
+    ));
 }
 
 #[test]
-fn store_event() {
+fn database_upgrade_v6() {
     let tmpdir = tempdir().unwrap();
     let db = Database::new(tmpdir.path()).unwrap();
-    let profile = Profile::new("Alice", "");
-    let id = Database::save_profile(
-        &db.connection.lock().unwrap(),
-        "@alice.example.org",
-        &profile,
-    )
-    .unwrap();
+    let connection = db.get_connection().unwrap();
 
-    let mut event = EVENT.clone();
-    let id = Database::save_event_helper(&db.connection.lock().unwrap(), &mut event, id).unwrap();
-    assert_eq!(id, 1);
+    // Recreate the checkpoints table of version 6, it allowed the same
+    // checkpoint to be stored with differing crawl types.
+    connection
+        .execute_batch(
+            "DROP TABLE crawlercheckpoints;
+             CREATE TABLE crawlercheckpoints (
+                 id INTEGER NOT NULL PRIMARY KEY,
+                 room_id TEXT NOT NULL,
+                 token TEXT NOT NULL,
+                 full_crawl BOOLEAN NOT NULL,
+                 direction TEXT NOT NULL,
+                 UNIQUE(room_id,token,full_crawl,direction)
+             );
+             INSERT INTO crawlercheckpoints (room_id, token, full_crawl, direction)
+             VALUES ('!test:room', '1234', 0, 'Backwards'),
+                    ('!test:room', '1234', 1, 'Backwards'),
+                    ('!test:room', '1234', 0, 'Forwards');
+             UPDATE version SET version = '6';",
+        )
+        .unwrap();
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
+    let db = Database::new(tmpdir.path()).unwrap();
+    let checkpoints = db.get_connection().unwrap().load_checkpoints().unwrap();
+
+    assert_eq!(checkpoints.len(), 2);
+    assert!(checkpoints
+        .iter()
+        .any(|c| c.direction == CheckpointDirection::Backwards && c.full_crawl));
+    assert!(checkpoints
+        .iter()
+        .any(|c| c.direction == CheckpointDirection::Forwards && !c.full_crawl));
 }
 
 #[test]
-fn store_event_and_profile() {
+fn database_upgrade_v7() {
     let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+    db.force_commit().unwrap();
+
+    let connection = db.get_connection().unwrap();
+    Database::save_profile(&connection, "@bob:example.org", &Profile::new("Bob", "")).unwrap();
+    connection
+        .execute("UPDATE version SET version = '7'", [])
+        .unwrap();
+    drop(connection);
+    db.shutdown().recv().unwrap().unwrap();
+
     let db = Database::new(tmpdir.path()).unwrap();
-    let mut profile = Profile::new("Alice", "");
-    let mut event = EVENT.clone();
-    Database::save_event(&db.connection.lock().unwrap(), &mut event, &mut profile).unwrap();
+    let connection = db.get_connection().unwrap();
+    let profiles: i64 = connection
+        .query_row("SELECT COUNT(*) FROM profile", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(profiles, 1);
 }
 
+#[cfg(test)]
+use crate::database::recovery::test::reindex_loop;
+
 #[test]
-fn load_event() {
-    let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
-    let mut profile = Profile::new("Alice", "");
+fn database_upgrade_v1_2() {
+    let mut path = PathBuf::from(file!());
+    path.pop();
+    path.pop();
+    path.pop();
+    path.push("data/database/v1_2");
+    let db = Database::new(&path);
+    match db {
+        Ok(_) => panic!("Database doesn't need a reindex."),
+        Err(e) => match e {
+            Error::ReindexError => (),
+            e => panic!("Database doesn't need a reindex: {}", e),
+        },
+    }
 
-    let mut event = EVENT.clone();
-    Database::save_event(&db.connection.lock().unwrap(), &mut event, &mut profile).unwrap();
-    let events = Database::load_events(
-        &db.connection.lock().unwrap(),
-        &[
-            (1.0, "$15163622445EBvZJ:localhost".to_string()),
-            (0.3, "$FAKE".to_string()),
-        ],
-        0,
-        0,
-        false,
-    )
-    .unwrap();
+    let mut recovery_db = RecoveryDatabase::new(&path).expect("Can't open recovery db");
 
-    assert_eq!(*EVENT.source, events[0].event_source)
+    recovery_db.delete_the_index().unwrap();
+    recovery_db.open_index().unwrap();
+
+    let events = recovery_db.load_events_deserialized(100, None).unwrap();
+
+    recovery_db.index_events(&events).unwrap();
+    reindex_loop(&mut recovery_db, events).unwrap();
+    recovery_db.commit_and_close().unwrap();
+
+    let db = Database::new(&path).expect("Can't open the db event after a reindex");
+
+    let mut connection = db.get_connection().unwrap();
+    let (version, _) = Database::get_version(&mut connection).unwrap();
+    assert_eq!(version, DATABASE_VERSION);
+
+    let result = db.search("Hello", &SearchConfig::new()).unwrap().results;
+    assert!(!result.is_empty())
 }
 
 #[test]
-fn commit_a_write() {
+fn delete_an_event() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
-    db.commit().unwrap();
+    let profile = Profile::new("Alice", "");
+
+    db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile);
+
+    db.force_commit().unwrap();
+
+    assert!(
+        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
+
+    let recv = db.delete_event(&EVENT.event_id);
+    recv.recv().unwrap().unwrap();
+
+    assert_eq!(
+        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .len(),
+        1
+    );
+
+    drop(db);
+
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(
+        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .len(),
+        1
+    );
+
+    db.force_commit().unwrap();
+    assert_eq!(
+        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .len(),
+        0
+    );
 }
 
 #[test]
-fn save_the_event_multithreaded() {
+fn delete_an_uncommitted_event() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
-    let profile = Profile::new("Alice", "");
 
-    db.add_event(EVENT.clone(), profile);
+    db.add_event(EVENT.clone(), Profile::new("Alice", ""));
+
+    // The live event is stored right away, but isn't committed to the index.
     db.commit().unwrap();
-    db.reload().unwrap();
+    assert!(
+        !Database::load_uncommitted_events(&db.connection.lock().unwrap())
+            .unwrap()
+            .is_empty()
+    );
 
-    let events = Database::load_events(
-        &db.connection.lock().unwrap(),
-        &[
-            (1.0, "$15163622445EBvZJ:localhost".to_string()),
-            (0.3, "$FAKE".to_string()),
-        ],
-        0,
-        0,
-        false,
-    )
-    .unwrap();
+    db.delete_event(&EVENT.event_id).recv().unwrap().unwrap();
 
-    assert_eq!(*EVENT.source, events[0].event_source)
+    let connection = db.get_connection().unwrap();
+    assert!(Database::load_uncommitted_events(&connection)
+        .unwrap()
+        .is_empty());
+    assert!(connection
+        .get_known_event_ids(&[EVENT.event_id.clone()])
+        .unwrap()
+        .is_empty());
+
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    assert!(result.is_empty());
 }
 
 #[test]
-fn load_a_profile() {
+fn delete_an_unknown_event() {
     let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
-
-    let profile = Profile::new("Alice", "");
-    let user_id = "@alice.example.org";
-    let profile_id =
-        Database::save_profile(&db.connection.lock().unwrap(), user_id, &profile).unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
 
-    let loaded_profile =
-        Database::load_profile(&db.connection.lock().unwrap(), profile_id).unwrap();
+    db.delete_event("$unknown:localhost")
+        .recv()
+        .unwrap()
+        .unwrap();
+    db.force_commit().unwrap();
 
-    assert_eq!(profile, loaded_profile);
+    let connection = db.get_connection().unwrap();
+    assert!(connection.is_empty().unwrap());
 }
 
 #[test]
-fn load_event_context() {
+fn delete_an_event_before_it_is_added() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
     let profile = Profile::new("Alice", "");
 
+    let deleted = db.delete_event(&EVENT.event_id).recv().unwrap().unwrap();
+    assert!(!deleted);
+
     db.add_event(EVENT.clone(), profile.clone());
+    db.add_event(TOPIC_EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    let mut before_event = None;
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 0);
 
-    for i in 1..6 {
-        let mut event: Event = Faker.fake();
-        event.server_ts = EVENT.server_ts - i;
-        event.source = format!("Hello before event {}", i);
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 1);
+    let pending: i64 = connection
+        .query_row("SELECT COUNT(*) FROM pending_deletions", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(pending, 0);
 
-        if before_event.is_none() {
-            before_event = Some(event.clone());
-        }
+    // The deletion was used up, the event can be added again.
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    let result = db.search("message", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+
+    let deleted = db.delete_event(&EVENT.event_id).recv().unwrap().unwrap();
+    assert!(deleted);
+}
+
+#[test]
+fn add_events_with_null_byte() {
+    let event_source: &str = r#"{
+        "content": {
+            "body": "\u00000",
+            "msgtype": "m.text"
+        },
+        "event_id": "$15163622448EBvZJ:localhost",
+        "origin_server_ts": 1516362244050,
+        "sender": "@example2:localhost",
+        "type": "m.room.message",
+        "unsigned": {"age": 43289803098},
+        "user_id": "@example2:localhost",
+        "age": 43289803098,
+        "room_id": "!test:example.org"
+    }"#;
+
+    let event = RecoveryDatabase::event_from_json(event_source).unwrap();
+
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", &event.content_value);
+
+    let events = vec![(event, profile)];
+    db.add_historic_events(events, None, None)
+        .recv()
+        .unwrap()
+        .expect("Event should be added");
+}
 
-        db.add_event(event, profile.clone());
-    }
+#[test]
+fn search_in_a_thread() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
 
-    let mut after_event = None;
+    let reply_source = |event_id: &str| {
+        format!(
+            r#"{{
+                "content": {{
+                    "body": "Test reply",
+                    "msgtype": "m.text",
+                    "m.relates_to": {{
+                        "rel_type": "m.thread",
+                        "event_id": "{}"
+                    }}
+                }},
+                "event_id": "{}",
+                "origin_server_ts": 1516362244050,
+                "sender": "@example2:localhost",
+                "type": "m.room.message",
+                "room_id": "{}"
+            }}"#,
+            EVENT.event_id, event_id, EVENT.room_id
+        )
+    };
 
-    for i in 1..6 {
-        let mut event: Event = Faker.fake();
-        event.server_ts = EVENT.server_ts + i;
-        event.source = format!("Hello after event {}", i);
+    db.add_event(EVENT.clone(), profile.clone());
 
-        if after_event.is_none() {
-            after_event = Some(event.clone());
-        }
+    for i in 0..3 {
+        let reply =
+            RecoveryDatabase::event_from_json(&reply_source(&format!("$reply{}", i))).unwrap();
+        assert_eq!(reply.thread_id.as_ref(), Some(&EVENT.event_id));
+        db.add_event(reply, profile.clone());
 
+        let mut event: Event = Faker.fake();
+        event.content_value = "Test message".to_string();
         db.add_event(event, profile.clone());
     }
 
     db.commit().unwrap();
+    db.reload().unwrap();
 
-    for i in 1..5 {
-        let (before, after, _) =
-            Database::load_event_context(&db.connection.lock().unwrap(), &EVENT, 1, 1).unwrap();
-
-        if (before.len() != 1
-            || after.len() != 1
-            || before[0] != before_event.as_ref().unwrap().source
-            || after[0] != after_event.as_ref().unwrap().source)
-            && i != 10
-        {
-            thread::sleep(time::Duration::from_millis(10));
-            continue;
-        }
+    let result = db.search("Test", &SearchConfig::new()).unwrap().results;
+    assert_eq!(result.len(), 7);
 
-        assert_eq!(before.len(), 1);
-        assert_eq!(before[0], before_event.as_ref().unwrap().source);
-        assert_eq!(after.len(), 1);
-        assert_eq!(after[0], after_event.as_ref().unwrap().source);
+    let result = db
+        .search("Test", SearchConfig::new().for_thread(&EVENT.event_id))
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 3);
+    assert!(result.iter().all(|r| r.event_source.contains("m.thread")));
 
-        return;
-    }
+    let reply =
+        Database::load_event(&db.get_connection().unwrap(), &EVENT.room_id, "$reply0").unwrap();
+    assert_eq!(reply.thread_id.as_ref(), Some(&EVENT.event_id));
 }
 
 #[test]
-fn save_and_load_checkpoints() {
+fn is_room_indexed() {
     let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
 
-    let checkpoint = CrawlerCheckpoint {
-        room_id: "!test:room".to_string(),
-        token: "1234".to_string(),
-        full_crawl: false,
-        direction: CheckpointDirection::Backwards,
-    };
+    let connection = db.get_connection().unwrap();
 
-    let mut connection = db.get_connection().unwrap();
-    let transaction = connection.transaction().unwrap();
+    assert!(connection.is_empty().unwrap());
+    assert!(!connection.is_room_indexed("!test_room:localhost").unwrap());
 
-    Database::replace_crawler_checkpoint(&transaction, Some(&checkpoint), None).unwrap();
-    transaction.commit().unwrap();
+    let profile = Profile::new("Alice", "");
+    db.add_event(EVENT.clone(), profile);
+    db.force_commit().unwrap();
 
-    let checkpoints = connection.load_checkpoints().unwrap();
+    assert!(connection.is_room_indexed("!test_room:localhost").unwrap());
+    assert!(!connection.is_room_indexed("!test_room2:localhost").unwrap());
+}
 
-    println!("{:?}", checkpoints);
+#[test]
+fn room_info() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
+    let profile = Profile::new("Alice", "");
 
-    assert!(checkpoints.contains(&checkpoint));
+    let mut new_event = EVENT.clone();
+    new_event.event_id = "$15163622445EBvZK:localhost".to_string();
+    new_event.server_ts = EVENT.server_ts + 1000;
 
-    let new_checkpoint = CrawlerCheckpoint {
-        room_id: "!test:room".to_string(),
-        token: "12345".to_string(),
-        full_crawl: false,
-        direction: CheckpointDirection::Backwards,
-    };
+    let mut other_room_event = EVENT.clone();
+    other_room_event.event_id = "$15163622446EBvZK:localhost".to_string();
+    other_room_event.room_id = "!test_room2:localhost".to_string();
 
-    Database::replace_crawler_checkpoint(&connection, Some(&new_checkpoint), Some(&checkpoint))
-        .unwrap();
+    db.add_historic_events(
+        vec![
+            (new_event.clone(), profile.clone()),
+            (EVENT.clone(), profile.clone()),
+            (other_room_event.clone(), profile),
+        ],
+        None,
+        None,
+    )
+    .recv()
+    .unwrap()
+    .unwrap();
 
-    let checkpoints = connection.load_checkpoints().unwrap();
+    let info = connection.get_room_info(&EVENT.room_id).unwrap();
+    assert_eq!(
+        info,
+        RoomInfo {
+            room_id: EVENT.room_id.clone(),
+            event_count: 2,
+            oldest_event_ts: Some(EVENT.server_ts),
+            newest_event_ts: Some(new_event.server_ts),
+        }
+    );
 
-    assert!(!checkpoints.contains(&checkpoint));
-    assert!(checkpoints.contains(&new_checkpoint));
+    let info = connection.get_room_info("!unknown:localhost").unwrap();
+    assert_eq!(info.event_count, 0);
+    assert_eq!(info.oldest_event_ts, None);
+    assert_eq!(info.newest_event_ts, None);
+
+    let infos = connection.get_all_room_infos().unwrap();
+    assert_eq!(infos.len(), 2);
+    assert!(infos.contains(&connection.get_room_info(&EVENT.room_id).unwrap()));
+    assert!(infos.contains(&RoomInfo {
+        room_id: other_room_event.room_id.clone(),
+        event_count: 1,
+        oldest_event_ts: Some(other_room_event.server_ts),
+        newest_event_ts: Some(other_room_event.server_ts),
+    }));
 }
 
 #[test]
-fn duplicate_empty_profiles() {
+fn find_similar_events() {
     let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
-    let profile = Profile {
-        displayname: None,
-        avatar_url: None,
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let message = |event_id: &str, room_id: &str, body: &str| {
+        let source = serde_json::json!({
+            "content": {"body": body, "msgtype": "m.text"},
+            "event_id": event_id,
+            "origin_server_ts": EVENT.server_ts,
+            "sender": EVENT.sender,
+            "type": "m.room.message",
+        });
+
+        Event::new(
+            EventType::Message,
+            body,
+            Some("m.text"),
+            event_id,
+            &EVENT.sender,
+            EVENT.server_ts,
+            room_id,
+            &source.to_string(),
+        )
     };
-    let user_id = "@alice.example.org";
 
-    let first_id =
-        Database::save_profile(&db.connection.lock().unwrap(), user_id, &profile).unwrap();
-    let second_id =
-        Database::save_profile(&db.connection.lock().unwrap(), user_id, &profile).unwrap();
+    let room_id = "!test_room:localhost";
+    let other_room_id = "!test_room2:localhost";
+
+    let events = vec![
+        message(
+            "$source",
+            room_id,
+            "The quick brown fox jumps over the lazy dog",
+        ),
+        message(
+            "$similar",
+            room_id,
+            "A quick brown fox was seen near the river",
+        ),
+        message("$unrelated", room_id, "Meeting notes for tomorrow"),
+        message("$other_room", other_room_id, "Brown foxes are quick"),
+        message("$short", room_id, "Hi"),
+    ];
+
+    for event in events {
+        db.add_event(event, profile.clone());
+    }
 
-    assert_eq!(first_id, second_id);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    let connection = db.connection.lock().unwrap();
+    let results = db.find_similar("$source", &SearchConfig::new()).unwrap();
+    let event_ids: Vec<String> = results
+        .iter()
+        .map(|r| {
+            let source: serde_json::Value = serde_json::from_str(&r.event_source).unwrap();
+            source["event_id"].as_str().unwrap().to_owned()
+        })
+        .collect();
 
-    let mut stmt = connection
-        .prepare("SELECT id FROM profile WHERE user_id=?1")
+    assert_eq!(event_ids.len(), 2);
+    assert!(event_ids.contains(&"$similar".to_owned()));
+    assert!(event_ids.contains(&"$other_room".to_owned()));
+
+    let results = db
+        .find_similar("$source", SearchConfig::new().for_room(room_id))
         .unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].event_source.contains("$similar"));
 
-    let profile_ids = stmt.query_map([user_id], |row| row.get(0)).unwrap();
+    assert!(db
+        .find_similar("$short", &SearchConfig::new())
+        .unwrap()
+        .is_empty());
 
-    let mut id_count = 0;
+    assert!(matches!(
+        db.find_similar("$unknown", &SearchConfig::new()),
+        Err(Error::EventNotFound(_))
+    ));
+}
 
-    for row in profile_ids {
-        let _profile_id: i64 = row.unwrap();
-        id_count += 1;
-    }
+#[test]
+fn user_version() {
+    let tmpdir = tempdir().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
 
-    assert_eq!(id_count, 1);
+    assert_eq!(connection.get_user_version().unwrap(), 0);
+    connection.set_user_version(10).unwrap();
+    assert_eq!(connection.get_user_version().unwrap(), 10);
 }
 
 #[test]
-fn is_empty() {
+fn user_version_survives_a_reopen() {
     let tmpdir = tempdir().unwrap();
-    let mut db = Database::new(tmpdir.path()).unwrap();
-    let connection = db.get_connection().unwrap();
-    assert!(connection.is_empty().unwrap());
+    let db = Database::new(tmpdir.path()).unwrap();
+    db.get_connection().unwrap().set_user_version(3).unwrap();
+    db.shutdown().recv().unwrap().unwrap();
 
-    let profile = Profile::new("Alice", "");
-    db.add_event(EVENT.clone(), profile);
-    db.commit().unwrap();
-    assert!(!connection.is_empty().unwrap());
+    let db = Database::new(tmpdir.path()).unwrap();
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_user_version().unwrap(), 3);
 }
 
-#[cfg(feature = "encryption")]
 #[test]
-fn encrypted_db() {
+fn version_info() {
     let tmpdir = tempdir().unwrap();
-    let db_config = Config::new().set_passphrase("test");
-    let mut db = match Database::new_with_config(tmpdir.path(), &db_config) {
-        Ok(db) => db,
-        Err(e) => panic!("Coulnd't open encrypted database {}", e),
-    };
+    let db = Database::new(tmpdir.path()).unwrap();
+    let info = db.get_version_info().unwrap();
 
-    let connection = match db.get_connection() {
-        Ok(c) => c,
-        Err(e) => panic!("Could not get database connection {}", e),
-    };
+    assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.database_version, DATABASE_VERSION);
+    assert_eq!(info.index_version, INDEX_VERSION);
 
-    assert!(
-        connection.is_empty().unwrap(),
-        "New database should be empty"
-    );
+    db.shutdown().recv().unwrap().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_version_info().unwrap(), info);
+}
 
-    let profile = Profile::new("Alice", "");
-    db.add_event(EVENT.clone(), profile);
+/// Create an event with a body of roughly `size` bytes, every word of the
+/// body is unique and the second byte of every word is in the middle of a
+/// character.
+#[cfg(test)]
+fn event_with_huge_body(size: usize) -> Event {
+    let mut body = String::with_capacity(size);
+    let mut i = 0;
 
-    match db.commit() {
-        Ok(_) => (),
-        Err(e) => panic!("Could not commit events to database {}", e),
+    while body.len() < size {
+        body.push_str(&format!("wörd{:07} ", i));
+        i += 1;
     }
-    assert!(
-        !connection.is_empty().unwrap(),
-        "Database shouldn't be empty anymore"
-    );
 
-    drop(db);
+    let mut event = EVENT.clone();
+    event.source = serde_json::json!({
+        "event_id": event.event_id,
+        "room_id": event.room_id,
+        "sender": event.sender,
+        "origin_server_ts": event.server_ts,
+        "type": "m.room.message",
+        "content": {"msgtype": "m.text", "body": body},
+    })
+    .to_string();
+    event.content_value = body;
 
-    let db = Database::new(tmpdir.path());
-    assert!(
-        db.is_err(),
-        "opening the database without a passphrase should fail"
-    );
+    event
 }
 
-#[cfg(feature = "encryption")]
 #[test]
-fn change_passphrase() {
+fn huge_event_bodies_are_truncated() {
     let tmpdir = tempdir().unwrap();
-    let db_config = Config::new().set_passphrase("test");
-    let mut db = match Database::new_with_config(tmpdir.path(), &db_config) {
-        Ok(db) => db,
-        Err(e) => panic!("Coulnd't open encrypted database {}", e),
+    // Every word is 13 bytes long, the limit ends in the middle of the
+    // "ö" of a word.
+    let config = Config::new().set_max_indexed_body_bytes(13 * 100 + 2);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+
+    // The index files live next to the Sqlite database, which stores the
+    // full source.
+    let index_size = || -> u64 {
+        fs::read_dir(tmpdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| {
+                !entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(EVENTS_DB_NAME)
+            })
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
     };
+    let size_before = index_size();
 
-    let connection = db
-        .get_connection()
-        .expect("Could not get database connection");
-    assert!(
-        connection.is_empty().unwrap(),
-        "New database should be empty"
-    );
-
-    let profile = Profile::new("Alice", "");
-    db.add_event(EVENT.clone(), profile);
+    let event = event_with_huge_body(5 * 1024 * 1024);
+    db.add_event(event.clone(), Profile::new("Alice", ""))
+        .unwrap();
+    db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    db.commit().expect("Could not commit events to database");
-    db.change_passphrase("wordpass")
-        .expect("Could not change the database passphrase");
+    // Indexing all of the 400000 unique words would take up megabytes.
+    assert!(index_size() - size_before < 1024 * 1024);
 
-    let db_config = Config::new().set_passphrase("wordpass");
-    let db = Database::new_with_config(tmpdir.path(), &db_config)
-        .expect("Could not open database with the new passphrase");
-    let connection = db
-        .get_connection()
-        .expect("Could not get database connection");
-    assert!(
-        !connection.is_empty().unwrap(),
-        "Database shouldn't be empty anymore"
-    );
-    drop(db);
+    let result = db.search("wörd0000099", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 1);
+    let result = db.search("wörd0000101", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 0);
 
-    let db_config = Config::new().set_passphrase("test");
-    let db = Database::new_with_config(tmpdir.path(), &db_config);
-    assert!(
-        db.is_err(),
-        "opening the database without a passphrase should fail"
-    );
+    // The stored source is kept as it is.
+    let connection = db.get_connection().unwrap();
+    let stored = connection
+        .get_context(&event.event_id, 0, 0)
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.event_source, event.source);
 }
 
 #[test]
-fn resume_committing() {
+fn truncate_stored_bodies() {
     let tmpdir = tempdir().unwrap();
-    let mut db = Database::new(tmpdir.path()).unwrap();
-    let profile = Profile::new("Alice", "");
+    let config = Config::new()
+        .set_max_indexed_body_bytes(13 * 100 + 2)
+        .set_truncate_stored_bodies(true);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
 
-    // Check that we don't have any uncommitted events.
-    assert!(
-        Database::load_uncommitted_events(&db.connection.lock().unwrap())
-            .unwrap()
-            .is_empty()
-    );
+    let event = event_with_huge_body(5 * 1024 * 1024);
+    db.add_event(event.clone(), Profile::new("Alice", ""))
+        .unwrap();
+    db.force_commit().unwrap();
 
-    db.add_event(EVENT.clone(), profile);
-    db.commit().unwrap();
-    db.reload().unwrap();
+    let connection = db.get_connection().unwrap();
+    let stored = connection
+        .get_context(&event.event_id, 0, 0)
+        .unwrap()
+        .unwrap();
+    let source: serde_json::Value = serde_json::from_str(&stored.event_source).unwrap();
+    let body = source["content"]["body"].as_str().unwrap();
 
-    // Now we do have uncommitted events.
-    assert!(
-        !Database::load_uncommitted_events(&db.connection.lock().unwrap())
-            .unwrap()
-            .is_empty()
+    assert_eq!(body.len(), 13 * 100 + 1);
+    assert!(event.content_value.starts_with(body));
+    assert_eq!(source["content"]["msgtype"], "m.text");
+}
+
+#[test]
+fn events_exceeding_the_maximal_size() {
+    let tmpdir = tempdir().unwrap();
+    let config = Config::new().set_max_event_size(1024 * 1024);
+    let mut db = Database::new_with_config(tmpdir.path(), &config).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let event = event_with_huge_body(5 * 1024 * 1024);
+
+    assert!(matches!(
+        db.try_add_event(event.clone(), profile.clone()),
+        Err(Error::EventTooLarge(..))
+    ));
+    assert!(matches!(
+        db.get_event_sender().send_batch(vec![
+            (EVENT.clone(), profile.clone()),
+            (event.clone(), profile.clone())
+        ]),
+        Err(Error::EventTooLarge(..))
+    ));
+    assert!(db.add_event(event.clone(), profile.clone()).is_none());
+    assert_eq!(db.queue_length(), 0);
+
+    // Events from the room history are dropped instead.
+    let mut other_event = EVENT.clone();
+    other_event.event_id = "$other".to_owned();
+    let receiver = db.add_historic_events(
+        vec![(event.clone(), profile.clone()), (other_event, profile)],
+        None,
+        None,
     );
+    assert!(receiver.recv().unwrap().unwrap());
+    db.force_commit().unwrap();
 
-    // Since the event wasn't committed to the index the search should fail.
-    assert!(db
-        .search("test", &SearchConfig::new())
+    let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_stats().unwrap().event_count, 1);
+    assert!(connection
+        .get_context(&event.event_id, 0, 0)
         .unwrap()
-        .results
-        .is_empty());
-
-    // Let us drop the DB to check if we're loading the uncommitted events
-    // correctly.
-    drop(db);
-    let mut counter = 0;
-    let mut db = Database::new(tmpdir.path());
+        .is_none());
+}
 
-    // Tantivy might still be in the process of being shut down
-    // and hold on to the write lock. Meaning that opening the database might
-    // not succeed immediately. Retry a couple of times before giving up.
-    while db.is_err() {
-        counter += 1;
-        if counter > 10 {
-            break;
-        }
-        thread::sleep(time::Duration::from_millis(100));
-        db = Database::new(tmpdir.path())
+#[test]
+fn event_builder_validation() {
+    let builder = EventBuilder::new(EventType::Message)
+        .content_value(EVENT.content_value.as_str())
+        .msgtype("m.text")
+        .event_id(EVENT.event_id.as_str())
+        .sender(EVENT.sender.as_str())
+        .server_ts(EVENT.server_ts)
+        .room_id(EVENT.room_id.as_str())
+        .source(EVENT_SOURCE);
+
+    assert_eq!(builder.clone().build().unwrap(), *EVENT);
+
+    let ret = builder
+        .clone()
+        .content_value(" \n")
+        .event_id("15163622445EBvZJ:localhost")
+        .sender("example2")
+        .server_ts(-1)
+        .room_id("")
+        .build();
+
+    match ret {
+        Err(Error::InvalidEvent(errors)) => assert_eq!(
+            errors,
+            vec![
+                EventValidationError::InvalidEventId("15163622445EBvZJ:localhost".to_owned()),
+                EventValidationError::InvalidRoomId("".to_owned()),
+                EventValidationError::InvalidSender("example2".to_owned()),
+                EventValidationError::InvalidTimestamp(-1),
+                EventValidationError::EmptyBody,
+                EventValidationError::SourceMismatch(Some(EVENT.event_id.clone())),
+            ]
+        ),
+        _ => panic!("The event should have been rejected"),
     }
 
-    let mut db = db.unwrap();
+    let ret = builder.clone().source("[]").build();
+    assert!(matches!(
+        ret,
+        Err(Error::InvalidEvent(errors)) if errors == vec![EventValidationError::InvalidSource]
+    ));
+
+    // Files without a body and cleared topics are valid.
+    assert!(builder
+        .clone()
+        .msgtype("m.file")
+        .content_value("")
+        .build()
+        .is_ok());
+
+    let ret = EventBuilder::new(EventType::Topic).build();
+    assert!(matches!(
+        ret,
+        Err(Error::InvalidEvent(errors)) if errors.len() == 6
+    ));
+}
 
-    // We still have uncommitted events.
+#[test]
+fn event_type_constructors() {
+    let event = Event::new_message(
+        &EVENT.content_value,
+        "m.text",
+        &EVENT.event_id,
+        &EVENT.sender,
+        EVENT.server_ts,
+        &EVENT.room_id,
+        EVENT_SOURCE,
+    );
+    assert_eq!(event, *EVENT);
+
+    let topic = Event::new_topic(
+        &TOPIC_EVENT.content_value,
+        &TOPIC_EVENT.event_id,
+        &TOPIC_EVENT.sender,
+        TOPIC_EVENT.server_ts,
+        &TOPIC_EVENT.room_id,
+        TOPIC_EVENT_SOURCE,
+    );
+    assert_eq!(topic, *TOPIC_EVENT);
+
+    assert_eq!(EventType::from("m.room.name"), EventType::Name);
+
+    let event_type = EventType::from("m.room.encrypted");
     assert_eq!(
-        Database::load_uncommitted_events(&db.connection.lock().unwrap()).unwrap()[0].1,
-        *EVENT
+        event_type,
+        EventType::Unknown("m.room.encrypted".to_owned())
     );
+    assert_eq!(event_type.to_string(), "m.room.encrypted");
+
+    let ret = EventBuilder::new(event_type)
+        .content_value(EVENT.content_value.as_str())
+        .event_id(EVENT.event_id.as_str())
+        .sender(EVENT.sender.as_str())
+        .server_ts(EVENT.server_ts)
+        .room_id(EVENT.room_id.as_str())
+        .source(EVENT_SOURCE)
+        .build();
+
+    assert!(matches!(
+        ret,
+        Err(Error::InvalidEvent(errors))
+            if errors == vec![EventValidationError::UnsupportedType("m.room.encrypted".to_owned())]
+    ));
+}
+
+#[test]
+fn only_latest_room_name_is_indexed() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
+
+    let name_event = |event_id: &str, name: &str, server_ts: i64| {
+        let mut event = TOPIC_EVENT.clone();
+        event.event_type = EventType::Name;
+        event.event_id = event_id.to_owned();
+        event.content_value = name.to_owned();
+        event.server_ts = server_ts;
+        event
+    };
+
+    let mut config = SearchConfig::new();
+    config.with_key(EventType::Name);
 
+    db.add_event(name_event("$name1", "Avocado", 10), profile.clone());
+    db.add_event(name_event("$name2", "Banana", 20), profile.clone());
+    db.add_event(name_event("$name3", "Cherry", 30), profile.clone());
     db.force_commit().unwrap();
     db.reload().unwrap();
 
-    // A forced commit gets rid of our uncommitted events.
-    assert!(
-        Database::load_uncommitted_events(&db.connection.lock().unwrap())
-            .unwrap()
-            .is_empty()
-    );
+    assert!(db.search("Avocado", &config).unwrap().results.is_empty());
+    assert!(db.search("Banana", &config).unwrap().results.is_empty());
+    let results = db.search("Cherry", &config).unwrap().results;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].event_source, TOPIC_EVENT.source);
 
-    let result = db.search("test", &SearchConfig::new()).unwrap().results;
+    // A name the crawler finds in the room history doesn't replace the
+    // current one, it stays in the store but isn't searchable.
+    db.add_event(name_event("$name0", "Durian", 5), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    // The search is now successful.
-    assert!(!result.is_empty());
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0].event_source, EVENT.source);
+    assert!(db.search("Durian", &config).unwrap().results.is_empty());
+    assert_eq!(db.search("Cherry", &config).unwrap().results.len(), 1);
+
+    // Every name stays searchable if the history of names is indexed.
+    let tmpdir = tempdir().unwrap();
+    let db_config = Config::new().set_index_state_history(true);
+    let mut db = Database::new_with_config(tmpdir.path(), &db_config).unwrap();
+
+    db.add_event(name_event("$name1", "Avocado", 10), profile.clone());
+    db.add_event(name_event("$name2", "Banana", 20), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+
+    assert_eq!(db.search("Avocado", &config).unwrap().results.len(), 1);
+    assert_eq!(db.search("Banana", &config).unwrap().results.len(), 1);
 }
 
 #[test]
-fn delete_uncommitted() {
+fn redact_the_latest_room_name() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
     let profile = Profile::new("Alice", "");
 
-    for i in 1..1000 {
-        let mut event: Event = Faker.fake();
-        event.server_ts += i;
-        db.add_event(event, profile.clone());
+    let name_event = |event_id: &str, name: &str, server_ts: i64| {
+        let mut event = TOPIC_EVENT.clone();
+        event.event_type = EventType::Name;
+        event.event_id = event_id.to_owned();
+        event.content_value = name.to_owned();
+        event.server_ts = server_ts;
+        event
+    };
 
-        if i % 100 == 0 {
-            db.commit().unwrap();
-        }
-    }
+    db.add_event(name_event("$name1", "Avocado", 10), profile.clone());
+    db.add_event(name_event("$name2", "Banana", 20), profile);
+    db.force_commit().unwrap();
 
+    db.delete_event("$name2").recv().unwrap().unwrap();
     db.force_commit().unwrap();
-    assert!(
-        Database::load_uncommitted_events(&db.connection.lock().unwrap())
+    db.reload().unwrap();
+
+    // The older name takes the place of the redacted one, the redacted name
+    // isn't shown as the name of the room anymore.
+    let connection = db.get_connection().unwrap();
+    let latest_name: String = connection
+        .query_row(
+            "SELECT event_id FROM latest_state_events WHERE room_id = ?1 AND type = ?2",
+            [TOPIC_EVENT.room_id.as_str(), "m.room.name"],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(latest_name, "$name1");
+    assert_eq!(
+        connection
+            .get_room_names(&[TOPIC_EVENT.room_id.clone()])
             .unwrap()
-            .is_empty()
+            .get(&TOPIC_EVENT.room_id),
+        Some(&None)
     );
+
+    let mut config = SearchConfig::new();
+    config.with_key(EventType::Name);
+    assert!(db.search("Banana", &config).unwrap().results.is_empty());
 }
 
 #[test]
-fn stats_getting() {
+fn search_results_contain_the_room_name() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
     let profile = Profile::new("Alice", "");
+    let room_id = EVENT.room_id.clone();
+    let other_room = "!other_room:localhost".to_owned();
+
+    let name_event = |event_id: &str, name: &str, server_ts: i64| {
+        let mut event = TOPIC_EVENT.clone();
+        event.event_type = EventType::Name;
+        event.event_id = event_id.to_owned();
+        event.content_value = name.to_owned();
+        event.server_ts = server_ts;
+        event
+    };
 
-    for i in 0..1000 {
-        let mut event: Event = Faker.fake();
-        event.server_ts += i;
-        db.add_event(event, profile.clone());
-    }
+    let display_name = |db: &Database| {
+        let results = db.search("test", &SearchConfig::new()).unwrap().results;
+        assert_eq!(results.len(), 1);
+        results[0].room_display_name.clone()
+    };
 
-    db.commit().unwrap();
+    db.add_event(EVENT.clone(), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    let connection = db.get_connection().unwrap();
+    assert_eq!(display_name(&db), None);
 
-    let stats = connection.get_stats().unwrap();
+    // The alias is used as long as the room doesn't have a name.
+    db.add_room_alias(RoomAlias::new(&room_id, "#test:localhost", 10));
+    db.force_commit().unwrap();
+    assert_eq!(display_name(&db).as_deref(), Some("#test:localhost"));
 
-    assert_eq!(stats.event_count, 1000);
-    assert_eq!(stats.room_count, 1);
-    assert!(stats.size > 0);
-}
+    db.add_event(name_event("$name1", " Avocado ", 20), profile.clone());
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert_eq!(display_name(&db).as_deref(), Some("Avocado"));
 
-#[test]
-fn database_upgrade_v1() {
-    let mut path = PathBuf::from(file!());
-    path.pop();
-    path.pop();
-    path.pop();
-    path.push("data/database/v1");
-    let db = Database::new(path);
+    // The newest name wins, older names the crawler finds don't replace it.
+    db.add_event(name_event("$name2", "Banana", 30), profile.clone());
+    db.add_event(name_event("$name0", "Cherry", 5), profile);
+    db.force_commit().unwrap();
+    db.reload().unwrap();
+    assert_eq!(display_name(&db).as_deref(), Some("Banana"));
 
-    // Sadly the v1 database has invalid json in the source field, reindexing it
-    // won't be possible. Let's check that it's marked for a reindex.
-    match db {
-        Ok(_) => panic!("Database doesn't need a reindex."),
-        Err(e) => match e {
-            Error::ReindexError => (),
-            e => panic!("Database doesn't need a reindex: {}", e),
-        },
-    }
+    let connection = db.get_connection().unwrap();
+    let names = connection
+        .get_room_names(&[room_id.clone(), other_room.clone()])
+        .unwrap();
+    assert_eq!(names.len(), 2);
+    assert_eq!(names[&room_id].as_deref(), Some("Banana"));
+    assert_eq!(names[&other_room], None);
+    assert!(connection.get_room_names(&[]).unwrap().is_empty());
 }
 
-#[cfg(test)]
-use crate::database::recovery::test::reindex_loop;
-
 #[test]
-fn database_upgrade_v1_2() {
-    let mut path = PathBuf::from(file!());
-    path.pop();
-    path.pop();
-    path.pop();
-    path.push("data/database/v1_2");
-    let db = Database::new(&path);
-    match db {
-        Ok(_) => panic!("Database doesn't need a reindex."),
-        Err(e) => match e {
-            Error::ReindexError => (),
-            e => panic!("Database doesn't need a reindex: {}", e),
-        },
-    }
+fn search_results_report_truncated_context() {
+    let tmpdir = tempdir().unwrap();
+    let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
 
-    let mut recovery_db = RecoveryDatabase::new(&path).expect("Can't open recovery db");
+    for i in 1..=3 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$context{}:localhost", i);
+        event.source = EVENT.source.replace(&EVENT.event_id, &event.event_id);
+        event.server_ts = i * 100;
+        db.add_event(event, profile.clone());
+    }
 
-    recovery_db.delete_the_index().unwrap();
-    recovery_db.open_index().unwrap();
+    db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    let events = recovery_db.load_events_deserialized(100, None).unwrap();
+    let mut config = SearchConfig::new();
+    config.before_limit(1).after_limit(1).order_by_recency(true);
 
-    recovery_db.index_events(&events).unwrap();
-    reindex_loop(&mut recovery_db, events).unwrap();
-    recovery_db.commit_and_close().unwrap();
+    let result = db.search("test", &config).unwrap();
+    let truncation: Vec<ContextTruncation> =
+        result.results.iter().map(|r| r.context_truncated).collect();
 
-    let db = Database::new(&path).expect("Can't open the db event after a reindex");
+    // The results are ordered by recency, the latest event comes first.
+    assert_eq!(
+        truncation,
+        vec![
+            ContextTruncation {
+                before: false,
+                after: true
+            },
+            ContextTruncation {
+                before: false,
+                after: false
+            },
+            ContextTruncation {
+                before: true,
+                after: false
+            },
+        ]
+    );
 
-    let mut connection = db.get_connection().unwrap();
-    let (version, _) = Database::get_version(&mut connection).unwrap();
-    assert_eq!(version, DATABASE_VERSION);
+    // Asking for more context than the room has truncates both sides.
+    config.before_limit(5).after_limit(5);
+    let result = db.search("test", &config).unwrap();
+    assert!(result
+        .results
+        .iter()
+        .all(|r| r.context_truncated.before && r.context_truncated.after));
 
-    let result = db.search("Hello", &SearchConfig::new()).unwrap().results;
-    assert!(!result.is_empty())
+    // Sides without a limit aren't truncated.
+    config.before_limit(0).after_limit(0);
+    let result = db.search("test", &config).unwrap();
+    assert!(result
+        .results
+        .iter()
+        .all(|r| r.context_truncated == ContextTruncation::default()));
 }
 
 #[test]
-fn delete_an_event() {
+fn context_limits_can_be_overridden_per_room() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
     let profile = Profile::new("Alice", "");
 
-    db.add_event(EVENT.clone(), profile.clone());
-    db.add_event(TOPIC_EVENT.clone(), profile);
+    for room in &["!direct:localhost", "!busy:localhost"] {
+        for i in 1..=7 {
+            let mut event = EVENT.clone();
+            event.event_id = format!("${}{}:localhost", &room[1..5], i);
+            event.source = EVENT.source.replace(&EVENT.event_id, &event.event_id);
+            event.room_id = room.to_string();
+            event.server_ts = i * 100;
+            db.add_event(event, profile.clone());
+        }
+    }
 
     db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    assert!(
-        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
-            .unwrap()
-            .is_empty()
-    );
-
-    let recv = db.delete_event(&EVENT.event_id);
-    recv.recv().unwrap().unwrap();
+    let mut overrides = HashMap::new();
+    overrides.insert("!busy:localhost".to_owned(), (3, 2));
+
+    let mut config = SearchConfig::new();
+    config
+        .limit(20)
+        .before_limit(1)
+        .after_limit(1)
+        .context_overrides(overrides);
+
+    let result = db.search("test", &config).unwrap();
+    assert_eq!(result.results.len(), 14);
+
+    for r in &result.results {
+        // The fourth event of a room has enough events around it for any of
+        // the limits.
+        if r.event_source.contains("$dire4:") {
+            assert_eq!((r.events_before.len(), r.events_after.len()), (1, 1));
+        } else if r.event_source.contains("$busy4:") {
+            assert_eq!((r.events_before.len(), r.events_after.len()), (3, 2));
+        }
 
-    assert_eq!(
-        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
-            .unwrap()
-            .len(),
-        1
-    );
+        // The context of the results of the busy room is truncated closer to
+        // the edges of the room.
+        if r.event_source.contains("$busy2:") {
+            assert!(r.context_truncated.before);
+            assert_eq!(r.events_before.len(), 1);
+        } else if r.event_source.contains("$dire2:") {
+            assert!(!r.context_truncated.before);
+        }
+    }
 
-    drop(db);
+    // Without the context the overrides don't load anything either.
+    config.include_context(false);
+    let result = db.search("test", &config).unwrap();
+    assert!(result
+        .results
+        .iter()
+        .all(|r| r.events_before.is_empty() && r.events_after.is_empty()));
+}
 
+#[test]
+fn search_requiring_context_skips_broken_results() {
+    let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
-    assert_eq!(
-        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
-            .unwrap()
-            .len(),
-        1
-    );
+    let profile = Profile::new("Alice", "");
+
+    for i in 1..=5 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$broken{}:localhost", i);
+        event.source = EVENT.source.replace(&EVENT.event_id, &event.event_id);
+        event.server_ts = i * 100;
+        db.add_event(event, profile.clone());
+    }
 
     db.force_commit().unwrap();
-    assert_eq!(
-        Database::load_pending_deletion_events(&db.connection.lock().unwrap())
-            .unwrap()
-            .len(),
-        0
-    );
-}
+    db.reload().unwrap();
 
-#[test]
-fn add_events_with_null_byte() {
-    let event_source: &str = r#"{
-        "content": {
-            "body": "\u00000",
-            "msgtype": "m.text"
-        },
-        "event_id": "$15163622448EBvZJ:localhost",
-        "origin_server_ts": 1516362244050,
-        "sender": "@example2:localhost",
-        "type": "m.room.message",
-        "unsigned": {"age": 43289803098},
-        "user_id": "@example2:localhost",
-        "age": 43289803098,
-        "room_id": "!test:example.org"
-    }"#;
+    // A source that isn't text can't be loaded, neither as a result nor as
+    // the context of the next event.
+    db.get_connection()
+        .unwrap()
+        .execute(
+            "UPDATE events SET source = X'00' WHERE event_id = '$broken1:localhost'",
+            [],
+        )
+        .unwrap();
 
-    let event = RecoveryDatabase::event_from_json(event_source).unwrap();
+    let mut config = SearchConfig::new();
+    config.before_limit(1).after_limit(1);
 
-    let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
-    let profile = Profile::new("Alice", &event.content_value);
+    assert!(db.search("test", &config).is_err());
 
-    let events = vec![(event, profile)];
-    db.add_historic_events(events, None, None)
-        .recv()
-        .unwrap()
-        .expect("Event should be added");
+    config.require_context(true);
+    let result = db.search("test", &config).unwrap();
+
+    assert_eq!(result.skipped, 2);
+    assert_eq!(result.results.len(), 3);
+    assert!(result.results.iter().all(|r| {
+        !r.event_source.contains("$broken1:") && !r.event_source.contains("$broken2:")
+    }));
+    assert!(result.results.iter().all(|r| r.events_before.len() == 1));
 }
 
 #[test]
-fn is_room_indexed() {
+fn search_after_a_watermark() {
     let tmpdir = tempdir().unwrap();
     let mut db = Database::new(tmpdir.path()).unwrap();
+    let profile = Profile::new("Alice", "");
 
-    let connection = db.get_connection().unwrap();
-
-    assert!(connection.is_empty().unwrap());
-    assert!(!connection.is_room_indexed("!test_room:localhost").unwrap());
+    for i in 1..=5 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$watermark{}:localhost", i);
+        event.source = EVENT.source.replace(&EVENT.event_id, &event.event_id);
+        event.server_ts = i * 100;
+        db.add_event(event, profile.clone());
+    }
 
-    let profile = Profile::new("Alice", "");
-    db.add_event(EVENT.clone(), profile);
     db.force_commit().unwrap();
+    db.reload().unwrap();
 
-    assert!(connection.is_room_indexed("!test_room:localhost").unwrap());
-    assert!(!connection.is_room_indexed("!test_room2:localhost").unwrap());
-}
+    assert_eq!(db.get_watermark("verified").unwrap(), None);
 
-#[test]
-fn user_version() {
-    let tmpdir = tempdir().unwrap();
-    let db = Database::new(tmpdir.path()).unwrap();
+    let mut config = SearchConfig::new();
+    config.after_watermark("verified");
+
+    let err = db.search("test", &config).unwrap_err();
+    assert!(matches!(err, Error::WatermarkNotFound(ref name) if name == "verified"));
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+
+    db.set_watermark("verified", 300).unwrap();
+    assert_eq!(db.get_watermark("verified").unwrap(), Some(300));
+
+    let result = db.search("test", &config).unwrap();
+    assert_eq!(result.count, 3);
+    assert!(result.results.iter().all(|r| {
+        !r.event_source.contains("$watermark1:") && !r.event_source.contains("$watermark2:")
+    }));
+
+    // The later of a timestamp limit and the watermark wins.
+    config.after_timestamp(450);
+    assert_eq!(db.search("test", &config).unwrap().count, 1);
+
+    let mut config = SearchConfig::new();
+    config.after_timestamp(200).after_watermark("verified");
+    assert_eq!(db.search("test", &config).unwrap().count, 3);
+
+    // Continuing a search keeps the timestamp the watermark had when the
+    // search started.
+    config.limit(1);
+    let first = db.search("test", &config).unwrap();
+    db.set_watermark("verified", 500).unwrap();
+
+    let mut next = config.clone();
+    next.next_batch(first.next_batch.unwrap());
+    let second = db.search("test", &next).unwrap();
+    assert_eq!(second.count, 3);
+    assert_eq!(second.results.len(), 1);
+
+    // Watermarks are shared with the connections and survive a reopen.
     let connection = db.get_connection().unwrap();
+    assert_eq!(connection.get_watermark("verified").unwrap(), Some(500));
+    connection.set_watermark("backup", 100).unwrap();
+    drop(connection);
 
-    assert_eq!(connection.get_user_version().unwrap(), 0);
-    connection.set_user_version(10).unwrap();
-    assert_eq!(connection.get_user_version().unwrap(), 10);
+    db.shutdown().recv().unwrap().unwrap();
+    let db = Database::new(tmpdir.path()).unwrap();
+    assert_eq!(db.get_watermark("verified").unwrap(), Some(500));
+    assert_eq!(db.get_watermark("backup").unwrap(), Some(100));
+    assert_eq!(db.search("test", &config).unwrap().count, 1);
 }
 
 #[test]
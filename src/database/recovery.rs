@@ -18,11 +18,14 @@ use rusqlite::ToSql;
 
 use crate::{
     config::Config,
-    database::{DATABASE_VERSION, EVENTS_DB_NAME},
+    database::{
+        consistency::ConsistencyState, source_cache::SourceCache, source_cipher::SourceCipher,
+        DATABASE_VERSION, EVENTS_DB_NAME,
+    },
     error::{Error, Result},
-    events::{Event, SerializedEvent},
+    events::{Event, SerializedEvent, FILE_MSGTYPES},
     index::{Index, Writer},
-    Connection, Database,
+    Connection, ConnectionPool, Database,
 };
 
 use crate::EventType;
@@ -35,6 +38,8 @@ use serde_json::Value;
 /// change.
 pub struct RecoveryDatabase {
     path: PathBuf,
+    index_path: PathBuf,
+    store_path: PathBuf,
     connection: PooledConnection<SqliteConnectionManager>,
     pool: r2d2::Pool<SqliteConnectionManager>,
     config: Config,
@@ -42,6 +47,7 @@ pub struct RecoveryDatabase {
     index_deleted: bool,
     index: Option<Index>,
     index_writer: Option<Writer>,
+    source_cipher: Option<Arc<SourceCipher>>,
 }
 
 #[derive(Debug, Clone)]
@@ -94,7 +100,9 @@ impl RecoveryDatabase {
     where
         PathBuf: std::convert::From<P>,
     {
-        let db_path = path.as_ref().join(EVENTS_DB_NAME);
+        let (index_path, store_path) = Database::create_locations(path.as_ref(), config)?;
+
+        let db_path = store_path.join(EVENTS_DB_NAME);
         let pool = Database::get_pool(&db_path, config)?;
 
         let mut connection = pool.get()?;
@@ -112,6 +120,7 @@ impl RecoveryDatabase {
             return Err(Error::DatabaseVersionError);
         }
 
+        let source_cipher = Database::open_source_cipher(&connection, config)?;
         let event_count = Database::get_event_count(&connection)?;
 
         let info = RecoveryInfo {
@@ -121,6 +130,8 @@ impl RecoveryDatabase {
 
         Ok(Self {
             path: path.into(),
+            index_path,
+            store_path,
             connection,
             pool,
             config: config.clone(),
@@ -128,6 +139,7 @@ impl RecoveryDatabase {
             index_deleted: false,
             index: None,
             index_writer: None,
+            source_cipher,
         })
     }
 
@@ -141,7 +153,7 @@ impl RecoveryDatabase {
         drop(writer);
         drop(index);
 
-        for entry in fs::read_dir(&self.path)? {
+        for entry in fs::read_dir(&self.index_path)? {
             let entry = entry?;
             let path = entry.path();
 
@@ -161,6 +173,11 @@ impl RecoveryDatabase {
                 fs::remove_file(path)?
             }
         }
+
+        // The index is gone on purpose, opening the database shouldn't
+        // complain that it's missing.
+        self.connection.execute("DELETE FROM index_location", [])?;
+
         self.index_deleted = true;
         Ok(())
     }
@@ -170,20 +187,25 @@ impl RecoveryDatabase {
         let content = &object["content"];
         let event_type = &object["type"];
 
-        let event_type = match event_type.as_str().unwrap_or_default() {
-            "m.room.message" => EventType::Message,
-            "m.room.name" => EventType::Name,
-            "m.room.topic" => EventType::Topic,
-            _ => return Err(IoError::new(ErrorKind::Other, "Invalid event type.")),
-        };
+        let event_type = EventType::from(event_type.as_str().unwrap_or_default());
 
         let (content_value, msgtype) = match event_type {
-            EventType::Message => (
-                content["body"]
-                    .as_str()
-                    .ok_or_else(|| IoError::new(ErrorKind::Other, "No content value found"))?,
-                Some("m.text"),
-            ),
+            EventType::Message => {
+                let msgtype = content["msgtype"].as_str().unwrap_or("m.text");
+
+                let content_value = if FILE_MSGTYPES.contains(&msgtype) {
+                    content["filename"]
+                        .as_str()
+                        .or_else(|| content["body"].as_str())
+                        .unwrap_or_default()
+                } else {
+                    content["body"]
+                        .as_str()
+                        .ok_or_else(|| IoError::new(ErrorKind::Other, "No content value found"))?
+                };
+
+                (content_value, Some(msgtype))
+            }
             EventType::Topic => (
                 content["topic"]
                     .as_str()
@@ -196,6 +218,9 @@ impl RecoveryDatabase {
                     .ok_or_else(|| IoError::new(ErrorKind::Other, "No content value found"))?,
                 None,
             ),
+            EventType::Unknown(_) => {
+                return Err(IoError::new(ErrorKind::Other, "Invalid event type."))
+            }
         };
 
         let event_id = object["event_id"]
@@ -211,7 +236,7 @@ impl RecoveryDatabase {
             .as_str()
             .ok_or_else(|| IoError::new(ErrorKind::Other, "No room id found"))?;
 
-        Ok(Event::new(
+        let mut event = Event::new(
             event_type,
             content_value,
             msgtype,
@@ -222,7 +247,15 @@ impl RecoveryDatabase {
             })?,
             room_id,
             event_source,
-        ))
+        );
+
+        let relation = &content["m.relates_to"];
+
+        if relation["rel_type"].as_str() == Some("m.thread") {
+            event.thread_id = relation["event_id"].as_str().map(|e| e.to_owned());
+        }
+
+        Ok(event)
     }
 
     /// Load deserialized events from the database.
@@ -256,11 +289,15 @@ impl RecoveryDatabase {
         limit: usize,
         from_event: Option<&Event>,
     ) -> Result<Vec<SerializedEvent>> {
-        Ok(Database::load_all_events(
-            &self.connection,
-            limit,
-            from_event,
-        )?)
+        let events = Database::load_all_events(&self.connection, limit, from_event)?;
+
+        match &self.source_cipher {
+            Some(cipher) => Ok(events
+                .iter()
+                .map(|e| cipher.decrypt(e))
+                .collect::<std::io::Result<_>>()?),
+            None => Ok(events),
+        }
     }
 
     /// Create and open a new index.
@@ -271,8 +308,15 @@ impl RecoveryDatabase {
             return Err(Error::ReindexError);
         }
 
-        let index = Index::new(&self.path, &self.config)?;
-        let writer = index.get_writer()?;
+        let index = Index::new(&self.index_path, &self.config)?;
+        let mut writer = Database::get_index_writer(&self.index_path, &index, &self.config)?;
+
+        // The new index belongs to this store, the consistency check
+        // compares the payload of its commits with the id of the store.
+        if let Some(state) = Database::load_index_state(&self.connection)? {
+            writer.set_commit_payload(&state.store_id);
+        }
+
         self.index = Some(index);
         self.index_writer = Some(writer);
 
@@ -288,14 +332,21 @@ impl RecoveryDatabase {
     ///
     /// Note that this connection should only be used for reading.
     pub fn get_connection(&self) -> Result<Connection> {
-        let connection = self.pool.get()?;
-        Database::unlock(&connection, &self.config)?;
-        Database::set_pragmas(&connection)?;
+        self.get_connection_pool().get()
+    }
 
-        Ok(Connection {
-            inner: connection,
-            path: self.path.clone(),
-        })
+    /// Get a handle to the connection pool of the database.
+    ///
+    /// The handle can be moved to the thread that needs a connection and
+    /// take it there, see `Database::get_connection_pool()`.
+    pub fn get_connection_pool(&self) -> ConnectionPool {
+        ConnectionPool {
+            pool: self.pool.clone(),
+            config: self.config.clone(),
+            locations: Database::distinct_locations(&self.path, &self.index_path, &self.store_path),
+            source_cipher: self.source_cipher.clone(),
+            source_cache: SourceCache::new_shared(0),
+        }
     }
 
     /// Re-index a batch of events.
@@ -307,8 +358,35 @@ impl RecoveryDatabase {
     /// Returns `ReindexError` if the index wasn't previously deleted and
     /// opened.
     pub fn index_events(&mut self, events: &[Event]) -> Result<()> {
+        let max_bytes = self.config.size_limits.max_indexed_body_bytes;
+        let index_state_history = self.config.index_state_history;
+
         match self.index_writer.as_mut() {
-            Some(writer) => events.iter().map(|e| writer.add_event(e)).collect(),
+            Some(writer) => {
+                for event in events {
+                    if !index_state_history
+                        && Database::is_outdated_state_event(&self.connection, event)?
+                    {
+                        continue;
+                    }
+
+                    // Sources that were encrypted couldn't be checked for
+                    // links while the database was upgraded.
+                    if event.contains_url() {
+                        Database::mark_url_event(&self.connection, event)?;
+                    }
+
+                    // The stored sources may contain bodies that are longer
+                    // than the ones that were originally indexed.
+                    if event.content_value.len() > max_bytes {
+                        let mut event = event.clone();
+                        event.truncate_content_value(max_bytes);
+                        writer.add_event(&event);
+                    } else {
+                        writer.add_event(event);
+                    }
+                }
+            }
             None => panic!("Index wasn't deleted"),
         }
 
@@ -346,6 +424,8 @@ impl RecoveryDatabase {
                 writer.force_commit()?;
                 self.connection
                     .execute("UPDATE reindex_needed SET reindex_needed = ?1", [false])?;
+                Database::save_index_opstamp(&self.connection, writer.committed_opstamp())?;
+                Database::save_consistency(&self.connection, ConsistencyState::Consistent)?;
                 Ok(())
             }
             None => Err(Error::ReindexError),
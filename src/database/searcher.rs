@@ -14,39 +14,87 @@
 
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
-    thread::sleep,
-    time::Duration,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
+use serde_json::{json, Value};
 use uuid::Uuid;
 
 use crate::{
-    config::SearchConfig,
-    error::Result,
-    events::{MxId, Profile, SerializedEvent},
-    index::IndexSearcher,
-    Database,
+    config::{ContextLimits, SearchConfig},
+    database::{
+        metrics::SharedMetrics, source_cache::SharedSourceCache, source_cipher::SourceCipher,
+    },
+    error::{Error, Result},
+    events::{ContextEvent, EventId, MxId, Profile, RoomId, SerializedEvent},
+    index::{Deadline, IndexSearcher, RoomGroup},
+    Database, RecoveryDatabase,
 };
 
-static BUSY_RETRY: usize = 10;
-static BUSY_SLEEP: Duration = Duration::from_millis(10);
+/// How many search results are loaded from the database at once, the
+/// deadline of a search is checked between the batches.
+const CONTEXT_BATCH_SIZE: usize = 10;
+
+/// How many search results an export fetches at once, only a single batch of
+/// results is held in memory.
+const EXPORT_BATCH_SIZE: usize = 100;
 
 #[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
 /// A search result
 pub struct SearchResult {
     /// The score that the full text search assigned to this event.
+    #[serde(rename = "rank", alias = "score")]
     pub score: f32,
+    /// The score before it was normalized, only set if score normalization
+    /// was requested in the `SearchConfig`.
+    pub raw_score: Option<f32>,
+    /// The name of the room of the event, or its canonical alias if the room
+    /// doesn't have a name. Only set if the database saw an event that set
+    /// the name or the alias, see `Database::add_room_alias()`.
+    pub room_display_name: Option<String>,
     /// The serialized source of the event that matched a search.
     pub event_source: SerializedEvent,
-    /// Events that happened before our matched event.
-    pub events_before: Vec<SerializedEvent>,
-    /// Events that happened after our matched event.
-    pub events_after: Vec<SerializedEvent>,
+    /// Events that happened before our matched event, the closest one
+    /// first.
+    pub events_before: Vec<ContextEvent>,
+    /// Events that happened after our matched event, the closest one first.
+    pub events_after: Vec<ContextEvent>,
     /// The profile of the sender of the matched event.
     pub profile_info: HashMap<MxId, Profile>,
+    /// The search terms that are contained in the matched event.
+    pub highlights: Vec<String>,
+    /// The fields of the matched event that contained a search term, any of
+    /// `content.body`, `content.topic`, `content.name` and `sender`.
+    pub matched_fields: Vec<String>,
+    /// Is the event source the full source of the event. If the database
+    /// doesn't store event sources, the source only contains the event id,
+    /// the room id, the sender and the timestamp of the event and there are
+    /// no context events.
+    pub source_available: bool,
+    /// Did the context of the event end before the requested number of
+    /// events, because there are no more indexed events in the room.
+    pub context_truncated: ContextTruncation,
+}
+
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy, Serialize, Deserialize)]
+/// Tells which side of the context of a search result contains fewer events
+/// than the context limits asked for.
+///
+/// A truncated side means that the result is at the edge of the indexed
+/// history of its room, e.g. the first or the latest message that was added.
+/// Sides that don't have a context limit are never truncated.
+pub struct ContextTruncation {
+    /// Are there fewer events before the result than requested.
+    pub before: bool,
+    /// Are there fewer events after the result than requested.
+    pub after: bool,
 }
 
 #[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
@@ -58,15 +106,112 @@ pub struct SearchBatch {
     /// always smaller of equal to the count and depends on the limit that was
     /// given in the `SearchConfig`.
     pub results: Vec<SearchResult>,
+    /// The search terms that are contained in any of the returned results.
+    pub highlights: Vec<String>,
     /// A token that can be set in the `SearchConfig` to continue fetching the
     /// next batch of `SearchResult`s.
     pub next_batch: Option<Uuid>,
+    /// The matching events grouped by the room they belong to, only set if
+    /// grouping was requested in the `SearchConfig`.
+    pub groups: Option<HashMap<RoomId, RoomGroup>>,
+    /// The rooms of the matching events ordered by their best match, the
+    /// results are ordered the same way. Only set if ordering the results by
+    /// room was requested in the `SearchConfig`.
+    pub room_order: Vec<RoomId>,
+    /// How long the phases of the search took, only set if timing
+    /// information was requested in the `SearchConfig`.
+    pub timing: Option<SearchTiming>,
+    /// Did the search run out of time before it was done, the results only
+    /// contain the events that were found until then. See
+    /// `SearchConfig::timeout()`.
+    pub timed_out: bool,
+    /// The number of results that were left out because they or their
+    /// context couldn't be loaded, only counted if the search required the
+    /// context of its results. See `SearchConfig::require_context()`.
+    pub skipped: usize,
+}
+
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+/// The time the phases of a search took, in milliseconds.
+pub struct SearchTiming {
+    /// The time it took to query the index.
+    pub index_millis: f64,
+    /// The time it took to load the matching events and their context from
+    /// the database.
+    pub context_millis: f64,
+    /// The time the whole search took.
+    pub total_millis: f64,
+}
+
+impl SearchTiming {
+    fn new(index_time: Duration, total_time: Duration) -> Self {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+        SearchTiming {
+            index_millis: millis(index_time),
+            context_millis: millis(total_time.saturating_sub(index_time)),
+            total_millis: millis(total_time),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Default, Clone)]
+/// Summary of an export of search results.
+pub struct ExportSummary {
+    /// The number of search results that were written.
+    pub count: usize,
+    /// The number of search results whose event source couldn't be parsed,
+    /// they aren't part of the export.
+    pub skipped: usize,
+    /// The time the whole export took.
+    pub duration: Duration,
+}
+
+/// A token that can be used to cancel a search that is in progress.
+///
+/// The token can be cloned and shared with another thread, cancelling any of
+/// the clones cancels the search. Cancelling a search that already finished
+/// does nothing.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a new token that isn't cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancel the search that uses this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Was the token cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Return a `SearchCancelled` error if the token was cancelled.
+    fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::SearchCancelled)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// The main entry point to the index and database.
 pub struct Searcher {
     pub(crate) inner: IndexSearcher,
     pub(crate) database: Arc<Mutex<PooledConnection<SqliteConnectionManager>>>,
+    pub(crate) store_sources: bool,
+    pub(crate) source_cipher: Option<Arc<SourceCipher>>,
+    pub(crate) source_cache: SharedSourceCache,
+    pub(crate) metrics: SharedMetrics,
+    pub(crate) record_searches: bool,
 }
 
 impl Searcher {
@@ -78,53 +223,490 @@ impl Searcher {
     ///   should contain.
     ///
     /// Returns a tuple of the count of matching documents and a list of
-    ///   `SearchResult`.
+    ///   `SearchResult`. An `EmptySearchTerm` error is returned if the term
+    ///   doesn't contain any words, unless the search lists the events of a
+    ///   room that contain links, see `SearchConfig::contains_url()`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(
+                term_length = term.len(),
+                limit = config.limit,
+                room_filter = config.room_id.is_some(),
+            )
+        )
+    )]
     pub fn search(&self, term: &str, config: &SearchConfig) -> Result<SearchBatch> {
-        let search_result = self.inner.search(term, config)?;
+        self.search_cancellable(term, config, &CancellationToken::new())
+    }
+
+    /// Search the index and return events matching a search term, the search
+    /// can be cancelled from another thread.
+    ///
+    /// This behaves like the `search()` method, but returns a
+    /// `SearchCancelled` error instead of the results if the given token gets
+    /// cancelled before the search finishes.
+    pub fn search_cancellable(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        cancellation: &CancellationToken,
+    ) -> Result<SearchBatch> {
+        let ret = self.search_helper(term, config, cancellation);
+        self.metrics.search(&ret);
+        ret
+    }
+
+    /// Run multiple searches against the same snapshot of the index.
+    ///
+    /// The results of the searches are consistent with each other, events
+    /// that are committed while the searches run aren't found by any of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The search terms and the `SearchConfig` of every search.
+    ///
+    /// Returns the result of every search in the order of the queries, a
+    /// search that fails doesn't affect the other ones.
+    pub fn search_batch(&self, queries: &[(String, SearchConfig)]) -> Vec<Result<SearchBatch>> {
+        queries
+            .iter()
+            .map(|(term, config)| self.search(term, config))
+            .collect()
+    }
+
+    /// Export all the events that match a search term.
+    ///
+    /// Every search result is written as a line of JSON containing its
+    /// `rank`, the event as the `result`, the `context` with the events
+    /// before and after it and the profiles of their senders, and the
+    /// `highlights` and `matched_fields` of the result.
+    ///
+    /// The search is paginated internally, the limit, the timeout and the
+    /// grouping of the config are ignored and results are written out one
+    /// batch at a time, so memory usage doesn't grow with the number of
+    /// matching events.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term.
+    /// * `config` - A SearchConfig that will modify what the search results
+    ///   should contain.
+    /// * `writer` - The writer the search results should be written to.
+    pub fn export_search<W: Write>(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        writer: W,
+    ) -> Result<ExportSummary> {
+        self.export_search_with_progress(term, config, writer, |_| ())
+    }
+
+    /// Export all the events that match a search term, reporting the
+    /// progress of the export.
+    ///
+    /// This behaves like the `export_search()` method, the `progress`
+    /// closure is called with the number of written results after every
+    /// batch of 100 results.
+    pub fn export_search_with_progress<W: Write>(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        mut writer: W,
+        mut progress: impl FnMut(usize),
+    ) -> Result<ExportSummary> {
+        let started = Instant::now();
+
+        let mut config = config.clone();
+        config.limit(EXPORT_BATCH_SIZE);
+        config.next_batch = None;
+        config.group_by_room = false;
+        config.include_timing = false;
+        config.timeout = None;
+
+        let mut summary = ExportSummary::default();
+
+        loop {
+            let batch = self.search(term, &config)?;
+
+            for result in &batch.results {
+                match Searcher::export_line(result) {
+                    Some(line) => {
+                        serde_json::to_writer(&mut writer, &line).map_err(io::Error::from)?;
+                        writer.write_all(b"\n")?;
+                        summary.count += 1;
+                    }
+                    None => summary.skipped += 1,
+                }
+            }
+
+            if !batch.results.is_empty() {
+                progress(summary.count);
+            }
+
+            match batch.next_batch {
+                Some(token) if !batch.results.is_empty() => {
+                    config.next_batch(token);
+                }
+                _ => break,
+            }
+        }
+
+        writer.flush()?;
+        summary.duration = started.elapsed();
+
+        Ok(summary)
+    }
+
+    /// Turn a search result into a line of a search export.
+    ///
+    /// Returns `None` if the source of the event can't be parsed, context
+    /// events that can't be parsed are left out.
+    fn export_line(result: &SearchResult) -> Option<Value> {
+        let event: Value = serde_json::from_str(&result.event_source).ok()?;
+        let context = |events: &[ContextEvent]| -> Vec<Value> {
+            events
+                .iter()
+                .filter_map(|e| serde_json::from_str(&e.source).ok())
+                .collect()
+        };
+
+        Some(json!({
+            "rank": result.score,
+            "result": event,
+            "context": {
+                "events_before": context(&result.events_before),
+                "events_after": context(&result.events_after),
+                "profile_info": result.profile_info,
+            },
+            "highlights": result.highlights,
+            "matched_fields": result.matched_fields,
+        }))
+    }
+
+    /// Prepare the index and the database for searches.
+    ///
+    /// The first search after opening a large database needs to read the
+    /// index files from disk, this loads the parts of the index and of the
+    /// database a search needs up front. Calling this when the search UI is
+    /// opened makes the first search of the user as fast as the later ones.
+    pub fn preload(&self) -> Result<()> {
+        self.inner.preload()?;
+
+        let connection = self.database.lock().unwrap();
+        Database::get_event_count(&connection)?;
+
+        Ok(())
+    }
+
+    fn search_helper(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        cancellation: &CancellationToken,
+    ) -> Result<SearchBatch> {
+        let term = term.trim();
+
+        // The tokenizer splits words on non-alphanumeric characters, a term
+        // without any of them would result in an empty query. Continuing a
+        // search reuses the term of the first batch.
+        if config.next_batch.is_none()
+            && !config.allows_empty_term()
+            && !term.chars().any(char::is_alphanumeric)
+        {
+            return Err(Error::EmptySearchTerm);
+        }
+
+        // The search might have waited for a free thread while it was
+        // cancelled.
+        cancellation.check()?;
+
+        let resolved_config = self.resolve_watermark(config)?;
+        let config = resolved_config.as_ref().unwrap_or(config);
+
+        let started = config.include_timing.then(Instant::now);
+        let deadline = config.timeout.map(Deadline::after);
+        let mut search_result = self
+            .inner
+            .search_with_deadline(term, config, deadline.as_ref())?;
+
+        if config.is_boosted_by_reactions() {
+            let dropped = self.boost_by_reactions(&mut search_result.results, config.limit)?;
+
+            if let Some(token) = &search_result.next_batch {
+                self.inner.return_results(token, &dropped);
+            }
+        }
+
+        let index_time = started.map(|s| s.elapsed());
+
+        // Loading the results and their context is the expensive part of a
+        // search, don't start it for a search nobody waits for anymore.
+        cancellation.check()?;
+
+        if self.record_searches && config.next_batch.is_none() {
+            Database::retry_on_busy(|| {
+                Ok(Database::add_search_term(
+                    &self.database.lock().unwrap(),
+                    term,
+                )?)
+            })?;
+        }
 
         if search_result.results.is_empty() {
             return Ok(SearchBatch {
                 count: 0,
                 next_batch: search_result.next_batch,
                 results: vec![],
+                highlights: vec![],
+                groups: search_result.groups,
+                room_order: search_result.room_order,
+                timing: started
+                    .zip(index_time)
+                    .map(|(s, index_time)| SearchTiming::new(index_time, s.elapsed())),
+                timed_out: search_result.timed_out,
+                skipped: 0,
             });
         }
 
-        let mut retry = 0;
+        // Context events can't be loaded without their sources.
+        let limits = if self.store_sources {
+            config.context_limits()
+        } else {
+            ContextLimits::default()
+        };
 
-        let events = loop {
-            match Database::load_events(
-                &self.database.lock().unwrap(),
-                &search_result.results,
-                config.before_limit,
-                config.after_limit,
-                config.order_by_recency,
-            ) {
-                Ok(e) => break e,
-                Err(e) => match e {
-                    // Usually the busy timeout on a sqlite connection should
-                    // handle this, but setting it on the connection didn't
-                    // seem to get rid of database busy errors like expected.
-                    rusqlite::Error::SqliteFailure(sql_error, _) => {
-                        if sql_error.code == rusqlite::ffi::ErrorCode::DatabaseBusy
-                            && retry < BUSY_RETRY
-                        {
-                            retry += 1;
-                            sleep(BUSY_SLEEP);
-                            continue;
-                        } else {
-                            return Err(e.into());
-                        }
-                    }
-                    e => return Err(e.into()),
-                },
-            }
+        let load = |hits: &[(f32, EventId)]| {
+            Database::retry_on_busy(|| {
+                Ok(Database::load_events_cached(
+                    &self.database.lock().unwrap(),
+                    Some(&self.source_cache),
+                    hits,
+                    &search_result.matches,
+                    &limits,
+                    config.is_ordered_by_recency(),
+                    config.include_redacted_stubs,
+                )?)
+            })
         };
 
+        // The events are loaded in batches, a search that runs out of time
+        // returns the events that were loaded so far.
+        let mut events = Vec::with_capacity(search_result.results.len());
+        let mut skipped = 0;
+
+        for (i, batch) in search_result.results.chunks(CONTEXT_BATCH_SIZE).enumerate() {
+            if deadline.as_ref().map_or(false, Deadline::check) {
+                // The hits that weren't loaded are handed back, the next
+                // batch of the search returns them.
+                if let Some(token) = &search_result.next_batch {
+                    let unloaded: Vec<EventId> = search_result.results[i * CONTEXT_BATCH_SIZE..]
+                        .iter()
+                        .map(|(_, event_id)| event_id.clone())
+                        .collect();
+                    self.inner.return_results(token, &unloaded);
+                }
+
+                break;
+            }
+
+            match load(batch) {
+                Ok(loaded) => events.extend(loaded),
+                // A single broken row fails the whole batch, find the hits
+                // that can't be loaded on their own and load the rest of the
+                // batch without them.
+                Err(_) if config.require_context => {
+                    let loadable: Vec<(f32, EventId)> = batch
+                        .iter()
+                        .filter(|hit| load(std::slice::from_ref(hit)).is_ok())
+                        .cloned()
+                        .collect();
+
+                    skipped += batch.len() - loadable.len();
+                    events.extend(load(&loadable)?);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        for event in &mut events {
+            cancellation.check()?;
+            event.source_available = self.store_sources;
+
+            if !config.include_profiles {
+                event.profile_info.clear();
+            }
+
+            if let Some(cipher) = &self.source_cipher {
+                cipher.decrypt_result(event)?;
+            }
+        }
+
+        if config.normalize_scores {
+            Searcher::normalize_scores(&mut events);
+        }
+
+        let mut highlights: Vec<String> = Vec::new();
+
+        for highlight in events.iter().flat_map(|e| e.highlights.iter()) {
+            if !highlights.contains(highlight) {
+                highlights.push(highlight.clone());
+            }
+        }
+
         Ok(SearchBatch {
             count: search_result.count,
             next_batch: search_result.next_batch,
             results: events,
+            highlights,
+            groups: search_result.groups,
+            room_order: search_result.room_order,
+            timing: started
+                .zip(index_time)
+                .map(|(s, index_time)| SearchTiming::new(index_time, s.elapsed())),
+            timed_out: deadline.as_ref().map_or(false, Deadline::was_hit),
+            skipped,
         })
     }
+
+    /// Turn the watermark of a new search into a timestamp limit, if it has one.
+    fn resolve_watermark(&self, config: &SearchConfig) -> Result<Option<SearchConfig>> {
+        let name = match &config.after_watermark {
+            Some(name) if config.next_batch.is_none() => name,
+            _ => return Ok(None),
+        };
+
+        let timestamp = Database::load_watermark(&self.database.lock().unwrap(), name)?
+            .ok_or_else(|| Error::WatermarkNotFound(name.clone()))?;
+
+        let mut config = config.clone();
+        let timestamp = config
+            .min_timestamp
+            .map_or(timestamp, |ts| ts.max(timestamp));
+        config.after_timestamp(timestamp);
+
+        Ok(Some(config))
+    }
+
+    /// Multiply the scores of the results by `1 + ln(1 + reactions)`, sort
+    /// them by the boosted score and keep the best of them.
+    ///
+    /// Returns the ids of the events that didn't make the cut.
+    fn boost_by_reactions(
+        &self,
+        results: &mut Vec<(f32, EventId)>,
+        limit: usize,
+    ) -> Result<Vec<EventId>> {
+        let event_ids: Vec<EventId> = results.iter().map(|(_, e)| e.clone()).collect();
+        let counts = Database::retry_on_busy(|| {
+            Ok(Database::load_reaction_counts(
+                &self.database.lock().unwrap(),
+                &event_ids,
+            )?)
+        })?;
+
+        for (score, event_id) in results.iter_mut() {
+            let reactions = counts.get(event_id).copied().unwrap_or(0) as f32;
+            *score *= 1.0 + reactions.ln_1p();
+        }
+
+        // The sort is stable, results with the same score keep the order the
+        // index returned them in.
+        results.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+        Ok(results
+            .split_off(limit.min(results.len()))
+            .into_iter()
+            .map(|(_, e)| e)
+            .collect())
+    }
+
+    /// Divide the scores of the results by the highest score among them.
+    fn normalize_scores(results: &mut [SearchResult]) {
+        let max_score = results.iter().map(|r| r.score).fold(0.0, f32::max);
+
+        for result in results {
+            result.raw_score = Some(result.score);
+
+            if max_score > 0.0 {
+                result.score /= max_score;
+            }
+        }
+    }
+
+    /// Find events that are similar to the given event.
+    ///
+    /// The most distinctive terms of the event are used to search the index,
+    /// the event itself is never part of the result. Events that contain too
+    /// few terms to find meaningful matches return an empty result.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event that the results should be similar
+    ///   to.
+    /// * `config` - A SearchConfig that will modify what the search result
+    ///   should contain. The limit, the room, the context limits and the
+    ///   inclusion of the context and profiles are honored.
+    ///
+    /// Returns an `EventNotFound` error if the event isn't part of the
+    /// database and a `SourcesNotStored` error if the database doesn't store
+    /// the sources of events.
+    pub fn find_similar(&self, event_id: &str, config: &SearchConfig) -> Result<Vec<SearchResult>> {
+        if !self.store_sources {
+            return Err(Error::SourcesNotStored);
+        }
+
+        let (source, room_id) = Database::retry_on_busy(|| {
+            Ok(Database::load_event_source(
+                &self.database.lock().unwrap(),
+                event_id,
+            )?)
+        })?
+        .ok_or_else(|| Error::EventNotFound(event_id.to_owned()))?;
+
+        let source = match &self.source_cipher {
+            Some(cipher) => cipher.decrypt(&source)?,
+            None => source,
+        };
+
+        // The stored source doesn't necessarily contain the room id.
+        let mut source: Value = serde_json::from_str(&source).map_err(io::Error::from)?;
+
+        if let Some(object) = source.as_object_mut() {
+            object
+                .entry("room_id")
+                .or_insert_with(|| Value::String(room_id));
+        }
+
+        let event = RecoveryDatabase::event_from_json(&source.to_string())?;
+        let search_result = self.inner.find_similar(&event, config)?;
+
+        let limits = config.context_limits();
+
+        let mut events = Database::retry_on_busy(|| {
+            Ok(Database::load_events_cached(
+                &self.database.lock().unwrap(),
+                Some(&self.source_cache),
+                &search_result.results,
+                &search_result.matches,
+                &limits,
+                false,
+                config.include_redacted_stubs,
+            )?)
+        })?;
+
+        for event in &mut events {
+            if let Some(cipher) = &self.source_cipher {
+                cipher.decrypt_result(event)?;
+            }
+
+            if !config.include_profiles {
+                event.profile_info.clear();
+            }
+        }
+
+        Ok(events)
+    }
 }
@@ -0,0 +1,138 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+
+use lru_cache::LruCache;
+
+use crate::events::{Event, EventId, Profile};
+
+/// An event row as it was loaded from the database, the event, the profile
+/// of its sender, the database id of its room and its insert id.
+pub(crate) type CachedEvent = (Event, Profile, i64, i64);
+
+/// A source cache that is shared between the searchers and the writer.
+pub(crate) type SharedSourceCache = Arc<Mutex<SourceCache>>;
+
+/// A cache for the events that were returned by recent searches.
+///
+/// Users tend to refine their search terms, consecutive searches return
+/// mostly the same events. The cache saves loading the sources of those from
+/// the database again. The sources are cached as they are stored, encrypted
+/// sources stay encrypted.
+pub(crate) struct SourceCache {
+    events: LruCache<EventId, CachedEvent>,
+    /// Increased every time events are invalidated, events that were loaded
+    /// from the database before an invalidation may be stale and aren't
+    /// cached.
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl SourceCache {
+    /// Create a new cache holding at most `capacity` events, a capacity of 0
+    /// disables the cache.
+    pub(crate) fn new(capacity: usize) -> Self {
+        SourceCache {
+            events: LruCache::new(capacity),
+            generation: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Create a new cache that can be shared between threads.
+    pub(crate) fn new_shared(capacity: usize) -> SharedSourceCache {
+        Arc::new(Mutex::new(SourceCache::new(capacity)))
+    }
+
+    /// Is the cache enabled, i.e. does it have a capacity.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.events.capacity() > 0
+    }
+
+    /// Get the cached events with the given ids.
+    ///
+    /// Returns the cached events and the ids of the events that need to be
+    /// loaded from the database.
+    pub(crate) fn get_many(&mut self, event_ids: &[EventId]) -> (Vec<CachedEvent>, Vec<EventId>) {
+        let mut cached = Vec::new();
+        let mut missing = Vec::new();
+
+        for event_id in event_ids {
+            match self.events.get_mut(event_id) {
+                Some(event) => {
+                    self.hits += 1;
+                    cached.push(event.clone());
+                }
+                None => {
+                    self.misses += 1;
+                    missing.push(event_id.clone());
+                }
+            }
+        }
+
+        (cached, missing)
+    }
+
+    /// The current generation of the cache, see `insert_many()`.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Cache events that were loaded from the database.
+    ///
+    /// The events are only cached if no events were invalidated since
+    /// `generation` was taken, they might have been deleted in the meantime.
+    pub(crate) fn insert_many(&mut self, generation: u64, events: &[CachedEvent]) {
+        if generation != self.generation {
+            return;
+        }
+
+        for event in events {
+            self.events.insert(event.0.event_id.clone(), event.clone());
+        }
+    }
+
+    /// Remove the events with the given ids from the cache.
+    pub(crate) fn invalidate(&mut self, event_ids: &[EventId]) {
+        if event_ids.is_empty() {
+            return;
+        }
+
+        self.generation += 1;
+
+        for event_id in event_ids {
+            self.events.remove(event_id);
+        }
+    }
+
+    /// Remove all the events from the cache.
+    pub(crate) fn clear(&mut self) {
+        self.generation += 1;
+        self.events.clear();
+    }
+
+    /// The number of lookups that were served from the cache.
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of lookups that needed to load the event from the
+    /// database.
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "encryption")]
+use std::io::{Error as IoError, ErrorKind};
+
+#[cfg(feature = "encryption")]
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes256,
+};
+#[cfg(feature = "encryption")]
+use hmac::{Hmac, Mac, NewMac};
+#[cfg(feature = "encryption")]
+use pbkdf2::pbkdf2;
+#[cfg(feature = "encryption")]
+use rand::{thread_rng, Rng};
+use rusqlite::OptionalExtension;
+#[cfg(feature = "encryption")]
+use sha2::{Sha256, Sha512};
+#[cfg(feature = "encryption")]
+use zeroize::Zeroizing;
+
+#[cfg(feature = "encryption")]
+use crate::Database;
+use crate::{
+    database::SearchResult,
+    error::{Error, Result},
+    events::{Event, Profile},
+};
+
+#[cfg(feature = "encryption")]
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+/// KeyBuffer type that makes sure that the buffer is zeroed out before being
+/// dropped.
+#[cfg(feature = "encryption")]
+type KeyBuffer = Zeroizing<Vec<u8>>;
+
+// 16 byte random salt.
+#[cfg(feature = "encryption")]
+const SALT_SIZE: usize = 16;
+// 16 byte IV for the AES-CTR mode.
+#[cfg(feature = "encryption")]
+const IV_SIZE: usize = 16;
+// 32 byte or 256 bit keys.
+#[cfg(feature = "encryption")]
+const KEY_SIZE: usize = 32;
+// 32 byte message authentication code since HMAC-SHA256 is used.
+#[cfg(feature = "encryption")]
+const MAC_LENGTH: usize = 32;
+// 1 byte for the format version.
+#[cfg(feature = "encryption")]
+const VERSION: u8 = 1;
+/// The plaintext of the sentinel that is used to check the passphrase when
+/// the database is opened.
+#[cfg(feature = "encryption")]
+const SENTINEL: &str = "seshat-source-encryption";
+
+#[cfg(all(feature = "encryption", test))]
+// Tests don't need to protect against brute force attacks.
+const PBKDF_COUNT: u32 = 10;
+
+#[cfg(all(feature = "encryption", not(test)))]
+// Unlike the index key, the sentinel is stored next to the encrypted sources,
+// so the count follows the Matrix key export format.
+const PBKDF_COUNT: u32 = 100_000;
+
+#[cfg(feature = "encryption")]
+/// Cipher for the event sources and profiles that are stored in the database.
+///
+/// A 768 bit key is derived from the user provided passphrase and a random
+/// salt using [PBKDF2][pbkdf], it's split into a 256 bit encryption key, a
+/// 256 bit MAC key and a 256 bit IV key. The salt and the PBKDF count are
+/// stored in the database.
+///
+/// Event sources are encrypted using AES256-CTR with a random IV and
+/// authenticated using HMAC-SHA256:
+///
+/// ```text
+///     ciphertext = AES256-CTR(iv, source)
+///     mac = HMAC-SHA256(mac_key, version || iv || ciphertext)
+///     stored_source = hex(version || iv || mac || ciphertext)
+/// ```
+///
+/// Profiles need to be deduplicated by the database, their IV is derived
+/// from the plaintext instead, `iv = HMAC-SHA256(iv_key, plaintext)`
+/// truncated to 128 bits.
+///
+/// A sentinel value is encrypted and stored alongside the salt, this allows
+/// us to detect a wrong passphrase when the database is opened.
+///
+/// [pbkdf]: https://en.wikipedia.org/wiki/PBKDF2
+pub(crate) struct SourceCipher {
+    encryption_key: KeyBuffer,
+    mac_key: KeyBuffer,
+    iv_key: KeyBuffer,
+}
+
+#[cfg(not(feature = "encryption"))]
+/// Placeholder for the source cipher, sources can't be encrypted without the
+/// encryption feature.
+pub(crate) enum SourceCipher {}
+
+impl SourceCipher {
+    /// Get the cipher for the event sources of the database.
+    ///
+    /// A new sentinel is stored if a passphrase is given and the sources of
+    /// the database aren't encrypted yet, this is only allowed for databases
+    /// that don't contain any events.
+    ///
+    /// Returns `None` if the sources of the database aren't encrypted and a
+    /// `WrongPassphrase` error if the passphrase doesn't match the stored
+    /// sentinel or if it's missing.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn open(
+        connection: &rusqlite::Connection,
+        passphrase: Option<&str>,
+    ) -> Result<Option<Self>> {
+        let stored: Option<(Vec<u8>, u32, String)> = connection
+            .query_row(
+                "SELECT salt, pbkdf_count, sentinel FROM source_encryption",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        match (stored, passphrase) {
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(Error::WrongPassphrase),
+            (Some((salt, pbkdf_count, sentinel)), Some(passphrase)) => {
+                let cipher = SourceCipher::derive(passphrase, &salt, pbkdf_count);
+
+                match cipher.decrypt(&sentinel) {
+                    Ok(s) if s == SENTINEL => Ok(Some(cipher)),
+                    _ => Err(Error::WrongPassphrase),
+                }
+            }
+            (None, Some(passphrase)) => {
+                if Database::get_event_count(connection)? > 0 {
+                    return Err(Error::DatabaseOpenError(
+                        "The database already contains unencrypted event sources".to_owned(),
+                    ));
+                }
+
+                let mut salt = vec![0u8; SALT_SIZE];
+                thread_rng().try_fill(&mut salt[..]).map_err(|e| {
+                    IoError::new(ErrorKind::Other, format!("error generating salt: {:?}", e))
+                })?;
+
+                let cipher = SourceCipher::derive(passphrase, &salt, PBKDF_COUNT);
+                let sentinel = cipher.encrypt(SENTINEL)?;
+
+                connection.execute(
+                    "INSERT INTO source_encryption (salt, pbkdf_count, sentinel)
+                     VALUES (?1, ?2, ?3)",
+                    rusqlite::params![salt, PBKDF_COUNT, sentinel],
+                )?;
+
+                Ok(Some(cipher))
+            }
+        }
+    }
+
+    /// Get the cipher for the event sources of the database.
+    ///
+    /// Returns a `WrongPassphrase` error if the sources of the database are
+    /// encrypted, those can't be decrypted without the encryption feature.
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn open(connection: &rusqlite::Connection) -> Result<Option<Self>> {
+        let encrypted: Option<i64> = connection
+            .query_row("SELECT id FROM source_encryption", [], |row| row.get(0))
+            .optional()?;
+
+        match encrypted {
+            Some(_) => Err(Error::WrongPassphrase),
+            None => Ok(None),
+        }
+    }
+
+    /// Derive the keys of the cipher from the passphrase and the salt.
+    #[cfg(feature = "encryption")]
+    fn derive(passphrase: &str, salt: &[u8], pbkdf_count: u32) -> Self {
+        let mut pbkdf_result = Zeroizing::new([0u8; KEY_SIZE * 3]);
+
+        pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), salt, pbkdf_count, &mut *pbkdf_result);
+        let (encryption_key, rest) = pbkdf_result.split_at(KEY_SIZE);
+        let (mac_key, iv_key) = rest.split_at(KEY_SIZE);
+
+        SourceCipher {
+            encryption_key: Zeroizing::new(Vec::from(encryption_key)),
+            mac_key: Zeroizing::new(Vec::from(mac_key)),
+            iv_key: Zeroizing::new(Vec::from(iv_key)),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn hmac(key: &[u8]) -> std::io::Result<Hmac<Sha256>> {
+        Hmac::<Sha256>::new_from_slice(key)
+            .map_err(|e| IoError::new(ErrorKind::Other, format!("error creating hmac: {:?}", e)))
+    }
+
+    #[cfg(feature = "encryption")]
+    fn encrypt_with_iv(&self, plaintext: &str, iv: &[u8]) -> std::io::Result<String> {
+        let mut encryptor = Aes256Ctr::new_from_slices(&self.encryption_key, iv).map_err(|e| {
+            IoError::new(
+                ErrorKind::Other,
+                format!("error initializing cipher: {:?}", e),
+            )
+        })?;
+
+        let mut ciphertext = plaintext.as_bytes().to_vec();
+        encryptor
+            .try_apply_keystream(&mut ciphertext)
+            .map_err(|e| IoError::new(ErrorKind::Other, format!("unable to encrypt: {:?}", e)))?;
+
+        let mut hmac = SourceCipher::hmac(&self.mac_key)?;
+        hmac.update(&[VERSION]);
+        hmac.update(iv);
+        hmac.update(&ciphertext);
+        let mac = hmac.finalize().into_bytes();
+
+        let mut data = Vec::with_capacity(1 + IV_SIZE + MAC_LENGTH + ciphertext.len());
+        data.push(VERSION);
+        data.extend_from_slice(iv);
+        data.extend_from_slice(&mac);
+        data.extend_from_slice(&ciphertext);
+
+        Ok(data.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Encrypt the given string using a random IV.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypt(&self, plaintext: &str) -> std::io::Result<String> {
+        let mut iv = [0u8; IV_SIZE];
+        thread_rng()
+            .try_fill(&mut iv[..])
+            .map_err(|e| IoError::new(ErrorKind::Other, format!("error generating iv: {:?}", e)))?;
+
+        self.encrypt_with_iv(plaintext, &iv)
+    }
+
+    /// Encrypt the given string using an IV that is derived from the
+    /// plaintext, encrypting the same string twice results in the same
+    /// ciphertext.
+    #[cfg(feature = "encryption")]
+    fn encrypt_deterministic(&self, plaintext: &str) -> std::io::Result<String> {
+        let mut hmac = SourceCipher::hmac(&self.iv_key)?;
+        hmac.update(plaintext.as_bytes());
+        let iv = hmac.finalize().into_bytes();
+
+        self.encrypt_with_iv(plaintext, &iv[..IV_SIZE])
+    }
+
+    /// Decrypt a string that was encrypted by this cipher.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn decrypt(&self, ciphertext: &str) -> std::io::Result<String> {
+        let invalid = || IoError::new(ErrorKind::InvalidData, "invalid encrypted event source");
+
+        if ciphertext.len() % 2 != 0 || !ciphertext.is_ascii() {
+            return Err(invalid());
+        }
+
+        let data = (0..ciphertext.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&ciphertext[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+
+        if data.len() < 1 + IV_SIZE + MAC_LENGTH || data[0] != VERSION {
+            return Err(invalid());
+        }
+
+        let (iv, rest) = data[1..].split_at(IV_SIZE);
+        let (expected_mac, ciphertext) = rest.split_at(MAC_LENGTH);
+
+        let mut hmac = SourceCipher::hmac(&self.mac_key)?;
+        hmac.update(&[VERSION]);
+        hmac.update(iv);
+        hmac.update(ciphertext);
+
+        if hmac.verify(expected_mac).is_err() {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "invalid MAC of the event source",
+            ));
+        }
+
+        let mut decryptor = Aes256Ctr::new_from_slices(&self.encryption_key, iv).map_err(|e| {
+            IoError::new(
+                ErrorKind::Other,
+                format!("error initializing cipher: {:?}", e),
+            )
+        })?;
+
+        let mut plaintext = ciphertext.to_vec();
+        decryptor.try_apply_keystream(&mut plaintext).map_err(|_| {
+            IoError::new(
+                ErrorKind::Other,
+                "Decryption error, reached end of the keystream.",
+            )
+        })?;
+
+        String::from_utf8(plaintext).map_err(|_| invalid())
+    }
+
+    /// Encrypt the sources and the sender profiles of the given events.
+    ///
+    /// Events that can't be encrypted are dropped.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypt_events(&self, events: &mut Vec<(Event, Profile)>) -> Result<()> {
+        let encrypt_field =
+            |field: Option<String>| field.map(|f| self.encrypt_deterministic(&f)).transpose();
+
+        *events = events
+            .drain(..)
+            .map(|(mut event, profile)| {
+                event.source = self.encrypt(&event.source)?;
+                let profile = Profile {
+                    displayname: encrypt_field(profile.displayname)?,
+                    avatar_url: encrypt_field(profile.avatar_url)?,
+                };
+                Ok((event, profile))
+            })
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(())
+    }
+
+    /// Decrypt a profile that was loaded from the database.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn decrypt_profile(&self, profile: &mut Profile) -> std::io::Result<()> {
+        // Missing profile fields are stored as empty strings.
+        for field in [&mut profile.displayname, &mut profile.avatar_url] {
+            if let Some(f) = field.as_mut().filter(|f| !f.is_empty()) {
+                *f = self.decrypt(f)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decrypt the sources and profiles of a search result.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn decrypt_result(&self, result: &mut SearchResult) -> std::io::Result<()> {
+        result.event_source = self.decrypt(&result.event_source)?;
+
+        // The stubs of redacted events aren't encrypted.
+        for event in result
+            .events_before
+            .iter_mut()
+            .chain(result.events_after.iter_mut())
+            .filter(|e| !e.redacted)
+        {
+            event.source = self.decrypt(&event.source)?;
+        }
+
+        for profile in result.profile_info.values_mut() {
+            self.decrypt_profile(profile)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn decrypt(&self, _ciphertext: &str) -> std::io::Result<String> {
+        match *self {}
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn encrypt_events(&self, _events: &mut Vec<(Event, Profile)>) -> Result<()> {
+        match *self {}
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn decrypt_profile(&self, _profile: &mut Profile) -> std::io::Result<()> {
+        match *self {}
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn decrypt_result(&self, _result: &mut SearchResult) -> std::io::Result<()> {
+        match *self {}
+    }
+}
@@ -12,9 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{cmp::Ordering, collections::HashMap};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::sleep,
+    time::Duration,
+};
 
-use rusqlite::{params, params_from_iter, ToSql};
+use rusqlite::{params, params_from_iter, OptionalExtension, ToSql};
 
 #[cfg(test)]
 use r2d2::PooledConnection;
@@ -22,47 +30,222 @@ use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
 
 use crate::{
-    config::LoadDirection,
-    database::{SearchResult, DATABASE_VERSION},
-    error::Result,
-    events::{CrawlerCheckpoint, Event, EventContext, EventId, Profile, SerializedEvent},
-    index::Writer as IndexWriter,
+    config::{Config, ContextLimits, LoadDirection},
+    database::{
+        source_cache::{CachedEvent, SharedSourceCache},
+        source_cipher::SourceCipher,
+        ContextTruncation, EventWithContext, RecoveryDatabase, SearchResult, DATABASE_VERSION,
+        INDEX_META,
+    },
+    error::{Error, Result},
+    events::{
+        CheckpointDirection, ContextEvent, CrawlerCheckpoint, Event, EventContext, EventId,
+        EventType, Profile, Reaction, RoomAlias, RoomId, SerializedEvent,
+    },
+    index::{Matches, Writer as IndexWriter},
     Database,
 };
 
 const FILE_EVENT_TYPES: &str = "'m.image', 'm.file', 'm.audio', 'm.video'";
 
+/// How many events should have their context loaded using a single query.
+const CONTEXT_BATCH_SIZE: usize = 100;
+
+/// How many event or room ids are looked up using a single query, this keeps
+/// the number of query parameters below the limit of SQLite.
+const LOOKUP_BATCH_SIZE: usize = 500;
+
+/// How many search terms the search history keeps.
+const MAX_SEARCH_HISTORY: i64 = 100;
+
+/// The type under which the canonical alias of a room is remembered, see
+/// `store_room_name()`.
+const CANONICAL_ALIAS_TYPE: &str = "m.room.canonical_alias";
+
+/// The outcome of recording a room name or topic event as the latest one of
+/// its room.
+pub(crate) enum StateUpdate {
+    /// The event is the latest one, it replaced the contained event if there
+    /// was one.
+    Latest(Option<EventId>),
+    /// A newer event of the same type is already known.
+    Outdated,
+}
+
+/// How many times should an operation be retried if the database is busy.
+const BUSY_RETRY: u32 = 10;
+/// How long should we wait before retrying a busy operation, the wait time is
+/// multiplied by the number of the retry.
+const BUSY_SLEEP: Duration = Duration::from_millis(10);
+
 impl Database {
+    /// Run the given database operation, retrying it with an increasing
+    /// back-off if the database is busy.
+    ///
+    /// Usually the busy timeout on a sqlite connection should handle this,
+    /// but setting it on the connection didn't seem to get rid of database
+    /// busy errors like expected.
+    pub(crate) fn retry_on_busy<T, F>(mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut retry = 0;
+
+        loop {
+            match operation() {
+                Err(Error::DatabaseError(rusqlite::Error::SqliteFailure(e, _)))
+                    if e.code == rusqlite::ffi::ErrorCode::DatabaseBusy && retry < BUSY_RETRY =>
+                {
+                    retry += 1;
+                    sleep(BUSY_SLEEP * retry);
+                }
+                ret => return ret,
+            }
+        }
+    }
+
     /// Write the events to the database.
     /// Returns a tuple containing a boolean and an array if integers. The
-    /// boolean notifies us if all the events were already added to the
-    /// database, the integers are the database ids of our events.
+    /// boolean notifies us if any of the events wasn't part of the database
+    /// yet, the integers are the database ids of our events.
     pub(crate) fn write_events_helper(
         connection: &rusqlite::Connection,
         index_writer: &mut IndexWriter,
         events: &mut Vec<(Event, Profile)>,
+        index_state_history: bool,
     ) -> Result<(bool, Vec<i64>)> {
-        let mut ret = Vec::new();
+        let mut new_events = false;
         let mut event_ids = Vec::new();
 
         for (mut e, mut p) in events.drain(..) {
+            // The event was deleted before it was added, e.g. a redaction
+            // arrived before the crawler got to the original event.
+            if Database::take_pending_deletion(connection, &e.event_id)? {
+                new_events = true;
+                continue;
+            }
+
+            if e.event_type == EventType::Name {
+                Database::store_room_name(
+                    connection,
+                    &e.room_id,
+                    &e.event_type.to_string(),
+                    &e.content_value,
+                    e.server_ts,
+                )?;
+            }
+
             let event_id = Database::save_event(connection, &mut e, &mut p)?;
-            match event_id {
-                Some(id) => {
-                    index_writer.add_event(&e);
-                    ret.push(false);
-                    event_ids.push(id);
-                }
-                None => {
-                    ret.push(true);
-                    continue;
+
+            if let Some(id) = event_id {
+                new_events = true;
+
+                if e.event_type != EventType::Message {
+                    let update = Database::replace_latest_state_event(connection, &e)?;
+
+                    // Older names and topics stay in the store for the
+                    // context of events, but aren't searchable anymore.
+                    if !index_state_history {
+                        match update {
+                            StateUpdate::Latest(Some(replaced)) => {
+                                // The deletion is committed together with the
+                                // batch, a failed batch rolls it back.
+                                index_writer.delete_event_on_commit(&replaced);
+                                Database::remove_uncommitted_event(connection, &replaced)?;
+                            }
+                            StateUpdate::Latest(None) => (),
+                            StateUpdate::Outdated => {
+                                Database::remove_uncommitted_event(connection, &e.event_id)?;
+                                continue;
+                            }
+                        }
+                    }
                 }
+
+                index_writer.add_event(&e);
+                event_ids.push(id);
+            }
+        }
+
+        Ok((new_events, event_ids))
+    }
+
+    /// Record a room name or topic event as the latest one of its type in
+    /// its room.
+    ///
+    /// The crawler adds events of the room history after newer ones, an
+    /// event that is older than the recorded one doesn't replace it.
+    pub(crate) fn replace_latest_state_event(
+        connection: &rusqlite::Connection,
+        event: &Event,
+    ) -> rusqlite::Result<StateUpdate> {
+        let latest: Option<(EventId, i64)> = connection
+            .query_row(
+                "SELECT event_id, server_ts FROM latest_state_events
+                 WHERE room_id = ?1 AND type = ?2",
+                params![event.room_id, event.event_type],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match latest {
+            Some((_, server_ts)) if server_ts > event.server_ts => Ok(StateUpdate::Outdated),
+            latest => {
+                connection.execute(
+                    "INSERT OR REPLACE INTO latest_state_events
+                     (room_id, type, event_id, server_ts) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        event.room_id,
+                        event.event_type,
+                        event.event_id,
+                        event.server_ts
+                    ],
+                )?;
+
+                Ok(StateUpdate::Latest(latest.map(|(event_id, _)| event_id)))
             }
         }
+    }
+
+    /// Is the given event a room name or topic event that was replaced by a
+    /// newer one.
+    pub(crate) fn is_outdated_state_event(
+        connection: &rusqlite::Connection,
+        event: &Event,
+    ) -> rusqlite::Result<bool> {
+        if event.event_type == EventType::Message {
+            return Ok(false);
+        }
+
+        connection.query_row(
+            "SELECT COUNT(*) > 0 FROM latest_state_events
+             WHERE room_id = ?1 AND type = ?2 AND event_id != ?3",
+            params![event.room_id, event.event_type, event.event_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Forget that a stored event still needs to be added to the index.
+    fn remove_uncommitted_event(
+        connection: &rusqlite::Connection,
+        event_id: &str,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "DELETE FROM uncommitted_events
+             WHERE event_id IN (SELECT id FROM events WHERE event_id = ?1)",
+            [event_id],
+        )?;
 
-        Ok((ret.iter().all(|&x| x), event_ids))
+        Ok(())
     }
 
+    /// Delete an event from the database and from the index.
+    ///
+    /// If the event isn't part of the database yet, the deletion is
+    /// remembered and the event is dropped once it gets added.
+    ///
+    /// Returns true if the event was deleted, false if the deletion was
+    /// deferred.
     pub(crate) fn delete_event_helper(
         connection: &mut rusqlite::Connection,
         index_writer: &mut IndexWriter,
@@ -71,23 +254,493 @@ impl Database {
     ) -> Result<bool> {
         let transaction = connection.transaction()?;
 
-        Database::delete_event_by_id(&transaction, &event_id)?;
+        // Remember where the message was so the context of other events can
+        // show that it was deleted. The stub keeps the insert id of the
+        // message unless a stub already took it, the id of the newest event
+        // gets reused once it's deleted.
+        transaction.execute(
+            "INSERT OR IGNORE INTO redacted_events (id, event_id, room_id, server_ts)
+             SELECT
+                 CASE WHEN EXISTS (
+                     SELECT 1 FROM redacted_events WHERE redacted_events.id == events.id
+                 ) THEN NULL ELSE id END,
+                 event_id, room_id, server_ts
+             FROM events
+             WHERE event_id == ?1 AND type == 'm.room.message'",
+            [&event_id],
+        )?;
+
+        let state_event: Option<(RoomId, EventType, i64)> = transaction
+            .query_row(
+                "SELECT rooms.room_id, type, server_ts
+                 FROM events INNER JOIN rooms ON rooms.id = events.room_id
+                 WHERE event_id = ?1 AND type != 'm.room.message'",
+                [&event_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        // A live event that wasn't committed to the index yet is still
+        // referenced by the uncommitted events table.
+        Database::remove_uncommitted_event(&transaction, &event_id)?;
+        let deleted = Database::delete_event_by_id(&transaction, &event_id)?;
+
+        // A redacted room name or topic stops being the latest one of its
+        // room.
+        if let Some((room_id, event_type, server_ts)) = state_event {
+            Database::forget_state_event(
+                &transaction,
+                &room_id,
+                &event_type,
+                &event_id,
+                server_ts,
+            )?;
+        }
+
+        // Reactions aren't stored as events, a redacted reaction only stops
+        // counting towards the event it relates to.
+        if deleted == 0 && Database::delete_reaction(&transaction, &event_id)? {
+            transaction.commit()?;
+            return Ok(true);
+        }
+
+        if deleted == 0 {
+            transaction.execute(
+                "INSERT OR IGNORE INTO pending_deletions (event_id) VALUES (?1)",
+                [&event_id],
+            )?;
+            transaction.commit()?;
+
+            return Ok(false);
+        }
+
+        Database::take_pending_deletion(&transaction, &event_id)?;
+        Database::add_tombstones(&transaction, deleted)?;
+        transaction.execute(
+            "DELETE FROM reactions WHERE target_event_id = ?1",
+            [&event_id],
+        )?;
         transaction.execute(
             "INSERT OR IGNORE INTO pending_deletion_events (event_id) VALUES (?1)",
             [&event_id],
         )?;
-        transaction.commit().unwrap();
+        transaction.commit()?;
 
-        index_writer.delete_event(&event_id);
+        index_writer.delete_event(&event_id)?;
         pending_deletion_events.push(event_id);
 
         let committed = index_writer.commit()?;
 
         if committed {
-            Database::mark_events_as_deleted(connection, pending_deletion_events)?;
+            Database::retry_on_busy(|| {
+                Database::mark_events_as_deleted(connection, pending_deletion_events)
+            })?;
         }
 
-        Ok(committed)
+        Ok(true)
+    }
+
+    /// Store a reaction to count it towards the event it relates to.
+    ///
+    /// Storing the same reaction again doesn't count it twice.
+    pub(crate) fn store_reaction(
+        connection: &rusqlite::Connection,
+        reaction: &Reaction,
+    ) -> rusqlite::Result<()> {
+        connection
+            .prepare_cached(
+                "INSERT OR IGNORE INTO reactions (event_id, target_event_id, room_id)
+                 VALUES (?1, ?2, ?3)",
+            )?
+            .execute([
+                &reaction.event_id,
+                &reaction.target_event_id,
+                &reaction.room_id,
+            ])?;
+
+        Ok(())
+    }
+
+    /// Remember the canonical alias of a room to label its search results.
+    pub(crate) fn store_room_alias(
+        connection: &rusqlite::Connection,
+        alias: &RoomAlias,
+    ) -> rusqlite::Result<()> {
+        Database::store_room_name(
+            connection,
+            &alias.room_id,
+            CANONICAL_ALIAS_TYPE,
+            &alias.alias,
+            alias.server_ts,
+        )
+    }
+
+    /// Remember a name or the canonical alias of a room.
+    ///
+    /// Every room keeps the newest name and the newest alias it had, older
+    /// ones that are added by the crawler afterwards don't replace them.
+    pub(crate) fn store_room_name(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+        name_type: &str,
+        name: &str,
+        server_ts: i64,
+    ) -> rusqlite::Result<()> {
+        connection
+            .prepare_cached(
+                "INSERT INTO room_names (room_id, type, name, server_ts)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (room_id, type) DO UPDATE
+                 SET name = excluded.name, server_ts = excluded.server_ts
+                 WHERE excluded.server_ts >= room_names.server_ts",
+            )?
+            .execute(params![room_id, name_type, name.trim(), server_ts])?;
+
+        Ok(())
+    }
+
+    /// Load the names to display for the given rooms.
+    ///
+    /// The name of a room is preferred over its canonical alias, rooms that
+    /// have neither, or whose name was removed, map to `None`.
+    pub(crate) fn load_room_names(
+        connection: &rusqlite::Connection,
+        room_ids: &[RoomId],
+    ) -> rusqlite::Result<HashMap<RoomId, Option<String>>> {
+        let mut names: HashMap<RoomId, Option<String>> =
+            room_ids.iter().map(|r| (r.clone(), None)).collect();
+
+        for chunk in room_ids.chunks(LOOKUP_BATCH_SIZE) {
+            let parameter_str = ", ?".repeat(chunk.len() - 1);
+
+            let mut stmt = connection.prepare(&format!(
+                "SELECT room_id, type, name FROM room_names
+                 WHERE room_id IN (?{}) AND name != ''",
+                &parameter_str
+            ))?;
+
+            let rows = stmt.query_map(params_from_iter(chunk), |row| {
+                Ok((
+                    row.get::<_, RoomId>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?;
+
+            for row in rows {
+                let (room_id, name_type, name) = row?;
+                let entry = names.entry(room_id).or_default();
+
+                if name_type != CANONICAL_ALIAS_TYPE || entry.is_none() {
+                    *entry = Some(name);
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Delete a reaction.
+    ///
+    /// Returns true if a reaction with the given event id was stored.
+    pub(crate) fn delete_reaction(
+        connection: &rusqlite::Connection,
+        event_id: &str,
+    ) -> rusqlite::Result<bool> {
+        let deleted = connection
+            .prepare_cached("DELETE FROM reactions WHERE event_id = ?1")?
+            .execute([event_id])?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Load the number of reactions of the given events.
+    ///
+    /// Events without any reactions are part of the result with a count of
+    /// zero.
+    pub(crate) fn load_reaction_counts(
+        connection: &rusqlite::Connection,
+        event_ids: &[EventId],
+    ) -> rusqlite::Result<HashMap<EventId, u64>> {
+        let mut counts: HashMap<EventId, u64> = event_ids.iter().map(|e| (e.clone(), 0)).collect();
+
+        for chunk in event_ids.chunks(50) {
+            let parameter_str = ", ?".repeat(chunk.len() - 1);
+
+            let mut stmt = connection.prepare(&format!(
+                "SELECT target_event_id, COUNT(*) FROM reactions
+                     WHERE target_event_id IN (?{})
+                     GROUP BY target_event_id",
+                &parameter_str
+            ))?;
+
+            let rows = stmt.query_map(params_from_iter(chunk), |row| {
+                Ok((row.get::<_, EventId>(0)?, row.get::<_, i64>(1)?))
+            })?;
+
+            for row in rows {
+                let (event_id, count) = row?;
+                counts.insert(event_id, count as u64);
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Forget a deferred deletion of the given event.
+    ///
+    /// Returns true if a deletion of the event was pending.
+    pub(crate) fn take_pending_deletion(
+        connection: &rusqlite::Connection,
+        event_id: &str,
+    ) -> rusqlite::Result<bool> {
+        let deleted = connection
+            .prepare_cached("DELETE FROM pending_deletions WHERE event_id = ?1")?
+            .execute([event_id])?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Delete the events that were sent before the given timestamp.
+    ///
+    /// The events are removed from the database in a single transaction and
+    /// the deletions of all of them are committed to the index together.
+    ///
+    /// Returns the number of deleted events.
+    pub(crate) fn delete_events_older_than_helper(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        timestamp: i64,
+        pending_deletion_events: &mut Vec<EventId>,
+    ) -> Result<usize> {
+        Database::delete_events_where(
+            connection,
+            index_writer,
+            "server_ts < ?1",
+            &timestamp,
+            pending_deletion_events,
+        )
+    }
+
+    pub(crate) fn delete_room_events_helper(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        room_id: &str,
+        pending_deletion_events: &mut Vec<EventId>,
+    ) -> Result<usize> {
+        let deleted = Database::delete_events_where(
+            connection,
+            index_writer,
+            "room_id = (SELECT id FROM rooms WHERE room_id = ?1)",
+            &room_id,
+            pending_deletion_events,
+        )?;
+
+        // Events that are added to the room again shouldn't be compared to
+        // the deleted ones.
+        connection.execute(
+            "DELETE FROM latest_state_events WHERE room_id = ?1",
+            [room_id],
+        )?;
+        connection.execute("DELETE FROM reactions WHERE room_id = ?1", [room_id])?;
+        connection.execute("DELETE FROM room_names WHERE room_id = ?1", [room_id])?;
+
+        Ok(deleted)
+    }
+
+    /// Delete a batch of the events of the given sender from the database and
+    /// from the index.
+    ///
+    /// Unlike for redactions, no stubs are left behind, nothing should
+    /// remember that the sender ever sent an event.
+    ///
+    /// Returns the number of deleted events, zero once no event of the sender
+    /// is left.
+    pub(crate) fn delete_sender_events_helper(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        sender: &str,
+        batch_size: usize,
+        pending_deletion_events: &mut Vec<EventId>,
+    ) -> Result<usize> {
+        let transaction = connection.transaction()?;
+
+        let events: Vec<(i64, EventId, RoomId, EventType, i64)> = {
+            let mut stmt = transaction.prepare_cached(
+                "SELECT events.id, event_id, rooms.room_id, type, server_ts
+                 FROM events INNER JOIN rooms ON rooms.id = events.room_id
+                 WHERE sender = ?1 LIMIT ?2",
+            )?;
+            let events = stmt.query_map(params![sender, batch_size as i64], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })?;
+            events.collect::<rusqlite::Result<_>>()?
+        };
+
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        {
+            // Events that weren't committed to the index yet reference the
+            // event rows, they need to go first.
+            let mut delete_uncommitted =
+                transaction.prepare("DELETE FROM uncommitted_events WHERE event_id = ?1")?;
+            let mut delete_event = transaction.prepare("DELETE FROM events WHERE id = ?1")?;
+            let mut delete_reactions =
+                transaction.prepare("DELETE FROM reactions WHERE target_event_id = ?1")?;
+            let mut add_pending_deletion = transaction
+                .prepare("INSERT OR IGNORE INTO pending_deletion_events (event_id) VALUES (?1)")?;
+
+            for (id, event_id, room_id, event_type, server_ts) in &events {
+                delete_uncommitted.execute([id])?;
+                delete_event.execute([id])?;
+                delete_reactions.execute([event_id])?;
+                add_pending_deletion.execute([event_id])?;
+
+                if *event_type != EventType::Message {
+                    Database::forget_state_event(
+                        &transaction,
+                        room_id,
+                        event_type,
+                        event_id,
+                        *server_ts,
+                    )?;
+                }
+            }
+        }
+
+        Database::add_tombstones(&transaction, events.len())?;
+        transaction.commit()?;
+
+        let event_ids: Vec<EventId> = events.into_iter().map(|(_, e, _, _, _)| e).collect();
+        index_writer.delete_events(&event_ids)?;
+
+        let count = event_ids.len();
+        pending_deletion_events.extend(event_ids);
+
+        Database::retry_on_busy(|| {
+            Database::mark_events_as_deleted(connection, pending_deletion_events)
+        })?;
+
+        Ok(count)
+    }
+
+    /// Forget a room name or topic event that was deleted.
+    ///
+    /// If it was the latest event of its type in its room, the newest one
+    /// that is left takes its place. The name of the room is removed if the
+    /// event set it, the remaining name events don't store their name.
+    fn forget_state_event(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+        event_type: &EventType,
+        event_id: &str,
+        server_ts: i64,
+    ) -> rusqlite::Result<()> {
+        let replaced = connection.execute(
+            "DELETE FROM latest_state_events
+             WHERE room_id = ?1 AND type = ?2 AND event_id = ?3",
+            params![room_id, event_type, event_id],
+        )?;
+
+        if replaced > 0 {
+            connection.execute(
+                "INSERT INTO latest_state_events (room_id, type, event_id, server_ts)
+                 SELECT rooms.room_id, events.type, events.event_id, MAX(events.server_ts)
+                 FROM events INNER JOIN rooms ON rooms.id = events.room_id
+                 WHERE rooms.room_id = ?1 AND events.type = ?2
+                 GROUP BY events.room_id",
+                params![room_id, event_type],
+            )?;
+        }
+
+        if *event_type == EventType::Name {
+            connection.execute(
+                "DELETE FROM room_names WHERE room_id = ?1 AND type = ?2 AND server_ts = ?3",
+                params![room_id, event_type, server_ts],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete the events that match the given SQL condition from the database
+    /// and from the index.
+    ///
+    /// Returns the number of deleted events.
+    fn delete_events_where(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        condition: &str,
+        parameter: &dyn ToSql,
+        pending_deletion_events: &mut Vec<EventId>,
+    ) -> Result<usize> {
+        let transaction = connection.transaction()?;
+
+        let event_ids: Vec<EventId> = {
+            let mut stmt =
+                transaction.prepare(&format!("SELECT event_id FROM events WHERE {}", condition))?;
+            let event_ids = stmt.query_map([parameter], |row| row.get(0))?;
+            event_ids.collect::<rusqlite::Result<_>>()?
+        };
+
+        // The stubs of redacted events go together with the events around
+        // them.
+        transaction.execute(
+            &format!("DELETE FROM redacted_events WHERE {}", condition),
+            [parameter],
+        )?;
+
+        if event_ids.is_empty() {
+            transaction.commit()?;
+            return Ok(0);
+        }
+
+        // Events that weren't committed to the index yet reference the event
+        // rows, they need to go first.
+        transaction.execute(
+            &format!(
+                "DELETE FROM uncommitted_events
+                 WHERE event_id IN (SELECT id FROM events WHERE {})",
+                condition
+            ),
+            [parameter],
+        )?;
+
+        let deleted = transaction.execute(
+            &format!("DELETE FROM events WHERE {}", condition),
+            [parameter],
+        )?;
+        Database::add_tombstones(&transaction, deleted)?;
+
+        {
+            let mut stmt = transaction
+                .prepare("INSERT OR IGNORE INTO pending_deletion_events (event_id) VALUES (?1)")?;
+
+            for event_id in &event_ids {
+                stmt.execute([event_id])?;
+            }
+        }
+
+        transaction.commit()?;
+
+        index_writer.delete_events(&event_ids)?;
+
+        let count = event_ids.len();
+        pending_deletion_events.extend(event_ids);
+
+        Database::retry_on_busy(|| {
+            Database::mark_events_as_deleted(connection, pending_deletion_events)
+        })?;
+
+        Ok(count)
     }
 
     pub(crate) fn mark_events_as_deleted(
@@ -145,7 +798,12 @@ impl Database {
         Ok(())
     }
 
-    pub(crate) fn write_events(
+    /// Store the events and update the crawler checkpoints in a single
+    /// transaction.
+    ///
+    /// The events are added to the index writer and recorded as uncommitted
+    /// events, they will be searchable after the next index commit.
+    pub(crate) fn store_events(
         connection: &mut rusqlite::Connection,
         index_writer: &mut IndexWriter,
         message: (
@@ -153,13 +811,14 @@ impl Database {
             Option<CrawlerCheckpoint>,
             &mut Vec<(Event, Profile)>,
         ),
-        force_commit: bool,
         uncommitted_events: &mut Vec<i64>,
-    ) -> Result<(bool, bool)> {
+        index_state_history: bool,
+    ) -> Result<bool> {
         let (new_checkpoint, old_checkpoint, events) = message;
         let transaction = connection.transaction()?;
 
-        let (ret, event_ids) = Database::write_events_helper(&transaction, index_writer, events)?;
+        let (ret, event_ids) =
+            Database::write_events_helper(&transaction, index_writer, events, index_state_history)?;
         Database::replace_crawler_checkpoint(
             &transaction,
             new_checkpoint.as_ref(),
@@ -170,6 +829,20 @@ impl Database {
 
         uncommitted_events.extend(event_ids);
 
+        Ok(ret)
+    }
+
+    /// Commit the index and mark the uncommitted events as indexed if the
+    /// commit happened.
+    ///
+    /// Returns true if the index was committed, commits are rate limited
+    /// unless they are forced.
+    pub(crate) fn commit_events(
+        connection: &mut rusqlite::Connection,
+        index_writer: &mut IndexWriter,
+        force_commit: bool,
+        uncommitted_events: &mut Vec<i64>,
+    ) -> Result<bool> {
         let committed = if force_commit {
             index_writer.force_commit()?;
             true
@@ -178,10 +851,12 @@ impl Database {
         };
 
         if committed {
-            Database::mark_events_as_indexed(connection, uncommitted_events)?;
+            Database::retry_on_busy(|| {
+                Database::mark_events_as_indexed(connection, uncommitted_events)
+            })?;
         }
 
-        Ok((ret, committed))
+        Ok(committed)
     }
 
     pub(crate) fn get_user_version(connection: &rusqlite::Connection) -> Result<i64> {
@@ -193,6 +868,49 @@ impl Database {
         Ok(())
     }
 
+    /// Record that the given number of events were deleted.
+    ///
+    /// The space deleted events take up is only reclaimed by a compaction of
+    /// the database, the number of deletions since the last compaction is
+    /// used to decide if a compaction is worthwhile.
+    pub(crate) fn add_tombstones(
+        connection: &rusqlite::Connection,
+        count: usize,
+    ) -> rusqlite::Result<()> {
+        if count > 0 {
+            connection.execute("UPDATE tombstones SET count = count + ?1", [count as i64])?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn get_tombstone_count(connection: &rusqlite::Connection) -> rusqlite::Result<i64> {
+        connection.query_row("SELECT count FROM tombstones", [], |row| row.get(0))
+    }
+
+    /// Reclaim the space of deleted rows and reset the tombstone count.
+    ///
+    /// Databases that were created before incremental vacuuming was enabled
+    /// are switched over to it using a full vacuum.
+    pub(crate) fn vacuum(connection: &rusqlite::Connection) -> Result<()> {
+        Database::delete_unused_profiles(connection)?;
+
+        let auto_vacuum: i64 =
+            connection.pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+
+        // 2 is the value of the INCREMENTAL auto vacuum mode.
+        if auto_vacuum == 2 {
+            connection.execute_batch("PRAGMA incremental_vacuum;")?;
+        } else {
+            connection.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+            connection.execute_batch("VACUUM;")?;
+        }
+
+        connection.execute("UPDATE tombstones SET count = 0", [])?;
+
+        Ok(())
+    }
+
     pub(crate) fn get_version(connection: &mut rusqlite::Connection) -> Result<(i64, bool)> {
         connection.execute(
             "CREATE TABLE IF NOT EXISTS version (
@@ -270,20 +988,213 @@ impl Database {
             version = 4;
         }
 
-        Ok((version, reindex_needed))
-    }
+        if version == 4 {
+            let transaction = connection.transaction()?;
 
-    pub(crate) fn create_tables(conn: &rusqlite::Connection) -> Result<()> {
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS profile (
-                id INTEGER NOT NULL PRIMARY KEY,
-                user_id TEXT NOT NULL,
-                displayname TEXT NOT NULL,
-                avatar_url TEXT NOT NULL,
-                UNIQUE(user_id,displayname,avatar_url)
-            )",
-            [],
-        )?;
+            transaction.execute("UPDATE reindex_needed SET reindex_needed = ?1", [true])?;
+            transaction.execute("UPDATE version SET version = '5'", [])?;
+            transaction.commit()?;
+
+            reindex_needed = true;
+            version = 5;
+        }
+
+        if version == 5 {
+            let transaction = connection.transaction()?;
+
+            // The thread of an event is added to the index, older events
+            // will get it from their source while reindexing.
+            let has_thread_id: bool = transaction.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('events') WHERE name = 'thread_id'",
+                [],
+                |row| row.get(0),
+            )?;
+
+            if !has_thread_id {
+                transaction.execute("ALTER TABLE events ADD COLUMN thread_id TEXT", [])?;
+            }
+            transaction.execute("UPDATE reindex_needed SET reindex_needed = ?1", [true])?;
+            transaction.execute("UPDATE version SET version = '6'", [])?;
+            transaction.commit()?;
+
+            reindex_needed = true;
+            version = 6;
+        }
+
+        if version == 6 {
+            let transaction = connection.transaction()?;
+
+            // Checkpoints used to be unique including the crawl type, a
+            // checkpoint that was stored once for a full crawl and once for a
+            // partial one made the crawler fetch the same history twice. Keep
+            // the most recently stored one of those.
+            let has_checkpoints: bool = transaction.query_row(
+                "SELECT COUNT(*) > 0 FROM sqlite_master
+                 WHERE type = 'table' AND name = 'crawlercheckpoints'",
+                [],
+                |row| row.get(0),
+            )?;
+
+            if has_checkpoints {
+                transaction.execute(
+                    "DELETE FROM crawlercheckpoints WHERE id NOT IN (
+                        SELECT MAX(id) FROM crawlercheckpoints
+                        GROUP BY room_id, token, direction
+                    )",
+                    [],
+                )?;
+                transaction.execute(
+                    "ALTER TABLE crawlercheckpoints RENAME TO old_crawlercheckpoints",
+                    [],
+                )?;
+                Database::create_checkpoints_table(&transaction)?;
+                transaction.execute(
+                    "INSERT INTO crawlercheckpoints
+                     (id, room_id, token, full_crawl, direction)
+                     SELECT id, room_id, token, full_crawl, direction
+                     FROM old_crawlercheckpoints",
+                    [],
+                )?;
+                transaction.execute("DROP TABLE old_crawlercheckpoints", [])?;
+            }
+
+            transaction.execute("UPDATE version SET version = '7'", [])?;
+            transaction.commit()?;
+
+            version = 7;
+        }
+
+        if version == 7 {
+            let transaction = connection.transaction()?;
+
+            // Profiles used to be kept forever, even after all the events
+            // that referenced them were deleted.
+            Database::delete_unused_profiles(&transaction)?;
+            transaction.execute("UPDATE version SET version = '8'", [])?;
+            transaction.commit()?;
+
+            version = 8;
+        }
+
+        if version == 8 {
+            let transaction = connection.transaction()?;
+
+            // Only the latest name and topic of a room stay searchable, find
+            // them among the existing events so they are known to a reindex.
+            Database::create_latest_state_table(&transaction)?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO latest_state_events (room_id, type, event_id, server_ts)
+                 SELECT rooms.room_id, events.type, events.event_id, MAX(events.server_ts)
+                 FROM events INNER JOIN rooms ON rooms.id = events.room_id
+                 WHERE events.type != 'm.room.message'
+                 GROUP BY events.room_id, events.type",
+                [],
+            )?;
+            transaction.execute("UPDATE version SET version = '9'", [])?;
+            transaction.commit()?;
+
+            version = 9;
+        }
+
+        if version == 9 {
+            let transaction = connection.transaction()?;
+
+            // Events can be filtered by the links they contain, find the
+            // links of the existing events whose sources can be read here.
+            // Encrypted sources get theirs while reindexing.
+            let has_contains_url: bool = transaction.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('events') WHERE name = 'contains_url'",
+                [],
+                |row| row.get(0),
+            )?;
+
+            if !has_contains_url {
+                transaction.execute(
+                    "ALTER TABLE events ADD COLUMN contains_url BOOLEAN NOT NULL DEFAULT 0",
+                    [],
+                )?;
+            }
+
+            let url_events: Vec<i64> = {
+                let mut stmt = transaction
+                    .prepare("SELECT id, source FROM events WHERE type == 'm.room.message'")?;
+                let rows = stmt.query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?;
+
+                rows.filter_map(|row| row.ok())
+                    .filter(|(_, source)| {
+                        RecoveryDatabase::event_from_json(source)
+                            .map_or(false, |event| event.contains_url())
+                    })
+                    .map(|(id, _)| id)
+                    .collect()
+            };
+
+            for id in url_events {
+                transaction.execute("UPDATE events SET contains_url = 1 WHERE id = ?1", [id])?;
+            }
+
+            transaction.execute("UPDATE reindex_needed SET reindex_needed = ?1", [true])?;
+            transaction.execute("UPDATE version SET version = '10'", [])?;
+            transaction.commit()?;
+
+            reindex_needed = true;
+            version = 10;
+        }
+
+        Ok((version, reindex_needed))
+    }
+
+    /// Create the table that stores the crawler checkpoints.
+    ///
+    /// A checkpoint is identified by the room, the token and the direction,
+    /// storing it again replaces the crawl type of the existing one.
+    fn create_checkpoints_table(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS crawlercheckpoints (
+                id INTEGER NOT NULL PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                token TEXT NOT NULL,
+                full_crawl BOOLEAN NOT NULL,
+                direction TEXT NOT NULL,
+                UNIQUE(room_id,token,direction)
+            )",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    /// Create the table that stores the latest name and topic event of every
+    /// room.
+    fn create_latest_state_table(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS latest_state_events (
+                id INTEGER NOT NULL PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                type TEXT NOT NULL,
+                event_id TEXT NOT NULL,
+                server_ts DATETIME NOT NULL,
+                UNIQUE(room_id,type)
+            )",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    pub(crate) fn create_tables(conn: &rusqlite::Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS profile (
+                id INTEGER NOT NULL PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                displayname TEXT NOT NULL,
+                avatar_url TEXT NOT NULL,
+                UNIQUE(user_id,displayname,avatar_url)
+            )",
+            [],
+        )?;
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS rooms (
@@ -305,6 +1216,8 @@ impl Database {
                 msgtype TEXT,
                 source TEXT NOT NULL,
                 profile_id INTEGER NOT NULL,
+                thread_id TEXT,
+                contains_url BOOLEAN NOT NULL DEFAULT 0,
                 FOREIGN KEY (profile_id) REFERENCES profile (id),
                 FOREIGN KEY (room_id) REFERENCES rooms (id),
                 UNIQUE(event_id, room_id)
@@ -333,13 +1246,114 @@ impl Database {
         )?;
 
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS crawlercheckpoints (
+            "CREATE TABLE IF NOT EXISTS redacted_events (
+                id INTEGER NOT NULL PRIMARY KEY,
+                event_id TEXT NOT NULL,
+                room_id INTEGER NOT NULL,
+                server_ts DATETIME NOT NULL,
+                UNIQUE(event_id)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS redacted_events_by_room
+             ON redacted_events(room_id, server_ts)",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS pending_deletions (
+                id INTEGER NOT NULL PRIMARY KEY,
+                event_id TEXT NOT NULL,
+                UNIQUE(event_id)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS store_sources (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+                store_sources BOOL NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS index_location (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+                path TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS source_encryption (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+                salt BLOB NOT NULL,
+                pbkdf_count INTEGER NOT NULL,
+                sentinel TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS unindexed_rooms (
                 id INTEGER NOT NULL PRIMARY KEY,
                 room_id TEXT NOT NULL,
-                token TEXT NOT NULL,
-                full_crawl BOOLEAN NOT NULL,
-                direction TEXT NOT NULL,
-                UNIQUE(room_id,token,full_crawl,direction)
+                UNIQUE(room_id)
+            )",
+            [],
+        )?;
+
+        Database::create_checkpoints_table(conn)?;
+        Database::create_latest_state_table(conn)?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS reactions (
+                id INTEGER NOT NULL PRIMARY KEY,
+                event_id TEXT NOT NULL,
+                target_event_id TEXT NOT NULL,
+                room_id TEXT NOT NULL,
+                UNIQUE(event_id)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS reactions_by_target ON reactions (target_event_id)",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS room_names (
+                id INTEGER NOT NULL PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                type TEXT NOT NULL,
+                name TEXT NOT NULL,
+                server_ts DATETIME NOT NULL,
+                UNIQUE(room_id,type)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS index_state (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+                store_id TEXT NOT NULL,
+                opstamp INTEGER NOT NULL,
+                consistency TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS room_state (
+                id INTEGER NOT NULL PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                fully_crawled BOOLEAN NOT NULL DEFAULT 0,
+                purge_epoch INTEGER NOT NULL DEFAULT 0,
+                UNIQUE(room_id)
             )",
             [],
         )?;
@@ -354,46 +1368,538 @@ impl Database {
             [],
         )?;
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS event_id ON events (event_id)",
-            [],
-        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS event_id ON events (event_id)",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS sender_events_by_timestamp ON events (sender, server_ts DESC)",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS room_events ON events (room_id, type, msgtype)",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS user_version (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+                version INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "INSERT OR IGNORE INTO user_version ( version ) VALUES(?1)",
+            [0],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tombstones (
+                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+                count INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "INSERT OR IGNORE INTO tombstones ( id, count ) VALUES(1, 0)",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS search_history (
+                id INTEGER NOT NULL PRIMARY KEY,
+                term TEXT NOT NULL,
+                UNIQUE(term)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS watermarks (
+                id INTEGER NOT NULL PRIMARY KEY,
+                name TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                UNIQUE(name)
+            )",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    pub(crate) fn get_event_count(connection: &rusqlite::Connection) -> rusqlite::Result<i64> {
+        connection.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))
+    }
+
+    pub(crate) fn get_event_count_for_room(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+    ) -> rusqlite::Result<i64> {
+        let room_id = Database::get_room_id(connection, room_id)?;
+        connection.query_row(
+            "SELECT COUNT(*) FROM events WHERE room_id=?1",
+            [room_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Check that the database is opened with the same setting for storing
+    /// event sources that it was created with.
+    ///
+    /// The setting is recorded the first time this is called, databases that
+    /// already contain events were created storing their sources.
+    pub(crate) fn check_store_sources(
+        connection: &rusqlite::Connection,
+        store_sources: bool,
+    ) -> Result<()> {
+        let recorded: Option<bool> = connection
+            .query_row("SELECT store_sources FROM store_sources", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        let recorded = match recorded {
+            Some(r) => r,
+            None => {
+                let recorded = store_sources || Database::get_event_count(connection)? > 0;
+                connection.execute(
+                    "INSERT INTO store_sources (store_sources) VALUES (?1)",
+                    [recorded],
+                )?;
+                recorded
+            }
+        };
+
+        if recorded == store_sources {
+            Ok(())
+        } else {
+            Err(Error::StoreSourcesMismatch)
+        }
+    }
+
+    /// Get the directory the index of the database was last opened from.
+    pub(crate) fn load_index_path(connection: &rusqlite::Connection) -> Result<Option<PathBuf>> {
+        let path: Option<String> = connection
+            .query_row("SELECT path FROM index_location", [], |row| row.get(0))
+            .optional()?;
+
+        Ok(path.map(PathBuf::from))
+    }
+
+    /// Check that the index of the database exists and record its location.
+    ///
+    /// Opening a store that contains events without its index would create an
+    /// empty index and the events would silently be missing from searches,
+    /// this returns an `IndexNotFound` error instead. Stores that didn't
+    /// record the location of their index yet are never rejected.
+    pub(crate) fn check_index_path(
+        connection: &rusqlite::Connection,
+        index_path: &Path,
+    ) -> Result<()> {
+        if let Some(recorded) = Database::load_index_path(connection)? {
+            if !index_path.join(INDEX_META).exists() && Database::get_event_count(connection)? > 0 {
+                return Err(Error::IndexNotFound(recorded));
+            }
+        }
+
+        let index_path = fs::canonicalize(index_path).unwrap_or_else(|_| index_path.to_owned());
+
+        connection.execute(
+            "INSERT OR REPLACE INTO index_location (id, path) VALUES (1, ?1)",
+            [index_path.to_string_lossy()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Get the cipher for the event sources, `None` if the sources of the
+    /// database aren't encrypted.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn open_source_cipher(
+        connection: &rusqlite::Connection,
+        config: &Config,
+    ) -> Result<Option<Arc<SourceCipher>>> {
+        #[cfg(feature = "encryption")]
+        let cipher = SourceCipher::open(
+            connection,
+            config
+                .source_encryption_passphrase
+                .as_deref()
+                .map(|p| p.as_str()),
+        )?;
+        #[cfg(not(feature = "encryption"))]
+        let cipher = SourceCipher::open(connection)?;
+
+        Ok(cipher.map(Arc::new))
+    }
+
+    /// Record a search term in the search history.
+    ///
+    /// Searching for a term again moves it to the top of the history, only
+    /// the `MAX_SEARCH_HISTORY` most recent terms are kept.
+    pub(crate) fn add_search_term(
+        connection: &rusqlite::Connection,
+        term: &str,
+    ) -> rusqlite::Result<()> {
+        let term = term.trim();
+
+        if term.is_empty() {
+            return Ok(());
+        }
+
+        connection.execute("DELETE FROM search_history WHERE term = ?1", [term])?;
+        connection.execute("INSERT INTO search_history (term) VALUES (?1)", [term])?;
+        connection.execute(
+            "DELETE FROM search_history WHERE id NOT IN (
+                SELECT id FROM search_history ORDER BY id DESC LIMIT ?1
+            )",
+            [MAX_SEARCH_HISTORY],
+        )?;
+
+        Ok(())
+    }
+
+    pub(crate) fn load_recent_searches(
+        connection: &rusqlite::Connection,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<String>> {
+        let mut stmt =
+            connection.prepare("SELECT term FROM search_history ORDER BY id DESC LIMIT ?1")?;
+        let terms = stmt.query_map([limit as i64], |row| row.get(0))?;
+
+        terms.collect()
+    }
+
+    pub(crate) fn clear_search_history(connection: &rusqlite::Connection) -> rusqlite::Result<()> {
+        connection.execute("DELETE FROM search_history", [])?;
+        Ok(())
+    }
+
+    pub(crate) fn store_watermark(
+        connection: &rusqlite::Connection,
+        name: &str,
+        timestamp: i64,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "INSERT INTO watermarks (name, timestamp) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET timestamp = excluded.timestamp",
+            params![name, timestamp],
+        )?;
+
+        Ok(())
+    }
+
+    pub(crate) fn load_watermark(
+        connection: &rusqlite::Connection,
+        name: &str,
+    ) -> rusqlite::Result<Option<i64>> {
+        connection
+            .query_row(
+                "SELECT timestamp FROM watermarks WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub(crate) fn load_unindexed_rooms(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<Vec<RoomId>> {
+        let mut stmt =
+            connection.prepare("SELECT room_id FROM unindexed_rooms ORDER BY room_id")?;
+        let rooms = stmt.query_map([], |row| row.get(0))?;
+
+        rooms.collect()
+    }
+
+    /// Load the ids of the rooms that have events but no crawler checkpoint.
+    ///
+    /// Rooms that have their indexing disabled are left out.
+    pub(crate) fn load_rooms_without_checkpoints(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<Vec<RoomId>> {
+        let mut stmt = connection.prepare(
+            "SELECT rooms.room_id FROM rooms
+             LEFT JOIN crawlercheckpoints ON crawlercheckpoints.room_id = rooms.room_id
+             LEFT JOIN unindexed_rooms ON unindexed_rooms.room_id = rooms.room_id
+             WHERE crawlercheckpoints.id IS NULL AND unindexed_rooms.room_id IS NULL
+             AND EXISTS (SELECT 1 FROM events WHERE events.room_id = rooms.id)
+             ORDER BY rooms.room_id",
+        )?;
+        let rooms = stmt.query_map([], |row| row.get(0))?;
+
+        rooms.collect()
+    }
+
+    /// Get the ids of the given events that are stored in the database.
+    ///
+    /// Every id is returned only once, in no particular order.
+    pub(crate) fn load_known_event_ids(
+        connection: &rusqlite::Connection,
+        event_ids: &[EventId],
+    ) -> rusqlite::Result<Vec<EventId>> {
+        let mut known = HashSet::new();
+
+        for chunk in event_ids.chunks(LOOKUP_BATCH_SIZE) {
+            let parameter_str = ", ?".repeat(chunk.len() - 1);
+
+            let mut stmt = connection.prepare(&format!(
+                "SELECT event_id FROM events WHERE event_id IN (?{})",
+                &parameter_str
+            ))?;
+
+            let ids = stmt.query_map(params_from_iter(chunk), |row| row.get(0))?;
+
+            for id in ids {
+                known.insert(id?);
+            }
+        }
+
+        Ok(known.into_iter().collect())
+    }
+
+    /// Check if the event is stored and its addition to the index was
+    /// committed.
+    pub(crate) fn is_event_committed(
+        connection: &rusqlite::Connection,
+        event_id: &str,
+    ) -> rusqlite::Result<bool> {
+        connection.query_row(
+            "SELECT EXISTS (
+                SELECT 1 FROM events
+                LEFT JOIN uncommitted_events ON uncommitted_events.event_id = events.id
+                WHERE events.event_id = ?1 AND uncommitted_events.id IS NULL
+            )",
+            [event_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Get the timestamp of the newest event of a room.
+    ///
+    /// This uses the index over the room and the timestamp of the events, it
+    /// doesn't scan the events of the room.
+    pub(crate) fn load_newest_event_ts(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+    ) -> rusqlite::Result<Option<i64>> {
+        connection.query_row(
+            "SELECT MAX(server_ts) FROM events
+             WHERE room_id = (SELECT id FROM rooms WHERE room_id = ?1)",
+            [room_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Get the timestamps of the newest events of the given rooms.
+    ///
+    /// Rooms without events map to `None`.
+    pub(crate) fn load_newest_event_timestamps(
+        connection: &rusqlite::Connection,
+        room_ids: &[RoomId],
+    ) -> rusqlite::Result<HashMap<RoomId, Option<i64>>> {
+        let mut timestamps: HashMap<RoomId, Option<i64>> =
+            room_ids.iter().map(|r| (r.clone(), None)).collect();
+
+        for chunk in room_ids.chunks(LOOKUP_BATCH_SIZE) {
+            let parameter_str = ", ?".repeat(chunk.len() - 1);
+
+            let mut stmt = connection.prepare(&format!(
+                "SELECT rooms.room_id,
+                 (SELECT MAX(server_ts) FROM events WHERE events.room_id = rooms.id)
+                 FROM rooms WHERE rooms.room_id IN (?{})",
+                &parameter_str
+            ))?;
+
+            let rows = stmt.query_map(params_from_iter(chunk), |row| {
+                Ok((row.get::<_, RoomId>(0)?, row.get::<_, Option<i64>>(1)?))
+            })?;
+
+            for row in rows {
+                let (room_id, timestamp) = row?;
+                timestamps.insert(room_id, timestamp);
+            }
+        }
+
+        Ok(timestamps)
+    }
+
+    /// Load the profile the sender had in their most recent stored event,
+    /// optionally only looking at the events of the given room.
+    pub(crate) fn load_latest_profile(
+        connection: &rusqlite::Connection,
+        sender: &str,
+        room_id: Option<&str>,
+    ) -> rusqlite::Result<Option<Profile>> {
+        let row_to_profile = |row: &rusqlite::Row| {
+            Ok(Profile {
+                displayname: row.get(0)?,
+                avatar_url: row.get(1)?,
+            })
+        };
+
+        match room_id {
+            Some(room_id) => connection
+                .prepare_cached(
+                    "SELECT displayname, avatar_url FROM events
+                     INNER JOIN profile ON profile.id = events.profile_id
+                     WHERE events.sender = ?1
+                     AND events.room_id = (SELECT id FROM rooms WHERE room_id = ?2)
+                     ORDER BY events.server_ts DESC, events.id DESC LIMIT 1",
+                )?
+                .query_row([sender, room_id], row_to_profile)
+                .optional(),
+            None => connection
+                .prepare_cached(
+                    "SELECT displayname, avatar_url FROM events
+                     INNER JOIN profile ON profile.id = events.profile_id
+                     WHERE events.sender = ?1
+                     ORDER BY events.server_ts DESC, events.id DESC LIMIT 1",
+                )?
+                .query_row([sender], row_to_profile)
+                .optional(),
+        }
+    }
+
+    pub(crate) fn load_rooms(connection: &rusqlite::Connection) -> rusqlite::Result<Vec<RoomId>> {
+        let mut stmt = connection.prepare(
+            "SELECT rooms.room_id FROM rooms
+             WHERE EXISTS (SELECT 1 FROM events WHERE events.room_id = rooms.id)
+             ORDER BY rooms.room_id",
+        )?;
+        let rooms = stmt.query_map([], |row| row.get(0))?;
+
+        rooms.collect()
+    }
+
+    pub(crate) fn load_checkpoint_only_rooms(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<Vec<RoomId>> {
+        let mut stmt = connection.prepare(
+            "SELECT DISTINCT crawlercheckpoints.room_id FROM crawlercheckpoints
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM events
+                 INNER JOIN rooms ON rooms.id = events.room_id
+                 WHERE rooms.room_id = crawlercheckpoints.room_id
+             )
+             ORDER BY crawlercheckpoints.room_id",
+        )?;
+        let rooms = stmt.query_map([], |row| row.get(0))?;
+
+        rooms.collect()
+    }
+
+    pub(crate) fn set_room_indexing_helper(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+        enabled: bool,
+    ) -> rusqlite::Result<()> {
+        if enabled {
+            connection.execute("DELETE FROM unindexed_rooms WHERE room_id = ?1", [room_id])?;
+        } else {
+            connection.execute(
+                "INSERT OR IGNORE INTO unindexed_rooms (room_id) VALUES (?1)",
+                [room_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_room_fully_crawled_helper(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+        fully_crawled: bool,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "INSERT INTO room_state (room_id, fully_crawled) VALUES (?1, ?2)
+             ON CONFLICT(room_id) DO UPDATE SET fully_crawled = excluded.fully_crawled",
+            [&room_id as &dyn ToSql, &fully_crawled],
+        )?;
+
+        Ok(())
+    }
 
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS room_events ON events (room_id, type, msgtype)",
-            [],
-        )?;
+    pub(crate) fn is_room_fully_crawled(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+    ) -> rusqlite::Result<bool> {
+        connection.query_row(
+            "SELECT EXISTS (
+                 SELECT 1 FROM room_state WHERE room_id = ?1 AND fully_crawled
+             )",
+            [room_id],
+            |row| row.get(0),
+        )
+    }
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS user_version (
-                id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
-                version INTEGER NOT NULL
-            )",
-            [],
-        )?;
+    pub(crate) fn load_fully_crawled_rooms(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<Vec<RoomId>> {
+        let mut stmt = connection
+            .prepare("SELECT room_id FROM room_state WHERE fully_crawled ORDER BY room_id")?;
+        let rooms = stmt.query_map([], |row| row.get(0))?;
 
-        conn.execute(
-            "INSERT OR IGNORE INTO user_version ( version ) VALUES(?1)",
-            [0],
+        rooms.collect()
+    }
+
+    /// Record that a room was purged, events of the room that were queued
+    /// before the purge epoch need to be dropped.
+    ///
+    /// A purged room isn't fully crawled anymore.
+    pub(crate) fn set_room_purge_epoch(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+        epoch: u64,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "INSERT INTO room_state (room_id, purge_epoch) VALUES (?1, ?2)
+             ON CONFLICT(room_id) DO UPDATE
+             SET purge_epoch = MAX(purge_epoch, excluded.purge_epoch), fully_crawled = 0",
+            [&room_id as &dyn ToSql, &(epoch as i64)],
         )?;
 
         Ok(())
     }
 
-    pub(crate) fn get_event_count(connection: &rusqlite::Connection) -> rusqlite::Result<i64> {
-        connection.query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))
+    /// Load the purge epochs of the rooms that were purged.
+    pub(crate) fn load_purge_epochs(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<HashMap<RoomId, u64>> {
+        let mut stmt = connection
+            .prepare("SELECT room_id, purge_epoch FROM room_state WHERE purge_epoch > 0")?;
+        let epochs = stmt.query_map([], |row| {
+            Ok((row.get::<_, RoomId>(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+
+        epochs.collect()
     }
 
-    pub(crate) fn get_event_count_for_room(
+    /// Load the latest purge epoch of the database, 0 if no room was ever
+    /// purged.
+    pub(crate) fn load_purge_epoch(connection: &rusqlite::Connection) -> rusqlite::Result<u64> {
+        let epoch: i64 = connection.query_row(
+            "SELECT COALESCE(MAX(purge_epoch), 0) FROM room_state",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(epoch as u64)
+    }
+
+    pub(crate) fn delete_room_checkpoints(
         connection: &rusqlite::Connection,
         room_id: &str,
-    ) -> rusqlite::Result<i64> {
-        let room_id = Database::get_room_id(connection, room_id)?;
-        connection.query_row(
-            "SELECT COUNT(*) FROM events WHERE room_id=?1",
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "DELETE FROM crawlercheckpoints WHERE room_id = ?1",
             [room_id],
-            |row| row.get(0),
-        )
+        )?;
+        Ok(())
     }
 
     pub(crate) fn get_room_count(connection: &rusqlite::Connection) -> rusqlite::Result<i64> {
@@ -402,6 +1908,20 @@ impl Database {
         connection.query_row("SELECT COUNT(*) FROM rooms", [], |row| row.get(0))
     }
 
+    /// Delete the profiles that aren't referenced by any event anymore.
+    ///
+    /// Returns the number of deleted profiles.
+    pub(crate) fn delete_unused_profiles(
+        connection: &rusqlite::Connection,
+    ) -> rusqlite::Result<usize> {
+        connection.execute(
+            "DELETE FROM profile WHERE NOT EXISTS (
+                SELECT 1 FROM events WHERE events.profile_id = profile.id
+            )",
+            [],
+        )
+    }
+
     pub(crate) fn save_profile(
         connection: &rusqlite::Connection,
         user_id: &str,
@@ -415,23 +1935,24 @@ impl Database {
 
         let avatar_url = if let Some(a) = avatar_url { a } else { "" };
 
-        connection.execute(
-            "
+        connection
+            .prepare_cached(
+                "
             INSERT OR IGNORE INTO profile (
                 user_id, displayname, avatar_url
             ) VALUES(?1, ?2, ?3)",
-            [user_id, displayname, avatar_url],
-        )?;
+            )?
+            .execute([user_id, displayname, avatar_url])?;
 
-        let profile_id: i64 = connection.query_row(
-            "
+        let profile_id: i64 = connection
+            .prepare_cached(
+                "
             SELECT id FROM profile WHERE (
                 user_id=?1
                 and displayname=?2
                 and avatar_url=?3)",
-            [user_id, displayname, avatar_url],
-            |row| row.get(0),
-        )?;
+            )?
+            .query_row([user_id, displayname, avatar_url], |row| row.get(0))?;
 
         Ok(profile_id)
     }
@@ -459,12 +1980,13 @@ impl Database {
         connection: &rusqlite::Connection,
         room: &str,
     ) -> rusqlite::Result<i64> {
-        connection.execute("INSERT OR IGNORE INTO rooms (room_id) VALUES(?1)", [room])?;
+        connection
+            .prepare_cached("INSERT OR IGNORE INTO rooms (room_id) VALUES(?1)")?
+            .execute([room])?;
 
-        let room_id: i64 =
-            connection.query_row("SELECT id FROM rooms WHERE (room_id=?1)", [room], |row| {
-                row.get(0)
-            })?;
+        let room_id: i64 = connection
+            .prepare_cached("SELECT id FROM rooms WHERE (room_id=?1)")?
+            .query_row([room], |row| row.get(0))?;
 
         Ok(room_id)
     }
@@ -483,7 +2005,7 @@ impl Database {
     ) -> rusqlite::Result<Vec<(i64, Event)>> {
         let mut stmt = connection.prepare(
                 "SELECT uncommitted_events.id, uncommitted_events.event_id, content_value, type, msgtype,
-                 events.event_id, sender, server_ts, rooms.room_id, source
+                 events.event_id, sender, server_ts, rooms.room_id, source, thread_id
                  FROM uncommitted_events
                  INNER JOIN events on events.id = uncommitted_events.event_id
                  INNER JOIN rooms on rooms.id = events.room_id
@@ -501,6 +2023,7 @@ impl Database {
                     server_ts: row.get(7)?,
                     room_id: row.get(8)?,
                     source: row.get(9)?,
+                    thread_id: row.get(10)?,
                 },
             ))
         })?;
@@ -515,14 +2038,19 @@ impl Database {
     ) -> rusqlite::Result<i64> {
         let room_id = Database::get_room_id(connection, &event.room_id)?;
 
-        let mut statement = connection.prepare(
+        // Events are saved one by one while importing large chunks, the
+        // statements are cached on the connection so they are only prepared
+        // once.
+        let mut statement = connection.prepare_cached(
             "
             INSERT INTO events (
                 event_id, sender, server_ts, room_id, type,
-                msgtype, source, profile_id
-            ) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                msgtype, source, profile_id, thread_id, contains_url
+            ) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
         )?;
 
+        let contains_url = event.contains_url();
+
         let event_id = statement.insert([
             &event.event_id,
             &event.sender,
@@ -532,9 +2060,11 @@ impl Database {
             &event.msgtype,
             &event.source,
             &profile_id as &dyn ToSql,
+            &event.thread_id,
+            &contains_url as &dyn ToSql,
         ])?;
 
-        let mut stmt = connection.prepare(
+        let mut stmt = connection.prepare_cached(
             "
             INSERT OR IGNORE INTO uncommitted_events (
                 event_id, content_value
@@ -546,6 +2076,20 @@ impl Database {
         Ok(id)
     }
 
+    /// Mark a stored event as containing a link, see `Event::contains_url()`.
+    pub(crate) fn mark_url_event(
+        connection: &rusqlite::Connection,
+        event: &Event,
+    ) -> rusqlite::Result<()> {
+        connection.execute(
+            "UPDATE events SET contains_url = 1
+             WHERE event_id == ?1 AND room_id == (SELECT id FROM rooms WHERE room_id == ?2)",
+            params![&event.event_id, &event.room_id],
+        )?;
+
+        Ok(())
+    }
+
     pub(crate) fn save_event(
         connection: &rusqlite::Connection,
         event: &mut Event,
@@ -605,14 +2149,14 @@ impl Database {
         event: &Event,
     ) -> rusqlite::Result<bool> {
         let room_id = Database::get_room_id(connection, &event.room_id)?;
-        let count: i64 = connection.query_row(
-            "
+        let count: i64 = connection
+            .prepare_cached(
+                "
             SELECT COUNT(*) FROM events WHERE (
                 event_id=?1
                 and room_id=?2)",
-            [&event.event_id, &room_id as &dyn ToSql],
-            |row| row.get(0),
-        )?;
+            )?
+            .query_row([&event.event_id, &room_id as &dyn ToSql], |row| row.get(0))?;
 
         match count {
             0 => Ok(false),
@@ -634,9 +2178,14 @@ impl Database {
                     "SELECT source FROM events
                      WHERE (
                          (type == 'm.room.message') &
-                         (event_id != ?1) &
-                         (server_ts <= ?2)
-                     ) ORDER BY server_ts DESC LIMIT ?3
+                         (
+                             (server_ts < ?2) |
+                             (
+                                 (server_ts == ?2) &
+                                 (id < (SELECT id FROM events WHERE event_id == ?1))
+                             )
+                         )
+                     ) ORDER BY server_ts DESC, id DESC LIMIT ?3
                      ",
                 )?;
 
@@ -650,7 +2199,7 @@ impl Database {
                 let mut stmt = connection.prepare(
                     "SELECT source FROM events
                      WHERE type == 'm.room.message'
-                     ORDER BY server_ts DESC LIMIT ?1
+                     ORDER BY server_ts DESC, id DESC LIMIT ?1
                      ",
                 )?;
 
@@ -666,14 +2215,53 @@ impl Database {
         limit: usize,
         from_event: Option<&str>,
         direction: &LoadDirection,
+    ) -> rusqlite::Result<Vec<(SerializedEvent, Profile)>> {
+        Database::load_matching_events(
+            connection,
+            &format!("msgtype in ({})", FILE_EVENT_TYPES),
+            room_id,
+            limit,
+            from_event,
+            direction,
+        )
+    }
+
+    /// Load the message events of a room that contain a link, see
+    /// `Event::contains_url()`.
+    pub(crate) fn load_url_events(
+        connection: &rusqlite::Connection,
+        room_id: &str,
+        limit: usize,
+        from_event: Option<&str>,
+        direction: &LoadDirection,
+    ) -> rusqlite::Result<Vec<(SerializedEvent, Profile)>> {
+        Database::load_matching_events(
+            connection,
+            "contains_url == 1",
+            room_id,
+            limit,
+            from_event,
+            direction,
+        )
+    }
+
+    /// Load the message events of a room that match the given SQL condition
+    /// on the events table.
+    fn load_matching_events(
+        connection: &rusqlite::Connection,
+        condition: &str,
+        room_id: &str,
+        limit: usize,
+        from_event: Option<&str>,
+        direction: &LoadDirection,
     ) -> rusqlite::Result<Vec<(SerializedEvent, Profile)>> {
         match from_event {
             Some(e) => {
                 let event = Database::load_event(connection, room_id, e)?;
 
                 let (direction, sort) = match direction {
-                    LoadDirection::Backwards => ("<=", "DESC"),
-                    LoadDirection::Forwards => (">=", "ASC"),
+                    LoadDirection::Backwards => ("<", "DESC"),
+                    LoadDirection::Forwards => (">", "ASC"),
                 };
 
                 let mut stmt = connection.prepare(&format!(
@@ -683,17 +2271,24 @@ impl Database {
                      WHERE (
                          (events.room_id == ?1) &
                          (type == 'm.room.message') &
-                         (msgtype in ({})) &
-                         (event_id != ?2) &
-                         (server_ts {} ?3)
-                     ) ORDER BY server_ts {} LIMIT ?4
+                         ({cond}) &
+                         (
+                             (server_ts {dir} ?2) |
+                             (
+                                 (server_ts == ?2) &
+                                 (events.id {dir} (SELECT id FROM events WHERE event_id == ?3))
+                             )
+                         )
+                     ) ORDER BY server_ts {sort}, events.id {sort} LIMIT ?4
                      ",
-                    FILE_EVENT_TYPES, direction, sort
+                    cond = condition,
+                    dir = direction,
+                    sort = sort
                 ))?;
 
                 let room_id = Database::get_room_id(connection, room_id)?;
                 let events = stmt.query_map(
-                    params![&room_id, &event.event_id, &event.server_ts, &limit,],
+                    params![&room_id, &event.server_ts, &event.event_id, &limit,],
                     |row| {
                         Ok((
                             row.get(0)?,
@@ -714,10 +2309,10 @@ impl Database {
                      WHERE (
                          (events.room_id == ?1) &
                          (type == 'm.room.message') &
-                         (msgtype in ({}))
-                     ) ORDER BY server_ts DESC LIMIT ?2
+                         ({})
+                     ) ORDER BY server_ts DESC, events.id DESC LIMIT ?2
                      ",
-                    FILE_EVENT_TYPES
+                    condition
                 ))?;
 
                 let room_id = Database::get_room_id(connection, room_id)?;
@@ -735,104 +2330,210 @@ impl Database {
         }
     }
 
-    /// Load events surounding the given event.
-    pub(crate) fn load_event_context(
+    /// Load the events surrounding the given events.
+    ///
+    /// The context of all the events is loaded using a single query per
+    /// direction for every `CONTEXT_BATCH_SIZE` events.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - A list of tuples containing the insert id, the database
+    ///   id of the room and the timestamp of the events.
+    ///
+    /// * `limits` - The number of events to load before and after every
+    ///   event, in the same order as the events.
+    ///
+    /// * `redacted_stubs` - Should messages that were redacted be part of the
+    ///   context as stubs, see `SearchConfig::include_redacted_stubs()`.
+    ///
+    /// Returns the context for every given event, in the same order as the
+    /// events.
+    pub(crate) fn load_event_contexts(
         connection: &rusqlite::Connection,
-        event: &Event,
-        before_limit: usize,
-        after_limit: usize,
-    ) -> rusqlite::Result<EventContext> {
-        let mut profiles: HashMap<String, Profile> = HashMap::new();
-        let room_id = Database::get_room_id(connection, &event.room_id)?;
+        events: &[(i64, i64, i64)],
+        limits: &[(usize, usize)],
+        redacted_stubs: bool,
+    ) -> rusqlite::Result<Vec<EventContext>> {
+        let (before_limits, after_limits): (Vec<usize>, Vec<usize>) =
+            limits.iter().copied().unzip();
+
+        let before = Database::load_context_window(
+            connection,
+            events,
+            &before_limits,
+            true,
+            redacted_stubs,
+        )?;
+        let after = Database::load_context_window(
+            connection,
+            events,
+            &after_limits,
+            false,
+            redacted_stubs,
+        )?;
 
-        let before = if before_limit == 0 {
-            vec![]
-        } else {
-            let mut stmt = connection.prepare(
-                "
-                WITH room_events AS (
-                    SELECT *
-                    FROM events
-                    WHERE room_id == ?2
+        Ok(before
+            .into_iter()
+            .zip(after)
+            .map(|(before, after)| {
+                let mut profiles: HashMap<String, Profile> = HashMap::new();
+                // The context events are ordered by their distance to the
+                // event, so a sender keeps the historic profile that is the
+                // closest to the event.
+                let mut events = |context: Vec<(ContextEvent, Option<(String, Profile)>)>| {
+                    context
+                        .into_iter()
+                        .map(|(event, sender)| {
+                            if let Some((sender, profile)) = sender {
+                                profiles.entry(sender).or_insert(profile);
+                            }
+                            event
+                        })
+                        .collect::<Vec<_>>()
+                };
+
+                let before = events(before);
+                let after = events(after);
+
+                (before, after, profiles)
+            })
+            .collect())
+    }
+
+    /// Load up to the given number of messages that happened in the same room
+    /// before, or after, every one of the given events. The limits are in the
+    /// same order as the events.
+    ///
+    /// Events are ordered by their timestamp and their insert id, so events
+    /// that share a timestamp end up in the order they were stored in and
+    /// never appear both before and after an event.
+    ///
+    /// Redacted messages are only part of the context if `redacted_stubs` is
+    /// set, as stubs that only contain their event id.
+    ///
+    /// Returns the context events, their senders and the profiles of their
+    /// senders for every given event, stubs have no sender. The context events
+    /// are ordered by their distance to the given event, the closest one
+    /// first, so events before the given event are in descending and events
+    /// after it in ascending order of their timestamps.
+    fn load_context_window(
+        connection: &rusqlite::Connection,
+        events: &[(i64, i64, i64)],
+        limits: &[usize],
+        before: bool,
+        redacted_stubs: bool,
+    ) -> rusqlite::Result<Vec<Vec<(ContextEvent, Option<(String, Profile)>)>>> {
+        let mut ret = vec![Vec::new(); events.len()];
+
+        let (comparison, order) = if before { ("<", "DESC") } else { (">", "ASC") };
+
+        // Redacted messages that were stored again aren't stubs.
+        let messages = if redacted_stubs {
+            "(
+                SELECT id, event_id, source, sender, profile_id, server_ts, room_id, type
+                FROM events
+                UNION ALL
+                SELECT id, event_id, NULL, NULL, NULL, server_ts, room_id, 'm.room.message'
+                FROM redacted_events
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM events WHERE events.event_id == redacted_events.event_id
                 )
-                SELECT source, sender, displayname, avatar_url
-                FROM room_events
-                INNER JOIN profile on profile.id = room_events.profile_id
-                WHERE (
-                    (event_id != ?1) &
-                    (server_ts <= ?3)
-                ) ORDER BY server_ts DESC LIMIT ?4
-                ",
-            )?;
-            let context = stmt.query_map(
-                params![&event.event_id, &room_id, &event.server_ts, &after_limit,],
-                |row| {
-                    Ok((
-                        row.get(0),
-                        row.get(1),
-                        Profile {
-                            displayname: row.get(2)?,
-                            avatar_url: row.get(3)?,
-                        },
-                    ))
-                },
-            )?;
-            let mut ret: Vec<String> = Vec::new();
+            )"
+        } else {
+            "events"
+        };
 
-            for row in context {
-                let (source, sender, profile) = row?;
-                profiles.insert(sender?, profile);
-                ret.push(source?)
+        for (batch_num, batch) in events.chunks(CONTEXT_BATCH_SIZE).enumerate() {
+            let offset = batch_num * CONTEXT_BATCH_SIZE;
+            let batch_limits: Vec<i64> = limits[offset..offset + batch.len()]
+                .iter()
+                .map(|l| *l as i64)
+                .collect();
+
+            if batch_limits.iter().all(|l| *l == 0) {
+                continue;
             }
 
-            ret
-        };
+            // Every event gets its own subquery so the LIMIT applies to each
+            // event separately, the subqueries are then combined so the whole
+            // batch is fetched at once.
+            let subquery = format!(
+                "SELECT * FROM (
+                    SELECT ? AS hit, id, event_id, source, sender, profile_id, server_ts
+                    FROM {messages}
+                    WHERE room_id = ? AND type == 'm.room.message'
+                    AND (server_ts {cmp} ? OR (server_ts == ? AND id {cmp} ?))
+                    ORDER BY server_ts {order}, id {order} LIMIT ?
+                )",
+                messages = messages,
+                cmp = comparison,
+                order = order
+            );
+
+            let mut stmt = connection.prepare(&format!(
+                "SELECT hit, event_id, server_ts, source, sender, displayname, avatar_url,
+                 context.id
+                 FROM ({}) AS context
+                 LEFT JOIN profile on profile.id = context.profile_id
+                 ORDER BY hit, context.server_ts {order}, context.id {order}",
+                vec![subquery; batch.len()].join(" UNION ALL "),
+                order = order
+            ))?;
 
-        let after = if after_limit == 0 {
-            vec![]
-        } else {
-            let mut stmt = connection.prepare(
-                "
-                WITH room_events AS (
-                    SELECT *
-                    FROM events
-                    WHERE room_id == ?2
-                )
-                SELECT source, sender, displayname, avatar_url
-                FROM room_events
-                INNER JOIN profile on profile.id = room_events.profile_id
-                WHERE (
-                    (event_id != ?1) &
-                    (server_ts >= ?3)
-                ) ORDER BY server_ts ASC LIMIT ?4
-                ",
-            )?;
-            let context = stmt.query_map(
-                params![&event.event_id, &room_id, &event.server_ts, &after_limit,],
-                |row| {
-                    Ok((
-                        row.get(0),
-                        row.get(1),
-                        Profile {
-                            displayname: row.get(2)?,
-                            avatar_url: row.get(3)?,
+            let hits: Vec<i64> = (0..batch.len() as i64).collect();
+            let mut parameters: Vec<&dyn ToSql> = Vec::with_capacity(batch.len() * 6);
+
+            for ((hit, (insert_id, room_id, server_ts)), limit) in
+                hits.iter().zip(batch).zip(&batch_limits)
+            {
+                parameters.extend_from_slice(&[
+                    hit as &dyn ToSql,
+                    room_id,
+                    server_ts,
+                    server_ts,
+                    insert_id,
+                    limit,
+                ]);
+            }
+
+            let context = stmt.query_map(params_from_iter(parameters), |row| {
+                let event_id: EventId = row.get(1)?;
+                let server_ts = row.get(2)?;
+                let insert_id = row.get(7)?;
+
+                let event = match row.get::<_, Option<SerializedEvent>>(3)? {
+                    Some(source) => (
+                        ContextEvent {
+                            event_id,
+                            server_ts,
+                            insert_id,
+                            source,
+                            redacted: false,
                         },
-                    ))
-                },
-            )?;
+                        Some((
+                            row.get(4)?,
+                            Profile {
+                                displayname: row.get(5)?,
+                                avatar_url: row.get(6)?,
+                            },
+                        )),
+                    ),
+                    None => (
+                        ContextEvent::redacted_stub(event_id, server_ts, insert_id),
+                        None,
+                    ),
+                };
 
-            let mut ret: Vec<String> = Vec::new();
+                Ok((row.get::<_, i64>(0)?, event))
+            })?;
 
             for row in context {
-                let (source, sender, profile) = row?;
-                profiles.insert(sender?, profile);
-                ret.push(source?)
+                let (hit, event) = row?;
+                ret[offset + hit as usize].push(event);
             }
+        }
 
-            ret
-        };
-
-        Ok((before, after, profiles))
+        Ok(ret)
     }
 
     pub(crate) fn load_event(
@@ -844,7 +2545,7 @@ impl Database {
 
         connection.query_row(
             "SELECT type, msgtype, event_id, sender,
-             server_ts, rooms.room_id, source
+             server_ts, rooms.room_id, source, thread_id
              FROM events
              INNER JOIN rooms on rooms.id = events.room_id
              WHERE (events.room_id == ?1) & (event_id == ?2)",
@@ -859,60 +2560,106 @@ impl Database {
                     server_ts: row.get(4)?,
                     room_id: row.get(5)?,
                     source: row.get(6)?,
+                    thread_id: row.get(7)?,
                 })
             },
         )
     }
 
-    pub(crate) fn load_events(
+    /// Load the source and the room id of an event using only its event id.
+    ///
+    /// Returns `None` if the event isn't part of the database.
+    pub(crate) fn load_event_source(
         connection: &rusqlite::Connection,
-        search_result: &[(f32, EventId)],
-        before_limit: usize,
-        after_limit: usize,
-        order_by_recency: bool,
-    ) -> rusqlite::Result<Vec<SearchResult>> {
-        if search_result.is_empty() {
-            return Ok(vec![]);
-        }
-
-        let event_num = search_result.len();
-        let parameter_str = ", ?".repeat(event_num - 1);
-
-        let mut stmt = if order_by_recency {
-            connection.prepare(&format!(
-                "SELECT type, msgtype, event_id, sender,
-                 server_ts, rooms.room_id, source, displayname, avatar_url
+        event_id: &str,
+    ) -> rusqlite::Result<Option<(SerializedEvent, String)>> {
+        connection
+            .query_row(
+                "SELECT source, rooms.room_id
                  FROM events
-                 INNER JOIN profile on profile.id = events.profile_id
                  INNER JOIN rooms on rooms.id = events.room_id
-                 WHERE event_id IN (?{})
-                 ORDER BY server_ts DESC
-                 ",
-                &parameter_str
-            ))?
-        } else {
-            connection.prepare(&format!(
-                "SELECT type, msgtype, event_id, sender,
-                 server_ts, rooms.room_id, source, displayname, avatar_url
+                 WHERE event_id = ?1",
+                [event_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    /// Load an event together with the events surrounding it in its room.
+    ///
+    /// Returns `None` if the event isn't part of the database.
+    pub(crate) fn load_event_with_context(
+        connection: &rusqlite::Connection,
+        event_id: &str,
+        before_limit: usize,
+        after_limit: usize,
+    ) -> rusqlite::Result<Option<EventWithContext>> {
+        let event = connection
+            .query_row(
+                "SELECT source, sender, server_ts, events.room_id, displayname, avatar_url,
+                 events.id
                  FROM events
                  INNER JOIN profile on profile.id = events.profile_id
-                 INNER JOIN rooms on rooms.id = events.room_id
-                 WHERE event_id IN (?{})
-                 ",
-                &parameter_str
-            ))?
+                 WHERE event_id = ?1",
+                [event_id],
+                |row| {
+                    Ok((
+                        row.get::<_, SerializedEvent>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, i64>(3)?,
+                        Profile {
+                            displayname: row.get(4)?,
+                            avatar_url: row.get(5)?,
+                        },
+                        row.get::<_, i64>(6)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let (source, sender, server_ts, room_id, profile, insert_id) = match event {
+            Some(e) => e,
+            None => return Ok(None),
         };
 
-        let (mut scores, event_ids): (HashMap<String, f32>, Vec<String>) = {
-            let mut s = HashMap::new();
-            let mut e = Vec::new();
+        let (events_before, events_after, mut profile_info) = Database::load_event_contexts(
+            connection,
+            &[(insert_id, room_id, server_ts)],
+            &[(before_limit, after_limit)],
+            false,
+        )?
+        .remove(0);
+        profile_info.insert(sender, profile);
+
+        Ok(Some(EventWithContext {
+            event_source: source,
+            events_before,
+            events_after,
+            profile_info,
+        }))
+    }
 
-            for (score, id) in search_result {
-                e.push(id.clone());
-                s.insert(id.clone(), *score);
-            }
-            (s, e)
-        };
+    /// Load the rows of the events with the given ids, the event, the
+    /// profile of the sender, the database id of the room and the insert id
+    /// of the event.
+    fn load_event_rows(
+        connection: &rusqlite::Connection,
+        event_ids: Vec<EventId>,
+    ) -> rusqlite::Result<Vec<CachedEvent>> {
+        let parameter_str = ", ?".repeat(event_ids.len() - 1);
+
+        let mut stmt = connection.prepare(&format!(
+            "SELECT type, msgtype, event_id, sender,
+             server_ts, rooms.room_id, source, displayname, avatar_url,
+             events.room_id, thread_id, events.id
+             FROM events
+             INNER JOIN profile on profile.id = events.profile_id
+             INNER JOIN rooms on rooms.id = events.room_id
+             WHERE event_id IN (?{})
+             ",
+            &parameter_str
+        ))?;
 
         let db_events = stmt.query_map(params_from_iter(event_ids), |row| {
             Ok((
@@ -925,29 +2672,150 @@ impl Database {
                     server_ts: row.get(4)?,
                     room_id: row.get(5)?,
                     source: row.get(6)?,
+                    thread_id: row.get(10)?,
                 },
                 Profile {
                     displayname: row.get(7)?,
                     avatar_url: row.get(8)?,
                 },
+                row.get(9)?,
+                row.get(11)?,
             ))
         })?;
 
-        let mut events = Vec::new();
-        for row in db_events {
-            let (event, profile): (Event, Profile) = row?;
-            let (before, after, profiles) =
-                Database::load_event_context(connection, &event, before_limit, after_limit)?;
+        db_events.collect()
+    }
+
+    pub(crate) fn load_events(
+        connection: &rusqlite::Connection,
+        search_result: &[(f32, EventId)],
+        matches: &HashMap<EventId, Matches>,
+        before_limit: usize,
+        after_limit: usize,
+        order_by_recency: bool,
+    ) -> rusqlite::Result<Vec<SearchResult>> {
+        Database::load_events_cached(
+            connection,
+            None,
+            search_result,
+            matches,
+            &ContextLimits::new(before_limit, after_limit),
+            order_by_recency,
+            false,
+        )
+    }
+
+    /// Load the events of a search result like `load_events()`, taking them
+    /// from the given source cache if possible.
+    ///
+    /// Events that need to be loaded from the database are added to the
+    /// cache. The context events are always loaded from the database, new
+    /// events change the context of the cached ones. Redacted messages are
+    /// part of the context as stubs if `redacted_stubs` is set.
+    ///
+    /// The number of context events of every result depends on its room, see
+    /// `SearchConfig::context_overrides()`.
+    pub(crate) fn load_events_cached(
+        connection: &rusqlite::Connection,
+        cache: Option<&SharedSourceCache>,
+        search_result: &[(f32, EventId)],
+        matches: &HashMap<EventId, Matches>,
+        limits: &ContextLimits,
+        order_by_recency: bool,
+        redacted_stubs: bool,
+    ) -> rusqlite::Result<Vec<SearchResult>> {
+        if search_result.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (mut scores, event_ids): (HashMap<String, f32>, Vec<String>) = {
+            let mut s = HashMap::new();
+            let mut e = Vec::new();
+
+            for (score, id) in search_result {
+                e.push(id.clone());
+                s.insert(id.clone(), *score);
+            }
+            (s, e)
+        };
+
+        let cache = cache.filter(|c| c.lock().unwrap().is_enabled());
+
+        let (mut db_events, missing, generation) = match cache {
+            Some(cache) => {
+                let mut cache = cache.lock().unwrap();
+                let (cached, missing) = cache.get_many(&event_ids);
+                (cached, missing, cache.generation())
+            }
+            None => (Vec::new(), event_ids, 0),
+        };
+
+        if !missing.is_empty() {
+            let loaded = Database::load_event_rows(connection, missing)?;
+
+            if let Some(cache) = cache {
+                cache.lock().unwrap().insert_many(generation, &loaded);
+            }
+
+            db_events.extend(loaded);
+        }
+
+        if order_by_recency {
+            db_events.sort_by(|a, b| {
+                b.0.server_ts
+                    .cmp(&a.0.server_ts)
+                    .then_with(|| b.3.cmp(&a.3))
+            });
+        }
 
-            let mut profiles = profiles;
+        let mut room_ids: Vec<RoomId> = db_events
+            .iter()
+            .map(|(e, _, _, _)| e.room_id.clone())
+            .collect();
+        room_ids.sort_unstable();
+        room_ids.dedup();
+        let room_names = Database::load_room_names(connection, &room_ids)?;
+
+        let context_events: Vec<(i64, i64, i64)> = db_events
+            .iter()
+            .map(|(event, _, room_id, insert_id)| (*insert_id, *room_id, event.server_ts))
+            .collect();
+        let context_limits: Vec<(usize, usize)> = db_events
+            .iter()
+            .map(|(event, _, _, _)| limits.for_room(&event.room_id))
+            .collect();
+        let contexts = Database::load_event_contexts(
+            connection,
+            &context_events,
+            &context_limits,
+            redacted_stubs,
+        )?;
+
+        let mut events = Vec::new();
+        for (
+            ((event, profile, _, _), (before, after, mut profiles)),
+            (before_limit, after_limit),
+        ) in db_events.into_iter().zip(contexts).zip(context_limits)
+        {
             profiles.insert(event.sender.clone(), profile);
+            let matched = matches.get(&event.event_id).cloned().unwrap_or_default();
+            let context_truncated = ContextTruncation {
+                before: before.len() < before_limit,
+                after: after.len() < after_limit,
+            };
 
             let result = SearchResult {
                 score: scores.remove(&event.event_id).unwrap(),
+                raw_score: None,
+                room_display_name: room_names.get(&event.room_id).cloned().flatten(),
                 event_source: event.source,
                 events_before: before,
                 events_after: after,
                 profile_info: profiles,
+                highlights: matched.highlights,
+                matched_fields: matched.fields,
+                source_available: true,
+                context_truncated,
             };
             events.push(result);
         }
@@ -961,28 +2829,35 @@ impl Database {
         Ok(events)
     }
 
+    /// Replace the old crawler checkpoint with a new one.
+    ///
+    /// The old checkpoint is removed first, so replacing a checkpoint with an
+    /// identical one keeps it. Storing a checkpoint that already exists
+    /// replaces it.
+    ///
+    /// Adding a backwards checkpoint means that the history of the room needs
+    /// to be crawled again, the room loses its fully crawled flag.
     pub(crate) fn replace_crawler_checkpoint(
         connection: &rusqlite::Connection,
         new: Option<&CrawlerCheckpoint>,
         old: Option<&CrawlerCheckpoint>,
     ) -> Result<()> {
-        if let Some(checkpoint) = new {
+        if let Some(checkpoint) = old {
             connection.execute(
-                "INSERT OR IGNORE INTO crawlercheckpoints
-                (room_id, token, full_crawl, direction) VALUES(?1, ?2, ?3, ?4)",
+                "DELETE FROM crawlercheckpoints
+                WHERE (room_id=?1 AND token=?2 AND direction=?3)",
                 [
                     &checkpoint.room_id,
                     &checkpoint.token,
-                    &checkpoint.full_crawl as &dyn ToSql,
-                    &checkpoint.direction,
+                    &checkpoint.direction as &dyn ToSql,
                 ],
             )?;
         }
 
-        if let Some(checkpoint) = old {
+        if let Some(checkpoint) = new {
             connection.execute(
-                "DELETE FROM crawlercheckpoints
-                WHERE (room_id=?1 AND token=?2 AND full_crawl=?3 AND direction=?4)",
+                "INSERT OR REPLACE INTO crawlercheckpoints
+                (room_id, token, full_crawl, direction) VALUES(?1, ?2, ?3, ?4)",
                 [
                     &checkpoint.room_id,
                     &checkpoint.token,
@@ -990,6 +2865,10 @@ impl Database {
                     &checkpoint.direction,
                 ],
             )?;
+
+            if checkpoint.direction == CheckpointDirection::Backwards {
+                Database::set_room_fully_crawled_helper(connection, &checkpoint.room_id, false)?;
+            }
         }
 
         Ok(())
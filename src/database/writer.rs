@@ -12,27 +12,109 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{SendError, Sender},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
 use r2d2_sqlite::SqliteConnectionManager;
 
 use crate::{
-    error::Result,
-    events::{CrawlerCheckpoint, Event, EventId, Profile},
+    config::EventSizeLimits,
+    database::{
+        consistency::ConsistencyState,
+        metrics::SharedMetrics,
+        source_cache::SharedSourceCache,
+        source_cipher::SourceCipher,
+        writer_errors::{WriterErrors, WriterOperation},
+    },
+    error::{Error, Result},
+    events::{CrawlerCheckpoint, Event, EventId, Profile, Reaction, RoomAlias, RoomId},
     index::Writer as IndexWriter,
-    Database,
+    Config, Database,
 };
 
+/// How often should the writer thread delete events that are older than the
+/// retention period.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long the writer thread needs to be idle before it automatically
+/// compacts the database.
+const COMPACTION_IDLE_TIME: Duration = Duration::from_secs(5 * 60);
+/// How many writes may fail in a row before the writer thread gives up, the
+/// error is most likely a persistent one, e.g. a full disk.
+const MAX_FAILED_WRITES: usize = 3;
+/// How many events of a sender are deleted in a single transaction when all
+/// the events of the sender are deleted.
+const SENDER_DELETION_BATCH_SIZE: usize = 1000;
+
 pub(crate) struct Writer {
     inner: IndexWriter,
     connection: r2d2::PooledConnection<SqliteConnectionManager>,
     events: Vec<(Event, Profile)>,
     uncommitted_events: Vec<i64>,
     pending_deletion_events: Vec<EventId>,
+    unprocessed_loaded: bool,
+    queue_length: Arc<AtomicUsize>,
+    write_error: Option<Error>,
+    auto_commit_interval: Option<Duration>,
+    auto_commit_after_events: Option<usize>,
+    last_commit: Instant,
+    retention: Option<Duration>,
+    last_purge: Option<Instant>,
+    indexed_msgtypes: Option<Vec<String>>,
+    index_state_history: bool,
+    unindexed_rooms: HashSet<RoomId>,
+    /// The epoch of the last purge of every purged room.
+    purged_rooms: HashMap<RoomId, u64>,
+    store_sources: bool,
+    size_limits: EventSizeLimits,
+    source_cipher: Option<Arc<SourceCipher>>,
+    source_cache: SharedSourceCache,
+    metrics: SharedMetrics,
+    errors: WriterErrors,
+    compaction_threshold: usize,
+    auto_compaction: bool,
+    tombstone_count: usize,
+    last_activity: Instant,
+    failed_writes: usize,
+    fatal_error: Option<String>,
+    /// The sequence number of the last add that was received.
+    received_sequence: u64,
+    /// The sequence number of the last add whose events were stored.
+    stored_sequence: u64,
+    /// The sequence number of the last add whose events were committed.
+    committed_sequence: u64,
+    /// The committed sequence number, shared with the database so it can be
+    /// read without a round trip to the writer thread.
+    shared_committed_sequence: Arc<AtomicU64>,
+    /// The opstamp of the last index commit that was recorded in the store.
+    recorded_opstamp: Option<u64>,
+    /// The timestamp of the oldest event of every room that was stored but
+    /// isn't committed to the index yet.
+    uncommitted_timestamps: HashMap<RoomId, i64>,
+    /// Callers that wait for the events of a room up to a timestamp to be
+    /// committed.
+    room_waiters: Vec<(RoomId, i64, Sender<Result<()>>)>,
 }
 
 impl Writer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         connection: r2d2::PooledConnection<SqliteConnectionManager>,
         index_writer: IndexWriter,
+        queue_length: Arc<AtomicUsize>,
+        committed_sequence: Arc<AtomicU64>,
+        config: &Config,
+        source_cipher: Option<Arc<SourceCipher>>,
+        source_cache: SharedSourceCache,
+        metrics: SharedMetrics,
+        errors: WriterErrors,
     ) -> Self {
         Writer {
             inner: index_writer,
@@ -40,74 +122,788 @@ impl Writer {
             events: Vec::new(),
             uncommitted_events: Vec::new(),
             pending_deletion_events: Vec::new(),
+            unprocessed_loaded: false,
+            queue_length,
+            write_error: None,
+            auto_commit_interval: config.auto_commit_interval,
+            auto_commit_after_events: config.auto_commit_after_events,
+            last_commit: Instant::now(),
+            retention: config.retention,
+            last_purge: None,
+            indexed_msgtypes: config.indexed_msgtypes.clone(),
+            index_state_history: config.index_state_history,
+            unindexed_rooms: HashSet::new(),
+            purged_rooms: HashMap::new(),
+            store_sources: config.store_sources,
+            size_limits: config.size_limits,
+            source_cipher,
+            source_cache,
+            metrics,
+            errors,
+            compaction_threshold: config.compaction_threshold,
+            auto_compaction: config.auto_compaction,
+            tombstone_count: 0,
+            last_activity: Instant::now(),
+            failed_writes: 0,
+            fatal_error: None,
+            received_sequence: 0,
+            stored_sequence: 0,
+            committed_sequence: 0,
+            shared_committed_sequence: committed_sequence,
+            recorded_opstamp: None,
+            uncommitted_timestamps: HashMap::new(),
+            room_waiters: Vec::new(),
+        }
+    }
+
+    /// Replace the sources of the events with minimal ones if the database
+    /// shouldn't store them.
+    fn strip_sources(store_sources: bool, events: &mut [(Event, Profile)]) {
+        if !store_sources {
+            for (event, _) in events.iter_mut() {
+                event.source = event.minimal_source();
+            }
+        }
+    }
+
+    /// Drop the events that exceed the maximal event size and truncate the
+    /// bodies of big events so they don't bloat the index.
+    ///
+    /// This needs to happen before the sources are encrypted.
+    fn limit_event_sizes(limits: &EventSizeLimits, events: &mut Vec<(Event, Profile)>) {
+        events.retain(|(event, _)| event.size() <= limits.max_event_size);
+
+        for (event, _) in events.iter_mut() {
+            event.truncate_content_value(limits.max_indexed_body_bytes);
+
+            if limits.truncate_stored_bodies {
+                event.truncate_source_content(limits.max_indexed_body_bytes);
+            }
+        }
+    }
+
+    /// Get the ids of the events that are about to be written, their cached
+    /// sources need to be invalidated once they are written.
+    fn event_ids(events: &[(Event, Profile)]) -> Vec<EventId> {
+        events.iter().map(|(e, _)| e.event_id.clone()).collect()
+    }
+
+    /// Encrypt the sources and the profiles of the events if the database
+    /// uses encrypted sources.
+    fn encrypt_sources(
+        source_cipher: &Option<Arc<SourceCipher>>,
+        events: &mut Vec<(Event, Profile)>,
+    ) -> Result<()> {
+        match source_cipher {
+            Some(cipher) => cipher.encrypt_events(events),
+            None => Ok(()),
+        }
+    }
+
+    /// Drop the events of rooms that shouldn't be indexed and the message
+    /// events with a msgtype that shouldn't be indexed.
+    fn retain_indexed_events(
+        indexed_msgtypes: &Option<Vec<String>>,
+        unindexed_rooms: &HashSet<RoomId>,
+        events: &mut Vec<(Event, Profile)>,
+    ) {
+        events.retain(|(event, _)| !unindexed_rooms.contains(&event.room_id));
+
+        if let Some(msgtypes) = indexed_msgtypes {
+            events.retain(|(event, _)| match &event.msgtype {
+                Some(msgtype) => msgtypes.contains(msgtype),
+                None => true,
+            });
+        }
+    }
+
+    /// Remember the timestamps of events that are about to be stored, they
+    /// aren't visible to searches until the next commit.
+    fn track_uncommitted<'a>(
+        timestamps: &mut HashMap<RoomId, i64>,
+        events: impl IntoIterator<Item = &'a Event>,
+    ) {
+        for event in events {
+            let oldest = timestamps
+                .entry(event.room_id.clone())
+                .or_insert(event.server_ts);
+            *oldest = (*oldest).min(event.server_ts);
+        }
+    }
+
+    /// Are all the stored events of the room up to the given timestamp
+    /// committed to the index.
+    fn is_room_committed(&self, room_id: &str, up_to_ts: i64) -> bool {
+        self.uncommitted_timestamps
+            .get(room_id)
+            .map_or(true, |oldest| *oldest > up_to_ts)
+    }
+
+    /// Notify the sender once the events of the room up to the given
+    /// timestamp are committed to the index.
+    ///
+    /// Events that were added before this was called are part of the wait,
+    /// the sender is notified right away if they are committed already.
+    pub fn wait_for_room(&mut self, room_id: RoomId, up_to_ts: i64, sender: Sender<Result<()>>) {
+        if self.is_room_committed(&room_id, up_to_ts) {
+            sender.send(Ok(())).unwrap_or(());
+        } else {
+            self.room_waiters.push((room_id, up_to_ts, sender));
         }
     }
 
-    pub fn add_event(&mut self, event: Event, profile: Profile) {
+    /// Forget the uncommitted events after a commit and notify the callers
+    /// that waited for them.
+    fn notify_room_waiters(&mut self) {
+        self.uncommitted_timestamps.clear();
+
+        for (_, _, sender) in self.room_waiters.drain(..) {
+            sender.send(Ok(())).unwrap_or(());
+        }
+    }
+
+    /// Load the events that were stored but weren't committed to the index
+    /// before the database was closed the last time.
+    ///
+    /// Errors are reported on the next commit.
+    pub fn resume(&mut self) {
+        match Database::load_unindexed_rooms(&self.connection) {
+            Ok(rooms) => self.unindexed_rooms = rooms.into_iter().collect(),
+            Err(e) => self.background_error(WriterOperation::Resume, e.into()),
+        }
+
+        match Database::load_purge_epochs(&self.connection) {
+            Ok(rooms) => self.purged_rooms = rooms,
+            Err(e) => self.background_error(WriterOperation::Resume, e.into()),
+        }
+
+        self.update_tombstone_count();
+
+        if let Err(e) = self.load_unprocessed_events_once() {
+            self.background_error(WriterOperation::Resume, e);
+        }
+    }
+
+    pub fn add_event(&mut self, event: Event, profile: Profile, sequence: u64) {
         self.events.push((event, profile));
+        self.received_sequence = sequence;
+
+        // Store the event right away, the uncommitted events table makes sure
+        // that the event survives a crash or a shutdown that happens before
+        // the next commit. Errors are reported on the next commit.
+        if let Err(e) = self.store_queued_events() {
+            self.background_error(WriterOperation::AddEvents, e);
+        }
+    }
+
+    pub fn add_events(&mut self, events: Vec<(Event, Profile)>, sequence: u64) {
+        self.events.extend(events);
+        self.received_sequence = sequence;
+
+        if let Err(e) = self.store_queued_events() {
+            self.background_error(WriterOperation::AddEvents, e);
+        }
+    }
+
+    /// Store a reaction, reactions aren't indexed so they count right away.
+    ///
+    /// Reactions in rooms that aren't indexed are dropped. Errors are
+    /// reported on the next commit.
+    pub fn add_reaction(&mut self, reaction: Reaction, sequence: u64) {
+        // A commit reports the sequence number of the reaction only if the
+        // events that were added before it are stored as well.
+        let caught_up = self.stored_sequence == self.received_sequence;
+        self.received_sequence = sequence;
+
+        let ret = if self.unindexed_rooms.contains(&reaction.room_id) {
+            Ok(())
+        } else {
+            Database::store_reaction(&self.connection, &reaction)
+        };
+
+        match ret {
+            Ok(()) if caught_up => self.stored_sequence = sequence,
+            Ok(()) => (),
+            Err(e) => self.background_error(WriterOperation::AddReaction, e.into()),
+        }
+    }
+
+    /// Store the canonical alias of a room, aliases are only used to label
+    /// search results so they are stored right away.
+    ///
+    /// Aliases of rooms that aren't indexed are dropped. Errors are reported
+    /// on the next commit.
+    pub fn add_room_alias(&mut self, alias: RoomAlias, sequence: u64) {
+        let caught_up = self.stored_sequence == self.received_sequence;
+        self.received_sequence = sequence;
+
+        let ret = if self.unindexed_rooms.contains(&alias.room_id) {
+            Ok(())
+        } else {
+            Database::store_room_alias(&self.connection, &alias)
+        };
+
+        match ret {
+            Ok(()) if caught_up => self.stored_sequence = sequence,
+            Ok(()) => (),
+            Err(e) => self.background_error(WriterOperation::AddRoomAlias, e.into()),
+        }
+    }
+
+    pub fn delete_events_older_than(&mut self, timestamp: i64) -> Result<usize> {
+        let ret = Database::delete_events_older_than_helper(
+            &mut self.connection,
+            &mut self.inner,
+            timestamp,
+            &mut self.pending_deletion_events,
+        );
+        self.source_cache.lock().unwrap().clear();
+        self.update_tombstone_count();
+
+        if let Ok(count) = &ret {
+            self.metrics.delete_events(*count);
+        }
+
+        ret
+    }
+
+    /// Enable or disable the indexing of a room.
+    ///
+    /// The events of the room are purged if a purge epoch is given.
+    ///
+    /// Returns the number of events of the room that were purged.
+    pub fn set_room_indexing(
+        &mut self,
+        room_id: RoomId,
+        enabled: bool,
+        purge: Option<u64>,
+    ) -> Result<usize> {
+        Database::set_room_indexing_helper(&self.connection, &room_id, enabled)?;
+
+        if enabled {
+            self.unindexed_rooms.remove(&room_id);
+            return Ok(0);
+        }
+
+        self.unindexed_rooms.insert(room_id.clone());
+
+        match purge {
+            Some(epoch) => self.purge_room(room_id, epoch),
+            None => Ok(0),
+        }
+    }
+
+    /// Delete the events and the crawler checkpoints of a room.
+    ///
+    /// Returns the number of events of the room that were deleted.
+    pub fn delete_room(&mut self, room_id: RoomId, epoch: u64) -> Result<usize> {
+        Database::delete_room_checkpoints(&self.connection, &room_id)?;
+        self.purge_room(room_id, epoch)
+    }
+
+    /// Delete the events of a room and remember the epoch of the purge,
+    /// historic events of the room that were queued before it are dropped.
+    fn purge_room(&mut self, room_id: RoomId, epoch: u64) -> Result<usize> {
+        Database::set_room_purge_epoch(&self.connection, &room_id, epoch)?;
+
+        let purged_epoch = self.purged_rooms.entry(room_id.clone()).or_insert(0);
+        *purged_epoch = (*purged_epoch).max(epoch);
+
+        let ret = Database::delete_room_events_helper(
+            &mut self.connection,
+            &mut self.inner,
+            &room_id,
+            &mut self.pending_deletion_events,
+        );
+        self.source_cache.lock().unwrap().clear();
+        self.update_tombstone_count();
+
+        if let Ok(count) = &ret {
+            self.metrics.delete_events(*count);
+        }
+
+        ret
+    }
+
+    /// Was the room purged after a batch with the given epoch was queued.
+    fn purged_since(purged_rooms: &HashMap<RoomId, u64>, room_id: &str, epoch: u64) -> bool {
+        purged_rooms.get(room_id).map_or(false, |e| *e > epoch)
     }
 
     pub fn delete_event(&mut self, event_id: EventId) -> Result<bool> {
-        Database::delete_event_helper(
+        // The cached source is invalidated after the event was deleted, a
+        // search that loads the event in the meantime doesn't cache it.
+        let ret = Database::delete_event_helper(
             &mut self.connection,
             &mut self.inner,
-            event_id,
+            event_id.clone(),
             &mut self.pending_deletion_events,
-        )
+        );
+        self.source_cache.lock().unwrap().invalidate(&[event_id]);
+        self.update_tombstone_count();
+
+        if let Ok(true) = ret {
+            self.metrics.delete_events(1);
+        }
+
+        ret
+    }
+
+    /// Delete all the events of a sender, in batches, together with the
+    /// profiles of the sender.
+    ///
+    /// The progress function is called with the number of deleted events
+    /// after every batch.
+    ///
+    /// Returns the number of deleted events.
+    pub fn delete_sender(
+        &mut self,
+        sender: &str,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<usize> {
+        // Events of the sender that are still queued up are dropped before
+        // they are ever stored.
+        let queued_events = self.events.len();
+        self.events.retain(|(e, _)| e.sender != sender);
+        self.queue_length
+            .fetch_sub(queued_events - self.events.len(), Ordering::SeqCst);
+
+        let ret = self.delete_sender_helper(sender, progress);
+
+        // Cached events carry the profile of their sender, the cache may hold
+        // deleted events even if a batch failed.
+        self.source_cache.lock().unwrap().clear();
+        self.update_tombstone_count();
+
+        ret
+    }
+
+    fn delete_sender_helper(
+        &mut self,
+        sender: &str,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<usize> {
+        let mut count = 0;
+
+        loop {
+            let deleted = Database::delete_sender_events_helper(
+                &mut self.connection,
+                &mut self.inner,
+                sender,
+                SENDER_DELETION_BATCH_SIZE,
+                &mut self.pending_deletion_events,
+            )?;
+
+            if deleted == 0 {
+                break;
+            }
+
+            count += deleted;
+            self.metrics.delete_events(deleted);
+            progress(count);
+        }
+
+        Database::delete_unused_profiles(&self.connection)?;
+
+        Ok(count)
+    }
+
+    /// Refresh the number of events that were deleted since the last
+    /// compaction.
+    fn update_tombstone_count(&mut self) {
+        if let Ok(count) = Database::get_tombstone_count(&self.connection) {
+            self.tombstone_count = count as usize;
+        }
+    }
+
+    /// Compact the database if enough events were deleted since the last
+    /// compaction.
+    ///
+    /// The queued up events are committed, the segments of the index that
+    /// contain deleted events are merged, unused index files are removed and
+    /// the free pages of the database are released.
+    ///
+    /// Returns false if the compaction threshold wasn't reached.
+    pub fn compact(&mut self) -> Result<bool> {
+        if self.tombstone_count < self.compaction_threshold {
+            return Ok(false);
+        }
+
+        self.compact_helper()?;
+
+        Ok(true)
+    }
+
+    /// Compact the database regardless of the compaction threshold.
+    ///
+    /// Returns the number of bytes by which the database directories shrank.
+    pub fn shrink(&mut self, locations: &[PathBuf]) -> Result<u64> {
+        let size_before = Database::get_locations_size(locations)?;
+        self.compact_helper()?;
+        let size_after = Database::get_locations_size(locations)?;
+
+        Ok(size_before.saturating_sub(size_after))
+    }
+
+    fn compact_helper(&mut self) -> Result<()> {
+        self.write_queued_events(true)?;
+        self.inner.compact()?;
+        Database::retry_on_busy(|| Database::vacuum(&self.connection))?;
+        self.tombstone_count = 0;
+
+        Ok(())
+    }
+
+    /// Record the opstamp of the last commit of the index in the store, the
+    /// consistency check compares it with the index when the database is
+    /// opened the next time.
+    ///
+    /// Errors are reported on the next commit.
+    pub fn record_index_commit(&mut self) {
+        let opstamp = self.inner.committed_opstamp();
+
+        if self.recorded_opstamp == Some(opstamp) {
+            return;
+        }
+
+        match Database::save_index_opstamp(&self.connection, opstamp) {
+            Ok(()) => self.recorded_opstamp = Some(opstamp),
+            Err(e) => self.background_error(WriterOperation::RecordIndexCommit, e.into()),
+        }
+    }
+
+    /// Repair the database if the consistency check found that the store
+    /// and the index disagree.
+    ///
+    /// Returns false if the store and the index were consistent.
+    pub fn repair(&mut self) -> Result<bool> {
+        let consistency = Database::load_consistency(&self.connection)?;
+
+        let (delete_orphans, reindex) = match consistency {
+            ConsistencyState::Consistent => return Ok(false),
+            ConsistencyState::IndexBehind => (false, true),
+            ConsistencyState::StoreBehind => (true, false),
+            ConsistencyState::Diverged => (true, true),
+        };
+
+        if reindex && !self.store_sources {
+            return Err(Error::SourcesNotStored);
+        }
+
+        self.write_queued_events(true)?;
+
+        if delete_orphans {
+            Database::delete_orphaned_events(&self.connection, &mut self.inner)?;
+        }
+
+        if reindex {
+            Database::reindex_stored_events(
+                &self.connection,
+                &mut self.inner,
+                &self.source_cipher,
+                self.size_limits.max_indexed_body_bytes,
+                self.index_state_history,
+            )?;
+        }
+
+        Database::save_index_opstamp(&self.connection, self.inner.committed_opstamp())?;
+        Database::save_consistency(&self.connection, ConsistencyState::Consistent)?;
+
+        Ok(true)
+    }
+
+    /// Remember that the writer thread just processed a message, automatic
+    /// compactions only happen while the writer is idle.
+    pub fn record_activity(&mut self) {
+        self.last_activity = Instant::now();
     }
 
     fn mark_events_as_deleted(&mut self) -> Result<()> {
         if self.pending_deletion_events.is_empty() {
             return Ok(());
         }
-        Database::mark_events_as_deleted(&mut self.connection, &mut self.pending_deletion_events)
+        Database::retry_on_busy(|| {
+            Database::mark_events_as_deleted(
+                &mut self.connection,
+                &mut self.pending_deletion_events,
+            )
+        })
+    }
+
+    /// Roll back the index writer if writing events failed.
+    ///
+    /// Events are stored in the uncommitted events table in the same
+    /// transaction that stores the events and updates the crawler checkpoints.
+    /// If the index commit fails we discard the documents the index writer
+    /// holds and load the events again from the table on the next write, so
+    /// no event gets lost nor indexed twice.
+    ///
+    /// Writes that keep failing until the last successful commit are counted,
+    /// the writer gives up once `MAX_FAILED_WRITES` is reached.
+    fn rollback_on_error<T>(&mut self, ret: Result<T>) -> Result<T> {
+        if let Err(e) = &ret {
+            self.failed_writes += 1;
+
+            if self.failed_writes >= MAX_FAILED_WRITES {
+                self.fatal_error = Some(e.to_string());
+            }
+
+            if self.inner.rollback().is_ok() {
+                self.uncommitted_events.clear();
+                self.pending_deletion_events.clear();
+                self.unprocessed_loaded = false;
+            }
+        }
+
+        ret
+    }
+
+    /// Remember the error of an operation nobody is waiting for.
+    ///
+    /// The error is returned by the next commit and kept until it's taken
+    /// from the database.
+    fn background_error(&mut self, operation: WriterOperation, error: Error) {
+        self.background_room_error(operation, None, error);
+    }
+
+    /// Remember the error of an operation on a room nobody is waiting for,
+    /// see `background_error()`.
+    fn background_room_error(
+        &mut self,
+        operation: WriterOperation,
+        room_id: Option<RoomId>,
+        error: Error,
+    ) {
+        self.errors.push(operation, room_id, &error);
+        self.write_error = Some(error);
+    }
+
+    /// The room a batch of events from the room history belongs to.
+    pub fn historic_room_id(
+        checkpoint: Option<&CrawlerCheckpoint>,
+        old_checkpoint: Option<&CrawlerCheckpoint>,
+        events: &[(Event, Profile)],
+    ) -> Option<RoomId> {
+        checkpoint
+            .or(old_checkpoint)
+            .map(|c| c.room_id.clone())
+            .or_else(|| events.first().map(|(e, _)| e.room_id.clone()))
+    }
+
+    /// Send the result of an operation to the caller that requested it.
+    ///
+    /// If the caller stopped waiting for the result, an error is kept until
+    /// it's taken from the database instead of getting lost.
+    pub fn reply<T>(
+        &self,
+        sender: Sender<Result<T>>,
+        ret: Result<T>,
+        operation: WriterOperation,
+        room_id: Option<RoomId>,
+    ) {
+        if let Err(SendError(Err(e))) = sender.send(ret) {
+            self.errors.push(operation, room_id, &e);
+        }
+    }
+
+    /// Take the error that made the writer give up, the writer thread should
+    /// stop if there is one.
+    pub fn fatal_error(&mut self) -> Option<String> {
+        self.fatal_error.take()
+    }
+
+    fn load_unprocessed_events_once(&mut self) -> Result<()> {
+        // We may have events that aren't deleted or committed to the index
+        // but are stored in the db, let us load them from the db and commit
+        // them to the index now. They will later be marked as committed in
+        // the database as part of a normal write.
+        if !self.unprocessed_loaded {
+            self.unprocessed_loaded = true;
+            self.load_unprocessed_events()?;
+        }
+
+        Ok(())
+    }
+
+    fn store_queued_events(&mut self) -> Result<()> {
+        self.load_unprocessed_events_once()?;
+
+        let queued_events = self.events.len();
+        Writer::retain_indexed_events(
+            &self.indexed_msgtypes,
+            &self.unindexed_rooms,
+            &mut self.events,
+        );
+        Writer::limit_event_sizes(&self.size_limits, &mut self.events);
+        Writer::strip_sources(self.store_sources, &mut self.events);
+        let event_ids = Writer::event_ids(&self.events);
+        Writer::track_uncommitted(
+            &mut self.uncommitted_timestamps,
+            self.events.iter().map(|(e, _)| e),
+        );
+
+        let ret = Writer::encrypt_sources(&self.source_cipher, &mut self.events).and_then(|_| {
+            Database::store_events(
+                &mut self.connection,
+                &mut self.inner,
+                (None, None, &mut self.events),
+                &mut self.uncommitted_events,
+                self.index_state_history,
+            )
+        });
+        self.source_cache.lock().unwrap().invalidate(&event_ids);
+
+        self.queue_length.fetch_sub(queued_events, Ordering::SeqCst);
+        self.rollback_on_error(ret)?;
+        self.stored_sequence = self.received_sequence;
+        self.metrics.add_events(event_ids.len());
+
+        Ok(())
     }
 
-    pub fn write_queued_events(&mut self, force_commit: bool) -> Result<()> {
-        let (_, committed) = Database::write_events(
+    /// Store and commit the queued up events.
+    ///
+    /// Returns the sequence number of the last add whose events were
+    /// committed, if the commit was rate limited this is the sequence number
+    /// of an earlier commit.
+    pub fn write_queued_events(&mut self, force_commit: bool) -> Result<u64> {
+        self.store_queued_events()?;
+
+        let ret = Database::commit_events(
             &mut self.connection,
             &mut self.inner,
-            (None, None, &mut self.events),
             force_commit,
             &mut self.uncommitted_events,
-        )?;
+        );
+        let committed = self.rollback_on_error(ret)?;
 
         if committed {
+            self.last_commit = Instant::now();
+            self.failed_writes = 0;
+            self.metrics.commit();
             self.mark_events_as_deleted()?;
         }
 
+        // Stored events that didn't add anything to the index, e.g. because
+        // they don't contain any text, don't need a commit to the index.
+        if committed || !self.inner.has_uncommitted_events() {
+            self.committed_sequence = self.stored_sequence;
+            self.shared_committed_sequence
+                .store(self.committed_sequence, Ordering::SeqCst);
+            self.notify_room_waiters();
+        }
+
+        if force_commit {
+            self.sync_database()?;
+        }
+
+        match self.write_error.take() {
+            Some(e) => Err(e),
+            None => Ok(self.committed_sequence),
+        }
+    }
+
+    /// Sync the Sqlite database to disk.
+    ///
+    /// Transactions that are committed to the write-ahead log aren't synced
+    /// with the synchronous mode we use, a checkpoint syncs them and moves them
+    /// into the database file. This does nothing if the write-ahead log is
+    /// disabled, every transaction is synced in that case.
+    fn sync_database(&self) -> Result<()> {
+        self.connection
+            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
         Ok(())
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(batch_size = events.len()))
+    )]
     pub fn write_historic_events(
         &mut self,
         checkpoint: Option<CrawlerCheckpoint>,
         old_checkpoint: Option<CrawlerCheckpoint>,
         mut events: Vec<(Event, Profile)>,
+        epoch: u64,
         force_commit: bool,
     ) -> Result<bool> {
+        self.load_unprocessed_events_once()?;
+
+        let room_id =
+            Writer::historic_room_id(checkpoint.as_ref(), old_checkpoint.as_ref(), &events);
+
+        // The batch was queued before its room was purged, adding it would
+        // resurrect the room.
+        let purged_rooms = &self.purged_rooms;
+        let is_stale = |room_id: &str| Writer::purged_since(purged_rooms, room_id, epoch);
+        let queued_events = events.len();
+
+        events.retain(|(event, _)| !is_stale(&event.room_id));
+        let purged = queued_events > 0 && events.is_empty();
+
+        let checkpoint = checkpoint.filter(|c| !is_stale(&c.room_id));
+        let old_checkpoint = old_checkpoint.filter(|c| !is_stale(&c.room_id));
+
+        // If every event got dropped the crawler should still continue, so
+        // treat the events as new instead of as already known ones.
+        Writer::retain_indexed_events(&self.indexed_msgtypes, &self.unindexed_rooms, &mut events);
+        Writer::limit_event_sizes(&self.size_limits, &mut events);
+        Writer::strip_sources(self.store_sources, &mut events);
+        Writer::encrypt_sources(&self.source_cipher, &mut events)?;
         let empty_events = events.is_empty();
-        let (ret, committed) = Database::write_events(
+        let event_ids = Writer::event_ids(&events);
+        Writer::track_uncommitted(
+            &mut self.uncommitted_timestamps,
+            events.iter().map(|(e, _)| e),
+        );
+        let ret = Database::store_events(
             &mut self.connection,
             &mut self.inner,
             (checkpoint, old_checkpoint, &mut events),
+            &mut self.uncommitted_events,
+            self.index_state_history,
+        );
+        self.source_cache.lock().unwrap().invalidate(&event_ids);
+        let ret = self.rollback_on_error(ret)?;
+        self.metrics.add_events(event_ids.len());
+
+        // The events and the new checkpoint are stored at this point, they
+        // are committed to the index by a later commit if this one fails. The
+        // crawler has to continue from the new checkpoint, so the failure is
+        // returned by the next commit instead.
+        let commit = Database::commit_events(
+            &mut self.connection,
+            &mut self.inner,
             force_commit,
             &mut self.uncommitted_events,
-        )?;
+        );
+        let committed = match self.rollback_on_error(commit) {
+            Ok(committed) => committed,
+            Err(e) => {
+                self.background_room_error(WriterOperation::HistoricEvents, room_id, e);
+                false
+            }
+        };
 
         if committed {
+            self.last_commit = Instant::now();
+            self.failed_writes = 0;
+            self.metrics.commit();
+            self.notify_room_waiters();
             self.mark_events_as_deleted()?;
         }
 
+        // There's nothing left to crawl in a room that is gone.
         if empty_events {
-            Ok(false)
+            Ok(!purged)
         } else {
             Ok(ret)
         }
     }
 
-    pub fn load_unprocessed_events(&mut self) -> Result<()> {
+    fn load_unprocessed_events(&mut self) -> Result<()> {
         let mut ret = Database::load_uncommitted_events(&self.connection)?;
+        Writer::track_uncommitted(&mut self.uncommitted_timestamps, ret.iter().map(|(_, e)| e));
 
         for (id, event) in ret.drain(..) {
             self.uncommitted_events.push(id);
@@ -117,7 +913,7 @@ impl Writer {
         let ret = Database::load_pending_deletion_events(&self.connection)?;
 
         for event_id in &ret {
-            self.inner.delete_event(event_id);
+            self.inner.delete_event(event_id)?;
         }
 
         self.pending_deletion_events.extend(ret);
@@ -125,6 +921,144 @@ impl Writer {
         Ok(())
     }
 
+    fn has_pending_changes(&self) -> bool {
+        !self.uncommitted_events.is_empty() || !self.pending_deletion_events.is_empty()
+    }
+
+    /// How long the writer thread may wait for a new message before the next
+    /// automatic commit or purge of expired events is due.
+    ///
+    /// Returns `None` if the writer thread has nothing to do on its own.
+    pub fn timeout(&self) -> Option<Duration> {
+        let purge_timeout = self.retention.map(|_| match self.last_purge {
+            Some(last_purge) => PURGE_INTERVAL
+                .checked_sub(last_purge.elapsed())
+                .unwrap_or_default(),
+            None => Duration::from_secs(0),
+        });
+
+        [
+            self.auto_commit_timeout(),
+            purge_timeout,
+            self.compaction_timeout(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    /// Run the automatic commit, the purge of expired events and the
+    /// automatic compaction if they are due.
+    pub fn run_timers(&mut self) {
+        self.auto_commit();
+        self.purge_expired_events();
+        self.auto_compact();
+    }
+
+    fn compaction_timeout(&self) -> Option<Duration> {
+        if self.auto_compaction && self.tombstone_count >= self.compaction_threshold {
+            Some(
+                COMPACTION_IDLE_TIME
+                    .checked_sub(self.last_activity.elapsed())
+                    .unwrap_or_default(),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// Compact the database if the writer has been idle for long enough.
+    ///
+    /// Errors are reported on the next manual commit.
+    fn auto_compact(&mut self) {
+        if self.compaction_timeout() != Some(Duration::from_secs(0)) {
+            return;
+        }
+
+        if let Err(e) = self.compact() {
+            self.background_error(WriterOperation::AutoCompaction, e);
+        }
+
+        // Don't retry a failing compaction right away.
+        self.record_activity();
+    }
+
+    /// Delete the events that are older than the retention period.
+    ///
+    /// Errors are reported on the next manual commit.
+    fn purge_expired_events(&mut self) {
+        let retention = match self.retention {
+            Some(r) => r,
+            None => return,
+        };
+
+        if self
+            .last_purge
+            .map_or(false, |last_purge| last_purge.elapsed() < PURGE_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_purge = Some(Instant::now());
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let cutoff = now.checked_sub(retention).unwrap_or_default();
+
+        if let Err(e) = self.delete_events_older_than(cutoff.as_millis() as i64) {
+            self.background_error(WriterOperation::RetentionPurge, e);
+        }
+    }
+
+    fn auto_commit_timeout(&self) -> Option<Duration> {
+        let interval = self.auto_commit_interval?;
+
+        if self.has_pending_changes() {
+            Some(
+                interval
+                    .checked_sub(self.last_commit.elapsed())
+                    .unwrap_or_default(),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// Commit the pending events if the auto commit interval elapsed or if
+    /// enough events are waiting to be committed.
+    ///
+    /// Errors are reported on the next manual commit.
+    pub fn auto_commit(&mut self) {
+        let enough_events = self
+            .auto_commit_after_events
+            .map_or(false, |count| self.uncommitted_events.len() >= count);
+        let interval_elapsed = self.auto_commit_timeout() == Some(Duration::from_secs(0));
+
+        if !enough_events && !interval_elapsed {
+            return;
+        }
+
+        if let Err(e) = self.write_queued_events(true) {
+            self.background_error(WriterOperation::AutoCommit, e);
+        }
+
+        // Don't retry a failing commit right away.
+        self.last_commit = Instant::now();
+    }
+
+    /// Commit the queued up events and write a backup of the database.
+    pub fn backup(
+        &mut self,
+        index_dir: &Path,
+        store_dir: &Path,
+        archive_path: &Path,
+    ) -> Result<()> {
+        self.write_queued_events(true)?;
+        Database::write_backup(&self.connection, index_dir, store_dir, archive_path)
+    }
+
     pub fn shutdown(self) -> Result<()> {
         self.inner.wait_merging_threads()?;
         Ok(())
@@ -0,0 +1,164 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::{error::Error, events::RoomId};
+
+/// How many errors are kept until they are taken, older errors are dropped
+/// first.
+const MAX_WRITER_ERRORS: usize = 100;
+
+/// An operation of the writer thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WriterOperation {
+    /// Loading the events that weren't committed before the database was
+    /// closed the last time.
+    Resume,
+    /// Storing added events.
+    AddEvents,
+    /// Storing an added reaction.
+    AddReaction,
+    /// Storing the canonical alias of a room.
+    AddRoomAlias,
+    /// Committing the queued up events.
+    Commit,
+    /// Committing the queued up events automatically.
+    AutoCommit,
+    /// Storing and committing a batch of events from the room history.
+    HistoricEvents,
+    /// Deleting a single event.
+    DeleteEvent,
+    /// Deleting the events that are older than a timestamp.
+    DeleteEventsOlderThan,
+    /// Deleting the events that are older than the retention period.
+    RetentionPurge,
+    /// Enabling or disabling the indexing of a room.
+    SetRoomIndexing,
+    /// Deleting the events of a room.
+    DeleteRoom,
+    /// Deleting the events of a sender.
+    DeleteSender,
+    /// Compacting the database.
+    Compact,
+    /// Compacting the database automatically once the writer was idle.
+    AutoCompaction,
+    /// Compacting the database unconditionally.
+    Shrink,
+    /// Writing a backup of the database.
+    Backup,
+    /// Repairing a database whose store and index disagree.
+    Repair,
+    /// Recording the last commit of the index in the store.
+    RecordIndexCommit,
+}
+
+impl WriterOperation {
+    /// The name of the operation.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WriterOperation::Resume => "resume",
+            WriterOperation::AddEvents => "addEvents",
+            WriterOperation::AddReaction => "addReaction",
+            WriterOperation::AddRoomAlias => "addRoomAlias",
+            WriterOperation::Commit => "commit",
+            WriterOperation::AutoCommit => "autoCommit",
+            WriterOperation::HistoricEvents => "historicEvents",
+            WriterOperation::DeleteEvent => "deleteEvent",
+            WriterOperation::DeleteEventsOlderThan => "deleteEventsOlderThan",
+            WriterOperation::RetentionPurge => "retentionPurge",
+            WriterOperation::SetRoomIndexing => "setRoomIndexing",
+            WriterOperation::DeleteRoom => "deleteRoom",
+            WriterOperation::DeleteSender => "deleteSender",
+            WriterOperation::Compact => "compact",
+            WriterOperation::AutoCompaction => "autoCompaction",
+            WriterOperation::Shrink => "shrink",
+            WriterOperation::Backup => "backup",
+            WriterOperation::Repair => "repair",
+            WriterOperation::RecordIndexCommit => "recordIndexCommit",
+        }
+    }
+}
+
+/// An error that happened in the writer thread while nobody was waiting for
+/// the result of the operation, e.g. during an automatic commit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WriterError {
+    /// The operation that failed.
+    pub operation: WriterOperation,
+    /// The room the operation was working on, if it was limited to a room.
+    pub room_id: Option<RoomId>,
+    /// The kind of the error, one of "DatabaseError", "IndexError",
+    /// "IOError", "FsError", "DatabaseLocked" or "Error" for any other
+    /// error.
+    ///
+    /// Database and IO errors are usually caused by a full disk, an index
+    /// error may mean that the index is corrupted and needs to be rebuilt.
+    pub kind: String,
+    /// The stable name of the kind of the error, see `ErrorKind::as_str()`.
+    pub code: String,
+    /// The description of the error.
+    pub message: String,
+}
+
+impl WriterError {
+    fn new(operation: WriterOperation, room_id: Option<RoomId>, error: &Error) -> Self {
+        let kind = match error {
+            Error::DatabaseError(_) => "DatabaseError",
+            Error::IndexError(_) => "IndexError",
+            Error::IOError(_) => "IOError",
+            Error::FsError(_) => "FsError",
+            Error::DatabaseLocked => "DatabaseLocked",
+            _ => "Error",
+        };
+
+        WriterError {
+            operation,
+            room_id,
+            kind: kind.to_owned(),
+            code: error.kind().as_str().to_owned(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// The errors of the writer thread that weren't taken yet, shared between
+/// the writer thread and the database.
+#[derive(Clone, Default)]
+pub(crate) struct WriterErrors {
+    inner: Arc<Mutex<VecDeque<WriterError>>>,
+}
+
+impl WriterErrors {
+    /// Remember an error of the writer thread.
+    pub(crate) fn push(&self, operation: WriterOperation, room_id: Option<RoomId>, error: &Error) {
+        let mut errors = self.inner.lock().unwrap();
+
+        if errors.len() >= MAX_WRITER_ERRORS {
+            errors.pop_front();
+        }
+
+        errors.push_back(WriterError::new(operation, room_id, error));
+    }
+
+    /// Take the remembered errors, oldest first.
+    pub(crate) fn take(&self) -> Vec<WriterError> {
+        self.inner.lock().unwrap().drain(..).collect()
+    }
+}
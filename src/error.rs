@@ -12,8 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::path::PathBuf;
+
+use rusqlite::ErrorCode;
+use tantivy::directory::error::LockError;
 use thiserror::Error;
 
+use crate::events::{CheckpointValidationError, EventValidationError};
+
 /// Result type for seshat operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -25,6 +31,10 @@ pub enum Error {
     /// Error signaling that there was an error with the Sqlite connection
     /// pool.
     PoolError(#[from] r2d2::Error),
+    #[error("Timed out while waiting for a free Sqlite connection")]
+    /// Error signaling that all the connections of the Sqlite connection pool
+    /// stayed in use until the connection timeout ran out.
+    PoolTimeout,
     #[error("Sqlite database error: {}", _0)]
     /// Error signaling that there was an error with a Sqlite transaction.
     DatabaseError(#[from] rusqlite::Error),
@@ -53,10 +63,251 @@ pub enum Error {
     /// Error indicating that the index needs to be rebuilt.
     #[error("Error opening the database, the index needs to be rebuilt.")]
     ReindexError,
+    /// Error signaling that the writer thread of the database has stopped and
+    /// the operation could not be completed.
+    #[error("The database writer thread has stopped.")]
+    WriterClosedError,
+    /// Error signaling that the writer thread of the database stopped because
+    /// of a failure, events that were added since then won't be written.
+    #[error("The database writer thread has failed: {}", _0)]
+    WriterDead(String),
+    /// Error signaling that the write queue is full and the event wasn't
+    /// added to the database.
+    #[error("The write queue of the database is full.")]
+    QueueFull,
+    /// Error signaling that an operation requires an encrypted database but
+    /// the database isn't encrypted.
+    #[error("The database isn't encrypted.")]
+    NotEncryptedError,
+    /// Error signaling that the search term is empty or doesn't contain any
+    /// searchable words.
+    #[error("The search term is empty.")]
+    EmptySearchTerm,
+    /// Error signaling that the index is locked, another process or another
+    /// `Database` object is already using the database.
+    #[error("The database is locked, another process is already using the index.")]
+    DatabaseLocked,
+    /// Error signaling that a backup couldn't be created or restored.
+    #[error("Backup error: {}", _0)]
+    BackupError(String),
+    /// Error signaling that a backup was created by a newer version of Seshat
+    /// and can't be restored.
+    #[error(
+        "The backup was created by a newer version of Seshat, database version {}.",
+        _0
+    )]
+    BackupVersionError(i64),
+    /// Error signaling that a room export couldn't be imported.
+    #[error("Error importing the room export: {}", _0)]
+    ImportError(String),
+    /// Error signaling that an event isn't part of the database.
+    #[error("The event {} isn't part of the database.", _0)]
+    EventNotFound(String),
+    /// Error signaling that the database was created with a different setting
+    /// for storing event sources than the one it's opened with.
+    #[error("The database was created with a different setting for storing event sources.")]
+    StoreSourcesMismatch,
+    /// Error signaling that an operation needs the source of an event, but
+    /// the database doesn't store event sources.
+    #[error("The database doesn't store the sources of events.")]
+    SourcesNotStored,
+    /// Error signaling that the passphrase for the encrypted event sources is
+    /// wrong or missing.
+    #[error("The passphrase for the encrypted event sources is wrong.")]
+    WrongPassphrase,
+    /// Error signaling that the `next_batch` token of a search is unknown or
+    /// has expired, the search needs to be started again.
+    #[error("The search token is unknown or has expired.")]
+    InvalidSearchToken,
+    /// Error signaling that a search query exceeds one of the configured
+    /// query limits, the search wasn't attempted.
+    #[error("The search query is too long: {}", _0)]
+    QueryTooComplex(String),
+    /// Error signaling that a search term using the advanced query syntax
+    /// couldn't be parsed.
+    #[error("The search query is invalid: {}", _0)]
+    QuerySyntaxError(String),
+    /// Error signaling that a search term only contains excluded words, such
+    /// a search would need to scan all events.
+    #[error("The search query only contains excluded terms.")]
+    OnlyExcludedTerms,
+    /// Error signaling that a search term contains a wildcard at the start of
+    /// a word, or a wildcard without a word, and leading wildcards aren't
+    /// enabled.
+    #[error("Wildcards at the start of a search word aren't supported.")]
+    LeadingWildcard,
+    /// Error signaling that a search is restricted to more events than the
+    /// configured maximum allows. Contains the number of events and the
+    /// maximum.
+    #[error("Too many restricted event ids: {}, at most {} are allowed.", _0, _1)]
+    TooManyRestrictedIds(usize, usize),
+    /// Error signaling that an event is bigger than the configured maximal
+    /// event size, the event was not added.
+    #[error("The event {} is too large: {} bytes.", _0, _1)]
+    EventTooLarge(String, usize),
+    /// Error signaling that a search was cancelled before it finished.
+    #[error("The search was cancelled.")]
+    SearchCancelled,
+    /// Error signaling that an event built by an `EventBuilder` isn't valid,
+    /// contains every problem that was found.
+    #[error(
+        "The event is invalid: {}",
+        .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    InvalidEvent(Vec<EventValidationError>),
+    /// Error signaling that a crawler checkpoint isn't valid, e.g. because
+    /// its token is empty.
+    #[error("The crawler checkpoint is invalid: {}", _0)]
+    InvalidCheckpoint(CheckpointValidationError),
+    /// Error signaling that the store of the database contains events but
+    /// its index is missing, e.g. because the volume of the index isn't
+    /// mounted. Contains the directory the index was last opened from.
+    #[error("The index of the database is missing, it was last opened from {}.", _0.display())]
+    IndexNotFound(PathBuf),
+    /// Error signaling that a search should only return the events after a
+    /// watermark that doesn't exist. Contains the name of the watermark.
+    #[error("The watermark {} doesn't exist.", _0)]
+    WatermarkNotFound(String),
 }
 
 impl From<tantivy::TantivyError> for Error {
     fn from(err: tantivy::TantivyError) -> Self {
-        Error::IndexError(err)
+        match err {
+            tantivy::TantivyError::LockFailure(LockError::LockBusy, _) => Error::DatabaseLocked,
+            _ => Error::IndexError(err),
+        }
+    }
+}
+
+/// The kind of a Seshat error.
+///
+/// Errors are grouped into kinds that need to be handled in the same way,
+/// e.g. a corrupted database needs to be recreated while a query that is
+/// too complex only needs to be changed. Unlike the error messages, the
+/// names of the kinds are stable.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ErrorKind {
+    /// The store or the index of the database is corrupted, the database
+    /// needs to be recreated.
+    Corrupted,
+    /// The index needs to be rebuilt, e.g. because its schema changed.
+    ReindexRequired,
+    /// Another process is using the database.
+    Locked,
+    /// The database is busy, the operation can be retried later.
+    Busy,
+    /// The passphrase of the database is wrong.
+    WrongPassphrase,
+    /// The database or a backup was created by an incompatible version.
+    VersionMismatch,
+    /// The database was created with a different configuration.
+    ConfigMismatch,
+    /// Reading or writing a file failed.
+    Io,
+    /// The disk the database lives on is full.
+    DiskFull,
+    /// The search query or its options are invalid.
+    Query,
+    /// An event is invalid or too large.
+    InvalidEvent,
+    /// A crawler checkpoint is invalid.
+    InvalidCheckpoint,
+    /// An event or the index couldn't be found.
+    NotFound,
+    /// The operation isn't supported by the configuration of the database.
+    Unsupported,
+    /// The database was closed or its writer thread stopped.
+    Closed,
+    /// The write queue of the database is full.
+    QueueFull,
+    /// The search was cancelled.
+    Cancelled,
+    /// A backup couldn't be created or restored.
+    Backup,
+    /// A room export couldn't be imported.
+    Import,
+    /// Any other error of the store.
+    Database,
+    /// Any other error of the index.
+    Index,
+}
+
+impl ErrorKind {
+    /// Get the stable name of the kind, e.g. `DATABASE_LOCKED`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Corrupted => "DATABASE_CORRUPTED",
+            ErrorKind::ReindexRequired => "REINDEX_REQUIRED",
+            ErrorKind::Locked => "DATABASE_LOCKED",
+            ErrorKind::Busy => "DATABASE_BUSY",
+            ErrorKind::WrongPassphrase => "WRONG_PASSPHRASE",
+            ErrorKind::VersionMismatch => "VERSION_MISMATCH",
+            ErrorKind::ConfigMismatch => "CONFIG_MISMATCH",
+            ErrorKind::Io => "IO_ERROR",
+            ErrorKind::DiskFull => "DISK_FULL",
+            ErrorKind::Query => "INVALID_QUERY",
+            ErrorKind::InvalidEvent => "INVALID_EVENT",
+            ErrorKind::InvalidCheckpoint => "INVALID_CHECKPOINT",
+            ErrorKind::NotFound => "NOT_FOUND",
+            ErrorKind::Unsupported => "UNSUPPORTED",
+            ErrorKind::Closed => "DATABASE_CLOSED",
+            ErrorKind::QueueFull => "QUEUE_FULL",
+            ErrorKind::Cancelled => "CANCELLED",
+            ErrorKind::Backup => "BACKUP_FAILED",
+            ErrorKind::Import => "IMPORT_FAILED",
+            ErrorKind::Database => "DATABASE_ERROR",
+            ErrorKind::Index => "INDEX_ERROR",
+        }
+    }
+}
+
+impl Error {
+    /// Get the kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::DatabaseError(e) => match e.sqlite_error_code() {
+                Some(ErrorCode::DatabaseCorrupt) | Some(ErrorCode::NotADatabase) => {
+                    ErrorKind::Corrupted
+                }
+                Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) => ErrorKind::Busy,
+                Some(ErrorCode::DiskFull) => ErrorKind::DiskFull,
+                _ => ErrorKind::Database,
+            },
+            Error::IndexError(e) => match e {
+                tantivy::TantivyError::DataCorruption(_) => ErrorKind::Corrupted,
+                tantivy::TantivyError::IOError(_) => ErrorKind::Io,
+                _ => ErrorKind::Index,
+            },
+            Error::PoolError(_) | Error::DatabaseOpenError(_) | Error::SqlCipherError(_) => {
+                ErrorKind::Database
+            }
+            Error::PoolTimeout => ErrorKind::Busy,
+            Error::FsError(_) | Error::IOError(_) => ErrorKind::Io,
+            Error::DatabaseUnlockError(_) | Error::WrongPassphrase => ErrorKind::WrongPassphrase,
+            Error::DatabaseVersionError | Error::BackupVersionError(_) => {
+                ErrorKind::VersionMismatch
+            }
+            Error::ReindexError => ErrorKind::ReindexRequired,
+            Error::DatabaseLocked => ErrorKind::Locked,
+            Error::StoreSourcesMismatch => ErrorKind::ConfigMismatch,
+            Error::WriterClosedError | Error::WriterDead(_) => ErrorKind::Closed,
+            Error::QueueFull => ErrorKind::QueueFull,
+            Error::NotEncryptedError | Error::SourcesNotStored => ErrorKind::Unsupported,
+            Error::EmptySearchTerm
+            | Error::InvalidSearchToken
+            | Error::QueryTooComplex(_)
+            | Error::QuerySyntaxError(_)
+            | Error::OnlyExcludedTerms
+            | Error::LeadingWildcard
+            | Error::TooManyRestrictedIds(..) => ErrorKind::Query,
+            Error::BackupError(_) => ErrorKind::Backup,
+            Error::ImportError(_) => ErrorKind::Import,
+            Error::EventNotFound(_) | Error::IndexNotFound(_) | Error::WatermarkNotFound(_) => {
+                ErrorKind::NotFound
+            }
+            Error::EventTooLarge(..) | Error::InvalidEvent(_) => ErrorKind::InvalidEvent,
+            Error::InvalidCheckpoint(_) => ErrorKind::InvalidCheckpoint,
+            Error::SearchCancelled => ErrorKind::Cancelled,
+        }
     }
 }
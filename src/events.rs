@@ -22,7 +22,7 @@ use std::{
     sync::mpsc::Sender,
 };
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 
 #[cfg(test)]
 use fake::faker::internet::raw::*;
@@ -46,6 +46,23 @@ pub enum EventType {
     /// inside of the content.
     #[serde(alias = "m.room.topic", alias = "content.topic")]
     Topic,
+    /// Any other Matrix event type, contains the type of the event.
+    ///
+    /// The content of such events isn't indexed, the `EventBuilder` rejects
+    /// them and searches ignore them as a search key.
+    Unknown(String),
+}
+
+impl From<&str> for EventType {
+    /// Get the event type of a Matrix event type, e.g. `m.room.message`.
+    fn from(event_type: &str) -> Self {
+        match event_type {
+            "m.room.message" => EventType::Message,
+            "m.room.name" => EventType::Name,
+            "m.room.topic" => EventType::Topic,
+            t => EventType::Unknown(t.to_owned()),
+        }
+    }
 }
 
 impl Display for EventType {
@@ -54,6 +71,7 @@ impl Display for EventType {
             EventType::Message => "m.room.message",
             EventType::Topic => "m.room.topic",
             EventType::Name => "m.room.name",
+            EventType::Unknown(t) => t,
         };
 
         write!(f, "{}", string)
@@ -71,15 +89,7 @@ impl FromSql for EventType {
         match value {
             ValueRef::Text(s) => {
                 let s = std::str::from_utf8(s).map_err(|e| FromSqlError::Other(Box::new(e)))?;
-
-                let e = match s {
-                    "m.room.message" => EventType::Message,
-                    "m.room.name" => EventType::Name,
-                    "m.room.topic" => EventType::Topic,
-                    _ => return Err(FromSqlError::InvalidType),
-                };
-
-                Ok(e)
+                Ok(EventType::from(s))
             }
             _ => Err(FromSqlError::InvalidType),
         }
@@ -108,6 +118,9 @@ pub struct Event {
     /// The serialized JSON string of the event. This string will be returned
     /// by a search later on.
     pub source: String,
+    /// The event id of the thread root if this event is part of a thread.
+    #[serde(default)]
+    pub thread_id: Option<EventId>,
 }
 
 #[cfg(test)]
@@ -131,18 +144,21 @@ impl<T> Dummy<T> for Event {
     }
 }
 
+/// A batch of events from the room history, the checkpoints that should be
+/// replaced, the purge epoch at the time the batch was queued and the sender
+/// for the result.
 pub(crate) type HistoricEventsT = (
     Option<CrawlerCheckpoint>,
     Option<CrawlerCheckpoint>,
     Vec<(Event, Profile)>,
+    u64,
     Sender<Result<bool>>,
 );
 
-pub(crate) type EventContext = (
-    Vec<SerializedEvent>,
-    Vec<SerializedEvent>,
-    HashMap<MxId, Profile>,
-);
+pub(crate) type EventContext = (Vec<ContextEvent>, Vec<ContextEvent>, HashMap<MxId, Profile>);
+
+/// Message types of `m.room.message` events that carry a file attachment.
+pub const FILE_MSGTYPES: &[&str] = &["m.image", "m.file", "m.audio", "m.video"];
 
 pub(crate) type RoomId = String;
 pub(crate) type MxId = String;
@@ -184,6 +200,532 @@ impl Event {
             server_ts,
             room_id: room_id.to_string(),
             source: source.to_string(),
+            thread_id: None,
+        }
+    }
+
+    /// Create a new `m.room.message` event.
+    /// # Arguments
+    ///
+    /// * `body` - The body of the message, or the filename if the message
+    ///   carries a file.
+    /// * `msgtype` - The type of the message, e.g. `m.text`.
+    /// * `event_id` - The unique identifier of the event.
+    /// * `sender` - The unique identifier of the event author.
+    /// * `server_ts` - The timestamp of the event.
+    /// * `room_id` - The unique identifier of the room that the event belongs
+    ///   to.
+    /// * `source` - The serialized version of the event.
+    pub fn new_message(
+        body: &str,
+        msgtype: &str,
+        event_id: &str,
+        sender: &str,
+        server_ts: i64,
+        room_id: &str,
+        source: &str,
+    ) -> Event {
+        Event::new(
+            EventType::Message,
+            body,
+            Some(msgtype),
+            event_id,
+            sender,
+            server_ts,
+            room_id,
+            source,
+        )
+    }
+
+    /// Create a new `m.room.topic` event.
+    ///
+    /// The arguments are the same as the ones of `Event::new_message()`,
+    /// without the msgtype.
+    pub fn new_topic(
+        topic: &str,
+        event_id: &str,
+        sender: &str,
+        server_ts: i64,
+        room_id: &str,
+        source: &str,
+    ) -> Event {
+        Event::new(
+            EventType::Topic,
+            topic,
+            None,
+            event_id,
+            sender,
+            server_ts,
+            room_id,
+            source,
+        )
+    }
+
+    /// Create a new `m.room.name` event.
+    ///
+    /// The arguments are the same as the ones of `Event::new_message()`,
+    /// without the msgtype.
+    pub fn new_name(
+        name: &str,
+        event_id: &str,
+        sender: &str,
+        server_ts: i64,
+        room_id: &str,
+        source: &str,
+    ) -> Event {
+        Event::new(
+            EventType::Name,
+            name,
+            None,
+            event_id,
+            sender,
+            server_ts,
+            room_id,
+            source,
+        )
+    }
+
+    /// Mark this event as being part of a thread.
+    /// # Arguments
+    ///
+    /// * `thread_id` - The event id of the root of the thread.
+    pub fn with_thread(mut self, thread_id: &str) -> Event {
+        self.thread_id = Some(thread_id.to_string());
+        self
+    }
+
+    /// A minimal source of the event that doesn't contain its content.
+    ///
+    /// This is stored instead of the source if the database is configured to
+    /// not store event sources.
+    pub(crate) fn minimal_source(&self) -> SerializedEvent {
+        serde_json::json!({
+            "event_id": self.event_id,
+            "room_id": self.room_id,
+            "sender": self.sender,
+            "origin_server_ts": self.server_ts,
+        })
+        .to_string()
+    }
+
+    /// Does this event carry a file attachment, e.g. is it a `m.room.message`
+    /// with a `m.file` or `m.image` msgtype.
+    ///
+    /// The content value of such events is the filename of the attachment.
+    pub fn is_file(&self) -> bool {
+        self.event_type == EventType::Message
+            && self
+                .msgtype
+                .as_deref()
+                .map_or(false, |t| FILE_MSGTYPES.contains(&t))
+    }
+
+    /// The part of the content value that should be indexed.
+    ///
+    /// Replies contain a quote of the message they are replying to as a
+    /// fallback for clients that don't support replies, the quote isn't
+    /// indexed so searching for the text of a message doesn't return every
+    /// reply to it.
+    pub(crate) fn indexable_text(&self) -> &str {
+        if self.event_type == EventType::Message && !self.is_file() {
+            strip_reply_fallback(&self.content_value)
+        } else {
+            &self.content_value
+        }
+    }
+
+    /// Does the body of this event contain a link, e.g. is it a
+    /// `m.room.message` event that isn't a file and its body contains an
+    /// `http://` or `https://` URL.
+    pub fn contains_url(&self) -> bool {
+        self.event_type == EventType::Message
+            && !self.is_file()
+            && contains_url(self.indexable_text())
+    }
+
+    /// The size of the event in bytes, the size of its source or of its
+    /// content value if that is bigger.
+    pub(crate) fn size(&self) -> usize {
+        self.source.len().max(self.content_value.len())
+    }
+
+    /// Truncate the content value of the event to at most `max_bytes` bytes.
+    pub(crate) fn truncate_content_value(&mut self, max_bytes: usize) {
+        truncate_at_char_boundary(&mut self.content_value, max_bytes);
+    }
+
+    /// Truncate the string fields of the content in the source of the event
+    /// to at most `max_bytes` bytes.
+    ///
+    /// Sources that can't be parsed are left as they are.
+    pub(crate) fn truncate_source_content(&mut self, max_bytes: usize) {
+        if self.source.len() <= max_bytes {
+            return;
+        }
+
+        let mut source: serde_json::Value = match serde_json::from_str(&self.source) {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+
+        let mut truncated = false;
+
+        if let Some(content) = source.get_mut("content").and_then(|c| c.as_object_mut()) {
+            for value in content.values_mut() {
+                if let serde_json::Value::String(s) = value {
+                    if s.len() > max_bytes {
+                        truncate_at_char_boundary(s, max_bytes);
+                        truncated = true;
+                    }
+                }
+            }
+        }
+
+        if truncated {
+            self.source = source.to_string();
+        }
+    }
+}
+
+/// The largest timestamp an event may have, the first millisecond of the year
+/// 10000.
+const MAX_EVENT_TIMESTAMP: i64 = 253_402_300_800_000;
+
+/// A problem that was found while validating an event built by an
+/// `EventBuilder`.
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone)]
+pub enum EventValidationError {
+    /// A required field of the event wasn't set.
+    #[error("the {} of the event is missing", _0)]
+    MissingField(&'static str),
+    /// The event id doesn't have the form `$opaque_id`.
+    #[error("the event id {:?} is invalid", _0)]
+    InvalidEventId(String),
+    /// The room id doesn't have the form `!opaque_id`.
+    #[error("the room id {:?} is invalid", _0)]
+    InvalidRoomId(String),
+    /// The sender doesn't have the form `@localpart:domain`.
+    #[error("the sender {:?} is invalid", _0)]
+    InvalidSender(String),
+    /// The timestamp is negative or too far in the future.
+    #[error("the timestamp {} is invalid", _0)]
+    InvalidTimestamp(i64),
+    /// The body of a message that doesn't carry a file is empty or only
+    /// contains whitespace.
+    #[error("the body of the message is empty")]
+    EmptyBody,
+    /// The source isn't a serialized JSON object.
+    #[error("the source isn't a JSON object")]
+    InvalidSource,
+    /// The source belongs to another event.
+    #[error("the source belongs to the event {:?}", _0)]
+    SourceMismatch(Option<String>),
+    /// The event type isn't one of the types whose content is indexed.
+    #[error("the event type {} isn't supported", _0)]
+    UnsupportedType(String),
+}
+
+/// Builder for events that validates the event before creating it.
+///
+/// Unlike `Event::new()`, which trusts its arguments, `build()` checks that
+/// the identifiers of the event have the expected sigils, that the timestamp
+/// is sane and that the source is a JSON object that belongs to the event.
+///
+/// # Example
+///
+/// ```noexecute
+/// let event = EventBuilder::new(EventType::Message)
+///     .content_value("Hello world")
+///     .msgtype("m.text")
+///     .event_id("$15163622445EBvZJ:localhost")
+///     .sender("@example2:localhost")
+///     .server_ts(1516362244026)
+///     .room_id("!test_room:localhost")
+///     .source(source)
+///     .build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct EventBuilder {
+    event_type: EventType,
+    content_value: Option<String>,
+    msgtype: Option<String>,
+    event_id: Option<String>,
+    sender: Option<String>,
+    server_ts: Option<i64>,
+    room_id: Option<String>,
+    source: Option<String>,
+    thread_id: Option<EventId>,
+}
+
+impl EventBuilder {
+    /// Create a new event builder for an event of the given type.
+    pub fn new(event_type: EventType) -> Self {
+        EventBuilder {
+            event_type,
+            content_value: None,
+            msgtype: None,
+            event_id: None,
+            sender: None,
+            server_ts: None,
+            room_id: None,
+            source: None,
+            thread_id: None,
+        }
+    }
+
+    /// Set the plain text value of the content, body for a message event,
+    /// topic for a topic event and name for a name event.
+    pub fn content_value<S: Into<String>>(mut self, content_value: S) -> Self {
+        self.content_value = Some(content_value.into());
+        self
+    }
+
+    /// Set the msgtype of a `m.room.message` event.
+    pub fn msgtype<S: Into<String>>(mut self, msgtype: S) -> Self {
+        self.msgtype = Some(msgtype.into());
+        self
+    }
+
+    /// Set the unique identifier of the event.
+    pub fn event_id<S: Into<String>>(mut self, event_id: S) -> Self {
+        self.event_id = Some(event_id.into());
+        self
+    }
+
+    /// Set the MXID of the user who sent the event.
+    pub fn sender<S: Into<String>>(mut self, sender: S) -> Self {
+        self.sender = Some(sender.into());
+        self
+    }
+
+    /// Set the timestamp, in milliseconds, of the event.
+    pub fn server_ts(mut self, server_ts: i64) -> Self {
+        self.server_ts = Some(server_ts);
+        self
+    }
+
+    /// Set the unique identifier of the room that the event belongs to.
+    pub fn room_id<S: Into<String>>(mut self, room_id: S) -> Self {
+        self.room_id = Some(room_id.into());
+        self
+    }
+
+    /// Set the serialized version of the event.
+    pub fn source<S: Into<String>>(mut self, source: S) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Set the event id of the thread root if the event is part of a thread.
+    pub fn thread_id<S: Into<String>>(mut self, thread_id: S) -> Self {
+        self.thread_id = Some(thread_id.into());
+        self
+    }
+
+    /// Validate the event and create it.
+    ///
+    /// Returns an `Error::InvalidEvent` containing every problem that was
+    /// found if the event isn't valid.
+    pub fn build(self) -> Result<Event> {
+        let mut errors = Vec::new();
+
+        if let EventType::Unknown(event_type) = &self.event_type {
+            errors.push(EventValidationError::UnsupportedType(event_type.clone()));
+        }
+
+        let has_sigil = |value: &str, sigil: char| {
+            value.len() > sigil.len_utf8()
+                && value.starts_with(sigil)
+                && !value.contains(char::is_whitespace)
+        };
+
+        match &self.event_id {
+            Some(id) if !has_sigil(id, '$') => {
+                errors.push(EventValidationError::InvalidEventId(id.clone()))
+            }
+            Some(_) => (),
+            None => errors.push(EventValidationError::MissingField("event id")),
+        }
+
+        match &self.room_id {
+            Some(id) if !has_sigil(id, '!') => {
+                errors.push(EventValidationError::InvalidRoomId(id.clone()))
+            }
+            Some(_) => (),
+            None => errors.push(EventValidationError::MissingField("room id")),
+        }
+
+        match &self.sender {
+            Some(sender) if !has_sigil(sender, '@') || !sender.contains(':') => {
+                errors.push(EventValidationError::InvalidSender(sender.clone()))
+            }
+            Some(_) => (),
+            None => errors.push(EventValidationError::MissingField("sender")),
+        }
+
+        match self.server_ts {
+            Some(ts) if !(0..MAX_EVENT_TIMESTAMP).contains(&ts) => {
+                errors.push(EventValidationError::InvalidTimestamp(ts))
+            }
+            Some(_) => (),
+            None => errors.push(EventValidationError::MissingField("timestamp")),
+        }
+
+        let is_file = self.event_type == EventType::Message
+            && self
+                .msgtype
+                .as_deref()
+                .map_or(false, |t| FILE_MSGTYPES.contains(&t));
+
+        match &self.content_value {
+            // Files without a filename or a body are still stored, and topics
+            // and names can be cleared, only message bodies need some text.
+            Some(content) if content.trim().is_empty() => {
+                if self.event_type == EventType::Message && !is_file {
+                    errors.push(EventValidationError::EmptyBody)
+                }
+            }
+            Some(_) => (),
+            None => errors.push(EventValidationError::MissingField("content value")),
+        }
+
+        if self.event_type == EventType::Message && self.msgtype.is_none() {
+            errors.push(EventValidationError::MissingField("msgtype"));
+        }
+
+        match &self.source {
+            Some(source) => match serde_json::from_str::<serde_json::Value>(source) {
+                Ok(serde_json::Value::Object(source)) => {
+                    let source_id = source.get("event_id").and_then(|id| id.as_str());
+
+                    if source_id != self.event_id.as_deref() {
+                        errors.push(EventValidationError::SourceMismatch(
+                            source_id.map(|id| id.to_owned()),
+                        ));
+                    }
+                }
+                _ => errors.push(EventValidationError::InvalidSource),
+            },
+            None => errors.push(EventValidationError::MissingField("source")),
+        }
+
+        if !errors.is_empty() {
+            return Err(Error::InvalidEvent(errors));
+        }
+
+        Ok(Event {
+            event_type: self.event_type,
+            content_value: self.content_value.unwrap_or_default(),
+            msgtype: self.msgtype,
+            event_id: self.event_id.unwrap_or_default(),
+            sender: self.sender.unwrap_or_default(),
+            server_ts: self.server_ts.unwrap_or_default(),
+            room_id: self.room_id.unwrap_or_default(),
+            source: self.source.unwrap_or_default(),
+            thread_id: self.thread_id,
+        })
+    }
+}
+
+/// Truncate a string to at most `max_bytes` bytes without splitting a
+/// character.
+fn truncate_at_char_boundary(string: &mut String, max_bytes: usize) {
+    if string.len() <= max_bytes {
+        return;
+    }
+
+    let mut end = max_bytes;
+
+    while !string.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    string.truncate(end);
+}
+
+/// Strip the reply fallback from the body of a message.
+///
+/// The fallback is a block of lines that start with `> ` at the start of the
+/// body, separated from the reply by an empty line. Bodies that don't follow
+/// this format are returned unchanged.
+fn strip_reply_fallback(body: &str) -> &str {
+    let mut offset = 0;
+
+    for line in body.split_inclusive('\n') {
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+
+        if content.starts_with("> ") || content == ">" {
+            offset += line.len();
+        } else if content.is_empty() && offset > 0 {
+            return &body[offset + line.len()..];
+        } else {
+            break;
+        }
+    }
+
+    body
+}
+
+/// Does the text contain an `http://` or `https://` URL.
+///
+/// The scheme needs to start a word and needs to be followed by a host, so
+/// text that merely mentions the scheme, e.g. `xhttp://` or a lone
+/// `https://`, doesn't count as a link.
+pub(crate) fn contains_url(text: &str) -> bool {
+    let text = text.to_lowercase();
+
+    ["http://", "https://"].iter().any(|scheme| {
+        text.match_indices(scheme).any(|(start, _)| {
+            let preceded_by_word = text[..start]
+                .chars()
+                .next_back()
+                .map_or(false, |c| c.is_alphanumeric());
+            let followed_by_host = text[start + scheme.len()..]
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_alphanumeric() || c == '[');
+
+            !preceded_by_word && followed_by_host
+        })
+    })
+}
+
+/// An event that surrounds a search result or an event in its room.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ContextEvent {
+    /// The unique id of the event.
+    pub event_id: EventId,
+    /// The timestamp of the event in milliseconds since the unix epoch.
+    pub server_ts: i64,
+    /// The position of the event in the order the events were stored in,
+    /// events that share a timestamp are ordered by it. It's an opaque value
+    /// that can only be compared to the insert ids of other events of the
+    /// same database.
+    #[serde(default)]
+    pub insert_id: i64,
+    /// The serialized source of the event.
+    pub source: SerializedEvent,
+    /// Was the event redacted, the source of a redacted event is a stub that
+    /// only contains its event id and a `redacted` flag.
+    #[serde(default)]
+    pub redacted: bool,
+}
+
+impl ContextEvent {
+    /// Create the stub of a message that was redacted.
+    pub(crate) fn redacted_stub(event_id: EventId, server_ts: i64, insert_id: i64) -> ContextEvent {
+        let source = serde_json::json!({
+            "event_id": event_id,
+            "redacted": true,
+        })
+        .to_string();
+
+        ContextEvent {
+            event_id,
+            server_ts,
+            insert_id,
+            source,
+            redacted: true,
         }
     }
 }
@@ -211,6 +753,68 @@ impl Profile {
     }
 }
 
+/// A reaction to an event, an `m.reaction` event with an `m.annotation`
+/// relation.
+///
+/// Reactions aren't indexed, they only count towards the reactions of the
+/// event they relate to, see `SearchConfig::boost_by_reactions()`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    /// The unique id of the reaction event.
+    pub event_id: EventId,
+    /// The id of the event that was reacted to.
+    pub target_event_id: EventId,
+    /// The unique id of the room that the reaction was sent in.
+    pub room_id: RoomId,
+}
+
+impl Reaction {
+    /// Create a new reaction.
+    /// # Arguments
+    ///
+    /// * `event_id` - The unique id of the reaction event.
+    /// * `target_event_id` - The id of the event that was reacted to.
+    /// * `room_id` - The unique id of the room of the reaction.
+    pub fn new(event_id: &str, target_event_id: &str, room_id: &str) -> Reaction {
+        Reaction {
+            event_id: event_id.to_string(),
+            target_event_id: target_event_id.to_string(),
+            room_id: room_id.to_string(),
+        }
+    }
+}
+
+/// The canonical alias of a room, from an `m.room.canonical_alias` event.
+///
+/// Aliases aren't indexed, they are only remembered to label the search
+/// results of rooms that don't have a name, see
+/// `SearchResult::room_display_name`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct RoomAlias {
+    /// The unique id of the room.
+    pub room_id: RoomId,
+    /// The canonical alias of the room.
+    pub alias: String,
+    /// The timestamp of the event that set the alias.
+    pub server_ts: i64,
+}
+
+impl RoomAlias {
+    /// Create a new room alias.
+    /// # Arguments
+    ///
+    /// * `room_id` - The unique id of the room.
+    /// * `alias` - The canonical alias of the room.
+    /// * `server_ts` - The timestamp of the event that set the alias.
+    pub fn new(room_id: &str, alias: &str, server_ts: i64) -> RoomAlias {
+        RoomAlias {
+            room_id: room_id.to_string(),
+            alias: alias.to_string(),
+            server_ts,
+        }
+    }
+}
+
 #[cfg(test)]
 #[allow(missing_docs)]
 pub static EVENT_SOURCE: &str = r#"{
@@ -315,6 +919,86 @@ pub struct CrawlerCheckpoint {
     pub direction: CheckpointDirection,
 }
 
+/// The longest token a crawler checkpoint may have, in bytes.
+const MAX_CHECKPOINT_TOKEN_LENGTH: usize = 4096;
+
+/// A problem that was found while validating a crawler checkpoint.
+#[derive(thiserror::Error, Debug, PartialEq, Eq, Clone)]
+pub enum CheckpointValidationError {
+    /// The room id doesn't have the form `!opaque_id`.
+    #[error("the room id {:?} of the checkpoint is invalid", _0)]
+    InvalidRoomId(String),
+    /// The token is empty or only contains whitespace.
+    #[error("the token of the checkpoint is empty")]
+    EmptyToken,
+    /// The token contains whitespace after it was trimmed.
+    #[error("the token {:?} of the checkpoint contains whitespace", _0)]
+    InvalidToken(String),
+    /// The token is longer than the allowed maximum, contains the length of
+    /// the token.
+    #[error(
+        "the token of the checkpoint is {} bytes long, the maximum is {} bytes",
+        _0,
+        MAX_CHECKPOINT_TOKEN_LENGTH
+    )]
+    TokenTooLong(usize),
+}
+
+impl CrawlerCheckpoint {
+    /// Create a new checkpoint for a complete crawl of the history of a room.
+    ///
+    /// Surrounding whitespace is removed from the room id and the token, so
+    /// tokens that only differ by whitespace produce the same checkpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room, it needs to have the form
+    ///   `!opaque_id`.
+    /// * `token` - The token that continues the crawl, it needs to be
+    ///   non-empty and can't contain whitespace.
+    ///
+    /// Returns an `Error::InvalidCheckpoint` if the room id or the token
+    /// isn't valid.
+    pub fn new(room_id: &str, token: &str) -> Result<Self> {
+        CrawlerCheckpoint {
+            room_id: room_id.to_owned(),
+            token: token.to_owned(),
+            full_crawl: false,
+            direction: CheckpointDirection::Backwards,
+        }
+        .canonicalize()
+    }
+
+    /// Validate the checkpoint and remove the surrounding whitespace of its
+    /// room id and token.
+    pub(crate) fn canonicalize(mut self) -> Result<Self> {
+        self.room_id = self.room_id.trim().to_owned();
+        self.token = self.token.trim().to_owned();
+
+        let error = if self.room_id.len() <= 1
+            || !self.room_id.starts_with('!')
+            || self.room_id.contains(char::is_whitespace)
+        {
+            Some(CheckpointValidationError::InvalidRoomId(
+                self.room_id.clone(),
+            ))
+        } else if self.token.is_empty() {
+            Some(CheckpointValidationError::EmptyToken)
+        } else if self.token.len() > MAX_CHECKPOINT_TOKEN_LENGTH {
+            Some(CheckpointValidationError::TokenTooLong(self.token.len()))
+        } else if self.token.contains(char::is_whitespace) {
+            Some(CheckpointValidationError::InvalidToken(self.token.clone()))
+        } else {
+            None
+        };
+
+        match error {
+            Some(e) => Err(Error::InvalidCheckpoint(e)),
+            None => Ok(self),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[allow(missing_docs)]
 pub enum CheckpointDirection {
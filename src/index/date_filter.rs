@@ -0,0 +1,108 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tantivy as tv;
+use tantivy::{
+    collector::{Collector, SegmentCollector},
+    fastfield::FastFieldReader,
+    schema::Field,
+    DocId, Score, SegmentLocalId, SegmentReader,
+};
+
+/// A collector that only hands the documents that were sent at or after a
+/// point in time to the wrapped collector.
+///
+/// The date field of the index is only a fast field, it can't be used in a
+/// range query, so the documents are filtered while they are collected.
+pub(crate) struct DateFilterCollector<C> {
+    inner: C,
+    date_field: Field,
+    min_date: Option<u64>,
+}
+
+impl<C> DateFilterCollector<C> {
+    /// Create a new date filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The collector that should receive the documents.
+    /// * `date_field` - The fast field containing the timestamp of a document
+    ///   in milliseconds.
+    /// * `min_date` - The timestamp of the oldest document that should be
+    ///   collected, every document is collected if this is `None`.
+    pub(crate) fn new(inner: C, date_field: Field, min_date: Option<u64>) -> Self {
+        DateFilterCollector {
+            inner,
+            date_field,
+            min_date,
+        }
+    }
+}
+
+pub(crate) struct DateFilterSegmentCollector<C> {
+    inner: C,
+    filter: Option<(FastFieldReader<u64>, u64)>,
+}
+
+impl<C: Collector> Collector for DateFilterCollector<C> {
+    type Fruit = C::Fruit;
+    type Child = DateFilterSegmentCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentLocalId,
+        segment: &SegmentReader,
+    ) -> tv::Result<Self::Child> {
+        let filter = match self.min_date {
+            Some(min_date) => {
+                let dates = segment.fast_fields().u64(self.date_field).ok_or_else(|| {
+                    tv::TantivyError::SchemaError("The date field isn't a fast field".to_owned())
+                })?;
+                Some((dates, min_date))
+            }
+            None => None,
+        };
+
+        Ok(DateFilterSegmentCollector {
+            inner: self.inner.for_segment(segment_local_id, segment)?,
+            filter,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Self::Fruit>) -> tv::Result<Self::Fruit> {
+        self.inner.merge_fruits(segment_fruits)
+    }
+}
+
+impl<C: SegmentCollector> SegmentCollector for DateFilterSegmentCollector<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if let Some((dates, min_date)) = &self.filter {
+            if dates.get(doc) < *min_date {
+                return;
+            }
+        }
+
+        self.inner.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.inner.harvest()
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tantivy as tv;
+use tantivy::{
+    collector::{Collector, SegmentCollector},
+    DocId, Score, SegmentLocalId, SegmentReader,
+};
+
+/// How many documents a segment collector collects between two checks of the
+/// deadline, reading the clock for every document would slow down the search.
+const DOCS_PER_CHECK: usize = 1024;
+
+/// The point in time a search needs to finish by.
+///
+/// Clones share the information whether the deadline was hit.
+#[derive(Clone, Debug)]
+pub(crate) struct Deadline {
+    at: Instant,
+    hit: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    /// Create a deadline that passes once the given time runs out.
+    pub(crate) fn after(timeout: Duration) -> Self {
+        Deadline {
+            at: Instant::now() + timeout,
+            hit: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Check if the deadline passed, the deadline is marked as hit if it did.
+    ///
+    /// Callers should stop whatever they are doing once this returns true.
+    pub(crate) fn check(&self) -> bool {
+        if self.was_hit() {
+            return true;
+        }
+
+        if Instant::now() >= self.at {
+            self.hit.store(true, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Was the deadline found to be passed by a check, i.e. was some work
+    /// cut short because of it.
+    pub(crate) fn was_hit(&self) -> bool {
+        self.hit.load(Ordering::Relaxed)
+    }
+}
+
+/// A collector that stops handing documents to the wrapped collector once
+/// the deadline passes.
+///
+/// Tantivy doesn't allow a collector to stop a search, the remaining
+/// documents still get scored but are dropped right away.
+pub(crate) struct DeadlineCollector<C> {
+    inner: C,
+    deadline: Option<Deadline>,
+}
+
+impl<C> DeadlineCollector<C> {
+    pub(crate) fn new(inner: C, deadline: Option<&Deadline>) -> Self {
+        DeadlineCollector {
+            inner,
+            deadline: deadline.cloned(),
+        }
+    }
+}
+
+pub(crate) struct DeadlineSegmentCollector<C> {
+    inner: C,
+    deadline: Option<Deadline>,
+    collected: usize,
+    passed: bool,
+}
+
+impl<C: Collector> Collector for DeadlineCollector<C> {
+    type Fruit = C::Fruit;
+    type Child = DeadlineSegmentCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentLocalId,
+        segment: &SegmentReader,
+    ) -> tv::Result<Self::Child> {
+        Ok(DeadlineSegmentCollector {
+            inner: self.inner.for_segment(segment_local_id, segment)?,
+            deadline: self.deadline.clone(),
+            collected: 0,
+            passed: self.deadline.as_ref().map_or(false, Deadline::check),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Self::Fruit>) -> tv::Result<Self::Fruit> {
+        self.inner.merge_fruits(segment_fruits)
+    }
+}
+
+impl<C: SegmentCollector> SegmentCollector for DeadlineSegmentCollector<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if self.passed {
+            return;
+        }
+
+        if self.collected % DOCS_PER_CHECK == 0 {
+            if let Some(deadline) = &self.deadline {
+                if deadline.check() {
+                    self.passed = true;
+                    return;
+                }
+            }
+        }
+
+        self.collected += 1;
+        self.inner.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.inner.harvest()
+    }
+}
@@ -60,7 +60,7 @@ type KeyDerivationResult = (KeyBuffer, KeyBuffer);
 // The constants here are chosen to be similar to the constants for the Matrix
 // key export format[1].
 // [1] https://matrix.org/docs/spec/client_server/r0.5.0#key-exports
-const KEYFILE: &str = "seshat-index.key";
+pub(crate) const KEYFILE: &str = "seshat-index.key";
 // 16 byte random salt.
 const SALT_SIZE: usize = 16;
 // 16 byte random IV for the AES-CTR mode.
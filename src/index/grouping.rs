@@ -0,0 +1,237 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp::Ordering, collections::HashMap, str};
+
+use tantivy as tv;
+use tantivy::{
+    collector::{Collector, SegmentCollector},
+    fastfield::FastFieldReader,
+    schema::{Field, IndexRecordOption},
+    DocAddress, DocId, DocSet, Score, SegmentLocalId, SegmentReader, SkipResult,
+};
+
+use crate::events::{EventId, RoomId};
+
+/// The search results of a single room.
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+pub struct RoomGroup {
+    /// The number of events of the room that matched the search.
+    pub count: usize,
+    /// The event ids of the best matching events of the room, ordered the same
+    /// way as the search results.
+    pub top_event_ids: Vec<EventId>,
+}
+
+/// The matching documents of a room, before the event ids are loaded.
+#[derive(Default)]
+pub(crate) struct DocGroup {
+    pub(crate) count: usize,
+    /// The best matching documents together with their sort key.
+    pub(crate) top_docs: Vec<(f64, DocAddress)>,
+}
+
+pub(crate) type DocGroups = HashMap<RoomId, DocGroup>;
+
+/// A collector that groups all the documents matching a query by their room.
+///
+/// The rooms of the matching documents are found by walking the postings of
+/// the room id terms, so only the stored documents of the best matches need
+/// to be loaded.
+pub(crate) struct RoomGroupCollector {
+    room_id_field: Field,
+    date_field: Field,
+    order_by_recency: bool,
+    limit: usize,
+}
+
+impl RoomGroupCollector {
+    /// Create a new room group collector.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id_field` - The field containing the room id of a document.
+    /// * `date_field` - The field containing the timestamp of a document.
+    /// * `order_by_recency` - Should the best matches of a room be the newest
+    ///   ones instead of the highest scoring ones.
+    /// * `limit` - How many of the best matches should be kept for every room.
+    pub(crate) fn new(
+        room_id_field: Field,
+        date_field: Field,
+        order_by_recency: bool,
+        limit: usize,
+    ) -> Self {
+        RoomGroupCollector {
+            room_id_field,
+            date_field,
+            order_by_recency,
+            limit,
+        }
+    }
+}
+
+pub(crate) struct RoomGroupSegmentCollector {
+    segment_ord: SegmentLocalId,
+    reader: SegmentReader,
+    room_id_field: Field,
+    dates: Option<FastFieldReader<u64>>,
+    limit: usize,
+    docs: Vec<(DocId, Score)>,
+}
+
+/// Sort the documents so the best ones come first and only keep the limit.
+fn truncate_top_docs(top_docs: &mut Vec<(f64, DocAddress)>, limit: usize) {
+    top_docs.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    top_docs.truncate(limit);
+}
+
+/// Order the groups of rooms by their best document, the room with the best
+/// one comes first.
+///
+/// The documents of every group need to be sorted already, rooms whose best
+/// documents are equally good are ordered by their room id.
+pub(crate) fn order_groups(groups: DocGroups) -> Vec<(RoomId, DocGroup)> {
+    let best = |group: &DocGroup| group.top_docs.first().map_or(f64::MIN, |(key, _)| *key);
+
+    let mut groups: Vec<(RoomId, DocGroup)> = groups.into_iter().collect();
+    groups.sort_by(|(a_id, a), (b_id, b)| {
+        best(b)
+            .partial_cmp(&best(a))
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a_id.cmp(b_id))
+    });
+
+    groups
+}
+
+impl Collector for RoomGroupCollector {
+    type Fruit = DocGroups;
+    type Child = RoomGroupSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_ord: SegmentLocalId,
+        reader: &SegmentReader,
+    ) -> tv::Result<Self::Child> {
+        let dates = if self.order_by_recency {
+            let dates = reader.fast_fields().u64(self.date_field).ok_or_else(|| {
+                tv::TantivyError::SchemaError("The date field isn't a fast field".to_owned())
+            })?;
+            Some(dates)
+        } else {
+            None
+        };
+
+        Ok(RoomGroupSegmentCollector {
+            segment_ord,
+            reader: reader.clone(),
+            room_id_field: self.room_id_field,
+            dates,
+            limit: self.limit,
+            docs: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        !self.order_by_recency
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<DocGroups>) -> tv::Result<DocGroups> {
+        let mut groups = DocGroups::new();
+
+        for (room_id, group) in segment_fruits.into_iter().flatten() {
+            let merged = groups.entry(room_id).or_default();
+            merged.count += group.count;
+            merged.top_docs.extend(group.top_docs);
+        }
+
+        for group in groups.values_mut() {
+            truncate_top_docs(&mut group.top_docs, self.limit);
+        }
+
+        Ok(groups)
+    }
+}
+
+impl SegmentCollector for RoomGroupSegmentCollector {
+    type Fruit = DocGroups;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        self.docs.push((doc, score));
+    }
+
+    fn harvest(self) -> DocGroups {
+        let mut groups = DocGroups::new();
+
+        if self.docs.is_empty() {
+            return groups;
+        }
+
+        let inverted_index = self.reader.inverted_index(self.room_id_field);
+        let mut terms = inverted_index.terms().stream();
+
+        while terms.advance() {
+            let room_id = match str::from_utf8(terms.key()) {
+                Ok(r) => r.to_owned(),
+                Err(_) => continue,
+            };
+
+            let mut postings =
+                inverted_index.read_postings_from_terminfo(terms.value(), IndexRecordOption::Basic);
+
+            // The matching documents are collected in order, walk them
+            // together with the postings of the room. Skipping always
+            // advances the postings, so remember where they stopped.
+            let mut current = None;
+            let mut group = DocGroup::default();
+
+            for &(doc, score) in &self.docs {
+                let reached = match current {
+                    Some(c) if c > doc => false,
+                    Some(c) if c == doc => true,
+                    _ => match postings.skip_next(doc) {
+                        SkipResult::Reached => {
+                            current = Some(doc);
+                            true
+                        }
+                        SkipResult::OverStep => {
+                            current = Some(postings.doc());
+                            false
+                        }
+                        SkipResult::End => break,
+                    },
+                };
+
+                if reached {
+                    let key = match &self.dates {
+                        Some(dates) => dates.get(doc) as f64,
+                        None => f64::from(score),
+                    };
+
+                    group.count += 1;
+                    group
+                        .top_docs
+                        .push((key, DocAddress(self.segment_ord, doc)));
+                }
+            }
+
+            if group.count > 0 {
+                truncate_top_docs(&mut group.top_docs, self.limit);
+                groups.insert(room_id, group);
+            }
+        }
+
+        groups
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tantivy::tokenizer::{BoxTokenStream, Token, TokenFilter, TokenStream};
+
+/// A token filter that splits CamelCase identifiers into the words they
+/// consist of, e.g. `IndexSearcher` into `Index` and `Searcher`.
+///
+/// The identifier itself is kept, it's followed by its words. The first word
+/// shares the position of the identifier and every other word takes the next
+/// position, the tokens after the identifier move back accordingly. Queries
+/// go through the same filter, so phrases containing an identifier still
+/// match.
+///
+/// snake_case and kebab-case identifiers don't need to be split here, the
+/// tokenizer already splits words at underscores and dashes. The filter needs
+/// to come before the lower caser, otherwise the words can't be told apart.
+#[derive(Clone)]
+pub(crate) struct IdentifierSplitter;
+
+impl TokenFilter for IdentifierSplitter {
+    fn transform<'a>(&self, token_stream: BoxTokenStream<'a>) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(IdentifierSplitterTokenStream {
+            tail: token_stream,
+            token: Token::default(),
+            words: Vec::new(),
+            shift: 0,
+        })
+    }
+}
+
+pub(crate) struct IdentifierSplitterTokenStream<'a> {
+    tail: BoxTokenStream<'a>,
+    token: Token,
+    /// The words of the current identifier that weren't returned yet, in
+    /// reverse order.
+    words: Vec<Token>,
+    /// How many positions the tokens of the tail move back because of the
+    /// words of earlier identifiers.
+    shift: usize,
+}
+
+impl<'a> TokenStream for IdentifierSplitterTokenStream<'a> {
+    fn advance(&mut self) -> bool {
+        if let Some(word) = self.words.pop() {
+            self.token = word;
+            return true;
+        }
+
+        if !self.tail.advance() {
+            return false;
+        }
+
+        self.token = self.tail.token().clone();
+        self.token.position += self.shift;
+
+        let boundaries = word_boundaries(&self.token.text);
+
+        if boundaries.len() > 1 {
+            self.shift += boundaries.len() - 1;
+
+            let identifier = &self.token;
+            self.words = boundaries
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, (start, end))| Token {
+                    offset_from: identifier.offset_from + start,
+                    offset_to: identifier.offset_from + end,
+                    position: identifier.position + i,
+                    text: identifier.text[*start..*end].to_owned(),
+                    ..identifier.clone()
+                })
+                .collect();
+        }
+
+        true
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+/// Find the words of a CamelCase identifier.
+///
+/// A word starts with an upper case letter that follows a lower case letter
+/// or a digit, or with the last upper case letter of an acronym, e.g.
+/// `HTTPServer` consists of `HTTP` and `Server`.
+///
+/// Returns the byte ranges of the words, text that isn't an identifier is a
+/// single word.
+fn word_boundaries(text: &str) -> Vec<(usize, usize)> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut words = Vec::new();
+    let mut start = 0;
+
+    for (i, (offset, c)) in chars.iter().enumerate().skip(1) {
+        let previous = chars[i - 1].1;
+        let next_is_lowercase = chars.get(i + 1).map_or(false, |(_, n)| n.is_lowercase());
+
+        if c.is_uppercase()
+            && (previous.is_lowercase()
+                || previous.is_numeric()
+                || (previous.is_uppercase() && next_is_lowercase))
+        {
+            words.push((start, *offset));
+            start = *offset;
+        }
+    }
+
+    words.push((start, text.len()));
+    words
+}
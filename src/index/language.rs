@@ -0,0 +1,72 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tantivy::schema::Field;
+use whatlang::Detector;
+
+use crate::config::Language;
+
+/// The confidence the detected language of a text needs to have before the
+/// text is indexed in the field of that language. Short messages are often
+/// detected with a low confidence, those are indexed in the default body
+/// field.
+const MIN_LANGUAGE_CONFIDENCE: f64 = 0.5;
+
+/// Detects the language of message bodies to pick the field they should be
+/// indexed in.
+///
+/// Every detected language has its own body field, which is analyzed using
+/// the stemmer of the language.
+pub(crate) struct LanguageDetector {
+    detector: Detector,
+    fields: Vec<(whatlang::Lang, Field)>,
+}
+
+impl LanguageDetector {
+    /// Create a new language detector.
+    ///
+    /// # Arguments
+    ///
+    /// * `fields` - The languages that should be detected and the body field
+    ///   of every language.
+    pub(crate) fn new(fields: &[(Language, Field)]) -> Self {
+        let fields: Vec<(whatlang::Lang, Field)> = fields
+            .iter()
+            .filter_map(|(language, field)| language.as_whatlang().map(|l| (l, *field)))
+            .collect();
+
+        LanguageDetector {
+            detector: Detector::with_allowlist(fields.iter().map(|(l, _)| *l).collect()),
+            fields,
+        }
+    }
+
+    /// Get the body field that the given text should be indexed in.
+    ///
+    /// Returns `None` if the language of the text couldn't be detected with
+    /// enough confidence, the text should be indexed in the default body
+    /// field then.
+    pub(crate) fn field_for(&self, text: &str) -> Option<Field> {
+        let info = self.detector.detect(text)?;
+
+        if info.confidence() < MIN_LANGUAGE_CONFIDENCE {
+            return None;
+        }
+
+        self.fields
+            .iter()
+            .find(|(language, _)| *language == info.lang())
+            .map(|(_, field)| *field)
+    }
+}
@@ -12,32 +12,62 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod date_filter;
+mod deadline;
 #[cfg(feature = "encryption")]
 mod encrypted_dir;
 #[cfg(feature = "encryption")]
 mod encrypted_stream;
+mod grouping;
+mod identifiers;
+mod language;
+mod recency;
 
 use std::{
-    path::Path,
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use futures::executor::block_on;
 use lru_cache::LruCache;
 use tantivy as tv;
 use tantivy::{
-    collector::{Count, MultiCollector, TopDocs},
-    Term,
+    collector::{Collector, Count, MultiCollector, TopDocs},
+    query::{BooleanQuery, Occur, TermQuery},
+    schema::IndexRecordOption,
+    DocSet, SkipResult, Term,
 };
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
 use uuid::Uuid;
 
+pub(crate) use crate::index::deadline::Deadline;
 #[cfg(feature = "encryption")]
-use crate::index::encrypted_dir::{EncryptedMmapDirectory, PBKDF_COUNT};
+use crate::index::encrypted_dir::{EncryptedMmapDirectory, KEYFILE, PBKDF_COUNT};
+pub use crate::index::grouping::RoomGroup;
 use crate::{
-    config::{Config, Language, SearchConfig},
-    events::{Event, EventId, EventType},
+    config::{Config, Language, QueryLimits, QueryOperator, SearchConfig, SearchOrder},
+    events::{Event, EventId, EventType, RoomId},
+    index::{
+        date_filter::DateFilterCollector,
+        deadline::DeadlineCollector,
+        grouping::{order_groups, RoomGroupCollector},
+        identifiers::IdentifierSplitter,
+        language::LanguageDetector,
+        recency::RecencyBoost,
+    },
+    Error,
 };
 
+/// The version of the index schema.
+///
+/// This corresponds to the database version that last required the index to
+/// be rebuilt and needs to be bumped together with the database version
+/// whenever the schema changes.
+pub(crate) const INDEX_VERSION: i64 = 10;
+
 // Tantivy requires at least 3MB per writer thread and will panic if we
 // give it less than 3MB for the total writer heap size. The amount of writer
 // threads that Tantivy will spawn depends on the amount of heap we give it.
@@ -46,9 +76,10 @@ use crate::{
 //     num_threads = { num_cpu,                  if heap_size / num_cpu >= 3MB
 //                   { max(heap_size / 3MB, 1),  if heap_size / num_cpu <  3MB
 //
-// We give Tantivy 50MB of heap size, which would spawn up to 16 writer threads
-// given a CPU with 16 or more cores.
-const TANTIVY_WRITER_HEAP_SIZE: usize = 50_000_000;
+// We give Tantivy 50MB of heap size by default, the writer only ever uses a
+// single thread so a smaller heap is fine as long as it stays above 3MB.
+pub(crate) const TANTIVY_WRITER_HEAP_SIZE: usize = 50_000_000;
+pub(crate) const TANTIVY_MIN_WRITER_HEAP_SIZE: usize = 3_000_000;
 
 // Tantivy doesn't behave nicely if `commit()` is called too often on the index
 // writer. A commit means that Tantivy will spawn threads that will try to merge
@@ -72,12 +103,35 @@ const COMMIT_RATE: usize = 500;
 /// committed.
 const COMMIT_TIME: Duration = Duration::from_secs(5);
 
-/// How many searches should be cached so pagination is supported.
+/// The bits of the index into the list of query parsers, every query parser
+/// searches a different set of fields. Query parsers with the `AND_KEY` bit
+/// set require all the words of a query to match.
+const BODY_KEY: usize = 1;
+const TOPIC_KEY: usize = 1 << 1;
+const NAME_KEY: usize = 1 << 2;
+const ROOM_KEY: usize = 1 << 3;
+const SENDER_KEY: usize = 1 << 4;
+const AND_KEY: usize = 1 << 5;
+
+/// How many searches should be cached so pagination is supported, this also
+/// bounds the number of index snapshots that paginated searches keep alive.
 const SEARCH_CACHE_SIZE: usize = 100;
 /// How much should the result limit increase every time we need to find more
 /// results due to a paginated search.
 const SEARCH_LIMIT_INCREMENT: usize = 50;
 
+/// The minimal number of distinct terms an event needs to contain before we
+/// look for similar events, fewer terms mostly produce unrelated matches.
+const MIN_SIMILAR_TERMS: usize = 3;
+
+/// The number of the most distinctive terms of an event that are used to find
+/// similar events.
+const MAX_SIMILAR_TERMS: usize = 25;
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use std::time::{SystemTime, UNIX_EPOCH};
 #[cfg(test)]
 use tempfile::TempDir;
 
@@ -92,9 +146,76 @@ pub(crate) struct Index {
     name_field: tv::schema::Field,
     event_id_field: tv::schema::Field,
     sender_field: tv::schema::Field,
+    sender_id_field: tv::schema::Field,
     date_field: tv::schema::Field,
     room_id_field: tv::schema::Field,
+    thread_id_field: tv::schema::Field,
+    contains_url_field: tv::schema::Field,
+    /// The body fields of the detected languages, empty if language
+    /// detection is disabled.
+    language_fields: Arc<Vec<tv::schema::Field>>,
+    language_detector: Option<Arc<LanguageDetector>>,
     search_cache: Arc<RwLock<LruCache<Uuid, Search>>>,
+    search_token_ttl: Duration,
+    query_limits: QueryLimits,
+    default_operator: QueryOperator,
+    leading_wildcards: bool,
+    searcher_cache: Arc<RwLock<SearcherCache>>,
+    query_parsers: Arc<Vec<tv::query::QueryParser>>,
+    /// Keeps the callback that invalidates the searcher cache on commits
+    /// registered, there is none if the index isn't reloaded automatically.
+    _watch_handle: Option<tv::directory::WatchHandle>,
+    auto_reload: bool,
+    writer_heap_size: usize,
+    /// Make commits of the writers of this index fail, used to test error
+    /// handling.
+    #[cfg(test)]
+    pub(crate) fail_commits: Arc<AtomicBool>,
+}
+
+/// A searcher that is shared between searches until the index gets reloaded.
+///
+/// Acquiring a searcher for every search is costly and two searches that
+/// happen in quick succession could end up using different generations of the
+/// index.
+#[derive(Default)]
+struct SearcherCache {
+    /// Incremented every time the cached searcher is invalidated, a searcher
+    /// that was acquired before an invalidation won't be cached.
+    generation: usize,
+    searcher: Option<Arc<tv::LeasedItem<tv::Searcher>>>,
+}
+
+impl SearcherCache {
+    fn get(
+        cache: &RwLock<SearcherCache>,
+        reader: &tv::IndexReader,
+    ) -> Arc<tv::LeasedItem<tv::Searcher>> {
+        let generation = {
+            let cache = cache.read().unwrap();
+
+            if let Some(searcher) = &cache.searcher {
+                return searcher.clone();
+            }
+
+            cache.generation
+        };
+
+        let searcher = Arc::new(reader.searcher());
+        let mut cache = cache.write().unwrap();
+
+        if cache.generation == generation && cache.searcher.is_none() {
+            cache.searcher = Some(searcher.clone());
+        }
+
+        searcher
+    }
+
+    fn invalidate(cache: &RwLock<SearcherCache>) {
+        let mut cache = cache.write().unwrap();
+        cache.generation += 1;
+        cache.searcher = None;
+    }
 }
 
 #[derive(Clone)]
@@ -102,26 +223,79 @@ struct Search {
     search_term: Arc<String>,
     search_config: Arc<SearchConfig>,
     event_ids: Arc<Vec<String>>,
+    /// The snapshot of the index the first page was served from, later pages
+    /// use it as well so commits don't shift the results between pages.
+    searcher: Arc<tv::LeasedItem<tv::Searcher>>,
+    /// When the token of this search was issued.
+    created: Instant,
+}
+
+/// The parts of a query that a document matched.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Matches {
+    /// The search terms that are contained in the document.
+    pub(crate) highlights: Vec<String>,
+    /// The event fields that contained a search term, e.g. `content.body`.
+    pub(crate) fields: Vec<String>,
 }
 
 #[derive(Debug)]
 pub(crate) struct SearchResult {
     pub(crate) count: usize,
     pub(crate) results: Vec<(f32, EventId)>,
+    /// The parts of the query that matched, for every event in the results.
+    pub(crate) matches: HashMap<EventId, Matches>,
+    /// The matching events grouped by room, if grouping was requested.
+    pub(crate) groups: Option<HashMap<RoomId, RoomGroup>>,
+    /// The rooms of the matching events ordered by their best match, if the
+    /// results were ordered by room.
+    pub(crate) room_order: Vec<RoomId>,
     pub(crate) next_batch: Option<Uuid>,
+    /// Did the deadline of the search pass before all the matches were
+    /// collected.
+    pub(crate) timed_out: bool,
+}
+
+type SearchHelperResult = ((usize, Vec<(f32, EventId, Matches)>), Vec<EventId>);
+
+/// Normalize text to the NFC form.
+///
+/// Input methods may produce decomposed characters, e.g. "café" as `cafe` and a
+/// combining accent. Both the indexed text and the search terms are normalized
+/// so they match regardless of the form they were typed in.
+fn normalize(text: &str) -> Cow<'_, str> {
+    match is_nfc_quick(text.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(text),
+        _ => Cow::Owned(text.nfc().collect()),
+    }
 }
 
 pub(crate) struct Writer {
     inner: tv::IndexWriter,
+    index: tv::Index,
     body_field: tv::schema::Field,
     topic_field: tv::schema::Field,
     name_field: tv::schema::Field,
     event_id_field: tv::schema::Field,
     sender_field: tv::schema::Field,
+    sender_id_field: tv::schema::Field,
     date_field: tv::schema::Field,
     added_events: usize,
     commit_timestamp: std::time::Instant,
     room_id_field: tv::schema::Field,
+    thread_id_field: tv::schema::Field,
+    contains_url_field: tv::schema::Field,
+    /// Picks the body field of messages if language detection is enabled.
+    language_detector: Option<Arc<LanguageDetector>>,
+    /// Reloads the index after every commit if the index is reloaded
+    /// automatically.
+    reloader: Option<Reloader>,
+    /// The payload that is stored together with every commit.
+    commit_payload: Option<String>,
+    /// The opstamp of the last commit of the index.
+    committed_opstamp: u64,
+    #[cfg(test)]
+    fail_commits: Arc<AtomicBool>,
 }
 
 impl Writer {
@@ -135,7 +309,27 @@ impl Writer {
                 || self.added_events >= COMMIT_RATE
                 || self.commit_timestamp.elapsed() >= COMMIT_TIME)
         {
-            self.inner.commit()?;
+            #[cfg(test)]
+            {
+                if self.fail_commits.load(Ordering::SeqCst) {
+                    return Err(tv::TantivyError::ErrorInThread(
+                        "Injected commit failure".to_owned(),
+                    ));
+                }
+            }
+
+            #[cfg(feature = "tracing")]
+            let started = Instant::now();
+
+            self.commit_and_reload()?;
+
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                docs = self.added_events,
+                duration_ms = started.elapsed().as_millis() as u64,
+                "Committed the index"
+            );
+
             self.added_events = 0;
             self.commit_timestamp = std::time::Instant::now();
             Ok(true)
@@ -144,34 +338,202 @@ impl Writer {
         }
     }
 
+    /// Commit the added documents and deletions, searches see the commit once
+    /// this returns if the index is reloaded automatically.
+    fn commit_and_reload(&mut self) -> Result<(), tv::TantivyError> {
+        let mut commit = self.inner.prepare_commit()?;
+
+        if let Some(payload) = &self.commit_payload {
+            commit.set_payload(payload);
+        }
+
+        self.committed_opstamp = commit.commit()?;
+
+        if let Some(reloader) = &self.reloader {
+            reloader.reload_index()?;
+        }
+
+        Ok(())
+    }
+
+    /// Discard all the documents and deletions that were added to the writer
+    /// since the last commit.
+    pub fn rollback(&mut self) -> Result<(), tv::TantivyError> {
+        self.inner.rollback()?;
+        self.added_events = 0;
+        Ok(())
+    }
+
     pub fn force_commit(&mut self) -> Result<(), tv::TantivyError> {
         self.commit_helper(true)?;
         Ok(())
     }
 
+    /// Are there added events that aren't committed yet.
+    pub fn has_uncommitted_events(&self) -> bool {
+        self.added_events > 0
+    }
+
+    /// Set the payload that should be stored together with the next commits,
+    /// `Index::last_commit()` returns the payload of the last commit.
+    pub fn set_commit_payload(&mut self, payload: &str) {
+        self.commit_payload = Some(payload.to_owned());
+    }
+
+    /// The opstamp of the last commit of the index, opstamps increase with
+    /// every commit.
+    pub fn committed_opstamp(&self) -> u64 {
+        self.committed_opstamp
+    }
+
+    /// Get the ids of the events that are part of the last commit of the
+    /// index, in no particular order.
+    pub fn committed_event_ids(&self) -> Result<Vec<EventId>, tv::TantivyError> {
+        let reader: tv::IndexReader = self
+            .index
+            .reader_builder()
+            .reload_policy(tv::ReloadPolicy::Manual)
+            .try_into()?;
+        let searcher = reader.searcher();
+        let mut event_ids = BTreeSet::new();
+
+        // The terms of deleted documents stay in the term dictionary until
+        // their segment is merged, only terms with a live document count.
+        for segment in searcher.segment_readers() {
+            let inverted_index = segment.inverted_index(self.event_id_field);
+            let mut terms = inverted_index.terms().stream();
+
+            while terms.advance() {
+                let event_id = match std::str::from_utf8(terms.key()) {
+                    Ok(e) => e,
+                    Err(_) => continue,
+                };
+                let term = Term::from_field_text(self.event_id_field, event_id);
+
+                if let Some(mut postings) =
+                    inverted_index.read_postings(&term, IndexRecordOption::Basic)
+                {
+                    while postings.advance() {
+                        if !segment.is_deleted(postings.doc()) {
+                            event_ids.insert(event_id.to_owned());
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(event_ids.into_iter().collect())
+    }
+
     pub fn add_event(&mut self, event: &Event) {
+        let content_value = normalize(event.indexable_text());
+
+        // Events without any text, e.g. files that were sent without a
+        // filename, are kept in the store but there's nothing to index.
+        if content_value.trim().is_empty() {
+            return;
+        }
+
         let mut doc = tv::Document::default();
 
+        // Filenames are indexed in the body field, the tokenizer splits words
+        // on non-alphanumeric characters so a filename like
+        // `quarterly_report-final.pdf` is split on the `_`, `-` and `.`
+        // characters and can be found searching for "quarterly report".
         match event.event_type {
-            EventType::Message => doc.add_text(self.body_field, &event.content_value),
-            EventType::Topic => doc.add_text(self.topic_field, &event.content_value),
-            EventType::Name => doc.add_text(self.name_field, &event.content_value),
+            EventType::Message => {
+                let field = self
+                    .language_detector
+                    .as_ref()
+                    .and_then(|d| d.field_for(&content_value))
+                    .unwrap_or(self.body_field);
+                doc.add_text(field, &content_value)
+            }
+            EventType::Topic => doc.add_text(self.topic_field, &content_value),
+            EventType::Name => doc.add_text(self.name_field, &content_value),
+            // The content of unknown events isn't searchable, the event is
+            // still added so it can be found by its sender or room.
+            EventType::Unknown(_) => (),
         }
 
         doc.add_text(self.event_id_field, &event.event_id);
         doc.add_text(self.room_id_field, &event.room_id);
         doc.add_text(self.sender_field, &event.sender);
-        doc.add_u64(self.date_field, event.server_ts as u64);
+        doc.add_text(self.sender_id_field, &event.sender);
+        // Bridged or imported events sometimes carry bogus negative
+        // timestamps, casting them would turn them into the newest events of
+        // the index.
+        doc.add_u64(self.date_field, event.server_ts.max(0) as u64);
+
+        if let Some(thread_id) = &event.thread_id {
+            doc.add_text(self.thread_id_field, thread_id);
+        }
+
+        if event.contains_url() {
+            doc.add_u64(self.contains_url_field, 1);
+        }
 
         self.inner.add_document(doc);
         self.added_events += 1;
     }
 
     /// Delete the event with the given event id from the index.
-    pub fn delete_event(&mut self, event_id: &str) {
+    pub fn delete_event(&mut self, event_id: &str) -> Result<(), tv::TantivyError> {
+        let term = Term::from_field_text(self.event_id_field, event_id);
+        self.inner.delete_term(term);
+        self.commit_and_reload()?;
+        Ok(())
+    }
+
+    /// Delete the event with the given event id from the index with the next
+    /// commit.
+    ///
+    /// Unlike `delete_event()` this doesn't commit the index, a rollback
+    /// discards the deletion together with the added events. The deletion
+    /// counts as an uncommitted change so the next commit doesn't skip it.
+    pub fn delete_event_on_commit(&mut self, event_id: &str) {
         let term = Term::from_field_text(self.event_id_field, event_id);
         self.inner.delete_term(term);
-        self.inner.commit().unwrap();
+        self.added_events += 1;
+    }
+
+    /// Delete the events with the given event ids from the index.
+    ///
+    /// The deletions are committed together.
+    pub fn delete_events(&mut self, event_ids: &[EventId]) -> Result<(), tv::TantivyError> {
+        for event_id in event_ids {
+            let term = Term::from_field_text(self.event_id_field, event_id);
+            self.inner.delete_term(term);
+        }
+
+        self.commit_and_reload()?;
+        Ok(())
+    }
+
+    /// Merge the segments that contain deleted documents and remove the index
+    /// files that aren't used anymore.
+    ///
+    /// Deleted documents only stop taking up space once the segment that
+    /// contains them is merged.
+    ///
+    /// Returns the number of segments that were merged.
+    pub fn compact(&mut self) -> Result<usize, tv::TantivyError> {
+        let segment_ids: Vec<tv::SegmentId> = self
+            .index
+            .searchable_segment_metas()?
+            .iter()
+            .filter(|segment| segment.has_deletes())
+            .map(|segment| segment.id())
+            .collect();
+
+        if !segment_ids.is_empty() {
+            block_on(self.inner.merge(&segment_ids))?;
+        }
+
+        block_on(self.inner.garbage_collect_files())?;
+
+        Ok(segment_ids.len())
     }
 
     pub fn wait_merging_threads(self) -> Result<(), tv::TantivyError> {
@@ -179,91 +541,715 @@ impl Writer {
     }
 }
 
+/// A handle that can be used to reload the index from another thread.
+#[derive(Clone)]
+pub struct Reloader {
+    inner: tv::IndexReader,
+    searcher_cache: Arc<RwLock<SearcherCache>>,
+}
+
+impl Reloader {
+    /// Reload the index so that a search reflects the state of the last
+    /// commit.
+    ///
+    /// This may block while the segments of a large commit are being loaded.
+    pub fn reload(&self) -> crate::Result<()> {
+        Ok(self.reload_index()?)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    fn reload_index(&self) -> Result<(), tv::TantivyError> {
+        self.inner.reload()?;
+        SearcherCache::invalidate(&self.searcher_cache);
+        Ok(())
+    }
+}
+
+/// A word of a search term that contains a wildcard.
+struct Wildcard {
+    /// The lowercased word without the wildcards.
+    word: String,
+    /// Does the wildcard precede the word.
+    leading: bool,
+    /// Does the wildcard follow the word.
+    trailing: bool,
+    /// Should events containing matching terms be excluded.
+    excluded: bool,
+}
+
+impl Wildcard {
+    /// Check if a term of the index matches the wildcard.
+    fn matches(&self, term: &str) -> bool {
+        match (self.leading, self.trailing) {
+            (true, true) => term.contains(&self.word),
+            (true, false) => term.ends_with(&self.word),
+            (false, true) => term.starts_with(&self.word),
+            (false, false) => term == self.word,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct IndexSearcher {
-    inner: tv::LeasedItem<tv::Searcher>,
-    schema: tv::schema::Schema,
-    tokenizer: tv::tokenizer::TokenizerManager,
+    inner: Arc<tv::LeasedItem<tv::Searcher>>,
+    query_parsers: Arc<Vec<tv::query::QueryParser>>,
     body_field: tv::schema::Field,
     topic_field: tv::schema::Field,
     name_field: tv::schema::Field,
     room_id_field: tv::schema::Field,
-    #[allow(dead_code)]
+    thread_id_field: tv::schema::Field,
+    contains_url_field: tv::schema::Field,
     sender_field: tv::schema::Field,
+    sender_id_field: tv::schema::Field,
     date_field: tv::schema::Field,
     event_id_field: tv::schema::Field,
+    language_fields: Arc<Vec<tv::schema::Field>>,
     search_cache: Arc<RwLock<LruCache<Uuid, Search>>>,
+    search_token_ttl: Duration,
+    query_limits: QueryLimits,
+    default_operator: QueryOperator,
+    leading_wildcards: bool,
 }
 
 impl IndexSearcher {
+    /// Load the parts of the index a search needs, the term dictionaries of
+    /// the searched fields and the dates of the events.
+    ///
+    /// The index files are memory mapped, the first search that touches them
+    /// otherwise waits for them to be read from disk.
+    ///
+    /// Returns the number of documents that were touched.
+    pub(crate) fn preload(&self) -> tv::Result<u64> {
+        let fields: Vec<tv::schema::Field> = [
+            self.body_field,
+            self.topic_field,
+            self.name_field,
+            self.room_id_field,
+        ]
+        .iter()
+        .chain(self.language_fields.iter())
+        .copied()
+        .collect();
+
+        let mut touched = 0;
+
+        for segment_reader in self.inner.segment_readers() {
+            for field in &fields {
+                let inverted_index = segment_reader.inverted_index(*field);
+                let mut terms = inverted_index.terms().stream();
+                while terms.advance() {}
+            }
+
+            let dates = segment_reader
+                .fast_fields()
+                .u64(self.date_field)
+                .ok_or_else(|| {
+                    tv::TantivyError::SchemaError("The date field isn't a fast field".to_owned())
+                })?;
+
+            for doc in 0..segment_reader.max_doc() {
+                if dates.get(doc) > 0 {
+                    touched += 1;
+                }
+            }
+        }
+
+        Ok(touched)
+    }
+
     fn parse_query(
         &self,
         term: &str,
         config: &SearchConfig,
-    ) -> Result<Box<dyn tv::query::Query>, tv::TantivyError> {
-        let mut keys = Vec::new();
+    ) -> crate::Result<Box<dyn tv::query::Query>> {
+        let mut keys = 0;
 
-        let term = if let Some(room) = &config.room_id {
-            keys.push(self.room_id_field);
+        if !config.advanced_syntax && IndexSearcher::only_excludes(term) {
+            return Err(Error::OnlyExcludedTerms);
+        }
+
+        // Words containing a wildcard are expanded to the matching terms of
+        // the index, the parser only gets the remaining words.
+        let (term, wildcards) = if config.advanced_syntax {
+            (term.to_owned(), Vec::new())
+        } else {
+            IndexSearcher::split_wildcards(term, self.leading_wildcards)?
+        };
+
+        // Advanced queries, queries with wildcards and empty queries are
+        // handed to the parser without the room, the room filter is added to
+        // the query below instead.
+        let filter_room = config.advanced_syntax || !wildcards.is_empty() || term.is_empty();
+
+        let term = if filter_room && term.is_empty() && wildcards.is_empty() {
+            "*".to_owned()
+        } else if filter_room {
+            term
+        } else if let Some(room) = &config.room_id {
+            keys |= ROOM_KEY;
             format!("+room_id:\"{}\" AND ({})", room, term)
         } else if term.is_empty() {
             "*".to_owned()
         } else {
-            term.to_owned()
+            term
         };
 
+        if config.search_senders {
+            keys |= SENDER_KEY;
+        }
+
         if config.keys.is_empty() {
-            keys.append(&mut vec![
-                self.body_field,
-                self.topic_field,
-                self.name_field,
-            ]);
+            keys |= BODY_KEY | TOPIC_KEY | NAME_KEY;
         } else {
             for key in config.keys.iter() {
                 match key {
-                    EventType::Message => keys.push(self.body_field),
-                    EventType::Topic => keys.push(self.topic_field),
-                    EventType::Name => keys.push(self.name_field),
+                    EventType::Message => keys |= BODY_KEY,
+                    EventType::Topic => keys |= TOPIC_KEY,
+                    EventType::Name => keys |= NAME_KEY,
+                    EventType::Unknown(_) => (),
+                }
+            }
+        }
+
+        let operator = config.default_operator.unwrap_or(self.default_operator);
+
+        if operator == QueryOperator::And {
+            keys |= AND_KEY;
+        }
+
+        let query = if wildcards.is_empty() {
+            self.parse_words(&term, keys, config)?
+        } else {
+            let occur = match operator {
+                QueryOperator::And => Occur::Must,
+                QueryOperator::Or => Occur::Should,
+            };
+            let mut clauses = Vec::new();
+
+            if IndexSearcher::only_excludes(&term) {
+                // The parser rejects queries that only exclude words, the
+                // words are excluded from the wildcard matches instead.
+                let words: Vec<&str> = term
+                    .split_whitespace()
+                    .map(|w| w.trim_start_matches('-'))
+                    .collect();
+                let query = self.parse_words(&words.join(" "), keys & !AND_KEY, config)?;
+                clauses.push((Occur::MustNot, query));
+            } else if !term.is_empty() {
+                clauses.push((occur, self.parse_words(&term, keys, config)?));
+            }
+
+            for wildcard in &wildcards {
+                let occur = if wildcard.excluded {
+                    Occur::MustNot
+                } else {
+                    occur
+                };
+                clauses.push((occur, self.expand_wildcard(wildcard, keys)));
+            }
+
+            Box::new(BooleanQuery::from(clauses))
+        };
+
+        let mut filters = Vec::new();
+
+        if filter_room {
+            if let Some(room) = &config.room_id {
+                filters.push(Term::from_field_text(self.room_id_field, room));
+            }
+        }
+
+        if let Some(thread_id) = &config.thread_id {
+            filters.push(Term::from_field_text(self.thread_id_field, thread_id));
+        }
+
+        let mut clauses: Vec<(Occur, Box<dyn tv::query::Query>)> = filters
+            .into_iter()
+            .map(|t| {
+                (
+                    Occur::Must,
+                    Box::new(TermQuery::new(t, IndexRecordOption::Basic))
+                        as Box<dyn tv::query::Query>,
+                )
+            })
+            .collect();
+
+        if let Some(event_ids) = &config.restrict_to_events {
+            clauses.push((Occur::Must, self.event_id_filter(event_ids)?));
+        }
+
+        clauses.extend(self.sender_exclusions(config));
+        clauses.extend(self.url_filter(config));
+
+        if clauses.is_empty() {
+            Ok(query)
+        } else {
+            clauses.insert(0, (Occur::Must, query));
+            Ok(Box::new(BooleanQuery::from(clauses)))
+        }
+    }
+
+    /// Build the clauses that exclude the events of the senders the search
+    /// config excludes.
+    fn sender_exclusions(&self, config: &SearchConfig) -> Vec<(Occur, Box<dyn tv::query::Query>)> {
+        config
+            .not_senders
+            .iter()
+            .map(|sender| {
+                (
+                    Occur::MustNot,
+                    Box::new(TermQuery::new(
+                        Term::from_field_text(self.sender_id_field, sender),
+                        IndexRecordOption::Basic,
+                    )) as Box<dyn tv::query::Query>,
+                )
+            })
+            .collect()
+    }
+
+    /// Build the clause that keeps or drops the events that contain a link,
+    /// depending on the `contains_url` setting of the search config.
+    fn url_filter(&self, config: &SearchConfig) -> Option<(Occur, Box<dyn tv::query::Query>)> {
+        let occur = if config.contains_url? {
+            Occur::Must
+        } else {
+            Occur::MustNot
+        };
+
+        Some((
+            occur,
+            Box::new(TermQuery::new(
+                Term::from_field_u64(self.contains_url_field, 1),
+                IndexRecordOption::Basic,
+            )),
+        ))
+    }
+
+    /// Build a query that only matches the events with the given ids.
+    ///
+    /// Returns a `TooManyRestrictedIds` error if there are more ids than the
+    /// configured limit allows. An empty list of ids doesn't match any event.
+    fn event_id_filter(&self, event_ids: &[EventId]) -> crate::Result<Box<dyn tv::query::Query>> {
+        let event_ids: BTreeSet<&EventId> = event_ids.iter().collect();
+        let limit = self.query_limits.max_restricted_ids;
+
+        if event_ids.len() > limit {
+            return Err(Error::TooManyRestrictedIds(event_ids.len(), limit));
+        }
+
+        let clauses: Vec<(Occur, Box<dyn tv::query::Query>)> = event_ids
+            .into_iter()
+            .map(|id| {
+                (
+                    Occur::Should,
+                    Box::new(TermQuery::new(
+                        Term::from_field_text(self.event_id_field, id),
+                        IndexRecordOption::Basic,
+                    )) as Box<dyn tv::query::Query>,
+                )
+            })
+            .collect();
+
+        Ok(Box::new(BooleanQuery::from(clauses)))
+    }
+
+    /// Parse the words of a search term using the query parser for the given
+    /// keys and check the parsed query against the query limits.
+    fn parse_words(
+        &self,
+        term: &str,
+        keys: usize,
+        config: &SearchConfig,
+    ) -> crate::Result<Box<dyn tv::query::Query>> {
+        let query =
+            IndexSearcher::parse_term(&self.query_parsers[keys], term, config.advanced_syntax)?;
+
+        IndexSearcher::check_query_limits(&*query, &self.query_limits, |field| {
+            !self.is_filter_field(field) && !self.language_fields.contains(&field)
+        })?;
+
+        Ok(query)
+    }
+
+    /// Parse a search term using the given query parser.
+    ///
+    /// Syntax errors of terms using the advanced syntax are returned as a
+    /// `QuerySyntaxError`, the position of an unclosed quote or parenthesis
+    /// is added to the message if the term contains one.
+    fn parse_term(
+        parser: &tv::query::QueryParser,
+        term: &str,
+        advanced_syntax: bool,
+    ) -> crate::Result<Box<dyn tv::query::Query>> {
+        if advanced_syntax {
+            parser.parse_query(term).map_err(|e| {
+                let message = match IndexSearcher::unbalanced_position(term) {
+                    Some(position) => format!("{} at position {}", e, position),
+                    None => e.to_string(),
+                };
+                Error::QuerySyntaxError(message)
+            })
+        } else {
+            Ok(parser.parse_query(term).map_err(tv::TantivyError::from)?)
+        }
+    }
+
+    /// Find the character position of the first quote or parenthesis of a
+    /// term that is never closed, or of a closing parenthesis that was never
+    /// opened.
+    fn unbalanced_position(term: &str) -> Option<usize> {
+        let mut quote = None;
+        let mut parentheses = Vec::new();
+
+        for (position, c) in term.chars().enumerate() {
+            match c {
+                '"' if quote.is_some() => quote = None,
+                '"' => quote = Some(position),
+                _ if quote.is_some() => (),
+                '(' => parentheses.push(position),
+                ')' => {
+                    if parentheses.pop().is_none() {
+                        return Some(position);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        quote.or_else(|| parentheses.first().copied())
+    }
+
+    /// Split the words of a search term that contain a wildcard off.
+    ///
+    /// Only words that are a single alphanumeric word with a wildcard at its
+    /// start, its end or both are treated as wildcards, all other words are
+    /// returned as they are. A wildcard without a word, or a wildcard at the
+    /// start of a word if those aren't enabled, is rejected with a
+    /// `LeadingWildcard` error.
+    fn split_wildcards(
+        term: &str,
+        leading_wildcards: bool,
+    ) -> crate::Result<(String, Vec<Wildcard>)> {
+        let mut words = Vec::new();
+        let mut wildcards = Vec::new();
+
+        for word in term.split_whitespace() {
+            let (excluded, pattern) = match word.strip_prefix('-') {
+                Some(p) if !p.is_empty() => (true, p),
+                _ => (false, word),
+            };
+
+            if !pattern.contains('*') || pattern.contains(|c: char| ":\"()[]{}^".contains(c)) {
+                words.push(word);
+                continue;
+            }
+
+            let core = pattern
+                .trim_matches('*')
+                .trim_matches(|c: char| !c.is_alphanumeric());
+
+            if core.is_empty() {
+                return Err(Error::LeadingWildcard);
+            } else if !core.chars().all(char::is_alphanumeric) {
+                words.push(word);
+                continue;
+            }
+
+            let leading = pattern.starts_with('*');
+
+            if leading && !leading_wildcards {
+                return Err(Error::LeadingWildcard);
+            }
+
+            wildcards.push(Wildcard {
+                word: core.to_lowercase(),
+                leading,
+                trailing: pattern.ends_with('*'),
+                excluded,
+            });
+        }
+
+        if wildcards.is_empty() {
+            Ok((term.to_owned(), wildcards))
+        } else {
+            Ok((words.join(" "), wildcards))
+        }
+    }
+
+    /// Expand a wildcard to a query for the terms of the searched fields that
+    /// it matches.
+    ///
+    /// At most `max_wildcard_terms` terms are searched for, words with a
+    /// leading wildcard need to go through all the terms of the fields.
+    fn expand_wildcard(&self, wildcard: &Wildcard, keys: usize) -> Box<dyn tv::query::Query> {
+        let limit = self.query_limits.max_wildcard_terms;
+        let prefix = if wildcard.leading {
+            ""
+        } else {
+            wildcard.word.as_str()
+        };
+
+        let fields: Vec<tv::schema::Field> = [
+            (BODY_KEY, self.body_field),
+            (TOPIC_KEY, self.topic_field),
+            (NAME_KEY, self.name_field),
+            (SENDER_KEY, self.sender_field),
+        ]
+        .iter()
+        .copied()
+        .chain(self.language_fields.iter().map(|f| (BODY_KEY, *f)))
+        .filter(|(key, _)| keys & *key != 0)
+        .map(|(_, field)| field)
+        .collect();
+
+        let mut terms = BTreeSet::new();
+
+        'fields: for field in fields {
+            for segment_reader in self.inner.segment_readers() {
+                let inverted_index = segment_reader.inverted_index(field);
+                let mut stream = inverted_index.terms().range().ge(prefix).into_stream();
+
+                while stream.advance() {
+                    let key = stream.key();
+
+                    if !key.starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+
+                    match std::str::from_utf8(key) {
+                        Ok(text) if wildcard.matches(text) => {
+                            terms.insert(Term::from_field_text(field, text));
+
+                            if terms.len() >= limit {
+                                break 'fields;
+                            }
+                        }
+                        _ => (),
+                    }
                 }
             }
         }
 
-        let query_parser =
-            tv::query::QueryParser::new(self.schema.clone(), keys, self.tokenizer.clone());
+        Box::new(BooleanQuery::from(
+            terms
+                .into_iter()
+                .map(|t| {
+                    (
+                        Occur::Should,
+                        Box::new(TermQuery::new(t, IndexRecordOption::WithFreqs))
+                            as Box<dyn tv::query::Query>,
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    /// Check if all the words of a search term are excluded.
+    ///
+    /// Words are excluded using a leading minus, a minus inside of a word,
+    /// e.g. "e-mail", doesn't exclude it.
+    fn only_excludes(term: &str) -> bool {
+        let mut words = term.split_whitespace().filter(|w| *w != "-").peekable();
+
+        words.peek().is_some() && words.all(|w| w.starts_with('-'))
+    }
+
+    /// Is the field only used to filter the results of a search, terms of
+    /// those fields aren't words of the search term.
+    fn is_filter_field(&self, field: tv::schema::Field) -> bool {
+        field == self.room_id_field
+            || field == self.thread_id_field
+            || field == self.event_id_field
+            || field == self.contains_url_field
+    }
+
+    /// Check that a search term isn't longer than the configured limit.
+    ///
+    /// This is checked before the term is parsed, since parsing a huge term
+    /// is already costly.
+    fn check_term_length(term: &str, limits: &QueryLimits) -> crate::Result<()> {
+        let length = term.chars().count();
+
+        if length > limits.max_length {
+            Err(Error::QueryTooComplex(format!(
+                "{} characters, at most {} are allowed",
+                length, limits.max_length
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that a parsed query doesn't contain more words or clauses than
+    /// the configured limits allow.
+    ///
+    /// Only the terms of the fields for which `counted` returns true count
+    /// against the limits. Every word is searched in the body field of every
+    /// detected language as well, those clauses don't count, and neither do
+    /// the filters of a search.
+    fn check_query_limits(
+        query: &dyn tv::query::Query,
+        limits: &QueryLimits,
+        counted: impl Fn(tv::schema::Field) -> bool,
+    ) -> crate::Result<()> {
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut terms);
+
+        let clauses: Vec<Term> = terms.into_iter().filter(|t| counted(t.field())).collect();
+        let words: BTreeSet<&str> = clauses.iter().map(|t| t.text()).collect();
+
+        if words.len() > limits.max_terms {
+            Err(Error::QueryTooComplex(format!(
+                "{} words, at most {} are allowed",
+                words.len(),
+                limits.max_terms
+            )))
+        } else if clauses.len() > limits.max_clauses {
+            Err(Error::QueryTooComplex(format!(
+                "{} clauses, at most {} are allowed",
+                clauses.len(),
+                limits.max_clauses
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get the terms of a query that should be highlighted in the search
+    /// results.
+    ///
+    /// Returns a list of tuples containing the term and the highlight for it,
+    /// the highlight keeps the casing of the search term where possible.
+    fn highlight_terms(&self, term: &str, query: &dyn tv::query::Query) -> Vec<(Term, String)> {
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut terms);
+
+        let words: Vec<&str> = term
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .collect();
+
+        terms
+            .into_iter()
+            .filter(|t| !self.is_filter_field(t.field()))
+            .map(|t| {
+                let highlight = words
+                    .iter()
+                    .find(|w| w.to_lowercase() == t.text())
+                    .map_or_else(|| t.text().to_owned(), |w| (*w).to_owned());
+                (t, highlight)
+            })
+            .collect()
+    }
+
+    /// Get the name of the event field that is indexed in the given field.
+    fn event_field_name(&self, field: tv::schema::Field) -> Option<&'static str> {
+        if field == self.body_field || self.language_fields.contains(&field) {
+            Some("content.body")
+        } else if field == self.topic_field {
+            Some("content.topic")
+        } else if field == self.name_field {
+            Some("content.name")
+        } else if field == self.sender_field {
+            Some("sender")
+        } else {
+            None
+        }
+    }
+
+    /// Get the highlights of the terms that are contained in a document and
+    /// the fields that contained them.
+    fn matched_terms(&self, terms: &[(Term, String)], doc: tv::DocAddress) -> Matches {
+        let segment_reader = self.inner.segment_reader(doc.segment_ord());
+        let mut matches = Matches::default();
+
+        for (term, highlight) in terms {
+            let field_name = self.event_field_name(term.field());
+
+            if matches.highlights.contains(highlight)
+                && field_name.map_or(true, |f| matches.fields.iter().any(|m| m == f))
+            {
+                continue;
+            }
+
+            let postings = segment_reader
+                .inverted_index(term.field())
+                .read_postings(term, tv::schema::IndexRecordOption::Basic);
+
+            if let Some(mut postings) = postings {
+                if postings.skip_next(doc.doc()) == SkipResult::Reached {
+                    if !matches.highlights.contains(highlight) {
+                        matches.highlights.push(highlight.clone());
+                    }
+
+                    if let Some(field_name) = field_name {
+                        if !matches.fields.iter().any(|m| m == field_name) {
+                            matches.fields.push(field_name.to_owned());
+                        }
+                    }
+                }
+            }
+        }
 
-        Ok(query_parser.parse_query(&term)?)
+        matches
     }
 
-    #[allow(clippy::type_complexity)]
+    #[allow(clippy::too_many_arguments)]
     fn search_helper(
         &self,
         og_limit: usize,
         limit: usize,
-        order_by_recency: bool,
+        order: SearchOrder,
         previous_results: &[EventId],
         query: &dyn tv::query::Query,
-    ) -> Result<((usize, Vec<(f32, EventId)>), Vec<EventId>), tv::TantivyError> {
+        terms: &[(Term, String)],
+        min_date: Option<u64>,
+        deadline: Option<&Deadline>,
+    ) -> Result<SearchHelperResult, tv::TantivyError> {
         let mut multicollector = MultiCollector::new();
         let count_handle = multicollector.add_collector(Count);
 
-        let (mut result, top_docs) = if order_by_recency {
-            let top_docs_handle = multicollector
-                .add_collector(TopDocs::with_limit(limit).order_by_u64_field(self.date_field));
-
-            let mut result = self.inner.search(query, &multicollector)?;
-            let mut top_docs = top_docs_handle.extract(&mut result);
-            (
-                result,
-                top_docs
-                    .drain(..)
-                    .map(|(_, address)| (1.0, address))
-                    .collect(),
-            )
-        } else {
-            let top_docs_handle = multicollector.add_collector(TopDocs::with_limit(limit));
-            let mut result = self.inner.search(query, &multicollector)?;
+        let (mut result, top_docs) = match order {
+            SearchOrder::Recency => {
+                let top_docs_handle = multicollector
+                    .add_collector(TopDocs::with_limit(limit).order_by_u64_field(self.date_field));
+
+                let collector = self.filter_collector(multicollector, min_date, deadline);
+                let mut result = self.inner.search(query, &collector)?;
+                let mut top_docs = top_docs_handle.extract(&mut result);
+                (
+                    result,
+                    top_docs
+                        .drain(..)
+                        .map(|(_, address)| (1.0, address))
+                        .collect(),
+                )
+            }
+            SearchOrder::RecencyBoosted { half_life } => {
+                let top_docs_handle = multicollector.add_collector(
+                    TopDocs::with_limit(limit)
+                        .tweak_score(RecencyBoost::new(self.date_field, half_life)),
+                );
+
+                let collector = self.filter_collector(multicollector, min_date, deadline);
+                let mut result = self.inner.search(query, &collector)?;
+                let mut top_docs = top_docs_handle.extract(&mut result);
+                (
+                    result,
+                    top_docs
+                        .drain(..)
+                        .map(|(score, address)| (score as f32, address))
+                        .collect(),
+                )
+            }
+            SearchOrder::Rank => {
+                let top_docs_handle = multicollector.add_collector(TopDocs::with_limit(limit));
+                let collector = self.filter_collector(multicollector, min_date, deadline);
+                let mut result = self.inner.search(query, &collector)?;
 
-            let top_docs = top_docs_handle.extract(&mut result);
-            (result, top_docs)
+                let top_docs = top_docs_handle.extract(&mut result);
+                (result, top_docs)
+            }
         };
 
         let mut docs = Vec::new();
@@ -290,23 +1276,30 @@ impl IndexSearcher {
             }
 
             event_ids.push(event_id.clone());
-            docs.push((score, event_id));
+            docs.push((score, event_id, self.matched_terms(terms, docaddress)));
 
             if docs.len() >= og_limit {
                 break;
             }
         }
 
+        // A search that ran out of time returns what it got so far instead of
+        // searching again for more results.
+        let timed_out = deadline.map_or(false, Deadline::was_hit);
+
         if docs.len() < og_limit {
-            if end {
+            if end || timed_out {
                 Ok(((count, docs), event_ids))
             } else {
                 self.search_helper(
                     og_limit,
                     limit + SEARCH_LIMIT_INCREMENT,
-                    order_by_recency,
+                    order,
                     previous_results,
                     query,
+                    terms,
+                    min_date,
+                    deadline,
                 )
             }
         } else {
@@ -314,63 +1307,446 @@ impl IndexSearcher {
         }
     }
 
-    pub fn search(
+    /// Search the index and order the results so that the matches of a room
+    /// follow each other.
+    ///
+    /// The rooms are ordered by their best match, the matches of a room keep
+    /// the order of the search. Returns the results together with the order
+    /// of all the rooms that matched.
+    fn search_by_room(
         &self,
-        term: &str,
-        config: &SearchConfig,
-    ) -> Result<SearchResult, tv::TantivyError> {
-        let past_search = if let Some(token) = &config.next_batch {
-            let mut search_cache = self.search_cache.write().unwrap();
-            search_cache.get_mut(token).cloned()
-        } else {
-            None
-        };
+        limit: usize,
+        order_by_recency: bool,
+        previous_results: &[EventId],
+        query: &dyn tv::query::Query,
+        terms: &[(Term, String)],
+        min_date: Option<u64>,
+        deadline: Option<&Deadline>,
+    ) -> Result<(SearchHelperResult, Vec<RoomId>), tv::TantivyError> {
+        // No room can contribute more events to the results up to the end
+        // of this batch than the batch and the previous batches contain.
+        let collector = RoomGroupCollector::new(
+            self.room_id_field,
+            self.date_field,
+            order_by_recency,
+            previous_results.len() + limit,
+        );
+        let collector = self.filter_collector(collector, min_date, deadline);
+
+        let groups = order_groups(self.inner.search(query, &collector)?);
+        let count = groups.iter().map(|(_, group)| group.count).sum();
+        let room_order = groups.iter().map(|(room_id, _)| room_id.clone()).collect();
 
-        let ((result, event_ids), term, config) = if let Some(past_search) = past_search {
-            let query = self.parse_query(term, &past_search.search_config)?;
-            let previous_results = &past_search.event_ids;
+        let mut docs = Vec::new();
+        let mut event_ids = Vec::new();
 
-            let (result, mut event_ids) = self.search_helper(
-                config.limit,
-                config.limit,
-                config.order_by_recency,
-                previous_results,
-                &query,
-            )?;
+        'rooms: for (_, group) in groups {
+            for (key, docaddress) in group.top_docs {
+                let doc = match self.inner.doc(docaddress) {
+                    Ok(d) => d,
+                    Err(_e) => continue,
+                };
 
-            // Add the previous results to the current ones.
-            event_ids.extend(previous_results.iter().cloned());
+                let event_id: EventId = match doc.get_first(self.event_id_field) {
+                    Some(s) => s.text().unwrap().to_owned(),
+                    None => continue,
+                };
 
-            (
-                (result, event_ids),
-                past_search.search_term.clone(),
-                past_search.search_config.clone(),
+                if previous_results.contains(&event_id) {
+                    continue;
+                }
+
+                // Results that are ordered by recency don't have a score.
+                let score = if order_by_recency { 1.0 } else { key as f32 };
+
+                event_ids.push(event_id.clone());
+                docs.push((score, event_id, self.matched_terms(terms, docaddress)));
+
+                if docs.len() >= limit {
+                    break 'rooms;
+                }
+            }
+        }
+
+        Ok((((count, docs), event_ids), room_order))
+    }
+
+    /// Wrap a collector so that it only collects the documents that were sent
+    /// at or after the given date, and only until the deadline passes.
+    fn filter_collector<C: Collector>(
+        &self,
+        collector: C,
+        min_date: Option<u64>,
+        deadline: Option<&Deadline>,
+    ) -> DateFilterCollector<DeadlineCollector<C>> {
+        DateFilterCollector::new(
+            DeadlineCollector::new(collector, deadline),
+            self.date_field,
+            min_date,
+        )
+    }
+
+    /// Collect the results of a search, continuing after the given previous
+    /// results.
+    #[allow(clippy::too_many_arguments)]
+    fn collect_results(
+        &self,
+        limit: usize,
+        order: SearchOrder,
+        group_rooms: bool,
+        previous_results: &[EventId],
+        query: &dyn tv::query::Query,
+        terms: &[(Term, String)],
+        min_date: Option<u64>,
+        deadline: Option<&Deadline>,
+    ) -> Result<(SearchHelperResult, Vec<RoomId>), tv::TantivyError> {
+        if group_rooms {
+            self.search_by_room(
+                limit,
+                order == SearchOrder::Recency,
+                previous_results,
+                query,
+                terms,
+                min_date,
+                deadline,
             )
         } else {
-            let query = self.parse_query(term, config)?;
+            let result = self.search_helper(
+                limit,
+                limit,
+                order,
+                previous_results,
+                query,
+                terms,
+                min_date,
+                deadline,
+            )?;
+
+            Ok((result, Vec::new()))
+        }
+    }
+
+    /// Group all the documents matching a query by their room.
+    ///
+    /// The counts cover every match, not only the ones that are part of the
+    /// current batch of results.
+    fn group_by_room(
+        &self,
+        query: &dyn tv::query::Query,
+        config: &SearchConfig,
+    ) -> Result<HashMap<RoomId, RoomGroup>, tv::TantivyError> {
+        let collector = RoomGroupCollector::new(
+            self.room_id_field,
+            self.date_field,
+            config.is_ordered_by_recency(),
+            config.limit,
+        );
+        let collector = DateFilterCollector::new(collector, self.date_field, config.min_date());
+
+        let doc_groups = self.inner.search(query, &collector)?;
+        let mut groups = HashMap::with_capacity(doc_groups.len());
+
+        for (room_id, doc_group) in doc_groups {
+            let mut top_event_ids = Vec::with_capacity(doc_group.top_docs.len());
+
+            for (_, docaddress) in doc_group.top_docs {
+                let doc = self.inner.doc(docaddress)?;
+
+                if let Some(event_id) = doc.get_first(self.event_id_field).and_then(|e| e.text()) {
+                    top_event_ids.push(event_id.to_owned());
+                }
+            }
+
+            groups.insert(
+                room_id,
+                RoomGroup {
+                    count: doc_group.count,
+                    top_event_ids,
+                },
+            );
+        }
+
+        Ok(groups)
+    }
+
+    /// Find the documents that are similar to the given event.
+    ///
+    /// The terms of the event are weighted by their frequency in the event and
+    /// their rarity in the index, the most distinctive ones are used to build
+    /// a query that excludes the event itself.
+    pub fn find_similar(
+        &self,
+        event: &Event,
+        config: &SearchConfig,
+    ) -> Result<SearchResult, tv::TantivyError> {
+        let field = match event.event_type {
+            EventType::Message | EventType::Unknown(_) => self.body_field,
+            EventType::Topic => self.topic_field,
+            EventType::Name => self.name_field,
+        };
+
+        let tokenizer = self.inner.index().tokenizer_for_field(field)?;
+        let content = normalize(&event.content_value);
+        let mut stream = tokenizer.token_stream(&content);
+        let mut frequencies: HashMap<String, usize> = HashMap::new();
+
+        while stream.advance() {
+            *frequencies.entry(stream.token().text.clone()).or_default() += 1;
+        }
+
+        if frequencies.len() < MIN_SIMILAR_TERMS {
+            return Ok(SearchResult {
+                count: 0,
+                results: Vec::new(),
+                matches: HashMap::new(),
+                groups: None,
+                room_order: Vec::new(),
+                next_batch: None,
+                timed_out: false,
+            });
+        }
+
+        let num_docs = self.inner.num_docs() as f64;
+
+        let mut weighted_terms: Vec<(f64, String)> = frequencies
+            .into_iter()
+            .map(|(text, frequency)| {
+                let doc_freq = self.inner.doc_freq(&Term::from_field_text(field, &text)) as f64;
+                let idf = (1.0 + num_docs / (doc_freq + 1.0)).ln();
+                (frequency as f64 * idf, text)
+            })
+            .collect();
+
+        weighted_terms.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        weighted_terms.truncate(MAX_SIMILAR_TERMS);
+
+        let mut terms = Vec::new();
+        let mut term_queries: Vec<(Occur, Box<dyn tv::query::Query>)> = Vec::new();
+
+        let fields: Vec<tv::schema::Field> = [self.body_field, self.topic_field, self.name_field]
+            .iter()
+            .chain(self.language_fields.iter())
+            .copied()
+            .collect();
+
+        for (_, text) in weighted_terms {
+            for field in &fields {
+                let term = Term::from_field_text(*field, &text);
+                term_queries.push((
+                    Occur::Should,
+                    Box::new(TermQuery::new(term.clone(), IndexRecordOption::WithFreqs)),
+                ));
+                terms.push((term, text.clone()));
+            }
+        }
+
+        let mut subqueries: Vec<(Occur, Box<dyn tv::query::Query>)> = vec![
+            (Occur::Must, Box::new(BooleanQuery::from(term_queries))),
             (
-                self.search_helper(
-                    config.limit,
-                    config.limit,
-                    config.order_by_recency,
+                Occur::MustNot,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.event_id_field, &event.event_id),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+        ];
+
+        if let Some(room_id) = &config.room_id {
+            subqueries.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.room_id_field, room_id),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+
+        if let Some(thread_id) = &config.thread_id {
+            subqueries.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.thread_id_field, thread_id),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+
+        subqueries.extend(self.sender_exclusions(config));
+        subqueries.extend(self.url_filter(config));
+
+        let query = BooleanQuery::from(subqueries);
+        let top_docs = self
+            .inner
+            .search(&query, &TopDocs::with_limit(config.limit))?;
+
+        let mut results = Vec::with_capacity(top_docs.len());
+        let mut matches = HashMap::new();
+
+        for (score, docaddress) in top_docs {
+            let doc = self.inner.doc(docaddress)?;
+
+            let event_id: EventId = match doc.get_first(self.event_id_field) {
+                Some(s) => s.text().unwrap().to_owned(),
+                None => continue,
+            };
+
+            matches.insert(event_id.clone(), self.matched_terms(&terms, docaddress));
+            results.push((score, event_id));
+        }
+
+        Ok(SearchResult {
+            count: results.len(),
+            results,
+            matches,
+            groups: None,
+            room_order: Vec::new(),
+            next_batch: None,
+            timed_out: false,
+        })
+    }
+
+    /// Search the index.
+    ///
+    /// A search that has more results than fit into a single page returns a
+    /// `next_batch` token, the following pages are served from the same
+    /// snapshot of the index as the first one. Returns an
+    /// `InvalidSearchToken` error if the token is unknown or has expired.
+    pub fn search(&self, term: &str, config: &SearchConfig) -> crate::Result<SearchResult> {
+        let deadline = config.timeout.map(Deadline::after);
+        self.search_with_deadline(term, config, deadline.as_ref())
+    }
+
+    /// Search the index, collecting matches only until the given deadline
+    /// passes.
+    ///
+    /// The result of a search that hit the deadline only contains the
+    /// matches that were collected before, its `timed_out` flag is set.
+    pub(crate) fn search_with_deadline(
+        &self,
+        term: &str,
+        config: &SearchConfig,
+        deadline: Option<&Deadline>,
+    ) -> crate::Result<SearchResult> {
+        let normalized_term = normalize(term);
+        let term = normalized_term.as_ref();
+        let limit = config.limit;
+
+        let past_search = if let Some(token) = &config.next_batch {
+            let mut search_cache = self.search_cache.write().unwrap();
+
+            match search_cache.get_mut(token) {
+                Some(s) if s.created.elapsed() <= self.search_token_ttl => Some(s.clone()),
+                Some(_) => {
+                    search_cache.remove(token);
+                    return Err(Error::InvalidSearchToken);
+                }
+                None => return Err(Error::InvalidSearchToken),
+            }
+        } else {
+            None
+        };
+
+        let ((result, event_ids), groups, room_order, term, config, searcher) =
+            if let Some(past_search) = past_search {
+                let searcher = IndexSearcher {
+                    inner: past_search.searcher.clone(),
+                    ..self.clone()
+                };
+                let term = past_search.search_term.as_str();
+                let query = searcher.parse_query(term, &past_search.search_config)?;
+                let terms = searcher.highlight_terms(term, &query);
+                let previous_results = &past_search.event_ids;
+                let groups = if past_search.search_config.group_by_room {
+                    Some(searcher.group_by_room(&query, &past_search.search_config)?)
+                } else {
+                    None
+                };
+
+                let ((result, mut event_ids), room_order) = searcher.collect_results(
+                    config.candidate_limit(),
+                    config.order,
+                    past_search.search_config.group_rooms,
+                    previous_results,
+                    &query,
+                    &terms,
+                    past_search.search_config.min_date(),
+                    deadline,
+                )?;
+
+                // Add the previous results to the current ones.
+                event_ids.extend(previous_results.iter().cloned());
+
+                (
+                    (result, event_ids),
+                    groups,
+                    room_order,
+                    past_search.search_term.clone(),
+                    past_search.search_config.clone(),
+                    past_search.searcher.clone(),
+                )
+            } else {
+                IndexSearcher::check_term_length(term, &self.query_limits)?;
+                let query = self.parse_query(term, config)?;
+                let terms = self.highlight_terms(term, &query);
+                let groups = if config.group_by_room {
+                    Some(self.group_by_room(&query, config)?)
+                } else {
+                    None
+                };
+                let (result, room_order) = self.collect_results(
+                    config.candidate_limit(),
+                    config.order,
+                    config.group_rooms,
                     &[],
                     &query,
-                )?,
-                Arc::new(term.to_owned()),
-                Arc::new(config.clone()),
-            )
-        };
+                    &terms,
+                    config.min_date(),
+                    deadline,
+                )?;
+                (
+                    result,
+                    groups,
+                    room_order,
+                    Arc::new(term.to_owned()),
+                    Arc::new(config.clone()),
+                    self.inner.clone(),
+                )
+            };
+
+        let (count, docs) = result;
+
+        let mut results = Vec::with_capacity(docs.len());
+        let mut matches = HashMap::new();
 
-        let (count, results) = result;
+        for (score, event_id, matched_terms) in docs {
+            matches.insert(event_id.clone(), matched_terms);
+            results.push((score, event_id));
+        }
 
-        let next_batch = if event_ids.len() == count {
+        // Over-fetched results that don't make the cut are handed back to the
+        // search, see `return_results()`, it needs a token to find them again.
+        let next_batch = if event_ids.len() == count && results.len() <= limit {
             None
         } else {
             let mut search_cache = self.search_cache.write().unwrap();
+
+            // Release the snapshots of searches whose tokens have expired.
+            let expired: Vec<Uuid> = search_cache
+                .iter()
+                .filter(|(_, s)| s.created.elapsed() > self.search_token_ttl)
+                .map(|(token, _)| *token)
+                .collect();
+
+            for token in expired {
+                search_cache.remove(&token);
+            }
+
             let search = Search {
                 search_term: term,
                 search_config: config,
                 event_ids: Arc::new(event_ids),
+                searcher,
+                created: Instant::now(),
             };
 
             let token = Uuid::new_v4();
@@ -381,9 +1757,29 @@ impl IndexSearcher {
         Ok(SearchResult {
             count,
             results,
+            matches,
+            groups,
+            room_order,
             next_batch,
+            timed_out: deadline.map_or(false, Deadline::was_hit),
         })
     }
+
+    /// Hand results of a search back to it, the next batch of the search
+    /// can return them again.
+    ///
+    /// This is used for results that were fetched but weren't returned,
+    /// e.g. because they were over-fetched to be ranked after the search.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token of the next batch of the search.
+    /// * `event_ids` - The ids of the events that weren't returned.
+    pub(crate) fn return_results(&self, token: &Uuid, event_ids: &[EventId]) {
+        if let Some(search) = self.search_cache.write().unwrap().get_mut(token) {
+            Arc::make_mut(&mut search.event_ids).retain(|e| !event_ids.contains(e));
+        }
+    }
 }
 
 impl Index {
@@ -393,35 +1789,106 @@ impl Index {
         let text_field_options = Index::create_text_options(&tokenizer_name);
         let mut schemabuilder = tv::schema::Schema::builder();
 
-        let body_field = schemabuilder.add_text_field("body", text_field_options.clone());
+        // Splitting identifiers gives the body fields a tokenizer of their
+        // own, an index that was created with a different setting has a
+        // different schema and needs to be rebuilt.
+        let body_field = schemabuilder.add_text_field(
+            "body",
+            Index::create_text_options(&Index::body_tokenizer_name(
+                &config.language,
+                config.split_identifiers,
+            )),
+        );
         let topic_field = schemabuilder.add_text_field("topic", text_field_options.clone());
         let name_field = schemabuilder.add_text_field("name", text_field_options);
 
         let date_field = schemabuilder.add_u64_field("date", tv::schema::FAST);
 
-        let sender_field = schemabuilder.add_text_field("sender", tv::schema::STRING);
+        // The sender is tokenized so searching for "alice" finds the events
+        // of `@alice:example.org`, the untokenized copy can be used to filter
+        // for an exact sender.
+        let sender_field =
+            schemabuilder.add_text_field("sender", Index::create_text_options("default"));
+        let sender_id_field = schemabuilder.add_text_field("sender_id", tv::schema::STRING);
         let room_id_field =
             schemabuilder.add_text_field("room_id", tv::schema::STORED | tv::schema::STRING);
 
         let event_id_field =
             schemabuilder.add_text_field("event_id", tv::schema::STORED | tv::schema::STRING);
+        let thread_id_field = schemabuilder.add_text_field("thread_id", tv::schema::STRING);
+        // Messages that contain a link get a 1 here, the field is only used
+        // to filter searches.
+        let contains_url_field = schemabuilder.add_u64_field("contains_url", tv::schema::INDEXED);
+
+        // Every detected language gets a body field that is analyzed with the
+        // stemmer of the language.
+        let languages: &[Language] = if config.detect_language {
+            &config.detected_languages
+        } else {
+            &[]
+        };
+        let language_fields: Vec<(Language, tv::schema::Field)> = languages
+            .iter()
+            .filter_map(|language| {
+                let code = language.as_iso_code()?;
+                let field = schemabuilder.add_text_field(
+                    &format!("body_{}", code),
+                    Index::create_text_options(&Index::body_tokenizer_name(
+                        language,
+                        config.split_identifiers,
+                    )),
+                );
+                Some((language.clone(), field))
+            })
+            .collect();
 
         let schema = schemabuilder.build();
 
         let index = Index::open_index(path, config, schema)?;
-        let reader = index.reader()?;
+        // Without automatic reloads searches keep using the state of the last
+        // explicit reload.
+        let reload_policy = if config.auto_reload {
+            tv::ReloadPolicy::OnCommit
+        } else {
+            tv::ReloadPolicy::Manual
+        };
+        let reader = index
+            .reader_builder()
+            .reload_policy(reload_policy)
+            .try_into()?;
 
-        match config.language {
-            Language::Unknown => (),
-            _ => {
-                let tokenizer = tv::tokenizer::TextAnalyzer::from(tv::tokenizer::SimpleTokenizer)
-                    .filter(tv::tokenizer::RemoveLongFilter::limit(40))
-                    .filter(tv::tokenizer::LowerCaser)
-                    .filter(tv::tokenizer::Stemmer::new(config.language.as_tantivy()));
-                index.tokenizers().register(&tokenizer_name, tokenizer);
-            }
+        Index::register_tokenizers(&index, &config.language, config.split_identifiers);
+
+        for (language, _) in &language_fields {
+            Index::register_tokenizers(&index, language, config.split_identifiers);
         }
 
+        let mut parser_fields = vec![
+            (BODY_KEY, body_field),
+            (TOPIC_KEY, topic_field),
+            (NAME_KEY, name_field),
+            (ROOM_KEY, room_id_field),
+            (SENDER_KEY, sender_field),
+        ];
+        parser_fields.extend(language_fields.iter().map(|(_, field)| (BODY_KEY, *field)));
+
+        let query_parsers = Index::create_query_parsers(&index, &parser_fields);
+
+        // The reader registers its own callback to reload itself on commits
+        // before us, so by the time we invalidate the cached searcher a fresh
+        // one can be acquired.
+        let searcher_cache = Arc::new(RwLock::new(SearcherCache::default()));
+        let cache = searcher_cache.clone();
+        let watch_handle = if config.auto_reload {
+            Some(
+                index
+                    .directory()
+                    .watch(Box::new(move || SearcherCache::invalidate(&cache)))?,
+            )
+        } else {
+            None
+        };
+
         Ok(Index {
             index,
             reader,
@@ -430,9 +1897,29 @@ impl Index {
             name_field,
             event_id_field,
             sender_field,
+            sender_id_field,
             date_field,
             room_id_field,
+            thread_id_field,
+            contains_url_field,
+            language_fields: Arc::new(language_fields.iter().map(|(_, field)| *field).collect()),
+            language_detector: if language_fields.is_empty() {
+                None
+            } else {
+                Some(Arc::new(LanguageDetector::new(&language_fields)))
+            },
             search_cache: Arc::new(RwLock::new(LruCache::new(SEARCH_CACHE_SIZE))),
+            search_token_ttl: config.search_token_ttl,
+            query_limits: config.query_limits,
+            default_operator: config.default_operator,
+            leading_wildcards: config.leading_wildcards,
+            searcher_cache,
+            query_parsers: Arc::new(query_parsers),
+            _watch_handle: watch_handle,
+            auto_reload: config.auto_reload,
+            writer_heap_size: config.writer_heap_size,
+            #[cfg(test)]
+            fail_commits: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -479,6 +1966,91 @@ impl Index {
         Ok(())
     }
 
+    /// Register the tokenizers of the given language, the one that stems
+    /// words of the language and, if identifiers should be split, the one of
+    /// the body fields.
+    ///
+    /// The unknown language uses the default tokenizer, which doesn't stem
+    /// words.
+    fn register_tokenizers(index: &tv::Index, language: &Language, split_identifiers: bool) {
+        if *language != Language::Unknown {
+            index.tokenizers().register(
+                &language.as_tokenizer_name(),
+                Index::create_analyzer(language, false),
+            );
+        }
+
+        if split_identifiers {
+            index.tokenizers().register(
+                &Index::body_tokenizer_name(language, true),
+                Index::create_analyzer(language, true),
+            );
+        }
+    }
+
+    /// Create the analyzer that tokenizes text of the given language,
+    /// optionally splitting identifiers into their words, see
+    /// `Config::set_split_identifiers()`.
+    fn create_analyzer(
+        language: &Language,
+        split_identifiers: bool,
+    ) -> tv::tokenizer::TextAnalyzer {
+        let mut analyzer = tv::tokenizer::TextAnalyzer::from(tv::tokenizer::SimpleTokenizer)
+            .filter(tv::tokenizer::RemoveLongFilter::limit(40));
+
+        if split_identifiers {
+            analyzer = analyzer.filter(IdentifierSplitter);
+        }
+
+        analyzer = analyzer.filter(tv::tokenizer::LowerCaser);
+
+        match language {
+            Language::Unknown => analyzer,
+            _ => analyzer.filter(tv::tokenizer::Stemmer::new(language.as_tantivy())),
+        }
+    }
+
+    /// Get the name of the tokenizer of the body fields of the given
+    /// language.
+    fn body_tokenizer_name(language: &Language, split_identifiers: bool) -> String {
+        if split_identifiers {
+            format!("{}_identifiers", language.as_tokenizer_name())
+        } else {
+            language.as_tokenizer_name()
+        }
+    }
+
+    /// Create a query parser for every combination of the fields that can be
+    /// searched.
+    ///
+    /// The query parser that searches a given set of fields can be found
+    /// using the bitwise or of the `*_KEY` constants of the fields as the
+    /// index into the returned list, every set of fields has an additional
+    /// parser that combines words using AND under the `AND_KEY` bit. A key
+    /// may belong to multiple fields.
+    fn create_query_parsers(
+        index: &tv::Index,
+        fields: &[(usize, tv::schema::Field)],
+    ) -> Vec<tv::query::QueryParser> {
+        (0..(AND_KEY << 1))
+            .map(|mask| {
+                let fields = fields
+                    .iter()
+                    .filter(|(key, _)| mask & *key != 0)
+                    .map(|(_, field)| *field)
+                    .collect();
+
+                let mut parser = tv::query::QueryParser::for_index(index, fields);
+
+                if mask & AND_KEY != 0 {
+                    parser.set_conjunction_by_default();
+                }
+
+                parser
+            })
+            .collect()
+    }
+
     fn create_text_options(tokenizer: &str) -> tv::schema::TextOptions {
         let indexing = tv::schema::TextFieldIndexing::default()
             .set_tokenizer(tokenizer)
@@ -486,44 +2058,188 @@ impl Index {
         tv::schema::TextOptions::default().set_indexing_options(indexing)
     }
 
+    /// Check that a search term can be parsed and stays within the query
+    /// limits, without searching the index.
+    ///
+    /// The term goes through the same parsing as the term of a search that
+    /// uses the default search config. Wildcards aren't expanded since that
+    /// needs the terms of the index, only their syntax is checked.
+    ///
+    /// # Arguments
+    ///
+    /// * `term` - The search term that should be checked.
+    /// * `advanced_syntax` - Does the term use the advanced query syntax.
+    pub fn validate_query(&self, term: &str, advanced_syntax: bool) -> crate::Result<()> {
+        IndexSearcher::check_term_length(term, &self.query_limits)?;
+
+        if !advanced_syntax && IndexSearcher::only_excludes(term) {
+            return Err(Error::OnlyExcludedTerms);
+        }
+
+        let (words, wildcards) = if advanced_syntax {
+            (term.to_owned(), Vec::new())
+        } else {
+            IndexSearcher::split_wildcards(term, self.leading_wildcards)?
+        };
+
+        let mut keys = BODY_KEY | TOPIC_KEY | NAME_KEY;
+
+        // Words that are all excluded are excluded from the wildcard matches
+        // of a search, they are parsed without their minus.
+        let words = if !wildcards.is_empty() && IndexSearcher::only_excludes(&words) {
+            words
+                .split_whitespace()
+                .map(|w| w.trim_start_matches('-'))
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            if self.default_operator == QueryOperator::And {
+                keys |= AND_KEY;
+            }
+            words
+        };
+
+        if words.is_empty() {
+            return Ok(());
+        }
+
+        let query = IndexSearcher::parse_term(&self.query_parsers[keys], &words, advanced_syntax)?;
+        let filter_fields = [
+            self.room_id_field,
+            self.thread_id_field,
+            self.event_id_field,
+            self.contains_url_field,
+        ];
+
+        IndexSearcher::check_query_limits(&*query, &self.query_limits, |field| {
+            !filter_fields.contains(&field) && !self.language_fields.contains(&field)
+        })
+    }
+
     pub fn get_searcher(&self) -> IndexSearcher {
-        let searcher = self.reader.searcher();
-        let schema = self.index.schema();
-        let tokenizer = self.index.tokenizers().clone();
+        let searcher = SearcherCache::get(&self.searcher_cache, &self.reader);
 
         IndexSearcher {
             inner: searcher,
-            schema,
-            tokenizer,
+            query_parsers: self.query_parsers.clone(),
             body_field: self.body_field,
             topic_field: self.topic_field,
             name_field: self.name_field,
             room_id_field: self.room_id_field,
+            thread_id_field: self.thread_id_field,
+            contains_url_field: self.contains_url_field,
             sender_field: self.sender_field,
+            sender_id_field: self.sender_id_field,
             date_field: self.date_field,
             event_id_field: self.event_id_field,
+            language_fields: self.language_fields.clone(),
             search_cache: self.search_cache.clone(),
+            search_token_ttl: self.search_token_ttl,
+            query_limits: self.query_limits,
+            default_operator: self.default_operator,
+            leading_wildcards: self.leading_wildcards,
         }
     }
 
+    /// Check if the event with the given event id is part of the index.
+    ///
+    /// Only committed events that the index was reloaded with are found,
+    /// deleted events aren't found anymore once the deletion is committed.
+    pub fn contains_event(&self, event_id: &str) -> Result<bool, tv::TantivyError> {
+        let searcher = SearcherCache::get(&self.searcher_cache, &self.reader);
+        let query = TermQuery::new(
+            Term::from_field_text(self.event_id_field, event_id),
+            IndexRecordOption::Basic,
+        );
+
+        Ok(searcher.search(&query, &Count)? > 0)
+    }
+
+    /// Get the number of events that the index contains.
+    ///
+    /// Only committed events that the index was reloaded with are counted.
+    pub fn event_count(&self) -> u64 {
+        SearcherCache::get(&self.searcher_cache, &self.reader).num_docs()
+    }
+
+    /// Get the opstamp and the payload of the last commit of the index.
+    ///
+    /// The payload is `None` if the index was never committed or if the
+    /// commit didn't set a payload.
+    pub fn last_commit(&self) -> Result<(u64, Option<String>), tv::TantivyError> {
+        let metas = self.index.load_metas()?;
+        Ok((metas.opstamp, metas.payload))
+    }
+
+    /// Get the names of the files that make up the live index.
+    ///
+    /// These are the files of the segments the current `meta.json` refers
+    /// to, the meta files themselves and the key file of an encrypted index.
+    /// Files of merged or deleted segments that weren't garbage collected
+    /// yet aren't included.
+    pub fn live_files(&self) -> Result<Vec<PathBuf>, tv::TantivyError> {
+        let mut files: Vec<PathBuf> = self
+            .index
+            .searchable_segment_metas()?
+            .iter()
+            .flat_map(|meta| meta.list_files())
+            .collect();
+
+        files.push(PathBuf::from("meta.json"));
+        files.push(PathBuf::from(".managed.json"));
+
+        #[cfg(feature = "encryption")]
+        files.push(PathBuf::from(KEYFILE));
+
+        files.sort();
+
+        Ok(files)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
     pub fn reload(&self) -> Result<(), tv::TantivyError> {
-        self.reader.reload()
+        self.reader.reload()?;
+        SearcherCache::invalidate(&self.searcher_cache);
+        Ok(())
+    }
+
+    pub fn get_reloader(&self) -> Reloader {
+        Reloader {
+            inner: self.reader.clone(),
+            searcher_cache: self.searcher_cache.clone(),
+        }
     }
 
     pub fn get_writer(&self) -> Result<Writer, tv::TantivyError> {
+        let (committed_opstamp, _) = self.last_commit()?;
+
         Ok(Writer {
             inner: self
                 .index
-                .writer_with_num_threads(1, TANTIVY_WRITER_HEAP_SIZE)?,
+                .writer_with_num_threads(1, self.writer_heap_size)?,
+            index: self.index.clone(),
             body_field: self.body_field,
             topic_field: self.topic_field,
             name_field: self.name_field,
             event_id_field: self.event_id_field,
             room_id_field: self.room_id_field,
             sender_field: self.sender_field,
+            sender_id_field: self.sender_id_field,
             date_field: self.date_field,
+            thread_id_field: self.thread_id_field,
+            contains_url_field: self.contains_url_field,
+            language_detector: self.language_detector.clone(),
             added_events: 0,
             commit_timestamp: std::time::Instant::now(),
+            reloader: if self.auto_reload {
+                Some(self.get_reloader())
+            } else {
+                None
+            },
+            commit_payload: None,
+            committed_opstamp,
+            #[cfg(test)]
+            fail_commits: self.fail_commits.clone(),
         })
     }
 }
@@ -552,6 +2268,40 @@ fn add_an_event() {
     assert_eq!(result[0].1, event_id)
 }
 
+#[test]
+fn searcher_is_reused_until_a_reload() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let searcher = index.get_searcher();
+    assert!(Arc::ptr_eq(&searcher.inner, &index.get_searcher().inner));
+
+    let mut writer = index.get_writer().unwrap();
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let new_searcher = index.get_searcher();
+    assert!(!Arc::ptr_eq(&searcher.inner, &new_searcher.inner));
+    assert!(Arc::ptr_eq(
+        &new_searcher.inner,
+        &index.get_searcher().inner
+    ));
+
+    let result = new_searcher
+        .search("Test", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+
+    index.get_reloader().reload().unwrap();
+    assert!(!Arc::ptr_eq(
+        &new_searcher.inner,
+        &index.get_searcher().inner
+    ));
+}
+
 #[test]
 fn add_events_to_differing_rooms() {
     let tmpdir = TempDir::new().unwrap();
@@ -587,77 +2337,488 @@ fn add_events_to_differing_rooms() {
 }
 
 #[test]
-fn switch_languages() {
+fn group_results_by_room() {
     let tmpdir = TempDir::new().unwrap();
     let config = Config::new().set_language(&Language::English);
     let index = Index::new(&tmpdir, &config).unwrap();
 
     let mut writer = index.get_writer().unwrap();
 
+    let mut other_room_event = EVENT.clone();
+    other_room_event.event_id = "$other:localhost".to_string();
+    other_room_event.room_id = "!Test2:room".to_string();
+
     writer.add_event(&EVENT);
+    writer.add_event(&other_room_event);
+    writer.force_commit().unwrap();
+
+    // Put the newest event into a separate segment.
+    let mut newest_event = EVENT.clone();
+    newest_event.event_id = "$newest:localhost".to_string();
+    newest_event.server_ts = EVENT.server_ts + 1000;
+
+    writer.add_event(&newest_event);
     writer.force_commit().unwrap();
     index.reload().unwrap();
 
     let searcher = index.get_searcher();
     let result = searcher
-        .search("Test", &Default::default())
-        .unwrap()
-        .results;
-
-    let event_id = EVENT.event_id.to_string();
-
-    assert_eq!(result.len(), 1);
-    assert_eq!(result[0].1, event_id);
+        .search(
+            "Test",
+            SearchConfig::new()
+                .limit(1)
+                .order_by_recency(true)
+                .group_by_room(true),
+        )
+        .unwrap();
 
-    drop(index);
+    assert_eq!(result.count, 3);
+    assert_eq!(result.results.len(), 1);
 
-    let config = Config::new().set_language(&Language::German);
-    let index = Index::new(&tmpdir, &config);
+    let groups = result.groups.unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(
+        groups[&EVENT.room_id],
+        RoomGroup {
+            count: 2,
+            top_event_ids: vec![newest_event.event_id.clone()],
+        }
+    );
+    assert_eq!(
+        groups[&other_room_event.room_id],
+        RoomGroup {
+            count: 1,
+            top_event_ids: vec![other_room_event.event_id.clone()],
+        }
+    );
 
-    assert!(index.is_err())
+    let result = searcher.search("Test", &Default::default()).unwrap();
+    assert!(result.groups.is_none());
 }
 
 #[test]
-fn event_count() {
+fn order_results_by_room() {
     let tmpdir = TempDir::new().unwrap();
     let config = Config::new().set_language(&Language::English);
     let index = Index::new(&tmpdir, &config).unwrap();
 
     let mut writer = index.get_writer().unwrap();
-
-    assert_eq!(writer.added_events, 0);
-    writer.add_event(&EVENT);
-    assert_eq!(writer.added_events, 1);
+    let rooms = ["!a:room", "!b:room", "!c:room"];
+    let mut event_rooms = HashMap::new();
+
+    // The scores and timestamps of the rooms interleave.
+    for i in 0..9 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        event.room_id = rooms[i % 3].to_string();
+        event.server_ts = EVENT.server_ts + i as i64;
+        event.content_value = "Test ".repeat(i % 4 + 1) + "message";
+
+        event_rooms.insert(event.event_id.clone(), event.room_id.clone());
+        writer.add_event(&event);
+
+        if i == 4 {
+            writer.force_commit().unwrap();
+        }
+    }
 
     writer.force_commit().unwrap();
-    assert_eq!(writer.added_events, 0);
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    for order_by_recency in [true, false] {
+        let mut config = SearchConfig::new();
+        config
+            .limit(2)
+            .order_by_recency(order_by_recency)
+            .group_rooms(true);
+
+        let mut result = searcher.search("Test", &config).unwrap();
+        let room_order = result.room_order.clone();
+        assert_eq!(result.count, 9);
+        assert_eq!(room_order.len(), 3);
+
+        let mut results = result.results.clone();
+
+        while let Some(token) = result.next_batch {
+            result = searcher
+                .search("Test", config.clone().next_batch(token))
+                .unwrap();
+            assert_eq!(result.room_order, room_order);
+            results.extend(result.results.iter().cloned());
+        }
+
+        assert_eq!(results.len(), 9);
+
+        // No room's events are split by the events of another room.
+        let mut seen_rooms: Vec<&RoomId> = Vec::new();
+
+        for (_, event_id) in &results {
+            let room_id = &event_rooms[event_id];
+
+            if seen_rooms.last() != Some(&room_id) {
+                assert!(!seen_rooms.contains(&room_id));
+                seen_rooms.push(room_id);
+            }
+        }
+
+        assert_eq!(seen_rooms, room_order.iter().collect::<Vec<_>>());
+
+        if order_by_recency {
+            assert_eq!(room_order, ["!c:room", "!b:room", "!a:room"]);
+            assert_eq!(results[0].1, "$8:localhost");
+            assert_eq!(results[3].1, "$7:localhost");
+        } else {
+            // Every room's best match is followed by its worse ones.
+            for window in results.windows(2) {
+                if event_rooms[&window[0].1] == event_rooms[&window[1].1] {
+                    assert!(window[0].0 >= window[1].0);
+                }
+            }
+        }
+    }
+
+    let result = searcher.search("Test", &Default::default()).unwrap();
+    assert!(result.room_order.is_empty());
 }
 
 #[test]
-fn delete_an_event() {
+fn negative_timestamps_sort_as_the_oldest_events() {
     let tmpdir = TempDir::new().unwrap();
     let config = Config::new().set_language(&Language::English);
     let index = Index::new(&tmpdir, &config).unwrap();
 
     let mut writer = index.get_writer().unwrap();
 
+    let mut bogus_event = EVENT.clone();
+    bogus_event.event_id = "$bogus:localhost".to_string();
+    bogus_event.server_ts = -1000;
+
+    writer.add_event(&bogus_event);
     writer.add_event(&EVENT);
-    writer.add_event(&TOPIC_EVENT);
     writer.force_commit().unwrap();
     index.reload().unwrap();
 
-    let searcher = index.get_searcher();
-    let result = searcher
-        .search("Test", &Default::default())
+    let result = index
+        .get_searcher()
+        .search("Test", SearchConfig::new().order_by_recency(true))
         .unwrap()
         .results;
 
-    let event_id = &EVENT.event_id;
-
     assert_eq!(result.len(), 2);
-    assert_eq!(&result[0].1, event_id);
+    assert_eq!(&result[0].1, &EVENT.event_id);
+    assert_eq!(&result[1].1, &bogus_event.event_id);
+}
+
+#[test]
+fn recency_boosted_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let day: i64 = 24 * 60 * 60 * 1000;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+
+    let event = |event_id: &str, body: &str, server_ts: i64| {
+        let mut event = EVENT.clone();
+        event.event_id = event_id.to_string();
+        event.content_value = body.to_string();
+        event.server_ts = server_ts;
+        event
+    };
+
+    // Two equally scoring events that are a year apart.
+    writer.add_event(&event("$old", "standup notes", now - 366 * day));
+    writer.add_event(&event("$new", "standup notes", now - day));
+
+    // A vastly better match that is slightly older than a worse one.
+    writer.add_event(&event("$better", "release checklist", now - 2 * day));
+    writer.add_event(&event(
+        "$worse",
+        "the checklist for lunch, the office party and a couple of other things",
+        now - day,
+    ));
 
-    writer.delete_event(event_id);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let mut search_config = SearchConfig::new();
+    search_config.order_by(SearchOrder::RecencyBoosted {
+        half_life: Duration::from_secs(30 * 24 * 60 * 60),
+    });
+
+    let result = searcher
+        .search("standup notes", &search_config)
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].1, "$new");
+    assert_eq!(result[1].1, "$old");
+    assert!(result[0].0 > result[1].0);
+
+    let result = searcher
+        .search("release checklist", &search_config)
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].1, "$better");
+    assert_eq!(result[1].1, "$worse");
+}
+
+#[test]
+fn search_senders() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.event_id = "$alice:localhost".to_string();
+    event.sender = "@alice:example.org".to_string();
+    event.content_value = "Hello there".to_string();
+
+    writer.add_event(&event);
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("alice", &Default::default()).unwrap();
+    assert!(result.results.is_empty());
+
+    let result = searcher
+        .search("alice", SearchConfig::new().search_senders(true))
+        .unwrap();
+    assert_eq!(result.results.len(), 1);
+    assert_eq!(result.results[0].1, event.event_id);
+    assert_eq!(result.matches[&event.event_id].fields, vec!["sender"]);
+}
+
+#[test]
+fn search_excluding_senders() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for i in 0..10 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        event.sender = if i % 2 == 0 {
+            "@alice:example.org".to_string()
+        } else {
+            "@bob:example.org".to_string()
+        };
+        writer.add_event(&event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher
+        .search("Test", SearchConfig::new().limit(10))
+        .unwrap();
+    assert_eq!(result.count, 10);
+    assert_eq!(result.results.len(), 10);
+
+    let mut config = SearchConfig::new();
+    config
+        .limit(10)
+        .not_senders(vec!["@alice:example.org".to_string()]);
+
+    let result = searcher.search("Test", &config).unwrap();
+    assert_eq!(result.count, 5);
+    assert_eq!(result.results.len(), 5);
+
+    let odd_ids: Vec<EventId> = (0..10)
+        .filter(|i| i % 2 == 1)
+        .map(|i| format!("${}:localhost", i))
+        .collect();
+    assert!(result.results.iter().all(|(_, id)| odd_ids.contains(id)));
+
+    // The exclusion composes with the room filter and with ordering.
+    config.for_room(&EVENT.room_id).order_by_recency(true);
+    let result = searcher.search("Test", &config).unwrap();
+    assert_eq!(result.count, 5);
+    assert!(result.results.iter().all(|(_, id)| odd_ids.contains(id)));
+}
+
+#[test]
+fn search_events_with_urls() {
+    assert!(crate::events::contains_url("see https://matrix.org"));
+    assert!(crate::events::contains_url("(http://[::1]:8008)"));
+    assert!(crate::events::contains_url("HTTPS://EXAMPLE.ORG"));
+    assert!(!crate::events::contains_url("the https:// scheme"));
+    assert!(!crate::events::contains_url("xhttps://example.org"));
+    assert!(!crate::events::contains_url("matrix.org"));
+
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut event = EVENT.clone();
+    event.event_id = "$link:localhost".to_string();
+    event.content_value = "Test message with https://matrix.org".to_string();
+    writer.add_event(&event);
+
+    let mut file = EVENT.clone();
+    file.event_id = "$file:localhost".to_string();
+    file.content_value = "Test https://matrix.org.png".to_string();
+    file.msgtype = Some("m.file".to_string());
+    writer.add_event(&file);
+
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 3);
+
+    let result = searcher
+        .search("Test", SearchConfig::new().contains_url(true))
+        .unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].1, event.event_id);
+
+    let result = searcher
+        .search("Test", SearchConfig::new().contains_url(false))
+        .unwrap();
+    assert_eq!(result.count, 2);
+    assert!(result.results.iter().all(|(_, id)| id != &event.event_id));
+}
+
+#[test]
+fn search_in_a_thread() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for i in 0..3 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("$reply{}:localhost", i);
+        writer.add_event(&event.with_thread(&EVENT.event_id));
+
+        let mut event = EVENT.clone();
+        event.event_id = format!("$message{}:localhost", i);
+        writer.add_event(&event);
+    }
+
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("Test", &Default::default()).unwrap();
+    assert_eq!(result.results.len(), 7);
+
+    let result = searcher
+        .search("Test", SearchConfig::new().for_thread(&EVENT.event_id))
+        .unwrap();
+    let mut event_ids: Vec<&str> = result.results.iter().map(|r| r.1.as_str()).collect();
+    event_ids.sort_unstable();
+
+    assert_eq!(
+        event_ids,
+        vec![
+            "$reply0:localhost",
+            "$reply1:localhost",
+            "$reply2:localhost"
+        ]
+    );
+    assert_eq!(result.matches["$reply0:localhost"].highlights, vec!["Test"]);
+}
+
+#[test]
+fn switch_languages() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher
+        .search("Test", &Default::default())
+        .unwrap()
+        .results;
+
+    let event_id = EVENT.event_id.to_string();
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, event_id);
+
+    drop(index);
+
+    let config = Config::new().set_language(&Language::German);
+    let index = Index::new(&tmpdir, &config);
+
+    assert!(index.is_err())
+}
+
+#[test]
+fn event_count() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    assert_eq!(writer.added_events, 0);
+    writer.add_event(&EVENT);
+    assert_eq!(writer.added_events, 1);
+
+    writer.force_commit().unwrap();
+    assert_eq!(writer.added_events, 0);
+}
+
+#[test]
+fn delete_an_event() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher
+        .search("Test", &Default::default())
+        .unwrap()
+        .results;
+
+    let event_id = &EVENT.event_id;
+
+    assert_eq!(result.len(), 2);
+    assert_eq!(&result[0].1, event_id);
+
+    writer.delete_event(event_id).unwrap();
     writer.force_commit().unwrap();
     index.reload().unwrap();
 
@@ -670,6 +2831,119 @@ fn delete_an_event() {
     assert_eq!(&result[0].1, &TOPIC_EVENT.event_id);
 }
 
+#[test]
+fn delete_an_event_on_commit() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+
+    writer.delete_event_on_commit(&EVENT.event_id);
+    assert!(writer.has_uncommitted_events());
+    index.reload().unwrap();
+
+    let result = index
+        .get_searcher()
+        .search("Test", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 2);
+
+    // A rollback discards the deletion.
+    writer.rollback().unwrap();
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let result = index
+        .get_searcher()
+        .search("Test", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 2);
+
+    writer.delete_event_on_commit(&EVENT.event_id);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let result = index
+        .get_searcher()
+        .search("Test", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(&result[0].1, &TOPIC_EVENT.event_id);
+}
+
+#[test]
+fn committed_event_ids() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+    assert_eq!(index.last_commit().unwrap(), (0, None));
+
+    writer.set_commit_payload("payload");
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    assert!(writer.committed_event_ids().unwrap().is_empty());
+
+    writer.force_commit().unwrap();
+    let opstamp = writer.committed_opstamp();
+    assert!(opstamp > 0);
+    assert_eq!(
+        index.last_commit().unwrap(),
+        (opstamp, Some("payload".to_owned()))
+    );
+
+    let mut event_ids = writer.committed_event_ids().unwrap();
+    event_ids.sort();
+    let mut expected = vec![EVENT.event_id.clone(), TOPIC_EVENT.event_id.clone()];
+    expected.sort();
+    assert_eq!(event_ids, expected);
+
+    // Deleted events aren't returned even if their segment wasn't merged.
+    writer.delete_event(&EVENT.event_id).unwrap();
+    assert!(writer.committed_opstamp() > opstamp);
+    assert_eq!(
+        writer.committed_event_ids().unwrap(),
+        vec![TOPIC_EVENT.event_id.clone()]
+    );
+
+    index.reload().unwrap();
+    assert_eq!(index.event_count(), 1);
+}
+
+#[test]
+fn contains_event() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_auto_reload(false);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+    let event_id = &EVENT.event_id;
+
+    writer.add_event(&EVENT);
+    assert!(!index.contains_event(event_id).unwrap());
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+    assert!(index.contains_event(event_id).unwrap());
+    assert!(!index.contains_event(&TOPIC_EVENT.event_id).unwrap());
+
+    writer.delete_event(event_id).unwrap();
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+    assert!(!index.contains_event(event_id).unwrap());
+}
+
 #[test]
 fn paginated_search() {
     let tmpdir = TempDir::new().unwrap();
@@ -703,3 +2977,753 @@ fn paginated_search() {
     assert_eq!(&second_search.results[0].1, &TOPIC_EVENT.event_id);
     assert!(second_search.next_batch.is_none());
 }
+
+#[test]
+fn search_timeout() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for i in 0..5000 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        writer.add_event(&event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("Test", &SearchConfig::new()).unwrap();
+    assert_eq!(result.count, 5000);
+    assert!(!result.timed_out);
+
+    // The deadline passes before the first match is collected.
+    let result = searcher
+        .search("Test", SearchConfig::new().timeout(Duration::from_nanos(1)))
+        .unwrap();
+    assert!(result.timed_out);
+    assert!(result.count < 5000);
+    assert!(result.results.len() <= 10);
+
+    let result = searcher
+        .search("Test", SearchConfig::new().timeout(Duration::from_secs(60)))
+        .unwrap();
+    assert_eq!(result.count, 5000);
+    assert!(!result.timed_out);
+}
+
+#[test]
+fn paginated_search_uses_the_same_snapshot() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let first_search = index
+        .get_searcher()
+        .search("Test", SearchConfig::new().limit(1))
+        .unwrap();
+    assert_eq!(&first_search.results[0].1, &EVENT.event_id);
+
+    writer.delete_event(&TOPIC_EVENT.event_id).unwrap();
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let second_search = index
+        .get_searcher()
+        .search(
+            "",
+            SearchConfig::new()
+                .limit(1)
+                .next_batch(first_search.next_batch.unwrap()),
+        )
+        .unwrap();
+    assert_eq!(second_search.count, 2);
+    assert_eq!(&second_search.results[0].1, &TOPIC_EVENT.event_id);
+
+    let result = index
+        .get_searcher()
+        .search("Test", &Default::default())
+        .unwrap();
+    assert_eq!(result.count, 1);
+}
+
+#[test]
+fn invalid_search_token() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_search_token_ttl(Duration::from_millis(0));
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher.search("Test", SearchConfig::new().next_batch(Uuid::new_v4()));
+    assert!(matches!(result, Err(Error::InvalidSearchToken)));
+
+    let first_search = searcher
+        .search("Test", SearchConfig::new().limit(1))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+
+    let result = searcher.search(
+        "Test",
+        SearchConfig::new()
+            .limit(1)
+            .next_batch(first_search.next_batch.unwrap()),
+    );
+    assert!(matches!(result, Err(Error::InvalidSearchToken)));
+}
+
+#[test]
+fn query_limits() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_max_query_length(100)
+        .set_max_query_terms(4)
+        .set_max_query_clauses(6);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search(&"Test ".repeat(30), &Default::default());
+    assert!(matches!(result, Err(Error::QueryTooComplex(_))));
+
+    // Repeated words are only counted once.
+    let result = searcher.search(&"Test ".repeat(10), &Default::default());
+    assert_eq!(result.unwrap().count, 1);
+
+    let result = searcher.search("one two three four five", &Default::default());
+    assert!(matches!(result, Err(Error::QueryTooComplex(_))));
+
+    // Every word is searched for in the body, the topic and the name.
+    let result = searcher.search("Test Message", &Default::default());
+    assert_eq!(result.unwrap().count, 1);
+
+    let result = searcher.search("one two three", &Default::default());
+    assert!(matches!(result, Err(Error::QueryTooComplex(_))));
+
+    let result = searcher.search(
+        "one two three",
+        SearchConfig::new().with_key(EventType::Message),
+    );
+    assert_eq!(result.unwrap().count, 0);
+}
+
+#[test]
+fn validate_queries() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_max_query_length(100)
+        .set_max_query_terms(4);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    assert!(index.validate_query("Test message", false).is_ok());
+    assert!(index
+        .validate_query("\"test message\" -other", false)
+        .is_ok());
+    assert!(index.validate_query("tes* -mess*", false).is_ok());
+    assert!(index
+        .validate_query("body:test AND topic:\"a topic\"", true)
+        .is_ok());
+
+    match index.validate_query("body:\"unclosed quote", true) {
+        Err(Error::QuerySyntaxError(message)) => assert!(message.ends_with("at position 5")),
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    match index.validate_query("body:(test", true) {
+        Err(Error::QuerySyntaxError(message)) => assert!(message.ends_with("at position 5")),
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    assert!(matches!(
+        index.validate_query(&"Test ".repeat(30), false),
+        Err(Error::QueryTooComplex(_))
+    ));
+    assert!(matches!(
+        index.validate_query("one two three four five", false),
+        Err(Error::QueryTooComplex(_))
+    ));
+    assert!(matches!(
+        index.validate_query("-test", false),
+        Err(Error::OnlyExcludedTerms)
+    ));
+    assert!(matches!(
+        index.validate_query("*test", false),
+        Err(Error::LeadingWildcard)
+    ));
+}
+
+#[test]
+fn search_restricted_to_events() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new()
+        .set_language(&Language::English)
+        .set_max_restricted_ids(5);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    for i in 0..10 {
+        let mut event = EVENT.clone();
+        event.event_id = format!("${}:localhost", i);
+        // The events that aren't part of the restriction match the term best.
+        event.content_value = if i % 3 == 0 {
+            "Test message".to_owned()
+        } else {
+            "Test test test message message".to_owned()
+        };
+        writer.add_event(&event);
+    }
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let restricted: Vec<EventId> = ["$0:localhost", "$3:localhost", "$6:localhost"]
+        .iter()
+        .map(|id| id.to_string())
+        .collect();
+
+    let mut config = SearchConfig::new();
+    config.limit(10).restrict_to_events(restricted.clone());
+
+    for term in &["test", "message", "tes*", "body:test"] {
+        config.advanced_syntax(term.contains(':'));
+        let result = searcher.search(term, &config).unwrap();
+
+        assert_eq!(result.count, 3);
+        assert!(result.results.iter().all(|(_, id)| restricted.contains(id)));
+    }
+
+    config.for_room("!other:localhost");
+    assert_eq!(searcher.search("test", &config).unwrap().count, 0);
+
+    let mut config = SearchConfig::new();
+    config.restrict_to_events(vec![]);
+    assert_eq!(searcher.search("test", &config).unwrap().count, 0);
+
+    let ids = (0..6).map(|i| format!("${}:localhost", i)).collect();
+    config.restrict_to_events(ids);
+    assert!(matches!(
+        searcher.search("test", &config),
+        Err(Error::TooManyRestrictedIds(6, 5))
+    ));
+}
+
+#[test]
+fn advanced_query_syntax() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher
+        .search("topic:test", SearchConfig::new().advanced_syntax(true))
+        .unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].1, TOPIC_EVENT.event_id);
+
+    let result = searcher
+        .search(
+            "body:test^2 OR topic:test",
+            SearchConfig::new()
+                .advanced_syntax(true)
+                .for_room(&EVENT.room_id),
+        )
+        .unwrap();
+    assert_eq!(result.count, 2);
+
+    let result = searcher
+        .search(
+            "test",
+            SearchConfig::new()
+                .advanced_syntax(true)
+                .for_room("!other_room:localhost"),
+        )
+        .unwrap();
+    assert_eq!(result.count, 0);
+
+    let result = searcher.search("body:(test", SearchConfig::new().advanced_syntax(true));
+    assert!(matches!(result, Err(Error::QuerySyntaxError(_))));
+
+    let result = searcher.search("missing:test", SearchConfig::new().advanced_syntax(true));
+    assert!(matches!(result, Err(Error::QuerySyntaxError(_))));
+}
+
+#[test]
+fn default_query_operator() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher
+        .search("message topic", &Default::default())
+        .unwrap();
+    assert_eq!(result.count, 2);
+
+    let result = searcher
+        .search(
+            "message topic",
+            SearchConfig::new().default_operator(QueryOperator::And),
+        )
+        .unwrap();
+    assert_eq!(result.count, 0);
+
+    let result = searcher
+        .search(
+            "test message",
+            SearchConfig::new()
+                .default_operator(QueryOperator::And)
+                .for_room(&EVENT.room_id),
+        )
+        .unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].1, EVENT.event_id);
+
+    drop(writer);
+    drop(searcher);
+    drop(index);
+
+    let config = config.set_default_operator(QueryOperator::And);
+    let index = Index::new(&tmpdir, &config).unwrap();
+    let searcher = index.get_searcher();
+
+    let result = searcher
+        .search("message topic", &Default::default())
+        .unwrap();
+    assert_eq!(result.count, 0);
+
+    let result = searcher
+        .search(
+            "message topic",
+            SearchConfig::new().default_operator(QueryOperator::Or),
+        )
+        .unwrap();
+    assert_eq!(result.count, 2);
+}
+
+#[test]
+fn excluded_terms() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let event = |event_id: &str, body: &str, room_id: &str| {
+        let mut event = EVENT.clone();
+        event.event_id = event_id.to_string();
+        event.content_value = body.to_string();
+        event.room_id = room_id.to_string();
+        event
+    };
+
+    writer.add_event(&event("$notes", "meeting notes", "!room:localhost"));
+    writer.add_event(&event("$standup", "meeting standup", "!room:localhost"));
+    writer.add_event(&event("$mail", "send me an e-mail", "!room:localhost"));
+    writer.add_event(&event("$other", "meeting agenda", "!other:localhost"));
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher
+        .search("meeting -standup", &Default::default())
+        .unwrap();
+    let mut event_ids: Vec<&str> = result.results.iter().map(|r| r.1.as_str()).collect();
+    event_ids.sort();
+    assert_eq!(event_ids, vec!["$notes", "$other"]);
+
+    let result = searcher
+        .search(
+            "meeting -standup",
+            SearchConfig::new().for_room("!room:localhost"),
+        )
+        .unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].1, "$notes");
+
+    let result = searcher.search("-standup -notes", &Default::default());
+    assert!(matches!(result, Err(Error::OnlyExcludedTerms)));
+
+    let result = searcher.search("-standup", SearchConfig::new().for_room("!room:localhost"));
+    assert!(matches!(result, Err(Error::OnlyExcludedTerms)));
+
+    // A minus inside of a word doesn't exclude it.
+    let result = searcher.search("e-mail", &Default::default()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].1, "$mail");
+}
+
+#[test]
+fn wildcard_search() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Unknown);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let event = |event_id: &str, body: &str, room_id: &str| {
+        let mut event = EVENT.clone();
+        event.event_id = event_id.to_string();
+        event.content_value = body.to_string();
+        event.room_id = room_id.to_string();
+        event
+    };
+
+    writer.add_event(&event("$reports", "reports are due", "!room:localhost"));
+    writer.add_event(&event("$reporting", "reporting tool", "!room:localhost"));
+    writer.add_event(&event("$report", "the report", "!other:localhost"));
+    writer.add_event(&event("$passport", "my passport", "!room:localhost"));
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher.search("report*", &Default::default()).unwrap();
+    assert_eq!(result.count, 3);
+    assert!(result.matches["$reporting"]
+        .highlights
+        .contains(&"reporting".to_owned()));
+
+    let result = searcher
+        .search("report* -tool", &Default::default())
+        .unwrap();
+    assert_eq!(result.count, 2);
+
+    let result = searcher
+        .search("REPORT*", SearchConfig::new().for_room("!room:localhost"))
+        .unwrap();
+    assert_eq!(result.count, 2);
+
+    let result = searcher
+        .search(
+            "report* tool",
+            SearchConfig::new().default_operator(QueryOperator::And),
+        )
+        .unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].1, "$reporting");
+
+    let result = searcher.search("nothing*", &Default::default()).unwrap();
+    assert_eq!(result.count, 0);
+
+    let result = searcher.search("*port", &Default::default());
+    assert!(matches!(result, Err(Error::LeadingWildcard)));
+
+    let result = searcher.search("*", &Default::default());
+    assert!(matches!(result, Err(Error::LeadingWildcard)));
+
+    drop(writer);
+    drop(searcher);
+    drop(index);
+
+    let config = config.set_leading_wildcards(true).set_max_wildcard_terms(1);
+    let index = Index::new(&tmpdir, &config).unwrap();
+    let searcher = index.get_searcher();
+
+    // The wildcard only expands to the first matching term, "passport".
+    let result = searcher.search("*port", &Default::default()).unwrap();
+    assert_eq!(result.count, 1);
+    assert_eq!(result.results[0].1, "$passport");
+
+    let result = searcher.search("*", &Default::default());
+    assert!(matches!(result, Err(Error::LeadingWildcard)));
+}
+
+#[test]
+fn reply_fallbacks_are_not_indexed() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Unknown);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut reply = EVENT.clone();
+    reply.event_id = "$15163622445EBvZR:localhost".to_string();
+    reply.content_value =
+        "> <@alice:example.org> The secret phrase\n> is here\n\nThanks for sharing".to_string();
+
+    let mut quote = EVENT.clone();
+    quote.event_id = "$15163622445EBvZQ:localhost".to_string();
+    quote.content_value = "> Just a quoted secret phrase".to_string();
+
+    writer.add_event(&reply);
+    writer.add_event(&quote);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let result = searcher
+        .search("\"secret phrase\"", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, quote.event_id);
+
+    let result = searcher
+        .search("sharing", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, reply.event_id);
+}
+
+#[test]
+fn search_highlights() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::Unknown);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher
+        .search("Test Message nonexistent", &Default::default())
+        .unwrap();
+
+    assert_eq!(result.results.len(), 2);
+    assert_eq!(
+        result.matches[&EVENT.event_id].highlights,
+        vec!["Message", "Test"]
+    );
+    assert_eq!(
+        result.matches[&TOPIC_EVENT.event_id].highlights,
+        vec!["Test"]
+    );
+}
+
+#[test]
+fn search_matched_fields() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher.search("Test", &Default::default()).unwrap();
+
+    assert_eq!(result.results.len(), 2);
+    assert_eq!(result.matches[&EVENT.event_id].fields, vec!["content.body"]);
+    assert_eq!(
+        result.matches[&TOPIC_EVENT.event_id].fields,
+        vec!["content.topic"]
+    );
+}
+
+#[test]
+fn search_room_names_only() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let name_event = |event_id: &str, room_id: &str, name: &str, offset: i64| {
+        let mut event = TOPIC_EVENT.clone();
+        event.event_type = EventType::Name;
+        event.event_id = event_id.to_owned();
+        event.room_id = room_id.to_owned();
+        event.content_value = name.to_owned();
+        event.server_ts += offset;
+        event
+    };
+
+    let first = name_event("$name1:localhost", &EVENT.room_id, "Test room", 0);
+    let second = name_event("$name2:localhost", "!other:localhost", "Test lounge", 1);
+    let third = name_event("$name3:localhost", &EVENT.room_id, "Test room renamed", 2);
+
+    writer.add_event(&EVENT);
+    writer.add_event(&TOPIC_EVENT);
+    writer.add_event(&first);
+    writer.add_event(&second);
+    writer.add_event(&third);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    let mut config = SearchConfig::new();
+    config.with_key(EventType::Name);
+    let result = searcher.search("Test", &config).unwrap();
+    assert_eq!(result.count, 3);
+
+    for (_, event_id) in &result.results {
+        assert_eq!(result.matches[event_id].fields, vec!["content.name"]);
+    }
+
+    // Name events are sparse, make sure the timestamp ordered collector and
+    // the room filter only consider them.
+    config.for_room(&EVENT.room_id).order_by_recency(true);
+    let result = searcher.search("Test", &config).unwrap();
+    assert_eq!(result.count, 2);
+    assert_eq!(
+        result
+            .results
+            .iter()
+            .map(|(_, id)| id.as_str())
+            .collect::<Vec<_>>(),
+        vec![third.event_id.as_str(), first.event_id.as_str()]
+    );
+}
+
+#[test]
+fn search_file_names() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut file_event = EVENT.clone();
+    file_event.event_id = "$15163622445EBvZF:localhost".to_string();
+    file_event.msgtype = Some("m.file".to_string());
+    file_event.content_value = "quarterly_report-final.pdf".to_string();
+    assert!(file_event.is_file());
+
+    let mut empty_file_event = EVENT.clone();
+    empty_file_event.event_id = "$15163622445EBvZG:localhost".to_string();
+    empty_file_event.msgtype = Some("m.image".to_string());
+    empty_file_event.content_value = "".to_string();
+
+    writer.add_event(&EVENT);
+    writer.add_event(&file_event);
+    writer.add_event(&empty_file_event);
+    assert_eq!(writer.added_events, 2);
+
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+    let result = searcher
+        .search("quarterly report", &Default::default())
+        .unwrap()
+        .results;
+
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, file_event.event_id);
+
+    let result = searcher.search("pdf", &Default::default()).unwrap().results;
+    assert_eq!(result.len(), 1);
+
+    let result = searcher
+        .search("Test", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, EVENT.event_id);
+}
+
+#[test]
+fn unicode_normalization() {
+    let tmpdir = TempDir::new().unwrap();
+    let config = Config::new().set_language(&Language::English);
+    let index = Index::new(&tmpdir, &config).unwrap();
+
+    let mut writer = index.get_writer().unwrap();
+
+    let mut decomposed = EVENT.clone();
+    decomposed.event_id = "$15163622445EBvZH:localhost".to_string();
+    decomposed.content_value = "Meet me at the cafe\u{301}".to_string();
+
+    let mut composed = EVENT.clone();
+    composed.event_id = "$15163622445EBvZI:localhost".to_string();
+    composed.content_value = "The cr\u{e8}me bru\u{302}le\u{301}e was great".to_string();
+
+    let mut emoji = EVENT.clone();
+    emoji.event_id = "$15163622445EBvZJ:localhost".to_string();
+    emoji.content_value = "Thumbs \u{1f44d}\u{1f3fd} from the \u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467} family a\u{301}\u{1f3fb}".to_string();
+
+    writer.add_event(&decomposed);
+    writer.add_event(&composed);
+    writer.add_event(&emoji);
+    writer.force_commit().unwrap();
+    index.reload().unwrap();
+
+    let searcher = index.get_searcher();
+
+    // A composed query finds the decomposed message.
+    let result = searcher
+        .search("caf\u{e9}", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, decomposed.event_id);
+
+    // A decomposed query finds the composed message.
+    let result = searcher
+        .search("cre\u{300}me", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, composed.event_id);
+
+    let result = searcher
+        .search("br\u{fb}l\u{e9}e", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, composed.event_id);
+
+    let result = searcher
+        .search("family \u{1f44d}\u{1f3fd}", &Default::default())
+        .unwrap()
+        .results;
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0].1, emoji.event_id);
+}
@@ -0,0 +1,91 @@
+// Copyright 2019 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tantivy as tv;
+use tantivy::{
+    collector::{ScoreSegmentTweaker, ScoreTweaker},
+    fastfield::FastFieldReader,
+    schema::Field,
+    DocId, Score, SegmentReader,
+};
+
+/// A score tweaker that lets the score of a document decay with its age.
+///
+/// The relevance score of a document is halved for every half-life that
+/// passed since the document was sent.
+pub(crate) struct RecencyBoost {
+    date_field: Field,
+    now: u64,
+    half_life: f64,
+}
+
+impl RecencyBoost {
+    /// Create a new recency boost.
+    ///
+    /// # Arguments
+    ///
+    /// * `date_field` - The fast field containing the timestamp of a document
+    ///   in milliseconds.
+    /// * `half_life` - The age after which the score of a document is halved.
+    pub(crate) fn new(date_field: Field, half_life: Duration) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        RecencyBoost {
+            date_field,
+            now,
+            // A zero half-life would divide by zero, use the smallest
+            // representable one instead.
+            half_life: (half_life.as_millis() as f64).max(1.0),
+        }
+    }
+}
+
+pub(crate) struct SegmentRecencyBoost {
+    dates: FastFieldReader<u64>,
+    now: u64,
+    half_life: f64,
+}
+
+impl ScoreTweaker<f64> for RecencyBoost {
+    type Child = SegmentRecencyBoost;
+
+    fn segment_tweaker(&self, segment_reader: &SegmentReader) -> tv::Result<Self::Child> {
+        let dates = segment_reader
+            .fast_fields()
+            .u64(self.date_field)
+            .ok_or_else(|| {
+                tv::TantivyError::SchemaError("The date field isn't a fast field".to_owned())
+            })?;
+
+        Ok(SegmentRecencyBoost {
+            dates,
+            now: self.now,
+            half_life: self.half_life,
+        })
+    }
+}
+
+impl ScoreSegmentTweaker<f64> for SegmentRecencyBoost {
+    fn score(&self, doc: DocId, score: Score) -> f64 {
+        // Events from the future, e.g. because of a skewed clock, don't get
+        // boosted any further.
+        let age = self.now.saturating_sub(self.dates.get(doc)) as f64;
+        f64::from(score) * 0.5f64.powf(age / self.half_life)
+    }
+}
@@ -64,16 +64,28 @@ mod events;
 mod index;
 
 pub use database::{
-    Connection, Database, DatabaseStats, RecoveryDatabase, RecoveryInfo, SearchBatch, SearchResult,
-    Searcher,
+    BulkImport, CancellationToken, Connection, ConnectionPool, ConsistencyState, ContextTruncation,
+    Database, DatabaseFiles, DatabaseManager, DatabaseMetrics, DatabaseSize, DatabaseStats,
+    EventSender, EventWithContext, ExportSummary, ImportSummary, RecoveryDatabase, RecoveryInfo,
+    RoomInfo, SearchBatch, SearchResult, SearchTiming, Searcher, VersionInfo, WriterError,
+    WriterOperation,
 };
 
-pub use error::{Error, Result};
+pub use error::{Error, ErrorKind, Result};
+pub use index::{Reloader, RoomGroup};
 
-pub use config::{Config, Language, LoadConfig, LoadDirection, SearchConfig};
-pub use events::{CheckpointDirection, CrawlerCheckpoint, Event, EventType, Profile};
+pub use config::{
+    Config, Language, LoadConfig, LoadDirection, QueryOperator, SearchConfig, SearchOrder,
+};
+pub use events::{
+    CheckpointDirection, CheckpointValidationError, ContextEvent, CrawlerCheckpoint, Event,
+    EventBuilder, EventType, EventValidationError, Profile, Reaction, RoomAlias, FILE_MSGTYPES,
+};
 
 pub use std::sync::mpsc::Receiver;
 
+/// The version of the Seshat crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 #[cfg(test)]
 pub use events::{EVENT, EVENT_SOURCE, TOPIC_EVENT, TOPIC_EVENT_SOURCE};
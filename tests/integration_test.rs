@@ -299,7 +299,7 @@ fn get_size() {
         db.add_event(event, profile.clone());
     }
     db.force_commit().unwrap();
-    assert!(db.get_size().unwrap() > 0);
+    assert!(db.get_size().unwrap().total_bytes() > 0);
 }
 
 #[test]